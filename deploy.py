@@ -364,6 +364,7 @@ class OpenSKInstaller:
 
     command = [
         "cargo", "build", "--release", "--target={}".format(props.arch),
+        "--no-default-features",
         "--features={}".format(",".join(self.args.features))
     ]
     if is_example:
@@ -803,6 +804,15 @@ if __name__ == "__main__":
             "This is useful to allow flashing multiple OpenSK authenticators "
             "in a row without them being considered clones."),
   )
+  main_parser.add_argument(
+      "--no-store-checksum",
+      action=RemoveConstAction,
+      const="store_checksum",
+      dest="features",
+      help=("Compiles the OpenSK application without checksums on the entries "
+            "of a fresh persistent storage. Existing storages keep their "
+            "format, with or without checksums."),
+  )
   main_parser.add_argument(
       "--no-persistent-storage",
       action="append_const",
@@ -834,6 +844,6 @@ if __name__ == "__main__":
       help=("Compiles and installs the crypto_bench example that tests "
             "the performance of the cryptographic algorithms on the board."))
 
-  main_parser.set_defaults(features=["with_ctap1"])
+  main_parser.set_defaults(features=["with_ctap1", "store_checksum"])
 
   main(main_parser.parse_args())
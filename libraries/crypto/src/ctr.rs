@@ -0,0 +1,106 @@
+// Copyright 2019 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::util::Block16;
+use super::Encrypt16BytesBlock;
+
+// Encrypts or decrypts the data in place, in counter mode as in NIST SP 800-38A section 6.5. The
+// counter block starts at the IV and is incremented as a 128-bit big-endian integer. The data
+// doesn't need to be a whole number of blocks.
+pub fn ctr_crypt<K>(key: &K, iv: Block16, data: &mut [u8])
+where
+    K: Encrypt16BytesBlock,
+{
+    let mut counter = iv;
+    for chunk in data.chunks_mut(16) {
+        let mut key_stream = counter;
+        key.encrypt_block(&mut key_stream);
+        for (byte, mask) in chunk.iter_mut().zip(key_stream.iter()) {
+            *byte ^= mask;
+        }
+        increment(&mut counter);
+    }
+}
+
+fn increment(counter: &mut Block16) {
+    for byte in counter.iter_mut().rev() {
+        *byte = byte.wrapping_add(1);
+        if *byte != 0 {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::aes256;
+    use super::*;
+
+    // NIST SP 800-38A section F.5.5, CTR-AES256.Encrypt. The counter carries over a byte after
+    // the first block.
+    #[test]
+    fn test_ctr_aes256_vector() {
+        let key = aes256::EncryptionKey::new(&[
+            0x60, 0x3d, 0xeb, 0x10, 0x15, 0xca, 0x71, 0xbe, 0x2b, 0x73, 0xae, 0xf0, 0x85, 0x7d,
+            0x77, 0x81, 0x1f, 0x35, 0x2c, 0x07, 0x3b, 0x61, 0x08, 0xd7, 0x2d, 0x98, 0x10, 0xa3,
+            0x09, 0x14, 0xdf, 0xf4,
+        ]);
+        let iv = [
+            0xf0, 0xf1, 0xf2, 0xf3, 0xf4, 0xf5, 0xf6, 0xf7, 0xf8, 0xf9, 0xfa, 0xfb, 0xfc, 0xfd,
+            0xfe, 0xff,
+        ];
+        let mut data = vec![
+            0x6b, 0xc1, 0xbe, 0xe2, 0x2e, 0x40, 0x9f, 0x96, 0xe9, 0x3d, 0x7e, 0x11, 0x73, 0x93,
+            0x17, 0x2a, 0xae, 0x2d, 0x8a, 0x57, 0x1e, 0x03, 0xac, 0x9c, 0x9e, 0xb7, 0x6f, 0xac,
+            0x45, 0xaf, 0x8e, 0x51, 0x30, 0xc8, 0x1c, 0x46, 0xa3, 0x5c, 0xe4, 0x11, 0xe5, 0xfb,
+            0xc1, 0x19, 0x1a, 0x0a, 0x52, 0xef, 0xf6, 0x9f, 0x24, 0x45, 0xdf, 0x4f, 0x9b, 0x17,
+            0xad, 0x2b, 0x41, 0x7b, 0xe6, 0x6c, 0x37, 0x10,
+        ];
+        let expected = vec![
+            0x60, 0x1e, 0xc3, 0x13, 0x77, 0x57, 0x89, 0xa5, 0xb7, 0xa7, 0xf5, 0x04, 0xbb, 0xf3,
+            0xd2, 0x28, 0xf4, 0x43, 0xe3, 0xca, 0x4d, 0x62, 0xb5, 0x9a, 0xca, 0x84, 0xe9, 0x90,
+            0xca, 0xca, 0xf5, 0xc5, 0x2b, 0x09, 0x30, 0xda, 0xa2, 0x3d, 0xe9, 0x4c, 0xe8, 0x70,
+            0x17, 0xba, 0x2d, 0x84, 0x98, 0x8d, 0xdf, 0xc9, 0xc5, 0x8d, 0xb6, 0x7a, 0xad, 0xa6,
+            0x13, 0xc2, 0xdd, 0x08, 0x45, 0x79, 0x41, 0xa6,
+        ];
+        ctr_crypt(&key, iv, &mut data);
+        assert_eq!(data, expected);
+    }
+
+    #[test]
+    fn test_ctr_partial_block() {
+        let key = aes256::EncryptionKey::new(&[0x11; 32]);
+        let iv = [0x22; 16];
+        let plaintext: Vec<u8> = (0..37).collect();
+        let mut data = plaintext.clone();
+        ctr_crypt(&key, iv, &mut data);
+        // A prefix of the data is encrypted as the prefix of the key stream.
+        let mut prefix = plaintext[..20].to_vec();
+        ctr_crypt(&key, iv, &mut prefix);
+        assert_eq!(prefix, &data[..20]);
+        ctr_crypt(&key, iv, &mut data);
+        assert_eq!(data, plaintext);
+    }
+
+    #[test]
+    fn test_ctr_counter_wraps() {
+        let mut counter = [0xff; 16];
+        increment(&mut counter);
+        assert_eq!(counter, [0; 16]);
+        let mut counter = [0; 16];
+        counter[15] = 0xff;
+        increment(&mut counter);
+        assert_eq!(counter[14..], [1, 0]);
+    }
+}
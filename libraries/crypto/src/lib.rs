@@ -27,6 +27,7 @@ extern crate cbor;
 
 pub mod aes256;
 pub mod cbc;
+pub mod ctr;
 mod ec;
 pub mod ecdh;
 pub mod ecdsa;
@@ -12,6 +12,10 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+#[cfg(feature = "std")]
+use super::hmac::hmac_256;
+#[cfg(feature = "std")]
+use super::sha256::Sha256;
 use libtock::rng;
 
 // Lightweight RNG trait to generate uniformly distributed 256 bits.
@@ -63,6 +67,31 @@ impl Rng256 for ThreadRng256 {
     }
 }
 
+// Deterministic RNG, expanding a 256-bit seed with HMAC-SHA256 in counter mode.
+// It is meant to reproduce the behavior of an authenticator, e.g. when replaying a trace: whoever
+// knows the seed knows every key generated from it. For this reason, it is not available on devices.
+#[cfg(feature = "std")]
+pub struct SeededRng256 {
+    seed: [u8; 32],
+    counter: u64,
+}
+
+#[cfg(feature = "std")]
+impl SeededRng256 {
+    pub fn new(seed: [u8; 32]) -> SeededRng256 {
+        SeededRng256 { seed, counter: 0 }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Rng256 for SeededRng256 {
+    fn gen_uniform_u8x32(&mut self) -> [u8; 32] {
+        let output = hmac_256::<Sha256>(&self.seed, &self.counter.to_be_bytes());
+        self.counter += 1;
+        output
+    }
+}
+
 #[cfg(test)]
 pub mod test {
     use super::*;
@@ -88,4 +117,27 @@ pub mod test {
 
         assert_eq!(bytes_to_u32(*bytes), expected);
     }
+
+    #[test]
+    fn test_seeded_rng_is_deterministic() {
+        let mut rng = SeededRng256::new([0x55; 32]);
+        let mut same_rng = SeededRng256::new([0x55; 32]);
+        let mut other_rng = SeededRng256::new([0xAA; 32]);
+        let first = rng.gen_uniform_u8x32();
+        let second = rng.gen_uniform_u8x32();
+        assert_ne!(first, second);
+        assert_eq!(same_rng.gen_uniform_u8x32(), first);
+        assert_eq!(same_rng.gen_uniform_u8x32(), second);
+        assert_ne!(other_rng.gen_uniform_u8x32(), first);
+    }
+
+    #[test]
+    fn test_seeded_rng_is_hmac_of_counter() {
+        let mut rng = SeededRng256::new([0x55; 32]);
+        rng.gen_uniform_u8x32();
+        assert_eq!(
+            rng.gen_uniform_u8x32(),
+            hmac_256::<Sha256>(&[0x55; 32], &[0, 0, 0, 0, 0, 0, 0, 1])
+        );
+    }
 }
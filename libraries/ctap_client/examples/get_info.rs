@@ -0,0 +1,55 @@
+// Copyright 2019 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Prints the GetInfo response of an authenticator, for example of the desktop build:
+//
+//     cargo run --example get_info -- --unix /tmp/opensk.sock
+
+extern crate ctap_client;
+
+use ctap_client::hid::HidDevice;
+use ctap_client::{CtapClient, Error};
+
+fn get_info<D: HidDevice>(device: D) -> Result<(), Error> {
+    let mut client = CtapClient::new(device)?;
+    println!("Channel: {:02x?}", client.channel());
+    println!("{:#?}", client.get_info()?);
+    println!("PIN retries: {}", client.get_pin_retries()?);
+    Ok(())
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let result = match args.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+        #[cfg(unix)]
+        ["--unix", path] => std::os::unix::net::UnixStream::connect(path)
+            .map_err(Error::Io)
+            .and_then(get_info),
+        ["--tcp", address] => std::net::TcpStream::connect(address)
+            .map_err(Error::Io)
+            .and_then(get_info),
+        #[cfg(target_os = "linux")]
+        ["--hidraw", path] => ctap_client::hid::HidrawDevice::open(path.as_ref())
+            .map_err(Error::Io)
+            .and_then(get_info),
+        _ => {
+            eprintln!("Usage: get_info (--unix PATH | --tcp ADDRESS | --hidraw PATH)");
+            std::process::exit(2);
+        }
+    };
+    if let Err(e) = result {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
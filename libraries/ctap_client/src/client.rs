@@ -0,0 +1,560 @@
+// Copyright 2019 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::ctap::command::{
+    AuthenticatorClientPinParameters, AuthenticatorGetAssertionParameters,
+    AuthenticatorMakeCredentialParameters, Command,
+};
+use super::ctap::data_formats::ClientPinSubCommand;
+use super::ctap::response::{
+    AuthenticatorClientPinResponse, AuthenticatorGetAssertionResponse,
+    AuthenticatorGetInfoResponse, AuthenticatorMakeCredentialResponse,
+};
+use super::ctap::status_code::Ctap2StatusCode;
+use super::hid::{
+    recv_message, send_message, ChannelID, HidDevice, Message, CHANNEL_BROADCAST, COMMAND_CBOR,
+    COMMAND_ERROR, COMMAND_INIT, COMMAND_KEEPALIVE, COMMAND_PING, COMMAND_WINK,
+};
+use super::pin::{SharedSecret, PIN_PROTOCOL_VERSION};
+use super::Error;
+use core::convert::TryFrom;
+use crypto::rng256::{Rng256, ThreadRng256};
+use std::time::Duration;
+
+// CTAP specification (version 20190130) section 8.1.9.1.3
+const INIT_NONCE_LENGTH: usize = 8;
+const INIT_RESPONSE_LENGTH: usize = 17;
+const CAPABILITY_WINK: u8 = 0x01;
+const CAPABILITY_CBOR: u8 = 0x04;
+
+// Longer than the 30 seconds the firmware waits for the user.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(35);
+
+// A client of an authenticator, on a channel allocated with CTAPHID_INIT.
+pub struct CtapClient<D: HidDevice> {
+    device: D,
+    cid: ChannelID,
+    capabilities: u8,
+    timeout: Duration,
+}
+
+impl<D: HidDevice> CtapClient<D> {
+    // Allocates a channel, and checks that the authenticator supports CTAP2.
+    pub fn new(mut device: D) -> Result<CtapClient<D>, Error> {
+        let nonce = ThreadRng256 {}.gen_uniform_u8x32()[..INIT_NONCE_LENGTH].to_vec();
+        let request = Message {
+            cid: CHANNEL_BROADCAST,
+            cmd: COMMAND_INIT,
+            payload: nonce.clone(),
+        };
+        send_message(&mut device, &request)?;
+        // Other clients may be allocating channels at the same time.
+        let response = loop {
+            let response = recv_message(&mut device, CHANNEL_BROADCAST, DEFAULT_TIMEOUT)?;
+            if response.cmd == COMMAND_ERROR {
+                return Err(Error::Hid(response.payload.get(0).cloned().unwrap_or(0)));
+            }
+            if response.cmd == COMMAND_INIT && response.payload.starts_with(&nonce) {
+                break response;
+            }
+        };
+        if response.payload.len() < INIT_RESPONSE_LENGTH {
+            return Err(Error::Protocol("the INIT response is too short"));
+        }
+        let capabilities = response.payload[16];
+        if capabilities & CAPABILITY_CBOR == 0 {
+            return Err(Error::Protocol("the authenticator doesn't support CTAP2"));
+        }
+        Ok(CtapClient {
+            device,
+            cid: *array_ref!(response.payload, 8, 4),
+            capabilities,
+            timeout: DEFAULT_TIMEOUT,
+        })
+    }
+
+    pub fn channel(&self) -> ChannelID {
+        self.cid
+    }
+
+    // How long to wait for a response. Keepalives restart the wait.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    pub fn into_device(self) -> D {
+        self.device
+    }
+
+    // Sends a CTAPHID command, and returns the payload of the response. Keepalives are skipped.
+    pub fn transact(&mut self, cmd: u8, payload: Vec<u8>) -> Result<Vec<u8>, Error> {
+        let request = Message {
+            cid: self.cid,
+            cmd,
+            payload,
+        };
+        send_message(&mut self.device, &request)?;
+        loop {
+            let response = recv_message(&mut self.device, self.cid, self.timeout)?;
+            match response.cmd {
+                COMMAND_KEEPALIVE => continue,
+                COMMAND_ERROR => {
+                    return Err(Error::Hid(response.payload.get(0).cloned().unwrap_or(0)))
+                }
+                _ if response.cmd == cmd => return Ok(response.payload),
+                _ => return Err(Error::Protocol("the response is for another command")),
+            }
+        }
+    }
+
+    pub fn ping(&mut self, data: Vec<u8>) -> Result<(), Error> {
+        if self.transact(COMMAND_PING, data.clone())? != data {
+            return Err(Error::Protocol(
+                "the PING response differs from the request",
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn wink(&mut self) -> Result<(), Error> {
+        if self.capabilities & CAPABILITY_WINK == 0 {
+            return Err(Error::Protocol("the authenticator can't wink"));
+        }
+        self.transact(COMMAND_WINK, vec![])?;
+        Ok(())
+    }
+
+    // Sends a CTAP2 command, and returns the CBOR value of the response, if any.
+    pub fn command(&mut self, command: Command) -> Result<Option<cbor::Value>, Error> {
+        let response = self.transact(COMMAND_CBOR, command.serialize()?)?;
+        match response.split_first() {
+            Some((0x00, [])) => Ok(None),
+            Some((0x00, cbor_bytes)) => {
+                Ok(Some(cbor::read(cbor_bytes).map_err(Ctap2StatusCode::from)?))
+            }
+            Some((&status, _)) => Err(Error::Status(status)),
+            None => Err(Error::Protocol("the CBOR response is empty")),
+        }
+    }
+
+    // Runs a command expecting a response, and decodes it.
+    fn command_with_response<T>(&mut self, command: Command) -> Result<T, Error>
+    where
+        T: TryFrom<cbor::Value, Error = Ctap2StatusCode>,
+    {
+        match self.command(command)? {
+            Some(value) => Ok(T::try_from(value)?),
+            None => Err(Error::Protocol("the response has no data")),
+        }
+    }
+
+    pub fn make_credential(
+        &mut self,
+        params: AuthenticatorMakeCredentialParameters,
+    ) -> Result<AuthenticatorMakeCredentialResponse, Error> {
+        self.command_with_response(Command::AuthenticatorMakeCredential(params))
+    }
+
+    pub fn get_assertion(
+        &mut self,
+        params: AuthenticatorGetAssertionParameters,
+    ) -> Result<AuthenticatorGetAssertionResponse, Error> {
+        self.command_with_response(Command::AuthenticatorGetAssertion(params))
+    }
+
+    pub fn get_next_assertion(&mut self) -> Result<AuthenticatorGetAssertionResponse, Error> {
+        self.command_with_response(Command::AuthenticatorGetNextAssertion)
+    }
+
+    pub fn get_info(&mut self) -> Result<AuthenticatorGetInfoResponse, Error> {
+        self.command_with_response(Command::AuthenticatorGetInfo)
+    }
+
+    pub fn client_pin(
+        &mut self,
+        params: AuthenticatorClientPinParameters,
+    ) -> Result<Option<AuthenticatorClientPinResponse>, Error> {
+        self.command(Command::AuthenticatorClientPin(params))?
+            .map(AuthenticatorClientPinResponse::try_from)
+            .transpose()
+            .map_err(Error::Format)
+    }
+
+    pub fn reset(&mut self) -> Result<(), Error> {
+        self.command(Command::AuthenticatorReset)?;
+        Ok(())
+    }
+
+    fn client_pin_params(sub_command: ClientPinSubCommand) -> AuthenticatorClientPinParameters {
+        AuthenticatorClientPinParameters {
+            pin_protocol: PIN_PROTOCOL_VERSION,
+            sub_command,
+            key_agreement: None,
+            pin_auth: None,
+            new_pin_enc: None,
+            pin_hash_enc: None,
+        }
+    }
+
+    pub fn get_pin_retries(&mut self) -> Result<u64, Error> {
+        let params = Self::client_pin_params(ClientPinSubCommand::GetPinRetries);
+        match self.client_pin(params)? {
+            Some(AuthenticatorClientPinResponse {
+                retries: Some(retries),
+                ..
+            }) => Ok(retries),
+            _ => Err(Error::Format(Ctap2StatusCode::CTAP2_ERR_MISSING_PARAMETER)),
+        }
+    }
+
+    // Agrees on a shared secret with the authenticator, for the other PIN subcommands.
+    pub fn key_agreement(&mut self) -> Result<SharedSecret, Error> {
+        let params = Self::client_pin_params(ClientPinSubCommand::GetKeyAgreement);
+        match self.client_pin(params)? {
+            Some(AuthenticatorClientPinResponse {
+                key_agreement: Some(key_agreement),
+                ..
+            }) => Ok(SharedSecret::new(&mut ThreadRng256 {}, key_agreement)?),
+            _ => Err(Error::Format(Ctap2StatusCode::CTAP2_ERR_MISSING_PARAMETER)),
+        }
+    }
+
+    pub fn set_pin(&mut self, pin: &str) -> Result<(), Error> {
+        let shared_secret = self.key_agreement()?;
+        let new_pin_enc = shared_secret
+            .encrypt_new_pin(pin)
+            .ok_or(Error::InvalidArgument("the PIN is too long"))?;
+        let mut params = Self::client_pin_params(ClientPinSubCommand::SetPin);
+        params.key_agreement = Some(shared_secret.platform_key());
+        params.pin_auth = Some(shared_secret.authenticate(&new_pin_enc));
+        params.new_pin_enc = Some(new_pin_enc);
+        self.client_pin(params)?;
+        Ok(())
+    }
+
+    pub fn change_pin(&mut self, current_pin: &str, new_pin: &str) -> Result<(), Error> {
+        let shared_secret = self.key_agreement()?;
+        let new_pin_enc = shared_secret
+            .encrypt_new_pin(new_pin)
+            .ok_or(Error::InvalidArgument("the PIN is too long"))?;
+        let pin_hash_enc = shared_secret.encrypt_pin_hash(current_pin);
+        let mut params = Self::client_pin_params(ClientPinSubCommand::ChangePin);
+        params.key_agreement = Some(shared_secret.platform_key());
+        params.pin_auth =
+            Some(shared_secret.authenticate(&[&new_pin_enc[..], &pin_hash_enc[..]].concat()));
+        params.new_pin_enc = Some(new_pin_enc);
+        params.pin_hash_enc = Some(pin_hash_enc);
+        self.client_pin(params)?;
+        Ok(())
+    }
+
+    // Returns the decrypted pinToken, see pin::authenticate_client_data_hash to use it.
+    pub fn get_pin_token(&mut self, pin: &str) -> Result<Vec<u8>, Error> {
+        let shared_secret = self.key_agreement()?;
+        let mut params = Self::client_pin_params(ClientPinSubCommand::GetPinUvAuthTokenUsingPin);
+        params.key_agreement = Some(shared_secret.platform_key());
+        params.pin_hash_enc = Some(shared_secret.encrypt_pin_hash(pin));
+        match self.client_pin(params)? {
+            Some(AuthenticatorClientPinResponse {
+                pin_token: Some(pin_token_enc),
+                ..
+            }) => shared_secret
+                .decrypt_pin_token(&pin_token_enc)
+                .ok_or(Error::Format(Ctap2StatusCode::CTAP1_ERR_INVALID_LENGTH)),
+            _ => Err(Error::Format(Ctap2StatusCode::CTAP2_ERR_MISSING_PARAMETER)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::ctap::data_formats::CoseKey;
+    use super::super::ctap::response::ResponseData;
+    use super::super::hid::HidPacket;
+    use super::*;
+    use crypto::aes256::{DecryptionKey, EncryptionKey};
+    use crypto::cbc::{cbc_decrypt, cbc_encrypt};
+    use crypto::hmac::hmac_256;
+    use crypto::sha256::Sha256;
+    use crypto::{ecdh, Hash256};
+    use std::collections::VecDeque;
+
+    const CID: ChannelID = [0x12, 0x34, 0x56, 0x78];
+
+    struct Packets(VecDeque<HidPacket>);
+
+    impl HidDevice for Packets {
+        fn send_packet(&mut self, packet: &HidPacket) -> std::io::Result<()> {
+            self.0.push_back(*packet);
+            Ok(())
+        }
+
+        fn recv_packet(&mut self, _timeout: Duration) -> std::io::Result<Option<HidPacket>> {
+            Ok(self.0.pop_front())
+        }
+    }
+
+    // Answers each request with the messages returned by the handler.
+    struct FakeAuthenticator<F: FnMut(Message) -> Vec<Message>> {
+        handler: F,
+        request: Packets,
+        responses: Packets,
+    }
+
+    impl<F: FnMut(Message) -> Vec<Message>> HidDevice for FakeAuthenticator<F> {
+        fn send_packet(&mut self, packet: &HidPacket) -> std::io::Result<()> {
+            self.request.send_packet(packet)?;
+            let first_packet = self.request.0[0];
+            let length = u16::from_be_bytes([first_packet[5], first_packet[6]]) as usize;
+            if length <= 57 + (self.request.0.len() - 1) * 59 {
+                let cid = *array_ref!(first_packet, 0, 4);
+                let request = recv_message(&mut self.request, cid, Duration::from_secs(1)).unwrap();
+                for response in (self.handler)(request) {
+                    send_message(&mut self.responses, &response).unwrap();
+                }
+            }
+            Ok(())
+        }
+
+        fn recv_packet(&mut self, timeout: Duration) -> std::io::Result<Option<HidPacket>> {
+            self.responses.recv_packet(timeout)
+        }
+    }
+
+    fn fake_authenticator(
+        handler: impl FnMut(Message) -> Vec<Message>,
+    ) -> FakeAuthenticator<impl FnMut(Message) -> Vec<Message>> {
+        FakeAuthenticator {
+            handler,
+            request: Packets(VecDeque::new()),
+            responses: Packets(VecDeque::new()),
+        }
+    }
+
+    fn init_response(request: &Message, capabilities: u8) -> Message {
+        let mut payload = request.payload.clone();
+        payload.extend_from_slice(&CID);
+        payload.extend_from_slice(&[0x02, 0x00, 0x00, 0x00, capabilities]);
+        Message {
+            cid: CHANNEL_BROADCAST,
+            cmd: COMMAND_INIT,
+            payload,
+        }
+    }
+
+    fn cbor_response(response_data: ResponseData) -> Message {
+        let mut payload = vec![0x00];
+        if let Some(value) = response_data.into() {
+            assert!(cbor::write(value, &mut payload));
+        }
+        Message {
+            cid: CID,
+            cmd: COMMAND_CBOR,
+            payload,
+        }
+    }
+
+    fn message(cmd: u8, payload: Vec<u8>) -> Message {
+        Message {
+            cid: CID,
+            cmd,
+            payload,
+        }
+    }
+
+    fn ctap_client(mut handler: impl FnMut(Message) -> Vec<Message>) -> CtapClient<impl HidDevice> {
+        let device = fake_authenticator(move |request| match request.cmd {
+            COMMAND_INIT => vec![init_response(&request, CAPABILITY_WINK | CAPABILITY_CBOR)],
+            _ => handler(request),
+        });
+        CtapClient::new(device).unwrap()
+    }
+
+    #[test]
+    fn test_init() {
+        let device = fake_authenticator(|request| {
+            let mut other_request = Message {
+                cid: CHANNEL_BROADCAST,
+                cmd: COMMAND_INIT,
+                payload: vec![0x00; 8],
+            };
+            other_request.payload[0] = !request.payload[0];
+            // The response to another client is ignored.
+            vec![
+                init_response(&other_request, CAPABILITY_CBOR),
+                init_response(&request, CAPABILITY_CBOR),
+            ]
+        });
+        let client = CtapClient::new(device).unwrap();
+        assert_eq!(client.channel(), CID);
+
+        let device = fake_authenticator(|request| vec![init_response(&request, CAPABILITY_WINK)]);
+        match CtapClient::new(device) {
+            Err(Error::Protocol(_)) => (),
+            _ => panic!("Unexpected success without CBOR capability"),
+        }
+    }
+
+    #[test]
+    fn test_ping_and_wink() {
+        let mut client = ctap_client(|request| vec![request]);
+        assert!(client.ping(vec![0x50; 100]).is_ok());
+        assert!(client.wink().is_ok());
+    }
+
+    #[test]
+    fn test_get_info() {
+        let mut client = ctap_client(|request| {
+            assert_eq!(request.payload, vec![0x04]);
+            let get_info_response = AuthenticatorGetInfoResponse {
+                versions: vec!["FIDO_2_0".to_string()],
+                extensions: None,
+                aaguid: [0xAA; 16],
+                options: None,
+                max_msg_size: Some(1024),
+                pin_protocols: Some(vec![1]),
+                #[cfg(feature = "with_ctap2_1")]
+                max_credential_count_in_list: None,
+                #[cfg(feature = "with_ctap2_1")]
+                max_credential_id_length: None,
+                #[cfg(feature = "with_ctap2_1")]
+                transports: None,
+                #[cfg(feature = "with_ctap2_1")]
+                algorithms: None,
+                #[cfg(feature = "with_ctap2_1")]
+                firmware_version: None,
+            };
+            vec![
+                message(COMMAND_KEEPALIVE, vec![0x01]),
+                message(COMMAND_KEEPALIVE, vec![0x02]),
+                cbor_response(ResponseData::AuthenticatorGetInfo(get_info_response)),
+            ]
+        });
+        let get_info_response = client.get_info().unwrap();
+        assert_eq!(get_info_response.versions, vec!["FIDO_2_0".to_string()]);
+        assert_eq!(get_info_response.aaguid, [0xAA; 16]);
+        assert_eq!(get_info_response.max_msg_size, Some(1024));
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut client = ctap_client(|request| match request.payload[0] {
+            0x04 => vec![message(COMMAND_CBOR, vec![0x31])],
+            0x07 => vec![message(COMMAND_ERROR, vec![0x06])],
+            _ => vec![],
+        });
+        match client.get_info() {
+            Err(Error::Status(0x31)) => (),
+            result => panic!("Unexpected result {:?}", result.map(|_| ())),
+        }
+        match client.reset() {
+            Err(Error::Hid(0x06)) => (),
+            result => panic!("Unexpected result {:?}", result),
+        }
+        client.set_timeout(Duration::from_millis(10));
+        match client.get_next_assertion() {
+            Err(Error::Timeout) => (),
+            result => panic!("Unexpected result {:?}", result.map(|_| ())),
+        }
+    }
+
+    // Implements the authenticator side of PIN protocol 1, like the firmware.
+    struct PinAuthenticator {
+        key_agreement_key: ecdh::SecKey,
+        pin_hash: Option<Vec<u8>>,
+    }
+
+    impl PinAuthenticator {
+        const PIN_TOKEN: [u8; 32] = [0x70; 32];
+
+        fn process(&mut self, request: Message) -> Vec<Message> {
+            let params = match Command::deserialize(&request.payload) {
+                Ok(Command::AuthenticatorClientPin(params)) => params,
+                _ => panic!("Unexpected command"),
+            };
+            let shared_secret = params.key_agreement.map(|key_agreement| {
+                let pk = ecdh::PubKey::try_from(key_agreement).unwrap();
+                self.key_agreement_key.exchange_x_sha256(&pk)
+            });
+            let aes_key = shared_secret.as_ref().map(EncryptionKey::new);
+            let decrypt = |data: &[u8]| {
+                let mut blocks: Vec<[u8; 16]> = data
+                    .chunks(16)
+                    .map(|chunk| *array_ref!(chunk, 0, 16))
+                    .collect();
+                let aes_key = aes_key.as_ref().unwrap();
+                cbc_decrypt(&DecryptionKey::new(aes_key), [0; 16], &mut blocks);
+                blocks.concat()
+            };
+            let response = match params.sub_command {
+                ClientPinSubCommand::GetKeyAgreement => Some(AuthenticatorClientPinResponse {
+                    key_agreement: Some(CoseKey::from(self.key_agreement_key.genpk())),
+                    pin_token: None,
+                    retries: None,
+                }),
+                ClientPinSubCommand::SetPin => {
+                    let new_pin_enc = params.new_pin_enc.unwrap();
+                    let pin_auth = hmac_256::<Sha256>(&shared_secret.unwrap(), &new_pin_enc);
+                    if params.pin_auth.unwrap() != pin_auth[..16].to_vec() {
+                        return vec![message(COMMAND_CBOR, vec![0x33])];
+                    }
+                    let pin: Vec<u8> = decrypt(&new_pin_enc)
+                        .into_iter()
+                        .take_while(|&byte| byte != 0)
+                        .collect();
+                    self.pin_hash = Some(Sha256::hash(&pin)[..16].to_vec());
+                    None
+                }
+                ClientPinSubCommand::GetPinUvAuthTokenUsingPin => {
+                    if Some(decrypt(&params.pin_hash_enc.unwrap())) != self.pin_hash {
+                        return vec![message(COMMAND_CBOR, vec![0x31])];
+                    }
+                    let mut blocks = [[0x70; 16]; 2];
+                    cbc_encrypt(aes_key.as_ref().unwrap(), [0; 16], &mut blocks);
+                    Some(AuthenticatorClientPinResponse {
+                        key_agreement: None,
+                        pin_token: Some(blocks.concat()),
+                        retries: None,
+                    })
+                }
+                _ => panic!("Unexpected subcommand"),
+            };
+            vec![cbor_response(ResponseData::AuthenticatorClientPin(
+                response,
+            ))]
+        }
+    }
+
+    #[test]
+    fn test_pin_protocol() {
+        let mut authenticator = PinAuthenticator {
+            key_agreement_key: ecdh::SecKey::gensk(&mut ThreadRng256 {}),
+            pin_hash: None,
+        };
+        let mut client = ctap_client(move |request| authenticator.process(request));
+        client.set_pin("1234").unwrap();
+        assert_eq!(
+            client.get_pin_token("1234").unwrap(),
+            PinAuthenticator::PIN_TOKEN.to_vec()
+        );
+        match client.get_pin_token("4321") {
+            Err(Error::Status(0x31)) => (),
+            result => panic!("Unexpected result {:?}", result),
+        }
+        match client.set_pin(&"1".repeat(64)) {
+            Err(Error::InvalidArgument(_)) => (),
+            result => panic!("Unexpected result {:?}", result),
+        }
+    }
+}
@@ -0,0 +1,35 @@
+// Copyright 2019 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// The data formats are the source files of the firmware, so that clients encode and decode
+// exactly what the authenticator does.
+
+#[path = "../../../../src/ctap/command.rs"]
+pub mod command;
+#[path = "../../../../src/ctap/data_formats.rs"]
+pub mod data_formats;
+#[path = "../../../../src/ctap/response.rs"]
+pub mod response;
+#[path = "../../../../src/ctap/status_code.rs"]
+pub mod status_code;
+
+use self::data_formats::{
+    PublicKeyCredentialParameter, PublicKeyCredentialType, SignatureAlgorithm,
+};
+
+// The only algorithm the firmware supports, see ES256_CRED_PARAM in src/ctap/mod.rs.
+pub const ES256_CRED_PARAM: PublicKeyCredentialParameter = PublicKeyCredentialParameter {
+    cred_type: PublicKeyCredentialType::PublicKey,
+    alg: SignatureAlgorithm::ES256,
+};
@@ -0,0 +1,390 @@
+// Copyright 2019 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// The host side of CTAPHID, see the CTAP specification (version 20190130) section 8.1.
+
+use super::Error;
+use std::io::{ErrorKind, Read, Write};
+use std::net::TcpStream;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::time::{Duration, Instant};
+
+pub type HidPacket = [u8; 64];
+pub type ChannelID = [u8; 4];
+
+pub const CHANNEL_BROADCAST: ChannelID = [0xFF, 0xFF, 0xFF, 0xFF];
+const TYPE_INIT_BIT: u8 = 0x80;
+
+// CTAP specification (version 20190130) section 8.1.9.1
+pub const COMMAND_PING: u8 = 0x01;
+pub const COMMAND_MSG: u8 = 0x03;
+pub const COMMAND_INIT: u8 = 0x06;
+pub const COMMAND_CBOR: u8 = 0x10;
+pub const COMMAND_CANCEL: u8 = 0x11;
+pub const COMMAND_KEEPALIVE: u8 = 0x3B;
+pub const COMMAND_ERROR: u8 = 0x3F;
+// CTAP specification (version 20190130) section 8.1.9.2
+pub const COMMAND_WINK: u8 = 0x08;
+
+// CTAP specification (version 20190130) section 8.1.4
+const INIT_PAYLOAD_LENGTH: usize = 64 - 7;
+const CONT_PAYLOAD_LENGTH: usize = 64 - 5;
+const MAX_SEQUENCE_NUMBER: usize = 0x7F;
+pub const MAX_PAYLOAD_LENGTH: usize =
+    INIT_PAYLOAD_LENGTH + (MAX_SEQUENCE_NUMBER + 1) * CONT_PAYLOAD_LENGTH;
+
+#[derive(Debug, PartialEq)]
+pub struct Message {
+    pub cid: ChannelID,
+    pub cmd: u8,
+    pub payload: Vec<u8>,
+}
+
+// A connection exchanging 64-byte HID reports with an authenticator.
+pub trait HidDevice {
+    fn send_packet(&mut self, packet: &HidPacket) -> std::io::Result<()>;
+
+    // Returns None if no packet arrives before the timeout.
+    fn recv_packet(&mut self, timeout: Duration) -> std::io::Result<Option<HidPacket>>;
+}
+
+// Splits a message into an initialization packet followed by continuation packets.
+pub fn split_message(message: &Message) -> Result<Vec<HidPacket>, Error> {
+    let payload = &message.payload;
+    if payload.len() > MAX_PAYLOAD_LENGTH {
+        return Err(Error::InvalidArgument(
+            "the message is too long for CTAPHID",
+        ));
+    }
+    let mut packet = [0; 64];
+    packet[..4].copy_from_slice(&message.cid);
+    packet[4] = message.cmd | TYPE_INIT_BIT;
+    packet[5..7].copy_from_slice(&(payload.len() as u16).to_be_bytes());
+    let init_length = std::cmp::min(payload.len(), INIT_PAYLOAD_LENGTH);
+    packet[7..7 + init_length].copy_from_slice(&payload[..init_length]);
+    let mut packets = vec![packet];
+    for (seq, chunk) in payload[init_length..]
+        .chunks(CONT_PAYLOAD_LENGTH)
+        .enumerate()
+    {
+        let mut packet = [0; 64];
+        packet[..4].copy_from_slice(&message.cid);
+        packet[4] = seq as u8;
+        packet[5..5 + chunk.len()].copy_from_slice(chunk);
+        packets.push(packet);
+    }
+    Ok(packets)
+}
+
+pub fn send_message<D: HidDevice>(device: &mut D, message: &Message) -> Result<(), Error> {
+    for packet in split_message(message)? {
+        device.send_packet(&packet)?;
+    }
+    Ok(())
+}
+
+// Receives the next message on the channel. Packets of other channels are ignored, since they are
+// meant for other clients of the authenticator.
+pub fn recv_message<D: HidDevice>(
+    device: &mut D,
+    cid: ChannelID,
+    timeout: Duration,
+) -> Result<Message, Error> {
+    let deadline = Instant::now() + timeout;
+    let mut next_packet = || -> Result<HidPacket, Error> {
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(Error::Timeout);
+            }
+            match device.recv_packet(deadline - now)? {
+                Some(packet) if packet[..4] == cid => return Ok(packet),
+                _ => (),
+            }
+        }
+    };
+
+    let packet = next_packet()?;
+    if packet[4] & TYPE_INIT_BIT == 0 {
+        return Err(Error::Protocol("expected an initialization packet"));
+    }
+    let cmd = packet[4] & !TYPE_INIT_BIT;
+    let length = u16::from_be_bytes([packet[5], packet[6]]) as usize;
+    if length > MAX_PAYLOAD_LENGTH {
+        return Err(Error::Protocol("the message is too long"));
+    }
+    let mut payload = Vec::with_capacity(length);
+    let init_length = std::cmp::min(length, INIT_PAYLOAD_LENGTH);
+    payload.extend_from_slice(&packet[7..7 + init_length]);
+    let mut seq = 0;
+    while payload.len() < length {
+        let packet = next_packet()?;
+        if packet[4] != seq {
+            return Err(Error::Protocol("unexpected continuation packet"));
+        }
+        let cont_length = std::cmp::min(length - payload.len(), CONT_PAYLOAD_LENGTH);
+        payload.extend_from_slice(&packet[5..5 + cont_length]);
+        seq += 1;
+    }
+    Ok(Message { cid, cmd, payload })
+}
+
+// Reads a whole packet from a stream socket, as exchanged with the desktop build of the firmware.
+// Only the start of the packet may time out, the rest is expected to follow.
+fn recv_stream_packet<S: Read>(stream: &mut S) -> std::io::Result<Option<HidPacket>> {
+    let mut packet = [0; 64];
+    let length = match stream.read(&mut packet) {
+        Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+        Ok(length) => length,
+        Err(e)
+            if e.kind() == ErrorKind::WouldBlock
+                || e.kind() == ErrorKind::TimedOut
+                || e.kind() == ErrorKind::Interrupted =>
+        {
+            return Ok(None)
+        }
+        Err(e) => return Err(e),
+    };
+    stream.read_exact(&mut packet[length..])?;
+    Ok(Some(packet))
+}
+
+impl HidDevice for TcpStream {
+    fn send_packet(&mut self, packet: &HidPacket) -> std::io::Result<()> {
+        self.write_all(packet)
+    }
+
+    fn recv_packet(&mut self, timeout: Duration) -> std::io::Result<Option<HidPacket>> {
+        self.set_read_timeout(Some(timeout))?;
+        recv_stream_packet(self)
+    }
+}
+
+#[cfg(unix)]
+impl HidDevice for UnixStream {
+    fn send_packet(&mut self, packet: &HidPacket) -> std::io::Result<()> {
+        self.write_all(packet)
+    }
+
+    fn recv_packet(&mut self, timeout: Duration) -> std::io::Result<Option<HidPacket>> {
+        self.set_read_timeout(Some(timeout))?;
+        recv_stream_packet(self)
+    }
+}
+
+// A security key seen through the hidraw driver of Linux, like /dev/hidraw0.
+#[cfg(target_os = "linux")]
+pub struct HidrawDevice {
+    file: std::fs::File,
+}
+
+#[cfg(target_os = "linux")]
+impl HidrawDevice {
+    const O_NONBLOCK: i32 = 0o4000;
+    // How often a non-blocking read is retried while waiting for a packet.
+    const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+    pub fn open(path: &std::path::Path) -> std::io::Result<HidrawDevice> {
+        use std::os::unix::fs::OpenOptionsExt;
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(HidrawDevice::O_NONBLOCK)
+            .open(path)?;
+        Ok(HidrawDevice { file })
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl HidDevice for HidrawDevice {
+    // Output reports are prefixed with the report number, which is 0 since FIDO report
+    // descriptors define no report ID.
+    fn send_packet(&mut self, packet: &HidPacket) -> std::io::Result<()> {
+        let mut report = [0; 65];
+        report[1..].copy_from_slice(packet);
+        self.file.write_all(&report)
+    }
+
+    fn recv_packet(&mut self, timeout: Duration) -> std::io::Result<Option<HidPacket>> {
+        let deadline = Instant::now() + timeout;
+        let mut packet = [0; 64];
+        loop {
+            match self.file.read(&mut packet) {
+                Ok(64) => return Ok(Some(packet)),
+                Ok(_) => return Err(ErrorKind::InvalidData.into()),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    if Instant::now() >= deadline {
+                        return Ok(None);
+                    }
+                    std::thread::sleep(HidrawDevice::POLL_INTERVAL);
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::VecDeque;
+
+    const CID: ChannelID = [0x12, 0x34, 0x56, 0x78];
+    const TIMEOUT: Duration = Duration::from_millis(100);
+
+    // Replays packets, and records the sent ones.
+    #[derive(Default)]
+    struct QueueDevice {
+        sent: Vec<HidPacket>,
+        received: VecDeque<HidPacket>,
+    }
+
+    impl HidDevice for QueueDevice {
+        fn send_packet(&mut self, packet: &HidPacket) -> std::io::Result<()> {
+            self.sent.push(*packet);
+            Ok(())
+        }
+
+        fn recv_packet(&mut self, _timeout: Duration) -> std::io::Result<Option<HidPacket>> {
+            Ok(self.received.pop_front())
+        }
+    }
+
+    fn round_trip(payload_length: usize) -> usize {
+        let message = Message {
+            cid: CID,
+            cmd: COMMAND_CBOR,
+            payload: (0..payload_length).map(|i| i as u8).collect(),
+        };
+        let mut device = QueueDevice::default();
+        send_message(&mut device, &message).unwrap();
+        let num_packets = device.sent.len();
+        device.received = device.sent.drain(..).collect();
+        assert_eq!(recv_message(&mut device, CID, TIMEOUT).unwrap(), message);
+        num_packets
+    }
+
+    #[test]
+    fn test_split_message() {
+        let message = Message {
+            cid: CID,
+            cmd: COMMAND_PING,
+            payload: vec![0xAA; 60],
+        };
+        let packets = split_message(&message).unwrap();
+        assert_eq!(packets.len(), 2);
+        assert_eq!(&packets[0][..7], &[0x12, 0x34, 0x56, 0x78, 0x81, 0x00, 60]);
+        assert_eq!(&packets[0][7..], &[0xAA; 57][..]);
+        assert_eq!(
+            &packets[1][..8],
+            &[0x12, 0x34, 0x56, 0x78, 0x00, 0xAA, 0xAA, 0xAA]
+        );
+        assert_eq!(&packets[1][8..], &[0x00; 56][..]);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        assert_eq!(round_trip(0), 1);
+        assert_eq!(round_trip(57), 1);
+        assert_eq!(round_trip(58), 2);
+        assert_eq!(round_trip(1024), 18);
+        assert_eq!(round_trip(MAX_PAYLOAD_LENGTH), 129);
+    }
+
+    #[test]
+    fn test_message_too_long() {
+        let message = Message {
+            cid: CID,
+            cmd: COMMAND_CBOR,
+            payload: vec![0x00; MAX_PAYLOAD_LENGTH + 1],
+        };
+        match split_message(&message) {
+            Err(Error::InvalidArgument(_)) => (),
+            result => panic!(
+                "Unexpected result {:?}",
+                result.map(|packets| packets.len())
+            ),
+        }
+    }
+
+    #[test]
+    fn test_recv_ignores_other_channels() {
+        let mut device = QueueDevice::default();
+        let mut other_packet = [0x00; 64];
+        other_packet[..7].copy_from_slice(&[0xAB, 0xCD, 0xEF, 0x01, 0x90, 0x00, 0x01]);
+        let mut packet = other_packet;
+        packet[..4].copy_from_slice(&CID);
+        device.received.push_back(other_packet);
+        device.received.push_back(packet);
+        let message = recv_message(&mut device, CID, TIMEOUT).unwrap();
+        assert_eq!(
+            message,
+            Message {
+                cid: CID,
+                cmd: COMMAND_CBOR,
+                payload: vec![0x00],
+            }
+        );
+    }
+
+    #[test]
+    fn test_recv_wrong_sequence() {
+        let message = Message {
+            cid: CID,
+            cmd: COMMAND_CBOR,
+            payload: vec![0x00; 200],
+        };
+        let mut device = QueueDevice::default();
+        device.received = split_message(&message).unwrap().into_iter().collect();
+        device.received.remove(1);
+        match recv_message(&mut device, CID, TIMEOUT) {
+            Err(Error::Protocol(_)) => (),
+            result => panic!("Unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_recv_timeout() {
+        let mut device = QueueDevice::default();
+        match recv_message(&mut device, CID, Duration::from_millis(1)) {
+            Err(Error::Timeout) => (),
+            result => panic!("Unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_stream_packets() {
+        let (mut host, mut device) = UnixStream::pair().unwrap();
+        // Packets may be split by the stream.
+        device.write_all(&[0x42; 20]).unwrap();
+        device.write_all(&[0x42; 44]).unwrap();
+        assert_eq!(
+            host.recv_packet(TIMEOUT)
+                .unwrap()
+                .map(|packet| packet.to_vec()),
+            Some(vec![0x42; 64])
+        );
+        assert!(host
+            .recv_packet(Duration::from_millis(1))
+            .unwrap()
+            .is_none());
+        host.send_packet(&[0x24; 64]).unwrap();
+        let mut packet = [0; 64];
+        device.read_exact(&mut packet).unwrap();
+        assert_eq!(&packet[..], &[0x24; 64][..]);
+    }
+}
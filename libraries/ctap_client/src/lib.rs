@@ -0,0 +1,79 @@
+// Copyright 2019 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// A host-side CTAP2 client, for test tools and provisioning scripts. It talks to an authenticator
+// through CTAPHID, and uses the CTAP data formats of the firmware.
+
+#[macro_use]
+extern crate alloc;
+#[macro_use]
+extern crate arrayref;
+#[macro_use]
+extern crate cbor;
+extern crate crypto;
+
+mod client;
+pub mod ctap;
+pub mod hid;
+pub mod pin;
+
+pub use self::client::CtapClient;
+use self::ctap::status_code::Ctap2StatusCode;
+use std::fmt;
+
+#[derive(Debug)]
+pub enum Error {
+    // The connection to the authenticator failed.
+    Io(std::io::Error),
+    // The authenticator didn't answer in time.
+    Timeout,
+    // The authenticator answered with a CTAPHID_ERROR and this error code.
+    Hid(u8),
+    // The authenticator answered with this CTAP status code, which is not CTAP2_OK.
+    Status(u8),
+    // A command or a response doesn't follow the CTAP data formats.
+    Format(Ctap2StatusCode),
+    // The authenticator doesn't follow the CTAPHID protocol.
+    Protocol(&'static str),
+    // The request can't be sent, for example because a message or a PIN is too long.
+    InvalidArgument(&'static str),
+}
+
+impl From<std::io::Error> for Error {
+    fn from(error: std::io::Error) -> Self {
+        Error::Io(error)
+    }
+}
+
+impl From<Ctap2StatusCode> for Error {
+    fn from(status_code: Ctap2StatusCode) -> Self {
+        Error::Format(status_code)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(error) => write!(f, "connection error: {}", error),
+            Error::Timeout => write!(f, "the authenticator did not answer in time"),
+            Error::Hid(code) => write!(f, "CTAPHID error 0x{:02X}", code),
+            Error::Status(code) => write!(f, "CTAP status 0x{:02X}", code),
+            Error::Format(status_code) => write!(f, "invalid CTAP data: {:?}", status_code),
+            Error::Protocol(reason) => write!(f, "CTAPHID protocol violation: {}", reason),
+            Error::InvalidArgument(reason) => write!(f, "invalid argument: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
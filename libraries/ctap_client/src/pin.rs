@@ -0,0 +1,184 @@
+// Copyright 2019 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// The platform side of PIN protocol 1, see the CTAP specification (version 20190130) section 5.5.
+
+use super::ctap::data_formats::CoseKey;
+use super::ctap::status_code::Ctap2StatusCode;
+use core::convert::TryFrom;
+use crypto::aes256::{DecryptionKey, EncryptionKey};
+use crypto::cbc::{cbc_decrypt, cbc_encrypt};
+use crypto::hmac::hmac_256;
+use crypto::rng256::Rng256;
+use crypto::sha256::Sha256;
+use crypto::{ecdh, Hash256};
+
+pub const PIN_PROTOCOL_VERSION: u64 = 1;
+// Same lengths as in the firmware.
+const PIN_AUTH_LENGTH: usize = 16;
+const PIN_PADDED_LENGTH: usize = 64;
+
+// Truncated HMAC, used for pinAuth.
+fn authenticate(key: &[u8], message: &[u8]) -> Vec<u8> {
+    hmac_256::<Sha256>(key, message)[..PIN_AUTH_LENGTH].to_vec()
+}
+
+// Computes the pinAuth of MakeCredential and GetAssertion, from the pinToken returned by the
+// authenticator.
+pub fn authenticate_client_data_hash(pin_token: &[u8], client_data_hash: &[u8]) -> Vec<u8> {
+    authenticate(pin_token, client_data_hash)
+}
+
+// The secret agreed with the key agreement key of the authenticator.
+pub struct SharedSecret {
+    platform_key: CoseKey,
+    secret: [u8; 32],
+}
+
+impl SharedSecret {
+    // The platform key is generated for this agreement only.
+    pub fn new<R: Rng256>(
+        rng: &mut R,
+        authenticator_key: CoseKey,
+    ) -> Result<SharedSecret, Ctap2StatusCode> {
+        let authenticator_key = ecdh::PubKey::try_from(authenticator_key)?;
+        let sk = ecdh::SecKey::gensk(rng);
+        Ok(SharedSecret {
+            platform_key: CoseKey::from(sk.genpk()),
+            secret: sk.exchange_x_sha256(&authenticator_key),
+        })
+    }
+
+    // The keyAgreement parameter, for the authenticator to compute the same secret.
+    pub fn platform_key(&self) -> CoseKey {
+        CoseKey(self.platform_key.0.clone())
+    }
+
+    // AES-256-CBC with a zero IV. Returns None if the data is not made of whole blocks.
+    fn cbc(&self, data: &[u8], encrypt: bool) -> Option<Vec<u8>> {
+        if data.len() % 16 != 0 {
+            return None;
+        }
+        let mut blocks: Vec<[u8; 16]> = data
+            .chunks(16)
+            .map(|chunk| *array_ref!(chunk, 0, 16))
+            .collect();
+        let aes_enc_key = EncryptionKey::new(&self.secret);
+        if encrypt {
+            cbc_encrypt(&aes_enc_key, [0; 16], &mut blocks);
+        } else {
+            cbc_decrypt(&DecryptionKey::new(&aes_enc_key), [0; 16], &mut blocks);
+        }
+        Some(blocks.concat())
+    }
+
+    pub fn authenticate(&self, message: &[u8]) -> Vec<u8> {
+        authenticate(&self.secret, message)
+    }
+
+    // The newPinEnc parameter. Returns None if the PIN doesn't fit in the padded length.
+    pub fn encrypt_new_pin(&self, pin: &str) -> Option<Vec<u8>> {
+        if pin.len() >= PIN_PADDED_LENGTH {
+            return None;
+        }
+        let mut padded_pin = pin.as_bytes().to_vec();
+        padded_pin.resize(PIN_PADDED_LENGTH, 0);
+        self.cbc(&padded_pin, true)
+    }
+
+    // The pinHashEnc parameter.
+    pub fn encrypt_pin_hash(&self, pin: &str) -> Vec<u8> {
+        let pin_hash = Sha256::hash(pin.as_bytes());
+        // A single block is always valid.
+        self.cbc(&pin_hash[..16], true).unwrap()
+    }
+
+    // Returns None if the encrypted token is not made of whole blocks.
+    pub fn decrypt_pin_token(&self, pin_token_enc: &[u8]) -> Option<Vec<u8>> {
+        self.cbc(pin_token_enc, false)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crypto::rng256::ThreadRng256;
+
+    // Returns the shared secret and the secret computed by an authenticator.
+    fn agree() -> (SharedSecret, SharedSecret) {
+        let mut rng = ThreadRng256 {};
+        let authenticator_sk = ecdh::SecKey::gensk(&mut rng);
+        let shared_secret =
+            SharedSecret::new(&mut rng, CoseKey::from(authenticator_sk.genpk())).unwrap();
+        let platform_key = ecdh::PubKey::try_from(shared_secret.platform_key()).unwrap();
+        let authenticator_secret = SharedSecret {
+            platform_key: CoseKey::from(authenticator_sk.genpk()),
+            secret: authenticator_sk.exchange_x_sha256(&platform_key),
+        };
+        (shared_secret, authenticator_secret)
+    }
+
+    #[test]
+    fn test_key_agreement() {
+        let (shared_secret, authenticator_secret) = agree();
+        assert_eq!(shared_secret.secret, authenticator_secret.secret);
+        assert_eq!(
+            SharedSecret::new(&mut ThreadRng256 {}, CoseKey(Default::default())).err(),
+            Some(Ctap2StatusCode::CTAP2_ERR_MISSING_PARAMETER)
+        );
+    }
+
+    #[test]
+    fn test_encrypt_new_pin() {
+        let (shared_secret, authenticator_secret) = agree();
+        let new_pin_enc = shared_secret.encrypt_new_pin("1234").unwrap();
+        assert_eq!(new_pin_enc.len(), PIN_PADDED_LENGTH);
+        let padded_pin = authenticator_secret.cbc(&new_pin_enc, false).unwrap();
+        assert_eq!(&padded_pin[..4], b"1234");
+        assert_eq!(&padded_pin[4..], &[0; 60][..]);
+        assert!(shared_secret.encrypt_new_pin(&"1".repeat(64)).is_none());
+    }
+
+    #[test]
+    fn test_encrypt_pin_hash() {
+        let (shared_secret, authenticator_secret) = agree();
+        let pin_hash_enc = shared_secret.encrypt_pin_hash("1234");
+        let pin_hash = authenticator_secret.cbc(&pin_hash_enc, false).unwrap();
+        assert_eq!(pin_hash, Sha256::hash(b"1234")[..16].to_vec());
+    }
+
+    #[test]
+    fn test_decrypt_pin_token() {
+        let (shared_secret, authenticator_secret) = agree();
+        let pin_token = vec![0x70; 32];
+        let pin_token_enc = authenticator_secret.cbc(&pin_token, true).unwrap();
+        assert_eq!(
+            shared_secret.decrypt_pin_token(&pin_token_enc),
+            Some(pin_token)
+        );
+        assert_eq!(shared_secret.decrypt_pin_token(&[0x00; 17]), None);
+    }
+
+    #[test]
+    fn test_authenticate() {
+        let (shared_secret, authenticator_secret) = agree();
+        let pin_auth = shared_secret.authenticate(b"message");
+        assert_eq!(pin_auth.len(), PIN_AUTH_LENGTH);
+        assert_eq!(pin_auth, authenticator_secret.authenticate(b"message"));
+        assert_eq!(
+            authenticate_client_data_hash(&[0x70; 32], &[0xCD; 32]),
+            hmac_256::<Sha256>(&[0x70; 32], &[0xCD; 32])[..16].to_vec()
+        );
+    }
+}
@@ -23,6 +23,9 @@ cd ../..
 cd libraries/crypto
 cargo fmt --all -- --check
 cd ../..
+cd libraries/ctap_client
+cargo fmt --all -- --check
+cd ../..
 
 echo "Building sha256sum tool..."
 cargo build --manifest-path third_party/tock/tools/sha256sum/Cargo.toml
@@ -35,6 +38,7 @@ cargo check --release --target=thumbv7em-none-eabi --features debug_ctap
 cargo check --release --target=thumbv7em-none-eabi --features panic_console
 cargo check --release --target=thumbv7em-none-eabi --features debug_allocations
 cargo check --release --target=thumbv7em-none-eabi --features ram_storage
+cargo check --release --target=thumbv7em-none-eabi --no-default-features --features with_ctap1
 cargo check --release --target=thumbv7em-none-eabi --features verbose
 cargo check --release --target=thumbv7em-none-eabi --features debug_ctap,with_ctap1
 cargo check --release --target=thumbv7em-none-eabi --features debug_ctap,with_ctap1,panic_console,debug_allocations,verbose
@@ -99,4 +103,16 @@ then
 
   echo "Running unit tests on the desktop (debug mode + CTAP1 + CTAP2.1)..."
   cargo test --features std,with_ctap1,with_ctap2_1
+
+  echo "Running unit tests of the desktop virtual authenticator..."
+  cargo test --features desktop,with_ctap1
+  cargo test --features desktop,with_ctap1,with_nfc
+  cargo test --features desktop,with_ccid
+  cargo test --features desktop,with_ble
+
+  echo "Running unit tests of the CTAP client..."
+  cd libraries/ctap_client
+  cargo test
+  cargo test --features with_ctap2_1
+  cd ../..
 fi
@@ -0,0 +1,615 @@
+// Copyright 2019 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use alloc::vec::Vec;
+use core::convert::Into;
+use core::convert::TryFrom;
+
+// The specification referenced in this file is ISO/IEC 7816-4:2013.
+
+// Status words, ISO 7816-4 section 5.6.
+// Status words with a variable second byte, like 61XX, are built with the functions below.
+#[allow(non_camel_case_types)]
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+#[cfg_attr(any(test, feature = "debug_ctap"), derive(Debug, PartialEq))]
+pub enum ApduStatusCode {
+    SW_NO_ERROR = 0x9000,
+    SW_WRONG_LENGTH = 0x6700,
+    SW_LOGICAL_CHANNEL_NOT_SUPPORTED = 0x6881,
+    SW_SECURE_MESSAGING_NOT_SUPPORTED = 0x6882,
+    SW_LAST_COMMAND_EXPECTED = 0x6883,
+    SW_CHAINING_NOT_SUPPORTED = 0x6884,
+    SW_CONDITIONS_NOT_SATISFIED = 0x6985,
+    SW_WRONG_DATA = 0x6A80,
+    SW_FILE_NOT_FOUND = 0x6A82,
+    SW_INCORRECT_P1P2 = 0x6A86,
+    SW_INS_NOT_SUPPORTED = 0x6D00,
+    SW_CLA_NOT_SUPPORTED = 0x6E00,
+    SW_UNKNOWN = 0x6F00,
+}
+
+impl TryFrom<u16> for ApduStatusCode {
+    type Error = ();
+
+    fn try_from(value: u16) -> Result<ApduStatusCode, ()> {
+        match value {
+            0x9000 => Ok(ApduStatusCode::SW_NO_ERROR),
+            0x6700 => Ok(ApduStatusCode::SW_WRONG_LENGTH),
+            0x6881 => Ok(ApduStatusCode::SW_LOGICAL_CHANNEL_NOT_SUPPORTED),
+            0x6882 => Ok(ApduStatusCode::SW_SECURE_MESSAGING_NOT_SUPPORTED),
+            0x6883 => Ok(ApduStatusCode::SW_LAST_COMMAND_EXPECTED),
+            0x6884 => Ok(ApduStatusCode::SW_CHAINING_NOT_SUPPORTED),
+            0x6985 => Ok(ApduStatusCode::SW_CONDITIONS_NOT_SATISFIED),
+            0x6A80 => Ok(ApduStatusCode::SW_WRONG_DATA),
+            0x6A82 => Ok(ApduStatusCode::SW_FILE_NOT_FOUND),
+            0x6A86 => Ok(ApduStatusCode::SW_INCORRECT_P1P2),
+            0x6D00 => Ok(ApduStatusCode::SW_INS_NOT_SUPPORTED),
+            0x6E00 => Ok(ApduStatusCode::SW_CLA_NOT_SUPPORTED),
+            0x6F00 => Ok(ApduStatusCode::SW_UNKNOWN),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Into<u16> for ApduStatusCode {
+    fn into(self) -> u16 {
+        self as u16
+    }
+}
+
+// Builds the status word 61XX, telling the host that it can fetch the remaining bytes of a
+// response with GET RESPONSE. SW2 is the number of available bytes, or 0 for 256 bytes or more.
+#[cfg(any(feature = "with_nfc", feature = "with_ccid"))]
+pub fn bytes_remaining_status(remaining: usize) -> u16 {
+    if remaining >= 0x100 {
+        0x6100
+    } else {
+        0x6100 | remaining as u16
+    }
+}
+
+// A command APDU, ISO 7816-4 section 5.1.
+// Both the short and the extended length encodings are accepted. The CTAPHID flavour of U2F
+// messages (section 3.1.2 of the U2F raw message format specification) is an extended APDU.
+#[derive(Clone)]
+#[cfg_attr(any(test, feature = "debug_ctap"), derive(Debug, PartialEq))]
+pub struct ApduCommand {
+    pub cla: u8,
+    pub ins: u8,
+    pub p1: u8,
+    pub p2: u8,
+    // The command data field, whose length is Lc.
+    pub data: Vec<u8>,
+    // The maximum number of bytes expected in the response, or 0 if Le is absent.
+    // An Le field of zero encodes 256 bytes for short APDUs, and 65536 bytes for extended APDUs.
+    pub ne: usize,
+}
+
+impl ApduCommand {
+    // Command chaining bit of the class byte, ISO 7816-4 section 5.4.1.
+    #[cfg(any(feature = "with_nfc", feature = "with_ccid"))]
+    pub const CLA_CHAINING_BIT: u8 = 0x10;
+
+    const HEADER_LEN: usize = 4;
+    const MAX_SHORT_NE: usize = 0x100;
+    const MAX_EXTENDED_NE: usize = 0x10000;
+
+    fn new(header: &[u8], data: &[u8], ne: usize) -> ApduCommand {
+        ApduCommand {
+            cla: header[0],
+            ins: header[1],
+            p1: header[2],
+            p2: header[3],
+            data: data.to_vec(),
+            ne,
+        }
+    }
+
+    // Whether more commands of the same chain follow this one.
+    #[cfg(any(feature = "with_nfc", feature = "with_ccid"))]
+    pub fn is_chained(&self) -> bool {
+        self.cla & ApduCommand::CLA_CHAINING_BIT != 0
+    }
+}
+
+impl TryFrom<&[u8]> for ApduCommand {
+    type Error = ApduStatusCode;
+
+    // The body following the 4 byte header is one of the following, where Lc is the length of the
+    // data field. Length fields are big-endian, and extended length fields start with a zero byte.
+    // - case 1: empty
+    // - case 2S: Le (1B)
+    // - case 3S: Lc (1B) | data
+    // - case 4S: Lc (1B) | data | Le (1B)
+    // - case 2E: 0x00 | Le (2B)
+    // - case 3E: 0x00 | Lc (2B) | data
+    // - case 4E: 0x00 | Lc (2B) | data | Le (2B)
+    fn try_from(apdu: &[u8]) -> Result<Self, ApduStatusCode> {
+        if apdu.len() < ApduCommand::HEADER_LEN {
+            return Err(ApduStatusCode::SW_WRONG_LENGTH);
+        }
+        let (header, body) = apdu.split_at(ApduCommand::HEADER_LEN);
+        let short_ne = |le: u8| {
+            if le == 0 {
+                ApduCommand::MAX_SHORT_NE
+            } else {
+                le as usize
+            }
+        };
+        let extended_ne = |le: &[u8]| match (le[0] as usize) << 8 | le[1] as usize {
+            0 => ApduCommand::MAX_EXTENDED_NE,
+            ne => ne,
+        };
+
+        let (data, ne) = match body.len() {
+            0 => (&body[..0], 0),
+            1 => (&body[..0], short_ne(body[0])),
+            _ if body[0] != 0 => {
+                let lc = body[0] as usize;
+                if body.len() == 1 + lc {
+                    (&body[1..], 0)
+                } else if body.len() == 2 + lc {
+                    (&body[1..1 + lc], short_ne(body[1 + lc]))
+                } else {
+                    return Err(ApduStatusCode::SW_WRONG_LENGTH);
+                }
+            }
+            2 => return Err(ApduStatusCode::SW_WRONG_LENGTH),
+            3 => (&body[..0], extended_ne(&body[1..3])),
+            _ => {
+                let lc = (body[1] as usize) << 8 | body[2] as usize;
+                if lc == 0 {
+                    // The CTAPHID flavour of U2F always encodes Lc, even without data. This is
+                    // not a valid ISO 7816-4 encoding, but some clients send it.
+                    if body.len() == 5 {
+                        return Ok(ApduCommand::new(header, &[], extended_ne(&body[3..])));
+                    }
+                    return Err(ApduStatusCode::SW_WRONG_LENGTH);
+                }
+                if body.len() == 3 + lc {
+                    (&body[3..], 0)
+                } else if body.len() == 5 + lc {
+                    (&body[3..3 + lc], extended_ne(&body[3 + lc..]))
+                } else {
+                    return Err(ApduStatusCode::SW_WRONG_LENGTH);
+                }
+            }
+        };
+
+        Ok(ApduCommand::new(header, data, ne))
+    }
+}
+
+// A response APDU, ISO 7816-4 section 5.1, before it is split to fit the expected length.
+#[cfg(any(feature = "with_nfc", feature = "with_ccid"))]
+#[cfg_attr(any(test, feature = "debug_ctap"), derive(Debug, PartialEq))]
+pub struct ApduResponse {
+    pub data: Vec<u8>,
+    pub status: u16,
+}
+
+#[cfg(any(feature = "with_nfc", feature = "with_ccid"))]
+impl ApduResponse {
+    pub fn new(data: Vec<u8>, status: u16) -> ApduResponse {
+        ApduResponse { data, status }
+    }
+
+    pub fn success(data: Vec<u8>) -> ApduResponse {
+        ApduResponse::new(data, ApduStatusCode::SW_NO_ERROR.into())
+    }
+
+    pub fn error(status: impl Into<u16>) -> ApduResponse {
+        ApduResponse::new(Vec::new(), status.into())
+    }
+
+    // Serializes the response as the data field followed by the 2 bytes of status word.
+    pub fn into_bytes(self) -> Vec<u8> {
+        let mut bytes = self.data;
+        bytes.extend_from_slice(&self.status.to_be_bytes());
+        bytes
+    }
+}
+
+// Handles command chaining and response chaining for a sequence of APDUs coming from one host.
+//
+// Chained commands (ISO 7816-4 section 5.4.1) are acknowledged one by one, and their data fields
+// are concatenated before the whole command is passed to the handler.
+// Responses longer than the expected length are split, and the host retrieves the remaining bytes
+// with GET RESPONSE commands (ISO 7816-4 section 7.6.1), following the status word 61XX.
+#[cfg(any(feature = "with_nfc", feature = "with_ccid"))]
+pub struct ApduSession {
+    // The command being assembled from a chain, if any.
+    chained_command: Option<ApduCommand>,
+    // The part of the last response that the host did not fetch yet, and its final status word.
+    pending_response: Option<ApduResponse>,
+}
+
+#[cfg(any(feature = "with_nfc", feature = "with_ccid"))]
+impl ApduSession {
+    const INS_GET_RESPONSE: u8 = 0xC0;
+    // The data of chained commands is limited to the largest CTAPHID message.
+    const MAX_CHAINED_DATA_LEN: usize = 7609;
+
+    pub fn new() -> ApduSession {
+        ApduSession {
+            chained_command: None,
+            pending_response: None,
+        }
+    }
+
+    // Resets the state, for example when the host deselects the application.
+    pub fn reset(&mut self) {
+        self.chained_command = None;
+        self.pending_response = None;
+    }
+
+    // Processes an incoming APDU and returns the response APDU to send back.
+    // Complete commands are passed to the handler, while chaining is handled here.
+    pub fn process_apdu<F>(&mut self, apdu: &[u8], handler: F) -> Vec<u8>
+    where
+        F: FnOnce(ApduCommand) -> ApduResponse,
+    {
+        let command = match ApduCommand::try_from(apdu) {
+            Ok(command) => command,
+            Err(status) => {
+                self.reset();
+                return ApduResponse::error(status).into_bytes();
+            }
+        };
+
+        if command.ins == ApduSession::INS_GET_RESPONSE && self.chained_command.is_none() {
+            return self.get_response(&command);
+        }
+        // Any other command drops the part of the previous response that was not fetched.
+        self.pending_response = None;
+
+        let command = match self.append_to_chain(command) {
+            Ok(Some(command)) => command,
+            Ok(None) => return ApduResponse::success(Vec::new()).into_bytes(),
+            Err(status) => return ApduResponse::error(status).into_bytes(),
+        };
+        let ne = if command.ne == 0 {
+            ApduCommand::MAX_SHORT_NE
+        } else {
+            command.ne
+        };
+        let response = handler(command);
+        self.split_response(response, ne)
+    }
+
+    // Returns the command once its chain is complete.
+    fn append_to_chain(
+        &mut self,
+        command: ApduCommand,
+    ) -> Result<Option<ApduCommand>, ApduStatusCode> {
+        let mut chain = match self.chained_command.take() {
+            None => command,
+            Some(mut chain) => {
+                // All commands of a chain share the same header, except for the chaining bit.
+                if (chain.cla | ApduCommand::CLA_CHAINING_BIT)
+                    != (command.cla | ApduCommand::CLA_CHAINING_BIT)
+                    || chain.ins != command.ins
+                    || chain.p1 != command.p1
+                    || chain.p2 != command.p2
+                {
+                    return Err(ApduStatusCode::SW_LAST_COMMAND_EXPECTED);
+                }
+                if chain.data.len() + command.data.len() > ApduSession::MAX_CHAINED_DATA_LEN {
+                    return Err(ApduStatusCode::SW_WRONG_LENGTH);
+                }
+                chain.data.extend_from_slice(&command.data);
+                // The expected length of the last command applies to the whole chain.
+                chain.cla = command.cla;
+                chain.ne = command.ne;
+                chain
+            }
+        };
+        if chain.is_chained() {
+            self.chained_command = Some(chain);
+            Ok(None)
+        } else {
+            chain.cla &= !ApduCommand::CLA_CHAINING_BIT;
+            Ok(Some(chain))
+        }
+    }
+
+    fn get_response(&mut self, command: &ApduCommand) -> Vec<u8> {
+        if command.p1 != 0 || command.p2 != 0 {
+            return ApduResponse::error(ApduStatusCode::SW_INCORRECT_P1P2).into_bytes();
+        }
+        match self.pending_response.take() {
+            Some(response) => {
+                let ne = if command.ne == 0 {
+                    ApduCommand::MAX_SHORT_NE
+                } else {
+                    command.ne
+                };
+                self.split_response(response, ne)
+            }
+            None => ApduResponse::error(ApduStatusCode::SW_CONDITIONS_NOT_SATISFIED).into_bytes(),
+        }
+    }
+
+    // Returns the first ne bytes of the response, and keeps the rest for GET RESPONSE.
+    fn split_response(&mut self, mut response: ApduResponse, ne: usize) -> Vec<u8> {
+        if response.data.len() <= ne {
+            return response.into_bytes();
+        }
+        let remaining = response.data.split_off(ne);
+        let status = bytes_remaining_status(remaining.len());
+        let first = ApduResponse::new(response.data, status);
+        self.pending_response = Some(ApduResponse::new(remaining, response.status));
+        first.into_bytes()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(apdu: &[u8]) -> Result<ApduCommand, ApduStatusCode> {
+        ApduCommand::try_from(apdu)
+    }
+
+    fn expected_command(data: Vec<u8>, ne: usize) -> ApduCommand {
+        ApduCommand {
+            cla: 0x00,
+            ins: 0x01,
+            p1: 0x02,
+            p2: 0x03,
+            data,
+            ne,
+        }
+    }
+
+    #[test]
+    fn test_parse_short_apdu() {
+        // Case 1.
+        assert_eq!(
+            parse(&[0x00, 0x01, 0x02, 0x03]),
+            Ok(expected_command(vec![], 0))
+        );
+        // Case 2S.
+        assert_eq!(
+            parse(&[0x00, 0x01, 0x02, 0x03, 0x10]),
+            Ok(expected_command(vec![], 0x10))
+        );
+        assert_eq!(
+            parse(&[0x00, 0x01, 0x02, 0x03, 0x00]),
+            Ok(expected_command(vec![], 256))
+        );
+        // Case 3S.
+        assert_eq!(
+            parse(&[0x00, 0x01, 0x02, 0x03, 0x02, 0xAA, 0xBB]),
+            Ok(expected_command(vec![0xAA, 0xBB], 0))
+        );
+        // Case 4S.
+        assert_eq!(
+            parse(&[0x00, 0x01, 0x02, 0x03, 0x02, 0xAA, 0xBB, 0x00]),
+            Ok(expected_command(vec![0xAA, 0xBB], 256))
+        );
+    }
+
+    #[test]
+    fn test_parse_extended_apdu() {
+        // Case 2E.
+        assert_eq!(
+            parse(&[0x00, 0x01, 0x02, 0x03, 0x00, 0x01, 0x00]),
+            Ok(expected_command(vec![], 256))
+        );
+        assert_eq!(
+            parse(&[0x00, 0x01, 0x02, 0x03, 0x00, 0x00, 0x00]),
+            Ok(expected_command(vec![], 65536))
+        );
+        // The CTAPHID flavour encodes an empty Lc before Le.
+        assert_eq!(
+            parse(&[0x00, 0x01, 0x02, 0x03, 0x00, 0x00, 0x00, 0x01, 0x00]),
+            Ok(expected_command(vec![], 256))
+        );
+        // Case 3E.
+        let mut apdu = vec![0x00, 0x01, 0x02, 0x03, 0x00, 0x01, 0x2C];
+        apdu.extend_from_slice(&[0x55; 300]);
+        assert_eq!(parse(&apdu), Ok(expected_command(vec![0x55; 300], 0)));
+        // Case 4E.
+        apdu.extend(&[0x01, 0x00]);
+        assert_eq!(parse(&apdu), Ok(expected_command(vec![0x55; 300], 256)));
+    }
+
+    #[test]
+    fn test_parse_wrong_length() {
+        assert_eq!(
+            parse(&[0x00, 0x01, 0x02]),
+            Err(ApduStatusCode::SW_WRONG_LENGTH)
+        );
+        // Short Lc of zero.
+        assert_eq!(
+            parse(&[0x00, 0x01, 0x02, 0x03, 0x00, 0xAA]),
+            Err(ApduStatusCode::SW_WRONG_LENGTH)
+        );
+        // Lc is larger than the data.
+        assert_eq!(
+            parse(&[0x00, 0x01, 0x02, 0x03, 0x03, 0xAA, 0xBB]),
+            Err(ApduStatusCode::SW_WRONG_LENGTH)
+        );
+        // Trailing bytes after Le.
+        assert_eq!(
+            parse(&[0x00, 0x01, 0x02, 0x03, 0x01, 0xAA, 0x00, 0x00]),
+            Err(ApduStatusCode::SW_WRONG_LENGTH)
+        );
+        // Extended Lc of zero.
+        assert_eq!(
+            parse(&[0x00, 0x01, 0x02, 0x03, 0x00, 0x00, 0x00, 0x00]),
+            Err(ApduStatusCode::SW_WRONG_LENGTH)
+        );
+        // Extended Le of a single byte.
+        assert_eq!(
+            parse(&[0x00, 0x01, 0x02, 0x03, 0x00, 0x00, 0x01, 0xAA, 0x00]),
+            Err(ApduStatusCode::SW_WRONG_LENGTH)
+        );
+    }
+
+    #[test]
+    #[cfg(any(feature = "with_nfc", feature = "with_ccid"))]
+    fn test_status_words() {
+        assert_eq!(bytes_remaining_status(0x12), 0x6112);
+        assert_eq!(bytes_remaining_status(0x100), 0x6100);
+        assert_eq!(bytes_remaining_status(0x1000), 0x6100);
+        assert_eq!(
+            ApduResponse::error(ApduStatusCode::SW_FILE_NOT_FOUND).into_bytes(),
+            vec![0x6A, 0x82]
+        );
+        assert_eq!(
+            ApduResponse::success(vec![0x01, 0x02]).into_bytes(),
+            vec![0x01, 0x02, 0x90, 0x00]
+        );
+    }
+
+    #[test]
+    #[cfg(any(feature = "with_nfc", feature = "with_ccid"))]
+    fn test_process_simple_command() {
+        let mut session = ApduSession::new();
+        let response = session.process_apdu(&[0x00, 0x01, 0x02, 0x03, 0x01, 0xAA], |command| {
+            assert_eq!(command, expected_command(vec![0xAA], 0));
+            ApduResponse::success(vec![0xBB])
+        });
+        assert_eq!(response, vec![0xBB, 0x90, 0x00]);
+    }
+
+    #[test]
+    #[cfg(any(feature = "with_nfc", feature = "with_ccid"))]
+    fn test_process_chained_command() {
+        let mut session = ApduSession::new();
+        let unexpected_handler = |_| panic!("Unexpected call to the handler");
+        let response =
+            session.process_apdu(&[0x10, 0x01, 0x02, 0x03, 0x01, 0xAA], unexpected_handler);
+        assert_eq!(response, vec![0x90, 0x00]);
+        let response =
+            session.process_apdu(&[0x10, 0x01, 0x02, 0x03, 0x01, 0xBB], unexpected_handler);
+        assert_eq!(response, vec![0x90, 0x00]);
+        let response =
+            session.process_apdu(&[0x00, 0x01, 0x02, 0x03, 0x01, 0xCC, 0x05], |command| {
+                assert_eq!(command, expected_command(vec![0xAA, 0xBB, 0xCC], 5));
+                ApduResponse::success(vec![])
+            });
+        assert_eq!(response, vec![0x90, 0x00]);
+    }
+
+    #[test]
+    #[cfg(any(feature = "with_nfc", feature = "with_ccid"))]
+    fn test_process_chained_command_wrong_header() {
+        let mut session = ApduSession::new();
+        let unexpected_handler = |_| panic!("Unexpected call to the handler");
+        session.process_apdu(&[0x10, 0x01, 0x02, 0x03, 0x01, 0xAA], unexpected_handler);
+        let response =
+            session.process_apdu(&[0x00, 0x02, 0x02, 0x03, 0x01, 0xBB], unexpected_handler);
+        assert_eq!(response, vec![0x68, 0x83]);
+
+        // The chain was dropped, so the next command is processed on its own.
+        let response = session.process_apdu(&[0x00, 0x01, 0x02, 0x03, 0x01, 0xBB], |command| {
+            assert_eq!(command.data, vec![0xBB]);
+            ApduResponse::success(vec![])
+        });
+        assert_eq!(response, vec![0x90, 0x00]);
+    }
+
+    #[test]
+    #[cfg(any(feature = "with_nfc", feature = "with_ccid"))]
+    fn test_process_chained_command_too_long() {
+        let mut session = ApduSession::new();
+        let unexpected_handler = |_| panic!("Unexpected call to the handler");
+        let mut apdu = vec![0x10, 0x01, 0x02, 0x03, 0xFF];
+        apdu.extend_from_slice(&[0x55; 0xFF]);
+        for _ in 0..ApduSession::MAX_CHAINED_DATA_LEN / 0xFF {
+            let response = session.process_apdu(&apdu, unexpected_handler);
+            assert_eq!(response, vec![0x90, 0x00]);
+        }
+        let response = session.process_apdu(&apdu, unexpected_handler);
+        assert_eq!(response, vec![0x67, 0x00]);
+    }
+
+    #[test]
+    #[cfg(any(feature = "with_nfc", feature = "with_ccid"))]
+    fn test_process_response_chaining() {
+        let mut session = ApduSession::new();
+        let data: Vec<u8> = (0..600).map(|i| i as u8).collect();
+        // Le is absent, so the response is split in chunks of 256 bytes.
+        let response = session.process_apdu(&[0x00, 0x01, 0x02, 0x03], |_| {
+            ApduResponse::success(data.clone())
+        });
+        let mut expected = data[..256].to_vec();
+        expected.extend(&[0x61, 0x00]);
+        assert_eq!(response, expected);
+
+        // GET RESPONSE with a smaller Le.
+        let unexpected_handler = |_| panic!("Unexpected call to the handler");
+        let response = session.process_apdu(&[0x00, 0xC0, 0x00, 0x00, 0x10], unexpected_handler);
+        let mut expected = data[256..272].to_vec();
+        expected.extend(&[0x61, 0x00]);
+        assert_eq!(response, expected);
+
+        let response = session.process_apdu(&[0x00, 0xC0, 0x00, 0x00, 0x00], unexpected_handler);
+        let mut expected = data[272..528].to_vec();
+        expected.extend(&[0x61, 72]);
+        assert_eq!(response, expected);
+
+        let response = session.process_apdu(&[0x00, 0xC0, 0x00, 0x00, 0x00], unexpected_handler);
+        let mut expected = data[528..].to_vec();
+        expected.extend(&[0x90, 0x00]);
+        assert_eq!(response, expected);
+
+        // Nothing is left to fetch.
+        let response = session.process_apdu(&[0x00, 0xC0, 0x00, 0x00, 0x00], unexpected_handler);
+        assert_eq!(response, vec![0x69, 0x85]);
+    }
+
+    #[test]
+    #[cfg(any(feature = "with_nfc", feature = "with_ccid"))]
+    fn test_process_response_chaining_keeps_status() {
+        let mut session = ApduSession::new();
+        let response = session.process_apdu(&[0x00, 0x01, 0x02, 0x03, 0x01], |_| {
+            ApduResponse::new(vec![0xAA, 0xBB], ApduStatusCode::SW_WRONG_DATA.into())
+        });
+        assert_eq!(response, vec![0xAA, 0x61, 0x01]);
+        let response = session.process_apdu(&[0x00, 0xC0, 0x00, 0x00, 0x00], |_| {
+            panic!("Unexpected call to the handler")
+        });
+        assert_eq!(response, vec![0xBB, 0x6A, 0x80]);
+    }
+
+    #[test]
+    #[cfg(any(feature = "with_nfc", feature = "with_ccid"))]
+    fn test_process_extended_response() {
+        let mut session = ApduSession::new();
+        let response = session.process_apdu(&[0x00, 0x01, 0x02, 0x03, 0x00, 0x00, 0x00], |_| {
+            ApduResponse::success(vec![0x55; 1000])
+        });
+        assert_eq!(response.len(), 1002);
+        assert_eq!(response[1000..], [0x90, 0x00]);
+    }
+
+    #[test]
+    #[cfg(any(feature = "with_nfc", feature = "with_ccid"))]
+    fn test_new_command_drops_pending_response() {
+        let mut session = ApduSession::new();
+        session.process_apdu(&[0x00, 0x01, 0x02, 0x03, 0x01], |_| {
+            ApduResponse::success(vec![0xAA, 0xBB])
+        });
+        session.process_apdu(&[0x00, 0x01, 0x02, 0x03], |_| ApduResponse::success(vec![]));
+        let response = session.process_apdu(&[0x00, 0xC0, 0x00, 0x00, 0x00], |_| {
+            panic!("Unexpected call to the handler")
+        });
+        assert_eq!(response, vec![0x69, 0x85]);
+    }
+}
@@ -0,0 +1,263 @@
+// Copyright 2019 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::apdu::{ApduCommand, ApduResponse, ApduStatusCode};
+#[cfg(feature = "with_ctap1")]
+use super::ctap1;
+use super::CtapState;
+#[cfg(feature = "with_ctap1")]
+use super::U2F_VERSION_STRING;
+use crate::env::Env;
+use crate::timer::ClockValue;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+// An application reachable through APDUs once selected by its AID, ISO 7816-4 section 8.2.
+pub trait Applet<E: Env> {
+    // The application identifier used in SELECT commands.
+    fn aid(&self) -> &[u8];
+
+    // Called when the applet is selected, returns the response to the SELECT command.
+    fn select(&mut self) -> ApduResponse;
+
+    // Called when another applet is selected, or when the card is reset.
+    fn deselect(&mut self) {}
+
+    // Processes a command, once the applet is selected. Command chaining and GET RESPONSE are
+    // handled by the transport.
+    fn process_apdu(
+        &mut self,
+        command: ApduCommand,
+        clock_value: ClockValue,
+        ctap_state: &mut CtapState<E>,
+    ) -> ApduResponse;
+}
+
+// Routes commands to the applet selected by the last SELECT command.
+pub struct AppletDispatcher<E: Env> {
+    applets: Vec<Box<dyn Applet<E>>>,
+    // Index of the selected applet.
+    selected: Option<usize>,
+}
+
+impl<E: Env> AppletDispatcher<E> {
+    const CLA_ISO: u8 = 0x00;
+    const INS_SELECT: u8 = 0xA4;
+    const SELECT_BY_NAME_P1: u8 = 0x04;
+    const SELECT_FIRST_P2: u8 = 0x00;
+
+    pub fn new() -> AppletDispatcher<E> {
+        AppletDispatcher {
+            applets: Vec::new(),
+            selected: None,
+        }
+    }
+
+    // Applets are matched in the order of registration.
+    pub fn register(&mut self, applet: Box<dyn Applet<E>>) {
+        self.applets.push(applet);
+    }
+
+    // Deselects the current applet, for example when the card is powered off.
+    pub fn reset(&mut self) {
+        if let Some(index) = self.selected.take() {
+            self.applets[index].deselect();
+        }
+    }
+
+    pub fn process_apdu(
+        &mut self,
+        command: ApduCommand,
+        clock_value: ClockValue,
+        ctap_state: &mut CtapState<E>,
+    ) -> ApduResponse {
+        if command.cla == Self::CLA_ISO && command.ins == Self::INS_SELECT {
+            return self.process_select(&command);
+        }
+        match self.selected {
+            Some(index) => self.applets[index].process_apdu(command, clock_value, ctap_state),
+            None => ApduResponse::error(ApduStatusCode::SW_CONDITIONS_NOT_SATISFIED),
+        }
+    }
+
+    // Only selection by full AID is supported, ISO 7816-4 section 11.2.2.
+    fn process_select(&mut self, command: &ApduCommand) -> ApduResponse {
+        if command.p1 != Self::SELECT_BY_NAME_P1 || command.p2 != Self::SELECT_FIRST_P2 {
+            return ApduResponse::error(ApduStatusCode::SW_INCORRECT_P1P2);
+        }
+        self.reset();
+        match self
+            .applets
+            .iter()
+            .position(|applet| applet.aid() == command.data.as_slice())
+        {
+            Some(index) => {
+                self.selected = Some(index);
+                self.applets[index].select()
+            }
+            None => ApduResponse::error(ApduStatusCode::SW_FILE_NOT_FOUND),
+        }
+    }
+}
+
+// The U2F applet, with the FIDO AID. The U2F messages are processed as for CTAPHID, including the
+// user presence check with the button.
+#[cfg(feature = "with_ctap1")]
+pub struct U2fApplet;
+
+#[cfg(feature = "with_ctap1")]
+impl U2fApplet {
+    // CTAP specification (version 20190130) section 8.2.3
+    pub const AID: [u8; 8] = [0xA0, 0x00, 0x00, 0x06, 0x47, 0x2F, 0x00, 0x01];
+}
+
+#[cfg(feature = "with_ctap1")]
+impl<E: Env> Applet<E> for U2fApplet {
+    fn aid(&self) -> &[u8] {
+        &U2fApplet::AID
+    }
+
+    fn select(&mut self) -> ApduResponse {
+        ApduResponse::success(U2F_VERSION_STRING.as_bytes().to_vec())
+    }
+
+    fn process_apdu(
+        &mut self,
+        command: ApduCommand,
+        clock_value: ClockValue,
+        ctap_state: &mut CtapState<E>,
+    ) -> ApduResponse {
+        match ctap1::Ctap1Command::process_apdu(command, ctap_state, clock_value, false) {
+            Ok(payload) => ApduResponse::success(payload),
+            Err(status_code) => ApduResponse::error(status_code),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::env::host::HostEnv;
+
+    const CLOCK_FREQUENCY_HZ: usize = 32768;
+    const DUMMY_CLOCK_VALUE: ClockValue = ClockValue::new(0, CLOCK_FREQUENCY_HZ);
+
+    // Replies to every command with its data, prefixed with the applet name.
+    struct EchoApplet {
+        aid: Vec<u8>,
+        name: u8,
+    }
+
+    impl<E: Env> Applet<E> for EchoApplet {
+        fn aid(&self) -> &[u8] {
+            &self.aid
+        }
+
+        fn select(&mut self) -> ApduResponse {
+            ApduResponse::success(vec![self.name])
+        }
+
+        fn process_apdu(
+            &mut self,
+            command: ApduCommand,
+            _clock_value: ClockValue,
+            _ctap_state: &mut CtapState<E>,
+        ) -> ApduResponse {
+            let mut data = vec![self.name];
+            data.extend_from_slice(&command.data);
+            ApduResponse::success(data)
+        }
+    }
+
+    fn apdu(bytes: &[u8]) -> ApduCommand {
+        use core::convert::TryFrom;
+        ApduCommand::try_from(bytes).unwrap()
+    }
+
+    #[test]
+    fn test_select_and_dispatch() {
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(HostEnv::new(user_immediately_present));
+        let mut dispatcher = AppletDispatcher::new();
+        dispatcher.register(Box::new(EchoApplet {
+            aid: vec![0x01, 0x02, 0x03, 0x04, 0x05],
+            name: 0xA1,
+        }));
+        dispatcher.register(Box::new(EchoApplet {
+            aid: vec![0x01, 0x02, 0x03, 0x04, 0x06],
+            name: 0xA2,
+        }));
+
+        let echo = apdu(&[0x00, 0x01, 0x00, 0x00, 0x01, 0x42]);
+        let response = dispatcher.process_apdu(echo.clone(), DUMMY_CLOCK_VALUE, &mut ctap_state);
+        assert_eq!(response.into_bytes(), vec![0x69, 0x85]);
+
+        let select = apdu(&[0x00, 0xA4, 0x04, 0x00, 0x05, 0x01, 0x02, 0x03, 0x04, 0x06]);
+        let response = dispatcher.process_apdu(select, DUMMY_CLOCK_VALUE, &mut ctap_state);
+        assert_eq!(response.into_bytes(), vec![0xA2, 0x90, 0x00]);
+        let response = dispatcher.process_apdu(echo.clone(), DUMMY_CLOCK_VALUE, &mut ctap_state);
+        assert_eq!(response.into_bytes(), vec![0xA2, 0x42, 0x90, 0x00]);
+
+        let select = apdu(&[0x00, 0xA4, 0x04, 0x00, 0x05, 0x01, 0x02, 0x03, 0x04, 0x05]);
+        let response = dispatcher.process_apdu(select, DUMMY_CLOCK_VALUE, &mut ctap_state);
+        assert_eq!(response.into_bytes(), vec![0xA1, 0x90, 0x00]);
+        let response = dispatcher.process_apdu(echo, DUMMY_CLOCK_VALUE, &mut ctap_state);
+        assert_eq!(response.into_bytes(), vec![0xA1, 0x42, 0x90, 0x00]);
+    }
+
+    #[test]
+    fn test_select_unknown_aid() {
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(HostEnv::new(user_immediately_present));
+        let mut dispatcher = AppletDispatcher::new();
+        dispatcher.register(Box::new(EchoApplet {
+            aid: vec![0x01, 0x02, 0x03, 0x04, 0x05],
+            name: 0xA1,
+        }));
+
+        let select = apdu(&[0x00, 0xA4, 0x04, 0x00, 0x05, 0x01, 0x02, 0x03, 0x04, 0x05]);
+        dispatcher.process_apdu(select, DUMMY_CLOCK_VALUE, &mut ctap_state);
+        // A failed selection leaves no applet selected.
+        let select = apdu(&[0x00, 0xA4, 0x04, 0x00, 0x04, 0x01, 0x02, 0x03, 0x04]);
+        let response = dispatcher.process_apdu(select, DUMMY_CLOCK_VALUE, &mut ctap_state);
+        assert_eq!(response.into_bytes(), vec![0x6A, 0x82]);
+        let echo = apdu(&[0x00, 0x01, 0x00, 0x00]);
+        let response = dispatcher.process_apdu(echo, DUMMY_CLOCK_VALUE, &mut ctap_state);
+        assert_eq!(response.into_bytes(), vec![0x69, 0x85]);
+
+        let select = apdu(&[0x00, 0xA4, 0x00, 0x0C, 0x02, 0x3F, 0x00]);
+        let response = dispatcher.process_apdu(select, DUMMY_CLOCK_VALUE, &mut ctap_state);
+        assert_eq!(response.into_bytes(), vec![0x6A, 0x86]);
+    }
+
+    #[cfg(feature = "with_ctap1")]
+    #[test]
+    fn test_u2f_applet() {
+        let dummy_user_presence = |_| panic!("Unexpected user presence check in CTAP1");
+        let mut ctap_state = CtapState::new(HostEnv::new(dummy_user_presence));
+        let mut dispatcher = AppletDispatcher::new();
+        dispatcher.register(Box::new(U2fApplet));
+
+        let mut select = vec![0x00, 0xA4, 0x04, 0x00, 0x08];
+        select.extend_from_slice(&U2fApplet::AID);
+        let response = dispatcher.process_apdu(apdu(&select), DUMMY_CLOCK_VALUE, &mut ctap_state);
+        let mut expected = U2F_VERSION_STRING.as_bytes().to_vec();
+        expected.extend_from_slice(&[0x90, 0x00]);
+        assert_eq!(response.into_bytes(), expected);
+
+        let version = apdu(&[0x00, 0x03, 0x00, 0x00, 0x00]);
+        let response = dispatcher.process_apdu(version, DUMMY_CLOCK_VALUE, &mut ctap_state);
+        assert_eq!(response.into_bytes(), expected);
+    }
+}
@@ -0,0 +1,249 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::data_formats::{
+    AttestationStatement, AttestationStatementFormat, FidoU2fAttestationStatement,
+    PackedAttestationStatement, SignatureAlgorithm,
+};
+use alloc::vec::Vec;
+use core::iter;
+use crypto::ecdsa;
+use crypto::sha256::Sha256;
+
+// Everything an attestation statement may sign or include for a new credential.
+pub struct AttestationInput<'a> {
+    pub auth_data: &'a [u8],
+    pub client_data_hash: &'a [u8],
+    pub rp_id_hash: &'a [u8],
+    pub credential_id: &'a [u8],
+    pub credential_key: &'a ecdsa::SecKey,
+    // The provisioned batch key and its certificate chain, if any.
+    pub batch_attestation: Option<(ecdsa::SecKey, Vec<Vec<u8>>)>,
+    // The key and certificate for formats that require a certificate.
+    pub u2f_attestation: Option<(ecdsa::SecKey, Vec<u8>)>,
+}
+
+// Returns the statement in the first format of the preference list that can attest the
+// credential, and falls back to the default format. Packed attestation always works, since
+// it can be self signed.
+pub fn attest(
+    input: &AttestationInput,
+    preference: &[AttestationStatementFormat],
+    default: AttestationStatementFormat,
+) -> AttestationStatement {
+    preference
+        .iter()
+        .chain(iter::once(&default))
+        .filter_map(|format| build(*format, input))
+        .next()
+        .unwrap_or_else(|| AttestationStatement::Packed(packed(input)))
+}
+
+// Returns None if the format is unknown, or lacks the necessary key material.
+fn build(
+    format: AttestationStatementFormat,
+    input: &AttestationInput,
+) -> Option<AttestationStatement> {
+    match format {
+        AttestationStatementFormat::None => Some(AttestationStatement::None),
+        AttestationStatementFormat::Packed => Some(AttestationStatement::Packed(packed(input))),
+        AttestationStatementFormat::FidoU2f => fido_u2f(input).map(AttestationStatement::FidoU2f),
+        AttestationStatementFormat::Unknown => None,
+    }
+}
+
+// https://www.w3.org/TR/webauthn/#sctn-packed-attestation
+fn packed(input: &AttestationInput) -> PackedAttestationStatement {
+    let mut signature_data = input.auth_data.to_vec();
+    signature_data.extend_from_slice(input.client_data_hash);
+    let (signature, x5c) = match &input.batch_attestation {
+        Some((attestation_key, certificates)) => (
+            attestation_key.sign_rfc6979::<Sha256>(&signature_data),
+            Some(certificates.clone()),
+        ),
+        None => (
+            input.credential_key.sign_rfc6979::<Sha256>(&signature_data),
+            None,
+        ),
+    };
+    PackedAttestationStatement {
+        alg: SignatureAlgorithm::ES256 as i64,
+        sig: signature.to_asn1_der(),
+        x5c,
+        ecdaa_key_id: None,
+    }
+}
+
+// https://www.w3.org/TR/webauthn/#sctn-fido-u2f-attestation
+fn fido_u2f(input: &AttestationInput) -> Option<FidoU2fAttestationStatement> {
+    let (attestation_key, certificate) = input.u2f_attestation.as_ref()?;
+    // The signed data is the same as for a U2F registration, see the FIDO U2F raw message
+    // formats specification section 4.3.
+    let mut signature_data = Vec::with_capacity(
+        1 + input.rp_id_hash.len() + input.client_data_hash.len() + input.credential_id.len() + 65,
+    );
+    signature_data.push(0x00);
+    signature_data.extend_from_slice(input.rp_id_hash);
+    signature_data.extend_from_slice(input.client_data_hash);
+    signature_data.extend_from_slice(input.credential_id);
+    signature_data.extend_from_slice(&input.credential_key.genpk().to_uncompressed());
+    Some(FidoU2fAttestationStatement {
+        sig: attestation_key
+            .sign_rfc6979::<Sha256>(&signature_data)
+            .to_asn1_der(),
+        attestation_certificate: certificate.clone(),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::conformance::verify_signature;
+    use super::*;
+    use crypto::rng256::ThreadRng256;
+
+    fn attest_format(
+        input: &AttestationInput,
+        preference: &[AttestationStatementFormat],
+    ) -> AttestationStatementFormat {
+        attest(input, preference, AttestationStatementFormat::Packed).format()
+    }
+
+    #[test]
+    fn test_attest_preference() {
+        let mut rng = ThreadRng256 {};
+        let credential_key = ecdsa::SecKey::gensk(&mut rng);
+        let mut input = AttestationInput {
+            auth_data: &[0xAD; 37],
+            client_data_hash: &[0xCD; 32],
+            rp_id_hash: &[0x1D; 32],
+            credential_id: &[0xC1; 32],
+            credential_key: &credential_key,
+            batch_attestation: None,
+            u2f_attestation: None,
+        };
+
+        assert_eq!(
+            attest_format(&input, &[]),
+            AttestationStatementFormat::Packed
+        );
+        assert_eq!(
+            attest_format(
+                &input,
+                &[
+                    AttestationStatementFormat::Unknown,
+                    AttestationStatementFormat::None
+                ]
+            ),
+            AttestationStatementFormat::None
+        );
+        // Without a certificate, fido-u2f is skipped.
+        assert_eq!(
+            attest_format(
+                &input,
+                &[
+                    AttestationStatementFormat::FidoU2f,
+                    AttestationStatementFormat::None
+                ]
+            ),
+            AttestationStatementFormat::None
+        );
+        assert_eq!(
+            attest(&input, &[], AttestationStatementFormat::FidoU2f).format(),
+            AttestationStatementFormat::Packed
+        );
+
+        input.u2f_attestation = Some((ecdsa::SecKey::gensk(&mut rng), vec![0x5C; 4]));
+        assert_eq!(
+            attest_format(
+                &input,
+                &[
+                    AttestationStatementFormat::FidoU2f,
+                    AttestationStatementFormat::None
+                ]
+            ),
+            AttestationStatementFormat::FidoU2f
+        );
+        assert_eq!(
+            attest(&input, &[], AttestationStatementFormat::None),
+            AttestationStatement::None
+        );
+    }
+
+    #[test]
+    fn test_attest_packed() {
+        let mut rng = ThreadRng256 {};
+        let credential_key = ecdsa::SecKey::gensk(&mut rng);
+        let attestation_key = ecdsa::SecKey::gensk(&mut rng);
+        let mut input = AttestationInput {
+            auth_data: &[0xAD; 37],
+            client_data_hash: &[0xCD; 32],
+            rp_id_hash: &[0x1D; 32],
+            credential_id: &[0xC1; 32],
+            credential_key: &credential_key,
+            batch_attestation: None,
+            u2f_attestation: None,
+        };
+        let mut signature_data = vec![0xAD; 37];
+        signature_data.extend(&[0xCD; 32]);
+
+        // Self attestation is signed by the credential.
+        let att_stmt = match attest(&input, &[], AttestationStatementFormat::Packed) {
+            AttestationStatement::Packed(att_stmt) => att_stmt,
+            _ => panic!("Invalid attestation statement"),
+        };
+        assert_eq!(att_stmt.alg, SignatureAlgorithm::ES256 as i64);
+        assert_eq!(att_stmt.x5c, None);
+        verify_signature(&credential_key.genpk(), &signature_data, &att_stmt.sig);
+
+        input.batch_attestation = Some((attestation_key.clone(), vec![vec![0x5C; 4]]));
+        let att_stmt = match attest(&input, &[], AttestationStatementFormat::Packed) {
+            AttestationStatement::Packed(att_stmt) => att_stmt,
+            _ => panic!("Invalid attestation statement"),
+        };
+        assert_eq!(att_stmt.x5c, Some(vec![vec![0x5C; 4]]));
+        verify_signature(&attestation_key.genpk(), &signature_data, &att_stmt.sig);
+    }
+
+    #[test]
+    fn test_attest_fido_u2f() {
+        let mut rng = ThreadRng256 {};
+        let credential_key = ecdsa::SecKey::gensk(&mut rng);
+        let attestation_key = ecdsa::SecKey::gensk(&mut rng);
+        let input = AttestationInput {
+            auth_data: &[0xAD; 37],
+            client_data_hash: &[0xCD; 32],
+            rp_id_hash: &[0x1D; 32],
+            credential_id: &[0xC1; 32],
+            credential_key: &credential_key,
+            batch_attestation: None,
+            u2f_attestation: Some((attestation_key.clone(), vec![0x5C; 4])),
+        };
+
+        let att_stmt = match attest(
+            &input,
+            &[AttestationStatementFormat::FidoU2f],
+            AttestationStatementFormat::None,
+        ) {
+            AttestationStatement::FidoU2f(att_stmt) => att_stmt,
+            _ => panic!("Invalid attestation statement"),
+        };
+        assert_eq!(att_stmt.attestation_certificate, vec![0x5C; 4]);
+        let mut signature_data = vec![0x00];
+        signature_data.extend(&[0x1D; 32]);
+        signature_data.extend(&[0xCD; 32]);
+        signature_data.extend(&[0xC1; 32]);
+        signature_data.extend(&credential_key.genpk().to_uncompressed()[..]);
+        verify_signature(&attestation_key.genpk(), &signature_data, &att_stmt.sig);
+    }
+}
@@ -0,0 +1,466 @@
+// Copyright 2019 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+mod receive;
+mod send;
+
+use self::receive::MessageAssembler;
+pub use self::send::BleFragmentIterator;
+#[cfg(feature = "with_ctap1")]
+use super::ctap1;
+use super::hid::{ChannelID, KeepaliveStatus};
+use super::status_code::Ctap2StatusCode;
+use super::CtapState;
+use crate::env::Env;
+use crate::timer::ClockValue;
+use alloc::vec::Vec;
+use core::cmp::{max, min};
+#[cfg(feature = "debug_ctap")]
+use core::fmt::Write;
+#[cfg(feature = "debug_ctap")]
+use libtock::console::Console;
+
+// CTAP specification (version 20190130) section 8.3
+// Requests are written to the fidoControlPoint characteristic, and responses are notified on the
+// fidoStatus characteristic, both in fragments of at most controlPointLength bytes.
+
+// CTAP2 commands don't carry a channel over BLE. This identifier is passed to the user presence
+// check instead, so that it can tell BLE requests apart. CTAPHID never allocates it.
+pub const BLE_CHANNEL_ID: ChannelID = [0xFF, 0xFF, 0xFF, 0xFE];
+
+// An assembled CTAP BLE frame.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Message {
+    // Command.
+    pub cmd: u8,
+    // Bytes of the message.
+    pub payload: Vec<u8>,
+}
+
+pub struct CtapBle {
+    assembler: MessageAssembler,
+    control_point_length: usize,
+}
+
+impl CtapBle {
+    // CTAP specification (version 20190130) section 8.3.3
+    const TYPE_INIT_BIT: u8 = 0x80;
+    // The length of a frame is encoded on 2 bytes.
+    const MAX_PAYLOAD_LEN: usize = 0xFFFF;
+
+    // CTAP specification (version 20190130) section 8.3.4
+    const COMMAND_PING: u8 = 0x81;
+    const COMMAND_KEEPALIVE: u8 = 0x82;
+    const COMMAND_MSG: u8 = 0x83;
+    pub const COMMAND_CANCEL: u8 = 0xBE;
+    const COMMAND_ERROR: u8 = 0xBF;
+
+    // Only the errors of the framing layer are sent, the others are kept for completeness.
+    const ERR_INVALID_CMD: u8 = 0x01;
+    #[allow(dead_code)]
+    const ERR_INVALID_PAR: u8 = 0x02;
+    const ERR_INVALID_LEN: u8 = 0x03;
+    const ERR_INVALID_SEQ: u8 = 0x04;
+    #[allow(dead_code)]
+    const ERR_REQ_TIMEOUT: u8 = 0x05;
+    #[allow(dead_code)]
+    const ERR_BUSY: u8 = 0x06;
+    #[allow(dead_code)]
+    const ERR_OTHER: u8 = 0x7F;
+
+    // CTAP specification (version 20190130) section 8.3.5
+    // The controlPointLength characteristic is between 20 and 512 bytes.
+    pub const MIN_CONTROL_POINT_LENGTH: usize = 20;
+    pub const MAX_CONTROL_POINT_LENGTH: usize = 512;
+
+    // The control point length is usually derived from the negotiated ATT MTU. Values outside of
+    // the range allowed by the specification are clamped.
+    pub fn new(control_point_length: usize) -> CtapBle {
+        let control_point_length = min(
+            max(control_point_length, CtapBle::MIN_CONTROL_POINT_LENGTH),
+            CtapBle::MAX_CONTROL_POINT_LENGTH,
+        );
+        CtapBle {
+            assembler: MessageAssembler::new(control_point_length),
+            control_point_length,
+        }
+    }
+
+    // The value of the controlPointLength characteristic. The desktop build has no GATT server.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn control_point_length(&self) -> usize {
+        self.control_point_length
+    }
+
+    // Resets the current transaction, discarding any partially received message.
+    // This is used when the connection is lost, or when the reply couldn't be sent.
+    pub fn reset(&mut self) {
+        self.assembler.reset();
+    }
+
+    // Process a fragment written to fidoControlPoint, and optionally returns a list of fragments
+    // to notify on fidoStatus as a reply.
+    pub fn process_fragment<E: Env>(
+        &mut self,
+        fragment: &[u8],
+        clock_value: ClockValue,
+        ctap_state: &mut CtapState<E>,
+    ) -> BleFragmentIterator {
+        match self.assembler.parse_fragment(fragment) {
+            Ok(Some(message)) => {
+                #[cfg(feature = "debug_ctap")]
+                writeln!(&mut Console::new(), "Received message: {:02x?}", message).unwrap();
+
+                match message.cmd {
+                    CtapBle::COMMAND_MSG => self.process_msg(message, clock_value, ctap_state),
+                    CtapBle::COMMAND_PING => {
+                        // Pong the same message.
+                        // This unwrap is safe because if we could parse the incoming message, its
+                        // payload length fits in a frame.
+                        self.split_message(message).unwrap()
+                    }
+                    CtapBle::COMMAND_CANCEL => {
+                        // Authenticators MUST NOT reply to this message.
+                        // CANCEL is handled during user presence checks by the caller.
+                        BleFragmentIterator::none()
+                    }
+                    _ => {
+                        // Unknown command, or one that only the authenticator sends.
+                        self.error_message(CtapBle::ERR_INVALID_CMD)
+                    }
+                }
+            }
+            Ok(None) => {
+                // Waiting for more fragments to assemble the message, nothing to send for now.
+                BleFragmentIterator::none()
+            }
+            Err(error) => match error {
+                receive::Error::UnexpectedInit | receive::Error::UnexpectedSeq => {
+                    self.error_message(CtapBle::ERR_INVALID_SEQ)
+                }
+                receive::Error::UnexpectedContinuation => {
+                    // Spurious continuation fragments are ignored, as for CTAPHID.
+                    BleFragmentIterator::none()
+                }
+                receive::Error::InvalidLength => self.error_message(CtapBle::ERR_INVALID_LEN),
+            },
+        }
+    }
+
+    // CTAP specification (version 20190130) section 8.3.4
+    // MSG frames carry either a U2F raw message, starting with the CLA byte 0x00, or a CTAP2
+    // command byte followed by its CBOR parameters.
+    fn process_msg<E: Env>(
+        &self,
+        message: Message,
+        clock_value: ClockValue,
+        ctap_state: &mut CtapState<E>,
+    ) -> BleFragmentIterator {
+        let response = match message.payload.first() {
+            #[cfg(feature = "with_ctap1")]
+            Some(0x00) => {
+                let (mut payload, status_code) = match ctap1::Ctap1Command::process_command(
+                    &message.payload,
+                    ctap_state,
+                    clock_value,
+                ) {
+                    Ok(payload) => (payload, ctap1::Ctap1StatusCode::SW_NO_ERROR),
+                    Err(status_code) => (Vec::new(), status_code),
+                };
+                let code: u16 = status_code.into();
+                payload.extend_from_slice(&code.to_be_bytes());
+                payload
+            }
+            _ => {
+                #[cfg(not(feature = "with_ctap1"))]
+                let _ = clock_value;
+                ctap_state.process_command(&message.payload, BLE_CHANNEL_ID)
+            }
+        };
+        if let Some(iterator) = self.split_message(Message {
+            cmd: CtapBle::COMMAND_MSG,
+            payload: response,
+        }) {
+            iterator
+        } else {
+            // Handle the case of a payload that doesn't fit in a frame, as for CTAPHID.
+            // The error payload is 1 byte long, so it is safe to unwrap() the result.
+            self.split_message(Message {
+                cmd: CtapBle::COMMAND_MSG,
+                payload: vec![Ctap2StatusCode::CTAP2_ERR_VENDOR_RESPONSE_TOO_LONG as u8],
+            })
+            .unwrap()
+        }
+    }
+
+    fn error_message(&self, error_code: u8) -> BleFragmentIterator {
+        // This unwrap is safe because the payload length is 1 byte.
+        self.split_message(Message {
+            cmd: CtapBle::COMMAND_ERROR,
+            payload: vec![error_code],
+        })
+        .unwrap()
+    }
+
+    fn split_message(&self, message: Message) -> Option<BleFragmentIterator> {
+        #[cfg(feature = "debug_ctap")]
+        writeln!(&mut Console::new(), "Sending message: {:02x?}", message).unwrap();
+        BleFragmentIterator::new(message, self.control_point_length)
+    }
+
+    // To be notified while a request waits for the user. The desktop build checks user presence
+    // synchronously and never sends it.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn keepalive(&self, status: KeepaliveStatus) -> BleFragmentIterator {
+        let status_code = match status {
+            KeepaliveStatus::Processing => 1,
+            KeepaliveStatus::UpNeeded => 2,
+        };
+        // This unwrap is safe because the payload length is 1 byte.
+        self.split_message(Message {
+            cmd: CtapBle::COMMAND_KEEPALIVE,
+            payload: vec![status_code],
+        })
+        .unwrap()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::env::host::HostEnv;
+
+    const CLOCK_FREQUENCY_HZ: usize = 32768;
+    const DUMMY_CLOCK_VALUE: ClockValue = ClockValue::new(0, CLOCK_FREQUENCY_HZ);
+    const GET_INFO: u8 = 0x04;
+
+    fn process_message<E: Env>(
+        ctap_ble: &mut CtapBle,
+        ctap_state: &mut CtapState<E>,
+        request: Message,
+    ) -> Vec<Message> {
+        let mut result = Vec::new();
+        let mut assembler_reply = MessageAssembler::new(ctap_ble.control_point_length());
+        let fragments: Vec<Vec<u8>> =
+            BleFragmentIterator::new(request, ctap_ble.control_point_length())
+                .unwrap()
+                .collect();
+        for fragment in fragments {
+            for reply in ctap_ble.process_fragment(&fragment, DUMMY_CLOCK_VALUE, ctap_state) {
+                if let Some(message) = assembler_reply.parse_fragment(&reply).unwrap() {
+                    result.push(message);
+                }
+            }
+        }
+        result
+    }
+
+    #[test]
+    fn test_split_assemble() {
+        for control_point_length in &[20, 23, 512] {
+            for payload_len in 0..1200 {
+                let message = Message {
+                    cmd: CtapBle::COMMAND_MSG,
+                    payload: vec![0xFF; payload_len],
+                };
+                let mut messages = Vec::new();
+                let mut assembler = MessageAssembler::new(*control_point_length);
+                for fragment in
+                    BleFragmentIterator::new(message.clone(), *control_point_length).unwrap()
+                {
+                    assert!(fragment.len() <= *control_point_length);
+                    if let Some(msg) = assembler.parse_fragment(&fragment).unwrap() {
+                        messages.push(msg);
+                    }
+                }
+                assert_eq!(messages, vec![message]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_control_point_length_clamped() {
+        assert_eq!(CtapBle::new(0).control_point_length(), 20);
+        assert_eq!(CtapBle::new(185).control_point_length(), 185);
+        assert_eq!(CtapBle::new(4096).control_point_length(), 512);
+    }
+
+    #[test]
+    fn test_command_ping() {
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(HostEnv::new(user_immediately_present));
+        let mut ctap_ble = CtapBle::new(20);
+
+        let ping = Message {
+            cmd: CtapBle::COMMAND_PING,
+            payload: vec![0x99; 100],
+        };
+        let reply = process_message(&mut ctap_ble, &mut ctap_state, ping.clone());
+        assert_eq!(reply, vec![ping]);
+    }
+
+    #[test]
+    fn test_command_msg_get_info() {
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(HostEnv::new(user_immediately_present));
+        let mut ctap_ble = CtapBle::new(20);
+
+        let reply = process_message(
+            &mut ctap_ble,
+            &mut ctap_state,
+            Message {
+                cmd: CtapBle::COMMAND_MSG,
+                payload: vec![GET_INFO],
+            },
+        );
+        let expected = ctap_state.process_command(&[GET_INFO], BLE_CHANNEL_ID);
+        assert_eq!(
+            reply,
+            vec![Message {
+                cmd: CtapBle::COMMAND_MSG,
+                payload: expected,
+            }]
+        );
+    }
+
+    #[cfg(feature = "with_ctap1")]
+    #[test]
+    fn test_command_msg_u2f_version() {
+        let dummy_user_presence = |_| panic!("Unexpected user presence check in CTAP1");
+        let mut ctap_state = CtapState::new(HostEnv::new(dummy_user_presence));
+        let mut ctap_ble = CtapBle::new(20);
+
+        let reply = process_message(
+            &mut ctap_ble,
+            &mut ctap_state,
+            Message {
+                cmd: CtapBle::COMMAND_MSG,
+                payload: vec![0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00],
+            },
+        );
+        let mut expected = super::super::U2F_VERSION_STRING.as_bytes().to_vec();
+        expected.extend_from_slice(&[0x90, 0x00]);
+        assert_eq!(
+            reply,
+            vec![Message {
+                cmd: CtapBle::COMMAND_MSG,
+                payload: expected,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_command_cancel() {
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(HostEnv::new(user_immediately_present));
+        let mut ctap_ble = CtapBle::new(20);
+
+        let reply = process_message(
+            &mut ctap_ble,
+            &mut ctap_state,
+            Message {
+                cmd: CtapBle::COMMAND_CANCEL,
+                payload: vec![],
+            },
+        );
+        assert_eq!(reply, vec![]);
+    }
+
+    #[test]
+    fn test_invalid_command() {
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(HostEnv::new(user_immediately_present));
+        let mut ctap_ble = CtapBle::new(20);
+
+        for &cmd in &[CtapBle::COMMAND_KEEPALIVE, CtapBle::COMMAND_ERROR, 0x84] {
+            let reply = process_message(
+                &mut ctap_ble,
+                &mut ctap_state,
+                Message {
+                    cmd,
+                    payload: vec![],
+                },
+            );
+            assert_eq!(
+                reply,
+                vec![Message {
+                    cmd: CtapBle::COMMAND_ERROR,
+                    payload: vec![CtapBle::ERR_INVALID_CMD],
+                }]
+            );
+        }
+    }
+
+    #[test]
+    fn test_invalid_seq() {
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(HostEnv::new(user_immediately_present));
+        let mut ctap_ble = CtapBle::new(20);
+
+        let mut init = vec![CtapBle::COMMAND_MSG, 0x00, 0x20];
+        init.extend_from_slice(&[0x55; 17]);
+        assert_eq!(
+            ctap_ble
+                .process_fragment(&init, DUMMY_CLOCK_VALUE, &mut ctap_state)
+                .count(),
+            0
+        );
+        let reply: Vec<Vec<u8>> = ctap_ble
+            .process_fragment(&[0x01, 0x55], DUMMY_CLOCK_VALUE, &mut ctap_state)
+            .collect();
+        assert_eq!(
+            reply,
+            vec![vec![
+                CtapBle::COMMAND_ERROR,
+                0x00,
+                0x01,
+                CtapBle::ERR_INVALID_SEQ
+            ]]
+        );
+    }
+
+    #[test]
+    fn test_reset() {
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(HostEnv::new(user_immediately_present));
+        let mut ctap_ble = CtapBle::new(20);
+
+        let mut init = vec![CtapBle::COMMAND_MSG, 0x00, 0x20];
+        init.extend_from_slice(&[0x55; 17]);
+        assert_eq!(
+            ctap_ble
+                .process_fragment(&init, DUMMY_CLOCK_VALUE, &mut ctap_state)
+                .count(),
+            0
+        );
+        ctap_ble.reset();
+        // The partial message is discarded, so a new one can start.
+        let ping = Message {
+            cmd: CtapBle::COMMAND_PING,
+            payload: vec![0x99; 10],
+        };
+        let reply = process_message(&mut ctap_ble, &mut ctap_state, ping.clone());
+        assert_eq!(reply, vec![ping]);
+    }
+
+    #[test]
+    fn test_keepalive() {
+        let ctap_ble = CtapBle::new(20);
+        let fragments: Vec<Vec<u8>> = ctap_ble.keepalive(KeepaliveStatus::UpNeeded).collect();
+        assert_eq!(
+            fragments,
+            vec![vec![CtapBle::COMMAND_KEEPALIVE, 0x00, 0x01, 0x02]]
+        );
+    }
+}
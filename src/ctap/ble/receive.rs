@@ -0,0 +1,253 @@
+// Copyright 2019 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::{CtapBle, Message};
+use alloc::vec::Vec;
+use core::mem::swap;
+
+// A structure to assemble CTAP BLE frames from a series of fragments written to fidoControlPoint.
+pub struct MessageAssembler {
+    // Maximum length of a fragment, i.e. the controlPointLength.
+    max_fragment_len: usize,
+    // Whether this is waiting to receive an initialization fragment.
+    idle: bool,
+    // Current command.
+    cmd: u8,
+    // Sequence number expected for the next fragment.
+    seq: u8,
+    // Number of bytes left to fill the current message.
+    remaining_payload_len: usize,
+    // Buffer for the current payload.
+    payload: Vec<u8>,
+}
+
+#[derive(PartialEq, Debug)]
+pub enum Error {
+    // Expected a continuation fragment, got an initialization fragment.
+    UnexpectedInit,
+    // Expected an initialization fragment, got a continuation fragment.
+    UnexpectedContinuation,
+    // Expected a continuation fragment with a specific sequence number, got another one.
+    UnexpectedSeq,
+    // The fragment is empty, longer than controlPointLength, or carries more data than announced.
+    InvalidLength,
+}
+
+impl MessageAssembler {
+    pub fn new(max_fragment_len: usize) -> MessageAssembler {
+        MessageAssembler {
+            max_fragment_len,
+            idle: true,
+            cmd: 0,
+            seq: 0,
+            remaining_payload_len: 0,
+            payload: Vec::new(),
+        }
+    }
+
+    // Resets the message assembler to the idle state.
+    // The caller can reset the assembler for example when the connection is lost.
+    pub fn reset(&mut self) {
+        self.idle = true;
+        self.cmd = 0;
+        self.seq = 0;
+        self.remaining_payload_len = 0;
+        self.payload.clear();
+    }
+
+    // Returns:
+    // - An Ok() result if the fragment was parsed correctly. This contains either Some(Message)
+    // if a full message was assembled after this fragment, or None if more fragments are needed.
+    // - An Err() result if there was a parsing error.
+    pub fn parse_fragment(&mut self, fragment: &[u8]) -> Result<Option<Message>, Error> {
+        if fragment.is_empty() || fragment.len() > self.max_fragment_len {
+            self.reset();
+            return Err(Error::InvalidLength);
+        }
+
+        if fragment[0] & CtapBle::TYPE_INIT_BIT != 0 {
+            if !self.idle {
+                self.reset();
+                return Err(Error::UnexpectedInit);
+            }
+            // CTAP specification (version 20190130) section 8.3.3
+            // An initialization fragment is CMD | HLEN | LLEN | DATA.
+            if fragment.len() < 3 {
+                return Err(Error::InvalidLength);
+            }
+            let len = (fragment[1] as usize) << 8 | fragment[2] as usize;
+            let data = &fragment[3..];
+            if data.len() > len {
+                return Err(Error::InvalidLength);
+            }
+            self.cmd = fragment[0];
+            self.seq = 0;
+            self.remaining_payload_len = len;
+            Ok(self.append_payload(data))
+        } else {
+            if self.idle {
+                return Err(Error::UnexpectedContinuation);
+            }
+            // A continuation fragment is SEQ | DATA.
+            if fragment[0] != self.seq {
+                // Reject fragments with the wrong sequence number.
+                self.reset();
+                return Err(Error::UnexpectedSeq);
+            }
+            let data = &fragment[1..];
+            if data.len() > self.remaining_payload_len {
+                self.reset();
+                return Err(Error::InvalidLength);
+            }
+            // Sequence numbers wrap around after 0x7F, the high bit marks initialization fragments.
+            self.seq = (self.seq + 1) & !CtapBle::TYPE_INIT_BIT;
+            Ok(self.append_payload(data))
+        }
+    }
+
+    fn append_payload(&mut self, data: &[u8]) -> Option<Message> {
+        self.payload.extend_from_slice(data);
+        self.remaining_payload_len -= data.len();
+        if self.remaining_payload_len > 0 {
+            self.idle = false;
+            None
+        } else {
+            self.idle = true;
+            let mut payload = Vec::new();
+            swap(&mut self.payload, &mut payload);
+            Some(Message {
+                cmd: self.cmd,
+                payload,
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const MAX_FRAGMENT_LEN: usize = 20;
+
+    #[test]
+    fn test_empty_payload() {
+        let mut assembler = MessageAssembler::new(MAX_FRAGMENT_LEN);
+        assert_eq!(
+            assembler.parse_fragment(&[0x81, 0x00, 0x00]),
+            Ok(Some(Message {
+                cmd: 0x81,
+                payload: vec![]
+            }))
+        );
+    }
+
+    #[test]
+    fn test_one_fragment() {
+        let mut assembler = MessageAssembler::new(MAX_FRAGMENT_LEN);
+        assert_eq!(
+            assembler.parse_fragment(&[0x83, 0x00, 0x02, 0x12, 0x34]),
+            Ok(Some(Message {
+                cmd: 0x83,
+                payload: vec![0x12, 0x34]
+            }))
+        );
+    }
+
+    #[test]
+    fn test_two_fragments() {
+        let mut assembler = MessageAssembler::new(MAX_FRAGMENT_LEN);
+        let mut fragment = vec![0x83, 0x00, 0x14];
+        fragment.extend_from_slice(&[0x55; 17]);
+        assert_eq!(assembler.parse_fragment(&fragment), Ok(None));
+        assert_eq!(
+            assembler.parse_fragment(&[0x00, 0x55, 0x55, 0x55]),
+            Ok(Some(Message {
+                cmd: 0x83,
+                payload: vec![0x55; 20]
+            }))
+        );
+    }
+
+    #[test]
+    fn test_sequence_wraps_around() {
+        let mut assembler = MessageAssembler::new(MAX_FRAGMENT_LEN);
+        // 17 bytes in the initialization fragment, then 130 full continuation fragments of 19.
+        let len = 17 + 130 * 19;
+        let mut fragment = vec![0x83, (len >> 8) as u8, len as u8];
+        fragment.extend_from_slice(&[0x55; 17]);
+        assert_eq!(assembler.parse_fragment(&fragment), Ok(None));
+        for i in 0..130 {
+            let mut fragment = vec![(i % 0x80) as u8];
+            fragment.extend_from_slice(&[0x55; 19]);
+            let expected = if i == 129 {
+                Some(Message {
+                    cmd: 0x83,
+                    payload: vec![0x55; len],
+                })
+            } else {
+                None
+            };
+            assert_eq!(assembler.parse_fragment(&fragment), Ok(expected));
+        }
+    }
+
+    #[test]
+    fn test_unexpected_seq() {
+        let mut assembler = MessageAssembler::new(MAX_FRAGMENT_LEN);
+        let mut fragment = vec![0x83, 0x00, 0x40];
+        fragment.extend_from_slice(&[0x55; 17]);
+        assert_eq!(assembler.parse_fragment(&fragment), Ok(None));
+        assert_eq!(
+            assembler.parse_fragment(&[0x01, 0x55]),
+            Err(Error::UnexpectedSeq)
+        );
+        // The assembler is idle again.
+        assert_eq!(
+            assembler.parse_fragment(&[0x00, 0x55]),
+            Err(Error::UnexpectedContinuation)
+        );
+    }
+
+    #[test]
+    fn test_unexpected_init() {
+        let mut assembler = MessageAssembler::new(MAX_FRAGMENT_LEN);
+        assert_eq!(assembler.parse_fragment(&[0x83, 0x00, 0x40]), Ok(None));
+        assert_eq!(
+            assembler.parse_fragment(&[0x81, 0x00, 0x00]),
+            Err(Error::UnexpectedInit)
+        );
+    }
+
+    #[test]
+    fn test_invalid_length() {
+        let mut assembler = MessageAssembler::new(MAX_FRAGMENT_LEN);
+        assert_eq!(assembler.parse_fragment(&[]), Err(Error::InvalidLength));
+        assert_eq!(
+            assembler.parse_fragment(&[0x83, 0x00]),
+            Err(Error::InvalidLength)
+        );
+        // More data than announced.
+        assert_eq!(
+            assembler.parse_fragment(&[0x83, 0x00, 0x01, 0x12, 0x34]),
+            Err(Error::InvalidLength)
+        );
+        // Longer than controlPointLength.
+        let mut fragment = vec![0x83, 0x00, 0x40];
+        fragment.extend_from_slice(&[0x55; 18]);
+        assert_eq!(
+            assembler.parse_fragment(&fragment),
+            Err(Error::InvalidLength)
+        );
+    }
+}
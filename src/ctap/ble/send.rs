@@ -0,0 +1,178 @@
+// Copyright 2019 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::{CtapBle, Message};
+use alloc::vec::Vec;
+use core::cmp::min;
+
+pub struct BleFragmentIterator(Option<MessageSplitter>);
+
+impl BleFragmentIterator {
+    pub fn new(message: Message, max_fragment_len: usize) -> Option<BleFragmentIterator> {
+        let splitter = MessageSplitter::new(message, max_fragment_len);
+        if splitter.is_some() {
+            Some(BleFragmentIterator(splitter))
+        } else {
+            None
+        }
+    }
+
+    pub fn none() -> BleFragmentIterator {
+        BleFragmentIterator(None)
+    }
+}
+
+impl Iterator for BleFragmentIterator {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Vec<u8>> {
+        match &mut self.0 {
+            Some(splitter) => splitter.next(),
+            None => None,
+        }
+    }
+}
+
+pub struct MessageSplitter {
+    message: Message,
+    max_fragment_len: usize,
+    seq: Option<u8>,
+    i: usize,
+}
+
+impl MessageSplitter {
+    // Try to split this message into an iterator of fragments of at most max_fragment_len bytes.
+    // This fails if the message is too long for the 16-bit length of the frame.
+    pub fn new(message: Message, max_fragment_len: usize) -> Option<MessageSplitter> {
+        if message.payload.len() > CtapBle::MAX_PAYLOAD_LEN {
+            None
+        } else {
+            Some(MessageSplitter {
+                message,
+                max_fragment_len,
+                seq: None,
+                i: 0,
+            })
+        }
+    }
+
+    // Builds a fragment from its header and as many payload bytes as fit.
+    fn next_fragment(&mut self, header: &[u8]) -> Vec<u8> {
+        let data_len = min(
+            self.max_fragment_len - header.len(),
+            self.message.payload.len() - self.i,
+        );
+        let mut fragment = Vec::with_capacity(header.len() + data_len);
+        fragment.extend_from_slice(header);
+        fragment.extend_from_slice(&self.message.payload[self.i..self.i + data_len]);
+        self.i += data_len;
+        fragment
+    }
+}
+
+impl Iterator for MessageSplitter {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Vec<u8>> {
+        let payload_len = self.message.payload.len();
+        match self.seq {
+            None => {
+                // First, send an initialization fragment.
+                self.seq = Some(0);
+                let header = [
+                    self.message.cmd,
+                    (payload_len >> 8) as u8,
+                    payload_len as u8,
+                ];
+                Some(self.next_fragment(&header))
+            }
+            Some(seq) => {
+                // Send the next continuation fragment, if any.
+                if self.i < payload_len {
+                    self.seq = Some((seq + 1) & !CtapBle::TYPE_INIT_BIT);
+                    Some(self.next_fragment(&[seq]))
+                } else {
+                    None
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const MAX_FRAGMENT_LEN: usize = 20;
+
+    fn split(message: Message) -> Vec<Vec<u8>> {
+        BleFragmentIterator::new(message, MAX_FRAGMENT_LEN)
+            .unwrap()
+            .collect()
+    }
+
+    #[test]
+    fn test_empty_payload() {
+        let message = Message {
+            cmd: 0x83,
+            payload: vec![],
+        };
+        assert_eq!(split(message), vec![vec![0x83, 0x00, 0x00]]);
+    }
+
+    #[test]
+    fn test_single_fragment() {
+        let message = Message {
+            cmd: 0x83,
+            payload: vec![0xAA; 17],
+        };
+        let mut expected = vec![0x83, 0x00, 0x11];
+        expected.extend_from_slice(&[0xAA; 17]);
+        assert_eq!(split(message), vec![expected]);
+    }
+
+    #[test]
+    fn test_two_fragments() {
+        let message = Message {
+            cmd: 0x83,
+            payload: vec![0xAA; 18],
+        };
+        let mut expected = vec![0x83, 0x00, 0x12];
+        expected.extend_from_slice(&[0xAA; 17]);
+        assert_eq!(split(message), vec![expected, vec![0x00, 0xAA]]);
+    }
+
+    #[test]
+    fn test_sequence_wraps_around() {
+        let message = Message {
+            cmd: 0x83,
+            payload: vec![0xAA; 17 + 130 * 19],
+        };
+        let fragments = split(message);
+        assert_eq!(fragments.len(), 131);
+        for (i, fragment) in fragments[1..].iter().enumerate() {
+            assert_eq!(fragment.len(), MAX_FRAGMENT_LEN);
+            assert_eq!(fragment[0], (i % 0x80) as u8);
+        }
+    }
+
+    #[test]
+    fn test_payload_too_long() {
+        let message = Message {
+            cmd: 0x83,
+            payload: vec![0xAA; 0x10000],
+        };
+        assert!(BleFragmentIterator::new(message, MAX_FRAGMENT_LEN).is_none());
+    }
+}
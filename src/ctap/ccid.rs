@@ -0,0 +1,462 @@
+// Copyright 2019 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::apdu::ApduSession;
+use super::applet::AppletDispatcher;
+use super::CtapState;
+use crate::env::Env;
+use crate::timer::ClockValue;
+use alloc::vec::Vec;
+#[cfg(feature = "debug_ctap")]
+use core::fmt::Write;
+#[cfg(feature = "debug_ctap")]
+use libtock::console::Console;
+
+// The specification referenced in this file is the USB Device Class Specification for Integrated
+// Circuit(s) Cards Interface Devices (revision 1.1).
+
+// The fields of a CCID message header, section 6.1 and 6.2.
+struct CcidHeader {
+    message_type: u8,
+    slot: u8,
+    seq: u8,
+}
+
+// Implements a CCID reader with a single slot, whose card is the set of registered applets.
+// The reader exchanges complete APDUs, i.e. the descriptor announces the short and extended APDU
+// level exchanges. Each bulk OUT transfer carries one message, and gets one message in reply.
+pub struct CtapCcid<E: Env> {
+    session: ApduSession,
+    dispatcher: AppletDispatcher<E>,
+    powered: bool,
+    // Sequence number of the message being processed, for time extension requests.
+    seq: u8,
+}
+
+impl<E: Env> CtapCcid<E> {
+    const HEADER_LEN: usize = 10;
+
+    // Section 6.1
+    const PC_TO_RDR_ICC_POWER_ON: u8 = 0x62;
+    const PC_TO_RDR_ICC_POWER_OFF: u8 = 0x63;
+    const PC_TO_RDR_GET_SLOT_STATUS: u8 = 0x65;
+    const PC_TO_RDR_XFR_BLOCK: u8 = 0x6F;
+
+    // Section 6.2
+    const RDR_TO_PC_DATA_BLOCK: u8 = 0x80;
+    const RDR_TO_PC_SLOT_STATUS: u8 = 0x81;
+
+    // Section 6.2.6, bits 0 and 1 of bStatus.
+    const ICC_STATUS_ACTIVE: u8 = 0x00;
+    const ICC_STATUS_INACTIVE: u8 = 0x01;
+    // Bits 6 and 7 of bStatus.
+    const COMMAND_STATUS_FAILED: u8 = 0x40;
+    const COMMAND_STATUS_TIME_EXTENSION: u8 = 0x80;
+
+    // Section 6.2.6, bError. Other values give the offset of the invalid field.
+    const ERROR_CMD_NOT_SUPPORTED: u8 = 0x00;
+    const ERROR_BAD_LENGTH: u8 = 0x01;
+    const ERROR_BAD_SLOT: u8 = 0x05;
+    const ERROR_ICC_MUTE: u8 = 0xFE;
+
+    // The answer to reset of the card, ISO 7816-3 section 8.2. It only announces the T=1 protocol:
+    // TS = 3B, T0 = 80, TD1 = 80, TD2 = 01 and the check byte TCK = 01.
+    const ATR: [u8; 5] = [0x3B, 0x80, 0x80, 0x01, 0x01];
+
+    pub fn new(dispatcher: AppletDispatcher<E>) -> CtapCcid<E> {
+        CtapCcid {
+            session: ApduSession::new(),
+            dispatcher,
+            powered: false,
+            seq: 0,
+        }
+    }
+
+    // Process a message received on the bulk OUT endpoint, and returns the message to send on the
+    // bulk IN endpoint. Messages too short to have a header are ignored, with an empty reply.
+    pub fn process_message(
+        &mut self,
+        message: &[u8],
+        clock_value: ClockValue,
+        ctap_state: &mut CtapState<E>,
+    ) -> Vec<u8> {
+        if message.len() < Self::HEADER_LEN {
+            return Vec::new();
+        }
+        let header = CcidHeader {
+            message_type: message[0],
+            slot: message[5],
+            seq: message[6],
+        };
+        self.seq = header.seq;
+        let length = u32::from_le_bytes([message[1], message[2], message[3], message[4]]);
+        let data = &message[Self::HEADER_LEN..];
+
+        #[cfg(feature = "debug_ctap")]
+        writeln!(
+            &mut Console::new(),
+            "Received CCID message: {:02x?}",
+            message
+        )
+        .unwrap();
+
+        if length as usize != data.len() {
+            return self.slot_status(&header, Self::COMMAND_STATUS_FAILED, Self::ERROR_BAD_LENGTH);
+        }
+        // Only slot 0 exists.
+        if header.slot != 0 {
+            return self.slot_status(&header, Self::COMMAND_STATUS_FAILED, Self::ERROR_BAD_SLOT);
+        }
+
+        match header.message_type {
+            // Section 6.1.1
+            Self::PC_TO_RDR_ICC_POWER_ON => {
+                self.power_off();
+                self.powered = true;
+                self.data_block(&header, 0, 0, &Self::ATR)
+            }
+            // Section 6.1.2
+            Self::PC_TO_RDR_ICC_POWER_OFF => {
+                self.power_off();
+                self.slot_status(&header, 0, 0)
+            }
+            // Section 6.1.3
+            Self::PC_TO_RDR_GET_SLOT_STATUS => self.slot_status(&header, 0, 0),
+            // Section 6.1.4
+            Self::PC_TO_RDR_XFR_BLOCK => {
+                if !self.powered {
+                    return self.slot_status(
+                        &header,
+                        Self::COMMAND_STATUS_FAILED,
+                        Self::ERROR_ICC_MUTE,
+                    );
+                }
+                let dispatcher = &mut self.dispatcher;
+                let response = self.session.process_apdu(data, |command| {
+                    dispatcher.process_apdu(command, clock_value, ctap_state)
+                });
+                self.data_block(&header, 0, 0, &response)
+            }
+            _ => self.slot_status(
+                &header,
+                Self::COMMAND_STATUS_FAILED,
+                Self::ERROR_CMD_NOT_SUPPORTED,
+            ),
+        }
+    }
+
+    // Section 6.2.1: asks the host to wait longer for the reply to the current message, for
+    // example while waiting for the user to touch the button. The host timeout is multiplied by
+    // the given factor. U2F requests fail until the user is present instead of waiting, so it is
+    // for the applets to come.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn time_extension(&self, multiplier: u8) -> Vec<u8> {
+        let header = CcidHeader {
+            message_type: Self::PC_TO_RDR_XFR_BLOCK,
+            slot: 0,
+            seq: self.seq,
+        };
+        self.data_block(
+            &header,
+            Self::COMMAND_STATUS_TIME_EXTENSION,
+            multiplier,
+            &[],
+        )
+    }
+
+    fn power_off(&mut self) {
+        self.powered = false;
+        self.session.reset();
+        self.dispatcher.reset();
+    }
+
+    fn icc_status(&self) -> u8 {
+        if self.powered {
+            Self::ICC_STATUS_ACTIVE
+        } else {
+            Self::ICC_STATUS_INACTIVE
+        }
+    }
+
+    // Section 6.2.1
+    fn data_block(
+        &self,
+        header: &CcidHeader,
+        command_status: u8,
+        error: u8,
+        data: &[u8],
+    ) -> Vec<u8> {
+        // The chain parameter is 0, the response is always complete.
+        self.reply(
+            Self::RDR_TO_PC_DATA_BLOCK,
+            header,
+            command_status,
+            error,
+            0,
+            data,
+        )
+    }
+
+    // Section 6.2.2
+    fn slot_status(&self, header: &CcidHeader, command_status: u8, error: u8) -> Vec<u8> {
+        // The clock status is 0, the clock is running.
+        self.reply(
+            Self::RDR_TO_PC_SLOT_STATUS,
+            header,
+            command_status,
+            error,
+            0,
+            &[],
+        )
+    }
+
+    fn reply(
+        &self,
+        message_type: u8,
+        header: &CcidHeader,
+        command_status: u8,
+        error: u8,
+        specific: u8,
+        data: &[u8],
+    ) -> Vec<u8> {
+        let mut reply = Vec::with_capacity(Self::HEADER_LEN + data.len());
+        reply.push(message_type);
+        reply.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        reply.push(header.slot);
+        reply.push(header.seq);
+        reply.push(command_status | self.icc_status());
+        reply.push(error);
+        reply.push(specific);
+        reply.extend_from_slice(data);
+        #[cfg(feature = "debug_ctap")]
+        writeln!(&mut Console::new(), "Sending CCID message: {:02x?}", reply).unwrap();
+        reply
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::apdu::{ApduCommand, ApduResponse};
+    use super::super::applet::Applet;
+    use super::*;
+    use crate::env::host::HostEnv;
+    use alloc::boxed::Box;
+
+    const CLOCK_FREQUENCY_HZ: usize = 32768;
+    const DUMMY_CLOCK_VALUE: ClockValue = ClockValue::new(0, CLOCK_FREQUENCY_HZ);
+
+    // Recorded bulk transfers, as sent by pcscd.
+    const POWER_ON: [u8; 10] = [0x62, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00];
+    const POWER_OFF: [u8; 10] = [0x63, 0x00, 0x00, 0x00, 0x00, 0x00, 0x07, 0x00, 0x00, 0x00];
+    const GET_SLOT_STATUS: [u8; 10] = [0x65, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00];
+
+    struct EchoApplet;
+
+    impl<E: Env> Applet<E> for EchoApplet {
+        fn aid(&self) -> &[u8] {
+            &[0xF0, 0x01, 0x02, 0x03, 0x04]
+        }
+
+        fn select(&mut self) -> ApduResponse {
+            ApduResponse::success(Vec::new())
+        }
+
+        fn process_apdu(
+            &mut self,
+            command: ApduCommand,
+            _clock_value: ClockValue,
+            _ctap_state: &mut CtapState<E>,
+        ) -> ApduResponse {
+            ApduResponse::success(command.data)
+        }
+    }
+
+    fn xfr_block(seq: u8, apdu: &[u8]) -> Vec<u8> {
+        let mut message = vec![0x6F];
+        message.extend_from_slice(&(apdu.len() as u32).to_le_bytes());
+        message.extend_from_slice(&[0x00, seq, 0x00, 0x00, 0x00]);
+        message.extend_from_slice(apdu);
+        message
+    }
+
+    fn data_block(seq: u8, data: &[u8]) -> Vec<u8> {
+        let mut message = vec![0x80];
+        message.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        message.extend_from_slice(&[0x00, seq, 0x00, 0x00, 0x00]);
+        message.extend_from_slice(data);
+        message
+    }
+
+    #[test]
+    fn test_power_on_off() {
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(HostEnv::new(user_immediately_present));
+        let mut ccid = CtapCcid::new(AppletDispatcher::new());
+
+        let reply = ccid.process_message(&GET_SLOT_STATUS, DUMMY_CLOCK_VALUE, &mut ctap_state);
+        assert_eq!(
+            reply,
+            vec![0x81, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x01, 0x00, 0x00]
+        );
+        let reply = ccid.process_message(&POWER_ON, DUMMY_CLOCK_VALUE, &mut ctap_state);
+        assert_eq!(
+            reply,
+            vec![
+                0x80, 0x05, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x3B, 0x80, 0x80, 0x01,
+                0x01
+            ]
+        );
+        let reply = ccid.process_message(&GET_SLOT_STATUS, DUMMY_CLOCK_VALUE, &mut ctap_state);
+        assert_eq!(
+            reply,
+            vec![0x81, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00]
+        );
+        let reply = ccid.process_message(&POWER_OFF, DUMMY_CLOCK_VALUE, &mut ctap_state);
+        assert_eq!(
+            reply,
+            vec![0x81, 0x00, 0x00, 0x00, 0x00, 0x00, 0x07, 0x01, 0x00, 0x00]
+        );
+    }
+
+    #[test]
+    fn test_xfr_block() {
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(HostEnv::new(user_immediately_present));
+        let mut dispatcher = AppletDispatcher::new();
+        dispatcher.register(Box::new(EchoApplet));
+        let mut ccid = CtapCcid::new(dispatcher);
+
+        ccid.process_message(&POWER_ON, DUMMY_CLOCK_VALUE, &mut ctap_state);
+        let select = [0x00, 0xA4, 0x04, 0x00, 0x05, 0xF0, 0x01, 0x02, 0x03, 0x04];
+        let reply =
+            ccid.process_message(&xfr_block(3, &select), DUMMY_CLOCK_VALUE, &mut ctap_state);
+        assert_eq!(reply, data_block(3, &[0x90, 0x00]));
+        let echo = [0x00, 0x01, 0x00, 0x00, 0x02, 0x12, 0x34];
+        let reply = ccid.process_message(&xfr_block(4, &echo), DUMMY_CLOCK_VALUE, &mut ctap_state);
+        assert_eq!(reply, data_block(4, &[0x12, 0x34, 0x90, 0x00]));
+        let reply = ccid.process_message(&xfr_block(5, &echo), DUMMY_CLOCK_VALUE, &mut ctap_state);
+        assert_eq!(reply, data_block(5, &[0x12, 0x34, 0x90, 0x00]));
+
+        // Powering the card off deselects the applet.
+        ccid.process_message(&POWER_OFF, DUMMY_CLOCK_VALUE, &mut ctap_state);
+        ccid.process_message(&POWER_ON, DUMMY_CLOCK_VALUE, &mut ctap_state);
+        let reply = ccid.process_message(&xfr_block(8, &echo), DUMMY_CLOCK_VALUE, &mut ctap_state);
+        assert_eq!(reply, data_block(8, &[0x69, 0x85]));
+    }
+
+    #[test]
+    fn test_xfr_block_powered_off() {
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(HostEnv::new(user_immediately_present));
+        let mut ccid = CtapCcid::new(AppletDispatcher::new());
+
+        let reply = ccid.process_message(
+            &xfr_block(1, &[0x00, 0xA4, 0x04, 0x00]),
+            DUMMY_CLOCK_VALUE,
+            &mut ctap_state,
+        );
+        assert_eq!(
+            reply,
+            vec![0x81, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x41, 0xFE, 0x00]
+        );
+    }
+
+    #[test]
+    fn test_invalid_messages() {
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(HostEnv::new(user_immediately_present));
+        let mut ccid = CtapCcid::new(AppletDispatcher::new());
+
+        assert!(ccid
+            .process_message(&[0x65, 0x00], DUMMY_CLOCK_VALUE, &mut ctap_state)
+            .is_empty());
+        // The length doesn't match the data.
+        let reply = ccid.process_message(
+            &[
+                0x6F, 0x02, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00,
+            ],
+            DUMMY_CLOCK_VALUE,
+            &mut ctap_state,
+        );
+        assert_eq!(
+            reply,
+            vec![0x81, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x41, 0x01, 0x00]
+        );
+        // Slot 1 doesn't exist.
+        let reply = ccid.process_message(
+            &[0x65, 0x00, 0x00, 0x00, 0x00, 0x01, 0x02, 0x00, 0x00, 0x00],
+            DUMMY_CLOCK_VALUE,
+            &mut ctap_state,
+        );
+        assert_eq!(
+            reply,
+            vec![0x81, 0x00, 0x00, 0x00, 0x00, 0x01, 0x02, 0x41, 0x05, 0x00]
+        );
+        // PC_to_RDR_GetParameters is not supported.
+        let reply = ccid.process_message(
+            &[0x6C, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00],
+            DUMMY_CLOCK_VALUE,
+            &mut ctap_state,
+        );
+        assert_eq!(
+            reply,
+            vec![0x81, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, 0x41, 0x00, 0x00]
+        );
+    }
+
+    #[test]
+    fn test_time_extension() {
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(HostEnv::new(user_immediately_present));
+        let mut ccid = CtapCcid::new(AppletDispatcher::new());
+
+        ccid.process_message(&POWER_ON, DUMMY_CLOCK_VALUE, &mut ctap_state);
+        ccid.process_message(
+            &xfr_block(9, &[0x00, 0x01, 0x00, 0x00]),
+            DUMMY_CLOCK_VALUE,
+            &mut ctap_state,
+        );
+        assert_eq!(
+            ccid.time_extension(1),
+            vec![0x80, 0x00, 0x00, 0x00, 0x00, 0x00, 0x09, 0x80, 0x01, 0x00]
+        );
+    }
+
+    #[cfg(feature = "with_ctap1")]
+    #[test]
+    fn test_u2f_over_ccid() {
+        use super::super::applet::U2fApplet;
+        use super::super::U2F_VERSION_STRING;
+        let dummy_user_presence = |_| panic!("Unexpected user presence check in CTAP1");
+        let mut ctap_state = CtapState::new(HostEnv::new(dummy_user_presence));
+        let mut dispatcher = AppletDispatcher::new();
+        dispatcher.register(Box::new(U2fApplet));
+        let mut ccid = CtapCcid::new(dispatcher);
+
+        ccid.process_message(&POWER_ON, DUMMY_CLOCK_VALUE, &mut ctap_state);
+        let select = [
+            0x00, 0xA4, 0x04, 0x00, 0x08, 0xA0, 0x00, 0x00, 0x06, 0x47, 0x2F, 0x00, 0x01,
+        ];
+        let reply =
+            ccid.process_message(&xfr_block(2, &select), DUMMY_CLOCK_VALUE, &mut ctap_state);
+        let mut expected = U2F_VERSION_STRING.as_bytes().to_vec();
+        expected.extend_from_slice(&[0x90, 0x00]);
+        assert_eq!(reply, data_block(2, &expected));
+        // An extended length U2F_VERSION, as in the CTAPHID flavour.
+        let version = [0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00];
+        let reply =
+            ccid.process_message(&xfr_block(3, &version), DUMMY_CLOCK_VALUE, &mut ctap_state);
+        assert_eq!(reply, data_block(3, &expected));
+    }
+}
@@ -14,9 +14,9 @@
 
 use super::data_formats::{
     ok_or_missing, read_array, read_byte_string, read_map, read_text_string, read_unsigned,
-    ClientPinSubCommand, CoseKey, Extensions, GetAssertionOptions, MakeCredentialOptions,
-    PublicKeyCredentialDescriptor, PublicKeyCredentialParameter, PublicKeyCredentialRpEntity,
-    PublicKeyCredentialUserEntity,
+    AttestationStatementFormat, ClientPinSubCommand, CoseKey, Extensions, GetAssertionOptions,
+    MakeCredentialOptions, PublicKeyCredentialDescriptor, PublicKeyCredentialParameter,
+    PublicKeyCredentialRpEntity, PublicKeyCredentialUserEntity,
 };
 use super::status_code::Ctap2StatusCode;
 use alloc::string::String;
@@ -37,6 +37,8 @@ pub enum Command {
     AuthenticatorClientPin(AuthenticatorClientPinParameters),
     AuthenticatorReset,
     AuthenticatorGetNextAssertion,
+    // The command byte is kept, as vendors can use all values in the reserved range.
+    AuthenticatorVendor(u8, AuthenticatorVendorParameters),
     // TODO(kaczmarczyck) implement FIDO 2.1 commands (see below consts)
 }
 
@@ -101,9 +103,46 @@ impl Command {
                 // Parameters are ignored.
                 Ok(Command::AuthenticatorGetNextAssertion)
             }
+            Command::AUTHENTICATOR_VENDOR_FIRST..=Command::AUTHENTICATOR_VENDOR_LAST => {
+                let decoded_cbor = cbor::read(&bytes[1..])?;
+                Ok(Command::AuthenticatorVendor(
+                    command_value,
+                    AuthenticatorVendorParameters::try_from(decoded_cbor)?,
+                ))
+            }
             _ => Err(Ctap2StatusCode::CTAP1_ERR_INVALID_COMMAND),
         }
     }
+
+    // This is the inverse of deserialize, for clients of an authenticator.
+    pub fn serialize(self) -> Result<Vec<u8>, Ctap2StatusCode> {
+        let (command_value, parameters): (u8, Option<cbor::Value>) = match self {
+            Command::AuthenticatorMakeCredential(params) => {
+                (Command::AUTHENTICATOR_MAKE_CREDENTIAL, Some(params.into()))
+            }
+            Command::AuthenticatorGetAssertion(params) => {
+                (Command::AUTHENTICATOR_GET_ASSERTION, Some(params.into()))
+            }
+            Command::AuthenticatorGetInfo => (Command::AUTHENTICATOR_GET_INFO, None),
+            Command::AuthenticatorClientPin(params) => {
+                (Command::AUTHENTICATOR_CLIENT_PIN, Some(params.into()))
+            }
+            Command::AuthenticatorReset => (Command::AUTHENTICATOR_RESET, None),
+            Command::AuthenticatorGetNextAssertion => {
+                (Command::AUTHENTICATOR_GET_NEXT_ASSERTION, None)
+            }
+            Command::AuthenticatorVendor(command_value, params) => {
+                (command_value, Some(params.into()))
+            }
+        };
+        let mut bytes = vec![command_value];
+        if let Some(value) = parameters {
+            if !cbor::write(value, &mut bytes) {
+                return Err(Ctap2StatusCode::CTAP2_ERR_INVALID_CBOR);
+            }
+        }
+        Ok(bytes)
+    }
 }
 
 #[cfg_attr(any(test, feature = "debug_ctap"), derive(Debug, PartialEq))]
@@ -118,6 +157,8 @@ pub struct AuthenticatorMakeCredentialParameters {
     pub options: MakeCredentialOptions,
     pub pin_uv_auth_param: Option<Vec<u8>>,
     pub pin_uv_auth_protocol: Option<u64>,
+    // Added in CTAP 2.2, ordered from most to least preferred.
+    pub attestation_formats_preference: Option<Vec<AttestationStatementFormat>>,
 }
 
 impl TryFrom<cbor::Value> for AuthenticatorMakeCredentialParameters {
@@ -178,6 +219,18 @@ impl TryFrom<cbor::Value> for AuthenticatorMakeCredentialParameters {
             .map(read_unsigned)
             .transpose()?;
 
+        // Enterprise attestation (0x0A) is not supported. See the CTAP specification
+        // (version 2.2) section 6.1 for attestationFormatsPreference.
+        let attestation_formats_preference = param_map
+            .get(&cbor_unsigned!(0x0B))
+            .map(|entry| {
+                read_array(entry)?
+                    .iter()
+                    .map(AttestationStatementFormat::try_from)
+                    .collect::<Result<Vec<AttestationStatementFormat>, Ctap2StatusCode>>()
+            })
+            .transpose()?;
+
         Ok(AuthenticatorMakeCredentialParameters {
             client_data_hash,
             rp,
@@ -188,10 +241,41 @@ impl TryFrom<cbor::Value> for AuthenticatorMakeCredentialParameters {
             options,
             pin_uv_auth_param,
             pin_uv_auth_protocol,
+            attestation_formats_preference,
         })
     }
 }
 
+impl From<AuthenticatorMakeCredentialParameters> for cbor::Value {
+    fn from(make_credential_params: AuthenticatorMakeCredentialParameters) -> Self {
+        let AuthenticatorMakeCredentialParameters {
+            client_data_hash,
+            rp,
+            user,
+            pub_key_cred_params,
+            exclude_list,
+            extensions,
+            options,
+            pin_uv_auth_param,
+            pin_uv_auth_protocol,
+            attestation_formats_preference,
+        } = make_credential_params;
+
+        cbor_map_options! {
+            1 => client_data_hash,
+            2 => rp,
+            3 => user,
+            4 => cbor_array_vec!(pub_key_cred_params),
+            5 => exclude_list.map(|vec| cbor_array_vec!(vec)),
+            6 => extensions,
+            7 => options,
+            8 => pin_uv_auth_param,
+            9 => pin_uv_auth_protocol,
+            0x0B => attestation_formats_preference.map(|vec| cbor_array_vec!(vec)),
+        }
+    }
+}
+
 #[cfg_attr(any(test, feature = "debug_ctap"), derive(Debug, PartialEq))]
 pub struct AuthenticatorGetAssertionParameters {
     pub rp_id: String,
@@ -262,6 +346,30 @@ impl TryFrom<cbor::Value> for AuthenticatorGetAssertionParameters {
     }
 }
 
+impl From<AuthenticatorGetAssertionParameters> for cbor::Value {
+    fn from(get_assertion_params: AuthenticatorGetAssertionParameters) -> Self {
+        let AuthenticatorGetAssertionParameters {
+            rp_id,
+            client_data_hash,
+            allow_list,
+            extensions,
+            options,
+            pin_uv_auth_param,
+            pin_uv_auth_protocol,
+        } = get_assertion_params;
+
+        cbor_map_options! {
+            1 => rp_id,
+            2 => client_data_hash,
+            3 => allow_list.map(|vec| cbor_array_vec!(vec)),
+            4 => extensions,
+            5 => options,
+            6 => pin_uv_auth_param,
+            7 => pin_uv_auth_protocol,
+        }
+    }
+}
+
 #[cfg_attr(any(test, feature = "debug_ctap"), derive(Debug, PartialEq))]
 pub struct AuthenticatorClientPinParameters {
     pub pin_protocol: u64,
@@ -315,6 +423,87 @@ impl TryFrom<cbor::Value> for AuthenticatorClientPinParameters {
     }
 }
 
+impl From<AuthenticatorClientPinParameters> for cbor::Value {
+    fn from(client_pin_params: AuthenticatorClientPinParameters) -> Self {
+        let AuthenticatorClientPinParameters {
+            pin_protocol,
+            sub_command,
+            key_agreement,
+            pin_auth,
+            new_pin_enc,
+            pin_hash_enc,
+        } = client_pin_params;
+
+        cbor_map_options! {
+            1 => pin_protocol,
+            2 => sub_command,
+            3 => key_agreement,
+            4 => pin_auth,
+            5 => new_pin_enc,
+            6 => pin_hash_enc,
+        }
+    }
+}
+
+// Vendor commands reuse the structure of authenticatorConfig in CTAP 2.1, so that firmware
+// modules only define their subcommands and the parameters of those.
+#[cfg_attr(any(test, feature = "debug_ctap"), derive(Debug, PartialEq))]
+pub struct AuthenticatorVendorParameters {
+    pub sub_command: u8,
+    pub sub_command_params: Option<cbor::Value>,
+    pub pin_uv_auth_protocol: Option<u64>,
+    pub pin_uv_auth_param: Option<Vec<u8>>,
+}
+
+impl TryFrom<cbor::Value> for AuthenticatorVendorParameters {
+    type Error = Ctap2StatusCode;
+
+    fn try_from(cbor_value: cbor::Value) -> Result<Self, Ctap2StatusCode> {
+        let param_map = read_map(&cbor_value)?;
+
+        let sub_command = read_unsigned(ok_or_missing(param_map.get(&cbor_unsigned!(1)))?)?;
+        let sub_command =
+            u8::try_from(sub_command).map_err(|_| Ctap2StatusCode::CTAP2_ERR_INVALID_SUBCOMMAND)?;
+
+        let sub_command_params = param_map.get(&cbor_unsigned!(2)).cloned();
+
+        let pin_uv_auth_protocol = param_map
+            .get(&cbor_unsigned!(3))
+            .map(read_unsigned)
+            .transpose()?;
+
+        let pin_uv_auth_param = param_map
+            .get(&cbor_unsigned!(4))
+            .map(read_byte_string)
+            .transpose()?;
+
+        Ok(AuthenticatorVendorParameters {
+            sub_command,
+            sub_command_params,
+            pin_uv_auth_protocol,
+            pin_uv_auth_param,
+        })
+    }
+}
+
+impl From<AuthenticatorVendorParameters> for cbor::Value {
+    fn from(vendor_params: AuthenticatorVendorParameters) -> Self {
+        let AuthenticatorVendorParameters {
+            sub_command,
+            sub_command_params,
+            pin_uv_auth_protocol,
+            pin_uv_auth_param,
+        } = vendor_params;
+
+        cbor_map_options! {
+            1 => sub_command as u64,
+            2 => sub_command_params,
+            3 => pin_uv_auth_protocol,
+            4 => pin_uv_auth_param,
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::super::data_formats::{
@@ -344,6 +533,7 @@ mod test {
             5 => cbor_array![],
             8 => vec![0x12, 0x34],
             9 => 1,
+            0x0B => cbor_array!["fido-u2f", "tpm", "none"],
         };
         let returned_make_credential_parameters =
             AuthenticatorMakeCredentialParameters::try_from(cbor_value).unwrap();
@@ -377,6 +567,11 @@ mod test {
             options,
             pin_uv_auth_param: Some(vec![0x12, 0x34]),
             pin_uv_auth_protocol: Some(1),
+            attestation_formats_preference: Some(vec![
+                AttestationStatementFormat::FidoU2f,
+                AttestationStatementFormat::Unknown,
+                AttestationStatementFormat::None,
+            ]),
         };
 
         assert_eq!(
@@ -480,4 +675,149 @@ mod test {
         let command = Command::deserialize(&cbor_bytes);
         assert_eq!(command, Ok(Command::AuthenticatorGetNextAssertion));
     }
+
+    #[test]
+    fn test_deserialize_vendor() {
+        let mut cbor_bytes = vec![0x41];
+        assert!(cbor::write(
+            cbor_map! {
+                1 => 0x02,
+                2 => cbor_map! { 1 => "diagnostics" },
+                3 => 1,
+                4 => vec![0x12, 0x34],
+            },
+            &mut cbor_bytes
+        ));
+        let expected_vendor_parameters = AuthenticatorVendorParameters {
+            sub_command: 0x02,
+            sub_command_params: Some(cbor_map! { 1 => "diagnostics" }),
+            pin_uv_auth_protocol: Some(1),
+            pin_uv_auth_param: Some(vec![0x12, 0x34]),
+        };
+        assert_eq!(
+            Command::deserialize(&cbor_bytes),
+            Ok(Command::AuthenticatorVendor(
+                0x41,
+                expected_vendor_parameters
+            ))
+        );
+
+        // The vendor range ends at 0xBF, the following bytes are reserved for CTAP 2.1.
+        cbor_bytes[0] = 0xC0;
+        assert_eq!(
+            Command::deserialize(&cbor_bytes),
+            Err(Ctap2StatusCode::CTAP1_ERR_INVALID_COMMAND)
+        );
+    }
+
+    #[test]
+    fn test_from_cbor_vendor_parameters_invalid_sub_command() {
+        assert_eq!(
+            AuthenticatorVendorParameters::try_from(cbor_map! {}),
+            Err(Ctap2StatusCode::CTAP2_ERR_MISSING_PARAMETER)
+        );
+        assert_eq!(
+            AuthenticatorVendorParameters::try_from(cbor_map! { 1 => 0x100 }),
+            Err(Ctap2StatusCode::CTAP2_ERR_INVALID_SUBCOMMAND)
+        );
+    }
+
+    fn assert_serialize_round_trip(create_command: impl Fn() -> Command) {
+        let command_bytes = create_command().serialize().unwrap();
+        assert_eq!(Command::deserialize(&command_bytes), Ok(create_command()));
+    }
+
+    #[test]
+    fn test_serialize_make_credential() {
+        assert_serialize_round_trip(|| {
+            Command::AuthenticatorMakeCredential(AuthenticatorMakeCredentialParameters {
+                client_data_hash: vec![0xCD; 32],
+                rp: PublicKeyCredentialRpEntity {
+                    rp_id: "example.com".to_string(),
+                    rp_name: None,
+                    rp_icon: None,
+                },
+                user: PublicKeyCredentialUserEntity {
+                    user_id: vec![0x1D, 0x1D, 0x1D, 0x1D],
+                    user_name: Some("foo".to_string()),
+                    user_display_name: None,
+                    user_icon: None,
+                },
+                pub_key_cred_params: vec![ES256_CRED_PARAM],
+                exclude_list: Some(vec![PublicKeyCredentialDescriptor {
+                    key_type: PublicKeyCredentialType::PublicKey,
+                    key_id: vec![0x2D, 0x2D, 0x2D, 0x2D],
+                    transports: None,
+                }]),
+                extensions: None,
+                options: MakeCredentialOptions {
+                    rk: true,
+                    uv: false,
+                },
+                pin_uv_auth_param: Some(vec![0x12, 0x34]),
+                pin_uv_auth_protocol: Some(1),
+                attestation_formats_preference: Some(vec![
+                    AttestationStatementFormat::None,
+                    AttestationStatementFormat::Packed,
+                ]),
+            })
+        });
+    }
+
+    #[test]
+    fn test_serialize_get_assertion() {
+        assert_serialize_round_trip(|| {
+            Command::AuthenticatorGetAssertion(AuthenticatorGetAssertionParameters {
+                rp_id: "example.com".to_string(),
+                client_data_hash: vec![0xCD; 32],
+                allow_list: None,
+                extensions: None,
+                options: GetAssertionOptions {
+                    up: false,
+                    uv: false,
+                },
+                pin_uv_auth_param: None,
+                pin_uv_auth_protocol: None,
+            })
+        });
+    }
+
+    #[test]
+    fn test_serialize_client_pin() {
+        assert_serialize_round_trip(|| {
+            Command::AuthenticatorClientPin(AuthenticatorClientPinParameters {
+                pin_protocol: 1,
+                sub_command: ClientPinSubCommand::ChangePin,
+                key_agreement: Some(CoseKey(BTreeMap::new())),
+                pin_auth: Some(vec![0xBB]),
+                new_pin_enc: Some(vec![0xCC]),
+                pin_hash_enc: Some(vec![0xDD]),
+            })
+        });
+    }
+
+    #[test]
+    fn test_serialize_vendor() {
+        assert_serialize_round_trip(|| {
+            Command::AuthenticatorVendor(
+                Command::AUTHENTICATOR_VENDOR_LAST,
+                AuthenticatorVendorParameters {
+                    sub_command: 0x01,
+                    sub_command_params: None,
+                    pin_uv_auth_protocol: None,
+                    pin_uv_auth_param: None,
+                },
+            )
+        });
+    }
+
+    #[test]
+    fn test_serialize_without_parameters() {
+        assert_eq!(
+            Command::AuthenticatorGetInfo.serialize(),
+            Ok(vec![Command::AUTHENTICATOR_GET_INFO])
+        );
+        assert_serialize_round_trip(|| Command::AuthenticatorReset);
+        assert_serialize_round_trip(|| Command::AuthenticatorGetNextAssertion);
+    }
 }
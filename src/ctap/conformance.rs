@@ -0,0 +1,1512 @@
+// Copyright 2019 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Conformance scenarios for sequences of CTAP commands, modelled on the FIDO conformance test plan.
+//
+// A scenario is a table of steps, each with its expected status. The steps are played by a
+// platform that encodes commands like a client would and sends them through process_command. Every
+// successful response is then checked: flags, signature counter, credential IDs, signatures and
+// hmac-secret outputs. Credentials are referred to by the order of their creation in the scenario.
+
+use super::command::{
+    AuthenticatorClientPinParameters, AuthenticatorGetAssertionParameters,
+    AuthenticatorMakeCredentialParameters, Command,
+};
+#[cfg(feature = "with_ctap1")]
+use super::ctap1::Ctap1Command;
+use super::data_formats::{
+    read_byte_string, read_map, AttestationStatement, AttestationStatementFormat,
+    ClientPinSubCommand, CoseKey, Extensions, GetAssertionOptions, MakeCredentialOptions,
+    PublicKeyCredentialDescriptor, PublicKeyCredentialRpEntity, PublicKeyCredentialType,
+    PublicKeyCredentialUserEntity, SignatureAlgorithm,
+};
+use super::hid::ChannelID;
+use super::key_material::AAGUID;
+#[cfg(feature = "with_ctap1")]
+use super::key_material::{ATTESTATION_CERTIFICATE, ATTESTATION_PRIVATE_KEY};
+use super::response::{
+    AuthenticatorClientPinResponse, AuthenticatorGetAssertionResponse,
+    AuthenticatorGetInfoResponse, AuthenticatorMakeCredentialResponse,
+};
+use super::status_code::Ctap2StatusCode;
+use super::storage::new_ram_storage;
+use super::{
+    CtapState, AT_FLAG, ED_FLAG, ENCRYPTED_CREDENTIAL_ID_SIZE, ES256_CRED_PARAM, PIN_AUTH_LENGTH,
+    PIN_PADDED_LENGTH, RESET_TIMEOUT_MS, UP_FLAG, USE_SIGNATURE_COUNTER, UV_FLAG,
+};
+use crate::env::host::HostEnv;
+#[cfg(feature = "with_ctap1")]
+use crate::env::Env;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use byteorder::{BigEndian, ByteOrder};
+use core::convert::TryFrom;
+use crypto::aes256::{DecryptionKey, EncryptionKey};
+use crypto::cbc::{cbc_decrypt, cbc_encrypt};
+use crypto::hmac::hmac_256;
+use crypto::rng256::{Rng256, ThreadRng256};
+use crypto::sha256::Sha256;
+use crypto::{ecdh, ecdsa, Hash256};
+use ctap2::embedded_flash::BufferStorage;
+use libtock::timer::Timestamp;
+
+// User presence is granted immediately, so the channel is irrelevant.
+const CHANNEL_ID: ChannelID = [0x12, 0x34, 0x56, 0x78];
+// The length of the COSE encoding of a P-256 public key, in the attested credential data.
+const COSE_KEY_LENGTH: usize = 77;
+
+type UserPresence = fn(ChannelID) -> Result<(), Ctap2StatusCode>;
+type TestEnv = HostEnv<UserPresence>;
+
+fn user_immediately_present(_: ChannelID) -> Result<(), Ctap2StatusCode> {
+    Ok(())
+}
+
+fn boot(storage: BufferStorage) -> CtapState<TestEnv> {
+    CtapState::new(HostEnv::with_storage(
+        user_immediately_present as UserPresence,
+        storage,
+    ))
+}
+
+#[derive(Clone, Copy)]
+struct MakeCredentialStep {
+    rp_id: &'static str,
+    rk: bool,
+    // Sends a pinAuth computed with the last pinToken.
+    uv: bool,
+    hmac_secret: bool,
+    exclude_list: &'static [usize],
+    // An empty preference is not sent, so that the default format is used.
+    attestation_formats: &'static [AttestationStatementFormat],
+}
+
+const MAKE_CREDENTIAL: MakeCredentialStep = MakeCredentialStep {
+    rp_id: "example.com",
+    rk: true,
+    uv: false,
+    hmac_secret: false,
+    exclude_list: &[],
+    attestation_formats: &[],
+};
+
+#[derive(Clone, Copy)]
+struct GetAssertionStep {
+    rp_id: &'static str,
+    // An empty list is not sent, so that resident credentials are used.
+    allow_list: &'static [usize],
+    up: bool,
+    // Sends a pinAuth computed with the last pinToken.
+    uv: bool,
+    // Requests the hmac-secret output of a salt made of this byte.
+    hmac_salt: Option<u8>,
+}
+
+const GET_ASSERTION: GetAssertionStep = GetAssertionStep {
+    rp_id: "example.com",
+    allow_list: &[],
+    up: true,
+    uv: false,
+    hmac_salt: None,
+};
+
+#[derive(Clone, Copy)]
+enum Step {
+    SetPin(&'static str),
+    ChangePin(&'static str, &'static str),
+    // Keeps the pinToken for the pinAuth of later steps.
+    GetPinToken(&'static str),
+    CheckPinRetries(u64),
+    // Checks the clientPin option of authenticatorGetInfo.
+    CheckClientPin(bool),
+    MakeCredential(MakeCredentialStep),
+    GetAssertion(GetAssertionStep),
+    Reset,
+    // Powers the authenticator off and on, keeping the persistent store.
+    Reboot,
+    // Lets the given number of milliseconds pass since boot.
+    Wait(isize),
+    // The U2F steps are expected to succeed.
+    #[cfg(feature = "with_ctap1")]
+    U2fRegister(&'static str),
+    #[cfg(feature = "with_ctap1")]
+    U2fAuthenticate(&'static str, usize),
+}
+
+struct Scenario {
+    name: &'static str,
+    steps: &'static [(Step, Result<(), Ctap2StatusCode>)],
+}
+
+// AES-256-CBC with a zero IV, as used by the PIN protocol.
+fn aes256_cbc(key: &[u8; 32], data: &[u8], encrypt: bool) -> Vec<u8> {
+    let mut blocks: Vec<[u8; 16]> = data
+        .chunks(16)
+        .map(|chunk| *array_ref!(chunk, 0, 16))
+        .collect();
+    let aes_enc_key = EncryptionKey::new(key);
+    if encrypt {
+        cbc_encrypt(&aes_enc_key, [0; 16], &mut blocks);
+    } else {
+        cbc_decrypt(&DecryptionKey::new(&aes_enc_key), [0; 16], &mut blocks);
+    }
+    blocks.concat()
+}
+
+fn authenticate(key: &[u8], message: &[u8]) -> Vec<u8> {
+    hmac_256::<Sha256>(key, message)[..PIN_AUTH_LENGTH].to_vec()
+}
+
+fn client_pin_parameters(sub_command: ClientPinSubCommand) -> AuthenticatorClientPinParameters {
+    AuthenticatorClientPinParameters {
+        pin_protocol: CtapState::<TestEnv>::PIN_PROTOCOL_VERSION,
+        sub_command,
+        key_agreement: None,
+        pin_auth: None,
+        new_pin_enc: None,
+        pin_hash_enc: None,
+    }
+}
+
+// The public key of the attested credential data.
+fn decode_public_key(cose_key: &[u8]) -> ecdsa::PubKey {
+    let cose_key = CoseKey::try_from(&cbor::read(cose_key).unwrap()).unwrap();
+    let public_key = ecdh::PubKey::try_from(cose_key).unwrap();
+    let mut x = [0; ecdh::NBYTES];
+    let mut y = [0; ecdh::NBYTES];
+    public_key.to_coordinates(&mut x, &mut y);
+    let mut uncompressed = vec![0x04];
+    uncompressed.extend(&x);
+    uncompressed.extend(&y);
+    ecdsa::PubKey::from_bytes_uncompressed(&uncompressed).unwrap()
+}
+
+// Checks an ASN.1 DER encoded ECDSA signature, a sequence of the integers r and s.
+pub fn verify_signature(public_key: &ecdsa::PubKey, message: &[u8], signature: &[u8]) {
+    assert_eq!(signature[0], 0x30);
+    assert_eq!(signature[1] as usize, signature.len() - 2);
+    let mut signature_bytes = [0; 64];
+    let mut offset = 2;
+    for integer_end in [32, 64].iter() {
+        assert_eq!(signature[offset], 0x02);
+        let length = signature[offset + 1] as usize;
+        let integer = &signature[offset + 2..offset + 2 + length];
+        // The encoding may add a zero byte to keep the integer positive.
+        let integer = &integer[integer.len().saturating_sub(32)..];
+        signature_bytes[integer_end - integer.len()..*integer_end].copy_from_slice(integer);
+        offset += 2 + length;
+    }
+    assert_eq!(offset, signature.len());
+    let signature = ecdsa::Signature::from_bytes(&signature_bytes).unwrap();
+    assert!(public_key.verify_vartime::<Sha256>(message, &signature));
+}
+
+// A credential created by a step of the scenario.
+struct Credential {
+    rp_id: &'static str,
+    id: Vec<u8>,
+    user_id: Vec<u8>,
+    public_key: ecdsa::PubKey,
+    resident: bool,
+}
+
+// Plays the steps of a scenario, checking successful responses as it goes.
+struct Platform {
+    // Only empty while rebooting.
+    ctap_state: Option<CtapState<TestEnv>>,
+    rng: ThreadRng256,
+    pin_token: Vec<u8>,
+    credentials: Vec<Credential>,
+    // The decrypted hmac-secret outputs, by credential and salt.
+    hmac_secret_outputs: BTreeMap<(usize, u8), Vec<u8>>,
+    // The last signature counter seen, by credential.
+    signature_counters: BTreeMap<usize, u32>,
+}
+
+impl Platform {
+    fn new() -> Platform {
+        Platform {
+            ctap_state: Some(boot(new_ram_storage())),
+            rng: ThreadRng256 {},
+            pin_token: vec![0; 32],
+            credentials: vec![],
+            hmac_secret_outputs: BTreeMap::new(),
+            signature_counters: BTreeMap::new(),
+        }
+    }
+
+    fn ctap_state(&mut self) -> &mut CtapState<TestEnv> {
+        self.ctap_state.as_mut().unwrap()
+    }
+
+    fn play(&mut self, step: Step) -> Result<(), u8> {
+        match step {
+            Step::SetPin(pin) => self.set_pin(pin),
+            Step::ChangePin(pin, new_pin) => self.change_pin(pin, new_pin),
+            Step::GetPinToken(pin) => self.get_pin_token(pin),
+            Step::CheckPinRetries(retries) => {
+                let response =
+                    self.client_pin(client_pin_parameters(ClientPinSubCommand::GetPinRetries))?;
+                assert_eq!(response.retries, Some(retries));
+                Ok(())
+            }
+            Step::CheckClientPin(client_pin) => {
+                let response = self.send(Command::AuthenticatorGetInfo)?.unwrap();
+                let response = AuthenticatorGetInfoResponse::try_from(response).unwrap();
+                assert_eq!(
+                    response.options.unwrap().get("clientPin"),
+                    Some(&client_pin)
+                );
+                Ok(())
+            }
+            Step::MakeCredential(step) => self.make_credential(step),
+            Step::GetAssertion(step) => self.get_assertion(step),
+            Step::Reset => {
+                self.send(Command::AuthenticatorReset)?;
+                self.signature_counters.clear();
+                Ok(())
+            }
+            Step::Reboot => {
+                let CtapState {
+                    persistent_store, ..
+                } = self.ctap_state.take().unwrap();
+                self.ctap_state = Some(boot(persistent_store.into_storage()));
+                Ok(())
+            }
+            Step::Wait(ms) => {
+                self.ctap_state()
+                    .check_disable_reset(Timestamp::<isize>::from_ms(ms));
+                Ok(())
+            }
+            #[cfg(feature = "with_ctap1")]
+            Step::U2fRegister(rp_id) => {
+                self.u2f_register(rp_id);
+                Ok(())
+            }
+            #[cfg(feature = "with_ctap1")]
+            Step::U2fAuthenticate(rp_id, credential) => {
+                self.u2f_authenticate(rp_id, credential);
+                Ok(())
+            }
+        }
+    }
+
+    // Sends a command the way a transport passes it, and returns the error as its status byte.
+    fn send(&mut self, command: Command) -> Result<Option<cbor::Value>, u8> {
+        let command_cbor = command.serialize().unwrap();
+        let response = self.ctap_state().process_command(&command_cbor, CHANNEL_ID);
+        if response[0] != Ctap2StatusCode::CTAP2_OK as u8 {
+            assert_eq!(response.len(), 1);
+            return Err(response[0]);
+        }
+        if response.len() == 1 {
+            Ok(None)
+        } else {
+            Ok(Some(cbor::read(&response[1..]).unwrap()))
+        }
+    }
+
+    fn client_pin(
+        &mut self,
+        parameters: AuthenticatorClientPinParameters,
+    ) -> Result<AuthenticatorClientPinResponse, u8> {
+        match self.send(Command::AuthenticatorClientPin(parameters))? {
+            Some(response) => Ok(AuthenticatorClientPinResponse::try_from(response).unwrap()),
+            None => Ok(AuthenticatorClientPinResponse {
+                key_agreement: None,
+                pin_token: None,
+                retries: None,
+            }),
+        }
+    }
+
+    // Returns the platform key and the shared secret, see the CTAP specification (version
+    // 20190130) section 5.5.4.
+    fn key_agreement(&mut self) -> Result<(CoseKey, [u8; 32]), u8> {
+        let response =
+            self.client_pin(client_pin_parameters(ClientPinSubCommand::GetKeyAgreement))?;
+        let authenticator_key = ecdh::PubKey::try_from(response.key_agreement.unwrap()).unwrap();
+        let platform_key = ecdh::SecKey::gensk(&mut self.rng);
+        Ok((
+            CoseKey::from(platform_key.genpk()),
+            platform_key.exchange_x_sha256(&authenticator_key),
+        ))
+    }
+
+    fn set_pin(&mut self, pin: &str) -> Result<(), u8> {
+        let (key_agreement, shared_secret) = self.key_agreement()?;
+        let mut padded_pin = pin.as_bytes().to_vec();
+        padded_pin.resize(PIN_PADDED_LENGTH, 0);
+        let new_pin_enc = aes256_cbc(&shared_secret, &padded_pin, true);
+        self.client_pin(AuthenticatorClientPinParameters {
+            key_agreement: Some(key_agreement),
+            pin_auth: Some(authenticate(&shared_secret, &new_pin_enc)),
+            new_pin_enc: Some(new_pin_enc),
+            ..client_pin_parameters(ClientPinSubCommand::SetPin)
+        })?;
+        Ok(())
+    }
+
+    fn change_pin(&mut self, pin: &str, new_pin: &str) -> Result<(), u8> {
+        let (key_agreement, shared_secret) = self.key_agreement()?;
+        let mut padded_pin = new_pin.as_bytes().to_vec();
+        padded_pin.resize(PIN_PADDED_LENGTH, 0);
+        let new_pin_enc = aes256_cbc(&shared_secret, &padded_pin, true);
+        let pin_hash_enc = aes256_cbc(&shared_secret, &Sha256::hash(pin.as_bytes())[..16], true);
+        let mut auth_message = new_pin_enc.clone();
+        auth_message.extend(&pin_hash_enc);
+        self.client_pin(AuthenticatorClientPinParameters {
+            key_agreement: Some(key_agreement),
+            pin_auth: Some(authenticate(&shared_secret, &auth_message)),
+            new_pin_enc: Some(new_pin_enc),
+            pin_hash_enc: Some(pin_hash_enc),
+            ..client_pin_parameters(ClientPinSubCommand::ChangePin)
+        })?;
+        Ok(())
+    }
+
+    fn get_pin_token(&mut self, pin: &str) -> Result<(), u8> {
+        let (key_agreement, shared_secret) = self.key_agreement()?;
+        let pin_hash_enc = aes256_cbc(&shared_secret, &Sha256::hash(pin.as_bytes())[..16], true);
+        let response = self.client_pin(AuthenticatorClientPinParameters {
+            key_agreement: Some(key_agreement),
+            pin_hash_enc: Some(pin_hash_enc),
+            ..client_pin_parameters(ClientPinSubCommand::GetPinUvAuthTokenUsingPin)
+        })?;
+        self.pin_token = aes256_cbc(&shared_secret, &response.pin_token.unwrap(), false);
+        assert_eq!(self.pin_token.len(), 32);
+        Ok(())
+    }
+
+    fn pin_uv_auth(&self, uv: bool, client_data_hash: &[u8]) -> (Option<Vec<u8>>, Option<u64>) {
+        if uv {
+            (
+                Some(authenticate(&self.pin_token, client_data_hash)),
+                Some(CtapState::<TestEnv>::PIN_PROTOCOL_VERSION),
+            )
+        } else {
+            (None, None)
+        }
+    }
+
+    fn credential_list(&self, credentials: &[usize]) -> Option<Vec<PublicKeyCredentialDescriptor>> {
+        if credentials.is_empty() {
+            return None;
+        }
+        Some(
+            credentials
+                .iter()
+                .map(|&credential| PublicKeyCredentialDescriptor {
+                    key_type: PublicKeyCredentialType::PublicKey,
+                    key_id: self.credentials[credential].id.clone(),
+                    transports: None,
+                })
+                .collect(),
+        )
+    }
+
+    // Checks the RP ID hash, the flags and the signature counter, see the WebAuthn specification
+    // section 6.1. Signatures must increase the counter of their credential, other operations may.
+    fn check_auth_data(
+        &mut self,
+        auth_data: &[u8],
+        rp_id: &str,
+        flags: u8,
+        credential: usize,
+        signed: bool,
+    ) {
+        assert_eq!(&auth_data[..32], &Sha256::hash(rp_id.as_bytes())[..]);
+        assert_eq!(auth_data[32], flags);
+        self.check_signature_counter(BigEndian::read_u32(&auth_data[33..37]), credential, signed);
+    }
+
+    fn check_signature_counter(&mut self, signature_counter: u32, credential: usize, signed: bool) {
+        let last_signature_counter = self
+            .signature_counters
+            .insert(credential, signature_counter)
+            .unwrap_or(0);
+        if signed && USE_SIGNATURE_COUNTER {
+            assert!(signature_counter > last_signature_counter);
+        } else {
+            assert!(signature_counter >= last_signature_counter);
+        }
+    }
+
+    fn make_credential(&mut self, step: MakeCredentialStep) -> Result<(), u8> {
+        let credential = self.credentials.len();
+        let client_data_hash = self.rng.gen_uniform_u8x32().to_vec();
+        let extensions = if step.hmac_secret {
+            let mut extension_map = BTreeMap::new();
+            extension_map.insert(String::from("hmac-secret"), cbor_bool!(true));
+            Some(Extensions::new(extension_map))
+        } else {
+            None
+        };
+        let (pin_uv_auth_param, pin_uv_auth_protocol) =
+            self.pin_uv_auth(step.uv, &client_data_hash);
+        let parameters = AuthenticatorMakeCredentialParameters {
+            client_data_hash: client_data_hash.clone(),
+            rp: PublicKeyCredentialRpEntity {
+                rp_id: String::from(step.rp_id),
+                rp_name: None,
+                rp_icon: None,
+            },
+            user: PublicKeyCredentialUserEntity {
+                user_id: vec![credential as u8],
+                user_name: None,
+                user_display_name: None,
+                user_icon: None,
+            },
+            pub_key_cred_params: vec![ES256_CRED_PARAM],
+            exclude_list: self.credential_list(step.exclude_list),
+            extensions,
+            options: MakeCredentialOptions {
+                rk: step.rk,
+                uv: false,
+            },
+            pin_uv_auth_param,
+            pin_uv_auth_protocol,
+            attestation_formats_preference: if step.attestation_formats.is_empty() {
+                None
+            } else {
+                Some(step.attestation_formats.to_vec())
+            },
+        };
+        let response = self
+            .send(Command::AuthenticatorMakeCredential(parameters))?
+            .unwrap();
+        let AuthenticatorMakeCredentialResponse {
+            auth_data,
+            att_stmt,
+        } = AuthenticatorMakeCredentialResponse::try_from(response).unwrap();
+
+        let mut flags = UP_FLAG | AT_FLAG;
+        if step.uv {
+            flags |= UV_FLAG;
+        }
+        if step.hmac_secret {
+            flags |= ED_FLAG;
+        }
+        self.check_auth_data(&auth_data, step.rp_id, flags, credential, false);
+        // The attested credential data, see the WebAuthn specification section 6.4.1.
+        assert_eq!(&auth_data[37..53], &AAGUID[..]);
+        let id_length = BigEndian::read_u16(&auth_data[53..55]) as usize;
+        if step.rk {
+            assert_eq!(id_length, 32);
+        } else {
+            assert_eq!(id_length, ENCRYPTED_CREDENTIAL_ID_SIZE);
+        }
+        let id = auth_data[55..55 + id_length].to_vec();
+        let public_key_end = 55 + id_length + COSE_KEY_LENGTH;
+        let public_key = decode_public_key(&auth_data[55 + id_length..public_key_end]);
+        let extensions = &auth_data[public_key_end..];
+        if step.hmac_secret {
+            assert_eq!(
+                cbor::read(extensions),
+                Ok(cbor_map! {"hmac-secret" => true})
+            );
+        } else {
+            assert!(extensions.is_empty());
+        }
+
+        assert_eq!(
+            att_stmt.format(),
+            expected_attestation_format(step.attestation_formats)
+        );
+        match att_stmt {
+            AttestationStatement::None => (),
+            AttestationStatement::Packed(att_stmt) => {
+                assert_eq!(att_stmt.alg, SignatureAlgorithm::ES256 as i64);
+                // Without batch attestation, the credential signs its own attestation.
+                if att_stmt.x5c.is_none() {
+                    let mut signature_data = auth_data.clone();
+                    signature_data.extend(&client_data_hash);
+                    verify_signature(&public_key, &signature_data, &att_stmt.sig);
+                }
+            }
+            #[cfg(feature = "with_ctap1")]
+            AttestationStatement::FidoU2f(att_stmt) => {
+                // The same signature as for a U2F registration.
+                assert_eq!(att_stmt.attestation_certificate, ATTESTATION_CERTIFICATE);
+                let mut signature_data = vec![0x00];
+                signature_data.extend(&Sha256::hash(step.rp_id.as_bytes()));
+                signature_data.extend(&client_data_hash);
+                signature_data.extend(&id);
+                signature_data.extend(&public_key.to_uncompressed()[..]);
+                let attestation_key = ecdsa::SecKey::from_bytes(ATTESTATION_PRIVATE_KEY)
+                    .unwrap()
+                    .genpk();
+                verify_signature(&attestation_key, &signature_data, &att_stmt.sig);
+            }
+            #[cfg(not(feature = "with_ctap1"))]
+            AttestationStatement::FidoU2f(_) => unreachable!(),
+        }
+
+        self.credentials.push(Credential {
+            rp_id: step.rp_id,
+            id,
+            user_id: vec![credential as u8],
+            public_key,
+            resident: step.rk,
+        });
+        Ok(())
+    }
+
+    fn get_assertion(&mut self, step: GetAssertionStep) -> Result<(), u8> {
+        let client_data_hash = self.rng.gen_uniform_u8x32().to_vec();
+        let (extensions, shared_secret) = match step.hmac_salt {
+            Some(salt) => {
+                let (key_agreement, shared_secret) = self.key_agreement()?;
+                let salt_enc = aes256_cbc(&shared_secret, &[salt; 32], true);
+                let hmac_secret_input = cbor_map! {
+                    1 => cbor::Value::Map(key_agreement.0),
+                    2 => salt_enc.clone(),
+                    3 => authenticate(&shared_secret, &salt_enc),
+                };
+                let mut extension_map = BTreeMap::new();
+                extension_map.insert(String::from("hmac-secret"), hmac_secret_input);
+                (Some(Extensions::new(extension_map)), shared_secret)
+            }
+            None => (None, [0; 32]),
+        };
+        let (pin_uv_auth_param, pin_uv_auth_protocol) =
+            self.pin_uv_auth(step.uv, &client_data_hash);
+        let parameters = AuthenticatorGetAssertionParameters {
+            rp_id: String::from(step.rp_id),
+            client_data_hash: client_data_hash.clone(),
+            allow_list: self.credential_list(step.allow_list),
+            extensions,
+            options: GetAssertionOptions {
+                up: step.up,
+                uv: false,
+            },
+            pin_uv_auth_param,
+            pin_uv_auth_protocol,
+        };
+        let response = self
+            .send(Command::AuthenticatorGetAssertion(parameters))?
+            .unwrap();
+        let AuthenticatorGetAssertionResponse {
+            credential,
+            auth_data,
+            signature,
+            user,
+            ..
+        } = AuthenticatorGetAssertionResponse::try_from(response).unwrap();
+
+        let credential_id = credential.unwrap().key_id;
+        let credential = self
+            .credentials
+            .iter()
+            .position(|credential| credential.id == credential_id)
+            .expect("Unknown credential");
+        assert_eq!(self.credentials[credential].rp_id, step.rp_id);
+        if step.allow_list.is_empty() {
+            assert!(self.credentials[credential].resident);
+        } else {
+            assert!(step.allow_list.contains(&credential));
+        }
+
+        let mut flags = 0;
+        if step.up {
+            flags |= UP_FLAG;
+        }
+        if step.uv {
+            flags |= UV_FLAG;
+        }
+        if step.hmac_salt.is_some() {
+            flags |= ED_FLAG;
+        }
+        self.check_auth_data(&auth_data, step.rp_id, flags, credential, true);
+        let mut signature_data = auth_data.clone();
+        signature_data.extend(&client_data_hash);
+        verify_signature(
+            &self.credentials[credential].public_key,
+            &signature_data,
+            &signature,
+        );
+        // The user is only identified after user verification.
+        if step.uv {
+            assert_eq!(user.unwrap().user_id, self.credentials[credential].user_id);
+        } else {
+            assert!(user.is_none());
+        }
+
+        if let Some(salt) = step.hmac_salt {
+            let extensions = cbor::read(&auth_data[37..]).unwrap();
+            let output_enc = read_map(&extensions)
+                .unwrap()
+                .get(&cbor_text!("hmac-secret"))
+                .map(|output_enc| read_byte_string(output_enc).unwrap())
+                .unwrap();
+            let output = aes256_cbc(&shared_secret, &output_enc, false);
+            assert_eq!(output.len(), 32);
+            // The output only depends on the credential and the salt.
+            for (&key, previous_output) in &self.hmac_secret_outputs {
+                assert_eq!(key == (credential, salt), *previous_output == output);
+            }
+            self.hmac_secret_outputs.insert((credential, salt), output);
+        } else {
+            assert_eq!(auth_data.len(), 37);
+        }
+        Ok(())
+    }
+
+    // The user touches the authenticator before each U2F command, as browsers ask them to.
+    #[cfg(feature = "with_ctap1")]
+    fn u2f(&mut self, message: &[u8]) -> Vec<u8> {
+        let ctap_state = self.ctap_state();
+        let clock_value = ctap_state.env().clock();
+        ctap_state.u2f_up_state.consume_up(clock_value);
+        ctap_state.u2f_up_state.grant_up(clock_value);
+        Ctap1Command::process_command(message, ctap_state, clock_value).unwrap()
+    }
+
+    // U2F raw message format specification (version 20170411) section 4
+    #[cfg(feature = "with_ctap1")]
+    fn u2f_register(&mut self, rp_id: &'static str) {
+        let application = Sha256::hash(rp_id.as_bytes());
+        let challenge = self.rng.gen_uniform_u8x32();
+        let mut message = vec![0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x40];
+        message.extend(&challenge);
+        message.extend(&application);
+        let response = self.u2f(&message);
+
+        assert_eq!(response[0], 0x05);
+        let user_pk = &response[1..66];
+        let id_length = response[66] as usize;
+        let id = response[67..67 + id_length].to_vec();
+        let certificate_end = 67 + id_length + ATTESTATION_CERTIFICATE.len();
+        assert_eq!(
+            &response[67 + id_length..certificate_end],
+            ATTESTATION_CERTIFICATE
+        );
+        let mut signature_data = vec![0x00];
+        signature_data.extend(&application);
+        signature_data.extend(&challenge);
+        signature_data.extend(&id);
+        signature_data.extend(user_pk);
+        let attestation_key = ecdsa::SecKey::from_bytes(ATTESTATION_PRIVATE_KEY)
+            .unwrap()
+            .genpk();
+        verify_signature(
+            &attestation_key,
+            &signature_data,
+            &response[certificate_end..],
+        );
+
+        self.credentials.push(Credential {
+            rp_id,
+            id,
+            user_id: vec![],
+            public_key: ecdsa::PubKey::from_bytes_uncompressed(user_pk).unwrap(),
+            resident: false,
+        });
+    }
+
+    // U2F raw message format specification (version 20170411) section 5
+    #[cfg(feature = "with_ctap1")]
+    fn u2f_authenticate(&mut self, rp_id: &'static str, credential: usize) {
+        let application = Sha256::hash(rp_id.as_bytes());
+        let challenge = self.rng.gen_uniform_u8x32();
+        let id = self.credentials[credential].id.clone();
+        let mut message = vec![0x00, 0x02, 0x03, 0x00, 0x00, 0x00, 65 + id.len() as u8];
+        message.extend(&challenge);
+        message.extend(&application);
+        message.push(id.len() as u8);
+        message.extend(&id);
+        let response = self.u2f(&message);
+
+        assert_eq!(response[0], 0x01);
+        self.check_signature_counter(BigEndian::read_u32(&response[1..5]), credential, true);
+        let mut signature_data = application.to_vec();
+        signature_data.extend(&response[..5]);
+        signature_data.extend(&challenge);
+        verify_signature(
+            &self.credentials[credential].public_key,
+            &signature_data,
+            &response[5..],
+        );
+    }
+}
+
+// The first supported format of the preference, see the CTAP specification (version 2.2)
+// section 6.1.2. Only U2F builds have a certificate for fido-u2f before provisioning.
+fn expected_attestation_format(
+    preference: &[AttestationStatementFormat],
+) -> AttestationStatementFormat {
+    preference
+        .iter()
+        .cloned()
+        .find(|format| match format {
+            AttestationStatementFormat::None | AttestationStatementFormat::Packed => true,
+            AttestationStatementFormat::FidoU2f => cfg!(feature = "with_ctap1"),
+            AttestationStatementFormat::Unknown => false,
+        })
+        .unwrap_or(AttestationStatementFormat::Packed)
+}
+
+fn run(scenarios: &[Scenario]) {
+    for scenario in scenarios {
+        let mut platform = Platform::new();
+        for (i, (step, expected)) in scenario.steps.iter().enumerate() {
+            let result = platform.play(*step);
+            let expected_status = expected.map_err(|status_code| status_code as u8);
+            if result != expected_status {
+                panic!(
+                    "{}, step {}: expected {:?}, got {:02X?}",
+                    scenario.name, i, expected, result
+                );
+            }
+        }
+    }
+}
+
+const PIN_SCENARIOS: &[Scenario] = &[
+    Scenario {
+        name: "setPin, getPinToken, MakeCredential and GetAssertion with pinAuth",
+        steps: &[
+            (Step::CheckClientPin(false), Ok(())),
+            (Step::SetPin("1234"), Ok(())),
+            (Step::CheckClientPin(true), Ok(())),
+            (Step::CheckPinRetries(6), Ok(())),
+            (
+                Step::MakeCredential(MAKE_CREDENTIAL),
+                Err(Ctap2StatusCode::CTAP2_ERR_PIN_REQUIRED),
+            ),
+            (Step::GetPinToken("1234"), Ok(())),
+            (
+                Step::MakeCredential(MakeCredentialStep {
+                    uv: true,
+                    ..MAKE_CREDENTIAL
+                }),
+                Ok(()),
+            ),
+            (
+                Step::GetAssertion(GetAssertionStep {
+                    uv: true,
+                    ..GET_ASSERTION
+                }),
+                Ok(()),
+            ),
+            (Step::GetAssertion(GET_ASSERTION), Ok(())),
+            (
+                Step::GetAssertion(GetAssertionStep {
+                    up: false,
+                    uv: true,
+                    allow_list: &[0],
+                    ..GET_ASSERTION
+                }),
+                Ok(()),
+            ),
+        ],
+    },
+    Scenario {
+        name: "pinAuth without a PIN",
+        steps: &[
+            (
+                Step::MakeCredential(MakeCredentialStep {
+                    uv: true,
+                    ..MAKE_CREDENTIAL
+                }),
+                Err(Ctap2StatusCode::CTAP2_ERR_PIN_NOT_SET),
+            ),
+            (Step::MakeCredential(MAKE_CREDENTIAL), Ok(())),
+            (
+                Step::GetAssertion(GetAssertionStep {
+                    uv: true,
+                    ..GET_ASSERTION
+                }),
+                Err(Ctap2StatusCode::CTAP2_ERR_PIN_NOT_SET),
+            ),
+            (
+                Step::GetPinToken("1234"),
+                Err(Ctap2StatusCode::CTAP2_ERR_PIN_REQUIRED),
+            ),
+        ],
+    },
+    Scenario {
+        name: "setPin only works once and checks the PIN policy",
+        steps: &[
+            (
+                Step::SetPin("123"),
+                Err(Ctap2StatusCode::CTAP2_ERR_PIN_POLICY_VIOLATION),
+            ),
+            (Step::CheckClientPin(false), Ok(())),
+            (Step::SetPin("1234"), Ok(())),
+            (
+                Step::SetPin("5678"),
+                Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_INVALID),
+            ),
+            (Step::GetPinToken("1234"), Ok(())),
+        ],
+    },
+    Scenario {
+        name: "changePin invalidates the PIN and the pinToken",
+        steps: &[
+            (Step::SetPin("1234"), Ok(())),
+            (Step::GetPinToken("1234"), Ok(())),
+            (Step::ChangePin("1234", "5678"), Ok(())),
+            (
+                Step::MakeCredential(MakeCredentialStep {
+                    uv: true,
+                    ..MAKE_CREDENTIAL
+                }),
+                Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_INVALID),
+            ),
+            (
+                Step::GetPinToken("1234"),
+                Err(Ctap2StatusCode::CTAP2_ERR_PIN_INVALID),
+            ),
+            (Step::CheckPinRetries(5), Ok(())),
+            (Step::GetPinToken("5678"), Ok(())),
+            (Step::CheckPinRetries(6), Ok(())),
+            (
+                Step::MakeCredential(MakeCredentialStep {
+                    uv: true,
+                    ..MAKE_CREDENTIAL
+                }),
+                Ok(()),
+            ),
+        ],
+    },
+    Scenario {
+        name: "a reboot invalidates the pinToken",
+        steps: &[
+            (Step::SetPin("1234"), Ok(())),
+            (Step::GetPinToken("1234"), Ok(())),
+            (Step::Reboot, Ok(())),
+            (Step::CheckClientPin(true), Ok(())),
+            (
+                Step::MakeCredential(MakeCredentialStep {
+                    uv: true,
+                    ..MAKE_CREDENTIAL
+                }),
+                Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_INVALID),
+            ),
+        ],
+    },
+];
+
+const LOCKOUT_SCENARIOS: &[Scenario] = &[
+    Scenario {
+        name: "three consecutive mismatches need a power cycle",
+        steps: &[
+            (Step::SetPin("1234"), Ok(())),
+            (
+                Step::GetPinToken("0000"),
+                Err(Ctap2StatusCode::CTAP2_ERR_PIN_INVALID),
+            ),
+            (Step::CheckPinRetries(5), Ok(())),
+            (
+                Step::GetPinToken("0000"),
+                Err(Ctap2StatusCode::CTAP2_ERR_PIN_INVALID),
+            ),
+            (
+                Step::GetPinToken("0000"),
+                Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_BLOCKED),
+            ),
+            // Blocked requests don't use up retries.
+            (
+                Step::GetPinToken("1234"),
+                Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_BLOCKED),
+            ),
+            (
+                Step::ChangePin("1234", "5678"),
+                Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_BLOCKED),
+            ),
+            (Step::CheckPinRetries(3), Ok(())),
+            (Step::Reboot, Ok(())),
+            (Step::CheckPinRetries(3), Ok(())),
+            (Step::GetPinToken("1234"), Ok(())),
+            (Step::CheckPinRetries(6), Ok(())),
+        ],
+    },
+    Scenario {
+        name: "the PIN is blocked when no retries are left",
+        steps: &[
+            (Step::SetPin("1234"), Ok(())),
+            (
+                Step::GetPinToken("0000"),
+                Err(Ctap2StatusCode::CTAP2_ERR_PIN_INVALID),
+            ),
+            (
+                Step::GetPinToken("0000"),
+                Err(Ctap2StatusCode::CTAP2_ERR_PIN_INVALID),
+            ),
+            (
+                Step::GetPinToken("0000"),
+                Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_BLOCKED),
+            ),
+            (Step::Reboot, Ok(())),
+            (
+                Step::GetPinToken("0000"),
+                Err(Ctap2StatusCode::CTAP2_ERR_PIN_INVALID),
+            ),
+            (
+                Step::GetPinToken("0000"),
+                Err(Ctap2StatusCode::CTAP2_ERR_PIN_INVALID),
+            ),
+            (
+                Step::GetPinToken("0000"),
+                Err(Ctap2StatusCode::CTAP2_ERR_PIN_BLOCKED),
+            ),
+            (Step::CheckPinRetries(0), Ok(())),
+            (Step::Reboot, Ok(())),
+            (
+                Step::GetPinToken("1234"),
+                Err(Ctap2StatusCode::CTAP2_ERR_PIN_BLOCKED),
+            ),
+            (
+                Step::ChangePin("1234", "5678"),
+                Err(Ctap2StatusCode::CTAP2_ERR_PIN_BLOCKED),
+            ),
+            (
+                Step::MakeCredential(MAKE_CREDENTIAL),
+                Err(Ctap2StatusCode::CTAP2_ERR_PIN_REQUIRED),
+            ),
+            // Only a reset makes the authenticator usable again.
+            (Step::Reset, Ok(())),
+            (Step::CheckClientPin(false), Ok(())),
+            (Step::CheckPinRetries(6), Ok(())),
+            (Step::SetPin("5678"), Ok(())),
+            (Step::GetPinToken("5678"), Ok(())),
+        ],
+    },
+];
+
+const RESET_SCENARIOS: &[Scenario] = &[
+    Scenario {
+        name: "reset deletes credentials and the PIN",
+        steps: &[
+            (Step::SetPin("1234"), Ok(())),
+            (Step::GetPinToken("1234"), Ok(())),
+            (
+                Step::MakeCredential(MakeCredentialStep {
+                    uv: true,
+                    ..MAKE_CREDENTIAL
+                }),
+                Ok(()),
+            ),
+            (
+                Step::MakeCredential(MakeCredentialStep {
+                    rk: false,
+                    uv: true,
+                    ..MAKE_CREDENTIAL
+                }),
+                Ok(()),
+            ),
+            (Step::GetAssertion(GET_ASSERTION), Ok(())),
+            (Step::Reset, Ok(())),
+            (Step::CheckClientPin(false), Ok(())),
+            (
+                Step::GetAssertion(GET_ASSERTION),
+                Err(Ctap2StatusCode::CTAP2_ERR_NO_CREDENTIALS),
+            ),
+            (
+                Step::GetAssertion(GetAssertionStep {
+                    allow_list: &[0],
+                    ..GET_ASSERTION
+                }),
+                Err(Ctap2StatusCode::CTAP2_ERR_NO_CREDENTIALS),
+            ),
+            // Credential IDs of non-resident credentials are invalidated too.
+            (
+                Step::GetAssertion(GetAssertionStep {
+                    allow_list: &[1],
+                    ..GET_ASSERTION
+                }),
+                Err(Ctap2StatusCode::CTAP2_ERR_NO_CREDENTIALS),
+            ),
+            (
+                Step::MakeCredential(MakeCredentialStep {
+                    uv: true,
+                    ..MAKE_CREDENTIAL
+                }),
+                Err(Ctap2StatusCode::CTAP2_ERR_PIN_NOT_SET),
+            ),
+        ],
+    },
+    Scenario {
+        name: "reset is only allowed shortly after boot",
+        steps: &[
+            (Step::MakeCredential(MAKE_CREDENTIAL), Ok(())),
+            (Step::Wait(RESET_TIMEOUT_MS), Ok(())),
+            (Step::Wait(RESET_TIMEOUT_MS + 1), Ok(())),
+            (Step::Reset, Err(Ctap2StatusCode::CTAP2_ERR_NOT_ALLOWED)),
+            (Step::GetAssertion(GET_ASSERTION), Ok(())),
+            // The window doesn't come back with time.
+            (Step::Wait(0), Ok(())),
+            (Step::Reset, Err(Ctap2StatusCode::CTAP2_ERR_NOT_ALLOWED)),
+            (Step::Reboot, Ok(())),
+            (Step::GetAssertion(GET_ASSERTION), Ok(())),
+            (Step::Wait(RESET_TIMEOUT_MS), Ok(())),
+            (Step::Reset, Ok(())),
+            (
+                Step::GetAssertion(GET_ASSERTION),
+                Err(Ctap2StatusCode::CTAP2_ERR_NO_CREDENTIALS),
+            ),
+        ],
+    },
+];
+
+const CREDENTIAL_LIST_SCENARIOS: &[Scenario] = &[
+    Scenario {
+        name: "exclude list",
+        steps: &[
+            (Step::MakeCredential(MAKE_CREDENTIAL), Ok(())),
+            (
+                Step::MakeCredential(MakeCredentialStep {
+                    exclude_list: &[0],
+                    ..MAKE_CREDENTIAL
+                }),
+                Err(Ctap2StatusCode::CTAP2_ERR_CREDENTIAL_EXCLUDED),
+            ),
+            // Credentials of other relying parties are not excluded.
+            (
+                Step::MakeCredential(MakeCredentialStep {
+                    rp_id: "example.org",
+                    exclude_list: &[0],
+                    ..MAKE_CREDENTIAL
+                }),
+                Ok(()),
+            ),
+            (
+                Step::MakeCredential(MakeCredentialStep {
+                    exclude_list: &[1],
+                    ..MAKE_CREDENTIAL
+                }),
+                Ok(()),
+            ),
+            (
+                Step::MakeCredential(MakeCredentialStep {
+                    rp_id: "example.org",
+                    exclude_list: &[2, 1],
+                    ..MAKE_CREDENTIAL
+                }),
+                Err(Ctap2StatusCode::CTAP2_ERR_CREDENTIAL_EXCLUDED),
+            ),
+        ],
+    },
+    Scenario {
+        name: "exclude list with a PIN",
+        steps: &[
+            (Step::SetPin("1234"), Ok(())),
+            (Step::GetPinToken("1234"), Ok(())),
+            (
+                Step::MakeCredential(MakeCredentialStep {
+                    uv: true,
+                    ..MAKE_CREDENTIAL
+                }),
+                Ok(()),
+            ),
+            (
+                Step::MakeCredential(MakeCredentialStep {
+                    uv: true,
+                    exclude_list: &[0],
+                    ..MAKE_CREDENTIAL
+                }),
+                Err(Ctap2StatusCode::CTAP2_ERR_CREDENTIAL_EXCLUDED),
+            ),
+        ],
+    },
+    Scenario {
+        name: "allow list",
+        steps: &[
+            (Step::MakeCredential(MAKE_CREDENTIAL), Ok(())),
+            (
+                Step::MakeCredential(MakeCredentialStep {
+                    rk: false,
+                    ..MAKE_CREDENTIAL
+                }),
+                Ok(()),
+            ),
+            (
+                Step::MakeCredential(MakeCredentialStep {
+                    rp_id: "example.org",
+                    rk: false,
+                    ..MAKE_CREDENTIAL
+                }),
+                Ok(()),
+            ),
+            (
+                Step::GetAssertion(GetAssertionStep {
+                    allow_list: &[1],
+                    ..GET_ASSERTION
+                }),
+                Ok(()),
+            ),
+            (
+                Step::GetAssertion(GetAssertionStep {
+                    allow_list: &[2, 1],
+                    ..GET_ASSERTION
+                }),
+                Ok(()),
+            ),
+            (
+                Step::GetAssertion(GetAssertionStep {
+                    allow_list: &[1, 0],
+                    ..GET_ASSERTION
+                }),
+                Ok(()),
+            ),
+            (
+                Step::GetAssertion(GetAssertionStep {
+                    allow_list: &[2],
+                    ..GET_ASSERTION
+                }),
+                Err(Ctap2StatusCode::CTAP2_ERR_NO_CREDENTIALS),
+            ),
+            // Non-resident credentials are only found through the allow list.
+            (
+                Step::GetAssertion(GetAssertionStep {
+                    rp_id: "example.org",
+                    ..GET_ASSERTION
+                }),
+                Err(Ctap2StatusCode::CTAP2_ERR_NO_CREDENTIALS),
+            ),
+            (
+                Step::GetAssertion(GetAssertionStep {
+                    rp_id: "example.org",
+                    allow_list: &[2],
+                    ..GET_ASSERTION
+                }),
+                Ok(()),
+            ),
+        ],
+    },
+];
+
+const HMAC_SECRET_SCENARIOS: &[Scenario] = &[
+    Scenario {
+        name: "hmac-secret outputs depend on the credential and the salt",
+        steps: &[
+            (
+                Step::MakeCredential(MakeCredentialStep {
+                    hmac_secret: true,
+                    ..MAKE_CREDENTIAL
+                }),
+                Ok(()),
+            ),
+            (
+                Step::GetAssertion(GetAssertionStep {
+                    hmac_salt: Some(0x01),
+                    ..GET_ASSERTION
+                }),
+                Ok(()),
+            ),
+            (
+                Step::GetAssertion(GetAssertionStep {
+                    hmac_salt: Some(0x01),
+                    ..GET_ASSERTION
+                }),
+                Ok(()),
+            ),
+            (
+                Step::GetAssertion(GetAssertionStep {
+                    hmac_salt: Some(0x02),
+                    ..GET_ASSERTION
+                }),
+                Ok(()),
+            ),
+            (
+                Step::MakeCredential(MakeCredentialStep {
+                    hmac_secret: true,
+                    ..MAKE_CREDENTIAL
+                }),
+                Ok(()),
+            ),
+            (
+                Step::GetAssertion(GetAssertionStep {
+                    allow_list: &[1],
+                    hmac_salt: Some(0x01),
+                    ..GET_ASSERTION
+                }),
+                Ok(()),
+            ),
+            (
+                Step::GetAssertion(GetAssertionStep {
+                    allow_list: &[0],
+                    hmac_salt: Some(0x02),
+                    ..GET_ASSERTION
+                }),
+                Ok(()),
+            ),
+            // The outputs survive a reboot.
+            (Step::Reboot, Ok(())),
+            (
+                Step::GetAssertion(GetAssertionStep {
+                    allow_list: &[0],
+                    hmac_salt: Some(0x01),
+                    ..GET_ASSERTION
+                }),
+                Ok(()),
+            ),
+        ],
+    },
+    Scenario {
+        name: "hmac-secret with a PIN",
+        steps: &[
+            (Step::SetPin("1234"), Ok(())),
+            (Step::GetPinToken("1234"), Ok(())),
+            (
+                Step::MakeCredential(MakeCredentialStep {
+                    uv: true,
+                    hmac_secret: true,
+                    ..MAKE_CREDENTIAL
+                }),
+                Ok(()),
+            ),
+            (
+                Step::GetAssertion(GetAssertionStep {
+                    uv: true,
+                    hmac_salt: Some(0x01),
+                    ..GET_ASSERTION
+                }),
+                Ok(()),
+            ),
+            (
+                Step::GetAssertion(GetAssertionStep {
+                    hmac_salt: Some(0x01),
+                    ..GET_ASSERTION
+                }),
+                Ok(()),
+            ),
+        ],
+    },
+    Scenario {
+        name: "hmac-secret needs user presence and a resident key",
+        steps: &[
+            (
+                Step::MakeCredential(MakeCredentialStep {
+                    rk: false,
+                    hmac_secret: true,
+                    ..MAKE_CREDENTIAL
+                }),
+                Err(Ctap2StatusCode::CTAP2_ERR_UNSUPPORTED_EXTENSION),
+            ),
+            (
+                Step::MakeCredential(MakeCredentialStep {
+                    hmac_secret: true,
+                    ..MAKE_CREDENTIAL
+                }),
+                Ok(()),
+            ),
+            (
+                Step::GetAssertion(GetAssertionStep {
+                    up: false,
+                    hmac_salt: Some(0x01),
+                    ..GET_ASSERTION
+                }),
+                Err(Ctap2StatusCode::CTAP2_ERR_UNSUPPORTED_EXTENSION),
+            ),
+        ],
+    },
+    Scenario {
+        name: "hmac-secret needs the extension at creation",
+        steps: &[
+            (Step::MakeCredential(MAKE_CREDENTIAL), Ok(())),
+            (
+                Step::GetAssertion(GetAssertionStep {
+                    hmac_salt: Some(0x01),
+                    ..GET_ASSERTION
+                }),
+                Err(Ctap2StatusCode::CTAP2_ERR_UNSUPPORTED_EXTENSION),
+            ),
+            (Step::GetAssertion(GET_ASSERTION), Ok(())),
+        ],
+    },
+];
+
+const ATTESTATION_SCENARIOS: &[Scenario] = &[
+    Scenario {
+        name: "the first supported attestation format is used",
+        steps: &[
+            (
+                Step::MakeCredential(MakeCredentialStep {
+                    attestation_formats: &[AttestationStatementFormat::None],
+                    ..MAKE_CREDENTIAL
+                }),
+                Ok(()),
+            ),
+            (
+                Step::MakeCredential(MakeCredentialStep {
+                    attestation_formats: &[
+                        AttestationStatementFormat::Unknown,
+                        AttestationStatementFormat::Packed,
+                        AttestationStatementFormat::None,
+                    ],
+                    ..MAKE_CREDENTIAL
+                }),
+                Ok(()),
+            ),
+            (
+                Step::MakeCredential(MakeCredentialStep {
+                    rk: false,
+                    attestation_formats: &[
+                        AttestationStatementFormat::FidoU2f,
+                        AttestationStatementFormat::None,
+                    ],
+                    ..MAKE_CREDENTIAL
+                }),
+                Ok(()),
+            ),
+            (
+                Step::MakeCredential(MakeCredentialStep {
+                    attestation_formats: &[AttestationStatementFormat::Unknown],
+                    ..MAKE_CREDENTIAL
+                }),
+                Ok(()),
+            ),
+        ],
+    },
+    Scenario {
+        name: "credentials work regardless of their attestation format",
+        steps: &[
+            (
+                Step::MakeCredential(MakeCredentialStep {
+                    attestation_formats: &[AttestationStatementFormat::None],
+                    ..MAKE_CREDENTIAL
+                }),
+                Ok(()),
+            ),
+            (
+                Step::MakeCredential(MakeCredentialStep {
+                    rk: false,
+                    attestation_formats: &[AttestationStatementFormat::FidoU2f],
+                    ..MAKE_CREDENTIAL
+                }),
+                Ok(()),
+            ),
+            (Step::GetAssertion(GET_ASSERTION), Ok(())),
+            (
+                Step::GetAssertion(GetAssertionStep {
+                    allow_list: &[1],
+                    ..GET_ASSERTION
+                }),
+                Ok(()),
+            ),
+        ],
+    },
+];
+
+#[cfg(feature = "with_ctap1")]
+const U2F_SCENARIOS: &[Scenario] = &[
+    Scenario {
+        name: "U2F registrations are usable through CTAP2",
+        steps: &[
+            (Step::U2fRegister("example.com"), Ok(())),
+            (
+                Step::GetAssertion(GetAssertionStep {
+                    allow_list: &[0],
+                    ..GET_ASSERTION
+                }),
+                Ok(()),
+            ),
+            (Step::U2fAuthenticate("example.com", 0), Ok(())),
+            (
+                Step::GetAssertion(GetAssertionStep {
+                    allow_list: &[0],
+                    up: false,
+                    ..GET_ASSERTION
+                }),
+                Ok(()),
+            ),
+            (
+                Step::GetAssertion(GetAssertionStep {
+                    rp_id: "example.org",
+                    allow_list: &[0],
+                    ..GET_ASSERTION
+                }),
+                Err(Ctap2StatusCode::CTAP2_ERR_NO_CREDENTIALS),
+            ),
+            (
+                Step::GetAssertion(GET_ASSERTION),
+                Err(Ctap2StatusCode::CTAP2_ERR_NO_CREDENTIALS),
+            ),
+        ],
+    },
+    Scenario {
+        name: "non-resident CTAP2 credentials are usable through U2F",
+        steps: &[
+            (
+                Step::MakeCredential(MakeCredentialStep {
+                    rk: false,
+                    ..MAKE_CREDENTIAL
+                }),
+                Ok(()),
+            ),
+            (Step::U2fAuthenticate("example.com", 0), Ok(())),
+            (
+                Step::GetAssertion(GetAssertionStep {
+                    allow_list: &[0],
+                    ..GET_ASSERTION
+                }),
+                Ok(()),
+            ),
+            (Step::U2fAuthenticate("example.com", 0), Ok(())),
+        ],
+    },
+    Scenario {
+        name: "U2F registrations don't survive a reset",
+        steps: &[
+            (Step::U2fRegister("example.com"), Ok(())),
+            (Step::Reset, Ok(())),
+            (
+                Step::GetAssertion(GetAssertionStep {
+                    allow_list: &[0],
+                    ..GET_ASSERTION
+                }),
+                Err(Ctap2StatusCode::CTAP2_ERR_NO_CREDENTIALS),
+            ),
+        ],
+    },
+];
+
+#[test]
+fn test_pin_scenarios() {
+    run(PIN_SCENARIOS);
+}
+
+#[test]
+fn test_lockout_scenarios() {
+    run(LOCKOUT_SCENARIOS);
+}
+
+#[test]
+fn test_reset_scenarios() {
+    run(RESET_SCENARIOS);
+}
+
+#[test]
+fn test_credential_list_scenarios() {
+    run(CREDENTIAL_LIST_SCENARIOS);
+}
+
+#[test]
+fn test_hmac_secret_scenarios() {
+    run(HMAC_SECRET_SCENARIOS);
+}
+
+#[test]
+fn test_attestation_scenarios() {
+    run(ATTESTATION_SCENARIOS);
+}
+
+#[cfg(feature = "with_ctap1")]
+#[test]
+fn test_u2f_scenarios() {
+    run(U2F_SCENARIOS);
+}
@@ -12,15 +12,14 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use super::hid::ChannelID;
-use super::key_material::{ATTESTATION_CERTIFICATE, ATTESTATION_PRIVATE_KEY};
-use super::status_code::Ctap2StatusCode;
+use super::apdu::ApduCommand;
+use super::storage::CounterOwner;
 use super::CtapState;
+use crate::env::Env;
 use crate::timer::ClockValue;
 use alloc::vec::Vec;
 use core::convert::Into;
 use core::convert::TryFrom;
-use crypto::rng256::Rng256;
 
 // The specification referenced in this file is at:
 // https://fidoalliance.org/specs/fido-u2f-v1.2-ps-20170411/fido-u2f-raw-message-formats-v1.2-ps-20170411.pdf
@@ -35,6 +34,8 @@ pub enum Ctap1StatusCode {
     SW_WRONG_LENGTH = 0x6700,
     SW_CLA_NOT_SUPPORTED = 0x6E00,
     SW_INS_NOT_SUPPORTED = 0x6D00,
+    // ISO 7816-4 memory failure, when the persistent store can't be used.
+    SW_MEMERR = 0x6501,
     SW_VENDOR_KEY_HANDLE_TOO_LONG = 0xF000,
 }
 
@@ -49,6 +50,7 @@ impl TryFrom<u16> for Ctap1StatusCode {
             0x6700 => Ok(Ctap1StatusCode::SW_WRONG_LENGTH),
             0x6E00 => Ok(Ctap1StatusCode::SW_CLA_NOT_SUPPORTED),
             0x6D00 => Ok(Ctap1StatusCode::SW_INS_NOT_SUPPORTED),
+            0x6501 => Ok(Ctap1StatusCode::SW_MEMERR),
             0xF000 => Ok(Ctap1StatusCode::SW_VENDOR_KEY_HANDLE_TOO_LONG),
             _ => Err(()),
         }
@@ -114,32 +116,26 @@ impl TryFrom<&[u8]> for U2fCommand {
     type Error = Ctap1StatusCode;
 
     fn try_from(message: &[u8]) -> Result<Self, Ctap1StatusCode> {
-        if message.len() < Ctap1Command::APDU_HEADER_LEN as usize {
-            return Err(Ctap1StatusCode::SW_WRONG_DATA);
-        }
+        // The CTAPHID flavour always uses the extended length encoding, but short APDUs are
+        // accepted as well.
+        let apdu = ApduCommand::try_from(message).map_err(|_| Ctap1StatusCode::SW_WRONG_LENGTH)?;
+        U2fCommand::try_from(apdu)
+    }
+}
 
-        let (apdu, payload) = message.split_at(Ctap1Command::APDU_HEADER_LEN as usize);
+impl TryFrom<ApduCommand> for U2fCommand {
+    type Error = Ctap1StatusCode;
 
-        // ISO7816 APDU Header format. Each cell is 1 byte. Note that the CTAP flavor always
-        // encodes the length on 3 bytes and doesn't use the field "Le" (Length Expected).
-        // We keep the 2 byte of "Le" for the packet length in mind, but always ignore its value.
-        // Lc is using big-endian encoding
-        // +-----+-----+----+----+-----+-----+-----+
-        // | CLA | INS | P1 | P2 | Lc1 | Lc2 | Lc3 |
-        // +-----+-----+----+----+-----+-----+-----+
-        if apdu[0] != Ctap1Command::CTAP1_CLA {
+    // The expected length Le is ignored, responses are never truncated here. Transports that need
+    // it take care of response chaining.
+    fn try_from(apdu: ApduCommand) -> Result<Self, Ctap1StatusCode> {
+        if apdu.cla != Ctap1Command::CTAP1_CLA {
             return Err(Ctap1StatusCode::SW_CLA_NOT_SUPPORTED);
         }
 
-        let lc = (((apdu[4] as u32) << 16) | ((apdu[5] as u32) << 8) | (apdu[6] as u32)) as usize;
-
-        // Since there is always request data, the expected length is either omitted or
-        // encoded in 2 bytes.
-        if lc != payload.len() && lc + 2 != payload.len() {
-            return Err(Ctap1StatusCode::SW_WRONG_LENGTH);
-        }
-
-        match apdu[1] {
+        let payload = &apdu.data;
+        let lc = payload.len();
+        match apdu.ins {
             // U2F raw message format specification, Section 4.1
             // +-----------------+-------------------+
             // + Challenge (32B) | Application (32B) |
@@ -166,7 +162,7 @@ impl TryFrom<&[u8]> for U2fCommand {
                 if lc != 65 + handle_length {
                     return Err(Ctap1StatusCode::SW_WRONG_LENGTH);
                 }
-                let flag = Ctap1Flags::try_from(apdu[2])?;
+                let flag = Ctap1Flags::try_from(apdu.p1)?;
                 Ok(Self::Authenticate {
                     challenge: *array_ref!(payload, 0, 32),
                     application: *array_ref!(payload, 32, 32),
@@ -185,9 +181,7 @@ impl TryFrom<&[u8]> for U2fCommand {
 
             // For Vendor specific command.
             Ctap1Command::VENDOR_SPECIFIC_FIRST..=Ctap1Command::VENDOR_SPECIFIC_LAST => {
-                Ok(Self::VendorSpecific {
-                    payload: payload.to_vec(),
-                })
+                Ok(Self::VendorSpecific { payload: apdu.data })
             }
 
             _ => Err(Ctap1StatusCode::SW_INS_NOT_SUPPORTED),
@@ -198,8 +192,6 @@ impl TryFrom<&[u8]> for U2fCommand {
 pub struct Ctap1Command {}
 
 impl Ctap1Command {
-    const APDU_HEADER_LEN: u32 = 7; // CLA + INS + P1 + P2 + LC1-3
-
     const CTAP1_CLA: u8 = 0;
     // This byte is used in Register, but only serves backwards compatibility.
     const LEGACY_BYTE: u8 = 0x05;
@@ -214,22 +206,47 @@ impl Ctap1Command {
     const VENDOR_SPECIFIC_FIRST: u8 = 0x40;
     const VENDOR_SPECIFIC_LAST: u8 = 0xBF;
 
-    pub fn process_command<R, CheckUserPresence>(
+    pub fn process_command<E: Env>(
         message: &[u8],
-        ctap_state: &mut CtapState<R, CheckUserPresence>,
+        ctap_state: &mut CtapState<E>,
         clock_value: ClockValue,
-    ) -> Result<Vec<u8>, Ctap1StatusCode>
-    where
-        R: Rng256,
-        CheckUserPresence: Fn(ChannelID) -> Result<(), Ctap2StatusCode>,
-    {
+    ) -> Result<Vec<u8>, Ctap1StatusCode> {
         let command = U2fCommand::try_from(message)?;
+        Ctap1Command::process_u2f_command(command, ctap_state, clock_value, false)
+    }
+
+    // Same as process_command, for an APDU that a transport already parsed. Transports where the
+    // command itself shows user presence, like tapping an NFC reader, set implicit_up. It only
+    // applies to this command, and leaves the user presence state of other transports untouched.
+    #[cfg(any(feature = "with_nfc", feature = "with_ccid"))]
+    pub fn process_apdu<E: Env>(
+        apdu: ApduCommand,
+        ctap_state: &mut CtapState<E>,
+        clock_value: ClockValue,
+        implicit_up: bool,
+    ) -> Result<Vec<u8>, Ctap1StatusCode> {
+        let command = U2fCommand::try_from(apdu)?;
+        Ctap1Command::process_u2f_command(command, ctap_state, clock_value, implicit_up)
+    }
+
+    fn process_u2f_command<E: Env>(
+        command: U2fCommand,
+        ctap_state: &mut CtapState<E>,
+        clock_value: ClockValue,
+        implicit_up: bool,
+    ) -> Result<Vec<u8>, Ctap1StatusCode> {
         match command {
+            // U2F raw message format specification (version 20170411) section 6.3
+            U2fCommand::Version => Ok(Vec::<u8>::from(super::U2F_VERSION_STRING)),
+
+            // Other commands could misinterpret the store, see `PersistentStore::migrate`.
+            _ if ctap_state.persistent_store.has_newer_schema() => Err(Ctap1StatusCode::SW_MEMERR),
+
             U2fCommand::Register {
                 challenge,
                 application,
             } => {
-                if !ctap_state.u2f_up_state.consume_up(clock_value) {
+                if !implicit_up && !ctap_state.u2f_up_state.consume_up(clock_value) {
                     return Err(Ctap1StatusCode::SW_CONDITIONS_NOT_SATISFIED);
                 }
                 Ctap1Command::process_register(challenge, application, ctap_state)
@@ -243,6 +260,7 @@ impl Ctap1Command {
             } => {
                 // The order is important due to side effects of checking user presence.
                 if flags == Ctap1Flags::EnforceUpAndSign
+                    && !implicit_up
                     && !ctap_state.u2f_up_state.consume_up(clock_value)
                 {
                     return Err(Ctap1StatusCode::SW_CONDITIONS_NOT_SATISFIED);
@@ -256,11 +274,8 @@ impl Ctap1Command {
                 )
             }
 
-            // U2F raw message format specification (version 20170411) section 6.3
-            U2fCommand::Version => Ok(Vec::<u8>::from(super::U2F_VERSION_STRING)),
-
-            // TODO: should we return an error instead such as SW_INS_NOT_SUPPORTED?
-            U2fCommand::VendorSpecific { .. } => Err(Ctap1StatusCode::SW_NO_ERROR),
+            // Vendor commands are only available through CTAP2 and CTAPHID, see the vendor module.
+            U2fCommand::VendorSpecific { .. } => Err(Ctap1StatusCode::SW_INS_NOT_SUPPORTED),
         }
     }
 
@@ -275,31 +290,30 @@ impl Ctap1Command {
     // +------+-------------------+-----------------+------------+--------------------+
     // + 0x00 | application (32B) | challenge (32B) | key handle | User pub key (65B) |
     // +------+-------------------+-----------------+------------+--------------------+
-    fn process_register<R, CheckUserPresence>(
+    fn process_register<E: Env>(
         challenge: [u8; 32],
         application: [u8; 32],
-        ctap_state: &mut CtapState<R, CheckUserPresence>,
-    ) -> Result<Vec<u8>, Ctap1StatusCode>
-    where
-        R: Rng256,
-        CheckUserPresence: Fn(ChannelID) -> Result<(), Ctap2StatusCode>,
-    {
-        let sk = crypto::ecdsa::SecKey::gensk(ctap_state.rng);
+        ctap_state: &mut CtapState<E>,
+    ) -> Result<Vec<u8>, Ctap1StatusCode> {
+        let sk = crypto::ecdsa::SecKey::gensk(ctap_state.env().rng());
         let pk = sk.genpk();
-        let key_handle = ctap_state.encrypt_key_handle(sk, &application);
+        let key_handle = ctap_state
+            .encrypt_key_handle(sk, &application)
+            .map_err(|_| Ctap1StatusCode::SW_MEMERR)?;
         if key_handle.len() > 0xFF {
             // This is just being defensive with unreachable code.
             return Err(Ctap1StatusCode::SW_VENDOR_KEY_HANDLE_TOO_LONG);
         }
 
-        let mut response =
-            Vec::with_capacity(105 + key_handle.len() + ATTESTATION_CERTIFICATE.len());
+        // With CTAP1, there is always a certificate to fall back to.
+        let (attestation_key, certificate) = ctap_state.u2f_attestation().unwrap();
+        let mut response = Vec::with_capacity(105 + key_handle.len() + certificate.len());
         response.push(Ctap1Command::LEGACY_BYTE);
         let user_pk = pk.to_uncompressed();
         response.extend_from_slice(&user_pk);
         response.push(key_handle.len() as u8);
         response.extend(key_handle.clone());
-        response.extend_from_slice(&ATTESTATION_CERTIFICATE);
+        response.extend_from_slice(&certificate);
 
         // The first byte is reserved.
         let mut signature_data = Vec::with_capacity(66 + key_handle.len());
@@ -309,7 +323,6 @@ impl Ctap1Command {
         signature_data.extend(key_handle);
         signature_data.extend_from_slice(&user_pk);
 
-        let attestation_key = crypto::ecdsa::SecKey::from_bytes(ATTESTATION_PRIVATE_KEY).unwrap();
         let signature = attestation_key.sign_rfc6979::<crypto::sha256::Sha256>(&signature_data);
 
         response.extend(signature.to_asn1_der());
@@ -330,25 +343,29 @@ impl Ctap1Command {
     // +-------------------+---------+--------------+-----------------+
     // + application (32B) | UP (1B) | Counter (4B) | challenge (32B) |
     // +-------------------+---------+--------------+-----------------+
-    fn process_authenticate<R, CheckUserPresence>(
+    fn process_authenticate<E: Env>(
         challenge: [u8; 32],
         application: [u8; 32],
         key_handle: Vec<u8>,
         flags: Ctap1Flags,
-        ctap_state: &mut CtapState<R, CheckUserPresence>,
-    ) -> Result<Vec<u8>, Ctap1StatusCode>
-    where
-        R: Rng256,
-        CheckUserPresence: Fn(ChannelID) -> Result<(), Ctap2StatusCode>,
-    {
-        let credential_source = ctap_state.decrypt_credential_source(key_handle, &application);
+        ctap_state: &mut CtapState<E>,
+    ) -> Result<Vec<u8>, Ctap1StatusCode> {
+        let credential_source = ctap_state
+            .decrypt_credential_source(key_handle, &application)
+            .map_err(|_| Ctap1StatusCode::SW_MEMERR)?;
         if let Some(credential_source) = credential_source {
             if flags == Ctap1Flags::CheckOnly {
                 return Err(Ctap1StatusCode::SW_CONDITIONS_NOT_SATISFIED);
             }
-            ctap_state.increment_global_signature_counter();
-            let mut signature_data = ctap_state
-                .generate_auth_data(&application, Ctap1Command::USER_PRESENCE_INDICATOR_BYTE);
+            // Key handles are non-resident credentials, which share the signature counter of their
+            // relying party with CTAP2.
+            let signature_counter =
+                ctap_state.increment_signature_counter(CounterOwner::RelyingParty(&application));
+            let mut signature_data = ctap_state.generate_auth_data(
+                &application,
+                Ctap1Command::USER_PRESENCE_INDICATOR_BYTE,
+                signature_counter,
+            );
             signature_data.extend(&challenge);
             let signature = credential_source
                 .private_key
@@ -365,8 +382,10 @@ impl Ctap1Command {
 
 #[cfg(test)]
 mod test {
+    use super::super::key_material::ATTESTATION_CERTIFICATE;
     use super::super::{ENCRYPTED_CREDENTIAL_ID_SIZE, USE_SIGNATURE_COUNTER};
     use super::*;
+    use crate::env::host::HostEnv;
     use crypto::rng256::ThreadRng256;
     use crypto::Hash256;
 
@@ -417,9 +436,8 @@ mod test {
 
     #[test]
     fn test_process_register() {
-        let mut rng = ThreadRng256 {};
         let dummy_user_presence = |_| panic!("Unexpected user presence check in CTAP1");
-        let mut ctap_state = CtapState::new(&mut rng, dummy_user_presence);
+        let mut ctap_state = CtapState::new(HostEnv::new(dummy_user_presence));
 
         let application = [0x0A; 32];
         let message = create_register_message(&application);
@@ -435,6 +453,7 @@ mod test {
                 response[67..67 + ENCRYPTED_CREDENTIAL_ID_SIZE].to_vec(),
                 &application
             )
+            .unwrap()
             .is_some());
         const CERT_START: usize = 67 + ENCRYPTED_CREDENTIAL_ID_SIZE;
         assert_eq!(
@@ -445,9 +464,8 @@ mod test {
 
     #[test]
     fn test_process_register_bad_message() {
-        let mut rng = ThreadRng256 {};
         let dummy_user_presence = |_| panic!("Unexpected user presence check in CTAP1");
-        let mut ctap_state = CtapState::new(&mut rng, dummy_user_presence);
+        let mut ctap_state = CtapState::new(HostEnv::new(dummy_user_presence));
 
         let application = [0x0A; 32];
         let message = create_register_message(&application);
@@ -464,10 +482,8 @@ mod test {
     fn test_process_register_without_up() {
         let application = [0x0A; 32];
         let message = create_register_message(&application);
-
-        let mut rng = ThreadRng256 {};
         let dummy_user_presence = |_| panic!("Unexpected user presence check in CTAP1");
-        let mut ctap_state = CtapState::new(&mut rng, dummy_user_presence);
+        let mut ctap_state = CtapState::new(HostEnv::new(dummy_user_presence));
 
         ctap_state.u2f_up_state.consume_up(START_CLOCK_VALUE);
         ctap_state.u2f_up_state.grant_up(START_CLOCK_VALUE);
@@ -476,16 +492,123 @@ mod test {
         assert_eq!(response, Err(Ctap1StatusCode::SW_CONDITIONS_NOT_SATISFIED));
     }
 
+    #[test]
+    fn test_process_register_newer_schema() {
+        let dummy_user_presence = |_| panic!("Unexpected user presence check in CTAP1");
+        let ctap_state = CtapState::new(HostEnv::new(dummy_user_presence));
+        let storage = ctap_state.persistent_store.into_downgraded_storage();
+        let mut ctap_state = CtapState::new(HostEnv::with_storage(dummy_user_presence, storage));
+
+        let message = create_register_message(&[0x0A; 32]);
+        ctap_state.u2f_up_state.consume_up(START_CLOCK_VALUE);
+        ctap_state.u2f_up_state.grant_up(START_CLOCK_VALUE);
+        let response = Ctap1Command::process_command(&message, &mut ctap_state, START_CLOCK_VALUE);
+        assert_eq!(response, Err(Ctap1StatusCode::SW_MEMERR));
+    }
+
+    #[test]
+    fn test_process_version() {
+        let dummy_user_presence = |_| panic!("Unexpected user presence check in CTAP1");
+        let mut ctap_state = CtapState::new(HostEnv::new(dummy_user_presence));
+
+        let messages = [
+            // Short encoding, without and with Le.
+            vec![
+                Ctap1Command::CTAP1_CLA,
+                Ctap1Command::U2F_VERSION,
+                0x00,
+                0x00,
+            ],
+            vec![
+                Ctap1Command::CTAP1_CLA,
+                Ctap1Command::U2F_VERSION,
+                0x00,
+                0x00,
+                0x00,
+            ],
+            // Extended encoding, as sent over CTAPHID with and without Le.
+            vec![
+                Ctap1Command::CTAP1_CLA,
+                Ctap1Command::U2F_VERSION,
+                0x00,
+                0x00,
+                0x00,
+                0x00,
+                0x00,
+            ],
+            vec![
+                Ctap1Command::CTAP1_CLA,
+                Ctap1Command::U2F_VERSION,
+                0x00,
+                0x00,
+                0x00,
+                0x00,
+                0x00,
+                0x00,
+                0x00,
+            ],
+        ];
+        for message in messages.iter() {
+            let response =
+                Ctap1Command::process_command(message, &mut ctap_state, START_CLOCK_VALUE);
+            assert_eq!(
+                response,
+                Ok(Vec::<u8>::from(super::super::U2F_VERSION_STRING))
+            );
+        }
+    }
+
+    #[test]
+    fn test_process_vendor_specific() {
+        let dummy_user_presence = |_| panic!("Unexpected user presence check in CTAP1");
+        let mut ctap_state = CtapState::new(HostEnv::new(dummy_user_presence));
+
+        let message = [
+            Ctap1Command::CTAP1_CLA,
+            Ctap1Command::VENDOR_SPECIFIC_FIRST,
+            0x00,
+            0x00,
+            0x00,
+            0x00,
+            0x00,
+        ];
+        let response = Ctap1Command::process_command(&message, &mut ctap_state, START_CLOCK_VALUE);
+        assert_eq!(response, Err(Ctap1StatusCode::SW_INS_NOT_SUPPORTED));
+    }
+
+    #[test]
+    fn test_process_register_short_apdu() {
+        let dummy_user_presence = |_| panic!("Unexpected user presence check in CTAP1");
+        let mut ctap_state = CtapState::new(HostEnv::new(dummy_user_presence));
+
+        let application = [0x0A; 32];
+        let mut message = vec![
+            Ctap1Command::CTAP1_CLA,
+            Ctap1Command::U2F_REGISTER,
+            0x00,
+            0x00,
+            0x40,
+        ];
+        message.extend(&[0x0C; 32]);
+        message.extend(&application);
+        message.push(0x00);
+        ctap_state.u2f_up_state.consume_up(START_CLOCK_VALUE);
+        ctap_state.u2f_up_state.grant_up(START_CLOCK_VALUE);
+        let response =
+            Ctap1Command::process_command(&message, &mut ctap_state, START_CLOCK_VALUE).unwrap();
+        assert_eq!(response[0], Ctap1Command::LEGACY_BYTE);
+    }
+
     #[test]
     fn test_process_authenticate_check_only() {
         let mut rng = ThreadRng256 {};
         let dummy_user_presence = |_| panic!("Unexpected user presence check in CTAP1");
         let sk = crypto::ecdsa::SecKey::gensk(&mut rng);
-        let mut ctap_state = CtapState::new(&mut rng, dummy_user_presence);
+        let mut ctap_state = CtapState::new(HostEnv::new(dummy_user_presence));
 
         let rp_id = "example.com";
         let application = crypto::sha256::Sha256::hash(rp_id.as_bytes());
-        let key_handle = ctap_state.encrypt_key_handle(sk, &application);
+        let key_handle = ctap_state.encrypt_key_handle(sk, &application).unwrap();
         let message = create_authenticate_message(&application, Ctap1Flags::CheckOnly, &key_handle);
 
         let response = Ctap1Command::process_command(&message, &mut ctap_state, START_CLOCK_VALUE);
@@ -497,11 +620,11 @@ mod test {
         let mut rng = ThreadRng256 {};
         let dummy_user_presence = |_| panic!("Unexpected user presence check in CTAP1");
         let sk = crypto::ecdsa::SecKey::gensk(&mut rng);
-        let mut ctap_state = CtapState::new(&mut rng, dummy_user_presence);
+        let mut ctap_state = CtapState::new(HostEnv::new(dummy_user_presence));
 
         let rp_id = "example.com";
         let application = crypto::sha256::Sha256::hash(rp_id.as_bytes());
-        let key_handle = ctap_state.encrypt_key_handle(sk, &application);
+        let key_handle = ctap_state.encrypt_key_handle(sk, &application).unwrap();
         let application = [0x55; 32];
         let message = create_authenticate_message(&application, Ctap1Flags::CheckOnly, &key_handle);
 
@@ -514,11 +637,11 @@ mod test {
         let mut rng = ThreadRng256 {};
         let dummy_user_presence = |_| panic!("Unexpected user presence check in CTAP1");
         let sk = crypto::ecdsa::SecKey::gensk(&mut rng);
-        let mut ctap_state = CtapState::new(&mut rng, dummy_user_presence);
+        let mut ctap_state = CtapState::new(HostEnv::new(dummy_user_presence));
 
         let rp_id = "example.com";
         let application = crypto::sha256::Sha256::hash(rp_id.as_bytes());
-        let key_handle = ctap_state.encrypt_key_handle(sk, &application);
+        let key_handle = ctap_state.encrypt_key_handle(sk, &application).unwrap();
         let mut message =
             create_authenticate_message(&application, Ctap1Flags::CheckOnly, &key_handle);
 
@@ -538,11 +661,11 @@ mod test {
         let mut rng = ThreadRng256 {};
         let dummy_user_presence = |_| panic!("Unexpected user presence check in CTAP1");
         let sk = crypto::ecdsa::SecKey::gensk(&mut rng);
-        let mut ctap_state = CtapState::new(&mut rng, dummy_user_presence);
+        let mut ctap_state = CtapState::new(HostEnv::new(dummy_user_presence));
 
         let rp_id = "example.com";
         let application = crypto::sha256::Sha256::hash(rp_id.as_bytes());
-        let key_handle = ctap_state.encrypt_key_handle(sk, &application);
+        let key_handle = ctap_state.encrypt_key_handle(sk, &application).unwrap();
         let mut message =
             create_authenticate_message(&application, Ctap1Flags::CheckOnly, &key_handle);
         message[0] = 0xEE;
@@ -556,11 +679,11 @@ mod test {
         let mut rng = ThreadRng256 {};
         let dummy_user_presence = |_| panic!("Unexpected user presence check in CTAP1");
         let sk = crypto::ecdsa::SecKey::gensk(&mut rng);
-        let mut ctap_state = CtapState::new(&mut rng, dummy_user_presence);
+        let mut ctap_state = CtapState::new(HostEnv::new(dummy_user_presence));
 
         let rp_id = "example.com";
         let application = crypto::sha256::Sha256::hash(rp_id.as_bytes());
-        let key_handle = ctap_state.encrypt_key_handle(sk, &application);
+        let key_handle = ctap_state.encrypt_key_handle(sk, &application).unwrap();
         let mut message =
             create_authenticate_message(&application, Ctap1Flags::CheckOnly, &key_handle);
         message[1] = 0xEE;
@@ -574,11 +697,11 @@ mod test {
         let mut rng = ThreadRng256 {};
         let dummy_user_presence = |_| panic!("Unexpected user presence check in CTAP1");
         let sk = crypto::ecdsa::SecKey::gensk(&mut rng);
-        let mut ctap_state = CtapState::new(&mut rng, dummy_user_presence);
+        let mut ctap_state = CtapState::new(HostEnv::new(dummy_user_presence));
 
         let rp_id = "example.com";
         let application = crypto::sha256::Sha256::hash(rp_id.as_bytes());
-        let key_handle = ctap_state.encrypt_key_handle(sk, &application);
+        let key_handle = ctap_state.encrypt_key_handle(sk, &application).unwrap();
         let mut message =
             create_authenticate_message(&application, Ctap1Flags::CheckOnly, &key_handle);
         message[2] = 0xEE;
@@ -592,11 +715,11 @@ mod test {
         let mut rng = ThreadRng256 {};
         let dummy_user_presence = |_| panic!("Unexpected user presence check in CTAP1");
         let sk = crypto::ecdsa::SecKey::gensk(&mut rng);
-        let mut ctap_state = CtapState::new(&mut rng, dummy_user_presence);
+        let mut ctap_state = CtapState::new(HostEnv::new(dummy_user_presence));
 
         let rp_id = "example.com";
         let application = crypto::sha256::Sha256::hash(rp_id.as_bytes());
-        let key_handle = ctap_state.encrypt_key_handle(sk, &application);
+        let key_handle = ctap_state.encrypt_key_handle(sk, &application).unwrap();
         let message =
             create_authenticate_message(&application, Ctap1Flags::EnforceUpAndSign, &key_handle);
 
@@ -617,11 +740,11 @@ mod test {
         let mut rng = ThreadRng256 {};
         let dummy_user_presence = |_| panic!("Unexpected user presence check in CTAP1");
         let sk = crypto::ecdsa::SecKey::gensk(&mut rng);
-        let mut ctap_state = CtapState::new(&mut rng, dummy_user_presence);
+        let mut ctap_state = CtapState::new(HostEnv::new(dummy_user_presence));
 
         let rp_id = "example.com";
         let application = crypto::sha256::Sha256::hash(rp_id.as_bytes());
-        let key_handle = ctap_state.encrypt_key_handle(sk, &application);
+        let key_handle = ctap_state.encrypt_key_handle(sk, &application).unwrap();
         let message = create_authenticate_message(
             &application,
             Ctap1Flags::DontEnforceUpAndSign,
@@ -644,10 +767,8 @@ mod test {
         let key_handle = vec![0x00; ENCRYPTED_CREDENTIAL_ID_SIZE];
         let message =
             create_authenticate_message(&application, Ctap1Flags::EnforceUpAndSign, &key_handle);
-
-        let mut rng = ThreadRng256 {};
         let dummy_user_presence = |_| panic!("Unexpected user presence check in CTAP1");
-        let mut ctap_state = CtapState::new(&mut rng, dummy_user_presence);
+        let mut ctap_state = CtapState::new(HostEnv::new(dummy_user_presence));
 
         ctap_state.u2f_up_state.consume_up(START_CLOCK_VALUE);
         ctap_state.u2f_up_state.grant_up(START_CLOCK_VALUE);
@@ -661,10 +782,8 @@ mod test {
         let key_handle = vec![0x00; ENCRYPTED_CREDENTIAL_ID_SIZE];
         let message =
             create_authenticate_message(&application, Ctap1Flags::EnforceUpAndSign, &key_handle);
-
-        let mut rng = ThreadRng256 {};
         let dummy_user_presence = |_| panic!("Unexpected user presence check in CTAP1");
-        let mut ctap_state = CtapState::new(&mut rng, dummy_user_presence);
+        let mut ctap_state = CtapState::new(HostEnv::new(dummy_user_presence));
 
         ctap_state.u2f_up_state.consume_up(START_CLOCK_VALUE);
         ctap_state.u2f_up_state.grant_up(START_CLOCK_VALUE);
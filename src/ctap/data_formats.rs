@@ -49,6 +49,16 @@ impl TryFrom<&cbor::Value> for PublicKeyCredentialRpEntity {
     }
 }
 
+impl From<PublicKeyCredentialRpEntity> for cbor::Value {
+    fn from(entity: PublicKeyCredentialRpEntity) -> Self {
+        cbor_map_options! {
+            "id" => entity.rp_id,
+            "name" => entity.rp_name,
+            "icon" => entity.rp_icon,
+        }
+    }
+}
+
 // https://www.w3.org/TR/webauthn/#dictdef-publickeycredentialuserentity
 #[cfg_attr(any(test, feature = "debug_ctap"), derive(Debug, PartialEq))]
 pub struct PublicKeyCredentialUserEntity {
@@ -363,6 +373,15 @@ impl TryFrom<&cbor::Value> for MakeCredentialOptions {
     }
 }
 
+impl From<MakeCredentialOptions> for cbor::Value {
+    fn from(options: MakeCredentialOptions) -> Self {
+        cbor_map! {
+            "rk" => options.rk,
+            "uv" => options.uv,
+        }
+    }
+}
+
 #[cfg_attr(any(test, feature = "debug_ctap"), derive(Debug, PartialEq))]
 pub struct GetAssertionOptions {
     pub up: bool,
@@ -391,6 +410,106 @@ impl TryFrom<&cbor::Value> for GetAssertionOptions {
     }
 }
 
+impl From<GetAssertionOptions> for cbor::Value {
+    fn from(options: GetAssertionOptions) -> Self {
+        cbor_map! {
+            "up" => options.up,
+            "uv" => options.uv,
+        }
+    }
+}
+
+// https://www.w3.org/TR/webauthn/#sctn-defined-attestation-formats
+#[derive(Clone, Copy, PartialEq)]
+#[cfg_attr(any(test, feature = "debug_ctap"), derive(Debug))]
+pub enum AttestationStatementFormat {
+    None,
+    Packed,
+    FidoU2f,
+    // This is the default for all strings not covered above.
+    // Unknown formats should be ignored, instead of returning errors.
+    Unknown,
+}
+
+impl From<AttestationStatementFormat> for cbor::Value {
+    fn from(format: AttestationStatementFormat) -> Self {
+        match format {
+            AttestationStatementFormat::None => "none",
+            AttestationStatementFormat::Packed => "packed",
+            AttestationStatementFormat::FidoU2f => "fido-u2f",
+            // We should never create this format.
+            AttestationStatementFormat::Unknown => "unknown",
+        }
+        .into()
+    }
+}
+
+impl TryFrom<&cbor::Value> for AttestationStatementFormat {
+    type Error = Ctap2StatusCode;
+
+    fn try_from(cbor_value: &cbor::Value) -> Result<Self, Ctap2StatusCode> {
+        let format_string = read_text_string(cbor_value)?;
+        match &format_string[..] {
+            "none" => Ok(AttestationStatementFormat::None),
+            "packed" => Ok(AttestationStatementFormat::Packed),
+            "fido-u2f" => Ok(AttestationStatementFormat::FidoU2f),
+            _ => Ok(AttestationStatementFormat::Unknown),
+        }
+    }
+}
+
+// The attStmt of an attestation object, whose structure depends on its format.
+#[cfg_attr(test, derive(PartialEq))]
+#[cfg_attr(any(test, feature = "debug_ctap"), derive(Debug))]
+pub enum AttestationStatement {
+    // https://www.w3.org/TR/webauthn/#sctn-none-attestation
+    None,
+    Packed(PackedAttestationStatement),
+    FidoU2f(FidoU2fAttestationStatement),
+}
+
+impl AttestationStatement {
+    pub fn format(&self) -> AttestationStatementFormat {
+        match self {
+            AttestationStatement::None => AttestationStatementFormat::None,
+            AttestationStatement::Packed(_) => AttestationStatementFormat::Packed,
+            AttestationStatement::FidoU2f(_) => AttestationStatementFormat::FidoU2f,
+        }
+    }
+
+    // The statement alone doesn't tell its format, the fmt entry next to it does.
+    pub fn read(
+        format: AttestationStatementFormat,
+        cbor_value: &cbor::Value,
+    ) -> Result<Self, Ctap2StatusCode> {
+        match format {
+            AttestationStatementFormat::None => {
+                if !read_map(cbor_value)?.is_empty() {
+                    return Err(Ctap2StatusCode::CTAP2_ERR_INVALID_CBOR);
+                }
+                Ok(AttestationStatement::None)
+            }
+            AttestationStatementFormat::Packed => Ok(AttestationStatement::Packed(
+                PackedAttestationStatement::try_from(cbor_value)?,
+            )),
+            AttestationStatementFormat::FidoU2f => Ok(AttestationStatement::FidoU2f(
+                FidoU2fAttestationStatement::try_from(cbor_value)?,
+            )),
+            AttestationStatementFormat::Unknown => Err(Ctap2StatusCode::CTAP2_ERR_INVALID_CBOR),
+        }
+    }
+}
+
+impl From<AttestationStatement> for cbor::Value {
+    fn from(att_stmt: AttestationStatement) -> Self {
+        match att_stmt {
+            AttestationStatement::None => cbor_map! {},
+            AttestationStatement::Packed(att_stmt) => att_stmt.into(),
+            AttestationStatement::FidoU2f(att_stmt) => att_stmt.into(),
+        }
+    }
+}
+
 // https://www.w3.org/TR/webauthn/#packed-attestation
 #[cfg_attr(test, derive(PartialEq))]
 #[cfg_attr(any(test, feature = "debug_ctap"), derive(Debug))]
@@ -412,6 +531,71 @@ impl From<PackedAttestationStatement> for cbor::Value {
     }
 }
 
+impl TryFrom<&cbor::Value> for PackedAttestationStatement {
+    type Error = Ctap2StatusCode;
+
+    fn try_from(cbor_value: &cbor::Value) -> Result<Self, Ctap2StatusCode> {
+        let att_stmt_map = read_map(cbor_value)?;
+        let alg = read_integer(ok_or_missing(att_stmt_map.get(&cbor_text!("alg")))?)?;
+        let sig = read_byte_string(ok_or_missing(att_stmt_map.get(&cbor_text!("sig")))?)?;
+        let x5c = match att_stmt_map.get(&cbor_text!("x5c")) {
+            Some(x5c_entry) => Some(
+                read_array(x5c_entry)?
+                    .iter()
+                    .map(read_byte_string)
+                    .collect::<Result<Vec<Vec<u8>>, Ctap2StatusCode>>()?,
+            ),
+            None => None,
+        };
+        let ecdaa_key_id = att_stmt_map
+            .get(&cbor_text!("ecdaaKeyId"))
+            .map(read_byte_string)
+            .transpose()?;
+        Ok(Self {
+            alg,
+            sig,
+            x5c,
+            ecdaa_key_id,
+        })
+    }
+}
+
+// https://www.w3.org/TR/webauthn/#sctn-fido-u2f-attestation
+#[cfg_attr(test, derive(PartialEq))]
+#[cfg_attr(any(test, feature = "debug_ctap"), derive(Debug))]
+pub struct FidoU2fAttestationStatement {
+    pub sig: Vec<u8>,
+    // The x5c array contains exactly the attestation certificate.
+    pub attestation_certificate: Vec<u8>,
+}
+
+impl From<FidoU2fAttestationStatement> for cbor::Value {
+    fn from(att_stmt: FidoU2fAttestationStatement) -> Self {
+        cbor_map! {
+            "sig" => att_stmt.sig,
+            "x5c" => cbor_array![att_stmt.attestation_certificate],
+        }
+    }
+}
+
+impl TryFrom<&cbor::Value> for FidoU2fAttestationStatement {
+    type Error = Ctap2StatusCode;
+
+    fn try_from(cbor_value: &cbor::Value) -> Result<Self, Ctap2StatusCode> {
+        let att_stmt_map = read_map(cbor_value)?;
+        let sig = read_byte_string(ok_or_missing(att_stmt_map.get(&cbor_text!("sig")))?)?;
+        let x5c = read_array(ok_or_missing(att_stmt_map.get(&cbor_text!("x5c")))?)?;
+        if x5c.len() != 1 {
+            return Err(Ctap2StatusCode::CTAP2_ERR_INVALID_CBOR);
+        }
+        let attestation_certificate = read_byte_string(&x5c[0])?;
+        Ok(Self {
+            sig,
+            attestation_certificate,
+        })
+    }
+}
+
 #[derive(PartialEq)]
 #[cfg_attr(any(test, feature = "debug_ctap"), derive(Debug))]
 pub enum SignatureAlgorithm {
@@ -516,7 +700,7 @@ impl TryFrom<cbor::Value> for PublicKeyCredentialSource {
         //    including to be reintroduced with the same semantics. In other words, removing a field
         //    is permanent.
         // 2. OpenSK is never used with a more recent version of the storage. In particular, OpenSK
-        //    is never rolled-back.
+        //    refuses to boot on a storage with a more recent schema version.
         // As a consequence, the unknown fields are only reserved fields and don't need to be
         // preserved.
         Ok(PublicKeyCredentialSource {
@@ -531,6 +715,50 @@ impl TryFrom<cbor::Value> for PublicKeyCredentialSource {
     }
 }
 
+// The fields of a serialized credential that identify it. They are read without the secret fields,
+// which the persistent store encrypts at rest, see `map_credential_secrets`.
+pub struct CredentialIdentity {
+    pub credential_id: Vec<u8>,
+    pub rp_id: String,
+    pub user_handle: Vec<u8>,
+}
+
+impl TryFrom<cbor::Value> for CredentialIdentity {
+    type Error = Ctap2StatusCode;
+
+    fn try_from(cbor_value: cbor::Value) -> Result<Self, Ctap2StatusCode> {
+        use PublicKeyCredentialSourceField::*;
+        let mut map = extract_map(cbor_value)?;
+        let credential_id = extract_byte_string(ok_or_missing(map.remove(&CredentialId.into()))?)?;
+        let rp_id = extract_text_string(ok_or_missing(map.remove(&RpId.into()))?)?;
+        let user_handle = extract_byte_string(ok_or_missing(map.remove(&UserHandle.into()))?)?;
+        Ok(CredentialIdentity {
+            credential_id,
+            rp_id,
+            user_handle,
+        })
+    }
+}
+
+// Replaces the fields of a serialized credential that hold secrets, namely the private key and
+// cred_random. The function is called with the tag of the field and its value, and returns the new
+// value or None to fail. The persistent store uses it to encrypt those fields at rest.
+pub fn map_credential_secrets(
+    cbor_value: cbor::Value,
+    mut f: impl FnMut(u64, Vec<u8>) -> Option<Vec<u8>>,
+) -> Result<cbor::Value, Ctap2StatusCode> {
+    use PublicKeyCredentialSourceField::*;
+    let mut map = extract_map(cbor_value)?;
+    for &field in &[PrivateKey as u64, CredRandom as u64] {
+        if let Some(value) = map.remove(&field.into()) {
+            let value = f(field, extract_byte_string(value)?)
+                .ok_or(Ctap2StatusCode::CTAP2_ERR_INVALID_CREDENTIAL)?;
+            map.insert(field.into(), value.into());
+        }
+    }
+    Ok(cbor::Value::Map(map))
+}
+
 // TODO(kaczmarczyck) we could decide to split this data type up
 // It depends on the algorithm though, I think.
 // So before creating a mess, this is my workaround.
@@ -570,6 +798,20 @@ impl From<ecdh::PubKey> for CoseKey {
     }
 }
 
+impl From<CoseKey> for cbor::Value {
+    fn from(cose_key: CoseKey) -> Self {
+        cbor_map_btree!(cose_key.0)
+    }
+}
+
+impl TryFrom<&cbor::Value> for CoseKey {
+    type Error = Ctap2StatusCode;
+
+    fn try_from(cbor_value: &cbor::Value) -> Result<Self, Ctap2StatusCode> {
+        Ok(CoseKey(read_map(cbor_value)?.clone()))
+    }
+}
+
 impl TryFrom<CoseKey> for ecdh::PubKey {
     type Error = Ctap2StatusCode;
 
@@ -1008,7 +1250,7 @@ mod test {
     }
 
     #[test]
-    fn test_from_public_key_credential_rp_entity() {
+    fn test_from_into_public_key_credential_rp_entity() {
         let cbor_rp_entity = cbor_map! {
             "id" => "example.com",
             "name" => "Example",
@@ -1021,6 +1263,8 @@ mod test {
             rp_icon: Some("example.com/icon.png".to_string()),
         };
         assert_eq!(rp_entity, Ok(expected_rp_entity));
+        let created_cbor: cbor::Value = rp_entity.unwrap().into();
+        assert_eq!(created_cbor, cbor_rp_entity);
     }
 
     #[test]
@@ -1184,7 +1428,7 @@ mod test {
     }
 
     #[test]
-    fn test_from_make_credential_options() {
+    fn test_from_into_make_credential_options() {
         let cbor_make_options = cbor_map! {
             "rk" => true,
             "uv" => false,
@@ -1195,10 +1439,12 @@ mod test {
             uv: false,
         };
         assert_eq!(make_options, Ok(expected_make_options));
+        let created_cbor: cbor::Value = make_options.unwrap().into();
+        assert_eq!(created_cbor, cbor_make_options);
     }
 
     #[test]
-    fn test_from_get_assertion_options() {
+    fn test_from_into_get_assertion_options() {
         let cbor_get_assertion = cbor_map! {
             "up" => true,
             "uv" => false,
@@ -1209,10 +1455,12 @@ mod test {
             uv: false,
         };
         assert_eq!(get_assertion, Ok(expected_get_assertion));
+        let created_cbor: cbor::Value = get_assertion.unwrap().into();
+        assert_eq!(created_cbor, cbor_get_assertion);
     }
 
     #[test]
-    fn test_into_packed_attestation_statement() {
+    fn test_from_into_packed_attestation_statement() {
         let certificate: cbor::values::KeyType = cbor_bytes![vec![0x5C, 0x5C, 0x5C, 0x5C]];
         let cbor_packed_attestation_statement = cbor_map! {
             "alg" => 1,
@@ -1228,6 +1476,18 @@ mod test {
         };
         let created_cbor: cbor::Value = packed_attestation_statement.into();
         assert_eq!(created_cbor, cbor_packed_attestation_statement);
+        let created_attestation_statement =
+            PackedAttestationStatement::try_from(&cbor_packed_attestation_statement);
+        let expected_attestation_statement = PackedAttestationStatement {
+            alg: 1,
+            sig: vec![0x55, 0x55, 0x55, 0x55],
+            x5c: Some(vec![vec![0x5C, 0x5C, 0x5C, 0x5C]]),
+            ecdaa_key_id: Some(vec![0xEC, 0xDA, 0x1D]),
+        };
+        assert_eq!(
+            created_attestation_statement,
+            Ok(expected_attestation_statement)
+        );
     }
 
     #[test]
@@ -1236,7 +1496,9 @@ mod test {
         let sk = crypto::ecdh::SecKey::gensk(&mut rng);
         let pk = sk.genpk();
         let cose_key = CoseKey::from(pk.clone());
-        let created_pk = ecdh::PubKey::try_from(cose_key);
+        let cbor_cose_key: cbor::Value = cose_key.into();
+        let created_cose_key = CoseKey::try_from(&cbor_cose_key).unwrap();
+        let created_pk = ecdh::PubKey::try_from(created_cose_key);
         assert_eq!(created_pk, Ok(pk));
     }
 
@@ -1289,6 +1551,66 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_credential_identity() {
+        let mut rng = ThreadRng256 {};
+        let credential = PublicKeyCredentialSource {
+            key_type: PublicKeyCredentialType::PublicKey,
+            credential_id: rng.gen_uniform_u8x32().to_vec(),
+            private_key: crypto::ecdsa::SecKey::gensk(&mut rng),
+            rp_id: "example.com".to_string(),
+            user_handle: b"foo".to_vec(),
+            other_ui: None,
+            cred_random: Some(vec![0x00; 32]),
+        };
+
+        // The identity doesn't need the secret fields to be readable.
+        let cbor_value = map_credential_secrets(credential.clone().into(), |_, _| Some(vec![]));
+        let identity = CredentialIdentity::try_from(cbor_value.unwrap()).unwrap();
+        assert_eq!(identity.credential_id, credential.credential_id);
+        assert_eq!(identity.rp_id, credential.rp_id);
+        assert_eq!(identity.user_handle, credential.user_handle);
+    }
+
+    #[test]
+    fn test_map_credential_secrets() {
+        let mut rng = ThreadRng256 {};
+        let credential = PublicKeyCredentialSource {
+            key_type: PublicKeyCredentialType::PublicKey,
+            credential_id: rng.gen_uniform_u8x32().to_vec(),
+            private_key: crypto::ecdsa::SecKey::gensk(&mut rng),
+            rp_id: "example.com".to_string(),
+            user_handle: b"foo".to_vec(),
+            other_ui: None,
+            cred_random: Some(vec![0x00; 32]),
+        };
+
+        // Only the private key and cred_random are secrets.
+        let mut fields = Vec::new();
+        let flip = |field, mut value: Vec<u8>| {
+            fields.push(field);
+            value.iter_mut().for_each(|byte| *byte ^= 0xFF);
+            Some(value)
+        };
+        let cbor_value = map_credential_secrets(credential.clone().into(), flip).unwrap();
+        assert_eq!(fields, vec![1, 5]);
+        let unflip = |_, mut value: Vec<u8>| {
+            value.iter_mut().for_each(|byte| *byte ^= 0xFF);
+            Some(value)
+        };
+        let cbor_value = map_credential_secrets(cbor_value, unflip).unwrap();
+        assert_eq!(
+            PublicKeyCredentialSource::try_from(cbor_value),
+            Ok(credential.clone())
+        );
+
+        // Failing to map a secret fails.
+        assert_eq!(
+            map_credential_secrets(credential.into(), |_, _| None),
+            Err(Ctap2StatusCode::CTAP2_ERR_INVALID_CREDENTIAL)
+        );
+    }
+
     #[test]
     fn test_credential_source_invalid_cbor() {
         assert!(PublicKeyCredentialSource::try_from(cbor_false!()).is_err());
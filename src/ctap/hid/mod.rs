@@ -12,8 +12,12 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-mod receive;
+pub mod receive;
 mod send;
+// The firmware only records traces, and the desktop build doesn't print them on the console.
+#[cfg(feature = "debug_ctap")]
+pub mod trace;
+pub mod transport;
 
 use self::receive::MessageAssembler;
 use self::send::HidPacketIterator;
@@ -22,11 +26,11 @@ use super::ctap1;
 use super::status_code::Ctap2StatusCode;
 use super::timed_permission::TimedPermission;
 use super::CtapState;
+use crate::env::Env;
 use crate::timer::{ClockValue, Duration, Timestamp};
 use alloc::vec::Vec;
 #[cfg(feature = "debug_ctap")]
 use core::fmt::Write;
-use crypto::rng256::Rng256;
 #[cfg(feature = "debug_ctap")]
 use libtock::console::Console;
 
@@ -83,34 +87,34 @@ pub enum KeepaliveStatus {
 impl CtapHid {
     // CTAP specification (version 20190130) section 8.1.3
     const CHANNEL_RESERVED: ChannelID = [0, 0, 0, 0];
-    const CHANNEL_BROADCAST: ChannelID = [0xFF, 0xFF, 0xFF, 0xFF];
+    pub const CHANNEL_BROADCAST: ChannelID = [0xFF, 0xFF, 0xFF, 0xFF];
     const TYPE_INIT_BIT: u8 = 0x80;
     const PACKET_TYPE_MASK: u8 = 0x80;
 
     // CTAP specification (version 20190130) section 8.1.9
-    const COMMAND_PING: u8 = 0x01;
-    const COMMAND_MSG: u8 = 0x03;
-    const COMMAND_INIT: u8 = 0x06;
-    const COMMAND_CBOR: u8 = 0x10;
+    pub const COMMAND_PING: u8 = 0x01;
+    pub const COMMAND_MSG: u8 = 0x03;
+    pub const COMMAND_INIT: u8 = 0x06;
+    pub const COMMAND_CBOR: u8 = 0x10;
     pub const COMMAND_CANCEL: u8 = 0x11;
-    const COMMAND_KEEPALIVE: u8 = 0x3B;
-    const COMMAND_ERROR: u8 = 0x3F;
+    pub const COMMAND_KEEPALIVE: u8 = 0x3B;
+    pub const COMMAND_ERROR: u8 = 0x3F;
     // TODO: optional lock command
-    const COMMAND_LOCK: u8 = 0x04;
-    const COMMAND_WINK: u8 = 0x08;
-    const COMMAND_VENDOR_FIRST: u8 = 0x40;
-    const COMMAND_VENDOR_LAST: u8 = 0x7F;
+    pub const COMMAND_LOCK: u8 = 0x04;
+    pub const COMMAND_WINK: u8 = 0x08;
+    pub const COMMAND_VENDOR_FIRST: u8 = 0x40;
+    pub const COMMAND_VENDOR_LAST: u8 = 0x7F;
 
     // CTAP specification (version 20190130) section 8.1.9.1.6
-    const ERR_INVALID_CMD: u8 = 0x01;
-    const ERR_INVALID_PAR: u8 = 0x02;
-    const ERR_INVALID_LEN: u8 = 0x03;
-    const ERR_INVALID_SEQ: u8 = 0x04;
-    const ERR_MSG_TIMEOUT: u8 = 0x05;
-    const ERR_CHANNEL_BUSY: u8 = 0x06;
-    const ERR_LOCK_REQUIRED: u8 = 0x0A;
-    const ERR_INVALID_CHANNEL: u8 = 0x0B;
-    const ERR_OTHER: u8 = 0x7F;
+    pub const ERR_INVALID_CMD: u8 = 0x01;
+    pub const ERR_INVALID_PAR: u8 = 0x02;
+    pub const ERR_INVALID_LEN: u8 = 0x03;
+    pub const ERR_INVALID_SEQ: u8 = 0x04;
+    pub const ERR_MSG_TIMEOUT: u8 = 0x05;
+    pub const ERR_CHANNEL_BUSY: u8 = 0x06;
+    pub const ERR_LOCK_REQUIRED: u8 = 0x0A;
+    pub const ERR_INVALID_CHANNEL: u8 = 0x0B;
+    pub const ERR_OTHER: u8 = 0x7F;
 
     // CTAP specification (version 20190130) section 8.1.9.1.3
     const PROTOCOL_VERSION: u8 = 2;
@@ -121,9 +125,9 @@ impl CtapHid {
     const DEVICE_VERSION_MINOR: u8 = 0;
     const DEVICE_VERSION_BUILD: u8 = 0;
 
-    const CAPABILITY_WINK: u8 = 0x01;
-    const CAPABILITY_CBOR: u8 = 0x04;
-    const CAPABILITY_NMSG: u8 = 0x08;
+    pub const CAPABILITY_WINK: u8 = 0x01;
+    pub const CAPABILITY_CBOR: u8 = 0x04;
+    pub const CAPABILITY_NMSG: u8 = 0x08;
     // Capabilitites currently supported by this device.
     #[cfg(feature = "with_ctap1")]
     const CAPABILITIES: u8 = CtapHid::CAPABILITY_WINK | CtapHid::CAPABILITY_CBOR;
@@ -143,18 +147,20 @@ impl CtapHid {
         }
     }
 
+    // Resets the current transaction, discarding any partially received message.
+    // This is used when the reply to a message couldn't be sent.
+    pub fn reset(&mut self) {
+        self.assembler.reset();
+    }
+
     // Process an incoming USB HID packet, and optionally returns a list of outgoing packets to
     // send as a reply.
-    pub fn process_hid_packet<R, CheckUserPresence>(
+    pub fn process_hid_packet<E: Env>(
         &mut self,
         packet: &HidPacket,
         clock_value: ClockValue,
-        ctap_state: &mut CtapState<R, CheckUserPresence>,
-    ) -> HidPacketIterator
-    where
-        R: Rng256,
-        CheckUserPresence: Fn(ChannelID) -> Result<(), Ctap2StatusCode>,
-    {
+        ctap_state: &mut CtapState<E>,
+    ) -> HidPacketIterator {
         // TODO: Send COMMAND_KEEPALIVE every 100ms?
         match self
             .assembler
@@ -199,29 +205,7 @@ impl CtapHid {
                         // don't handle any other packet in the meantime.
                         // TODO: Send keep-alive packets in the meantime.
                         let response = ctap_state.process_command(&message.payload, cid);
-                        if let Some(iterator) = CtapHid::split_message(Message {
-                            cid,
-                            cmd: CtapHid::COMMAND_CBOR,
-                            payload: response,
-                        }) {
-                            iterator
-                        } else {
-                            // Handle the case of a payload > 7609 bytes.
-                            // Although this shouldn't happen if the FIDO2 commands are implemented
-                            // correctly, we reply with a vendor specific code instead of silently
-                            // ignoring the error.
-                            //
-                            // The error payload that we send instead is 1 <= 7609 bytes, so it is
-                            // safe to unwrap() the result.
-                            CtapHid::split_message(Message {
-                                cid,
-                                cmd: CtapHid::COMMAND_CBOR,
-                                payload: vec![
-                                    Ctap2StatusCode::CTAP2_ERR_VENDOR_RESPONSE_TOO_LONG as u8,
-                                ],
-                            })
-                            .unwrap()
-                        }
+                        CtapHid::cbor_response_message(cid, CtapHid::COMMAND_CBOR, response)
                     }
                     // CTAP specification (version 20190130) section 8.1.9.1.3
                     CtapHid::COMMAND_INIT => {
@@ -292,6 +276,17 @@ impl CtapHid {
                         })
                         .unwrap()
                     }
+                    // CTAP specification (version 20190130) section 8.1.9.3
+                    // Vendor commands share the CBOR format of CTAP2 vendor commands, see the
+                    // vendor module. Commands without registered subcommands are unknown.
+                    CtapHid::COMMAND_VENDOR_FIRST..=CtapHid::COMMAND_VENDOR_LAST
+                        if ctap_state.has_vendor_command(message.cmd) =>
+                    {
+                        let mut command = vec![message.cmd];
+                        command.extend(&message.payload);
+                        let response = ctap_state.process_command(&command, cid);
+                        CtapHid::cbor_response_message(cid, message.cmd, response)
+                    }
                     // CTAP specification (version 20190130) section 8.1.9.2.2
                     // TODO: implement LOCK
                     _ => {
@@ -359,6 +354,30 @@ impl CtapHid {
         .unwrap()
     }
 
+    fn cbor_response_message(cid: ChannelID, cmd: u8, response: Vec<u8>) -> HidPacketIterator {
+        if let Some(iterator) = CtapHid::split_message(Message {
+            cid,
+            cmd,
+            payload: response,
+        }) {
+            iterator
+        } else {
+            // Handle the case of a payload > 7609 bytes.
+            // Although this shouldn't happen if the FIDO2 commands are implemented
+            // correctly, we reply with a vendor specific code instead of silently
+            // ignoring the error.
+            //
+            // The error payload that we send instead is 1 <= 7609 bytes, so it is
+            // safe to unwrap() the result.
+            CtapHid::split_message(Message {
+                cid,
+                cmd,
+                payload: vec![Ctap2StatusCode::CTAP2_ERR_VENDOR_RESPONSE_TOO_LONG as u8],
+            })
+            .unwrap()
+        }
+    }
+
     pub fn process_single_packet(packet: &HidPacket) -> (&ChannelID, ProcessedPacket) {
         let (cid, rest) = array_refs![packet, 4, 60];
         if rest[0] & CtapHid::PACKET_TYPE_MASK != 0 {
@@ -434,22 +453,20 @@ impl CtapHid {
 
 #[cfg(test)]
 mod test {
+    use super::super::vendor::{NoParameters, VendorAuthorization, VendorSubCommand};
     use super::*;
-    use crypto::rng256::ThreadRng256;
+    use crate::env::host::HostEnv;
 
     const CLOCK_FREQUENCY_HZ: usize = 32768;
     // Except for tests for timeouts (done in ctap1.rs), transactions are time independant.
     const DUMMY_CLOCK_VALUE: ClockValue = ClockValue::new(0, CLOCK_FREQUENCY_HZ);
     const DUMMY_TIMESTAMP: Timestamp<isize> = Timestamp::from_ms(0);
 
-    fn process_messages<CheckUserPresence>(
+    fn process_messages<E: Env>(
         ctap_hid: &mut CtapHid,
-        ctap_state: &mut CtapState<ThreadRng256, CheckUserPresence>,
+        ctap_state: &mut CtapState<E>,
         request: Vec<Message>,
-    ) -> Option<Vec<Message>>
-    where
-        CheckUserPresence: Fn(ChannelID) -> Result<(), Ctap2StatusCode>,
-    {
+    ) -> Option<Vec<Message>> {
         let mut result = Vec::new();
         let mut assembler_reply = MessageAssembler::new();
         for msg_request in request {
@@ -468,13 +485,7 @@ mod test {
         Some(result)
     }
 
-    fn cid_from_init<CheckUserPresence>(
-        ctap_hid: &mut CtapHid,
-        ctap_state: &mut CtapState<ThreadRng256, CheckUserPresence>,
-    ) -> ChannelID
-    where
-        CheckUserPresence: Fn(ChannelID) -> Result<(), Ctap2StatusCode>,
-    {
+    fn cid_from_init<E: Env>(ctap_hid: &mut CtapHid, ctap_state: &mut CtapState<E>) -> ChannelID {
         let nonce = vec![0x12, 0x34, 0x56, 0x78, 0x9A, 0xBC, 0xDE, 0xF0];
         let reply = process_messages(
             ctap_hid,
@@ -523,9 +534,8 @@ mod test {
 
     #[test]
     fn test_command_init() {
-        let mut rng = ThreadRng256 {};
         let user_immediately_present = |_| Ok(());
-        let mut ctap_state = CtapState::new(&mut rng, user_immediately_present);
+        let mut ctap_state = CtapState::new(HostEnv::new(user_immediately_present));
         let mut ctap_hid = CtapHid::new();
 
         let reply = process_messages(
@@ -568,9 +578,8 @@ mod test {
 
     #[test]
     fn test_command_ping() {
-        let mut rng = ThreadRng256 {};
         let user_immediately_present = |_| Ok(());
-        let mut ctap_state = CtapState::new(&mut rng, user_immediately_present);
+        let mut ctap_state = CtapState::new(HostEnv::new(user_immediately_present));
         let mut ctap_hid = CtapHid::new();
         let cid = cid_from_init(&mut ctap_hid, &mut ctap_state);
 
@@ -593,4 +602,82 @@ mod test {
             }])
         );
     }
+
+    struct Version;
+
+    impl<E: Env> VendorSubCommand<E> for Version {
+        const COMMAND: u8 = 0x50;
+        const SUB_COMMAND: u8 = 0x01;
+        const AUTHORIZATION: VendorAuthorization = VendorAuthorization::None;
+        type Parameters = NoParameters;
+        type Response = u64;
+
+        fn process(
+            _: &mut CtapState<E>,
+            _: Option<NoParameters>,
+        ) -> Result<Option<u64>, Ctap2StatusCode> {
+            Ok(Some(7))
+        }
+    }
+
+    #[test]
+    fn test_command_vendor() {
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(HostEnv::new(user_immediately_present));
+        let mut ctap_hid = CtapHid::new();
+        let cid = cid_from_init(&mut ctap_hid, &mut ctap_state);
+        // The CBOR map {1: 1}, to call the first subcommand.
+        let payload = vec![0xA1, 0x01, 0x01];
+
+        let reply = process_messages(
+            &mut ctap_hid,
+            &mut ctap_state,
+            vec![Message {
+                cid,
+                cmd: 0x50,
+                payload: payload.clone(),
+            }],
+        );
+        assert_eq!(
+            reply,
+            Some(vec![Message {
+                cid,
+                cmd: CtapHid::COMMAND_ERROR,
+                payload: vec![CtapHid::ERR_INVALID_CMD]
+            }])
+        );
+
+        ctap_state.register_vendor_command::<Version>();
+        let reply = process_messages(
+            &mut ctap_hid,
+            &mut ctap_state,
+            vec![
+                Message {
+                    cid,
+                    cmd: 0x50,
+                    payload: payload.clone(),
+                },
+                Message {
+                    cid,
+                    cmd: CtapHid::COMMAND_CBOR,
+                    payload: [&[0x50], &payload[..]].concat(),
+                },
+            ],
+        );
+        assert_eq!(
+            reply,
+            Some(vec![
+                Message {
+                    cid,
+                    cmd: 0x50,
+                    payload: vec![0x00, 0x07]
+                },
+                Message {
+                    cid,
+                    cmd: CtapHid::COMMAND_CBOR,
+                    payload: vec![0x00, 0x07]
+                }
+            ])
+        );
+    }
 }
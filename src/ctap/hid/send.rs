@@ -14,6 +14,7 @@
 
 use super::{CtapHid, HidPacket, Message};
 
+#[derive(Clone)]
 pub struct HidPacketIterator(Option<MessageSplitter>);
 
 impl HidPacketIterator {
@@ -42,6 +43,7 @@ impl Iterator for HidPacketIterator {
     }
 }
 
+#[derive(Clone)]
 pub struct MessageSplitter {
     message: Message,
     packet: HidPacket,
@@ -0,0 +1,459 @@
+// Copyright 2019 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Traces of the HID packets exchanged by the authenticator, to reproduce bugs from the field.
+//
+// A trace is a header followed by records, with integers in big endian:
+// - The header is the magic "OSKTRACE", the format version, and a flag byte telling whether the
+//   32-byte seed of the rng follows. Without the seed, replies that depend on randomness can't be
+//   reproduced.
+// - Each record is a direction byte, the time in milliseconds at which the packet was received or
+//   its reply computed, and the 64-byte packet.
+//
+// Devices without a file system print the same bytes in hexadecimal on the debug console, on lines
+// starting with CONSOLE_PREFIX. Traces are only decoded and replayed on the host.
+
+use super::send::HidPacketIterator;
+#[cfg(feature = "std")]
+use super::CtapHid;
+use super::HidPacket;
+#[cfg(feature = "std")]
+use crate::ctap::CtapState;
+#[cfg(feature = "std")]
+use crate::env::Env;
+use crate::timer::{ClockValue, Timestamp};
+use alloc::vec::Vec;
+use core::fmt::Write;
+
+const MAGIC: &[u8; 8] = b"OSKTRACE";
+const VERSION: u8 = 1;
+const HEADER_LENGTH: usize = 10;
+const NO_SEED: u8 = 0x00;
+const WITH_SEED: u8 = 0x01;
+const RECORD_LENGTH: usize = 69;
+// Replays use a millisecond clock, like the host environment.
+#[cfg(feature = "std")]
+const CLOCK_FREQUENCY_HZ: usize = 1000;
+
+pub const CONSOLE_PREFIX: &str = "CTAP trace: ";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    Received = 0x00,
+    Sent = 0x01,
+}
+
+#[derive(Clone, Copy)]
+pub struct Record {
+    pub direction: Direction,
+    pub time_ms: u32,
+    pub packet: HidPacket,
+}
+
+impl Record {
+    fn new(direction: Direction, clock_value: ClockValue, packet: HidPacket) -> Record {
+        Record {
+            direction,
+            time_ms: Timestamp::<isize>::from_clock_value(clock_value).ms() as u32,
+            packet,
+        }
+    }
+
+    fn encode(&self) -> [u8; RECORD_LENGTH] {
+        let mut bytes = [0; RECORD_LENGTH];
+        bytes[0] = self.direction as u8;
+        bytes[1..5].copy_from_slice(&self.time_ms.to_be_bytes());
+        bytes[5..].copy_from_slice(&self.packet);
+        bytes
+    }
+
+    #[cfg(feature = "std")]
+    fn decode(bytes: &[u8; RECORD_LENGTH]) -> Result<Record, TraceError> {
+        let direction = match bytes[0] {
+            0x00 => Direction::Received,
+            0x01 => Direction::Sent,
+            _ => return Err(TraceError::InvalidRecord),
+        };
+        let mut packet = [0; 64];
+        packet.copy_from_slice(&bytes[5..]);
+        Ok(Record {
+            direction,
+            time_ms: u32::from_be_bytes(*array_ref!(bytes, 1, 4)),
+            packet,
+        })
+    }
+
+    #[cfg(feature = "std")]
+    pub fn clock_value(&self) -> ClockValue {
+        ClockValue::new(self.time_ms as isize, CLOCK_FREQUENCY_HZ)
+    }
+}
+
+#[cfg(feature = "std")]
+#[derive(Debug, PartialEq)]
+pub enum TraceError {
+    InvalidHeader,
+    UnsupportedVersion,
+    InvalidRecord,
+    Truncated,
+    InvalidHex,
+}
+
+#[cfg(feature = "std")]
+pub struct Trace {
+    pub seed: Option<[u8; 32]>,
+    pub records: Vec<Record>,
+}
+
+#[cfg(feature = "std")]
+impl Trace {
+    pub fn decode(bytes: &[u8]) -> Result<Trace, TraceError> {
+        if bytes.len() < HEADER_LENGTH || &bytes[..MAGIC.len()] != MAGIC {
+            return Err(TraceError::InvalidHeader);
+        }
+        if bytes[MAGIC.len()] != VERSION {
+            return Err(TraceError::UnsupportedVersion);
+        }
+        let mut records = &bytes[HEADER_LENGTH..];
+        let seed = match bytes[MAGIC.len() + 1] {
+            NO_SEED => None,
+            WITH_SEED => {
+                if records.len() < 32 {
+                    return Err(TraceError::Truncated);
+                }
+                let seed = *array_ref!(records, 0, 32);
+                records = &records[32..];
+                Some(seed)
+            }
+            _ => return Err(TraceError::InvalidHeader),
+        };
+        if records.len() % RECORD_LENGTH != 0 {
+            return Err(TraceError::Truncated);
+        }
+        let records = records
+            .chunks(RECORD_LENGTH)
+            .map(|record| Record::decode(array_ref!(record, 0, RECORD_LENGTH)))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Trace { seed, records })
+    }
+
+    // Decodes a trace from a console log, ignoring the other lines. The log may span several
+    // boots of the device, in which case only the trace of the last one is kept.
+    pub fn from_console(log: &str) -> Result<Trace, TraceError> {
+        let mut bytes = Vec::new();
+        for line in log.lines() {
+            let hex = match line.find(CONSOLE_PREFIX) {
+                Some(start) => line[start + CONSOLE_PREFIX.len()..].trim(),
+                None => continue,
+            };
+            let line_bytes = decode_hex(hex)?;
+            if line_bytes.starts_with(MAGIC) {
+                bytes.clear();
+            }
+            bytes.extend_from_slice(&line_bytes);
+        }
+        Trace::decode(&bytes)
+    }
+}
+
+#[cfg(feature = "std")]
+fn decode_hex(hex: &str) -> Result<Vec<u8>, TraceError> {
+    if !hex.is_ascii() || hex.len() % 2 != 0 {
+        return Err(TraceError::InvalidHex);
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| TraceError::InvalidHex))
+        .collect()
+}
+
+// Destination of the encoded trace.
+pub trait TraceWriter {
+    fn append(&mut self, bytes: &[u8]);
+}
+
+impl TraceWriter for Vec<u8> {
+    fn append(&mut self, bytes: &[u8]) {
+        self.extend_from_slice(bytes);
+    }
+}
+
+// Writes each part of the trace as a line on the console.
+pub struct ConsoleWriter<W: Write>(pub W);
+
+impl<W: Write> TraceWriter for ConsoleWriter<W> {
+    fn append(&mut self, bytes: &[u8]) {
+        write!(self.0, "{}", CONSOLE_PREFIX).unwrap();
+        for byte in bytes {
+            write!(self.0, "{:02x}", byte).unwrap();
+        }
+        writeln!(self.0).unwrap();
+    }
+}
+
+#[cfg(feature = "std")]
+impl TraceWriter for std::fs::File {
+    fn append(&mut self, bytes: &[u8]) {
+        use std::io::Write;
+        self.write_all(bytes).expect("cannot write the trace");
+    }
+}
+
+pub struct Recorder<W: TraceWriter> {
+    writer: W,
+}
+
+impl<W: TraceWriter> Recorder<W> {
+    // The seed should only be given if the rng of the authenticator is seeded with it.
+    pub fn new(mut writer: W, seed: Option<&[u8; 32]>) -> Recorder<W> {
+        let mut header = Vec::with_capacity(HEADER_LENGTH + 32);
+        header.extend_from_slice(MAGIC);
+        header.push(VERSION);
+        match seed {
+            None => header.push(NO_SEED),
+            Some(seed) => {
+                header.push(WITH_SEED);
+                header.extend_from_slice(seed);
+            }
+        }
+        writer.append(&header);
+        Recorder { writer }
+    }
+
+    pub fn record_received(&mut self, clock_value: ClockValue, packet: &HidPacket) {
+        let record = Record::new(Direction::Received, clock_value, *packet);
+        self.writer.append(&record.encode());
+    }
+
+    // Records the reply as computed, even if sending it fails later.
+    pub fn record_reply(&mut self, clock_value: ClockValue, reply: &HidPacketIterator) {
+        for packet in reply.clone() {
+            let record = Record::new(Direction::Sent, clock_value, packet);
+            self.writer.append(&record.encode());
+        }
+    }
+
+    #[cfg(test)]
+    fn into_writer(self) -> W {
+        self.writer
+    }
+}
+
+// The reply of the authenticator to a received packet, when it differs from the trace.
+#[cfg(feature = "std")]
+pub struct Divergence {
+    // Index of the received packet in the records of the trace.
+    pub index: usize,
+    pub expected: Vec<HidPacket>,
+    pub actual: Vec<HidPacket>,
+}
+
+// Feeds the received packets of the trace to the authenticator, like the main loop does, and
+// returns the replies that differ from the recorded ones.
+// The outcome of user presence checks comes from the environment of ctap_state, which also grants
+// user presence for U2F instead of the buttons of the device.
+#[cfg(feature = "std")]
+pub fn replay<E: Env>(trace: &Trace, ctap_state: &mut CtapState<E>) -> Vec<Divergence> {
+    let mut ctap_hid = CtapHid::new();
+    let mut divergences = Vec::new();
+    let mut index = 0;
+    while index < trace.records.len() {
+        let record = &trace.records[index];
+        index += 1;
+        if record.direction != Direction::Received {
+            continue;
+        }
+        let expected: Vec<HidPacket> = trace.records[index..]
+            .iter()
+            .take_while(|reply| reply.direction == Direction::Sent)
+            .map(|reply| reply.packet)
+            .collect();
+
+        let now = record.clock_value();
+        #[cfg(feature = "with_ctap1")]
+        {
+            if ctap_state.u2f_up_state.is_up_needed(now)
+                && ctap_state.env().check_user_presence([0xFF; 4]).is_ok()
+            {
+                ctap_state.u2f_up_state.grant_up(now);
+            }
+        }
+        ctap_state.check_disable_reset(Timestamp::<isize>::from_clock_value(now));
+        ctap_hid.wink_permission = ctap_hid.wink_permission.check_expiration(now);
+        let actual: Vec<HidPacket> = ctap_hid
+            .process_hid_packet(&record.packet, now, ctap_state)
+            .collect();
+
+        // Arrays of 64 bytes can only be compared as slices.
+        if actual.len() != expected.len()
+            || actual.iter().zip(&expected).any(|(a, e)| a[..] != e[..])
+        {
+            divergences.push(Divergence {
+                index: index - 1,
+                expected,
+                actual,
+            });
+        }
+    }
+    divergences
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::{ChannelID, Message};
+    use super::*;
+    use crate::env::host::HostEnv;
+    use crypto::rng256::SeededRng256;
+
+    const SEED: [u8; 32] = [0x5E; 32];
+    // ClientPin getKeyAgreement, whose reply depends on the rng.
+    const GET_KEY_AGREEMENT: [u8; 6] = [0x06, 0xA2, 0x01, 0x01, 0x02, 0x02];
+
+    fn new_ctap_state(seed: [u8; 32]) -> CtapState<impl Env> {
+        CtapState::new(HostEnv::with_storage_and_rng(
+            |_| Ok(()),
+            crate::ctap::storage::new_ram_storage(),
+            SeededRng256::new(seed),
+        ))
+    }
+
+    fn packets(cid: ChannelID, cmd: u8, payload: &[u8]) -> Vec<HidPacket> {
+        HidPacketIterator::new(Message {
+            cid,
+            cmd,
+            payload: payload.to_vec(),
+        })
+        .unwrap()
+        .collect()
+    }
+
+    // Records a session allocating a channel, then getting info and a key agreement key, like the
+    // main loop would.
+    fn record_session(seed: Option<&[u8; 32]>) -> Vec<u8> {
+        let mut ctap_state = new_ctap_state(SEED);
+        let mut ctap_hid = CtapHid::new();
+        let mut recorder = Recorder::new(Vec::new(), seed);
+        let mut time_ms = 0;
+        let mut exchange = |packet: HidPacket| {
+            time_ms += 10;
+            let now = ClockValue::new(time_ms, CLOCK_FREQUENCY_HZ);
+            recorder.record_received(now, &packet);
+            let reply = ctap_hid.process_hid_packet(&packet, now, &mut ctap_state);
+            recorder.record_reply(now, &reply);
+            reply.collect::<Vec<_>>()
+        };
+
+        let init = packets(
+            CtapHid::CHANNEL_BROADCAST,
+            CtapHid::COMMAND_INIT,
+            &[0x42; 8],
+        );
+        let reply = exchange(init[0]);
+        let cid = *array_ref!(reply[0], 15, 4);
+        for &payload in &[&[0x04][..], &GET_KEY_AGREEMENT[..]] {
+            for packet in packets(cid, CtapHid::COMMAND_CBOR, payload) {
+                exchange(packet);
+            }
+        }
+        recorder.into_writer()
+    }
+
+    #[test]
+    fn test_encode_decode() {
+        let trace = Trace::decode(&record_session(Some(&SEED))).unwrap();
+        assert_eq!(trace.seed, Some(SEED));
+        // INIT and getKeyAgreement fit in one packet, getInfo is longer.
+        let directions: Vec<Direction> = trace.records.iter().map(|r| r.direction).collect();
+        assert_eq!(directions[..2], [Direction::Received, Direction::Sent]);
+        assert_eq!(directions[2], Direction::Received);
+        assert!(directions.len() > 6);
+        assert_eq!(trace.records[0].time_ms, 10);
+        // Initialization packets have the high bit of the command set.
+        assert_eq!(trace.records[0].packet[4], 0x80 | CtapHid::COMMAND_INIT);
+
+        let trace = Trace::decode(&record_session(None)).unwrap();
+        assert_eq!(trace.seed, None);
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        let bytes = record_session(None);
+        assert_eq!(
+            Trace::decode(&bytes[1..]).err(),
+            Some(TraceError::InvalidHeader)
+        );
+        assert_eq!(
+            Trace::decode(&bytes[..bytes.len() - 1]).err(),
+            Some(TraceError::Truncated)
+        );
+        let mut bytes = bytes;
+        bytes[MAGIC.len()] = VERSION + 1;
+        assert_eq!(
+            Trace::decode(&bytes).err(),
+            Some(TraceError::UnsupportedVersion)
+        );
+        bytes[MAGIC.len()] = VERSION;
+        bytes[HEADER_LENGTH] = 0x02;
+        assert_eq!(Trace::decode(&bytes).err(), Some(TraceError::InvalidRecord));
+    }
+
+    #[test]
+    fn test_from_console() {
+        let mut console = String::new();
+        let mut recorder = Recorder::new(ConsoleWriter(&mut console), None);
+        recorder.record_received(ClockValue::new(7, CLOCK_FREQUENCY_HZ), &[0xAB; 64]);
+        drop(recorder);
+
+        let log = format!(
+            "Old boot\n{}{}\nReceived packet at 0.007000 s\n{}",
+            CONSOLE_PREFIX, "4f534b54524143450100", console
+        );
+        let trace = Trace::from_console(&log).unwrap();
+        assert_eq!(trace.seed, None);
+        assert_eq!(trace.records.len(), 1);
+        assert_eq!(trace.records[0].time_ms, 7);
+        assert_eq!(trace.records[0].packet[..], [0xAB; 64][..]);
+
+        let log = format!("{}4f534b5452414345010", CONSOLE_PREFIX);
+        assert_eq!(
+            Trace::from_console(&log).err(),
+            Some(TraceError::InvalidHex)
+        );
+    }
+
+    #[test]
+    fn test_replay_without_divergence() {
+        let trace = Trace::decode(&record_session(Some(&SEED))).unwrap();
+        let mut ctap_state = new_ctap_state(trace.seed.unwrap());
+        assert!(replay(&trace, &mut ctap_state).is_empty());
+    }
+
+    #[test]
+    fn test_replay_with_other_seed() {
+        let trace = Trace::decode(&record_session(Some(&SEED))).unwrap();
+        let mut ctap_state = new_ctap_state([0x00; 32]);
+        let divergences = replay(&trace, &mut ctap_state);
+        // Only the key agreement key depends on the rng.
+        assert_eq!(divergences.len(), 1);
+        let divergence = &divergences[0];
+        let last_received = trace
+            .records
+            .iter()
+            .rposition(|record| record.direction == Direction::Received);
+        assert_eq!(Some(divergence.index), last_received);
+        assert_eq!(divergence.actual.len(), divergence.expected.len());
+        // Same channel, command and length, different key.
+        assert_eq!(divergence.actual[0][..7], divergence.expected[0][..7]);
+    }
+}
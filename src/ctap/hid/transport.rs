@@ -0,0 +1,512 @@
+// Copyright 2019 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::send::HidPacketIterator;
+use super::{CtapHid, HidPacket};
+use crate::timer::Duration;
+use alloc::collections::VecDeque;
+#[cfg(feature = "debug_ctap")]
+use core::fmt::Write;
+#[cfg(feature = "debug_ctap")]
+use libtock::console::Console;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(any(test, feature = "debug_ctap"), derive(Debug))]
+pub enum SendOrRecvStatus {
+    Error,
+    Sent,
+    Received,
+}
+
+// An endpoint exchanging HID packets with the host.
+// This abstracts the USB driver, so that the recovery logic below can be tested without hardware.
+pub trait HidEndpoint {
+    // Connects the endpoint, and returns whether it succeeded. This is called again after the
+    // endpoint reported an error, e.g. because of a bus reset.
+    fn setup(&mut self) -> bool;
+
+    // Receives a packet into buf. Returns None if the timeout elapsed.
+    fn recv_with_timeout(
+        &mut self,
+        buf: &mut HidPacket,
+        timeout_delay: Duration<isize>,
+    ) -> Option<SendOrRecvStatus>;
+
+    // Waits for the delay without any transfer. This is used after a failed setup, so that it is
+    // not retried in a busy loop.
+    fn wait(&mut self, delay: Duration<isize>);
+
+    // Sends buf to the host, unless a packet is received first. In that case, buf is replaced by
+    // the received packet. Returns None if the timeout elapsed.
+    fn send_or_recv_with_timeout(
+        &mut self,
+        buf: &mut HidPacket,
+        timeout_delay: Duration<isize>,
+    ) -> Option<SendOrRecvStatus>;
+}
+
+// Sends and receives HID packets over an endpoint, and recovers from transmission errors instead
+// of giving up:
+// - Packets received while sending a reply are queued, and returned by the next receive calls.
+// If too many are received, the reply is cancelled like on a timeout.
+// - If sending a packet times out, the rest of the reply is dropped and the current transaction
+// of the CtapHid state is reset.
+// - If the endpoint reports an error, it is set up again before the next transfer.
+pub struct HidTransport<E: HidEndpoint> {
+    endpoint: E,
+    // Whether the endpoint was successfully set up since the last error.
+    connected: bool,
+    // Packets received while sending a reply, which still need to be processed.
+    pending_packets: VecDeque<HidPacket>,
+}
+
+impl<E: HidEndpoint> HidTransport<E> {
+    // A reply is cancelled if the host sends more packets than this without reading it, since it
+    // is misbehaving anyway. This also bounds the time spent sending a reply, as each attempt to
+    // send a packet either succeeds, times out or queues a received packet.
+    const MAX_PENDING_PACKETS: usize = 16;
+
+    pub fn new(endpoint: E) -> HidTransport<E> {
+        HidTransport {
+            endpoint,
+            connected: false,
+            pending_packets: VecDeque::new(),
+        }
+    }
+
+    // Receives the next packet into buf, and returns whether there was one.
+    // Packets queued while sending a reply are returned first, without waiting for the endpoint.
+    pub fn recv_with_timeout(
+        &mut self,
+        buf: &mut HidPacket,
+        timeout_delay: Duration<isize>,
+    ) -> bool {
+        if let Some(packet) = self.pending_packets.pop_front() {
+            *buf = packet;
+            return true;
+        }
+        if !self.check_connected() {
+            self.endpoint.wait(timeout_delay);
+            return false;
+        }
+        match self.endpoint.recv_with_timeout(buf, timeout_delay) {
+            Some(SendOrRecvStatus::Received) => true,
+            Some(_) => {
+                #[cfg(feature = "debug_ctap")]
+                writeln!(
+                    Console::new(),
+                    "Error receiving packet, resetting the endpoint"
+                )
+                .unwrap();
+                self.disconnect();
+                false
+            }
+            None => false,
+        }
+    }
+
+    // Sends all packets of the reply, and returns whether it succeeded.
+    // If the reply can't be sent, its remaining packets are cancelled and the current transaction
+    // of ctap_hid is reset, because the host won't be able to make sense of a truncated reply.
+    // This takes at most (number of packets + MAX_PENDING_PACKETS + 1) * timeout_delay.
+    pub fn send_reply(
+        &mut self,
+        reply: HidPacketIterator,
+        ctap_hid: &mut CtapHid,
+        timeout_delay: Duration<isize>,
+    ) -> bool {
+        for packet in reply {
+            if !self.check_connected() {
+                ctap_hid.reset();
+                return false;
+            }
+            loop {
+                let mut buf = packet;
+                match self
+                    .endpoint
+                    .send_or_recv_with_timeout(&mut buf, timeout_delay)
+                {
+                    Some(SendOrRecvStatus::Sent) => break,
+                    Some(SendOrRecvStatus::Received) => {
+                        // The host sent a packet before reading ours. Keep it for later, and
+                        // retry sending.
+                        if !self.queue_packet(buf) {
+                            #[cfg(feature = "debug_ctap")]
+                            writeln!(
+                                Console::new(),
+                                "Too many packets received while sending, cancelling the reply"
+                            )
+                            .unwrap();
+                            ctap_hid.reset();
+                            return false;
+                        }
+                    }
+                    Some(SendOrRecvStatus::Error) => {
+                        #[cfg(feature = "debug_ctap")]
+                        writeln!(
+                            Console::new(),
+                            "Error sending packet, resetting the endpoint"
+                        )
+                        .unwrap();
+                        self.disconnect();
+                        ctap_hid.reset();
+                        return false;
+                    }
+                    None => {
+                        #[cfg(feature = "debug_ctap")]
+                        writeln!(
+                            Console::new(),
+                            "Sending packet timed out, cancelling the reply"
+                        )
+                        .unwrap();
+                        ctap_hid.reset();
+                        return false;
+                    }
+                }
+            }
+        }
+        true
+    }
+
+    // Returns whether the endpoint is usable, setting it up again if needed.
+    fn check_connected(&mut self) -> bool {
+        if !self.connected {
+            self.connected = self.endpoint.setup();
+        }
+        self.connected
+    }
+
+    fn disconnect(&mut self) {
+        self.connected = false;
+        // Packets received before a bus reset belong to transactions that the host abandoned.
+        self.pending_packets.clear();
+    }
+
+    // Queues a packet received while sending, and returns whether there was room for it.
+    fn queue_packet(&mut self, packet: HidPacket) -> bool {
+        if self.pending_packets.len() < HidTransport::<E>::MAX_PENDING_PACKETS {
+            self.pending_packets.push_back(packet);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::{ChannelID, Message};
+    use super::*;
+    use crate::ctap::CtapState;
+    use crate::env::host::HostEnv;
+    use crate::timer::ClockValue;
+    use alloc::vec::Vec;
+
+    const CLOCK_FREQUENCY_HZ: usize = 32768;
+    const DUMMY_CLOCK_VALUE: ClockValue = ClockValue::new(0, CLOCK_FREQUENCY_HZ);
+    const DUMMY_TIMEOUT: Duration<isize> = Duration::from_ms(1000);
+    const CID: ChannelID = [0x12, 0x34, 0x56, 0x78];
+
+    enum MockEvent {
+        Sent,
+        Received(HidPacket),
+        Timeout,
+        Error,
+    }
+
+    // Replays a list of scripted events for each transfer. Once the script is exhausted, all
+    // packets are sent and no packet is received.
+    struct MockEndpoint {
+        events: VecDeque<MockEvent>,
+        setup_results: VecDeque<bool>,
+        setup_calls: usize,
+        waited_ms: isize,
+        sent_packets: Vec<HidPacket>,
+    }
+
+    impl MockEndpoint {
+        fn new(events: Vec<MockEvent>) -> MockEndpoint {
+            MockEndpoint {
+                events: events.into_iter().collect(),
+                setup_results: VecDeque::new(),
+                setup_calls: 0,
+                waited_ms: 0,
+                sent_packets: Vec::new(),
+            }
+        }
+    }
+
+    impl HidEndpoint for MockEndpoint {
+        fn setup(&mut self) -> bool {
+            self.setup_calls += 1;
+            self.setup_results.pop_front().unwrap_or(true)
+        }
+
+        fn recv_with_timeout(
+            &mut self,
+            buf: &mut HidPacket,
+            _timeout_delay: Duration<isize>,
+        ) -> Option<SendOrRecvStatus> {
+            match self.events.pop_front() {
+                Some(MockEvent::Received(packet)) => {
+                    *buf = packet;
+                    Some(SendOrRecvStatus::Received)
+                }
+                Some(MockEvent::Sent) => panic!("Unexpected send event while receiving"),
+                Some(MockEvent::Error) => Some(SendOrRecvStatus::Error),
+                Some(MockEvent::Timeout) | None => None,
+            }
+        }
+
+        fn wait(&mut self, delay: Duration<isize>) {
+            self.waited_ms += delay.ms();
+        }
+
+        fn send_or_recv_with_timeout(
+            &mut self,
+            buf: &mut HidPacket,
+            _timeout_delay: Duration<isize>,
+        ) -> Option<SendOrRecvStatus> {
+            match self.events.pop_front() {
+                Some(MockEvent::Sent) | None => {
+                    self.sent_packets.push(*buf);
+                    Some(SendOrRecvStatus::Sent)
+                }
+                Some(MockEvent::Received(packet)) => {
+                    *buf = packet;
+                    Some(SendOrRecvStatus::Received)
+                }
+                Some(MockEvent::Error) => Some(SendOrRecvStatus::Error),
+                Some(MockEvent::Timeout) => None,
+            }
+        }
+    }
+
+    fn reply(payload_len: usize) -> HidPacketIterator {
+        HidPacketIterator::new(Message {
+            cid: CID,
+            cmd: 0x01,
+            payload: vec![0x55; payload_len],
+        })
+        .unwrap()
+    }
+
+    fn packets(payload_len: usize) -> Vec<HidPacket> {
+        reply(payload_len).collect()
+    }
+
+    fn assert_packets_eq(actual: &[HidPacket], expected: &[HidPacket]) {
+        assert_eq!(actual.len(), expected.len());
+        for (a, e) in actual.iter().zip(expected.iter()) {
+            assert_eq!(&a[..], &e[..]);
+        }
+    }
+
+    #[test]
+    fn test_send_reply() {
+        let mut ctap_hid = CtapHid::new();
+        let mut transport = HidTransport::new(MockEndpoint::new(vec![]));
+        assert!(transport.send_reply(reply(100), &mut ctap_hid, DUMMY_TIMEOUT));
+        assert_packets_eq(&transport.endpoint.sent_packets, &packets(100));
+        assert_eq!(transport.endpoint.setup_calls, 1);
+    }
+
+    #[test]
+    fn test_packet_received_while_sending_is_queued() {
+        let received = packets(10)[0];
+        let mut ctap_hid = CtapHid::new();
+        let mut transport = HidTransport::new(MockEndpoint::new(vec![
+            MockEvent::Sent,
+            MockEvent::Received(received),
+        ]));
+        assert!(transport.send_reply(reply(100), &mut ctap_hid, DUMMY_TIMEOUT));
+        // The whole reply is sent despite the interruption.
+        assert_packets_eq(&transport.endpoint.sent_packets, &packets(100));
+
+        // The received packet is returned without waiting for the endpoint.
+        let mut buf = [0; 64];
+        assert!(transport.recv_with_timeout(&mut buf, DUMMY_TIMEOUT));
+        assert_eq!(&buf[..], &received[..]);
+        assert!(!transport.recv_with_timeout(&mut buf, DUMMY_TIMEOUT));
+    }
+
+    #[test]
+    fn test_pending_packets_are_bounded() {
+        let received = packets(10)[0];
+        let mut events = Vec::new();
+        for _ in 0..HidTransport::<MockEndpoint>::MAX_PENDING_PACKETS {
+            events.push(MockEvent::Received(received));
+        }
+        let mut ctap_hid = CtapHid::new();
+        let mut transport = HidTransport::new(MockEndpoint::new(events));
+        assert!(transport.send_reply(reply(10), &mut ctap_hid, DUMMY_TIMEOUT));
+        assert_packets_eq(&transport.endpoint.sent_packets, &packets(10));
+        assert_eq!(
+            transport.pending_packets.len(),
+            HidTransport::<MockEndpoint>::MAX_PENDING_PACKETS
+        );
+    }
+
+    #[test]
+    fn test_too_many_pending_packets_cancels_reply() {
+        let received = packets(10)[0];
+        let mut events = Vec::new();
+        // Without a bound, a host that keeps sending would keep the reply from being sent.
+        for _ in 0..1000 {
+            events.push(MockEvent::Received(received));
+        }
+        let mut ctap_hid = CtapHid::new();
+        let mut transport = HidTransport::new(MockEndpoint::new(events));
+        assert!(!transport.send_reply(reply(10), &mut ctap_hid, DUMMY_TIMEOUT));
+        assert!(transport.endpoint.sent_packets.is_empty());
+        assert_eq!(
+            transport.endpoint.events.len(),
+            1000 - HidTransport::<MockEndpoint>::MAX_PENDING_PACKETS - 1
+        );
+
+        // The queued packets are still processed.
+        let mut buf = [0; 64];
+        for _ in 0..HidTransport::<MockEndpoint>::MAX_PENDING_PACKETS {
+            assert!(transport.recv_with_timeout(&mut buf, DUMMY_TIMEOUT));
+            assert_eq!(&buf[..], &received[..]);
+        }
+    }
+
+    #[test]
+    fn test_send_timeout_cancels_reply() {
+        let mut ctap_hid = CtapHid::new();
+        let mut transport =
+            HidTransport::new(MockEndpoint::new(vec![MockEvent::Sent, MockEvent::Timeout]));
+        assert!(!transport.send_reply(reply(200), &mut ctap_hid, DUMMY_TIMEOUT));
+        // Only the first packet was sent, the others are dropped.
+        assert_packets_eq(&transport.endpoint.sent_packets, &packets(200)[..1]);
+
+        // A timeout doesn't require to set up the endpoint again.
+        assert!(transport.send_reply(reply(10), &mut ctap_hid, DUMMY_TIMEOUT));
+        assert_eq!(transport.endpoint.setup_calls, 1);
+    }
+
+    #[test]
+    fn test_send_timeout_resets_transaction() {
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(HostEnv::new(user_immediately_present));
+        let mut ctap_hid = CtapHid::new();
+
+        // Allocate a channel.
+        let init_packet = HidPacketIterator::new(Message {
+            cid: CtapHid::CHANNEL_BROADCAST,
+            cmd: CtapHid::COMMAND_INIT,
+            payload: vec![0x12, 0x34, 0x56, 0x78, 0x9A, 0xBC, 0xDE, 0xF0],
+        })
+        .unwrap()
+        .next()
+        .unwrap();
+        let init_reply = ctap_hid
+            .process_hid_packet(&init_packet, DUMMY_CLOCK_VALUE, &mut ctap_state)
+            .next()
+            .unwrap();
+        let cid = *array_ref!(init_reply, 15, 4);
+
+        // Start a long PING message, but don't finish it.
+        let ping_packets: Vec<HidPacket> = HidPacketIterator::new(Message {
+            cid,
+            cmd: CtapHid::COMMAND_PING,
+            payload: vec![0x99; 100],
+        })
+        .unwrap()
+        .collect();
+        assert_eq!(ping_packets.len(), 2);
+        assert!(ctap_hid
+            .process_hid_packet(&ping_packets[0], DUMMY_CLOCK_VALUE, &mut ctap_state)
+            .next()
+            .is_none());
+
+        // A reply times out in the meantime.
+        let mut transport = HidTransport::new(MockEndpoint::new(vec![MockEvent::Timeout]));
+        assert!(!transport.send_reply(reply(10), &mut ctap_hid, DUMMY_TIMEOUT));
+
+        // The partial message was discarded, so the continuation packet is ignored.
+        assert!(ctap_hid
+            .process_hid_packet(&ping_packets[1], DUMMY_CLOCK_VALUE, &mut ctap_state)
+            .next()
+            .is_none());
+    }
+
+    #[test]
+    fn test_send_error_sets_up_endpoint_again() {
+        let received = packets(10)[0];
+        let mut ctap_hid = CtapHid::new();
+        let mut transport = HidTransport::new(MockEndpoint::new(vec![
+            MockEvent::Received(received),
+            MockEvent::Error,
+        ]));
+        assert!(!transport.send_reply(reply(10), &mut ctap_hid, DUMMY_TIMEOUT));
+        assert!(transport.endpoint.sent_packets.is_empty());
+        assert_eq!(transport.endpoint.setup_calls, 1);
+
+        // Packets received before the error are dropped, and the endpoint is set up again.
+        let mut buf = [0; 64];
+        assert!(!transport.recv_with_timeout(&mut buf, DUMMY_TIMEOUT));
+        assert_eq!(transport.endpoint.setup_calls, 2);
+    }
+
+    #[test]
+    fn test_recv_error_sets_up_endpoint_again() {
+        let received = packets(10)[0];
+        let mut transport = HidTransport::new(MockEndpoint::new(vec![
+            MockEvent::Error,
+            MockEvent::Received(received),
+        ]));
+        let mut buf = [0; 64];
+        assert!(!transport.recv_with_timeout(&mut buf, DUMMY_TIMEOUT));
+        assert!(transport.recv_with_timeout(&mut buf, DUMMY_TIMEOUT));
+        assert_eq!(&buf[..], &received[..]);
+        assert_eq!(transport.endpoint.setup_calls, 2);
+    }
+
+    #[test]
+    fn test_setup_failure_is_retried() {
+        let received = packets(10)[0];
+        let mut endpoint = MockEndpoint::new(vec![MockEvent::Received(received)]);
+        endpoint.setup_results = vec![false, false].into_iter().collect();
+        let mut ctap_hid = CtapHid::new();
+        let mut transport = HidTransport::new(endpoint);
+
+        let mut buf = [0; 64];
+        assert!(!transport.recv_with_timeout(&mut buf, DUMMY_TIMEOUT));
+        assert!(!transport.send_reply(reply(10), &mut ctap_hid, DUMMY_TIMEOUT));
+        assert!(transport.recv_with_timeout(&mut buf, DUMMY_TIMEOUT));
+        assert_eq!(&buf[..], &received[..]);
+        assert_eq!(transport.endpoint.setup_calls, 3);
+    }
+
+    #[test]
+    fn test_setup_failure_waits() {
+        let mut endpoint = MockEndpoint::new(vec![]);
+        endpoint.setup_results = vec![false, false].into_iter().collect();
+        let mut ctap_hid = CtapHid::new();
+        let mut transport = HidTransport::new(endpoint);
+
+        // The caller loops on receiving, so it must not return before the timeout.
+        let mut buf = [0; 64];
+        assert!(!transport.recv_with_timeout(&mut buf, DUMMY_TIMEOUT));
+        assert_eq!(transport.endpoint.waited_ms, DUMMY_TIMEOUT.ms());
+        // The reply is dropped right away, the next receive waits if needed.
+        assert!(!transport.send_reply(reply(10), &mut ctap_hid, DUMMY_TIMEOUT));
+        assert_eq!(transport.endpoint.waited_ms, DUMMY_TIMEOUT.ms());
+        assert!(!transport.recv_with_timeout(&mut buf, DUMMY_TIMEOUT));
+        assert_eq!(transport.endpoint.waited_ms, DUMMY_TIMEOUT.ms());
+        assert_eq!(transport.endpoint.setup_calls, 3);
+    }
+}
@@ -14,8 +14,11 @@
 
 pub const AAGUID: &[u8; 16] = include_bytes!(concat!(env!("OUT_DIR"), "/opensk_aaguid.bin"));
 
+// Without a provisioned attestation, U2F falls back to this batch certificate.
+#[cfg(feature = "with_ctap1")]
 pub const ATTESTATION_CERTIFICATE: &[u8] =
     include_bytes!(concat!(env!("OUT_DIR"), "/opensk_cert.bin"));
 
+#[cfg(feature = "with_ctap1")]
 pub const ATTESTATION_PRIVATE_KEY: &[u8; 32] =
     include_bytes!(concat!(env!("OUT_DIR"), "/opensk_pkey.bin"));
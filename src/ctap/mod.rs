@@ -12,40 +12,59 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+pub mod apdu;
+#[cfg(feature = "with_ccid")]
+pub mod applet;
+mod attestation;
+#[cfg(feature = "with_ble")]
+pub mod ble;
+#[cfg(feature = "with_ccid")]
+pub mod ccid;
 pub mod command;
+#[cfg(test)]
+mod conformance;
 #[cfg(feature = "with_ctap1")]
 mod ctap1;
 pub mod data_formats;
 pub mod hid;
 mod key_material;
+#[cfg(feature = "with_nfc")]
+pub mod nfc;
+mod provisioning;
 pub mod response;
 pub mod status_code;
-mod storage;
+pub mod storage;
 mod timed_permission;
+pub mod vendor;
 
+use self::attestation::AttestationInput;
 #[cfg(feature = "with_ctap2_1")]
 use self::command::MAX_CREDENTIAL_COUNT_IN_LIST;
 use self::command::{
     AuthenticatorClientPinParameters, AuthenticatorGetAssertionParameters,
-    AuthenticatorMakeCredentialParameters, Command,
+    AuthenticatorMakeCredentialParameters, AuthenticatorVendorParameters, Command,
 };
 #[cfg(feature = "with_ctap2_1")]
 use self::data_formats::AuthenticatorTransport;
 use self::data_formats::{
-    ClientPinSubCommand, CoseKey, GetAssertionHmacSecretInput, PackedAttestationStatement,
+    AttestationStatementFormat, ClientPinSubCommand, CoseKey, GetAssertionHmacSecretInput,
     PublicKeyCredentialDescriptor, PublicKeyCredentialParameter, PublicKeyCredentialSource,
     PublicKeyCredentialType, PublicKeyCredentialUserEntity, SignatureAlgorithm,
 };
 use self::hid::ChannelID;
-use self::key_material::{AAGUID, ATTESTATION_CERTIFICATE, ATTESTATION_PRIVATE_KEY};
+use self::key_material::AAGUID;
+#[cfg(feature = "with_ctap1")]
+use self::key_material::{ATTESTATION_CERTIFICATE, ATTESTATION_PRIVATE_KEY};
 use self::response::{
     AuthenticatorClientPinResponse, AuthenticatorGetAssertionResponse,
     AuthenticatorGetInfoResponse, AuthenticatorMakeCredentialResponse, ResponseData,
 };
 use self::status_code::Ctap2StatusCode;
-use self::storage::PersistentStore;
+use self::storage::{CounterOwner, PersistentStore};
 #[cfg(feature = "with_ctap1")]
 use self::timed_permission::U2fUserPresenceState;
+use self::vendor::{VendorAuthorization, VendorRegistry, VendorSubCommand};
+use crate::env::Env;
 use alloc::collections::BTreeMap;
 use alloc::string::{String, ToString};
 use alloc::vec::Vec;
@@ -58,22 +77,22 @@ use crypto::hmac::{hmac_256, verify_hmac_256, verify_hmac_256_first_128bits};
 use crypto::rng256::Rng256;
 use crypto::sha256::Sha256;
 use crypto::Hash256;
-#[cfg(feature = "debug_ctap")]
-use libtock::console::Console;
 use libtock::timer::{Duration, Timestamp};
 use subtle::ConstantTimeEq;
 
-// This flag enables or disables basic attestation for FIDO2. U2F is unaffected by
-// this setting. The basic attestation uses the signing key from key_material.rs
-// as a batch key. Turn it on if you want attestation. In this case, be aware that
-// it is your responsibility to generate your own key material and keep it secret.
-const USE_BATCH_ATTESTATION: bool = false;
-// The signature counter is currently implemented as a global counter, if you set
-// this flag to true. The spec strongly suggests to have per-credential-counters,
-// but it means you can't have an infinite amount of credentials anymore. Also,
-// since this is the only piece of information that needs writing often, we might
-// need a flash storage friendly way to implement this feature. The implemented
-// solution is a compromise to be compatible with U2F and not wasting storage.
+// Basic attestation for FIDO2 is enabled once a batch key and certificate chain are provisioned,
+// see the provisioning module. Until then, FIDO2 uses self attestation, while U2F and the AAGUID
+// fall back to the key material from key_material.rs. It is your responsibility to generate your
+// own key material and keep it secret.
+// Clients can ask for an attestation format, otherwise this one is used. Packed attestation is
+// self signed without a provisioned batch key. If you'd rather not attest credentials, use the
+// "none" format, which also saves signing.
+const DEFAULT_ATTESTATION_FORMAT: AttestationStatementFormat = AttestationStatementFormat::Packed;
+// Signature counters are used if you set this flag to true. As the spec strongly
+// suggests, resident credentials have their own counter. Non-resident credentials
+// aren't stored, so they share a counter per relying party, which U2F uses too.
+// Since counters are the only piece of information that needs writing often, see
+// `PersistentStore::signature_counter` for their cost in flash.
 const USE_SIGNATURE_COUNTER: bool = true;
 // Those constants have to be multiples of 16, the AES block size.
 const PIN_AUTH_LENGTH: usize = 16;
@@ -194,13 +213,10 @@ fn truncate_to_char_boundary(s: &str, mut max: usize) -> &str {
 
 // This struct currently holds all state, not only the persistent memory. The persistent members are
 // in the persistent store field.
-pub struct CtapState<'a, R: Rng256, CheckUserPresence: Fn(ChannelID) -> Result<(), Ctap2StatusCode>>
-{
-    rng: &'a mut R,
-    // A function to check user presence, ultimately returning true if user presence was detected,
-    // false otherwise.
-    check_user_presence: CheckUserPresence,
-    persistent_store: PersistentStore,
+pub struct CtapState<E: Env> {
+    // The platform, including the random number generator and the user presence check.
+    env: E,
+    persistent_store: PersistentStore<E::Storage>,
     key_agreement_key: crypto::ecdh::SecKey,
     pin_uv_auth_token: [u8; PIN_TOKEN_LENGTH],
     consecutive_pin_mismatches: u64,
@@ -208,25 +224,27 @@ pub struct CtapState<'a, R: Rng256, CheckUserPresence: Fn(ChannelID) -> Result<(
     accepts_reset: bool,
     #[cfg(feature = "with_ctap1")]
     pub u2f_up_state: U2fUserPresenceState,
+    vendor_commands: VendorRegistry<E>,
 }
 
-impl<'a, R, CheckUserPresence> CtapState<'a, R, CheckUserPresence>
-where
-    R: Rng256,
-    CheckUserPresence: Fn(ChannelID) -> Result<(), Ctap2StatusCode>,
-{
+impl<E: Env> CtapState<E> {
     pub const PIN_PROTOCOL_VERSION: u64 = 1;
 
-    pub fn new(
-        rng: &'a mut R,
-        check_user_presence: CheckUserPresence,
-    ) -> CtapState<'a, R, CheckUserPresence> {
-        let key_agreement_key = crypto::ecdh::SecKey::gensk(rng);
-        let pin_uv_auth_token = rng.gen_uniform_u8x32();
-        let persistent_store = PersistentStore::new(rng);
-        CtapState {
-            rng,
-            check_user_presence,
+    pub fn new(mut env: E) -> CtapState<E> {
+        let key_agreement_key = crypto::ecdh::SecKey::gensk(env.rng());
+        let pin_uv_auth_token = env.rng().gen_uniform_u8x32();
+        let storage = env.take_storage().unwrap();
+        let device_secret = env.device_secret();
+        let persistent_store = PersistentStore::new(storage, &device_secret, env.rng());
+        #[cfg(feature = "debug_ctap")]
+        {
+            let health = persistent_store.health();
+            if !health.is_healthy() {
+                writeln!(&mut env.write(), "Persistent store: {:?}", health).unwrap();
+            }
+        }
+        let mut ctap_state = CtapState {
+            env,
             persistent_store,
             key_agreement_key,
             pin_uv_auth_token,
@@ -237,6 +255,55 @@ where
                 U2F_UP_PROMPT_TIMEOUT,
                 Duration::from_ms(TOUCH_TIMEOUT_MS),
             ),
+            vendor_commands: VendorRegistry::new(),
+        };
+        provisioning::register_vendor_commands(&mut ctap_state);
+        ctap_state
+    }
+
+    pub fn env(&mut self) -> &mut E {
+        &mut self.env
+    }
+
+    // Firmware modules add their vendor commands at boot, before processing any packet.
+    pub fn register_vendor_command<C: VendorSubCommand<E>>(&mut self) {
+        self.vendor_commands.register::<C>();
+    }
+
+    pub fn has_vendor_command(&self, command: u8) -> bool {
+        self.vendor_commands.has_command(command)
+    }
+
+    // The AAGUID of the provisioned attestation, or the one from key_material.rs.
+    fn aaguid(&self) -> &[u8; 16] {
+        self.persistent_store.aaguid().unwrap_or(AAGUID)
+    }
+
+    // The batch attestation key and certificate chain, once provisioned.
+    fn batch_attestation(&self) -> Option<(crypto::ecdsa::SecKey, Vec<Vec<u8>>)> {
+        let private_key = self.persistent_store.attestation_private_key()?;
+        // The key was checked during provisioning.
+        let attestation_key = crypto::ecdsa::SecKey::from_bytes(&private_key)?;
+        Some((
+            attestation_key,
+            self.persistent_store.attestation_certificates(),
+        ))
+    }
+
+    // U2F always attests with the batch certificate, provisioned or from key_material.rs. The
+    // fido-u2f attestation format uses the same certificate.
+    fn u2f_attestation(&self) -> Option<(crypto::ecdsa::SecKey, Vec<u8>)> {
+        match self.batch_attestation() {
+            Some((attestation_key, mut certificates)) => {
+                Some((attestation_key, certificates.swap_remove(0)))
+            }
+            #[cfg(feature = "with_ctap1")]
+            None => Some((
+                crypto::ecdsa::SecKey::from_bytes(ATTESTATION_PRIVATE_KEY).unwrap(),
+                ATTESTATION_CERTIFICATE.to_vec(),
+            )),
+            #[cfg(not(feature = "with_ctap1"))]
+            None => None,
         }
     }
 
@@ -246,9 +313,28 @@ where
         }
     }
 
-    pub fn increment_global_signature_counter(&mut self) {
+    // Does some storage maintenance, to be called when no command is being processed. Returns
+    // whether there was work to do, in which case it can be called again.
+    pub fn idle_step(&mut self) -> bool {
+        self.persistent_store.compact_step()
+    }
+
+    // Returns the signature counter of a credential, which is 0 if USE_SIGNATURE_COUNTER is false.
+    pub fn signature_counter(&self, owner: CounterOwner) -> u32 {
         if USE_SIGNATURE_COUNTER {
-            self.persistent_store.incr_global_signature_counter();
+            self.persistent_store.signature_counter(owner)
+        } else {
+            0
+        }
+    }
+
+    // Increments the signature counter of a credential and returns its new value, see
+    // `signature_counter`.
+    pub fn increment_signature_counter(&mut self, owner: CounterOwner) -> u32 {
+        if USE_SIGNATURE_COUNTER {
+            self.persistent_store.incr_signature_counter(owner)
+        } else {
+            0
         }
     }
 
@@ -260,13 +346,13 @@ where
         &mut self,
         private_key: crypto::ecdsa::SecKey,
         application: &[u8; 32],
-    ) -> Vec<u8> {
-        let master_keys = self.persistent_store.master_keys();
-        let aes_enc_key = crypto::aes256::EncryptionKey::new(master_keys.encryption);
+    ) -> Result<Vec<u8>, Ctap2StatusCode> {
+        let master_keys = self.persistent_store.master_keys()?;
+        let aes_enc_key = crypto::aes256::EncryptionKey::new(&master_keys.encryption);
         let mut sk_bytes = [0; 32];
         private_key.to_bytes(&mut sk_bytes);
         let mut iv = [0; 16];
-        iv.copy_from_slice(&self.rng.gen_uniform_u8x32()[..16]);
+        iv.copy_from_slice(&self.env.rng().gen_uniform_u8x32()[..16]);
 
         let mut blocks = [[0u8; 16]; 4];
         blocks[0].copy_from_slice(&sk_bytes[..16]);
@@ -280,9 +366,9 @@ where
         for b in &blocks {
             encrypted_id.extend(b);
         }
-        let id_hmac = hmac_256::<Sha256>(master_keys.hmac, &encrypted_id[..]);
+        let id_hmac = hmac_256::<Sha256>(&master_keys.hmac, &encrypted_id[..]);
         encrypted_id.extend(&id_hmac);
-        encrypted_id
+        Ok(encrypted_id)
     }
 
     // Decrypts a credential ID and writes the private key into a PublicKeyCredentialSource.
@@ -292,20 +378,20 @@ where
         &self,
         credential_id: Vec<u8>,
         rp_id_hash: &[u8],
-    ) -> Option<PublicKeyCredentialSource> {
+    ) -> Result<Option<PublicKeyCredentialSource>, Ctap2StatusCode> {
         if credential_id.len() != ENCRYPTED_CREDENTIAL_ID_SIZE {
-            return None;
+            return Ok(None);
         }
-        let master_keys = self.persistent_store.master_keys();
+        let master_keys = self.persistent_store.master_keys()?;
         let payload_size = ENCRYPTED_CREDENTIAL_ID_SIZE - 32;
         if !verify_hmac_256::<Sha256>(
-            master_keys.hmac,
+            &master_keys.hmac,
             &credential_id[..payload_size],
             array_ref![credential_id, payload_size, 32],
         ) {
-            return None;
+            return Ok(None);
         }
-        let aes_enc_key = crypto::aes256::EncryptionKey::new(master_keys.encryption);
+        let aes_enc_key = crypto::aes256::EncryptionKey::new(&master_keys.encryption);
         let aes_dec_key = crypto::aes256::DecryptionKey::new(&aes_enc_key);
         let mut iv = [0; 16];
         iv.copy_from_slice(&credential_id[..16]);
@@ -323,11 +409,11 @@ where
         decrypted_rp_id_hash[16..].clone_from_slice(&blocks[3]);
 
         if rp_id_hash != decrypted_rp_id_hash {
-            return None;
+            return Ok(None);
         }
 
         let sk_option = crypto::ecdsa::SecKey::from_bytes(&decrypted_sk);
-        sk_option.map(|sk| PublicKeyCredentialSource {
+        Ok(sk_option.map(|sk| PublicKeyCredentialSource {
             key_type: PublicKeyCredentialType::PublicKey,
             credential_id,
             private_key: sk,
@@ -335,13 +421,13 @@ where
             user_handle: vec![],
             other_ui: None,
             cred_random: None,
-        })
+        }))
     }
 
     pub fn process_command(&mut self, command_cbor: &[u8], cid: ChannelID) -> Vec<u8> {
         let cmd = Command::deserialize(command_cbor);
         #[cfg(feature = "debug_ctap")]
-        writeln!(&mut Console::new(), "Received command: {:#?}", cmd).unwrap();
+        writeln!(&mut self.env.write(), "Received command: {:#?}", cmd).unwrap();
         match cmd {
             Ok(command) => {
                 // Correct behavior between CTAP1 and CTAP2 isn't defined yet. Just a guess.
@@ -353,20 +439,30 @@ where
                     );
                 }
                 let response = match command {
+                    Command::AuthenticatorGetInfo => self.process_get_info(),
+                    // Other commands could misinterpret the store, see `PersistentStore::migrate`.
+                    _ if self.persistent_store.has_newer_schema() => {
+                        Err(Ctap2StatusCode::CTAP2_ERR_VENDOR_INTERNAL_ERROR)
+                    }
                     Command::AuthenticatorMakeCredential(params) => {
                         self.process_make_credential(params, cid)
                     }
                     Command::AuthenticatorGetAssertion(params) => {
                         self.process_get_assertion(params, cid)
                     }
-                    Command::AuthenticatorGetInfo => self.process_get_info(),
                     Command::AuthenticatorClientPin(params) => self.process_client_pin(params),
                     Command::AuthenticatorReset => self.process_reset(cid),
-                    // TODO(kaczmarczyck) implement GetNextAssertion and FIDO 2.1 commands
-                    _ => unimplemented!(),
+                    // TODO(kaczmarczyck) implement GetNextAssertion
+                    // Without it, no GetAssertion leaves credentials to iterate over.
+                    Command::AuthenticatorGetNextAssertion => {
+                        Err(Ctap2StatusCode::CTAP2_ERR_NOT_ALLOWED)
+                    }
+                    Command::AuthenticatorVendor(command, params) => {
+                        self.process_vendor(command, params, cid)
+                    }
                 };
                 #[cfg(feature = "debug_ctap")]
-                writeln!(&mut Console::new(), "Sending response: {:#?}", response).unwrap();
+                writeln!(&mut self.env.write(), "Sending response: {:#?}", response).unwrap();
                 match response {
                     Ok(response_data) => {
                         let mut response_vec = vec![0x00];
@@ -402,6 +498,7 @@ where
             options,
             pin_uv_auth_param,
             pin_uv_auth_protocol,
+            attestation_formats_preference,
         } = make_credential_params;
 
         if let Some(auth_param) = &pin_uv_auth_param {
@@ -416,7 +513,7 @@ where
 
             match pin_uv_auth_protocol {
                 Some(protocol) => {
-                    if protocol != CtapState::<R, CheckUserPresence>::PIN_PROTOCOL_VERSION {
+                    if protocol != CtapState::<E>::PIN_PROTOCOL_VERSION {
                         return Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_INVALID);
                     }
                 }
@@ -435,7 +532,7 @@ where
             return Err(Ctap2StatusCode::CTAP2_ERR_UNSUPPORTED_EXTENSION);
         }
         let cred_random = if use_hmac_extension {
-            Some(self.rng.gen_uniform_u8x32().to_vec())
+            Some(self.env.rng().gen_uniform_u8x32().to_vec())
         } else {
             None
         };
@@ -451,7 +548,7 @@ where
                 {
                     // Perform this check, so bad actors can't brute force exclude_list
                     // without user interaction. Discard the user presence check's outcome.
-                    let _ = (self.check_user_presence)(cid);
+                    let _ = self.env.check_user_presence(cid);
                     return Err(Ctap2StatusCode::CTAP2_ERR_CREDENTIAL_EXCLUDED);
                 }
             }
@@ -481,14 +578,14 @@ where
             }
         };
 
-        (self.check_user_presence)(cid)?;
+        self.env.check_user_presence(cid)?;
 
-        let sk = crypto::ecdsa::SecKey::gensk(self.rng);
+        let sk = crypto::ecdsa::SecKey::gensk(self.env.rng());
         let pk = sk.genpk();
 
         let rp_id_hash = Sha256::hash(rp_id.as_bytes());
         let credential_id = if options.rk {
-            let random_id = self.rng.gen_uniform_u8x32().to_vec();
+            let random_id = self.env.rng().gen_uniform_u8x32().to_vec();
             let credential_source = PublicKeyCredentialSource {
                 key_type: PublicKeyCredentialType::PublicKey,
                 credential_id: random_id.clone(),
@@ -502,14 +599,20 @@ where
                     .map(|s| truncate_to_char_boundary(&s, 64).to_string()),
                 cred_random,
             };
-            self.persistent_store.store_credential(credential_source)?;
+            self.persistent_store
+                .store_credential(credential_source, self.env.rng())?;
             random_id
         } else {
-            self.encrypt_key_handle(sk.clone(), &rp_id_hash)
+            self.encrypt_key_handle(sk.clone(), &rp_id_hash)?
         };
 
-        let mut auth_data = self.generate_auth_data(&rp_id_hash, flags);
-        auth_data.extend(AAGUID);
+        let signature_counter = if options.rk {
+            self.signature_counter(CounterOwner::Credential(&credential_id))
+        } else {
+            self.signature_counter(CounterOwner::RelyingParty(&rp_id_hash))
+        };
+        let mut auth_data = self.generate_auth_data(&rp_id_hash, flags, signature_counter);
+        auth_data.extend(self.aaguid());
         // The length is fixed to 0x20 or 0x70 and fits one byte.
         if credential_id.len() > 0xFF {
             return Err(Ctap2StatusCode::CTAP2_ERR_VENDOR_RESPONSE_TOO_LONG);
@@ -530,32 +633,26 @@ where
             }
         }
 
-        let mut signature_data = auth_data.clone();
-        signature_data.extend(client_data_hash);
-        let (signature, x5c) = if USE_BATCH_ATTESTATION {
-            let attestation_key =
-                crypto::ecdsa::SecKey::from_bytes(ATTESTATION_PRIVATE_KEY).unwrap();
-            (
-                attestation_key.sign_rfc6979::<crypto::sha256::Sha256>(&signature_data),
-                Some(vec![ATTESTATION_CERTIFICATE.to_vec()]),
-            )
-        } else {
-            (
-                sk.sign_rfc6979::<crypto::sha256::Sha256>(&signature_data),
-                None,
-            )
-        };
-        let attestation_statement = PackedAttestationStatement {
-            alg: SignatureAlgorithm::ES256 as i64,
-            sig: signature.to_asn1_der(),
-            x5c,
-            ecdaa_key_id: None,
+        let attestation_input = AttestationInput {
+            auth_data: &auth_data,
+            client_data_hash: &client_data_hash,
+            rp_id_hash: &rp_id_hash,
+            credential_id: &credential_id,
+            credential_key: &sk,
+            batch_attestation: self.batch_attestation(),
+            u2f_attestation: self.u2f_attestation(),
         };
+        let att_stmt = attestation::attest(
+            &attestation_input,
+            attestation_formats_preference
+                .as_ref()
+                .map_or(&[], |p| &p[..]),
+            DEFAULT_ATTESTATION_FORMAT,
+        );
         Ok(ResponseData::AuthenticatorMakeCredential(
             AuthenticatorMakeCredentialResponse {
-                fmt: String::from("packed"),
                 auth_data,
-                att_stmt: attestation_statement,
+                att_stmt,
             },
         ))
     }
@@ -587,7 +684,7 @@ where
 
             match pin_uv_auth_protocol {
                 Some(protocol) => {
-                    if protocol != CtapState::<R, CheckUserPresence>::PIN_PROTOCOL_VERSION {
+                    if protocol != CtapState::<E>::PIN_PROTOCOL_VERSION {
                         return Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_INVALID);
                     }
                 }
@@ -607,7 +704,7 @@ where
         if pin_uv_auth_param.is_some() {
             match pin_uv_auth_protocol {
                 Some(protocol) => {
-                    if protocol != CtapState::<R, CheckUserPresence>::PIN_PROTOCOL_VERSION {
+                    if protocol != CtapState::<E>::PIN_PROTOCOL_VERSION {
                         return Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_INVALID);
                     }
                 }
@@ -664,8 +761,10 @@ where
                     Some(credential) => found_credentials.push(credential),
                     None => {
                         if decrypted_credential.is_none() {
-                            decrypted_credential = self
-                                .decrypt_credential_source(allowed_credential.key_id, &rp_id_hash);
+                            decrypted_credential = self.decrypt_credential_source(
+                                allowed_credential.key_id,
+                                &rp_id_hash,
+                            )?;
                         }
                     }
                 }
@@ -685,12 +784,18 @@ where
         };
 
         if options.up {
-            (self.check_user_presence)(cid)?;
+            self.env.check_user_presence(cid)?;
         }
 
-        self.increment_global_signature_counter();
-
-        let mut auth_data = self.generate_auth_data(&rp_id_hash, flags);
+        // Resident credentials have their own signature counter, and non-resident credentials
+        // share the one of their relying party.
+        let owner = if credentials.is_empty() {
+            CounterOwner::RelyingParty(&rp_id_hash)
+        } else {
+            CounterOwner::Credential(&credential.credential_id)
+        };
+        let signature_counter = self.increment_signature_counter(owner);
+        let mut auth_data = self.generate_auth_data(&rp_id_hash, flags, signature_counter);
         // Process extensions.
         if let Some(get_assertion_hmac_secret_input) = get_assertion_hmac_secret_input {
             let GetAssertionHmacSecretInput {
@@ -769,12 +874,10 @@ where
                     String::from(FIDO2_VERSION_STRING),
                 ],
                 extensions: Some(vec![String::from("hmac-secret")]),
-                aaguid: *AAGUID,
+                aaguid: *self.aaguid(),
                 options: Some(options_map),
                 max_msg_size: Some(1024),
-                pin_protocols: Some(vec![
-                    CtapState::<R, CheckUserPresence>::PIN_PROTOCOL_VERSION,
-                ]),
+                pin_protocols: Some(vec![CtapState::<E>::PIN_PROTOCOL_VERSION]),
                 #[cfg(feature = "with_ctap2_1")]
                 max_credential_count_in_list: MAX_CREDENTIAL_COUNT_IN_LIST.map(|c| c as u64),
                 // You can use ENCRYPTED_CREDENTIAL_ID_SIZE here, but if your
@@ -821,7 +924,8 @@ where
         }
         let mut pin_hash = [0; 16];
         pin_hash.copy_from_slice(&Sha256::hash(&pin[..])[..16]);
-        self.persistent_store.set_pin_hash(&pin_hash);
+        self.persistent_store
+            .set_pin_hash(&pin_hash, self.env.rng());
         true
     }
 
@@ -850,7 +954,7 @@ where
 
                 let pin_comparison = array_ref![pin_hash, 0, PIN_AUTH_LENGTH].ct_eq(&blocks[0]);
                 if !bool::from(pin_comparison) {
-                    self.key_agreement_key = crypto::ecdh::SecKey::gensk(self.rng);
+                    self.key_agreement_key = crypto::ecdh::SecKey::gensk(self.env.rng());
                     if self.persistent_store.pin_retries() == 0 {
                         return Err(Ctap2StatusCode::CTAP2_ERR_PIN_BLOCKED);
                     }
@@ -907,7 +1011,6 @@ where
         if !self.check_and_store_new_pin(&aes_dec_key, new_pin_enc) {
             return Err(Ctap2StatusCode::CTAP2_ERR_PIN_POLICY_VIOLATION);
         }
-        self.persistent_store.reset_pin_retries();
         Ok(())
     }
 
@@ -937,7 +1040,7 @@ where
         if !self.check_and_store_new_pin(&aes_dec_key, new_pin_enc) {
             return Err(Ctap2StatusCode::CTAP2_ERR_PIN_POLICY_VIOLATION);
         }
-        self.pin_uv_auth_token = self.rng.gen_uniform_u8x32();
+        self.pin_uv_auth_token = self.env.rng().gen_uniform_u8x32();
         Ok(())
     }
 
@@ -1054,11 +1157,11 @@ where
         if !self.accepts_reset {
             return Err(Ctap2StatusCode::CTAP2_ERR_NOT_ALLOWED);
         }
-        (self.check_user_presence)(cid)?;
+        self.env.check_user_presence(cid)?;
 
-        self.persistent_store.reset(self.rng);
-        self.key_agreement_key = crypto::ecdh::SecKey::gensk(self.rng);
-        self.pin_uv_auth_token = self.rng.gen_uniform_u8x32();
+        self.persistent_store.reset(self.env.rng());
+        self.key_agreement_key = crypto::ecdh::SecKey::gensk(self.env.rng());
+        self.pin_uv_auth_token = self.env.rng().gen_uniform_u8x32();
         self.consecutive_pin_mismatches = 0;
         #[cfg(feature = "with_ctap1")]
         {
@@ -1070,18 +1173,66 @@ where
         Ok(ResponseData::AuthenticatorReset)
     }
 
-    pub fn generate_auth_data(&self, rp_id_hash: &[u8], flag_byte: u8) -> Vec<u8> {
+    fn process_vendor(
+        &mut self,
+        command: u8,
+        vendor_params: AuthenticatorVendorParameters,
+        cid: ChannelID,
+    ) -> Result<ResponseData, Ctap2StatusCode> {
+        let AuthenticatorVendorParameters {
+            sub_command,
+            sub_command_params,
+            pin_uv_auth_protocol,
+            pin_uv_auth_param,
+        } = vendor_params;
+
+        let vendor_command = self.vendor_commands.find(command, sub_command)?;
+        match vendor_command.authorization {
+            VendorAuthorization::None => (),
+            VendorAuthorization::PinToken => {
+                let pin_auth = match pin_uv_auth_param {
+                    Some(pin_auth) => pin_auth,
+                    None if self.persistent_store.pin_hash().is_none() => {
+                        return Err(Ctap2StatusCode::CTAP2_ERR_PIN_NOT_SET)
+                    }
+                    None => return Err(Ctap2StatusCode::CTAP2_ERR_PIN_REQUIRED),
+                };
+                match pin_uv_auth_protocol {
+                    Some(CtapState::<E>::PIN_PROTOCOL_VERSION) => (),
+                    Some(_) => return Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_INVALID),
+                    None => return Err(Ctap2StatusCode::CTAP2_ERR_MISSING_PARAMETER),
+                }
+                let mut auth_contents = vec![0xFF; 32];
+                auth_contents.push(command);
+                auth_contents.push(sub_command);
+                if let Some(value) = sub_command_params.clone() {
+                    if !cbor::write(value, &mut auth_contents) {
+                        return Err(Ctap2StatusCode::CTAP2_ERR_INVALID_CBOR);
+                    }
+                }
+                if !check_pin_auth(&self.pin_uv_auth_token, &auth_contents, &pin_auth) {
+                    return Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_INVALID);
+                }
+            }
+            VendorAuthorization::UserPresence => self.env.check_user_presence(cid)?,
+        }
+        let response = (vendor_command.process)(self, sub_command_params)?;
+        Ok(ResponseData::AuthenticatorVendor(response))
+    }
+
+    pub fn generate_auth_data(
+        &self,
+        rp_id_hash: &[u8],
+        flag_byte: u8,
+        signature_counter: u32,
+    ) -> Vec<u8> {
         let mut auth_data = vec![];
         auth_data.extend(rp_id_hash);
         auth_data.push(flag_byte);
-        // The global counter is only increased if USE_SIGNATURE_COUNTER is true.
-        // It uses a big-endian representation.
-        let mut signature_counter = [0u8; 4];
-        BigEndian::write_u32(
-            &mut signature_counter,
-            self.persistent_store.global_signature_counter(),
-        );
-        auth_data.extend(&signature_counter);
+        // The signature counter uses a big-endian representation.
+        let mut signature_counter_bytes = [0u8; 4];
+        BigEndian::write_u32(&mut signature_counter_bytes, signature_counter);
+        auth_data.extend(&signature_counter_bytes);
         auth_data
     }
 }
@@ -1089,10 +1240,11 @@ where
 #[cfg(test)]
 mod test {
     use super::data_formats::{
-        Extensions, GetAssertionOptions, MakeCredentialOptions, PublicKeyCredentialRpEntity,
-        PublicKeyCredentialUserEntity,
+        AttestationStatement, Extensions, GetAssertionOptions, MakeCredentialOptions,
+        PublicKeyCredentialRpEntity, PublicKeyCredentialUserEntity,
     };
     use super::*;
+    use crate::env::host::HostEnv;
     use crypto::rng256::ThreadRng256;
 
     // The keep-alive logic in the processing of some commands needs a channel ID to send
@@ -1103,9 +1255,8 @@ mod test {
 
     #[test]
     fn test_get_info() {
-        let mut rng = ThreadRng256 {};
         let user_immediately_present = |_| Ok(());
-        let mut ctap_state = CtapState::new(&mut rng, user_immediately_present);
+        let mut ctap_state = CtapState::new(HostEnv::new(user_immediately_present));
         let info_reponse = ctap_state.process_command(&[0x04], DUMMY_CHANNEL_ID);
 
         #[cfg(feature = "with_ctap2_1")]
@@ -1142,7 +1293,7 @@ mod test {
         assert_eq!(info_reponse, expected_response);
     }
 
-    fn create_minimal_make_credential_parameters() -> AuthenticatorMakeCredentialParameters {
+    pub fn create_minimal_make_credential_parameters() -> AuthenticatorMakeCredentialParameters {
         let client_data_hash = vec![0xCD];
         let rp = PublicKeyCredentialRpEntity {
             rp_id: String::from("example.com"),
@@ -1170,14 +1321,14 @@ mod test {
             options,
             pin_uv_auth_param: None,
             pin_uv_auth_protocol: None,
+            attestation_formats_preference: None,
         }
     }
 
     #[test]
     fn test_residential_process_make_credential() {
-        let mut rng = ThreadRng256 {};
         let user_immediately_present = |_| Ok(());
-        let mut ctap_state = CtapState::new(&mut rng, user_immediately_present);
+        let mut ctap_state = CtapState::new(HostEnv::new(user_immediately_present));
 
         let make_credential_params = create_minimal_make_credential_parameters();
         let make_credential_response =
@@ -1186,12 +1337,10 @@ mod test {
         match make_credential_response.unwrap() {
             ResponseData::AuthenticatorMakeCredential(make_credential_response) => {
                 let AuthenticatorMakeCredentialResponse {
-                    fmt,
                     auth_data,
                     att_stmt,
                 } = make_credential_response;
                 // The expected response is split to only assert the non-random parts.
-                assert_eq!(fmt, "packed");
                 let mut expected_auth_data = vec![
                     0xA3, 0x79, 0xA6, 0xF6, 0xEE, 0xAF, 0xB9, 0xA5, 0x5E, 0x37, 0x8C, 0x11, 0x80,
                     0x34, 0xE2, 0x75, 0x1E, 0x68, 0x2F, 0xAB, 0x9F, 0x2D, 0x30, 0xAB, 0x13, 0xD2,
@@ -1203,7 +1352,12 @@ mod test {
                     auth_data[0..expected_auth_data.len()],
                     expected_auth_data[..]
                 );
-                assert_eq!(att_stmt.alg, SignatureAlgorithm::ES256 as i64);
+                match att_stmt {
+                    AttestationStatement::Packed(att_stmt) => {
+                        assert_eq!(att_stmt.alg, SignatureAlgorithm::ES256 as i64)
+                    }
+                    _ => panic!("Invalid attestation statement"),
+                }
             }
             _ => panic!("Invalid response type"),
         }
@@ -1211,9 +1365,8 @@ mod test {
 
     #[test]
     fn test_non_residential_process_make_credential() {
-        let mut rng = ThreadRng256 {};
         let user_immediately_present = |_| Ok(());
-        let mut ctap_state = CtapState::new(&mut rng, user_immediately_present);
+        let mut ctap_state = CtapState::new(HostEnv::new(user_immediately_present));
 
         let mut make_credential_params = create_minimal_make_credential_parameters();
         make_credential_params.options.rk = false;
@@ -1223,12 +1376,10 @@ mod test {
         match make_credential_response.unwrap() {
             ResponseData::AuthenticatorMakeCredential(make_credential_response) => {
                 let AuthenticatorMakeCredentialResponse {
-                    fmt,
                     auth_data,
                     att_stmt,
                 } = make_credential_response;
                 // The expected response is split to only assert the non-random parts.
-                assert_eq!(fmt, "packed");
                 let mut expected_auth_data = vec![
                     0xA3, 0x79, 0xA6, 0xF6, 0xEE, 0xAF, 0xB9, 0xA5, 0x5E, 0x37, 0x8C, 0x11, 0x80,
                     0x34, 0xE2, 0x75, 0x1E, 0x68, 0x2F, 0xAB, 0x9F, 0x2D, 0x30, 0xAB, 0x13, 0xD2,
@@ -1240,7 +1391,12 @@ mod test {
                     auth_data[0..expected_auth_data.len()],
                     expected_auth_data[..]
                 );
-                assert_eq!(att_stmt.alg, SignatureAlgorithm::ES256 as i64);
+                match att_stmt {
+                    AttestationStatement::Packed(att_stmt) => {
+                        assert_eq!(att_stmt.alg, SignatureAlgorithm::ES256 as i64)
+                    }
+                    _ => panic!("Invalid attestation statement"),
+                }
             }
             _ => panic!("Invalid response type"),
         }
@@ -1248,9 +1404,8 @@ mod test {
 
     #[test]
     fn test_process_make_credential_unsupported_algorithm() {
-        let mut rng = ThreadRng256 {};
         let user_immediately_present = |_| Ok(());
-        let mut ctap_state = CtapState::new(&mut rng, user_immediately_present);
+        let mut ctap_state = CtapState::new(HostEnv::new(user_immediately_present));
 
         let mut make_credential_params = create_minimal_make_credential_parameters();
         make_credential_params.pub_key_cred_params = vec![];
@@ -1268,7 +1423,7 @@ mod test {
         let mut rng = ThreadRng256 {};
         let excluded_private_key = crypto::ecdsa::SecKey::gensk(&mut rng);
         let user_immediately_present = |_| Ok(());
-        let mut ctap_state = CtapState::new(&mut rng, user_immediately_present);
+        let mut ctap_state = CtapState::new(HostEnv::new(user_immediately_present));
 
         let excluded_credential_id = vec![0x01, 0x23, 0x45, 0x67];
         let excluded_credential_source = PublicKeyCredentialSource {
@@ -1282,7 +1437,7 @@ mod test {
         };
         assert!(ctap_state
             .persistent_store
-            .store_credential(excluded_credential_source)
+            .store_credential(excluded_credential_source, &mut rng)
             .is_ok());
 
         let excluded_credential_descriptor = PublicKeyCredentialDescriptor {
@@ -1304,9 +1459,8 @@ mod test {
 
     #[test]
     fn test_process_make_credential_hmac_secret() {
-        let mut rng = ThreadRng256 {};
         let user_immediately_present = |_| Ok(());
-        let mut ctap_state = CtapState::new(&mut rng, user_immediately_present);
+        let mut ctap_state = CtapState::new(HostEnv::new(user_immediately_present));
 
         let mut extension_map = BTreeMap::new();
         extension_map.insert("hmac-secret".to_string(), cbor_bool!(true));
@@ -1319,12 +1473,10 @@ mod test {
         match make_credential_response.unwrap() {
             ResponseData::AuthenticatorMakeCredential(make_credential_response) => {
                 let AuthenticatorMakeCredentialResponse {
-                    fmt,
                     auth_data,
                     att_stmt,
                 } = make_credential_response;
                 // The expected response is split to only assert the non-random parts.
-                assert_eq!(fmt, "packed");
                 let mut expected_auth_data = vec![
                     0xA3, 0x79, 0xA6, 0xF6, 0xEE, 0xAF, 0xB9, 0xA5, 0x5E, 0x37, 0x8C, 0x11, 0x80,
                     0x34, 0xE2, 0x75, 0x1E, 0x68, 0x2F, 0xAB, 0x9F, 0x2D, 0x30, 0xAB, 0x13, 0xD2,
@@ -1344,7 +1496,49 @@ mod test {
                     auth_data[auth_data.len() - expected_extension_cbor.len()..auth_data.len()],
                     expected_extension_cbor[..]
                 );
-                assert_eq!(att_stmt.alg, SignatureAlgorithm::ES256 as i64);
+                match att_stmt {
+                    AttestationStatement::Packed(att_stmt) => {
+                        assert_eq!(att_stmt.alg, SignatureAlgorithm::ES256 as i64)
+                    }
+                    _ => panic!("Invalid attestation statement"),
+                }
+            }
+            _ => panic!("Invalid response type"),
+        }
+    }
+
+    #[test]
+    fn test_process_make_credential_attestation_formats() {
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(HostEnv::new(user_immediately_present));
+
+        let mut make_credential_params = create_minimal_make_credential_parameters();
+        make_credential_params.attestation_formats_preference = Some(vec![
+            AttestationStatementFormat::Unknown,
+            AttestationStatementFormat::None,
+        ]);
+        match ctap_state.process_make_credential(make_credential_params, DUMMY_CHANNEL_ID) {
+            Ok(ResponseData::AuthenticatorMakeCredential(make_credential_response)) => {
+                assert_eq!(
+                    make_credential_response.att_stmt,
+                    AttestationStatement::None
+                );
+            }
+            _ => panic!("Invalid response type"),
+        }
+
+        // U2F brings its own certificate, otherwise fido-u2f needs a provisioned one.
+        let mut make_credential_params = create_minimal_make_credential_parameters();
+        make_credential_params.attestation_formats_preference =
+            Some(vec![AttestationStatementFormat::FidoU2f]);
+        let expected_format = if cfg!(feature = "with_ctap1") {
+            AttestationStatementFormat::FidoU2f
+        } else {
+            DEFAULT_ATTESTATION_FORMAT
+        };
+        match ctap_state.process_make_credential(make_credential_params, DUMMY_CHANNEL_ID) {
+            Ok(ResponseData::AuthenticatorMakeCredential(make_credential_response)) => {
+                assert_eq!(make_credential_response.att_stmt.format(), expected_format);
             }
             _ => panic!("Invalid response type"),
         }
@@ -1352,9 +1546,8 @@ mod test {
 
     #[test]
     fn test_process_make_credential_cancelled() {
-        let mut rng = ThreadRng256 {};
         let user_presence_always_cancel = |_| Err(Ctap2StatusCode::CTAP2_ERR_KEEPALIVE_CANCEL);
-        let mut ctap_state = CtapState::new(&mut rng, user_presence_always_cancel);
+        let mut ctap_state = CtapState::new(HostEnv::new(user_presence_always_cancel));
 
         let make_credential_params = create_minimal_make_credential_parameters();
         let make_credential_response =
@@ -1368,9 +1561,8 @@ mod test {
 
     #[test]
     fn test_residential_process_get_assertion() {
-        let mut rng = ThreadRng256 {};
         let user_immediately_present = |_| Ok(());
-        let mut ctap_state = CtapState::new(&mut rng, user_immediately_present);
+        let mut ctap_state = CtapState::new(HostEnv::new(user_immediately_present));
 
         let make_credential_params = create_minimal_make_credential_parameters();
         assert!(ctap_state
@@ -1418,7 +1610,7 @@ mod test {
         let mut rng = ThreadRng256 {};
         let sk = crypto::ecdh::SecKey::gensk(&mut rng);
         let user_immediately_present = |_| Ok(());
-        let mut ctap_state = CtapState::new(&mut rng, user_immediately_present);
+        let mut ctap_state = CtapState::new(HostEnv::new(user_immediately_present));
 
         let mut extension_map = BTreeMap::new();
         extension_map.insert("hmac-secret".to_string(), cbor_bool!(true));
@@ -1460,12 +1652,30 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_process_command_newer_schema() {
+        let user_immediately_present = |_| Ok(());
+        let ctap_state = CtapState::new(HostEnv::new(user_immediately_present));
+        let storage = ctap_state.persistent_store.into_downgraded_storage();
+        let mut ctap_state =
+            CtapState::new(HostEnv::with_storage(user_immediately_present, storage));
+
+        let info_response = ctap_state.process_command(&[0x04], DUMMY_CHANNEL_ID);
+        assert_eq!(info_response[0], 0x00);
+        let reset_response = ctap_state.process_command(&[0x07], DUMMY_CHANNEL_ID);
+        assert_eq!(
+            reset_response,
+            vec![Ctap2StatusCode::CTAP2_ERR_VENDOR_INTERNAL_ERROR as u8]
+        );
+        assert!(!ctap_state.idle_step());
+    }
+
     #[test]
     fn test_process_reset() {
         let mut rng = ThreadRng256 {};
         let user_immediately_present = |_| Ok(());
         let private_key = crypto::ecdsa::SecKey::gensk(&mut rng);
-        let mut ctap_state = CtapState::new(&mut rng, user_immediately_present);
+        let mut ctap_state = CtapState::new(HostEnv::new(user_immediately_present));
 
         let credential_id = vec![0x01, 0x23, 0x45, 0x67];
         let credential_source = PublicKeyCredentialSource {
@@ -1479,7 +1689,7 @@ mod test {
         };
         assert!(ctap_state
             .persistent_store
-            .store_credential(credential_source)
+            .store_credential(credential_source, &mut rng)
             .is_ok());
         assert!(ctap_state.persistent_store.count_credentials() > 0);
 
@@ -1491,9 +1701,8 @@ mod test {
 
     #[test]
     fn test_process_reset_cancelled() {
-        let mut rng = ThreadRng256 {};
         let user_presence_always_cancel = |_| Err(Ctap2StatusCode::CTAP2_ERR_KEEPALIVE_CANCEL);
-        let mut ctap_state = CtapState::new(&mut rng, user_presence_always_cancel);
+        let mut ctap_state = CtapState::new(HostEnv::new(user_presence_always_cancel));
 
         let reset_reponse = ctap_state.process_reset(DUMMY_CHANNEL_ID);
 
@@ -1503,19 +1712,41 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_process_unsupported_commands() {
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(HostEnv::new(user_immediately_present));
+
+        let get_next_assertion_response = ctap_state.process_command(&[0x08], DUMMY_CHANNEL_ID);
+        assert_eq!(
+            get_next_assertion_response,
+            vec![Ctap2StatusCode::CTAP2_ERR_NOT_ALLOWED as u8]
+        );
+        // Vendor commands are unknown until registered, 0x40 is used for provisioning.
+        let vendor_response =
+            ctap_state.process_command(&[0x41, 0xA1, 0x01, 0x01], DUMMY_CHANNEL_ID);
+        assert_eq!(
+            vendor_response,
+            vec![Ctap2StatusCode::CTAP1_ERR_INVALID_COMMAND as u8]
+        );
+    }
+
     #[test]
     fn test_encrypt_decrypt_credential() {
         let mut rng = ThreadRng256 {};
         let user_immediately_present = |_| Ok(());
         let private_key = crypto::ecdsa::SecKey::gensk(&mut rng);
-        let mut ctap_state = CtapState::new(&mut rng, user_immediately_present);
+        let mut ctap_state = CtapState::new(HostEnv::new(user_immediately_present));
 
         // Usually, the relying party ID or its hash is provided by the client.
         // We are not testing the correctness of our SHA256 here, only if it is checked.
         let rp_id_hash = [0x55; 32];
-        let encrypted_id = ctap_state.encrypt_key_handle(private_key.clone(), &rp_id_hash);
+        let encrypted_id = ctap_state
+            .encrypt_key_handle(private_key.clone(), &rp_id_hash)
+            .unwrap();
         let decrypted_source = ctap_state
             .decrypt_credential_source(encrypted_id, &rp_id_hash)
+            .unwrap()
             .unwrap();
 
         assert_eq!(private_key, decrypted_source.private_key);
@@ -1526,16 +1757,19 @@ mod test {
         let mut rng = ThreadRng256 {};
         let user_immediately_present = |_| Ok(());
         let private_key = crypto::ecdsa::SecKey::gensk(&mut rng);
-        let mut ctap_state = CtapState::new(&mut rng, user_immediately_present);
+        let mut ctap_state = CtapState::new(HostEnv::new(user_immediately_present));
 
         // Same as above.
         let rp_id_hash = [0x55; 32];
-        let encrypted_id = ctap_state.encrypt_key_handle(private_key, &rp_id_hash);
+        let encrypted_id = ctap_state
+            .encrypt_key_handle(private_key, &rp_id_hash)
+            .unwrap();
         for i in 0..encrypted_id.len() {
             let mut modified_id = encrypted_id.clone();
             modified_id[i] ^= 0x01;
             assert!(ctap_state
                 .decrypt_credential_source(modified_id, &rp_id_hash)
+                .unwrap()
                 .is_none());
         }
     }
@@ -0,0 +1,473 @@
+// Copyright 2019 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::apdu::{ApduCommand, ApduResponse, ApduSession, ApduStatusCode};
+#[cfg(feature = "with_ctap1")]
+use super::ctap1;
+use super::hid::{ChannelID, KeepaliveStatus};
+use super::CtapState;
+#[cfg(not(feature = "with_ctap1"))]
+use super::FIDO2_VERSION_STRING;
+#[cfg(feature = "with_ctap1")]
+use super::U2F_VERSION_STRING;
+use crate::env::Env;
+use crate::timer::ClockValue;
+use alloc::vec::Vec;
+
+// The specification referenced in this file is the CTAP specification (version 20190130),
+// section 8.2 for the NFC transport.
+
+// CTAP2 commands don't carry a channel over NFC. This identifier is passed to the user presence
+// check instead, so that it can tell NFC requests apart.
+pub const NFC_CHANNEL_ID: ChannelID = [0, 0, 0, 0];
+
+// Status word of a response telling the host that the command is still being processed, with the
+// keepalive status as data. The host then polls with NFCCTAP_GETRESPONSE.
+const SW_STATUS_UPDATE: u16 = 0x9100;
+
+// Implements the FIDO applet behind an ISO-DEP link. The board feeds each received APDU to
+// process_apdu and sends back the returned bytes, which always end with a status word.
+// Command chaining and GET RESPONSE are handled by the APDU session.
+pub struct CtapNfc {
+    session: ApduSession,
+    applet: FidoApplet,
+}
+
+impl CtapNfc {
+    pub fn new() -> CtapNfc {
+        CtapNfc {
+            session: ApduSession::new(),
+            applet: FidoApplet::new(),
+        }
+    }
+
+    // To be called when the reader leaves the field or the link is deactivated.
+    pub fn reset(&mut self) {
+        self.session.reset();
+        self.applet = FidoApplet::new();
+    }
+
+    pub fn process_apdu<E: Env>(
+        &mut self,
+        apdu: &[u8],
+        clock_value: ClockValue,
+        ctap_state: &mut CtapState<E>,
+    ) -> Vec<u8> {
+        let applet = &mut self.applet;
+        self.session.process_apdu(apdu, |command| {
+            applet.process_command(command, clock_value, ctap_state)
+        })
+    }
+
+    // Whether a CTAP2 command was accepted with NFCCTAP_MSG and still waits to be processed.
+    pub fn has_pending_command(&self) -> bool {
+        self.applet.pending_request.is_some()
+    }
+
+    // Processes the command deferred by NFCCTAP_MSG, if any. Boards call this outside of the
+    // exchange of APDUs, so that the host keeps receiving status updates in the meantime.
+    pub fn process_pending_command<E: Env>(&mut self, ctap_state: &mut CtapState<E>) {
+        if let Some(request) = self.applet.pending_request.take() {
+            self.applet.pending_response =
+                Some(ctap_state.process_command(&request, NFC_CHANNEL_ID));
+        }
+    }
+}
+
+struct FidoApplet {
+    selected: bool,
+    // A CTAP2 request accepted with status updates, and its response once processed.
+    pending_request: Option<Vec<u8>>,
+    pending_response: Option<Vec<u8>>,
+}
+
+impl FidoApplet {
+    // Section 8.2.3
+    const AID: [u8; 8] = [0xA0, 0x00, 0x00, 0x06, 0x47, 0x2F, 0x00, 0x01];
+
+    const CLA_ISO: u8 = 0x00;
+    const CLA_PROPRIETARY: u8 = 0x80;
+
+    const INS_SELECT: u8 = 0xA4;
+    const SELECT_BY_NAME_P1: u8 = 0x04;
+    const SELECT_FIRST_P2: u8 = 0x00;
+
+    // Section 8.2.5
+    const INS_NFCCTAP_MSG: u8 = 0x10;
+    const INS_NFCCTAP_GETRESPONSE: u8 = 0x11;
+    const NFCCTAP_MSG_P1_NO_STATUS_UPDATES: u8 = 0x00;
+    const NFCCTAP_MSG_P1_STATUS_UPDATES: u8 = 0x80;
+
+    fn new() -> FidoApplet {
+        FidoApplet {
+            selected: false,
+            pending_request: None,
+            pending_response: None,
+        }
+    }
+
+    fn process_command<E: Env>(
+        &mut self,
+        command: ApduCommand,
+        clock_value: ClockValue,
+        ctap_state: &mut CtapState<E>,
+    ) -> ApduResponse {
+        if command.cla == FidoApplet::CLA_ISO && command.ins == FidoApplet::INS_SELECT {
+            return self.process_select(&command);
+        }
+        if !self.selected {
+            return ApduResponse::error(ApduStatusCode::SW_CONDITIONS_NOT_SATISFIED);
+        }
+        match command.cla {
+            FidoApplet::CLA_PROPRIETARY => match command.ins {
+                FidoApplet::INS_NFCCTAP_MSG => self.process_nfcctap_msg(command, ctap_state),
+                FidoApplet::INS_NFCCTAP_GETRESPONSE => self.process_nfcctap_getresponse(),
+                _ => ApduResponse::error(ApduStatusCode::SW_INS_NOT_SUPPORTED),
+            },
+            // Section 8.2.4: U2F messages are sent as is, with the ISO class.
+            FidoApplet::CLA_ISO => FidoApplet::process_u2f(command, clock_value, ctap_state),
+            _ => ApduResponse::error(ApduStatusCode::SW_CLA_NOT_SUPPORTED),
+        }
+    }
+
+    // Section 8.2.3: the response to a successful SELECT is the version string.
+    fn process_select(&mut self, command: &ApduCommand) -> ApduResponse {
+        if command.p1 != FidoApplet::SELECT_BY_NAME_P1 || command.p2 != FidoApplet::SELECT_FIRST_P2
+        {
+            return ApduResponse::error(ApduStatusCode::SW_INCORRECT_P1P2);
+        }
+        self.pending_request = None;
+        self.pending_response = None;
+        if command.data.as_slice() != FidoApplet::AID {
+            self.selected = false;
+            return ApduResponse::error(ApduStatusCode::SW_FILE_NOT_FOUND);
+        }
+        self.selected = true;
+        #[cfg(feature = "with_ctap1")]
+        let version = U2F_VERSION_STRING;
+        #[cfg(not(feature = "with_ctap1"))]
+        let version = FIDO2_VERSION_STRING;
+        ApduResponse::success(version.as_bytes().to_vec())
+    }
+
+    fn process_nfcctap_msg<E: Env>(
+        &mut self,
+        command: ApduCommand,
+        ctap_state: &mut CtapState<E>,
+    ) -> ApduResponse {
+        if command.p2 != 0x00 {
+            return ApduResponse::error(ApduStatusCode::SW_INCORRECT_P1P2);
+        }
+        // A new command replaces the one that was pending.
+        self.pending_request = None;
+        self.pending_response = None;
+        match command.p1 {
+            FidoApplet::NFCCTAP_MSG_P1_NO_STATUS_UPDATES => {
+                ApduResponse::success(ctap_state.process_command(&command.data, NFC_CHANNEL_ID))
+            }
+            FidoApplet::NFCCTAP_MSG_P1_STATUS_UPDATES => {
+                self.pending_request = Some(command.data);
+                FidoApplet::status_update(KeepaliveStatus::Processing)
+            }
+            _ => ApduResponse::error(ApduStatusCode::SW_INCORRECT_P1P2),
+        }
+    }
+
+    fn process_nfcctap_getresponse(&mut self) -> ApduResponse {
+        if let Some(response) = self.pending_response.take() {
+            ApduResponse::success(response)
+        } else if self.pending_request.is_some() {
+            FidoApplet::status_update(KeepaliveStatus::Processing)
+        } else {
+            ApduResponse::error(ApduStatusCode::SW_CONDITIONS_NOT_SATISFIED)
+        }
+    }
+
+    fn status_update(status: KeepaliveStatus) -> ApduResponse {
+        // Section 8.2.5: the status byte has the same values as in CTAPHID_KEEPALIVE.
+        let status_byte = match status {
+            KeepaliveStatus::Processing => 1,
+            KeepaliveStatus::UpNeeded => 2,
+        };
+        ApduResponse::new(vec![status_byte], SW_STATUS_UPDATE)
+    }
+
+    #[cfg(feature = "with_ctap1")]
+    fn process_u2f<E: Env>(
+        command: ApduCommand,
+        clock_value: ClockValue,
+        ctap_state: &mut CtapState<E>,
+    ) -> ApduResponse {
+        // Bringing the authenticator into the field of the reader counts as user presence.
+        match ctap1::Ctap1Command::process_apdu(command, ctap_state, clock_value, true) {
+            Ok(payload) => ApduResponse::success(payload),
+            Err(status_code) => ApduResponse::error(status_code),
+        }
+    }
+
+    #[cfg(not(feature = "with_ctap1"))]
+    fn process_u2f<E: Env>(
+        _command: ApduCommand,
+        _clock_value: ClockValue,
+        _ctap_state: &mut CtapState<E>,
+    ) -> ApduResponse {
+        ApduResponse::error(ApduStatusCode::SW_INS_NOT_SUPPORTED)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::env::host::HostEnv;
+
+    const CLOCK_FREQUENCY_HZ: usize = 32768;
+    const START_CLOCK_VALUE: ClockValue = ClockValue::new(0, CLOCK_FREQUENCY_HZ);
+    const GET_INFO: u8 = 0x04;
+
+    const SELECT_FIDO: [u8; 13] = [
+        0x00, 0xA4, 0x04, 0x00, 0x08, 0xA0, 0x00, 0x00, 0x06, 0x47, 0x2F, 0x00, 0x01,
+    ];
+    const GET_INFO_MSG: [u8; 7] = [0x80, 0x10, 0x00, 0x00, 0x01, GET_INFO, 0x00];
+
+    fn split_status(response: &[u8]) -> (&[u8], u16) {
+        let (data, status) = response.split_at(response.len() - 2);
+        (data, (status[0] as u16) << 8 | status[1] as u16)
+    }
+
+    #[cfg(feature = "with_ctap1")]
+    fn expected_version() -> &'static [u8] {
+        U2F_VERSION_STRING.as_bytes()
+    }
+
+    #[cfg(not(feature = "with_ctap1"))]
+    fn expected_version() -> &'static [u8] {
+        FIDO2_VERSION_STRING.as_bytes()
+    }
+
+    #[test]
+    fn test_select() {
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(HostEnv::new(user_immediately_present));
+        let mut nfc = CtapNfc::new();
+
+        let response = nfc.process_apdu(&SELECT_FIDO, START_CLOCK_VALUE, &mut ctap_state);
+        let (data, status) = split_status(&response);
+        assert_eq!(data, expected_version());
+        assert_eq!(status, 0x9000);
+    }
+
+    #[test]
+    fn test_select_wrong_aid() {
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(HostEnv::new(user_immediately_present));
+        let mut nfc = CtapNfc::new();
+
+        nfc.process_apdu(&SELECT_FIDO, START_CLOCK_VALUE, &mut ctap_state);
+        let select_other = [0x00, 0xA4, 0x04, 0x00, 0x03, 0x01, 0x02, 0x03];
+        let response = nfc.process_apdu(&select_other, START_CLOCK_VALUE, &mut ctap_state);
+        assert_eq!(response, vec![0x6A, 0x82]);
+        // Selecting another application deselects the FIDO applet.
+        let response = nfc.process_apdu(&GET_INFO_MSG, START_CLOCK_VALUE, &mut ctap_state);
+        assert_eq!(response, vec![0x69, 0x85]);
+    }
+
+    #[test]
+    fn test_command_before_select() {
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(HostEnv::new(user_immediately_present));
+        let mut nfc = CtapNfc::new();
+
+        let response = nfc.process_apdu(&GET_INFO_MSG, START_CLOCK_VALUE, &mut ctap_state);
+        assert_eq!(response, vec![0x69, 0x85]);
+    }
+
+    #[test]
+    fn test_reset() {
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(HostEnv::new(user_immediately_present));
+        let mut nfc = CtapNfc::new();
+
+        nfc.process_apdu(&SELECT_FIDO, START_CLOCK_VALUE, &mut ctap_state);
+        nfc.reset();
+        // Leaving the field deselects the FIDO applet.
+        let response = nfc.process_apdu(&GET_INFO_MSG, START_CLOCK_VALUE, &mut ctap_state);
+        assert_eq!(response, vec![0x69, 0x85]);
+    }
+
+    #[test]
+    fn test_nfcctap_msg() {
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(HostEnv::new(user_immediately_present));
+        let mut nfc = CtapNfc::new();
+
+        nfc.process_apdu(&SELECT_FIDO, START_CLOCK_VALUE, &mut ctap_state);
+        let response = nfc.process_apdu(&GET_INFO_MSG, START_CLOCK_VALUE, &mut ctap_state);
+        let (data, status) = split_status(&response);
+        assert_eq!(status, 0x9000);
+        let expected = ctap_state.process_command(&[GET_INFO], NFC_CHANNEL_ID);
+        assert_eq!(data, expected.as_slice());
+    }
+
+    #[test]
+    fn test_nfcctap_msg_get_response() {
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(HostEnv::new(user_immediately_present));
+        let mut nfc = CtapNfc::new();
+
+        nfc.process_apdu(&SELECT_FIDO, START_CLOCK_VALUE, &mut ctap_state);
+        // The host only expects 16 bytes, the rest is fetched with GET RESPONSE.
+        let mut get_info_msg = GET_INFO_MSG;
+        get_info_msg[6] = 0x10;
+        let mut response = nfc.process_apdu(&get_info_msg, START_CLOCK_VALUE, &mut ctap_state);
+        let mut data = Vec::new();
+        loop {
+            let (chunk, status) = split_status(&response);
+            data.extend_from_slice(chunk);
+            if status == 0x9000 {
+                break;
+            }
+            assert_eq!(status & 0xFF00, 0x6100);
+            let get_response = [0x00, 0xC0, 0x00, 0x00, 0x10];
+            response = nfc.process_apdu(&get_response, START_CLOCK_VALUE, &mut ctap_state);
+        }
+        let expected = ctap_state.process_command(&[GET_INFO], NFC_CHANNEL_ID);
+        assert_eq!(data, expected);
+    }
+
+    #[test]
+    fn test_nfcctap_msg_chained() {
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(HostEnv::new(user_immediately_present));
+        let mut nfc = CtapNfc::new();
+
+        nfc.process_apdu(&SELECT_FIDO, START_CLOCK_VALUE, &mut ctap_state);
+        // An empty first part of the chain, then the command byte.
+        let first = [0x90, 0x10, 0x00, 0x00];
+        let response = nfc.process_apdu(&first, START_CLOCK_VALUE, &mut ctap_state);
+        assert_eq!(response, vec![0x90, 0x00]);
+        let response = nfc.process_apdu(&GET_INFO_MSG, START_CLOCK_VALUE, &mut ctap_state);
+        let (data, status) = split_status(&response);
+        assert_eq!(status, 0x9000);
+        assert_eq!(data[0], 0x00);
+    }
+
+    #[test]
+    fn test_nfcctap_msg_status_updates() {
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(HostEnv::new(user_immediately_present));
+        let mut nfc = CtapNfc::new();
+
+        nfc.process_apdu(&SELECT_FIDO, START_CLOCK_VALUE, &mut ctap_state);
+        let mut get_info_msg = GET_INFO_MSG;
+        get_info_msg[2] = 0x80;
+        let response = nfc.process_apdu(&get_info_msg, START_CLOCK_VALUE, &mut ctap_state);
+        assert_eq!(response, vec![0x01, 0x91, 0x00]);
+        assert!(nfc.has_pending_command());
+
+        let get_response = [0x80, 0x11, 0x00, 0x00];
+        let response = nfc.process_apdu(&get_response, START_CLOCK_VALUE, &mut ctap_state);
+        assert_eq!(response, vec![0x01, 0x91, 0x00]);
+
+        nfc.process_pending_command(&mut ctap_state);
+        assert!(!nfc.has_pending_command());
+        let response = nfc.process_apdu(&get_response, START_CLOCK_VALUE, &mut ctap_state);
+        let (data, status) = split_status(&response);
+        assert_eq!(status, 0x9000);
+        assert_eq!(data[0], 0x00);
+
+        // The response can only be fetched once.
+        let response = nfc.process_apdu(&get_response, START_CLOCK_VALUE, &mut ctap_state);
+        assert_eq!(response, vec![0x69, 0x85]);
+    }
+
+    #[test]
+    fn test_nfcctap_msg_wrong_p1() {
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(HostEnv::new(user_immediately_present));
+        let mut nfc = CtapNfc::new();
+
+        nfc.process_apdu(&SELECT_FIDO, START_CLOCK_VALUE, &mut ctap_state);
+        let mut get_info_msg = GET_INFO_MSG;
+        get_info_msg[2] = 0x01;
+        let response = nfc.process_apdu(&get_info_msg, START_CLOCK_VALUE, &mut ctap_state);
+        assert_eq!(response, vec![0x6A, 0x86]);
+    }
+
+    #[test]
+    fn test_unsupported_cla_ins() {
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(HostEnv::new(user_immediately_present));
+        let mut nfc = CtapNfc::new();
+
+        nfc.process_apdu(&SELECT_FIDO, START_CLOCK_VALUE, &mut ctap_state);
+        let response = nfc.process_apdu(
+            &[0x80, 0x42, 0x00, 0x00],
+            START_CLOCK_VALUE,
+            &mut ctap_state,
+        );
+        assert_eq!(response, vec![0x6D, 0x00]);
+        let response = nfc.process_apdu(
+            &[0x40, 0x10, 0x00, 0x00],
+            START_CLOCK_VALUE,
+            &mut ctap_state,
+        );
+        assert_eq!(response, vec![0x6E, 0x00]);
+    }
+
+    #[cfg(feature = "with_ctap1")]
+    #[test]
+    fn test_u2f_version() {
+        let dummy_user_presence = |_| panic!("Unexpected user presence check in CTAP1");
+        let mut ctap_state = CtapState::new(HostEnv::new(dummy_user_presence));
+        let mut nfc = CtapNfc::new();
+
+        nfc.process_apdu(&SELECT_FIDO, START_CLOCK_VALUE, &mut ctap_state);
+        let version = [0x00, 0x03, 0x00, 0x00, 0x00];
+        let response = nfc.process_apdu(&version, START_CLOCK_VALUE, &mut ctap_state);
+        let (data, status) = split_status(&response);
+        assert_eq!(data, U2F_VERSION_STRING.as_bytes());
+        assert_eq!(status, 0x9000);
+        // The presence implied by NFC doesn't carry over to other transports.
+        assert!(!ctap_state.u2f_up_state.consume_up(START_CLOCK_VALUE));
+    }
+
+    #[cfg(feature = "with_ctap1")]
+    #[test]
+    fn test_u2f_register() {
+        let dummy_user_presence = |_| panic!("Unexpected user presence check in CTAP1");
+        let mut ctap_state = CtapState::new(HostEnv::new(dummy_user_presence));
+        let mut nfc = CtapNfc::new();
+
+        nfc.process_apdu(&SELECT_FIDO, START_CLOCK_VALUE, &mut ctap_state);
+        // A short APDU with the challenge and application parameters, no button press needed.
+        let mut register = vec![0x00, 0x01, 0x00, 0x00, 0x40];
+        register.extend_from_slice(&[0x55; 64]);
+        register.push(0x00);
+        let mut response = nfc.process_apdu(&register, START_CLOCK_VALUE, &mut ctap_state);
+        let mut data = Vec::new();
+        loop {
+            let (chunk, status) = split_status(&response);
+            data.extend_from_slice(chunk);
+            if status == 0x9000 {
+                break;
+            }
+            assert_eq!(status & 0xFF00, 0x6100);
+            let get_response = [0x00, 0xC0, 0x00, 0x00, 0x00];
+            response = nfc.process_apdu(&get_response, START_CLOCK_VALUE, &mut ctap_state);
+        }
+        // Reserved byte, followed by the uncompressed public key.
+        assert_eq!(data[0], 0x05);
+        assert_eq!(data[1], 0x04);
+    }
+}
@@ -0,0 +1,364 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::data_formats::{ok_or_missing, read_array, read_byte_string, read_map};
+use super::status_code::Ctap2StatusCode;
+use super::vendor::{NoParameters, VendorAuthorization, VendorSubCommand};
+use super::CtapState;
+use crate::env::Env;
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+
+// Vendor commands to provision the attestation of a device during manufacturing. A batch shares
+// its attestation key, certificate chain and AAGUID, so the same firmware serves all batches.
+//
+// The attestation can be replaced until it is locked. Those commands don't need any
+// authorization, so devices should be locked before they leave the factory.
+pub const PROVISIONING_COMMAND: u8 = 0x40;
+
+pub fn register_vendor_commands<E: Env>(ctap_state: &mut CtapState<E>) {
+    ctap_state.register_vendor_command::<ProvisionAttestation>();
+    ctap_state.register_vendor_command::<LockAttestation>();
+    ctap_state.register_vendor_command::<GetAttestationState>();
+}
+
+// Writes the private key, the certificate chain and the AAGUID. The chain starts with the
+// certificate of the batch key, and is returned in the x5c field of packed attestations.
+pub struct ProvisionAttestation;
+
+pub struct ProvisionAttestationParameters {
+    pub private_key: [u8; 32],
+    pub certificates: Vec<Vec<u8>>,
+    pub aaguid: [u8; 16],
+}
+
+impl TryFrom<cbor::Value> for ProvisionAttestationParameters {
+    type Error = Ctap2StatusCode;
+
+    fn try_from(cbor_value: cbor::Value) -> Result<Self, Ctap2StatusCode> {
+        let param_map = read_map(&cbor_value)?;
+
+        let private_key = read_byte_string(ok_or_missing(param_map.get(&cbor_unsigned!(1)))?)?;
+        if private_key.len() != 32 {
+            return Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER);
+        }
+
+        let certificates = read_array(ok_or_missing(param_map.get(&cbor_unsigned!(2)))?)?
+            .iter()
+            .map(read_byte_string)
+            .collect::<Result<Vec<Vec<u8>>, Ctap2StatusCode>>()?;
+
+        let aaguid = read_byte_string(ok_or_missing(param_map.get(&cbor_unsigned!(3)))?)?;
+        if aaguid.len() != 16 {
+            return Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER);
+        }
+
+        Ok(ProvisionAttestationParameters {
+            private_key: *array_ref!(private_key, 0, 32),
+            certificates,
+            aaguid: *array_ref!(aaguid, 0, 16),
+        })
+    }
+}
+
+impl<E: Env> VendorSubCommand<E> for ProvisionAttestation {
+    const COMMAND: u8 = PROVISIONING_COMMAND;
+    const SUB_COMMAND: u8 = 0x01;
+    const AUTHORIZATION: VendorAuthorization = VendorAuthorization::None;
+    type Parameters = ProvisionAttestationParameters;
+    type Response = cbor::Value;
+
+    fn process(
+        ctap_state: &mut CtapState<E>,
+        parameters: Option<ProvisionAttestationParameters>,
+    ) -> Result<Option<cbor::Value>, Ctap2StatusCode> {
+        let ProvisionAttestationParameters {
+            private_key,
+            certificates,
+            aaguid,
+        } = ok_or_missing(parameters)?;
+        let attestation_key = crypto::ecdsa::SecKey::from_bytes(&private_key)
+            .ok_or(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER)?;
+        // We don't parse X.509, but the batch certificate must at least contain the public key,
+        // which catches chains that don't belong to the key.
+        let public_key = attestation_key.genpk().to_uncompressed();
+        let certifies_key = certificates.first().map_or(false, |certificate| {
+            certificate
+                .windows(public_key.len())
+                .any(|window| window == &public_key[..])
+        });
+        if !certifies_key {
+            return Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER);
+        }
+        ctap_state.persistent_store.set_attestation(
+            &private_key,
+            &certificates,
+            &aaguid,
+            ctap_state.env.rng(),
+        )?;
+        Ok(None)
+    }
+}
+
+// Prevents any further provisioning, including after a reset.
+pub struct LockAttestation;
+
+impl<E: Env> VendorSubCommand<E> for LockAttestation {
+    const COMMAND: u8 = PROVISIONING_COMMAND;
+    const SUB_COMMAND: u8 = 0x02;
+    const AUTHORIZATION: VendorAuthorization = VendorAuthorization::None;
+    type Parameters = NoParameters;
+    type Response = cbor::Value;
+
+    fn process(
+        ctap_state: &mut CtapState<E>,
+        _: Option<NoParameters>,
+    ) -> Result<Option<cbor::Value>, Ctap2StatusCode> {
+        ctap_state.persistent_store.lock_attestation()?;
+        Ok(None)
+    }
+}
+
+// Tells whether the attestation is provisioned and locked, to check devices at the end of the
+// manufacturing line.
+pub struct GetAttestationState;
+
+pub struct AttestationState {
+    pub provisioned: bool,
+    pub locked: bool,
+}
+
+impl From<AttestationState> for cbor::Value {
+    fn from(state: AttestationState) -> Self {
+        cbor_map! {
+            1 => state.provisioned,
+            2 => state.locked,
+        }
+    }
+}
+
+impl<E: Env> VendorSubCommand<E> for GetAttestationState {
+    const COMMAND: u8 = PROVISIONING_COMMAND;
+    const SUB_COMMAND: u8 = 0x03;
+    const AUTHORIZATION: VendorAuthorization = VendorAuthorization::None;
+    type Parameters = NoParameters;
+    type Response = AttestationState;
+
+    fn process(
+        ctap_state: &mut CtapState<E>,
+        _: Option<NoParameters>,
+    ) -> Result<Option<AttestationState>, Ctap2StatusCode> {
+        Ok(Some(AttestationState {
+            provisioned: ctap_state.persistent_store.aaguid().is_some(),
+            locked: ctap_state.persistent_store.attestation_locked(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::command::AuthenticatorVendorParameters;
+    use super::super::data_formats::{AttestationStatement, AttestationStatementFormat};
+    use super::super::hid::ChannelID;
+    use super::super::response::{AuthenticatorMakeCredentialResponse, ResponseData};
+    use super::*;
+    use crate::env::host::HostEnv;
+    use crypto::rng256::ThreadRng256;
+
+    const DUMMY_CHANNEL_ID: ChannelID = [0x12, 0x34, 0x56, 0x78];
+
+    fn vendor_command(sub_command: u8, sub_command_params: Option<cbor::Value>) -> Vec<u8> {
+        let params = AuthenticatorVendorParameters {
+            sub_command,
+            sub_command_params,
+            pin_uv_auth_protocol: None,
+            pin_uv_auth_param: None,
+        };
+        let mut bytes = vec![PROVISIONING_COMMAND];
+        assert!(cbor::write(params.into(), &mut bytes));
+        bytes
+    }
+
+    // A fake batch certificate, that only has to contain the public key.
+    fn create_attestation(rng: &mut ThreadRng256) -> ([u8; 32], Vec<u8>) {
+        let attestation_key = crypto::ecdsa::SecKey::gensk(rng);
+        let mut private_key = [0; 32];
+        attestation_key.to_bytes(&mut private_key);
+        let mut certificate = vec![0x30, 0x82, 0x01, 0x00];
+        certificate.extend_from_slice(&attestation_key.genpk().to_uncompressed());
+        (private_key, certificate)
+    }
+
+    fn provision_command(private_key: &[u8; 32], certificates: Vec<Vec<u8>>) -> Vec<u8> {
+        let certificates: Vec<cbor::Value> = certificates.into_iter().map(Into::into).collect();
+        vendor_command(
+            0x01,
+            Some(cbor_map! {
+                1 => private_key.to_vec(),
+                2 => cbor_array_vec!(certificates),
+                3 => vec![0xAA; 16],
+            }),
+        )
+    }
+
+    fn attestation_state_response(provisioned: bool, locked: bool) -> Vec<u8> {
+        let mut response = vec![0x00];
+        assert!(cbor::write(
+            AttestationState {
+                provisioned,
+                locked,
+            }
+            .into(),
+            &mut response
+        ));
+        response
+    }
+
+    #[test]
+    fn test_provision_attestation() {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(HostEnv::new(user_immediately_present));
+        let (private_key, certificate) = create_attestation(&mut rng);
+        let intermediate = vec![0x30; 300];
+
+        assert_eq!(
+            ctap_state.process_command(&vendor_command(0x03, None), DUMMY_CHANNEL_ID),
+            attestation_state_response(false, false)
+        );
+        let command = provision_command(
+            &private_key,
+            vec![certificate.clone(), intermediate.clone()],
+        );
+        assert_eq!(
+            ctap_state.process_command(&command, DUMMY_CHANNEL_ID),
+            vec![0x00]
+        );
+        assert_eq!(
+            ctap_state.process_command(&vendor_command(0x03, None), DUMMY_CHANNEL_ID),
+            attestation_state_response(true, false)
+        );
+        assert_eq!(ctap_state.aaguid(), &[0xAA; 16]);
+        let (_, certificates) = ctap_state.batch_attestation().unwrap();
+        assert_eq!(certificates, vec![certificate, intermediate]);
+    }
+
+    #[test]
+    fn test_provision_attestation_invalid() {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(HostEnv::new(user_immediately_present));
+        let (private_key, certificate) = create_attestation(&mut rng);
+        let (_, other_certificate) = create_attestation(&mut rng);
+
+        // The batch certificate has to match the private key.
+        let command = provision_command(&private_key, vec![other_certificate]);
+        assert_eq!(
+            ctap_state.process_command(&command, DUMMY_CHANNEL_ID),
+            vec![Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER as u8]
+        );
+        let command = provision_command(&private_key, vec![]);
+        assert_eq!(
+            ctap_state.process_command(&command, DUMMY_CHANNEL_ID),
+            vec![Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER as u8]
+        );
+        // Zero isn't a valid private key.
+        let command = provision_command(&[0x00; 32], vec![certificate]);
+        assert_eq!(
+            ctap_state.process_command(&command, DUMMY_CHANNEL_ID),
+            vec![Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER as u8]
+        );
+        assert!(ctap_state.batch_attestation().is_none());
+    }
+
+    #[test]
+    fn test_lock_attestation() {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(HostEnv::new(user_immediately_present));
+        let (private_key, certificate) = create_attestation(&mut rng);
+
+        assert_eq!(
+            ctap_state.process_command(&vendor_command(0x02, None), DUMMY_CHANNEL_ID),
+            vec![Ctap2StatusCode::CTAP2_ERR_NOT_ALLOWED as u8]
+        );
+        let command = provision_command(&private_key, vec![certificate]);
+        assert_eq!(
+            ctap_state.process_command(&command, DUMMY_CHANNEL_ID),
+            vec![0x00]
+        );
+        assert_eq!(
+            ctap_state.process_command(&vendor_command(0x02, None), DUMMY_CHANNEL_ID),
+            vec![0x00]
+        );
+        assert_eq!(
+            ctap_state.process_command(&vendor_command(0x03, None), DUMMY_CHANNEL_ID),
+            attestation_state_response(true, true)
+        );
+        assert_eq!(
+            ctap_state.process_command(&command, DUMMY_CHANNEL_ID),
+            vec![Ctap2StatusCode::CTAP2_ERR_NOT_ALLOWED as u8]
+        );
+    }
+
+    #[test]
+    fn test_make_credential_with_batch_attestation() {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(HostEnv::new(user_immediately_present));
+        let (private_key, certificate) = create_attestation(&mut rng);
+        let command = provision_command(&private_key, vec![certificate.clone()]);
+        assert_eq!(
+            ctap_state.process_command(&command, DUMMY_CHANNEL_ID),
+            vec![0x00]
+        );
+
+        let make_credential_params =
+            super::super::test::create_minimal_make_credential_parameters();
+        let response = ctap_state
+            .process_make_credential(make_credential_params, DUMMY_CHANNEL_ID)
+            .unwrap();
+        let response = match response {
+            ResponseData::AuthenticatorMakeCredential(response) => response,
+            _ => panic!("Invalid response type"),
+        };
+        let AuthenticatorMakeCredentialResponse {
+            auth_data,
+            att_stmt,
+            ..
+        } = response;
+        assert_eq!(&auth_data[37..53], &[0xAA; 16]);
+        match att_stmt {
+            AttestationStatement::Packed(att_stmt) => {
+                assert_eq!(att_stmt.x5c, Some(vec![certificate.clone()]))
+            }
+            _ => panic!("Invalid attestation statement"),
+        }
+
+        // The fido-u2f format uses the batch certificate too.
+        let mut make_credential_params =
+            super::super::test::create_minimal_make_credential_parameters();
+        make_credential_params.attestation_formats_preference =
+            Some(vec![AttestationStatementFormat::FidoU2f]);
+        match ctap_state.process_make_credential(make_credential_params, DUMMY_CHANNEL_ID) {
+            Ok(ResponseData::AuthenticatorMakeCredential(
+                AuthenticatorMakeCredentialResponse {
+                    att_stmt: AttestationStatement::FidoU2f(att_stmt),
+                    ..
+                },
+            )) => assert_eq!(att_stmt.attestation_certificate, certificate),
+            _ => panic!("Invalid response type"),
+        }
+    }
+}
@@ -12,15 +12,18 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-#[cfg(feature = "with_ctap2_1")]
-use super::data_formats::{AuthenticatorTransport, PublicKeyCredentialParameter};
 use super::data_formats::{
-    CoseKey, PackedAttestationStatement, PublicKeyCredentialDescriptor,
-    PublicKeyCredentialUserEntity,
+    ok_or_missing, read_array, read_bool, read_byte_string, read_map, read_text_string,
+    read_unsigned, AttestationStatement, AttestationStatementFormat, CoseKey,
+    PublicKeyCredentialDescriptor, PublicKeyCredentialUserEntity,
 };
+#[cfg(feature = "with_ctap2_1")]
+use super::data_formats::{AuthenticatorTransport, PublicKeyCredentialParameter};
+use super::status_code::Ctap2StatusCode;
 use alloc::collections::BTreeMap;
 use alloc::string::String;
 use alloc::vec::Vec;
+use core::convert::TryFrom;
 
 #[cfg_attr(test, derive(PartialEq))]
 #[cfg_attr(any(test, feature = "debug_ctap"), derive(Debug))]
@@ -31,6 +34,7 @@ pub enum ResponseData {
     AuthenticatorGetInfo(AuthenticatorGetInfoResponse),
     AuthenticatorClientPin(Option<AuthenticatorClientPinResponse>),
     AuthenticatorReset,
+    AuthenticatorVendor(Option<cbor::Value>),
 }
 
 impl From<ResponseData> for Option<cbor::Value> {
@@ -43,6 +47,7 @@ impl From<ResponseData> for Option<cbor::Value> {
             ResponseData::AuthenticatorClientPin(Some(data)) => Some(data.into()),
             ResponseData::AuthenticatorClientPin(None) => None,
             ResponseData::AuthenticatorReset => None,
+            ResponseData::AuthenticatorVendor(data) => data,
         }
     }
 }
@@ -50,27 +55,48 @@ impl From<ResponseData> for Option<cbor::Value> {
 #[cfg_attr(test, derive(PartialEq))]
 #[cfg_attr(any(test, feature = "debug_ctap"), derive(Debug))]
 pub struct AuthenticatorMakeCredentialResponse {
-    pub fmt: String,
+    // The fmt entry is written from the attestation statement's format.
     pub auth_data: Vec<u8>,
-    pub att_stmt: PackedAttestationStatement,
+    pub att_stmt: AttestationStatement,
 }
 
 impl From<AuthenticatorMakeCredentialResponse> for cbor::Value {
     fn from(make_credential_response: AuthenticatorMakeCredentialResponse) -> Self {
         let AuthenticatorMakeCredentialResponse {
-            fmt,
             auth_data,
             att_stmt,
         } = make_credential_response;
 
         cbor_map_options! {
-            1 => fmt,
+            1 => att_stmt.format(),
             2 => auth_data,
             3 => att_stmt,
         }
     }
 }
 
+impl TryFrom<cbor::Value> for AuthenticatorMakeCredentialResponse {
+    type Error = Ctap2StatusCode;
+
+    fn try_from(cbor_value: cbor::Value) -> Result<Self, Ctap2StatusCode> {
+        let response_map = read_map(&cbor_value)?;
+
+        let fmt = AttestationStatementFormat::try_from(ok_or_missing(
+            response_map.get(&cbor_unsigned!(1)),
+        )?)?;
+
+        let auth_data = read_byte_string(ok_or_missing(response_map.get(&cbor_unsigned!(2)))?)?;
+
+        let att_stmt =
+            AttestationStatement::read(fmt, ok_or_missing(response_map.get(&cbor_unsigned!(3)))?)?;
+
+        Ok(AuthenticatorMakeCredentialResponse {
+            auth_data,
+            att_stmt,
+        })
+    }
+}
+
 #[cfg_attr(test, derive(PartialEq))]
 #[cfg_attr(any(test, feature = "debug_ctap"), derive(Debug))]
 pub struct AuthenticatorGetAssertionResponse {
@@ -101,6 +127,41 @@ impl From<AuthenticatorGetAssertionResponse> for cbor::Value {
     }
 }
 
+impl TryFrom<cbor::Value> for AuthenticatorGetAssertionResponse {
+    type Error = Ctap2StatusCode;
+
+    fn try_from(cbor_value: cbor::Value) -> Result<Self, Ctap2StatusCode> {
+        let response_map = read_map(&cbor_value)?;
+
+        let credential = response_map
+            .get(&cbor_unsigned!(1))
+            .map(PublicKeyCredentialDescriptor::try_from)
+            .transpose()?;
+
+        let auth_data = read_byte_string(ok_or_missing(response_map.get(&cbor_unsigned!(2)))?)?;
+
+        let signature = read_byte_string(ok_or_missing(response_map.get(&cbor_unsigned!(3)))?)?;
+
+        let user = response_map
+            .get(&cbor_unsigned!(4))
+            .map(PublicKeyCredentialUserEntity::try_from)
+            .transpose()?;
+
+        let number_of_credentials = response_map
+            .get(&cbor_unsigned!(5))
+            .map(read_unsigned)
+            .transpose()?;
+
+        Ok(AuthenticatorGetAssertionResponse {
+            credential,
+            auth_data,
+            signature,
+            user,
+            number_of_credentials,
+        })
+    }
+}
+
 #[cfg_attr(test, derive(PartialEq))]
 #[cfg_attr(any(test, feature = "debug_ctap"), derive(Debug))]
 pub struct AuthenticatorGetInfoResponse {
@@ -193,6 +254,126 @@ impl From<AuthenticatorGetInfoResponse> for cbor::Value {
     }
 }
 
+impl TryFrom<cbor::Value> for AuthenticatorGetInfoResponse {
+    type Error = Ctap2StatusCode;
+
+    fn try_from(cbor_value: cbor::Value) -> Result<Self, Ctap2StatusCode> {
+        let response_map = read_map(&cbor_value)?;
+
+        let versions = read_array(ok_or_missing(response_map.get(&cbor_unsigned!(0x01)))?)?
+            .iter()
+            .map(read_text_string)
+            .collect::<Result<Vec<String>, Ctap2StatusCode>>()?;
+
+        let extensions = response_map
+            .get(&cbor_unsigned!(0x02))
+            .map(|entry| {
+                read_array(entry)?
+                    .iter()
+                    .map(read_text_string)
+                    .collect::<Result<Vec<String>, Ctap2StatusCode>>()
+            })
+            .transpose()?;
+
+        let aaguid_bytes =
+            read_byte_string(ok_or_missing(response_map.get(&cbor_unsigned!(0x03)))?)?;
+        if aaguid_bytes.len() != 16 {
+            return Err(Ctap2StatusCode::CTAP2_ERR_INVALID_CBOR);
+        }
+        let mut aaguid = [0; 16];
+        aaguid.copy_from_slice(&aaguid_bytes);
+
+        let options = match response_map.get(&cbor_unsigned!(0x04)) {
+            Some(entry) => {
+                let mut options = BTreeMap::new();
+                for (key, value) in read_map(entry)? {
+                    if let cbor::KeyType::TextString(key_string) = key {
+                        options.insert(key_string.clone(), read_bool(value)?);
+                    } else {
+                        return Err(Ctap2StatusCode::CTAP2_ERR_CBOR_UNEXPECTED_TYPE);
+                    }
+                }
+                Some(options)
+            }
+            None => None,
+        };
+
+        let max_msg_size = response_map
+            .get(&cbor_unsigned!(0x05))
+            .map(read_unsigned)
+            .transpose()?;
+
+        let pin_protocols = response_map
+            .get(&cbor_unsigned!(0x06))
+            .map(|entry| {
+                read_array(entry)?
+                    .iter()
+                    .map(read_unsigned)
+                    .collect::<Result<Vec<u64>, Ctap2StatusCode>>()
+            })
+            .transpose()?;
+
+        #[cfg(feature = "with_ctap2_1")]
+        let max_credential_count_in_list = response_map
+            .get(&cbor_unsigned!(0x07))
+            .map(read_unsigned)
+            .transpose()?;
+
+        #[cfg(feature = "with_ctap2_1")]
+        let max_credential_id_length = response_map
+            .get(&cbor_unsigned!(0x08))
+            .map(read_unsigned)
+            .transpose()?;
+
+        #[cfg(feature = "with_ctap2_1")]
+        let transports = response_map
+            .get(&cbor_unsigned!(0x09))
+            .map(|entry| {
+                read_array(entry)?
+                    .iter()
+                    .map(AuthenticatorTransport::try_from)
+                    .collect::<Result<Vec<AuthenticatorTransport>, Ctap2StatusCode>>()
+            })
+            .transpose()?;
+
+        #[cfg(feature = "with_ctap2_1")]
+        let algorithms = response_map
+            .get(&cbor_unsigned!(0x0A))
+            .map(|entry| {
+                read_array(entry)?
+                    .iter()
+                    .map(PublicKeyCredentialParameter::try_from)
+                    .collect::<Result<Vec<PublicKeyCredentialParameter>, Ctap2StatusCode>>()
+            })
+            .transpose()?;
+
+        #[cfg(feature = "with_ctap2_1")]
+        let firmware_version = response_map
+            .get(&cbor_unsigned!(0x0E))
+            .map(read_unsigned)
+            .transpose()?;
+
+        Ok(AuthenticatorGetInfoResponse {
+            versions,
+            extensions,
+            aaguid,
+            options,
+            max_msg_size,
+            pin_protocols,
+            #[cfg(feature = "with_ctap2_1")]
+            max_credential_count_in_list,
+            #[cfg(feature = "with_ctap2_1")]
+            max_credential_id_length,
+            #[cfg(feature = "with_ctap2_1")]
+            transports,
+            #[cfg(feature = "with_ctap2_1")]
+            algorithms,
+            #[cfg(feature = "with_ctap2_1")]
+            firmware_version,
+        })
+    }
+}
+
 #[cfg_attr(test, derive(PartialEq))]
 #[cfg_attr(any(test, feature = "debug_ctap"), derive(Debug))]
 pub struct AuthenticatorClientPinResponse {
@@ -210,16 +391,47 @@ impl From<AuthenticatorClientPinResponse> for cbor::Value {
         } = client_pin_response;
 
         cbor_map_options! {
-            1 => key_agreement.map(|cose_key| cbor_map_btree!(cose_key.0)),
+            1 => key_agreement,
             2 => pin_token,
             3 => retries,
         }
     }
 }
 
+impl TryFrom<cbor::Value> for AuthenticatorClientPinResponse {
+    type Error = Ctap2StatusCode;
+
+    fn try_from(cbor_value: cbor::Value) -> Result<Self, Ctap2StatusCode> {
+        let response_map = read_map(&cbor_value)?;
+
+        let key_agreement = response_map
+            .get(&cbor_unsigned!(1))
+            .map(CoseKey::try_from)
+            .transpose()?;
+
+        let pin_token = response_map
+            .get(&cbor_unsigned!(2))
+            .map(read_byte_string)
+            .transpose()?;
+
+        let retries = response_map
+            .get(&cbor_unsigned!(3))
+            .map(read_unsigned)
+            .transpose()?;
+
+        Ok(AuthenticatorClientPinResponse {
+            key_agreement,
+            pin_token,
+            retries,
+        })
+    }
+}
+
 #[cfg(test)]
 mod test {
-    use super::super::data_formats::PackedAttestationStatement;
+    use super::super::data_formats::{
+        FidoU2fAttestationStatement, PackedAttestationStatement, PublicKeyCredentialType,
+    };
     #[cfg(feature = "with_ctap2_1")]
     use super::super::ES256_CRED_PARAM;
     use super::*;
@@ -241,9 +453,8 @@ mod test {
         };
 
         let make_credential_response = AuthenticatorMakeCredentialResponse {
-            fmt: "packed".to_string(),
             auth_data: vec![0xAD],
-            att_stmt,
+            att_stmt: AttestationStatement::Packed(att_stmt),
         };
         let response_cbor: Option<cbor::Value> =
             ResponseData::AuthenticatorMakeCredential(make_credential_response).into();
@@ -255,6 +466,104 @@ mod test {
         assert_eq!(response_cbor, Some(expected_cbor));
     }
 
+    #[test]
+    fn test_make_credential_from_cbor() {
+        let cbor_value = cbor_map! {
+            1 => "packed",
+            2 => vec![0xAD],
+            3 => cbor_map! {
+                "alg" => -7,
+                "sig" => vec![0x55, 0x55, 0x55, 0x55],
+            },
+        };
+        let make_credential_response = AuthenticatorMakeCredentialResponse::try_from(cbor_value);
+        let expected_make_credential_response = AuthenticatorMakeCredentialResponse {
+            auth_data: vec![0xAD],
+            att_stmt: AttestationStatement::Packed(PackedAttestationStatement {
+                alg: -7,
+                sig: vec![0x55, 0x55, 0x55, 0x55],
+                x5c: None,
+                ecdaa_key_id: None,
+            }),
+        };
+        assert_eq!(
+            make_credential_response,
+            Ok(expected_make_credential_response)
+        );
+        assert_eq!(
+            AuthenticatorMakeCredentialResponse::try_from(cbor_map! { 1 => "packed" }),
+            Err(Ctap2StatusCode::CTAP2_ERR_MISSING_PARAMETER)
+        );
+    }
+
+    #[test]
+    fn test_make_credential_other_formats() {
+        let make_credential_response = AuthenticatorMakeCredentialResponse {
+            auth_data: vec![0xAD],
+            att_stmt: AttestationStatement::None,
+        };
+        let response_cbor: cbor::Value = make_credential_response.into();
+        assert_eq!(
+            response_cbor,
+            cbor_map! {
+                1 => "none",
+                2 => vec![0xAD],
+                3 => cbor_map! {},
+            }
+        );
+        assert_eq!(
+            AuthenticatorMakeCredentialResponse::try_from(response_cbor),
+            Ok(AuthenticatorMakeCredentialResponse {
+                auth_data: vec![0xAD],
+                att_stmt: AttestationStatement::None,
+            })
+        );
+
+        let make_credential_response = AuthenticatorMakeCredentialResponse {
+            auth_data: vec![0xAD],
+            att_stmt: AttestationStatement::FidoU2f(FidoU2fAttestationStatement {
+                sig: vec![0x55, 0x55, 0x55, 0x55],
+                attestation_certificate: vec![0x5C, 0x5C, 0x5C, 0x5C],
+            }),
+        };
+        let response_cbor: cbor::Value = make_credential_response.into();
+        assert_eq!(
+            response_cbor,
+            cbor_map! {
+                1 => "fido-u2f",
+                2 => vec![0xAD],
+                3 => cbor_map! {
+                    "sig" => vec![0x55, 0x55, 0x55, 0x55],
+                    "x5c" => cbor_array![vec![0x5C, 0x5C, 0x5C, 0x5C]],
+                },
+            }
+        );
+        assert!(AuthenticatorMakeCredentialResponse::try_from(response_cbor).is_ok());
+
+        // The statement has to match its format.
+        let cbor_value = cbor_map! {
+            1 => "fido-u2f",
+            2 => vec![0xAD],
+            3 => cbor_map! {
+                "alg" => -7,
+                "sig" => vec![0x55, 0x55, 0x55, 0x55],
+            },
+        };
+        assert_eq!(
+            AuthenticatorMakeCredentialResponse::try_from(cbor_value),
+            Err(Ctap2StatusCode::CTAP2_ERR_MISSING_PARAMETER)
+        );
+        let cbor_value = cbor_map! {
+            1 => "tpm",
+            2 => vec![0xAD],
+            3 => cbor_map! {},
+        };
+        assert_eq!(
+            AuthenticatorMakeCredentialResponse::try_from(cbor_value),
+            Err(Ctap2StatusCode::CTAP2_ERR_INVALID_CBOR)
+        );
+    }
+
     #[test]
     fn test_get_assertion_into_cbor() {
         let get_assertion_response = AuthenticatorGetAssertionResponse {
@@ -273,6 +582,40 @@ mod test {
         assert_eq!(response_cbor, Some(expected_cbor));
     }
 
+    #[test]
+    fn test_get_assertion_from_cbor() {
+        let cbor_value = cbor_map! {
+            1 => cbor_map! {
+                "type" => "public-key",
+                "id" => vec![0x2D, 0x2D],
+            },
+            2 => vec![0xAD],
+            3 => vec![0x51],
+            4 => cbor_map! {
+                "id" => vec![0x1D],
+            },
+            5 => 2,
+        };
+        let get_assertion_response = AuthenticatorGetAssertionResponse::try_from(cbor_value);
+        let expected_get_assertion_response = AuthenticatorGetAssertionResponse {
+            credential: Some(PublicKeyCredentialDescriptor {
+                key_type: PublicKeyCredentialType::PublicKey,
+                key_id: vec![0x2D, 0x2D],
+                transports: None,
+            }),
+            auth_data: vec![0xAD],
+            signature: vec![0x51],
+            user: Some(PublicKeyCredentialUserEntity {
+                user_id: vec![0x1D],
+                user_name: None,
+                user_display_name: None,
+                user_icon: None,
+            }),
+            number_of_credentials: Some(2),
+        };
+        assert_eq!(get_assertion_response, Ok(expected_get_assertion_response));
+    }
+
     #[test]
     fn test_get_info_into_cbor() {
         let get_info_response = AuthenticatorGetInfoResponse {
@@ -338,6 +681,80 @@ mod test {
         assert_eq!(response_cbor, Some(expected_cbor));
     }
 
+    #[test]
+    fn test_get_info_from_cbor() {
+        let cbor_value = cbor_map! {
+            0x01 => cbor_array_vec![vec!["FIDO_2_0"]],
+            0x03 => vec![0x00; 16],
+            0x04 => cbor_map! {"rk" => true, "clientPin" => false},
+            0x06 => cbor_array_vec![vec![1]],
+            // Unknown fields are ignored.
+            0x10 => 0,
+        };
+        let get_info_response = AuthenticatorGetInfoResponse::try_from(cbor_value);
+        let mut options_map = BTreeMap::new();
+        options_map.insert(String::from("rk"), true);
+        options_map.insert(String::from("clientPin"), false);
+        let expected_get_info_response = AuthenticatorGetInfoResponse {
+            versions: vec!["FIDO_2_0".to_string()],
+            extensions: None,
+            aaguid: [0x00; 16],
+            options: Some(options_map),
+            max_msg_size: None,
+            pin_protocols: Some(vec![1]),
+            #[cfg(feature = "with_ctap2_1")]
+            max_credential_count_in_list: None,
+            #[cfg(feature = "with_ctap2_1")]
+            max_credential_id_length: None,
+            #[cfg(feature = "with_ctap2_1")]
+            transports: None,
+            #[cfg(feature = "with_ctap2_1")]
+            algorithms: None,
+            #[cfg(feature = "with_ctap2_1")]
+            firmware_version: None,
+        };
+        assert_eq!(get_info_response, Ok(expected_get_info_response));
+
+        let cbor_value = cbor_map! {
+            0x01 => cbor_array_vec![vec!["FIDO_2_0"]],
+            0x03 => vec![0x00; 15],
+        };
+        assert_eq!(
+            AuthenticatorGetInfoResponse::try_from(cbor_value),
+            Err(Ctap2StatusCode::CTAP2_ERR_INVALID_CBOR)
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "with_ctap2_1")]
+    fn test_get_info_optionals_from_cbor() {
+        let cbor_value = cbor_map! {
+            0x01 => cbor_array_vec![vec!["FIDO_2_0"]],
+            0x02 => cbor_array_vec![vec!["extension"]],
+            0x03 => vec![0x00; 16],
+            0x05 => 1024,
+            0x07 => 20,
+            0x08 => 256,
+            0x09 => cbor_array_vec![vec!["usb"]],
+            0x0A => cbor_array_vec![vec![ES256_CRED_PARAM]],
+            0x0E => 0,
+        };
+        let get_info_response = AuthenticatorGetInfoResponse::try_from(cbor_value).unwrap();
+        assert_eq!(
+            get_info_response.extensions,
+            Some(vec!["extension".to_string()])
+        );
+        assert_eq!(get_info_response.max_msg_size, Some(1024));
+        assert_eq!(get_info_response.max_credential_count_in_list, Some(20));
+        assert_eq!(get_info_response.max_credential_id_length, Some(256));
+        assert_eq!(
+            get_info_response.transports,
+            Some(vec![AuthenticatorTransport::Usb])
+        );
+        assert_eq!(get_info_response.algorithms, Some(vec![ES256_CRED_PARAM]));
+        assert_eq!(get_info_response.firmware_version, Some(0));
+    }
+
     #[test]
     fn test_used_client_pin_into_cbor() {
         let client_pin_response = AuthenticatorClientPinResponse {
@@ -353,6 +770,21 @@ mod test {
         assert_eq!(response_cbor, Some(expected_cbor));
     }
 
+    #[test]
+    fn test_client_pin_from_cbor() {
+        let cbor_value = cbor_map! {
+            1 => cbor_map! {},
+            3 => 8,
+        };
+        let client_pin_response = AuthenticatorClientPinResponse::try_from(cbor_value);
+        let expected_client_pin_response = AuthenticatorClientPinResponse {
+            key_agreement: Some(CoseKey(BTreeMap::new())),
+            pin_token: None,
+            retries: Some(8),
+        };
+        assert_eq!(client_pin_response, Ok(expected_client_pin_response));
+    }
+
     #[test]
     fn test_empty_client_pin_into_cbor() {
         let response_cbor: Option<cbor::Value> = ResponseData::AuthenticatorClientPin(None).into();
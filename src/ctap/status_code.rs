@@ -12,11 +12,13 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use core::convert::TryFrom;
+
 // CTAP specification (version 20190130) section 6.3
 // For now, only the CTAP2 codes are here, the CTAP1 are not included.
 #[allow(non_camel_case_types)]
 #[allow(dead_code)]
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Ctap2StatusCode {
     CTAP2_OK = 0x00,
     CTAP1_ERR_INVALID_COMMAND = 0x01,
@@ -60,6 +62,8 @@ pub enum Ctap2StatusCode {
     CTAP2_ERR_ACTION_TIMEOUT = 0x3A,
     CTAP2_ERR_UP_REQUIRED = 0x3B,
     CTAP2_ERR_UV_BLOCKED = 0x3C,
+    // CTAP specification (version 20191217) section 6.3
+    CTAP2_ERR_INVALID_SUBCOMMAND = 0x3E,
     CTAP1_ERR_OTHER = 0x7F,
     CTAP2_ERR_SPEC_LAST = 0xDF,
     CTAP2_ERR_EXTENSION_FIRST = 0xE0,
@@ -67,5 +71,68 @@ pub enum Ctap2StatusCode {
     // CTAP2_ERR_VENDOR_FIRST = 0xF0,
     CTAP2_ERR_VENDOR_RESPONSE_TOO_LONG = 0xF0,
     CTAP2_ERR_VENDOR_RESPONSE_CANNOT_WRITE_CBOR = 0xF1,
+    // The persistent store can't be used, see `PersistentStore`.
+    CTAP2_ERR_VENDOR_INTERNAL_ERROR = 0xF2,
     CTAP2_ERR_VENDOR_LAST = 0xFF,
 }
+
+impl TryFrom<u8> for Ctap2StatusCode {
+    type Error = ();
+
+    fn try_from(value: u8) -> Result<Ctap2StatusCode, ()> {
+        match value {
+            0x00 => Ok(Ctap2StatusCode::CTAP2_OK),
+            0x01 => Ok(Ctap2StatusCode::CTAP1_ERR_INVALID_COMMAND),
+            0x02 => Ok(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER),
+            0x03 => Ok(Ctap2StatusCode::CTAP1_ERR_INVALID_LENGTH),
+            0x04 => Ok(Ctap2StatusCode::CTAP1_ERR_INVALID_SEQ),
+            0x05 => Ok(Ctap2StatusCode::CTAP1_ERR_TIMEOUT),
+            0x06 => Ok(Ctap2StatusCode::CTAP1_ERR_CHANNEL_BUSY),
+            0x0A => Ok(Ctap2StatusCode::CTAP1_ERR_LOCK_REQUIRED),
+            0x0B => Ok(Ctap2StatusCode::CTAP1_ERR_INVALID_CHANNEL),
+            0x11 => Ok(Ctap2StatusCode::CTAP2_ERR_CBOR_UNEXPECTED_TYPE),
+            0x12 => Ok(Ctap2StatusCode::CTAP2_ERR_INVALID_CBOR),
+            0x14 => Ok(Ctap2StatusCode::CTAP2_ERR_MISSING_PARAMETER),
+            0x15 => Ok(Ctap2StatusCode::CTAP2_ERR_LIMIT_EXCEEDED),
+            0x16 => Ok(Ctap2StatusCode::CTAP2_ERR_UNSUPPORTED_EXTENSION),
+            0x19 => Ok(Ctap2StatusCode::CTAP2_ERR_CREDENTIAL_EXCLUDED),
+            0x21 => Ok(Ctap2StatusCode::CTAP2_ERR_PROCESSING),
+            0x22 => Ok(Ctap2StatusCode::CTAP2_ERR_INVALID_CREDENTIAL),
+            0x23 => Ok(Ctap2StatusCode::CTAP2_ERR_USER_ACTION_PENDING),
+            0x24 => Ok(Ctap2StatusCode::CTAP2_ERR_OPERATION_PENDING),
+            0x25 => Ok(Ctap2StatusCode::CTAP2_ERR_NO_OPERATIONS),
+            0x26 => Ok(Ctap2StatusCode::CTAP2_ERR_UNSUPPORTED_ALGORITHM),
+            0x27 => Ok(Ctap2StatusCode::CTAP2_ERR_OPERATION_DENIED),
+            0x28 => Ok(Ctap2StatusCode::CTAP2_ERR_KEY_STORE_FULL),
+            0x2A => Ok(Ctap2StatusCode::CTAP2_ERR_NO_OPERATION_PENDING),
+            0x2B => Ok(Ctap2StatusCode::CTAP2_ERR_UNSUPPORTED_OPTION),
+            0x2C => Ok(Ctap2StatusCode::CTAP2_ERR_INVALID_OPTION),
+            0x2D => Ok(Ctap2StatusCode::CTAP2_ERR_KEEPALIVE_CANCEL),
+            0x2E => Ok(Ctap2StatusCode::CTAP2_ERR_NO_CREDENTIALS),
+            0x2F => Ok(Ctap2StatusCode::CTAP2_ERR_USER_ACTION_TIMEOUT),
+            0x30 => Ok(Ctap2StatusCode::CTAP2_ERR_NOT_ALLOWED),
+            0x31 => Ok(Ctap2StatusCode::CTAP2_ERR_PIN_INVALID),
+            0x32 => Ok(Ctap2StatusCode::CTAP2_ERR_PIN_BLOCKED),
+            0x33 => Ok(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_INVALID),
+            0x34 => Ok(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_BLOCKED),
+            0x35 => Ok(Ctap2StatusCode::CTAP2_ERR_PIN_NOT_SET),
+            0x36 => Ok(Ctap2StatusCode::CTAP2_ERR_PIN_REQUIRED),
+            0x37 => Ok(Ctap2StatusCode::CTAP2_ERR_PIN_POLICY_VIOLATION),
+            0x38 => Ok(Ctap2StatusCode::CTAP2_ERR_PIN_TOKEN_EXPIRED),
+            0x39 => Ok(Ctap2StatusCode::CTAP2_ERR_REQUEST_TOO_LARGE),
+            0x3A => Ok(Ctap2StatusCode::CTAP2_ERR_ACTION_TIMEOUT),
+            0x3B => Ok(Ctap2StatusCode::CTAP2_ERR_UP_REQUIRED),
+            0x3C => Ok(Ctap2StatusCode::CTAP2_ERR_UV_BLOCKED),
+            0x3E => Ok(Ctap2StatusCode::CTAP2_ERR_INVALID_SUBCOMMAND),
+            0x7F => Ok(Ctap2StatusCode::CTAP1_ERR_OTHER),
+            0xDF => Ok(Ctap2StatusCode::CTAP2_ERR_SPEC_LAST),
+            0xE0 => Ok(Ctap2StatusCode::CTAP2_ERR_EXTENSION_FIRST),
+            0xEF => Ok(Ctap2StatusCode::CTAP2_ERR_EXTENSION_LAST),
+            0xF0 => Ok(Ctap2StatusCode::CTAP2_ERR_VENDOR_RESPONSE_TOO_LONG),
+            0xF1 => Ok(Ctap2StatusCode::CTAP2_ERR_VENDOR_RESPONSE_CANNOT_WRITE_CBOR),
+            0xF2 => Ok(Ctap2StatusCode::CTAP2_ERR_VENDOR_INTERNAL_ERROR),
+            0xFF => Ok(Ctap2StatusCode::CTAP2_ERR_VENDOR_LAST),
+            _ => Err(()),
+        }
+    }
+}
@@ -12,38 +12,57 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::crypto::aes256::EncryptionKey;
+use crate::crypto::ctr::ctr_crypt;
+use crate::crypto::hmac::{hmac_256, verify_hmac_256_first_128bits};
 use crate::crypto::rng256::Rng256;
-use crate::ctap::data_formats::PublicKeyCredentialSource;
+use crate::crypto::sha256::Sha256;
+use crate::crypto::Hash256;
+use crate::ctap::data_formats::{
+    map_credential_secrets, CredentialIdentity, PublicKeyCredentialSource,
+};
 use crate::ctap::status_code::Ctap2StatusCode;
 use crate::ctap::PIN_AUTH_LENGTH;
-use alloc::string::String;
 use alloc::vec::Vec;
+use core::cell::{Ref, RefCell};
 use core::convert::TryInto;
-use ctap2::embedded_flash::{self, StoreConfig, StoreEntry, StoreError, StoreIndex};
-
-#[cfg(any(test, feature = "ram_storage"))]
-type Storage = embedded_flash::BufferStorage;
-#[cfg(not(any(test, feature = "ram_storage")))]
-type Storage = embedded_flash::SyscallStorage;
+use ctap2::embedded_flash::{self, StoreConfig, StoreEntry, StoreError, StoreIndex, StoreUpdate};
 
 // Those constants may be modified before compilation to tune the behavior of the key.
 //
 // The number of pages should be at least 2 and at most what the flash can hold. There should be no
 // reason to put a small number here, except that the latency of flash operations depends on the
 // number of pages. This will improve in the future. Currently, using 20 pages gives 65ms per
-// operation. The rule of thumb is 3.5ms per additional page.
+// operation. The rule of thumb is 3.5ms per additional page. Pages are compacted while the key is
+// idle when possible (see `compact_step`), so that commands rarely wait for a page erase.
 //
-// Limiting the number of residential keys permits to ensure a minimum number of counter increments.
-// Let:
+// Limiting the number of residential keys permits to ensure that they fit in the store. Let:
 // - P the number of pages (NUM_PAGES)
 // - K the maximum number of residential keys (MAX_SUPPORTED_RESIDENTIAL_KEYS)
-// - S the maximum size of a residential key (about 500)
-// - C the number of erase cycles (10000)
-// - I the minimum number of counter increments
+// - S the maximum size of a residential key (MAX_CREDENTIAL_SIZE)
+//
+// We need: K * S < (P - 1) * 4092
+//
+// With P=20 and K=150, residential keys of 500 bytes take less than 75K out of 77K. Keys with the
+// longest RP ID and user fields take up to 82K, so the store may be full before K of them, in which
+// case make_credential fails with CTAP2_ERR_KEY_STORE_FULL.
 //
-// We have: I = ((P - 1) * 4092 - K * S) / 12 * C
+// The counters don't wear the pages of the store, they have their own. A 4K page holds 2045
+// increments (2 per word), and each of the 2 pages of a counter can be erased 10000 times. So a
+// counter can be incremented about 40M times, which is enough for 10000 increments per day for 10
+// years.
 //
-// With P=20 and K=150, we have I > 2M which is enough for 500 increments per day for 10 years.
+// The signature counters of credentials and relying parties are entries of the store, of about 28
+// bytes each. They take up to 5K with K=150, so they may not all fit next to the residential keys.
+// Counters that don't fit fall back to the global signature counter, and counters are deleted when
+// a residential key needs their space. Each assertion replaces a counter,
+// and the store wears out after about 25M assertions, see `test_signature_counter_wear`.
+//
+// Master keys, PIN hashes, credentials and attestation keys are sensitive entries. When flash
+// words can be written again, the store overwrites them with zeroes as they are deleted or
+// replaced. With a single write per word, their old value stays on flash until its page is
+// compacted, which `compact_step` does at the next idle step. Sealing them with the device secret
+// still keeps that value from being read without the secret.
 #[cfg(feature = "ram_storage")]
 const NUM_PAGES: usize = 2;
 #[cfg(not(feature = "ram_storage"))]
@@ -51,33 +70,229 @@ const NUM_PAGES: usize = 20;
 const MAX_SUPPORTED_RESIDENTIAL_KEYS: usize = 150;
 
 // List of tags. They should all be unique. And there should be less than NUM_TAGS.
+//
+// The number of bits of a tag in the flash format depends on NUM_TAGS. Going over 8 tags changes
+// the format, and flash written by previous versions can't be read anymore.
 const TAG_CREDENTIAL: usize = 0;
+// Only in stores of schema version 2 and below, the global signature counter is now a counter.
 const GLOBAL_SIGNATURE_COUNTER: usize = 1;
 const MASTER_KEYS: usize = 2;
 const PIN_HASH: usize = 3;
 const PIN_RETRIES: usize = 4;
-const NUM_TAGS: usize = 5;
+const ATTESTATION_PRIVATE_KEY: usize = 5;
+const ATTESTATION_CERTIFICATE: usize = 6;
+// All other tags are used, so small entries share this tag. Their first byte is their kind, see
+// below.
+const DEVICE_STATE: usize = 7;
+const NUM_TAGS: usize = 8;
+
+// List of kinds of DEVICE_STATE entries. They should all be unique.
+//
+// The version of the schema of the persistent store, see SCHEMA_VERSION.
+const SCHEMA_VERSION_STATE: u8 = 0;
+// The AAGUID of the provisioned attestation, and whether it is locked.
+const ATTESTATION_STATE: u8 = 1;
+// The signature counter of a credential or relying party, see `CounterOwner`.
+const SIGNATURE_COUNTER_STATE: u8 = 2;
+
+// List of counters. They should all be unique. And there should be less than NUM_COUNTERS.
+//
+// Each counter takes 2 pages after the pages of the store.
+const SIGNATURE_COUNTER: usize = 0;
+// The number of failed PIN attempts, see `PersistentStore::pin_retries`.
+const PIN_FAILURES: usize = 1;
+const NUM_COUNTERS: usize = 2;
+
+// The version of the schema of the persistent store, i.e. the meaning of the tags and the format of
+// their entries (including the CBOR layout of credentials). It should be incremented, and a
+// migration added to `PersistentStore::migrate_from`, whenever an entry changes in a way that
+// previous versions can't read. Version 0 is the schema before the version was stored.
+const SCHEMA_VERSION: u32 = 4;
+
+// Sealing a sensitive value adds its IV and its tag, see `StorageKey`.
+const SEAL_IV_LENGTH: usize = 16;
+const SEAL_TAG_LENGTH: usize = 16;
+const SEAL_OVERHEAD: usize = SEAL_IV_LENGTH + SEAL_TAG_LENGTH;
+
+// Whether store entries are protected by a checksum, to detect bits flipped by flash retention
+// errors. This costs 2 bytes per entry. It is enabled by the default "store_checksum" feature. The
+// store records in its page headers whether entries have a checksum, so this only applies to a
+// fresh store: devices flashed before checksums keep their entries, without checksums.
+const STORE_CHECKSUM: bool = cfg!(feature = "store_checksum");
 
 const MAX_PIN_RETRIES: u8 = 6;
+// Relying parties with non-resident credentials beyond this number use the global signature
+// counter.
+const MAX_RELYING_PARTY_COUNTERS: usize = 32;
+// A signature counter entry is its kind, its owner, and its value in big-endian.
+const COUNTER_OWNER_LENGTH: usize = 17;
+const COUNTER_ENTRY_LENGTH: usize = 1 + COUNTER_OWNER_LENGTH + 4;
+// The attestation certificate chain holds the batch certificate and its intermediates.
+pub const MAX_ATTESTATION_CERTIFICATES: usize = 4;
+// Each certificate is one entry, which is at most a page long.
+pub const MAX_ATTESTATION_CERTIFICATE_LENGTH: usize = 2048;
+const AAGUID_LENGTH: usize = 16;
+// The size of the largest sealed residential key, for which idle compaction keeps room. Its RP ID
+// is 253 bytes, the longest domain name, and its user handle 64 bytes, the longest in WebAuthn.
+// Display names are truncated to 64 bytes by make_credential. Larger keys are still stored, but
+// their command may wait for a compaction, see `test_store_credential_size`.
+const MAX_CREDENTIAL_SIZE: usize = 560;
 
 #[derive(PartialEq, Eq, PartialOrd, Ord)]
 enum Key {
-    // TODO(cretin): Test whether this doesn't consume too much memory. Otherwise, we can use less
-    // keys. Either only a simple enum value for all credentials, or group by rp_id.
-    Credential {
-        rp_id: Option<String>,
-        credential_id: Option<Vec<u8>>,
-        user_handle: Option<Vec<u8>>,
-    },
-    GlobalSignatureCounter,
+    // Credentials are looked up through the credential index.
+    Credential,
     MasterKeys,
     PinHash,
     PinRetries,
+    SchemaVersion,
+    SignatureCounter([u8; COUNTER_OWNER_LENGTH]),
+    AttestationSecret,
+    AttestationCertificate,
+    AttestationState,
+}
+
+// Whose signature counter is used by an assertion.
+#[derive(Clone, Copy)]
+pub enum CounterOwner<'a> {
+    // A resident credential, by its credential ID.
+    Credential(&'a [u8]),
+    // The non-resident credentials of a relying party, by its RP ID hash.
+    RelyingParty(&'a [u8; 32]),
+}
+
+impl<'a> CounterOwner<'a> {
+    // The kinds of owners in the store.
+    const CREDENTIAL: u8 = 0;
+    const RELYING_PARTY: u8 = 1;
+
+    // Identifies the owner in the store, by its kind and a hash truncated to 16 bytes.
+    fn key(self) -> [u8; COUNTER_OWNER_LENGTH] {
+        let (kind, hash) = match self {
+            CounterOwner::Credential(credential_id) => {
+                (CounterOwner::CREDENTIAL, Sha256::hash(credential_id))
+            }
+            CounterOwner::RelyingParty(rp_id_hash) => (CounterOwner::RELYING_PARTY, *rp_id_hash),
+        };
+        let mut key = [0; COUNTER_OWNER_LENGTH];
+        key[0] = kind;
+        key[1..].copy_from_slice(&hash[..COUNTER_OWNER_LENGTH - 1]);
+        key
+    }
 }
 
-pub struct MasterKeys<'a> {
-    pub encryption: &'a [u8; 32],
-    pub hmac: &'a [u8; 32],
+pub struct MasterKeys {
+    pub encryption: [u8; 32],
+    pub hmac: [u8; 32],
+}
+
+// The keys sealing the sensitive values of the persistent store at rest, derived from the device
+// secret. The sealed values are the master keys, the PIN hash, the attestation private key, and the
+// private key and cred_random of credentials. The other fields of credentials stay readable, so
+// that lookups don't decrypt anything.
+//
+// Sealing is the AES-CTR-HMAC authenticated encryption with associated data (as in Tink): the value
+// is encrypted with AES-256-CTR under a random IV, and the tag is the HMAC-SHA256 of the associated
+// data, the IV, the ciphertext, and the length in bits of the associated data, truncated to 16
+// bytes. The associated data binds the value to its place in the store, so sealed values can't be
+// swapped: it is the tag of the entry, and for credentials also the field, the credential ID and
+// the relying party.
+struct StorageKey {
+    encryption: EncryptionKey,
+    hmac: [u8; 32],
+}
+
+impl StorageKey {
+    fn new(device_secret: &[u8; 32]) -> StorageKey {
+        let encryption = hmac_256::<Sha256>(device_secret, b"OpenSK storage encryption");
+        StorageKey {
+            encryption: EncryptionKey::new(&encryption),
+            hmac: hmac_256::<Sha256>(device_secret, b"OpenSK storage authentication"),
+        }
+    }
+
+    // Returns the IV, followed by the ciphertext and the tag.
+    fn seal(&self, rng: &mut impl Rng256, associated_data: &[u8], value: &[u8]) -> Vec<u8> {
+        let random = rng.gen_uniform_u8x32();
+        let iv = *array_ref!(random, 0, SEAL_IV_LENGTH);
+        let mut sealed = Vec::with_capacity(SEAL_OVERHEAD + value.len());
+        sealed.extend_from_slice(&iv);
+        sealed.extend_from_slice(value);
+        ctr_crypt(&self.encryption, iv, &mut sealed[SEAL_IV_LENGTH..]);
+        let tag = hmac_256::<Sha256>(&self.hmac, &authenticated_data(associated_data, &sealed));
+        sealed.extend_from_slice(&tag[..SEAL_TAG_LENGTH]);
+        sealed
+    }
+
+    // Returns None if the sealed value was modified, or sealed with other associated data or under
+    // another device secret.
+    fn open(&self, associated_data: &[u8], sealed: &[u8]) -> Option<Vec<u8>> {
+        if sealed.len() < SEAL_OVERHEAD {
+            return None;
+        }
+        let (payload, tag) = sealed.split_at(sealed.len() - SEAL_TAG_LENGTH);
+        if !verify_hmac_256_first_128bits::<Sha256>(
+            &self.hmac,
+            &authenticated_data(associated_data, payload),
+            array_ref!(tag, 0, SEAL_TAG_LENGTH),
+        ) {
+            return None;
+        }
+        let iv = *array_ref!(payload, 0, SEAL_IV_LENGTH);
+        let mut value = payload[SEAL_IV_LENGTH..].to_vec();
+        ctr_crypt(&self.encryption, iv, &mut value);
+        Some(value)
+    }
+
+    fn seal_credential(
+        &self,
+        rng: &mut impl Rng256,
+        credential: PublicKeyCredentialSource,
+    ) -> Option<Vec<u8>> {
+        let credential_id = credential.credential_id.clone();
+        let rp_id = credential.rp_id.clone();
+        let cbor = map_credential_secrets(credential.into(), |field, value| {
+            let associated_data = credential_associated_data(field, &credential_id, &rp_id);
+            Some(self.seal(rng, &associated_data, &value))
+        })
+        .ok()?;
+        let mut data = Vec::new();
+        if cbor::write(cbor, &mut data) {
+            Some(data)
+        } else {
+            None
+        }
+    }
+
+    fn open_credential(&self, data: &[u8]) -> Option<PublicKeyCredentialSource> {
+        let cbor = cbor::read(data).ok()?;
+        let identity: CredentialIdentity = cbor.clone().try_into().ok()?;
+        let cbor = map_credential_secrets(cbor, |field, sealed| {
+            let associated_data =
+                credential_associated_data(field, &identity.credential_id, &identity.rp_id);
+            self.open(&associated_data, &sealed)
+        })
+        .ok()?;
+        cbor.try_into().ok()
+    }
+}
+
+// The data authenticated by the tag of a sealed value.
+fn authenticated_data(associated_data: &[u8], payload: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(associated_data.len() + payload.len() + 8);
+    data.extend_from_slice(associated_data);
+    data.extend_from_slice(payload);
+    data.extend_from_slice(&(associated_data.len() as u64 * 8).to_be_bytes());
+    data
+}
+
+// The associated data of a secret field of a credential. The credential ID is hashed, so that the
+// relying party ID can follow it unambiguously.
+fn credential_associated_data(field: u64, credential_id: &[u8], rp_id: &str) -> Vec<u8> {
+    let mut data = vec![TAG_CREDENTIAL as u8, field as u8];
+    data.extend_from_slice(&Sha256::hash(credential_id));
+    data.extend_from_slice(rp_id.as_bytes());
+    data
 }
 
 struct Config;
@@ -89,48 +304,98 @@ impl StoreConfig for Config {
         NUM_TAGS
     }
 
+    // Malformed entries have no key, so they are never found, except credentials which are checked
+    // by the credential index. This happens when the flash is
+    // corrupted and checksums are disabled. Those entries are reported by the health check.
     fn keys(&self, entry: StoreEntry, mut add: impl FnMut(Key)) {
+        let length = entry.data.len();
         match entry.tag {
-            TAG_CREDENTIAL => {
-                let credential = match deserialize_credential(entry.data) {
-                    None => {
-                        debug_assert!(false);
-                        return;
-                    }
-                    Some(credential) => credential,
-                };
-                add(Key::Credential {
-                    rp_id: Some(credential.rp_id.clone()),
-                    credential_id: Some(credential.credential_id),
-                    user_handle: None,
-                });
-                add(Key::Credential {
-                    rp_id: Some(credential.rp_id.clone()),
-                    credential_id: None,
-                    user_handle: None,
-                });
-                add(Key::Credential {
-                    rp_id: Some(credential.rp_id),
-                    credential_id: None,
-                    user_handle: Some(credential.user_handle),
-                });
-                add(Key::Credential {
-                    rp_id: None,
-                    credential_id: None,
-                    user_handle: None,
-                });
-            }
-            GLOBAL_SIGNATURE_COUNTER => add(Key::GlobalSignatureCounter),
-            MASTER_KEYS => add(Key::MasterKeys),
-            PIN_HASH => add(Key::PinHash),
-            PIN_RETRIES => add(Key::PinRetries),
-            _ => debug_assert!(false),
+            // Parsing credentials is left to the credential index, which does it once.
+            TAG_CREDENTIAL => add(Key::Credential),
+            MASTER_KEYS if length == SEAL_OVERHEAD + 64 => add(Key::MasterKeys),
+            PIN_HASH if length == SEAL_OVERHEAD + PIN_AUTH_LENGTH => add(Key::PinHash),
+            PIN_RETRIES if length == 4 => add(Key::PinRetries),
+            ATTESTATION_PRIVATE_KEY if length == SEAL_OVERHEAD + 32 => add(Key::AttestationSecret),
+            ATTESTATION_CERTIFICATE if length > 1 => add(Key::AttestationCertificate),
+            DEVICE_STATE if length > 0 => match (entry.data[0], length - 1) {
+                (SCHEMA_VERSION_STATE, 4) => add(Key::SchemaVersion),
+                (ATTESTATION_STATE, length) if length == AAGUID_LENGTH + 1 => {
+                    add(Key::AttestationState)
+                }
+                (SIGNATURE_COUNTER_STATE, length) if length == COUNTER_ENTRY_LENGTH - 1 => add(
+                    Key::SignatureCounter(*array_ref!(entry.data, 1, COUNTER_OWNER_LENGTH)),
+                ),
+                _ => (),
+            },
+            _ => (),
         }
     }
+
+    fn checksum(&self) -> bool {
+        STORE_CHECKSUM
+    }
+
+    fn num_counters(&self) -> usize {
+        NUM_COUNTERS
+    }
 }
 
-pub struct PersistentStore {
-    store: embedded_flash::Store<Storage, Config>,
+// Counts the entries of the persistent store that can't be used.
+#[cfg(any(test, feature = "debug_ctap"))]
+#[derive(Debug, PartialEq)]
+pub struct StorageHealth {
+    // Entries quarantined by the store, because they failed their integrity checks.
+    pub corrupt_entries: usize,
+    // Entries that passed the integrity checks but can't be parsed.
+    pub malformed_entries: usize,
+    // Entries created at boot that are missing or can't be opened, so that the commands using
+    // them fail until a reset.
+    pub missing_entries: usize,
+}
+
+#[cfg(any(test, feature = "debug_ctap"))]
+impl StorageHealth {
+    pub fn is_healthy(&self) -> bool {
+        self.corrupt_entries == 0 && self.malformed_entries == 0 && self.missing_entries == 0
+    }
+}
+
+// The position of a credential in the store, with hashes of the fields it is looked up by.
+struct IndexedCredential {
+    rp_id: u32,
+    user_handle: u32,
+    credential_id: u32,
+    position: StoreIndex,
+}
+
+impl IndexedCredential {
+    fn new(identity: &CredentialIdentity, position: StoreIndex) -> IndexedCredential {
+        IndexedCredential {
+            rp_id: index_hash(identity.rp_id.as_bytes()),
+            user_handle: index_hash(&identity.user_handle),
+            credential_id: index_hash(&identity.credential_id),
+            position,
+        }
+    }
+}
+
+// A compact index of the credentials, so that lookups only deserialize the credentials they
+// return. It is built at boot, updated when credentials are written, and rebuilt when a compaction
+// moved entries.
+// See `bench_credential_index` for the time of the lookups compared to deserializing all
+// credentials, and for the size of the index.
+struct CredentialIndex {
+    // The store generation at which the positions are valid.
+    generation: usize,
+    credentials: Vec<IndexedCredential>,
+}
+
+pub struct PersistentStore<S: embedded_flash::Storage> {
+    store: embedded_flash::Store<S, Config>,
+    key: StorageKey,
+    credentials: RefCell<CredentialIndex>,
+    // Whether the store was written by a more recent firmware, see `migrate`.
+    newer_schema: bool,
 }
 
 #[cfg(feature = "ram_storage")]
@@ -138,62 +403,140 @@ const PAGE_SIZE: usize = 0x100;
 #[cfg(not(feature = "ram_storage"))]
 const PAGE_SIZE: usize = 0x1000;
 
-const STORE_SIZE: usize = NUM_PAGES * PAGE_SIZE;
+// The storage of the persistent store holds the pages of the store followed by those of the
+// counters.
+const STORAGE_PAGES: usize = NUM_PAGES + NUM_COUNTERS * embedded_flash::COUNTER_PAGES;
+const STORAGE_SIZE: usize = STORAGE_PAGES * PAGE_SIZE;
 
-#[cfg(not(any(test, feature = "ram_storage")))]
+// The flash reserved for the persistent store, at the start of the app state region. The storage
+// of the persistent store comes first, so that its address doesn't change, and the device secret
+// takes the last page. So the device secret doesn't move when pages are added to the store or
+// counters, as long as they fit in the region, which is checked at compile time.
+#[cfg(not(any(test, feature = "desktop", feature = "ram_storage")))]
+const APP_STATE_SIZE: usize = 32 * PAGE_SIZE;
+#[cfg(not(any(test, feature = "desktop", feature = "ram_storage")))]
+const DEVICE_SECRET_OFFSET: usize = APP_STATE_SIZE - PAGE_SIZE;
+#[cfg(not(any(test, feature = "desktop", feature = "ram_storage")))]
+const _: [(); 0 - !(STORAGE_SIZE <= DEVICE_SECRET_OFFSET) as usize] = [];
+
+#[cfg(not(any(test, feature = "desktop", feature = "ram_storage")))]
 #[link_section = ".app_state"]
-static STORE: [u8; STORE_SIZE] = [0xff; STORE_SIZE];
+static STORE: [u8; APP_STATE_SIZE] = [0xff; APP_STATE_SIZE];
+
+// Returns the flash region of the persistent store.
+//
+// This should be called at most once per program lifetime, because the storage would alias.
+#[cfg(not(any(test, feature = "desktop", feature = "ram_storage")))]
+pub fn new_flash_storage() -> embedded_flash::SyscallStorage {
+    let store = unsafe {
+        // Safety: The store cannot alias because this function is called only once.
+        core::slice::from_raw_parts_mut(STORE.as_ptr() as *mut u8, STORAGE_SIZE)
+    };
+    unsafe {
+        // Safety: The store is in a writeable flash region.
+        embedded_flash::SyscallStorage::new(store).unwrap()
+    }
+}
+
+// Returns the device secret, which is generated on first boot.
+//
+// The secret lives in the last page of the app state region, at a fixed offset. So it is never
+// erased, including by a reset, and it isn't part of the store. This doesn't protect against a
+// dump of the whole flash, which needs a key bound to the hardware. If the page is erased anyway, a
+// new secret is generated and the sealed values of the store can't be opened anymore, which the
+// persistent store reports as errors until a reset.
+//
+// This should be called at most once per program lifetime, because the storage would alias.
+#[cfg(not(any(test, feature = "desktop", feature = "ram_storage")))]
+pub fn flash_device_secret(rng: &mut impl Rng256) -> [u8; 32] {
+    use embedded_flash::Storage;
+    let page = unsafe {
+        // Safety: The page cannot alias because this function is called only once, and the storage
+        // of the store ends before it.
+        core::slice::from_raw_parts_mut(
+            STORE.as_ptr().add(DEVICE_SECRET_OFFSET) as *mut u8,
+            PAGE_SIZE,
+        )
+    };
+    let mut storage = unsafe {
+        // Safety: The page is in a writeable flash region.
+        embedded_flash::SyscallStorage::new(page).unwrap()
+    };
+    let index = embedded_flash::Index { page: 0, byte: 0 };
+    if storage
+        .read_slice(index, 32)
+        .unwrap()
+        .iter()
+        .all(|&byte| byte == 0xff)
+    {
+        storage
+            .write_slice(index, &rng.gen_uniform_u8x32())
+            .unwrap();
+    }
+    *array_ref!(storage.read_slice(index, 32).unwrap(), 0, 32)
+}
+
+// Returns a storage in RAM with the same geometry as the persistent store, losing its content on
+// reboot.
+#[cfg(any(test, feature = "desktop", feature = "ram_storage"))]
+pub fn new_ram_storage() -> embedded_flash::BufferStorage {
+    let store = vec![0xff; STORAGE_SIZE].into_boxed_slice();
+    embedded_flash::BufferStorage::new(store, EMULATED_STORAGE_OPTIONS)
+}
+
+// Returns a storage persisted in a file with the same geometry as the persistent store. The file
+// is created if needed, and otherwise holds the flash image of a previous run or of a device.
+#[cfg(feature = "desktop")]
+pub fn new_file_storage(path: &std::path::Path) -> std::io::Result<embedded_flash::FileStorage> {
+    embedded_flash::FileStorage::open(path, STORAGE_PAGES, EMULATED_STORAGE_OPTIONS)
+}
+
+// The flash characteristics of the nRF52840, for storages emulated on other media.
+#[cfg(any(test, feature = "desktop", feature = "ram_storage"))]
+const EMULATED_STORAGE_OPTIONS: embedded_flash::BufferOptions = embedded_flash::BufferOptions {
+    word_size: 4,
+    page_size: PAGE_SIZE,
+    max_word_writes: 2,
+    max_page_erases: 10000,
+    strict_write: true,
+};
 
-impl PersistentStore {
+impl<S: embedded_flash::Storage> PersistentStore<S> {
     /// Gives access to the persistent store.
     ///
     /// # Safety
     ///
-    /// This should be at most one instance of persistent store per program lifetime.
-    pub fn new(rng: &mut impl Rng256) -> PersistentStore {
-        #[cfg(not(any(test, feature = "ram_storage")))]
-        let storage = PersistentStore::new_prod_storage();
-        #[cfg(any(test, feature = "ram_storage"))]
-        let storage = PersistentStore::new_test_storage();
+    /// This should be at most one instance of persistent store per storage.
+    pub fn new(storage: S, device_secret: &[u8; 32], rng: &mut impl Rng256) -> PersistentStore<S> {
         let mut store = PersistentStore {
             store: embedded_flash::Store::new(storage, Config).unwrap(),
+            key: StorageKey::new(device_secret),
+            credentials: RefCell::new(CredentialIndex {
+                generation: 0,
+                credentials: Vec::new(),
+            }),
+            newer_schema: false,
         };
-        store.init(rng);
-        store
-    }
-
-    #[cfg(not(any(test, feature = "ram_storage")))]
-    fn new_prod_storage() -> Storage {
-        let store = unsafe {
-            // Safety: The store cannot alias because this function is called only once.
-            core::slice::from_raw_parts_mut(STORE.as_ptr() as *mut u8, STORE_SIZE)
-        };
-        unsafe {
-            // Safety: The store is in a writeable flash region.
-            Storage::new(store).unwrap()
+        store.newer_schema = store.schema_version() > SCHEMA_VERSION;
+        if !store.newer_schema {
+            store.init(rng);
         }
+        // Migrations may have rewritten credentials without changing the generation.
+        *store.credentials.get_mut() = store.build_credential_index();
+        store
     }
 
-    #[cfg(any(test, feature = "ram_storage"))]
-    fn new_test_storage() -> Storage {
-        let store = vec![0xff; STORE_SIZE].into_boxed_slice();
-        let options = embedded_flash::BufferOptions {
-            word_size: 4,
-            page_size: PAGE_SIZE,
-            max_word_writes: 2,
-            max_page_erases: 10000,
-            strict_write: true,
-        };
-        Storage::new(store, options)
+    // Returns whether the store was written by a more recent firmware. Its schema may be
+    // misinterpreted, so commands must not use it, see `migrate`.
+    pub fn has_newer_schema(&self) -> bool {
+        self.newer_schema
     }
 
     fn init(&mut self, rng: &mut impl Rng256) {
+        // Migrations run first, so that the entries created below are in the current schema.
+        self.migrate(rng);
         if self.store.find_one(&Key::MasterKeys).is_none() {
-            let master_encryption_key = rng.gen_uniform_u8x32();
-            let master_hmac_key = rng.gen_uniform_u8x32();
-            let mut master_keys = Vec::with_capacity(64);
-            master_keys.extend_from_slice(&master_encryption_key);
-            master_keys.extend_from_slice(&master_hmac_key);
+            let master_keys = self.generate_master_keys(rng);
             self.store
                 .insert(StoreEntry {
                     tag: MASTER_KEYS,
@@ -203,209 +546,748 @@ impl PersistentStore {
                 .unwrap();
         }
         if self.store.find_one(&Key::PinRetries).is_none() {
+            let pin_retries = self.max_pin_retries();
             self.store
                 .insert(StoreEntry {
                     tag: PIN_RETRIES,
-                    data: &[MAX_PIN_RETRIES],
+                    data: &pin_retries,
                     sensitive: false,
                 })
                 .unwrap();
         }
     }
 
+    fn schema_version(&self) -> u32 {
+        self.store
+            .find_one(&Key::SchemaVersion)
+            .map_or(0, |(_, entry)| {
+                u32::from_ne_bytes(*array_ref!(entry.data, 1, 4))
+            })
+    }
+
+    fn set_schema_version(&mut self, version: u32) {
+        let mut data = [SCHEMA_VERSION_STATE; 5];
+        data[1..].copy_from_slice(&version.to_ne_bytes());
+        let entry = StoreEntry {
+            tag: DEVICE_STATE,
+            data: &data,
+            sensitive: false,
+        };
+        match self.store.find_one(&Key::SchemaVersion) {
+            None => self.store.insert(entry).unwrap(),
+            Some((index, _)) => self.store.replace(index, entry).unwrap(),
+        };
+    }
+
+    // Upgrades the store to the current schema, one version at a time.
+    //
+    // Downgrades are refused: a store written by a more recent version may be misinterpreted, and
+    // writing to it could lose credentials. The store isn't migrated nor written, and commands fail
+    // with CTAP2_ERR_VENDOR_INTERNAL_ERROR until a firmware supporting its schema is flashed.
+    fn migrate(&mut self, rng: &mut impl Rng256) {
+        for version in self.schema_version()..SCHEMA_VERSION {
+            self.migrate_from(version, rng);
+            self.set_schema_version(version + 1);
+        }
+    }
+
+    // Migrates the store from a schema version to the next one.
+    //
+    // A migration may be interrupted by a power loss before the new version is written, in which
+    // case it runs again at the next boot. So migrations must be idempotent.
+    fn migrate_from(&mut self, version: u32, rng: &mut impl Rng256) {
+        match version {
+            // Version 1 only adds the schema version entry.
+            0 => (),
+            // Version 2 seals the sensitive values.
+            1 => self.seal_plaintext_entries(rng),
+            // Version 3 moves the signature counter and the PIN retries to counters.
+            2 => self.migrate_counters(),
+            // Version 4 adds the signature counters of credentials and relying parties. Those
+            // without one keep using the global signature counter.
+            3 => (),
+            _ => unreachable!(),
+        }
+    }
+
+    // Counters only increase, so moving values to them is idempotent.
+    fn migrate_counters(&mut self) {
+        let signature_counter = self
+            .store
+            .iter()
+            .find(|(_, entry)| entry.tag == GLOBAL_SIGNATURE_COUNTER && entry.data.len() == 4)
+            .map(|(index, entry)| (index, u32::from_ne_bytes(*array_ref!(entry.data, 0, 4))));
+        if let Some((index, value)) = signature_counter {
+            self.store.advance(SIGNATURE_COUNTER, value).unwrap();
+            self.store.delete(index).unwrap();
+        }
+        let pin_retries = self
+            .store
+            .iter()
+            .find(|(_, entry)| entry.tag == PIN_RETRIES && entry.data.len() == 1)
+            .map(|(index, entry)| (index, core::cmp::min(entry.data[0], MAX_PIN_RETRIES)));
+        if let Some((index, pin_retries)) = pin_retries {
+            let limit = self.pin_failures().saturating_add(u32::from(pin_retries));
+            let entry = StoreEntry {
+                tag: PIN_RETRIES,
+                data: &limit.to_ne_bytes(),
+                sensitive: false,
+            };
+            self.store.replace(index, entry).unwrap();
+        }
+    }
+
+    // Returns new master keys, sealed.
+    fn generate_master_keys(&self, rng: &mut impl Rng256) -> Vec<u8> {
+        let master_encryption_key = rng.gen_uniform_u8x32();
+        let master_hmac_key = rng.gen_uniform_u8x32();
+        let mut master_keys = Vec::with_capacity(64);
+        master_keys.extend_from_slice(&master_encryption_key);
+        master_keys.extend_from_slice(&master_hmac_key);
+        self.key.seal(rng, &[MASTER_KEYS as u8], &master_keys)
+    }
+
+    // Sealed values are longer than plaintext ones, so sealing is idempotent.
+    fn seal_plaintext_entries(&mut self, rng: &mut impl Rng256) {
+        loop {
+            let updates: Vec<(StoreIndex, usize, Vec<u8>)> = self
+                .store
+                .iter()
+                .filter_map(|(index, entry)| {
+                    let data = self.seal_plaintext_entry(rng, entry)?;
+                    Some((index, entry.tag, data))
+                })
+                .collect();
+            if updates.is_empty() {
+                break;
+            }
+            for (index, tag, data) in updates {
+                let entry = StoreEntry {
+                    tag,
+                    data: &data,
+                    sensitive: true,
+                };
+                match self.store.replace(index, entry) {
+                    Ok(_) => (),
+                    // A compaction invalidated the remaining indices.
+                    Err(StoreError::InvalidPrecondition) => break,
+                    Err(error) => panic!("{:?}", error),
+                }
+            }
+        }
+    }
+
+    fn seal_plaintext_entry(&self, rng: &mut impl Rng256, entry: StoreEntry) -> Option<Vec<u8>> {
+        let length = entry.data.len();
+        match entry.tag {
+            TAG_CREDENTIAL => {
+                let mut sealed = false;
+                let identity = credential_identity(entry.data)?;
+                let cbor = cbor::read(entry.data).ok()?;
+                let cbor = map_credential_secrets(cbor, |field, value| {
+                    if value.len() != 32 {
+                        return Some(value);
+                    }
+                    sealed = true;
+                    let associated_data =
+                        credential_associated_data(field, &identity.credential_id, &identity.rp_id);
+                    Some(self.key.seal(rng, &associated_data, &value))
+                })
+                .ok()?;
+                if !sealed {
+                    return None;
+                }
+                let mut data = Vec::new();
+                if cbor::write(cbor, &mut data) {
+                    Some(data)
+                } else {
+                    None
+                }
+            }
+            MASTER_KEYS if length == 64 => {
+                Some(self.key.seal(rng, &[MASTER_KEYS as u8], entry.data))
+            }
+            PIN_HASH if length == PIN_AUTH_LENGTH => {
+                Some(self.key.seal(rng, &[PIN_HASH as u8], entry.data))
+            }
+            ATTESTATION_PRIVATE_KEY if length == 32 => Some(self.key.seal(
+                rng,
+                &[ATTESTATION_PRIVATE_KEY as u8],
+                entry.data,
+            )),
+            _ => None,
+        }
+    }
+
+    #[cfg(any(test, feature = "debug_ctap"))]
+    pub fn health(&self) -> StorageHealth {
+        let malformed_entries = self
+            .store
+            .iter()
+            .filter(|&(_, entry)| {
+                let mut has_key = false;
+                Config.keys(entry, |_| has_key = true);
+                !has_key
+                    || (entry.tag == TAG_CREDENTIAL && credential_identity(entry.data).is_none())
+            })
+            .count();
+        let missing_entries = [
+            self.master_keys().is_err(),
+            self.pin_retries_entry().is_none(),
+        ]
+        .iter()
+        .filter(|&&missing| missing)
+        .count();
+        StorageHealth {
+            corrupt_entries: self.store.health().corrupt_entries,
+            malformed_entries,
+            missing_entries,
+        }
+    }
+
+    fn build_credential_index(&self) -> CredentialIndex {
+        let mut credentials: Vec<IndexedCredential> = self
+            .store
+            .find_all(&Key::Credential)
+            .filter_map(|(position, entry)| {
+                let identity = credential_identity(entry.data)?;
+                Some(IndexedCredential::new(&identity, position))
+            })
+            .collect();
+        credentials.shrink_to_fit();
+        CredentialIndex {
+            generation: self.store.generation(),
+            credentials,
+        }
+    }
+
+    // Returns the credential index, after rebuilding it if a compaction invalidated it.
+    fn credential_index(&self) -> Ref<CredentialIndex> {
+        if self.credentials.borrow().generation != self.store.generation() {
+            *self.credentials.borrow_mut() = self.build_credential_index();
+        }
+        self.credentials.borrow()
+    }
+
+    // Returns the credential entries whose hashes match. Hashes may collide, so the caller checks
+    // the fields of the entries.
+    fn find_credential_entries(
+        &self,
+        matches: impl Fn(&IndexedCredential) -> bool,
+    ) -> impl Iterator<Item = (StoreIndex, StoreEntry)> {
+        let positions: Vec<StoreIndex> = self
+            .credential_index()
+            .credentials
+            .iter()
+            .filter(|credential| matches(credential))
+            .map(|credential| credential.position)
+            .collect();
+        positions.into_iter().filter_map(move |position| {
+            let entry = self.store.get(position)?;
+            debug_assert_eq!(entry.tag, TAG_CREDENTIAL);
+            Some((position, entry))
+        })
+    }
+
     pub fn find_credential(
         &self,
         rp_id: &str,
         credential_id: &[u8],
     ) -> Option<PublicKeyCredentialSource> {
-        let key = Key::Credential {
-            rp_id: Some(rp_id.into()),
-            credential_id: Some(credential_id.into()),
-            user_handle: None,
-        };
-        let (_, entry) = self.store.find_one(&key)?;
-        debug_assert_eq!(entry.tag, TAG_CREDENTIAL);
-        let result = deserialize_credential(entry.data);
-        debug_assert!(result.is_some());
-        result
+        let rp_id_hash = index_hash(rp_id.as_bytes());
+        let credential_id_hash = index_hash(credential_id);
+        self.find_credential_entries(move |credential| {
+            credential.rp_id == rp_id_hash && credential.credential_id == credential_id_hash
+        })
+        // Opening fails if the flash was tampered with.
+        .filter_map(|(_, entry)| self.key.open_credential(entry.data))
+        .find(|credential| credential.rp_id == rp_id && credential.credential_id == credential_id)
     }
 
     pub fn store_credential(
         &mut self,
         credential: PublicKeyCredentialSource,
+        rng: &mut impl Rng256,
     ) -> Result<(), Ctap2StatusCode> {
-        let key = Key::Credential {
-            rp_id: Some(credential.rp_id.clone()),
-            credential_id: None,
-            user_handle: Some(credential.user_handle.clone()),
+        let identity = CredentialIdentity {
+            credential_id: credential.credential_id.clone(),
+            rp_id: credential.rp_id.clone(),
+            user_handle: credential.user_handle.clone(),
         };
-        let old_entry = self.store.find_one(&key);
-        if old_entry.is_none() && self.count_credentials() >= MAX_SUPPORTED_RESIDENTIAL_KEYS {
+        let rp_id_hash = index_hash(identity.rp_id.as_bytes());
+        let user_handle_hash = index_hash(&identity.user_handle);
+        let old_credential = self
+            .find_credential_entries(move |credential| {
+                credential.rp_id == rp_id_hash && credential.user_handle == user_handle_hash
+            })
+            .filter_map(|(position, entry)| Some((position, credential_identity(entry.data)?)))
+            .find(|(_, old_identity)| {
+                old_identity.rp_id == identity.rp_id
+                    && old_identity.user_handle == identity.user_handle
+            });
+        if old_credential.is_none() && self.count_credentials() >= MAX_SUPPORTED_RESIDENTIAL_KEYS {
             return Err(Ctap2StatusCode::CTAP2_ERR_KEY_STORE_FULL);
         }
-        let credential = serialize_credential(credential)?;
+        // The overwritten credential loses its signature counter first, so that an interrupted
+        // overwrite doesn't leave it behind. Deleting doesn't change the generation, so the
+        // position stays valid.
+        let old_position = old_credential.map(|(position, old_identity)| {
+            self.delete_signature_counter(CounterOwner::Credential(&old_identity.credential_id));
+            position
+        });
+        let sealed_credential = self
+            .key
+            .seal_credential(rng, credential.clone())
+            .ok_or(Ctap2StatusCode::CTAP2_ERR_INVALID_CREDENTIAL)?;
         let new_entry = StoreEntry {
             tag: TAG_CREDENTIAL,
-            data: &credential,
+            data: &sealed_credential,
             sensitive: true,
         };
-        match old_entry {
-            None => self.store.insert(new_entry)?,
-            Some((index, old_entry)) => {
-                debug_assert_eq!(old_entry.tag, TAG_CREDENTIAL);
-                self.store.replace(index, new_entry)?
+        // An overwritten credential may stay on flash until the next idle step, see `compact_step`.
+        let result = match old_position {
+            None => self.store.insert(new_entry),
+            Some(old_position) => self.store.replace(old_position, new_entry),
+        };
+        let position = match result {
+            Ok(position) => position,
+            // Signature counters give their space up for credentials, and their owners use the
+            // global counter. Storing is tried again because a compaction may have moved the old
+            // credential.
+            Err(StoreError::StoreFull) if self.signature_counters().next().is_some() => {
+                let indices: Vec<_> = self.signature_counters().collect();
+                for index in indices {
+                    self.store.delete(index).unwrap();
+                }
+                return self.store_credential(credential, rng);
             }
+            Err(error) => return Err(error.into()),
         };
+        // The index is rebuilt on next use if a compaction invalidated it.
+        let generation = self.store.generation();
+        let index = self.credentials.get_mut();
+        if index.generation == generation {
+            index
+                .credentials
+                .retain(|credential| Some(credential.position) != old_position);
+            index
+                .credentials
+                .push(IndexedCredential::new(&identity, position));
+        }
+        // A new credential starts its own signature counter. If it can't, it uses the global one.
+        let owner = CounterOwner::Credential(&identity.credential_id);
+        self.store
+            .insert(StoreEntry {
+                tag: DEVICE_STATE,
+                data: &counter_data(&owner.key(), 0),
+                sensitive: false,
+            })
+            .ok();
         Ok(())
     }
 
     pub fn filter_credential(&self, rp_id: &str) -> Vec<PublicKeyCredentialSource> {
-        self.store
-            .find_all(&Key::Credential {
-                rp_id: Some(rp_id.into()),
-                credential_id: None,
-                user_handle: None,
-            })
-            .filter_map(|(_, entry)| {
-                debug_assert_eq!(entry.tag, TAG_CREDENTIAL);
-                let credential = deserialize_credential(entry.data);
-                debug_assert!(credential.is_some());
-                credential
-            })
+        let rp_id_hash = index_hash(rp_id.as_bytes());
+        self.find_credential_entries(move |credential| credential.rp_id == rp_id_hash)
+            .filter_map(|(_, entry)| self.key.open_credential(entry.data))
+            .filter(|credential| credential.rp_id == rp_id)
             .collect()
     }
 
     pub fn count_credentials(&self) -> usize {
-        self.store
-            .find_all(&Key::Credential {
-                rp_id: None,
-                credential_id: None,
-                user_handle: None,
-            })
-            .count()
+        self.credential_index().credentials.len()
     }
 
-    pub fn global_signature_counter(&self) -> u32 {
-        self.store
-            .find_one(&Key::GlobalSignatureCounter)
-            .map_or(0, |(_, entry)| {
-                u32::from_ne_bytes(*array_ref!(entry.data, 0, 4))
-            })
+    // Compacts a page of the store if storing a residential key would need it, so that commands
+    // don't wait for page erases, or if it holds deleted sensitive entries that the flash couldn't
+    // wipe. Returns whether a page was compacted.
+    pub fn compact_step(&mut self) -> bool {
+        if self.newer_schema {
+            return false;
+        }
+        let length = self.store.replace_len(true, MAX_CREDENTIAL_SIZE);
+        if !self.store.compact_step(length) {
+            return false;
+        }
+        // The compaction moved entries, so the index is rebuilt now rather than by a command.
+        *self.credentials.get_mut() = self.build_credential_index();
+        true
+    }
+
+    fn global_signature_counter(&self) -> u32 {
+        self.store.counter(SIGNATURE_COUNTER).unwrap()
     }
 
-    pub fn incr_global_signature_counter(&mut self) {
-        let mut buffer = [0; core::mem::size_of::<u32>()];
-        match self.store.find_one(&Key::GlobalSignatureCounter) {
+    fn incr_global_signature_counter(&mut self) {
+        // The counter can't wrap, so it stays at its maximum value. Servers would see a counter
+        // that doesn't increase either way.
+        match self.store.increment(SIGNATURE_COUNTER) {
+            Ok(_) | Err(StoreError::StoreFull) => (),
+            Err(error) => panic!("{:?}", error),
+        }
+    }
+
+    // Signature counters are kept per owner, so that relying parties can't correlate users by
+    // their counters. Owners without a counter entry use the global signature counter, which is
+    // incremented by all assertions. So it is never smaller than the other counters, and owners
+    // can fall back to it or start from it without their counter decreasing. This happens for
+    // credentials and relying parties that used the global counter before schema version 4, for
+    // relying parties that don't fit in MAX_RELYING_PARTY_COUNTERS, after a reset, and when the
+    // store is full.
+    pub fn signature_counter(&self, owner: CounterOwner) -> u32 {
+        match self.store.find_one(&Key::SignatureCounter(owner.key())) {
+            None => self.global_signature_counter(),
+            Some((_, entry)) => counter_value(entry.data),
+        }
+    }
+
+    // Increments the signature counter of an owner and returns its new value.
+    pub fn incr_signature_counter(&mut self, owner: CounterOwner) -> u32 {
+        self.incr_global_signature_counter();
+        let global_value = self.global_signature_counter();
+        let key = owner.key();
+        let (old, value) = match self.store.find_one(&Key::SignatureCounter(key)) {
+            Some((index, entry)) => (Some(index), counter_value(entry.data).saturating_add(1)),
             None => {
-                buffer.copy_from_slice(&1u32.to_ne_bytes());
-                self.store
-                    .insert(StoreEntry {
-                        tag: GLOBAL_SIGNATURE_COUNTER,
-                        data: &buffer,
-                        sensitive: false,
-                    })
-                    .unwrap();
+                let is_full = match owner {
+                    CounterOwner::Credential(_) => false,
+                    CounterOwner::RelyingParty(_) => {
+                        self.count_relying_party_counters() >= MAX_RELYING_PARTY_COUNTERS
+                    }
+                };
+                if is_full {
+                    return global_value;
+                }
+                (None, global_value)
             }
-            Some((index, entry)) => {
-                let value = u32::from_ne_bytes(*array_ref!(entry.data, 0, 4));
-                // In hopes that servers handle the wrapping gracefully.
-                buffer.copy_from_slice(&value.wrapping_add(1).to_ne_bytes());
-                self.store
-                    .replace(
-                        index,
-                        StoreEntry {
-                            tag: GLOBAL_SIGNATURE_COUNTER,
-                            data: &buffer,
-                            sensitive: false,
-                        },
-                    )
-                    .unwrap();
+        };
+        let entry = StoreEntry {
+            tag: DEVICE_STATE,
+            data: &counter_data(&key, value),
+            sensitive: false,
+        };
+        let result = match old {
+            None => self.store.insert(entry),
+            Some(old) => self.store.replace(old, entry),
+        };
+        match result {
+            Ok(_) => value,
+            // The store is full, so the owner falls back to the global counter.
+            Err(_) => {
+                self.delete_signature_counter(owner);
+                global_value
             }
         }
     }
 
-    pub fn master_keys(&self) -> MasterKeys {
-        // We have as invariant that there is always exactly one MasterKeys entry in the store.
-        let (_, entry) = self.store.find_one(&Key::MasterKeys).unwrap();
-        let data = entry.data;
-        // And this entry is well formed: the encryption key followed by the hmac key.
-        let encryption = array_ref!(data, 0, 32);
-        let hmac = array_ref!(data, 32, 32);
-        MasterKeys { encryption, hmac }
+    fn delete_signature_counter(&mut self, owner: CounterOwner) {
+        if let Some((index, _)) = self.store.find_one(&Key::SignatureCounter(owner.key())) {
+            self.store.delete(index).unwrap();
+        }
     }
 
-    pub fn pin_hash(&self) -> Option<&[u8; PIN_AUTH_LENGTH]> {
+    fn signature_counters(&self) -> impl Iterator<Item = StoreIndex> + '_ {
         self.store
-            .find_one(&Key::PinHash)
-            .map(|(_, entry)| array_ref!(entry.data, 0, PIN_AUTH_LENGTH))
+            .iter()
+            .filter(|(_, entry)| is_signature_counter(entry))
+            .map(|(index, _)| index)
     }
 
-    pub fn set_pin_hash(&mut self, pin_hash: &[u8; PIN_AUTH_LENGTH]) {
-        let entry = StoreEntry {
+    fn count_relying_party_counters(&self) -> usize {
+        self.store
+            .iter()
+            .filter(|(_, entry)| {
+                is_signature_counter(entry) && entry.data[1] == CounterOwner::RELYING_PARTY
+            })
+            .count()
+    }
+
+    // There is always a MasterKeys entry in the store, but it may not open if the flash was
+    // tampered with or the device secret changed. The key is then unusable until a reset generates
+    // new master keys.
+    pub fn master_keys(&self) -> Result<MasterKeys, Ctap2StatusCode> {
+        let data = self
+            .store
+            .find_one(&Key::MasterKeys)
+            .and_then(|(_, entry)| self.key.open(&[MASTER_KEYS as u8], entry.data))
+            .ok_or(Ctap2StatusCode::CTAP2_ERR_VENDOR_INTERNAL_ERROR)?;
+        // The opened entry is the encryption key followed by the hmac key.
+        Ok(MasterKeys {
+            encryption: *array_ref!(data, 0, 32),
+            hmac: *array_ref!(data, 32, 32),
+        })
+    }
+
+    pub fn pin_hash(&self) -> Option<[u8; PIN_AUTH_LENGTH]> {
+        let (_, entry) = self.store.find_one(&Key::PinHash)?;
+        let data = self.key.open(&[PIN_HASH as u8], entry.data)?;
+        Some(*array_ref!(data, 0, PIN_AUTH_LENGTH))
+    }
+
+    // Sets the PIN hash and resets the PIN retries, atomically.
+    pub fn set_pin_hash(&mut self, pin_hash: &[u8; PIN_AUTH_LENGTH], rng: &mut impl Rng256) {
+        let sealed_pin_hash = self.key.seal(rng, &[PIN_HASH as u8], pin_hash);
+        let pin_hash = StoreEntry {
             tag: PIN_HASH,
-            data: pin_hash,
+            data: &sealed_pin_hash,
             sensitive: true,
         };
-        match self.store.find_one(&Key::PinHash) {
-            None => self.store.insert(entry).unwrap(),
-            Some((index, _)) => {
-                self.store.replace(index, entry).unwrap();
-            }
+        let pin_retries = self.max_pin_retries();
+        let pin_hash_update = match self.store.find_one(&Key::PinHash) {
+            None => StoreUpdate::Insert(pin_hash),
+            Some((index, _)) => StoreUpdate::Replace {
+                old: index,
+                new: pin_hash,
+            },
+        };
+        self.store
+            .transaction(&[pin_hash_update, self.pin_retries_update(&pin_retries)])
+            .unwrap();
+    }
+
+    // The PIN retries entry holds the value of the PIN failures counter at which no retries are
+    // left. So a failed attempt only increments the counter, and the entry is only written when
+    // the retries are reset. It is created at boot, but may be missing if it was corrupted, in
+    // which case no retries are left until they are reset.
+    fn pin_retries_entry(&self) -> Option<(StoreIndex, u32)> {
+        let (index, entry) = self.store.find_one(&Key::PinRetries)?;
+        Some((index, u32::from_ne_bytes(*array_ref!(entry.data, 0, 4))))
+    }
+
+    // Writes the PIN retries entry, whether it is missing or not.
+    fn pin_retries_update<'a>(&self, pin_retries: &'a [u8; 4]) -> StoreUpdate<'a> {
+        let new = StoreEntry {
+            tag: PIN_RETRIES,
+            data: pin_retries,
+            sensitive: false,
+        };
+        match self.pin_retries_entry() {
+            None => StoreUpdate::Insert(new),
+            Some((old, _)) => StoreUpdate::Replace { old, new },
         }
     }
 
-    fn pin_retries_entry(&self) -> (StoreIndex, u8) {
-        let (index, entry) = self.store.find_one(&Key::PinRetries).unwrap();
-        let data = entry.data;
-        debug_assert_eq!(data.len(), 1);
-        (index, data[0])
+    fn pin_failures(&self) -> u32 {
+        self.store.counter(PIN_FAILURES).unwrap()
+    }
+
+    // Returns the data of the PIN retries entry giving the maximum number of retries.
+    fn max_pin_retries(&self) -> [u8; 4] {
+        let limit = self
+            .pin_failures()
+            .saturating_add(u32::from(MAX_PIN_RETRIES));
+        limit.to_ne_bytes()
     }
 
     pub fn pin_retries(&self) -> u8 {
-        self.pin_retries_entry().1
+        let limit = self.pin_retries_entry().map_or(0, |(_, limit)| limit);
+        let pin_retries = limit.saturating_sub(self.pin_failures());
+        core::cmp::min(pin_retries, u32::from(MAX_PIN_RETRIES)) as u8
     }
 
     pub fn decr_pin_retries(&mut self) {
-        let (index, old_value) = self.pin_retries_entry();
-        let new_value = old_value.saturating_sub(1);
-        self.store
-            .replace(
-                index,
-                StoreEntry {
-                    tag: PIN_RETRIES,
-                    data: &[new_value],
-                    sensitive: false,
-                },
-            )
-            .unwrap();
+        // The counter is below the limit, so it doesn't overflow.
+        if self.pin_retries() > 0 {
+            self.store.increment(PIN_FAILURES).unwrap();
+        }
     }
 
     pub fn reset_pin_retries(&mut self) {
-        let (index, _) = self.pin_retries_entry();
+        if self.pin_retries() == MAX_PIN_RETRIES {
+            return;
+        }
+        let pin_retries = self.max_pin_retries();
         self.store
-            .replace(
-                index,
-                StoreEntry {
-                    tag: PIN_RETRIES,
-                    data: &[MAX_PIN_RETRIES],
-                    sensitive: false,
-                },
-            )
+            .transaction(&[self.pin_retries_update(&pin_retries)])
             .unwrap();
     }
 
+    // Provisioning is done once per device, so the attestation survives a reset. So does the schema
+    // version, which also describes the attestation entries.
+    // Counters aren't entries, and they survive a reset as they can't decrease. The signature
+    // counters of credentials and relying parties are deleted, so they fall back to the global one.
+    //
+    // The reset is atomic if it fits in a page of the store, which is the case unless the store
+    // is almost full. Otherwise, entries are deleted one by one. With a single write per word, the
+    // deleted entries are only erased by the following idle steps.
     pub fn reset(&mut self, rng: &mut impl Rng256) {
-        loop {
-            let index = {
-                let mut iter = self.store.iter();
-                match iter.next() {
+        let master_keys = self.generate_master_keys(rng);
+        let pin_retries = self.max_pin_retries();
+        let mut updates: Vec<StoreUpdate> = self
+            .store
+            .iter()
+            .filter(|(_, entry)| !survives_reset(entry))
+            .map(|(index, _)| StoreUpdate::Delete(index))
+            .collect();
+        updates.push(StoreUpdate::Insert(StoreEntry {
+            tag: MASTER_KEYS,
+            data: &master_keys,
+            sensitive: true,
+        }));
+        updates.push(StoreUpdate::Insert(StoreEntry {
+            tag: PIN_RETRIES,
+            data: &pin_retries,
+            sensitive: false,
+        }));
+        if self.store.transaction(&updates).is_err() {
+            loop {
+                let index = match self.store.iter().find(|(_, entry)| !survives_reset(entry)) {
                     None => break,
                     Some((index, _)) => index,
-                }
-            };
-            self.store.delete(index).unwrap();
+                };
+                self.store.delete(index).unwrap();
+            }
+            self.init(rng);
+        }
+        // Deleting entries doesn't change the generation.
+        *self.credentials.get_mut() = self.build_credential_index();
+    }
+
+    // The attestation is provisioned once its state entry exists. This entry is written last, so
+    // that an interrupted provisioning leaves the attestation unprovisioned. Returns the state
+    // without its kind.
+    fn attestation_state(&self) -> Option<&[u8]> {
+        self.store
+            .find_one(&Key::AttestationState)
+            .map(|(_, entry)| &entry.data[1..])
+    }
+
+    pub fn attestation_private_key(&self) -> Option<[u8; 32]> {
+        self.attestation_state()?;
+        let (_, entry) = self.store.find_one(&Key::AttestationSecret)?;
+        let data = self
+            .key
+            .open(&[ATTESTATION_PRIVATE_KEY as u8], entry.data)?;
+        Some(*array_ref!(data, 0, 32))
+    }
+
+    // Returns the certificate chain, starting with the batch certificate.
+    pub fn attestation_certificates(&self) -> Vec<Vec<u8>> {
+        if self.attestation_state().is_none() {
+            return vec![];
         }
-        self.init(rng);
+        let mut certificates: Vec<(u8, Vec<u8>)> = self
+            .store
+            .find_all(&Key::AttestationCertificate)
+            .map(|(_, entry)| (entry.data[0], entry.data[1..].to_vec()))
+            .collect();
+        certificates.sort_by_key(|(position, _)| *position);
+        certificates
+            .into_iter()
+            .map(|(_, certificate)| certificate)
+            .collect()
+    }
+
+    pub fn aaguid(&self) -> Option<&[u8; AAGUID_LENGTH]> {
+        self.attestation_state()
+            .map(|data| array_ref!(data, 0, AAGUID_LENGTH))
+    }
+
+    pub fn attestation_locked(&self) -> bool {
+        self.attestation_state()
+            .map_or(false, |data| data[AAGUID_LENGTH] != 0)
+    }
+
+    // Replaces the attestation, unless it is locked. The caller checks that the private key and
+    // the certificates are valid.
+    pub fn set_attestation(
+        &mut self,
+        private_key: &[u8; 32],
+        certificates: &[Vec<u8>],
+        aaguid: &[u8; AAGUID_LENGTH],
+        rng: &mut impl Rng256,
+    ) -> Result<(), Ctap2StatusCode> {
+        if self.attestation_locked() {
+            return Err(Ctap2StatusCode::CTAP2_ERR_NOT_ALLOWED);
+        }
+        if certificates.is_empty()
+            || certificates.len() > MAX_ATTESTATION_CERTIFICATES
+            || certificates
+                .iter()
+                .any(|c| c.is_empty() || c.len() > MAX_ATTESTATION_CERTIFICATE_LENGTH)
+        {
+            return Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER);
+        }
+        self.delete_attestation()?;
+        let private_key = self
+            .key
+            .seal(rng, &[ATTESTATION_PRIVATE_KEY as u8], private_key);
+        self.store.insert(StoreEntry {
+            tag: ATTESTATION_PRIVATE_KEY,
+            data: &private_key,
+            sensitive: true,
+        })?;
+        for (position, certificate) in certificates.iter().enumerate() {
+            let mut data = Vec::with_capacity(1 + certificate.len());
+            data.push(position as u8);
+            data.extend_from_slice(certificate);
+            self.store.insert(StoreEntry {
+                tag: ATTESTATION_CERTIFICATE,
+                data: &data,
+                sensitive: false,
+            })?;
+        }
+        let mut state = [0; AAGUID_LENGTH + 2];
+        state[0] = ATTESTATION_STATE;
+        state[1..=AAGUID_LENGTH].copy_from_slice(aaguid);
+        self.store.insert(StoreEntry {
+            tag: DEVICE_STATE,
+            data: &state,
+            sensitive: false,
+        })?;
+        Ok(())
+    }
+
+    // Prevents any further change to the attestation, which must be provisioned.
+    pub fn lock_attestation(&mut self) -> Result<(), Ctap2StatusCode> {
+        let (index, entry) = self
+            .store
+            .find_one(&Key::AttestationState)
+            .ok_or(Ctap2StatusCode::CTAP2_ERR_NOT_ALLOWED)?;
+        let mut state = [0; AAGUID_LENGTH + 2];
+        state[..=AAGUID_LENGTH].copy_from_slice(&entry.data[..=AAGUID_LENGTH]);
+        state[AAGUID_LENGTH + 1] = 1;
+        self.store.replace(
+            index,
+            StoreEntry {
+                tag: DEVICE_STATE,
+                data: &state,
+                sensitive: false,
+            },
+        )?;
+        Ok(())
+    }
+
+    fn delete_attestation(&mut self) -> Result<(), Ctap2StatusCode> {
+        // The state goes first, to unprovision before removing the rest.
+        for key in &[
+            Key::AttestationState,
+            Key::AttestationSecret,
+            Key::AttestationCertificate,
+        ] {
+            loop {
+                let index = match self.store.find_all(key).next() {
+                    None => break,
+                    Some((index, _)) => index,
+                };
+                self.store.delete(index)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl PersistentStore<embedded_flash::BufferStorage> {
+    // Returns the storage as it would be found after a reboot.
+    pub fn into_storage(self) -> embedded_flash::BufferStorage {
+        embedded_flash::BufferStorage::new(self.store.get_storage(), EMULATED_STORAGE_OPTIONS)
+    }
+
+    // Returns the storage as a firmware would find it after a downgrade.
+    pub fn into_downgraded_storage(mut self) -> embedded_flash::BufferStorage {
+        self.set_schema_version(SCHEMA_VERSION + 1);
+        self.into_storage()
     }
 }
 
@@ -419,23 +1301,51 @@ impl From<StoreError> for Ctap2StatusCode {
     }
 }
 
-fn deserialize_credential(data: &[u8]) -> Option<PublicKeyCredentialSource> {
-    let cbor = cbor::read(data).ok()?;
-    cbor.try_into().ok()
+fn survives_reset(entry: &StoreEntry) -> bool {
+    match entry.tag {
+        ATTESTATION_PRIVATE_KEY | ATTESTATION_CERTIFICATE => true,
+        DEVICE_STATE => !is_signature_counter(entry),
+        _ => false,
+    }
 }
 
-fn serialize_credential(credential: PublicKeyCredentialSource) -> Result<Vec<u8>, Ctap2StatusCode> {
-    let mut data = Vec::new();
-    if cbor::write(credential.into(), &mut data) {
-        Ok(data)
-    } else {
-        Err(Ctap2StatusCode::CTAP2_ERR_INVALID_CREDENTIAL)
-    }
+fn is_signature_counter(entry: &StoreEntry) -> bool {
+    entry.tag == DEVICE_STATE
+        && entry.data.len() == COUNTER_ENTRY_LENGTH
+        && entry.data[0] == SIGNATURE_COUNTER_STATE
+}
+
+fn counter_data(key: &[u8; COUNTER_OWNER_LENGTH], value: u32) -> [u8; COUNTER_ENTRY_LENGTH] {
+    let mut data = [0; COUNTER_ENTRY_LENGTH];
+    data[0] = SIGNATURE_COUNTER_STATE;
+    data[1..=COUNTER_OWNER_LENGTH].copy_from_slice(key);
+    data[1 + COUNTER_OWNER_LENGTH..].copy_from_slice(&value.to_be_bytes());
+    data
+}
+
+fn counter_value(data: &[u8]) -> u32 {
+    u32::from_be_bytes(*array_ref!(data, 1 + COUNTER_OWNER_LENGTH, 4))
+}
+
+// Hashes a field of a credential for the credential index, with FNV-1a. Collisions only cost a
+// deserialization, because lookups check the fields of the entries they find.
+fn index_hash(data: &[u8]) -> u32 {
+    data.iter().fold(0x811c_9dc5, |hash, &byte| {
+        (hash ^ byte as u32).wrapping_mul(0x0100_0193)
+    })
+}
+
+fn credential_identity(data: &[u8]) -> Option<CredentialIdentity> {
+    let cbor = cbor::read(data).ok()?;
+    cbor.try_into().ok()
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use alloc::string::String;
+
+    const DEVICE_SECRET: [u8; 32] = [0x55; 32];
     use crate::crypto;
     use crate::crypto::rng256::{Rng256, ThreadRng256};
     use crate::ctap::data_formats::{PublicKeyCredentialSource, PublicKeyCredentialType};
@@ -471,7 +1381,7 @@ mod test {
             max_page_erases: 10000,
             strict_write: true,
         };
-        let storage = Storage::new(store, options);
+        let storage = embedded_flash::BufferStorage::new(store, options);
         let store = embedded_flash::Store::new(storage, Config).unwrap();
         // We can replace 3 bytes with minimal overhead.
         assert_eq!(store.replace_len(false, 0), 2 * WORD_SIZE);
@@ -479,21 +1389,143 @@ mod test {
         assert_eq!(store.replace_len(false, 4), 3 * WORD_SIZE);
     }
 
+    #[test]
+    fn test_health() {
+        let mut rng = ThreadRng256 {};
+        let mut persistent_store =
+            PersistentStore::new(new_ram_storage(), &DEVICE_SECRET, &mut rng);
+        assert!(persistent_store.health().is_healthy());
+        let credential_source = create_credential_source(&mut rng, "example.com", vec![]);
+        assert!(persistent_store
+            .store_credential(credential_source, &mut rng)
+            .is_ok());
+        let master_keys = persistent_store.master_keys().unwrap().encryption.to_vec();
+
+        // Entries that can't be parsed are ignored.
+        for &(tag, data) in &[
+            (MASTER_KEYS, &[0x00; 3][..]),
+            (TAG_CREDENTIAL, &[0xFF][..]),
+            (DEVICE_STATE, &[0xFF, 0x00, 0x00, 0x00, 0x00][..]),
+        ] {
+            persistent_store
+                .store
+                .insert(StoreEntry {
+                    tag,
+                    data,
+                    sensitive: false,
+                })
+                .unwrap();
+        }
+        assert_eq!(
+            persistent_store.health(),
+            StorageHealth {
+                corrupt_entries: 0,
+                malformed_entries: 3,
+                missing_entries: 0,
+            }
+        );
+        assert_eq!(
+            persistent_store.master_keys().unwrap().encryption,
+            &master_keys[..]
+        );
+        assert_eq!(persistent_store.count_credentials(), 1);
+        assert_eq!(persistent_store.filter_credential("example.com").len(), 1);
+    }
+
+    #[test]
+    fn test_missing_entries() {
+        let mut rng = ThreadRng256 {};
+        let mut persistent_store =
+            PersistentStore::new(new_ram_storage(), &DEVICE_SECRET, &mut rng);
+
+        // Corrupted entries are quarantined by the store, as if they were deleted.
+        for key in &[Key::MasterKeys, Key::PinRetries] {
+            let (index, _) = persistent_store.store.find_one(key).unwrap();
+            persistent_store.store.delete(index).unwrap();
+        }
+        assert_eq!(
+            persistent_store.health(),
+            StorageHealth {
+                corrupt_entries: 0,
+                malformed_entries: 0,
+                missing_entries: 2,
+            }
+        );
+        assert!(persistent_store.master_keys().is_err());
+        assert_eq!(persistent_store.pin_retries(), 0);
+        persistent_store.decr_pin_retries();
+        assert_eq!(persistent_store.pin_retries(), 0);
+
+        // Resetting the PIN retries or the store writes the entries again.
+        persistent_store.reset_pin_retries();
+        assert_eq!(persistent_store.pin_retries(), MAX_PIN_RETRIES);
+        persistent_store.reset(&mut rng);
+        assert!(persistent_store.master_keys().is_ok());
+        assert!(persistent_store.health().is_healthy());
+    }
+
     #[test]
     fn test_store() {
         let mut rng = ThreadRng256 {};
-        let mut persistent_store = PersistentStore::new(&mut rng);
+        let mut persistent_store =
+            PersistentStore::new(new_ram_storage(), &DEVICE_SECRET, &mut rng);
         assert_eq!(persistent_store.count_credentials(), 0);
         let credential_source = create_credential_source(&mut rng, "example.com", vec![]);
-        assert!(persistent_store.store_credential(credential_source).is_ok());
+        assert!(persistent_store
+            .store_credential(credential_source, &mut rng)
+            .is_ok());
         assert!(persistent_store.count_credentials() > 0);
     }
 
+    #[test]
+    fn test_store_credential_size() {
+        let mut rng = ThreadRng256 {};
+        let mut persistent_store =
+            PersistentStore::new(new_ram_storage(), &DEVICE_SECRET, &mut rng);
+        // The largest residential key has the longest fields that make_credential stores.
+        let rp_id = "a".repeat(253);
+        let mut credential_source = create_credential_source(&mut rng, &rp_id, vec![0x55; 64]);
+        credential_source.other_ui = Some("b".repeat(64));
+        credential_source.cred_random = Some(vec![0xC1; 32]);
+        assert!(persistent_store
+            .store_credential(credential_source.clone(), &mut rng)
+            .is_ok());
+        assert_eq!(
+            persistent_store.filter_credential(&rp_id),
+            vec![credential_source]
+        );
+        let (_, entry) = persistent_store
+            .store
+            .iter()
+            .find(|(_, entry)| entry.tag == TAG_CREDENTIAL)
+            .unwrap();
+        assert_eq!(entry.data.len(), MAX_CREDENTIAL_SIZE);
+
+        // Larger keys are stored as long as they fit in a page.
+        let rp_id = "a".repeat(2000);
+        let credential_source = create_credential_source(&mut rng, &rp_id, vec![0x55; 64]);
+        assert!(persistent_store
+            .store_credential(credential_source.clone(), &mut rng)
+            .is_ok());
+        assert_eq!(
+            persistent_store.filter_credential(&rp_id),
+            vec![credential_source]
+        );
+        let rp_id = "a".repeat(5000);
+        let credential_source = create_credential_source(&mut rng, &rp_id, vec![0x55; 64]);
+        assert_eq!(
+            persistent_store.store_credential(credential_source, &mut rng),
+            Err(Ctap2StatusCode::CTAP2_ERR_KEY_STORE_FULL)
+        );
+        assert_eq!(persistent_store.count_credentials(), 2);
+    }
+
     #[test]
     #[allow(clippy::assertions_on_constants)]
     fn test_fill_store() {
         let mut rng = ThreadRng256 {};
-        let mut persistent_store = PersistentStore::new(&mut rng);
+        let mut persistent_store =
+            PersistentStore::new(new_ram_storage(), &DEVICE_SECRET, &mut rng);
         assert_eq!(persistent_store.count_credentials(), 0);
 
         // To make this test work for bigger storages, implement better int -> Vec conversion.
@@ -501,7 +1533,9 @@ mod test {
         for i in 0..MAX_SUPPORTED_RESIDENTIAL_KEYS {
             let credential_source =
                 create_credential_source(&mut rng, "example.com", vec![i as u8]);
-            assert!(persistent_store.store_credential(credential_source).is_ok());
+            assert!(persistent_store
+                .store_credential(credential_source, &mut rng)
+                .is_ok());
             assert_eq!(persistent_store.count_credentials(), i + 1);
         }
         let credential_source = create_credential_source(
@@ -510,7 +1544,7 @@ mod test {
             vec![MAX_SUPPORTED_RESIDENTIAL_KEYS as u8],
         );
         assert_eq!(
-            persistent_store.store_credential(credential_source),
+            persistent_store.store_credential(credential_source, &mut rng),
             Err(Ctap2StatusCode::CTAP2_ERR_KEY_STORE_FULL)
         );
         assert_eq!(
@@ -523,7 +1557,8 @@ mod test {
     #[allow(clippy::assertions_on_constants)]
     fn test_overwrite() {
         let mut rng = ThreadRng256 {};
-        let mut persistent_store = PersistentStore::new(&mut rng);
+        let mut persistent_store =
+            PersistentStore::new(new_ram_storage(), &DEVICE_SECRET, &mut rng);
         assert_eq!(persistent_store.count_credentials(), 0);
         // These should have different IDs.
         let credential_source0 = create_credential_source(&mut rng, "example.com", vec![0x00]);
@@ -531,10 +1566,10 @@ mod test {
         let expected_credential = credential_source1.clone();
 
         assert!(persistent_store
-            .store_credential(credential_source0)
+            .store_credential(credential_source0, &mut rng)
             .is_ok());
         assert!(persistent_store
-            .store_credential(credential_source1)
+            .store_credential(credential_source1, &mut rng)
             .is_ok());
         assert_eq!(persistent_store.count_credentials(), 1);
         assert_eq!(
@@ -547,7 +1582,9 @@ mod test {
         for i in 0..MAX_SUPPORTED_RESIDENTIAL_KEYS {
             let credential_source =
                 create_credential_source(&mut rng, "example.com", vec![i as u8]);
-            assert!(persistent_store.store_credential(credential_source).is_ok());
+            assert!(persistent_store
+                .store_credential(credential_source, &mut rng)
+                .is_ok());
             assert_eq!(persistent_store.count_credentials(), i + 1);
         }
         let credential_source = create_credential_source(
@@ -556,7 +1593,7 @@ mod test {
             vec![MAX_SUPPORTED_RESIDENTIAL_KEYS as u8],
         );
         assert_eq!(
-            persistent_store.store_credential(credential_source),
+            persistent_store.store_credential(credential_source, &mut rng),
             Err(Ctap2StatusCode::CTAP2_ERR_KEY_STORE_FULL)
         );
         assert_eq!(
@@ -565,10 +1602,39 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_compact_step() {
+        let mut rng = ThreadRng256 {};
+        let mut persistent_store =
+            PersistentStore::new(new_ram_storage(), &DEVICE_SECRET, &mut rng);
+        let credential_source = create_credential_source(&mut rng, "example.com", vec![0x00]);
+        // Overwriting a credential leaves deleted entries, until a page is compacted while idle.
+        let mut overwrites = 0;
+        while !persistent_store.compact_step() {
+            assert!(persistent_store
+                .store_credential(credential_source.clone(), &mut rng)
+                .is_ok());
+            overwrites += 1;
+            assert!(overwrites < 1000);
+        }
+        while persistent_store.compact_step() {}
+        // The next overwrite doesn't compact.
+        let generation = persistent_store.store.generation();
+        assert!(persistent_store
+            .store_credential(credential_source.clone(), &mut rng)
+            .is_ok());
+        assert_eq!(persistent_store.store.generation(), generation);
+        assert_eq!(
+            persistent_store.filter_credential("example.com"),
+            vec![credential_source]
+        );
+    }
+
     #[test]
     fn test_filter() {
         let mut rng = ThreadRng256 {};
-        let mut persistent_store = PersistentStore::new(&mut rng);
+        let mut persistent_store =
+            PersistentStore::new(new_ram_storage(), &DEVICE_SECRET, &mut rng);
         assert_eq!(persistent_store.count_credentials(), 0);
         let credential_source0 = create_credential_source(&mut rng, "example.com", vec![0x00]);
         let credential_source1 = create_credential_source(&mut rng, "example.com", vec![0x01]);
@@ -577,13 +1643,13 @@ mod test {
         let id0 = credential_source0.credential_id.clone();
         let id1 = credential_source1.credential_id.clone();
         assert!(persistent_store
-            .store_credential(credential_source0)
+            .store_credential(credential_source0, &mut rng)
             .is_ok());
         assert!(persistent_store
-            .store_credential(credential_source1)
+            .store_credential(credential_source1, &mut rng)
             .is_ok());
         assert!(persistent_store
-            .store_credential(credential_source2)
+            .store_credential(credential_source2, &mut rng)
             .is_ok());
 
         let filtered_credentials = persistent_store.filter_credential("example.com");
@@ -596,20 +1662,150 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_credential_index() {
+        let mut rng = ThreadRng256 {};
+        let mut persistent_store =
+            PersistentStore::new(new_ram_storage(), &DEVICE_SECRET, &mut rng);
+        let mut credential_ids = Vec::new();
+        for i in 0..10 {
+            let credential_source = create_credential_source(&mut rng, "example.com", vec![i]);
+            credential_ids.push(credential_source.credential_id.clone());
+            assert!(persistent_store
+                .store_credential(credential_source, &mut rng)
+                .is_ok());
+        }
+
+        // Overwriting a credential updates the index.
+        let credential_source = create_credential_source(&mut rng, "example.com", vec![0]);
+        credential_ids[0] = credential_source.credential_id.clone();
+        assert!(persistent_store
+            .store_credential(credential_source, &mut rng)
+            .is_ok());
+        assert_eq!(persistent_store.count_credentials(), 10);
+
+        // The index is rebuilt after a compaction moved the credentials.
+        let generation = persistent_store.store.generation();
+        while persistent_store.store.generation() == generation {
+            persistent_store.set_pin_hash(&[0x88; 16], &mut rng);
+        }
+        assert_eq!(persistent_store.count_credentials(), 10);
+        for credential_id in &credential_ids {
+            assert!(persistent_store
+                .find_credential("example.com", credential_id)
+                .is_some());
+        }
+
+        // The index is rebuilt at boot.
+        let persistent_store =
+            PersistentStore::new(persistent_store.into_storage(), &DEVICE_SECRET, &mut rng);
+        assert_eq!(persistent_store.count_credentials(), 10);
+        assert_eq!(persistent_store.filter_credential("example.com").len(), 10);
+    }
+
+    #[test]
+    fn test_credential_index_size() {
+        let mut rng = ThreadRng256 {};
+        let mut persistent_store =
+            PersistentStore::new(new_ram_storage(), &DEVICE_SECRET, &mut rng);
+        for i in 0..MAX_SUPPORTED_RESIDENTIAL_KEYS {
+            let rp_id = format!("rp{}.example.com", i % 15);
+            let credential_source = create_credential_source(&mut rng, &rp_id, vec![i as u8]);
+            assert!(persistent_store
+                .store_credential(credential_source, &mut rng)
+                .is_ok());
+        }
+        *persistent_store.credentials.get_mut() = persistent_store.build_credential_index();
+        // The index takes 40 bytes per credential on 64-bit hosts, and less on the device.
+        assert!(core::mem::size_of::<IndexedCredential>() <= 40);
+        assert_eq!(
+            persistent_store.credentials.borrow().credentials.capacity(),
+            MAX_SUPPORTED_RESIDENTIAL_KEYS
+        );
+    }
+
+    // Measures credential lookups with a full store. Run with:
+    // cargo test --release --features std bench_credential_index -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn bench_credential_index() {
+        use std::time::Instant;
+        const NUM_RPS: usize = 15;
+        const ROUNDS: u32 = 100;
+        let mut rng = ThreadRng256 {};
+        let mut persistent_store =
+            PersistentStore::new(new_ram_storage(), &DEVICE_SECRET, &mut rng);
+        let mut credentials = Vec::new();
+        for i in 0..MAX_SUPPORTED_RESIDENTIAL_KEYS {
+            let rp_id = format!("rp{}.example.com", i % NUM_RPS);
+            let credential_source = create_credential_source(&mut rng, &rp_id, vec![i as u8]);
+            credentials.push((rp_id, credential_source.credential_id.clone()));
+            assert!(persistent_store
+                .store_credential(credential_source, &mut rng)
+                .is_ok());
+        }
+
+        let start = Instant::now();
+        for _ in 0..ROUNDS {
+            *persistent_store.credentials.get_mut() = persistent_store.build_credential_index();
+        }
+        let build = start.elapsed() / ROUNDS;
+        // Scanning all credentials is what each lookup did before the index.
+        let start = Instant::now();
+        for _ in 0..ROUNDS {
+            let count = persistent_store
+                .store
+                .iter()
+                .filter_map(|(_, entry)| credential_identity(entry.data))
+                .count();
+            assert_eq!(count, MAX_SUPPORTED_RESIDENTIAL_KEYS);
+        }
+        let scan = start.elapsed() / ROUNDS;
+        let start = Instant::now();
+        for (rp_id, credential_id) in &credentials {
+            assert!(persistent_store
+                .find_credential(rp_id, credential_id)
+                .is_some());
+        }
+        let find = start.elapsed() / credentials.len() as u32;
+        let start = Instant::now();
+        for i in 0..NUM_RPS {
+            let rp_id = format!("rp{}.example.com", i);
+            assert_eq!(
+                persistent_store.filter_credential(&rp_id).len(),
+                MAX_SUPPORTED_RESIDENTIAL_KEYS / NUM_RPS
+            );
+        }
+        let filter = start.elapsed() / NUM_RPS as u32;
+        let heap = persistent_store.credentials.borrow().credentials.capacity()
+            * core::mem::size_of::<IndexedCredential>();
+
+        println!(
+            "{} credentials: build {:?}, scan {:?}, find {:?}, filter {:?}",
+            MAX_SUPPORTED_RESIDENTIAL_KEYS, build, scan, find, filter
+        );
+        println!(
+            "Index heap: {} bytes ({} bytes per credential)",
+            heap,
+            core::mem::size_of::<IndexedCredential>()
+        );
+    }
+
     #[test]
     fn test_find() {
         let mut rng = ThreadRng256 {};
-        let mut persistent_store = PersistentStore::new(&mut rng);
+        let mut persistent_store =
+            PersistentStore::new(new_ram_storage(), &DEVICE_SECRET, &mut rng);
         assert_eq!(persistent_store.count_credentials(), 0);
         let credential_source0 = create_credential_source(&mut rng, "example.com", vec![0x00]);
         let credential_source1 = create_credential_source(&mut rng, "example.com", vec![0x01]);
         let id0 = credential_source0.credential_id.clone();
         let key0 = credential_source0.private_key.clone();
         assert!(persistent_store
-            .store_credential(credential_source0)
+            .store_credential(credential_source0, &mut rng)
             .is_ok());
         assert!(persistent_store
-            .store_credential(credential_source1)
+            .store_credential(credential_source1, &mut rng)
             .is_ok());
 
         let no_credential = persistent_store.find_credential("another.example.com", &id0);
@@ -630,11 +1826,12 @@ mod test {
     #[test]
     fn test_master_keys() {
         let mut rng = ThreadRng256 {};
-        let mut persistent_store = PersistentStore::new(&mut rng);
+        let mut persistent_store =
+            PersistentStore::new(new_ram_storage(), &DEVICE_SECRET, &mut rng);
 
         // Master keys stay the same between resets.
-        let master_keys_1 = persistent_store.master_keys();
-        let master_keys_2 = persistent_store.master_keys();
+        let master_keys_1 = persistent_store.master_keys().unwrap();
+        let master_keys_2 = persistent_store.master_keys().unwrap();
         assert_eq!(master_keys_2.encryption, master_keys_1.encryption);
         assert_eq!(master_keys_2.hmac, master_keys_1.hmac);
 
@@ -643,16 +1840,17 @@ mod test {
         let master_encryption_key = master_keys_1.encryption.to_vec();
         let master_hmac_key = master_keys_1.hmac.to_vec();
         persistent_store.reset(&mut rng);
-        let master_keys_3 = persistent_store.master_keys();
-        assert!(master_keys_3.encryption as &[u8] != &master_encryption_key[..]);
-        assert!(master_keys_3.hmac as &[u8] != &master_hmac_key[..]);
+        let master_keys_3 = persistent_store.master_keys().unwrap();
+        assert!(master_keys_3.encryption[..] != master_encryption_key[..]);
+        assert!(master_keys_3.hmac[..] != master_hmac_key[..]);
     }
 
     #[test]
     fn test_pin_hash() {
         use crate::ctap::PIN_AUTH_LENGTH;
         let mut rng = ThreadRng256 {};
-        let mut persistent_store = PersistentStore::new(&mut rng);
+        let mut persistent_store =
+            PersistentStore::new(new_ram_storage(), &DEVICE_SECRET, &mut rng);
 
         // Pin hash is initially not set.
         assert!(persistent_store.pin_hash().is_none());
@@ -662,12 +1860,15 @@ mod test {
         assert_eq!(random_data.len(), 2 * PIN_AUTH_LENGTH);
         let pin_hash_1 = array_ref!(random_data, 0, PIN_AUTH_LENGTH);
         let pin_hash_2 = array_ref!(random_data, PIN_AUTH_LENGTH, PIN_AUTH_LENGTH);
-        persistent_store.set_pin_hash(&pin_hash_1);
-        assert_eq!(persistent_store.pin_hash(), Some(pin_hash_1));
-        assert_eq!(persistent_store.pin_hash(), Some(pin_hash_1));
-        persistent_store.set_pin_hash(&pin_hash_2);
-        assert_eq!(persistent_store.pin_hash(), Some(pin_hash_2));
-        assert_eq!(persistent_store.pin_hash(), Some(pin_hash_2));
+        persistent_store.set_pin_hash(&pin_hash_1, &mut rng);
+        assert_eq!(persistent_store.pin_hash(), Some(*pin_hash_1));
+        assert_eq!(persistent_store.pin_hash(), Some(*pin_hash_1));
+        persistent_store.decr_pin_retries();
+        persistent_store.set_pin_hash(&pin_hash_2, &mut rng);
+        assert_eq!(persistent_store.pin_hash(), Some(*pin_hash_2));
+        assert_eq!(persistent_store.pin_hash(), Some(*pin_hash_2));
+        // Setting the pin hash resets the pin retries.
+        assert_eq!(persistent_store.pin_retries(), MAX_PIN_RETRIES);
 
         // Resetting the storage resets the pin hash.
         persistent_store.reset(&mut rng);
@@ -677,7 +1878,8 @@ mod test {
     #[test]
     fn test_pin_retries() {
         let mut rng = ThreadRng256 {};
-        let mut persistent_store = PersistentStore::new(&mut rng);
+        let mut persistent_store =
+            PersistentStore::new(new_ram_storage(), &DEVICE_SECRET, &mut rng);
 
         // The pin retries is initially at the maximum.
         assert_eq!(persistent_store.pin_retries(), MAX_PIN_RETRIES);
@@ -696,4 +1898,566 @@ mod test {
         persistent_store.reset_pin_retries();
         assert_eq!(persistent_store.pin_retries(), MAX_PIN_RETRIES);
     }
+
+    #[test]
+    fn test_counters() {
+        let mut rng = ThreadRng256 {};
+        let mut persistent_store =
+            PersistentStore::new(new_ram_storage(), &DEVICE_SECRET, &mut rng);
+        assert_eq!(persistent_store.global_signature_counter(), 0);
+
+        // Counters don't write entries.
+        let generation = persistent_store.store.generation();
+        let entries = persistent_store.store.iter().count();
+        for value in 1..=5000 {
+            persistent_store.incr_global_signature_counter();
+            assert_eq!(persistent_store.global_signature_counter(), value);
+        }
+        persistent_store.decr_pin_retries();
+        assert_eq!(persistent_store.store.generation(), generation);
+        assert_eq!(persistent_store.store.iter().count(), entries);
+
+        // Counters survive a reboot and a reset.
+        let mut persistent_store =
+            PersistentStore::new(persistent_store.into_storage(), &DEVICE_SECRET, &mut rng);
+        assert_eq!(persistent_store.global_signature_counter(), 5000);
+        assert_eq!(persistent_store.pin_retries(), MAX_PIN_RETRIES - 1);
+        persistent_store.reset(&mut rng);
+        assert_eq!(persistent_store.global_signature_counter(), 5000);
+        assert_eq!(persistent_store.pin_retries(), MAX_PIN_RETRIES);
+    }
+
+    #[test]
+    fn test_signature_counters() {
+        let mut rng = ThreadRng256 {};
+        let mut persistent_store =
+            PersistentStore::new(new_ram_storage(), &DEVICE_SECRET, &mut rng);
+        for _ in 0..10 {
+            persistent_store.incr_global_signature_counter();
+        }
+        let credential_source = create_credential_source(&mut rng, "example.com", vec![0x00]);
+        let credential_id = credential_source.credential_id.clone();
+        assert!(persistent_store
+            .store_credential(credential_source, &mut rng)
+            .is_ok());
+        let credential = CounterOwner::Credential(&credential_id);
+        let rp_id_hash = Sha256::hash(b"example.com");
+        let relying_party = CounterOwner::RelyingParty(&rp_id_hash);
+
+        // New credentials start at 0, and relying parties at the global counter.
+        assert_eq!(persistent_store.signature_counter(credential), 0);
+        assert_eq!(persistent_store.signature_counter(relying_party), 10);
+        assert_eq!(persistent_store.incr_signature_counter(credential), 1);
+        assert_eq!(persistent_store.incr_signature_counter(credential), 2);
+        assert_eq!(persistent_store.incr_signature_counter(relying_party), 13);
+        assert_eq!(persistent_store.incr_signature_counter(credential), 3);
+        assert_eq!(persistent_store.incr_signature_counter(relying_party), 14);
+        assert_eq!(persistent_store.global_signature_counter(), 15);
+
+        // Counters survive a reboot.
+        let mut persistent_store =
+            PersistentStore::new(persistent_store.into_storage(), &DEVICE_SECRET, &mut rng);
+        assert_eq!(persistent_store.signature_counter(credential), 3);
+        assert_eq!(persistent_store.signature_counter(relying_party), 14);
+
+        // An overwritten credential loses its counter.
+        let credential_source = create_credential_source(&mut rng, "example.com", vec![0x00]);
+        let new_credential_id = credential_source.credential_id.clone();
+        assert!(persistent_store
+            .store_credential(credential_source, &mut rng)
+            .is_ok());
+        assert_eq!(persistent_store.signature_counter(credential), 15);
+        let new_credential = CounterOwner::Credential(&new_credential_id);
+        assert_eq!(persistent_store.signature_counter(new_credential), 0);
+
+        // Relying parties beyond the limit share the global counter.
+        let rp_id_hashes: Vec<_> = (0..MAX_RELYING_PARTY_COUNTERS)
+            .map(|i| Sha256::hash(format!("rp{}.example.com", i).as_bytes()))
+            .collect();
+        for rp_id_hash in &rp_id_hashes[1..] {
+            persistent_store.incr_signature_counter(CounterOwner::RelyingParty(rp_id_hash));
+        }
+        assert_eq!(
+            persistent_store.count_relying_party_counters(),
+            MAX_RELYING_PARTY_COUNTERS
+        );
+        let other = CounterOwner::RelyingParty(&rp_id_hashes[0]);
+        let global = persistent_store.incr_signature_counter(other);
+        assert_eq!(global, persistent_store.global_signature_counter());
+        assert_eq!(persistent_store.signature_counter(other), global);
+
+        // A reset deletes the counters, and owners fall back to the global counter.
+        persistent_store.reset(&mut rng);
+        assert!(!persistent_store
+            .store
+            .iter()
+            .any(|(_, entry)| is_signature_counter(&entry)));
+        assert_eq!(persistent_store.signature_counter(relying_party), global);
+        assert_eq!(
+            persistent_store.incr_signature_counter(relying_party),
+            global + 1
+        );
+    }
+
+    #[test]
+    fn test_signature_counter_entry() {
+        let mut rng = ThreadRng256 {};
+        let mut persistent_store =
+            PersistentStore::new(new_ram_storage(), &DEVICE_SECRET, &mut rng);
+        let rp_id_hash = Sha256::hash(b"example.com");
+        let relying_party = CounterOwner::RelyingParty(&rp_id_hash);
+        for _ in 0..0x0102 {
+            persistent_store.incr_signature_counter(relying_party);
+        }
+
+        // Counters are their kind, then their owner, and their value in big-endian.
+        let key = Key::SignatureCounter(relying_party.key());
+        let (_, entry) = persistent_store.store.find_one(&key).unwrap();
+        assert_eq!(entry.data[0], SIGNATURE_COUNTER_STATE);
+        assert_eq!(entry.data[1], CounterOwner::RELYING_PARTY);
+        assert_eq!(entry.data[2..18], rp_id_hash[..16]);
+        assert_eq!(entry.data[18..], [0x00, 0x00, 0x01, 0x02]);
+    }
+
+    #[test]
+    fn test_signature_counters_give_way() {
+        let mut rng = ThreadRng256 {};
+        let mut persistent_store =
+            PersistentStore::new(new_ram_storage(), &DEVICE_SECRET, &mut rng);
+        for i in 0..MAX_RELYING_PARTY_COUNTERS {
+            let rp_id_hash = Sha256::hash(format!("rp{}.example.com", i).as_bytes());
+            persistent_store.incr_signature_counter(CounterOwner::RelyingParty(&rp_id_hash));
+        }
+        let global = persistent_store.global_signature_counter();
+
+        // Big credentials fill the store, and signature counters make room for them.
+        for i in 0..MAX_SUPPORTED_RESIDENTIAL_KEYS {
+            let mut credential_source =
+                create_credential_source(&mut rng, "example.com", vec![i as u8]);
+            credential_source.other_ui = Some("x".repeat(360));
+            assert!(persistent_store
+                .store_credential(credential_source, &mut rng)
+                .is_ok());
+        }
+        assert_eq!(persistent_store.count_relying_party_counters(), 0);
+        let rp_id_hash = Sha256::hash(b"rp0.example.com");
+        let relying_party = CounterOwner::RelyingParty(&rp_id_hash);
+        assert_eq!(persistent_store.signature_counter(relying_party), global);
+    }
+
+    // Simulates the flash wear of signature counters, which are the only entries written by
+    // assertions. With 50 resident credentials of 5 relying parties, an assertion replaces a
+    // counter entry of about 28 bytes, and the most erased page of the store gets one erase every
+    // 2500 assertions. So the store wears out after about 25M assertions, against 40M for the
+    // global counter alone. It takes minutes in debug builds, run with:
+    // cargo test --features std test_signature_counter_wear -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn test_signature_counter_wear() {
+        const NUM_CREDENTIALS: usize = 50;
+        const NUM_RPS: usize = 5;
+        const NUM_ASSERTIONS: usize = 30000;
+        let mut rng = ThreadRng256 {};
+        let mut persistent_store =
+            PersistentStore::new(new_ram_storage(), &DEVICE_SECRET, &mut rng);
+        let mut credential_ids = Vec::new();
+        for i in 0..NUM_CREDENTIALS {
+            let rp_id = format!("rp{}.example.com", i % NUM_RPS);
+            let credential_source = create_credential_source(&mut rng, &rp_id, vec![i as u8]);
+            credential_ids.push(credential_source.credential_id.clone());
+            assert!(persistent_store
+                .store_credential(credential_source, &mut rng)
+                .is_ok());
+        }
+        let rp_id_hashes: Vec<_> = (0..NUM_RPS)
+            .map(|i| Sha256::hash(format!("rp{}.example.com", i).as_bytes()))
+            .collect();
+        let initial_erases = persistent_store.store.compaction_info();
+
+        // Half of the assertions use resident credentials and half non-resident ones.
+        for i in 0..NUM_ASSERTIONS {
+            let owner = if i % 2 == 0 {
+                CounterOwner::Credential(&credential_ids[i / 2 % NUM_CREDENTIALS])
+            } else {
+                CounterOwner::RelyingParty(&rp_id_hashes[i / 2 % NUM_RPS])
+            };
+            persistent_store.incr_signature_counter(owner);
+        }
+        let erases = persistent_store.store.compaction_info();
+        let max_erases = initial_erases
+            .iter()
+            .zip(erases.iter())
+            .map(|(initial, current)| current - initial)
+            .max()
+            .unwrap();
+        println!("{} assertions: max erases {}", NUM_ASSERTIONS, max_erases);
+        // The lifetime in assertions, with 10000 erases per page.
+        let lifetime = NUM_ASSERTIONS * EMULATED_STORAGE_OPTIONS.max_page_erases / max_erases;
+        assert!(lifetime >= 20_000_000);
+        assert!(persistent_store.health().is_healthy());
+        assert_eq!(persistent_store.count_credentials(), NUM_CREDENTIALS);
+    }
+
+    #[test]
+    fn test_schema_version() {
+        let mut rng = ThreadRng256 {};
+        let mut persistent_store =
+            PersistentStore::new(new_ram_storage(), &DEVICE_SECRET, &mut rng);
+        assert_eq!(persistent_store.schema_version(), SCHEMA_VERSION);
+        let master_keys = persistent_store.master_keys().unwrap().encryption.to_vec();
+
+        // A store without schema version is migrated at boot, and keeps its entries.
+        let (index, _) = persistent_store
+            .store
+            .find_one(&Key::SchemaVersion)
+            .unwrap();
+        persistent_store.store.delete(index).unwrap();
+        let credential_source = create_credential_source(&mut rng, "example.com", vec![]);
+        assert!(persistent_store
+            .store_credential(credential_source, &mut rng)
+            .is_ok());
+        let mut persistent_store =
+            PersistentStore::new(persistent_store.into_storage(), &DEVICE_SECRET, &mut rng);
+        assert_eq!(persistent_store.schema_version(), SCHEMA_VERSION);
+        assert_eq!(
+            persistent_store.master_keys().unwrap().encryption,
+            &master_keys[..]
+        );
+        assert_eq!(persistent_store.count_credentials(), 1);
+
+        // The schema version survives a reset.
+        persistent_store.reset(&mut rng);
+        assert_eq!(persistent_store.schema_version(), SCHEMA_VERSION);
+    }
+
+    #[test]
+    fn test_sealed_at_rest() {
+        let mut rng = ThreadRng256 {};
+        let mut persistent_store =
+            PersistentStore::new(new_ram_storage(), &DEVICE_SECRET, &mut rng);
+        let master_keys = persistent_store.master_keys().unwrap();
+        persistent_store.set_pin_hash(&[0x88; 16], &mut rng);
+        let mut credential_source = create_credential_source(&mut rng, "example.com", vec![]);
+        credential_source.cred_random = Some(vec![0x99; 32]);
+        let mut private_key = [0; 32];
+        credential_source.private_key.to_bytes(&mut private_key);
+        assert!(persistent_store
+            .store_credential(credential_source.clone(), &mut rng)
+            .is_ok());
+        assert_eq!(
+            persistent_store.find_credential("example.com", &credential_source.credential_id),
+            Some(credential_source.clone())
+        );
+
+        // No secret can be read from the flash.
+        let storage = persistent_store.store.get_storage();
+        let contains = |secret: &[u8]| storage.windows(secret.len()).any(|window| window == secret);
+        assert!(!contains(&master_keys.encryption));
+        assert!(!contains(&master_keys.hmac));
+        assert!(!contains(&[0x88; 16]));
+        assert!(!contains(&private_key));
+        assert!(!contains(&[0x99; 32]));
+        // But credentials can still be looked up.
+        assert!(contains(b"example.com"));
+
+        // The secrets can't be opened with another device secret. The key then refuses to use
+        // its master keys, until a reset replaces them.
+        let storage = embedded_flash::BufferStorage::new(storage, EMULATED_STORAGE_OPTIONS);
+        let mut persistent_store = PersistentStore::new(storage, &[0x66; 32], &mut rng);
+        assert_eq!(
+            persistent_store.master_keys().err(),
+            Some(Ctap2StatusCode::CTAP2_ERR_VENDOR_INTERNAL_ERROR)
+        );
+        assert!(persistent_store.pin_hash().is_none());
+        assert_eq!(persistent_store.count_credentials(), 1);
+        assert!(persistent_store
+            .find_credential("example.com", &credential_source.credential_id)
+            .is_none());
+        persistent_store.reset(&mut rng);
+        assert!(persistent_store.master_keys().is_ok());
+    }
+
+    #[test]
+    fn test_sealed_secrets_are_bound() {
+        let mut rng = ThreadRng256 {};
+        let mut persistent_store =
+            PersistentStore::new(new_ram_storage(), &DEVICE_SECRET, &mut rng);
+        for user_handle in 0..2 {
+            let credential_source =
+                create_credential_source(&mut rng, "example.com", vec![user_handle]);
+            assert!(persistent_store
+                .store_credential(credential_source, &mut rng)
+                .is_ok());
+        }
+        let entries: Vec<Vec<u8>> = persistent_store
+            .store
+            .find_all(&Key::Credential)
+            .map(|(_, entry)| entry.data.to_vec())
+            .collect();
+        assert!(persistent_store.key.open_credential(&entries[0]).is_some());
+
+        // The sealed private key of a credential doesn't open in another credential.
+        let mut sealed_private_key = None;
+        map_credential_secrets(cbor::read(&entries[1]).unwrap(), |_, value| {
+            sealed_private_key = Some(value.clone());
+            Some(value)
+        })
+        .unwrap();
+        let swapped = map_credential_secrets(cbor::read(&entries[0]).unwrap(), |_, _| {
+            sealed_private_key.clone()
+        })
+        .unwrap();
+        let mut data = Vec::new();
+        assert!(cbor::write(swapped, &mut data));
+        assert!(persistent_store.key.open_credential(&data).is_none());
+
+        // Sealed values are bound to their associated data.
+        let sealed = persistent_store
+            .key
+            .seal(&mut rng, &[PIN_HASH as u8], &[0x88; 16]);
+        assert_eq!(
+            persistent_store.key.open(&[PIN_HASH as u8], &sealed),
+            Some(vec![0x88; 16])
+        );
+        assert!(persistent_store
+            .key
+            .open(&[MASTER_KEYS as u8], &sealed)
+            .is_none());
+        for i in 0..sealed.len() {
+            let mut modified = sealed.clone();
+            modified[i] ^= 0x01;
+            assert!(persistent_store
+                .key
+                .open(&[PIN_HASH as u8], &modified)
+                .is_none());
+        }
+    }
+
+    #[test]
+    fn test_seal_migration() {
+        let mut rng = ThreadRng256 {};
+        let mut persistent_store =
+            PersistentStore::new(new_ram_storage(), &DEVICE_SECRET, &mut rng);
+
+        // Writes the entries of a store at schema version 1, where nothing is sealed.
+        let master_keys = [0x77; 64];
+        let (index, _) = persistent_store.store.find_one(&Key::MasterKeys).unwrap();
+        let entry = StoreEntry {
+            tag: MASTER_KEYS,
+            data: &master_keys,
+            sensitive: true,
+        };
+        persistent_store.store.replace(index, entry).unwrap();
+        let entry = StoreEntry {
+            tag: PIN_HASH,
+            data: &[0x88; 16],
+            sensitive: true,
+        };
+        persistent_store.store.insert(entry).unwrap();
+        let mut credential_source = create_credential_source(&mut rng, "example.com", vec![]);
+        credential_source.cred_random = Some(vec![0x99; 32]);
+        let mut credential = Vec::new();
+        assert!(cbor::write(
+            credential_source.clone().into(),
+            &mut credential
+        ));
+        let entry = StoreEntry {
+            tag: TAG_CREDENTIAL,
+            data: &credential,
+            sensitive: true,
+        };
+        persistent_store.store.insert(entry).unwrap();
+        persistent_store.set_schema_version(1);
+
+        // The migration seals all of them.
+        let persistent_store =
+            PersistentStore::new(persistent_store.into_storage(), &DEVICE_SECRET, &mut rng);
+        assert_eq!(persistent_store.schema_version(), SCHEMA_VERSION);
+        assert_eq!(
+            persistent_store.master_keys().unwrap().encryption,
+            [0x77; 32]
+        );
+        assert_eq!(persistent_store.master_keys().unwrap().hmac, [0x77; 32]);
+        assert_eq!(persistent_store.pin_hash(), Some([0x88; 16]));
+        assert_eq!(
+            persistent_store.find_credential("example.com", &credential_source.credential_id),
+            Some(credential_source)
+        );
+        let storage = persistent_store.store.get_storage();
+        assert!(!storage.windows(16).any(|window| window == [0x88; 16]));
+    }
+
+    #[test]
+    fn test_counter_migration() {
+        let mut rng = ThreadRng256 {};
+        let mut persistent_store =
+            PersistentStore::new(new_ram_storage(), &DEVICE_SECRET, &mut rng);
+
+        // Writes the entries of a store at schema version 2, where counters are entries.
+        let entry = StoreEntry {
+            tag: GLOBAL_SIGNATURE_COUNTER,
+            data: &1234u32.to_ne_bytes(),
+            sensitive: false,
+        };
+        persistent_store.store.insert(entry).unwrap();
+        let (index, _) = persistent_store.store.find_one(&Key::PinRetries).unwrap();
+        let entry = StoreEntry {
+            tag: PIN_RETRIES,
+            data: &[2],
+            sensitive: false,
+        };
+        persistent_store.store.replace(index, entry).unwrap();
+        persistent_store.set_schema_version(2);
+
+        // The migration moves their values to the counters.
+        let mut persistent_store =
+            PersistentStore::new(persistent_store.into_storage(), &DEVICE_SECRET, &mut rng);
+        assert_eq!(persistent_store.schema_version(), SCHEMA_VERSION);
+        assert_eq!(persistent_store.global_signature_counter(), 1234);
+        assert_eq!(persistent_store.pin_retries(), 2);
+        assert!(persistent_store.health().is_healthy());
+        persistent_store.incr_global_signature_counter();
+        assert_eq!(persistent_store.global_signature_counter(), 1235);
+        persistent_store.decr_pin_retries();
+        assert_eq!(persistent_store.pin_retries(), 1);
+    }
+
+    #[test]
+    fn test_schema_downgrade() {
+        let mut rng = ThreadRng256 {};
+        let mut persistent_store =
+            PersistentStore::new(new_ram_storage(), &DEVICE_SECRET, &mut rng);
+        assert!(!persistent_store.has_newer_schema());
+        persistent_store.set_schema_version(SCHEMA_VERSION + 1);
+        let (index, _) = persistent_store.store.find_one(&Key::PinRetries).unwrap();
+        persistent_store.store.delete(index).unwrap();
+
+        // A store of a more recent firmware is left untouched at boot.
+        let mut persistent_store =
+            PersistentStore::new(persistent_store.into_storage(), &DEVICE_SECRET, &mut rng);
+        assert!(persistent_store.has_newer_schema());
+        assert_eq!(persistent_store.schema_version(), SCHEMA_VERSION + 1);
+        assert!(persistent_store.store.find_one(&Key::PinRetries).is_none());
+        assert!(!persistent_store.compact_step());
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut rng = ThreadRng256 {};
+        let mut persistent_store =
+            PersistentStore::new(new_ram_storage(), &DEVICE_SECRET, &mut rng);
+
+        // A store with few entries is reset in a single transaction, while a full store falls back
+        // to deleting entries one by one.
+        for &num_credentials in &[1, MAX_SUPPORTED_RESIDENTIAL_KEYS] {
+            for i in 0..num_credentials {
+                let credential_source =
+                    create_credential_source(&mut rng, "example.com", vec![i as u8]);
+                assert!(persistent_store
+                    .store_credential(credential_source, &mut rng)
+                    .is_ok());
+            }
+            persistent_store.set_pin_hash(&[0x88; 16], &mut rng);
+            persistent_store.decr_pin_retries();
+            let master_encryption_key = persistent_store.master_keys().unwrap().encryption.to_vec();
+            persistent_store.reset(&mut rng);
+            assert_eq!(persistent_store.count_credentials(), 0);
+            assert!(persistent_store.pin_hash().is_none());
+            assert_eq!(persistent_store.pin_retries(), MAX_PIN_RETRIES);
+            assert!(
+                persistent_store.master_keys().unwrap().encryption[..] != master_encryption_key[..]
+            );
+        }
+    }
+
+    #[test]
+    fn test_attestation() {
+        let mut rng = ThreadRng256 {};
+        let mut persistent_store =
+            PersistentStore::new(new_ram_storage(), &DEVICE_SECRET, &mut rng);
+        let certificates = vec![vec![0x30; 500], vec![0x31; 700]];
+
+        // The attestation is initially not provisioned.
+        assert!(persistent_store.attestation_private_key().is_none());
+        assert!(persistent_store.attestation_certificates().is_empty());
+        assert!(persistent_store.aaguid().is_none());
+        assert!(!persistent_store.attestation_locked());
+
+        // Provisioning sets all parts of the attestation, and keeps the chain order.
+        persistent_store
+            .set_attestation(&[0x41; 32], &certificates, &[0xAA; 16], &mut rng)
+            .unwrap();
+        assert_eq!(persistent_store.attestation_private_key(), Some([0x41; 32]));
+        assert_eq!(persistent_store.attestation_certificates(), certificates);
+        assert_eq!(persistent_store.aaguid(), Some(&[0xAA; 16]));
+
+        // Provisioning again replaces the previous attestation.
+        persistent_store
+            .set_attestation(&[0x42; 32], &certificates[1..], &[0xBB; 16], &mut rng)
+            .unwrap();
+        assert_eq!(persistent_store.attestation_private_key(), Some([0x42; 32]));
+        assert_eq!(
+            persistent_store.attestation_certificates(),
+            &certificates[1..]
+        );
+        assert_eq!(persistent_store.aaguid(), Some(&[0xBB; 16]));
+
+        // Invalid chains are rejected.
+        assert_eq!(
+            persistent_store.set_attestation(&[0x43; 32], &[], &[0xCC; 16], &mut rng),
+            Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER)
+        );
+        assert_eq!(
+            persistent_store.set_attestation(
+                &[0x43; 32],
+                &[vec![0x30; MAX_ATTESTATION_CERTIFICATE_LENGTH + 1]],
+                &[0xCC; 16],
+                &mut rng
+            ),
+            Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER)
+        );
+        assert_eq!(persistent_store.aaguid(), Some(&[0xBB; 16]));
+    }
+
+    #[test]
+    fn test_attestation_lock() {
+        let mut rng = ThreadRng256 {};
+        let mut persistent_store =
+            PersistentStore::new(new_ram_storage(), &DEVICE_SECRET, &mut rng);
+
+        // Only a provisioned attestation can be locked.
+        assert_eq!(
+            persistent_store.lock_attestation(),
+            Err(Ctap2StatusCode::CTAP2_ERR_NOT_ALLOWED)
+        );
+        persistent_store
+            .set_attestation(&[0x41; 32], &[vec![0x30; 500]], &[0xAA; 16], &mut rng)
+            .unwrap();
+        persistent_store.lock_attestation().unwrap();
+        assert!(persistent_store.attestation_locked());
+
+        // A locked attestation can't be replaced.
+        assert_eq!(
+            persistent_store.set_attestation(
+                &[0x42; 32],
+                &[vec![0x31; 500]],
+                &[0xBB; 16],
+                &mut rng
+            ),
+            Err(Ctap2StatusCode::CTAP2_ERR_NOT_ALLOWED)
+        );
+        assert_eq!(persistent_store.attestation_private_key(), Some([0x41; 32]));
+
+        // Resetting keeps the attestation, but not the rest.
+        persistent_store.set_pin_hash(&[0x88; 16], &mut rng);
+        persistent_store.reset(&mut rng);
+        assert!(persistent_store.pin_hash().is_none());
+        assert_eq!(persistent_store.attestation_private_key(), Some([0x41; 32]));
+        assert_eq!(
+            persistent_store.attestation_certificates(),
+            vec![vec![0x30; 500]]
+        );
+        assert_eq!(persistent_store.aaguid(), Some(&[0xAA; 16]));
+        assert!(persistent_store.attestation_locked());
+    }
 }
@@ -0,0 +1,395 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::status_code::Ctap2StatusCode;
+use super::CtapState;
+use crate::env::Env;
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+
+// CTAP specification (version 20190130) section 6.1 reserves the command bytes 0x40 to 0xBF for
+// vendors, and section 8.1.9.3 reserves the CTAPHID commands 0x40 to 0x7F. Both are dispatched to
+// the vendor commands registered in CtapState. A request carries the AuthenticatorVendorParameters
+// map, and the response is a status byte followed by the optional CBOR response.
+//
+// Over CTAPHID, a vendor command is only accepted if a subcommand is registered for its command
+// byte. The payload is then the same as for CTAPHID_CBOR, without the leading command byte.
+
+// The check performed before processing a vendor subcommand.
+// The provisioning subcommands only use None, the other variants are for firmware modules.
+#[derive(Clone, Copy)]
+#[cfg_attr(any(test, feature = "debug_ctap"), derive(Debug, PartialEq))]
+pub enum VendorAuthorization {
+    // Anybody talking to the authenticator can run the subcommand.
+    None,
+    // The client proves knowledge of the PIN token with the pinUvAuthParam, computed as
+    // LEFT(HMAC-SHA-256(pinToken, 32 * 0xFF || command || subCommand || subCommandParams), 16),
+    // like authenticatorConfig in CTAP 2.1. The parameters are in their CBOR encoding. A PIN is
+    // required for those subcommands.
+    #[cfg_attr(not(test), allow(dead_code))]
+    PinToken,
+    // The user confirms the subcommand on the device.
+    #[cfg_attr(not(test), allow(dead_code))]
+    UserPresence,
+}
+
+// A vendor subcommand, implemented by a firmware module and registered with
+// CtapState::register_vendor_command.
+pub trait VendorSubCommand<E: Env> {
+    // Must be in the range 0x40 to 0xBF. Only 0x40 to 0x7F are also reachable over CTAPHID.
+    const COMMAND: u8;
+    const SUB_COMMAND: u8;
+    const AUTHORIZATION: VendorAuthorization;
+    // Use NoParameters for subcommands without parameters.
+    type Parameters: TryFrom<cbor::Value, Error = Ctap2StatusCode>;
+    type Response: Into<cbor::Value>;
+
+    fn process(
+        ctap_state: &mut CtapState<E>,
+        parameters: Option<Self::Parameters>,
+    ) -> Result<Option<Self::Response>, Ctap2StatusCode>;
+}
+
+// Parameters of subcommands that don't accept any. Passing them anyway is an error.
+pub enum NoParameters {}
+
+impl TryFrom<cbor::Value> for NoParameters {
+    type Error = Ctap2StatusCode;
+
+    fn try_from(_: cbor::Value) -> Result<Self, Ctap2StatusCode> {
+        Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER)
+    }
+}
+
+type ProcessFn<E> =
+    fn(&mut CtapState<E>, Option<cbor::Value>) -> Result<Option<cbor::Value>, Ctap2StatusCode>;
+
+// A registered subcommand, with its parameter types erased.
+pub struct VendorCommand<E: Env> {
+    pub command: u8,
+    pub sub_command: u8,
+    pub authorization: VendorAuthorization,
+    pub process: ProcessFn<E>,
+}
+
+// The derived implementations would require E to be Clone.
+impl<E: Env> Clone for VendorCommand<E> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<E: Env> Copy for VendorCommand<E> {}
+
+fn process_typed<E: Env, C: VendorSubCommand<E>>(
+    ctap_state: &mut CtapState<E>,
+    parameters: Option<cbor::Value>,
+) -> Result<Option<cbor::Value>, Ctap2StatusCode> {
+    let parameters = parameters.map(C::Parameters::try_from).transpose()?;
+    Ok(C::process(ctap_state, parameters)?.map(Into::into))
+}
+
+pub struct VendorRegistry<E: Env> {
+    commands: Vec<VendorCommand<E>>,
+}
+
+impl<E: Env> VendorRegistry<E> {
+    pub fn new() -> VendorRegistry<E> {
+        VendorRegistry {
+            commands: Vec::new(),
+        }
+    }
+
+    // Registering happens once at boot, so invalid or duplicate subcommands are programming errors.
+    pub fn register<C: VendorSubCommand<E>>(&mut self) {
+        assert!((0x40..=0xBF).contains(&C::COMMAND));
+        assert!(self.find(C::COMMAND, C::SUB_COMMAND).is_err());
+        self.commands.push(VendorCommand {
+            command: C::COMMAND,
+            sub_command: C::SUB_COMMAND,
+            authorization: C::AUTHORIZATION,
+            process: process_typed::<E, C>,
+        });
+    }
+
+    pub fn has_command(&self, command: u8) -> bool {
+        self.commands.iter().any(|c| c.command == command)
+    }
+
+    pub fn find(&self, command: u8, sub_command: u8) -> Result<VendorCommand<E>, Ctap2StatusCode> {
+        if !self.has_command(command) {
+            return Err(Ctap2StatusCode::CTAP1_ERR_INVALID_COMMAND);
+        }
+        self.commands
+            .iter()
+            .find(|c| c.command == command && c.sub_command == sub_command)
+            .cloned()
+            .ok_or(Ctap2StatusCode::CTAP2_ERR_INVALID_SUBCOMMAND)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::command::AuthenticatorVendorParameters;
+    use super::super::data_formats::{ok_or_missing, read_map, read_text_string};
+    use super::super::hid::ChannelID;
+    use super::*;
+    use crate::env::host::HostEnv;
+    use alloc::string::String;
+    use crypto::hmac::hmac_256;
+    use crypto::sha256::Sha256;
+
+    const DUMMY_CHANNEL_ID: ChannelID = [0x12, 0x34, 0x56, 0x78];
+
+    struct TextParameters {
+        text: String,
+    }
+
+    impl TryFrom<cbor::Value> for TextParameters {
+        type Error = Ctap2StatusCode;
+
+        fn try_from(cbor_value: cbor::Value) -> Result<Self, Ctap2StatusCode> {
+            let param_map = read_map(&cbor_value)?;
+            let text = read_text_string(ok_or_missing(param_map.get(&cbor_unsigned!(1)))?)?;
+            Ok(TextParameters { text })
+        }
+    }
+
+    struct Echo;
+
+    impl<E: Env> VendorSubCommand<E> for Echo {
+        const COMMAND: u8 = 0x41;
+        const SUB_COMMAND: u8 = 0x01;
+        const AUTHORIZATION: VendorAuthorization = VendorAuthorization::None;
+        type Parameters = TextParameters;
+        type Response = String;
+
+        fn process(
+            _: &mut CtapState<E>,
+            parameters: Option<TextParameters>,
+        ) -> Result<Option<String>, Ctap2StatusCode> {
+            Ok(parameters.map(|p| p.text))
+        }
+    }
+
+    struct Confirm;
+
+    impl<E: Env> VendorSubCommand<E> for Confirm {
+        const COMMAND: u8 = 0x41;
+        const SUB_COMMAND: u8 = 0x02;
+        const AUTHORIZATION: VendorAuthorization = VendorAuthorization::UserPresence;
+        type Parameters = NoParameters;
+        type Response = cbor::Value;
+
+        fn process(
+            _: &mut CtapState<E>,
+            _: Option<NoParameters>,
+        ) -> Result<Option<cbor::Value>, Ctap2StatusCode> {
+            Ok(None)
+        }
+    }
+
+    struct Protected;
+
+    impl<E: Env> VendorSubCommand<E> for Protected {
+        const COMMAND: u8 = 0xB0;
+        const SUB_COMMAND: u8 = 0x01;
+        const AUTHORIZATION: VendorAuthorization = VendorAuthorization::PinToken;
+        type Parameters = TextParameters;
+        type Response = u64;
+
+        fn process(
+            _: &mut CtapState<E>,
+            parameters: Option<TextParameters>,
+        ) -> Result<Option<u64>, Ctap2StatusCode> {
+            Ok(parameters.map(|p| p.text.len() as u64))
+        }
+    }
+
+    fn vendor_command(
+        command: u8,
+        sub_command: u8,
+        sub_command_params: Option<cbor::Value>,
+    ) -> Vec<u8> {
+        vendor_command_with_pin_auth(command, sub_command, sub_command_params, None)
+    }
+
+    fn vendor_command_with_pin_auth(
+        command: u8,
+        sub_command: u8,
+        sub_command_params: Option<cbor::Value>,
+        pin_uv_auth_param: Option<Vec<u8>>,
+    ) -> Vec<u8> {
+        let pin_uv_auth_protocol = pin_uv_auth_param.as_ref().map(|_| 1);
+        let params = AuthenticatorVendorParameters {
+            sub_command,
+            sub_command_params,
+            pin_uv_auth_protocol,
+            pin_uv_auth_param,
+        };
+        let mut bytes = vec![command];
+        assert!(cbor::write(params.into(), &mut bytes));
+        bytes
+    }
+
+    fn create_ctap_state<E: Env>(env: E) -> CtapState<E> {
+        let mut ctap_state = CtapState::new(env);
+        ctap_state.register_vendor_command::<Echo>();
+        ctap_state.register_vendor_command::<Confirm>();
+        ctap_state.register_vendor_command::<Protected>();
+        ctap_state
+    }
+
+    #[test]
+    fn test_find() {
+        let mut registry =
+            VendorRegistry::<HostEnv<fn(ChannelID) -> Result<(), Ctap2StatusCode>>>::new();
+        registry.register::<Echo>();
+        registry.register::<Confirm>();
+        assert!(registry.has_command(0x41));
+        assert!(!registry.has_command(0x42));
+
+        let confirm = registry.find(0x41, 0x02).ok().unwrap();
+        assert_eq!(confirm.authorization, VendorAuthorization::UserPresence);
+        assert_eq!(
+            registry.find(0x41, 0x03).err(),
+            Some(Ctap2StatusCode::CTAP2_ERR_INVALID_SUBCOMMAND)
+        );
+        assert_eq!(
+            registry.find(0x42, 0x01).err(),
+            Some(Ctap2StatusCode::CTAP1_ERR_INVALID_COMMAND)
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_register_twice() {
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(HostEnv::new(user_immediately_present));
+        ctap_state.register_vendor_command::<Echo>();
+        ctap_state.register_vendor_command::<Echo>();
+    }
+
+    #[test]
+    fn test_process_vendor_command() {
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = create_ctap_state(HostEnv::new(user_immediately_present));
+
+        let parameters = cbor_map! { 1 => "ping" };
+        let command = vendor_command(0x41, 0x01, Some(parameters));
+        assert_eq!(
+            ctap_state.process_command(&command, DUMMY_CHANNEL_ID),
+            vec![0x00, 0x64, b'p', b'i', b'n', b'g']
+        );
+        let command = vendor_command(0x41, 0x01, Some(cbor_map! { 1 => 0x01 }));
+        assert_eq!(
+            ctap_state.process_command(&command, DUMMY_CHANNEL_ID),
+            vec![Ctap2StatusCode::CTAP2_ERR_CBOR_UNEXPECTED_TYPE as u8]
+        );
+
+        let command = vendor_command(0x41, 0x02, None);
+        assert_eq!(
+            ctap_state.process_command(&command, DUMMY_CHANNEL_ID),
+            vec![0x00]
+        );
+
+        let command = vendor_command(0x41, 0x02, Some(cbor_map! {}));
+        assert_eq!(
+            ctap_state.process_command(&command, DUMMY_CHANNEL_ID),
+            vec![Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER as u8]
+        );
+    }
+
+    #[test]
+    fn test_process_unknown_vendor_command() {
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = create_ctap_state(HostEnv::new(user_immediately_present));
+
+        let command = vendor_command(0x42, 0x01, None);
+        assert_eq!(
+            ctap_state.process_command(&command, DUMMY_CHANNEL_ID),
+            vec![Ctap2StatusCode::CTAP1_ERR_INVALID_COMMAND as u8]
+        );
+        let command = vendor_command(0x41, 0x03, None);
+        assert_eq!(
+            ctap_state.process_command(&command, DUMMY_CHANNEL_ID),
+            vec![Ctap2StatusCode::CTAP2_ERR_INVALID_SUBCOMMAND as u8]
+        );
+    }
+
+    #[test]
+    fn test_process_vendor_command_user_presence() {
+        let user_presence_always_cancel = |_| Err(Ctap2StatusCode::CTAP2_ERR_KEEPALIVE_CANCEL);
+        let mut ctap_state = create_ctap_state(HostEnv::new(user_presence_always_cancel));
+
+        let command = vendor_command(0x41, 0x02, None);
+        assert_eq!(
+            ctap_state.process_command(&command, DUMMY_CHANNEL_ID),
+            vec![Ctap2StatusCode::CTAP2_ERR_KEEPALIVE_CANCEL as u8]
+        );
+        // Subcommands without authorization don't ask the user.
+        let command = vendor_command(0x41, 0x01, None);
+        assert_eq!(
+            ctap_state.process_command(&command, DUMMY_CHANNEL_ID),
+            vec![0x00]
+        );
+    }
+
+    #[test]
+    fn test_process_vendor_command_pin_token() {
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = create_ctap_state(HostEnv::new(user_immediately_present));
+        let parameters = cbor_map! { 1 => "provision" };
+
+        let command = vendor_command(0xB0, 0x01, Some(parameters.clone()));
+        assert_eq!(
+            ctap_state.process_command(&command, DUMMY_CHANNEL_ID),
+            vec![Ctap2StatusCode::CTAP2_ERR_PIN_NOT_SET as u8]
+        );
+        ctap_state
+            .persistent_store
+            .set_pin_hash(&[0x88; 16], ctap_state.env.rng());
+        assert_eq!(
+            ctap_state.process_command(&command, DUMMY_CHANNEL_ID),
+            vec![Ctap2StatusCode::CTAP2_ERR_PIN_REQUIRED as u8]
+        );
+
+        let mut auth_contents = vec![0xFF; 32];
+        auth_contents.extend(&[0xB0, 0x01]);
+        assert!(cbor::write(parameters.clone(), &mut auth_contents));
+        let pin_auth = hmac_256::<Sha256>(&ctap_state.pin_uv_auth_token, &auth_contents);
+        let command = vendor_command_with_pin_auth(
+            0xB0,
+            0x01,
+            Some(parameters),
+            Some(pin_auth[..16].to_vec()),
+        );
+        assert_eq!(
+            ctap_state.process_command(&command, DUMMY_CHANNEL_ID),
+            vec![0x00, 0x09]
+        );
+
+        // The pinUvAuthParam covers the parameters.
+        let command = vendor_command_with_pin_auth(
+            0xB0,
+            0x01,
+            Some(cbor_map! { 1 => "tampered" }),
+            Some(pin_auth[..16].to_vec()),
+        );
+        assert_eq!(
+            ctap_state.process_command(&command, DUMMY_CHANNEL_ID),
+            vec![Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_INVALID as u8]
+        );
+    }
+}
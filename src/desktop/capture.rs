@@ -0,0 +1,444 @@
+// Copyright 2019 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Readers for USB captures taken with Linux usbmon, to extract the interrupt transfers that carry
+// HID reports. Three formats are supported:
+// - the usbmon text format, as read from /sys/kernel/debug/usb/usbmon/<bus>u,
+// - pcap and pcapng files with the Linux usbmon link types, as saved by Wireshark or tcpdump.
+//
+// The usbmon text format only keeps the first 32 bytes of each transfer, so the packets read from
+// it are usually truncated.
+
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+
+// Documentation of the usbmon kernel module, sections 5 (text) and 5.2 (binary).
+const USBMON_HEADER_LENGTH: usize = 48;
+const USBMON_MMAPPED_HEADER_LENGTH: usize = 64;
+const USBMON_INTERRUPT: u8 = 1;
+const USBMON_ENDPOINT_IN: u8 = 0x80;
+
+// Link types of https://www.tcpdump.org/linktypes.html
+const LINKTYPE_USB_LINUX: u32 = 189;
+const LINKTYPE_USB_LINUX_MMAPPED: u32 = 220;
+
+const PCAP_MAGIC_MICROSECONDS: u32 = 0xA1B2_C3D4;
+const PCAP_MAGIC_NANOSECONDS: u32 = 0xA1B2_3C4D;
+const PCAP_HEADER_LENGTH: usize = 24;
+const PCAP_RECORD_HEADER_LENGTH: usize = 16;
+
+const PCAPNG_SECTION_HEADER: u32 = 0x0A0D_0D0A;
+const PCAPNG_BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+const PCAPNG_INTERFACE_DESCRIPTION: u32 = 1;
+const PCAPNG_ENHANCED_PACKET: u32 = 6;
+const PCAPNG_OPTION_TSRESOL: u16 = 9;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Direction {
+    HostToDevice,
+    DeviceToHost,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct CapturedPacket {
+    pub time_us: u64,
+    pub bus: u16,
+    pub device: u8,
+    pub direction: Direction,
+    // Length of the transfer, which may be more than the captured data.
+    pub length: usize,
+    pub data: Vec<u8>,
+}
+
+// Returns the interrupt transfers of the capture that carry data, in order.
+pub fn parse_capture(bytes: &[u8]) -> Result<Vec<CapturedPacket>, String> {
+    if bytes.len() >= 4 {
+        let magic = LittleEndian::read_u32(bytes);
+        if magic == PCAPNG_SECTION_HEADER {
+            return parse_pcapng(bytes);
+        }
+        for &magic in &[magic, magic.swap_bytes()] {
+            if magic == PCAP_MAGIC_MICROSECONDS || magic == PCAP_MAGIC_NANOSECONDS {
+                return parse_pcap(bytes);
+            }
+        }
+    }
+    match std::str::from_utf8(bytes) {
+        Ok(text) => parse_usbmon_text(text),
+        Err(_) => Err("unknown capture format".to_string()),
+    }
+}
+
+// Each line is an event: tag, timestamp, event type, address, status, length and data, e.g.
+// ffff8d2a7e8b5e40 3573214566 S Io:1:004:2 -115:8 64 = 01000000 86000800 ...
+// Older kernels omit the bus in the address of the 0u file, which we don't support.
+fn parse_usbmon_text(text: &str) -> Result<Vec<CapturedPacket>, String> {
+    let mut packets = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let invalid = || format!("invalid usbmon line {}", i + 1);
+        let words: Vec<&str> = line.split_whitespace().collect();
+        if words.is_empty() {
+            continue;
+        }
+        if words.len() < 6 {
+            return Err(invalid());
+        }
+        let time_us = words[1].parse::<u64>().map_err(|_| invalid())?;
+        let address: Vec<&str> = words[3].split(':').collect();
+        if address.len() != 4 {
+            return Err(invalid());
+        }
+        let direction = match (words[2], address[0]) {
+            ("S", "Io") => Direction::HostToDevice,
+            ("C", "Ii") => Direction::DeviceToHost,
+            _ => continue,
+        };
+        let bus = address[1].parse::<u16>().map_err(|_| invalid())?;
+        let device = address[2].parse::<u8>().map_err(|_| invalid())?;
+        let length = words[5].parse::<usize>().map_err(|_| invalid())?;
+        // Without the = data tag, no data was captured.
+        if words.get(6) != Some(&"=") {
+            continue;
+        }
+        let mut data = Vec::new();
+        for word in &words[7..] {
+            if word.len() % 2 != 0 {
+                return Err(invalid());
+            }
+            for j in (0..word.len()).step_by(2) {
+                let byte = word
+                    .get(j..j + 2)
+                    .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                    .ok_or_else(invalid)?;
+                data.push(byte);
+            }
+        }
+        packets.push(CapturedPacket {
+            time_us,
+            bus,
+            device,
+            direction,
+            length,
+            data,
+        });
+    }
+    Ok(packets)
+}
+
+// Reads integers in the byte order of a capture file.
+#[derive(Clone, Copy)]
+struct Endian {
+    big: bool,
+}
+
+impl Endian {
+    fn u16(self, bytes: &[u8]) -> u16 {
+        if self.big {
+            BigEndian::read_u16(bytes)
+        } else {
+            LittleEndian::read_u16(bytes)
+        }
+    }
+
+    fn u32(self, bytes: &[u8]) -> u32 {
+        if self.big {
+            BigEndian::read_u32(bytes)
+        } else {
+            LittleEndian::read_u32(bytes)
+        }
+    }
+}
+
+// Extracts the packet from a usbmon record, see struct usbmon_packet in the kernel documentation.
+// The header is in the byte order of the capturing host, which is the one of the capture file.
+fn parse_usbmon_record(
+    record: &[u8],
+    header_length: usize,
+    time_us: u64,
+    endian: Endian,
+) -> Result<Option<CapturedPacket>, String> {
+    if record.len() < header_length {
+        return Err("truncated usbmon header".to_string());
+    }
+    let event_type = record[8];
+    let transfer_type = record[9];
+    let endpoint = record[10];
+    // The data flag is 0 when data is present.
+    let has_data = record[15] == 0;
+    let direction = match (event_type, endpoint & USBMON_ENDPOINT_IN) {
+        (b'S', 0) => Direction::HostToDevice,
+        (b'C', USBMON_ENDPOINT_IN) => Direction::DeviceToHost,
+        _ => return Ok(None),
+    };
+    if transfer_type != USBMON_INTERRUPT || !has_data {
+        return Ok(None);
+    }
+    let captured_length = endian.u32(&record[36..40]) as usize;
+    let data = &record[header_length..];
+    Ok(Some(CapturedPacket {
+        time_us,
+        bus: endian.u16(&record[12..14]),
+        device: record[11],
+        direction,
+        length: endian.u32(&record[32..36]) as usize,
+        data: data[..core::cmp::min(captured_length, data.len())].to_vec(),
+    }))
+}
+
+fn usbmon_header_length(link_type: u32) -> Result<usize, String> {
+    match link_type {
+        LINKTYPE_USB_LINUX => Ok(USBMON_HEADER_LENGTH),
+        LINKTYPE_USB_LINUX_MMAPPED => Ok(USBMON_MMAPPED_HEADER_LENGTH),
+        _ => Err(format!(
+            "link type {} is not a Linux usbmon capture",
+            link_type
+        )),
+    }
+}
+
+// https://wiki.wireshark.org/Development/LibpcapFileFormat
+fn parse_pcap(bytes: &[u8]) -> Result<Vec<CapturedPacket>, String> {
+    if bytes.len() < PCAP_HEADER_LENGTH {
+        return Err("truncated pcap header".to_string());
+    }
+    let mut endian = Endian { big: false };
+    let mut magic = endian.u32(bytes);
+    if magic != PCAP_MAGIC_MICROSECONDS && magic != PCAP_MAGIC_NANOSECONDS {
+        endian.big = true;
+        magic = endian.u32(bytes);
+    }
+    let nanoseconds = magic == PCAP_MAGIC_NANOSECONDS;
+    let header_length = usbmon_header_length(endian.u32(&bytes[20..24]))?;
+
+    let mut packets = Vec::new();
+    let mut rest = &bytes[PCAP_HEADER_LENGTH..];
+    while !rest.is_empty() {
+        if rest.len() < PCAP_RECORD_HEADER_LENGTH {
+            return Err("truncated pcap record".to_string());
+        }
+        let seconds = endian.u32(&rest[0..4]) as u64;
+        let mut fraction = endian.u32(&rest[4..8]) as u64;
+        if nanoseconds {
+            fraction /= 1000;
+        }
+        let captured_length = endian.u32(&rest[8..12]) as usize;
+        let record = rest[PCAP_RECORD_HEADER_LENGTH..]
+            .get(..captured_length)
+            .ok_or_else(|| "truncated pcap record".to_string())?;
+        let time_us = seconds * 1_000_000 + fraction;
+        if let Some(packet) = parse_usbmon_record(record, header_length, time_us, endian)? {
+            packets.push(packet);
+        }
+        rest = &rest[PCAP_RECORD_HEADER_LENGTH + captured_length..];
+    }
+    Ok(packets)
+}
+
+// https://www.ietf.org/archive/id/draft-tuexen-opsawg-pcapng-02.html
+// Only enhanced packet blocks are read, the other blocks are skipped.
+fn parse_pcapng(bytes: &[u8]) -> Result<Vec<CapturedPacket>, String> {
+    let truncated = || "truncated pcapng block".to_string();
+    let mut endian = Endian { big: false };
+    // For each interface of the current section, the usbmon header length and the number of
+    // timestamp units per second.
+    let mut interfaces: Vec<(Result<usize, String>, u64)> = Vec::new();
+    let mut packets = Vec::new();
+    let mut rest = bytes;
+    while !rest.is_empty() {
+        if rest.len() < 12 {
+            return Err(truncated());
+        }
+        let block_type = endian.u32(&rest[0..4]);
+        if block_type == PCAPNG_SECTION_HEADER {
+            endian.big = BigEndian::read_u32(&rest[8..12]) == PCAPNG_BYTE_ORDER_MAGIC;
+            interfaces.clear();
+        }
+        let block_length = endian.u32(&rest[4..8]) as usize;
+        if block_length < 12 || block_length % 4 != 0 || rest.len() < block_length {
+            return Err(truncated());
+        }
+        let body = &rest[8..block_length - 4];
+        match block_type {
+            PCAPNG_INTERFACE_DESCRIPTION => {
+                if body.len() < 8 {
+                    return Err(truncated());
+                }
+                let link_type = endian.u16(&body[0..2]) as u32;
+                let units_per_second = parse_tsresol(&body[8..], endian)?;
+                interfaces.push((usbmon_header_length(link_type), units_per_second));
+            }
+            PCAPNG_ENHANCED_PACKET => {
+                if body.len() < 20 {
+                    return Err(truncated());
+                }
+                let (header_length, units_per_second) = interfaces
+                    .get(endian.u32(&body[0..4]) as usize)
+                    .ok_or_else(|| "packet of an unknown pcapng interface".to_string())?;
+                let header_length = header_length.clone()?;
+                let timestamp =
+                    (endian.u32(&body[4..8]) as u64) << 32 | endian.u32(&body[8..12]) as u64;
+                let time_us = (timestamp as u128 * 1_000_000 / *units_per_second as u128) as u64;
+                let captured_length = endian.u32(&body[12..16]) as usize;
+                let record = body[20..].get(..captured_length).ok_or_else(truncated)?;
+                if let Some(packet) = parse_usbmon_record(record, header_length, time_us, endian)? {
+                    packets.push(packet);
+                }
+            }
+            _ => (),
+        }
+        rest = &rest[block_length..];
+    }
+    Ok(packets)
+}
+
+// Returns the timestamp resolution of an interface from its options, microseconds by default.
+fn parse_tsresol(mut options: &[u8], endian: Endian) -> Result<u64, String> {
+    let mut units_per_second = 1_000_000;
+    while options.len() >= 4 {
+        let code = endian.u16(&options[0..2]);
+        let length = endian.u16(&options[2..4]) as usize;
+        let padded_length = (length + 3) / 4 * 4;
+        let value = options
+            .get(4..4 + length)
+            .ok_or_else(|| "truncated pcapng option".to_string())?;
+        if code == PCAPNG_OPTION_TSRESOL && length == 1 {
+            // The high bit selects a power of 2 instead of a power of 10.
+            let exponent = (value[0] & 0x7F) as u32;
+            let base: u64 = if value[0] & 0x80 == 0 { 10 } else { 2 };
+            units_per_second = base
+                .checked_pow(exponent)
+                .ok_or_else(|| "unsupported pcapng timestamp resolution".to_string())?;
+        }
+        options = options.get(4 + padded_length..).unwrap_or(&[]);
+    }
+    Ok(units_per_second)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn usbmon_record(event_type: u8, endpoint: u8, data: &[u8]) -> Vec<u8> {
+        let mut record = vec![0; USBMON_HEADER_LENGTH];
+        record[8] = event_type;
+        record[9] = USBMON_INTERRUPT;
+        record[10] = endpoint;
+        record[11] = 4;
+        LittleEndian::write_u16(&mut record[12..14], 1);
+        LittleEndian::write_u32(&mut record[32..36], data.len() as u32);
+        LittleEndian::write_u32(&mut record[36..40], data.len() as u32);
+        record.extend_from_slice(data);
+        record
+    }
+
+    fn expected_packets() -> Vec<CapturedPacket> {
+        vec![
+            CapturedPacket {
+                time_us: 1_000_002,
+                bus: 1,
+                device: 4,
+                direction: Direction::HostToDevice,
+                length: 3,
+                data: vec![0x01, 0x02, 0x03],
+            },
+            CapturedPacket {
+                time_us: 1_000_005,
+                bus: 1,
+                device: 4,
+                direction: Direction::DeviceToHost,
+                length: 2,
+                data: vec![0x04, 0x05],
+            },
+        ]
+    }
+
+    #[test]
+    fn test_parse_usbmon_text() {
+        let text = "\
+ffff8d2a7e8b5e40 1000001 S Ii:1:004:1 -115:8 64 <
+ffff8d2a7e8b5e40 1000002 S Io:1:004:2 -115:8 3 = 010203
+ffff8d2a7e8b5e40 1000003 C Io:1:004:2 0:8 3 >
+ffff8d2a7e8b5e40 1000005 C Ii:1:004:1 0:8 2 = 0405
+";
+        assert_eq!(parse_capture(text.as_bytes()), Ok(expected_packets()));
+        assert_eq!(
+            parse_capture(b"ffff8d2a7e8b5e40 1000002 S Io:1:004 -115:8 3 = 010203"),
+            Err("invalid usbmon line 1".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_pcap() {
+        let mut pcap = Vec::new();
+        pcap.extend_from_slice(&PCAP_MAGIC_MICROSECONDS.to_le_bytes());
+        pcap.extend_from_slice(&[2, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 4, 0]);
+        pcap.extend_from_slice(&LINKTYPE_USB_LINUX.to_le_bytes());
+        // The submission of the IN transfer carries no data.
+        let records = [
+            (2, usbmon_record(b'S', 0x01, &[0x01, 0x02, 0x03])),
+            (3, usbmon_record(b'S', 0x81, &[])),
+            (5, usbmon_record(b'C', 0x81, &[0x04, 0x05])),
+        ];
+        for (microseconds, record) in records.iter() {
+            pcap.extend_from_slice(&1u32.to_le_bytes());
+            pcap.extend_from_slice(&(*microseconds as u32).to_le_bytes());
+            pcap.extend_from_slice(&(record.len() as u32).to_le_bytes());
+            pcap.extend_from_slice(&(record.len() as u32).to_le_bytes());
+            pcap.extend_from_slice(record);
+        }
+        assert_eq!(parse_capture(&pcap), Ok(expected_packets()));
+
+        pcap[20] = 1;
+        assert_eq!(
+            parse_capture(&pcap),
+            Err("link type 1 is not a Linux usbmon capture".to_string())
+        );
+    }
+
+    fn pcapng_block(block_type: u32, body: &[u8]) -> Vec<u8> {
+        let length = (12 + body.len()) as u32;
+        let mut block = Vec::new();
+        block.extend_from_slice(&block_type.to_le_bytes());
+        block.extend_from_slice(&length.to_le_bytes());
+        block.extend_from_slice(body);
+        block.extend_from_slice(&length.to_le_bytes());
+        block
+    }
+
+    #[test]
+    fn test_parse_pcapng() {
+        let mut section = PCAPNG_BYTE_ORDER_MAGIC.to_le_bytes().to_vec();
+        section.extend_from_slice(&[1, 0, 0, 0, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]);
+        let mut pcapng = pcapng_block(PCAPNG_SECTION_HEADER, &section);
+        // Nanosecond timestamps, with the if_tsresol option.
+        let mut interface = vec![LINKTYPE_USB_LINUX as u8, 0, 0, 0, 0, 0, 0, 0];
+        interface.extend_from_slice(&[9, 0, 1, 0, 9, 0, 0, 0, 0, 0, 0, 0]);
+        pcapng.extend_from_slice(&pcapng_block(PCAPNG_INTERFACE_DESCRIPTION, &interface));
+        let records = [
+            (2, usbmon_record(b'S', 0x01, &[0x01, 0x02, 0x03])),
+            (5, usbmon_record(b'C', 0x81, &[0x04, 0x05])),
+        ];
+        for (microseconds, record) in records.iter() {
+            let timestamp = (1_000_000 + *microseconds as u64) * 1000;
+            let mut body = vec![0; 4];
+            body.extend_from_slice(&((timestamp >> 32) as u32).to_le_bytes());
+            body.extend_from_slice(&(timestamp as u32).to_le_bytes());
+            body.extend_from_slice(&(record.len() as u32).to_le_bytes());
+            body.extend_from_slice(&(record.len() as u32).to_le_bytes());
+            body.extend_from_slice(record);
+            body.resize((body.len() + 3) / 4 * 4, 0);
+            pcapng.extend_from_slice(&pcapng_block(PCAPNG_ENHANCED_PACKET, &body));
+        }
+        assert_eq!(parse_capture(&pcapng), Ok(expected_packets()));
+    }
+}
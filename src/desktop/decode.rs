@@ -0,0 +1,700 @@
+// Copyright 2019 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Reassembles the CTAPHID messages of a USB capture and describes the CTAP2 and U2F requests and
+// responses they carry, using the same parsers as the authenticator.
+
+use super::capture::{CapturedPacket, Direction};
+use crate::ctap::apdu::{ApduCommand, ApduStatusCode};
+use crate::ctap::command::Command;
+use crate::ctap::hid::receive::MessageAssembler;
+use crate::ctap::hid::{ChannelID, CtapHid, HidPacket, Message};
+use crate::ctap::response::{
+    AuthenticatorClientPinResponse, AuthenticatorGetAssertionResponse,
+    AuthenticatorGetInfoResponse, AuthenticatorMakeCredentialResponse,
+};
+use crate::ctap::status_code::Ctap2StatusCode;
+use crate::timer::Timestamp;
+use core::convert::TryFrom;
+use core::fmt::{Debug, Write};
+use std::collections::BTreeMap;
+
+// CTAP specification (version 20190130) section 8.1.4
+const INIT_HEADER_LENGTH: usize = 7;
+const CONT_HEADER_LENGTH: usize = 5;
+const INIT_PAYLOAD_LENGTH: usize = 64 - INIT_HEADER_LENGTH;
+const CONT_PAYLOAD_LENGTH: usize = 64 - CONT_HEADER_LENGTH;
+
+// CTAP specification (version 20190130) section 8.1.9.1.7
+const KEEPALIVE_STATUS_PROCESSING: u8 = 1;
+const KEEPALIVE_STATUS_UPNEEDED: u8 = 2;
+
+// U2F raw message formats (version 20170411) sections 4.1, 5.1 and 6.1
+const U2F_REGISTER: u8 = 0x01;
+const U2F_AUTHENTICATE: u8 = 0x02;
+const U2F_VERSION: u8 = 0x03;
+
+// Bus, device and channel of a message.
+type Channel = (u16, u8, ChannelID);
+
+// The request a response answers, to know how to decode it.
+#[derive(Clone, Copy)]
+enum Request {
+    MakeCredential,
+    GetAssertion,
+    GetInfo,
+    ClientPin,
+    OtherCbor,
+    U2f { ins: u8 },
+}
+
+// The payload length of a message being received, and how much of it was captured if the capture
+// truncated some of its packets.
+struct PartialMessage {
+    length: usize,
+    captured: Option<usize>,
+}
+
+#[derive(Default)]
+struct Decoder {
+    assemblers: BTreeMap<(Channel, Direction), MessageAssembler>,
+    partial_messages: BTreeMap<(Channel, Direction), PartialMessage>,
+    requests: BTreeMap<Channel, Request>,
+    start_us: Option<u64>,
+    output: String,
+}
+
+// Returns the description of the CTAPHID messages of the capture.
+pub fn decode_capture(packets: &[CapturedPacket]) -> String {
+    let mut decoder = Decoder::default();
+    for packet in packets {
+        decoder.process_packet(packet);
+    }
+    decoder.output
+}
+
+impl Decoder {
+    fn process_packet(&mut self, packet: &CapturedPacket) {
+        // CTAPHID reports are 64 bytes long, other HID devices on the bus are ignored.
+        if packet.length != 64 || packet.data.len() < INIT_HEADER_LENGTH {
+            return;
+        }
+        let start_us = *self.start_us.get_or_insert(packet.time_us);
+        let time_us = packet.time_us.saturating_sub(start_us);
+        let mut hid_packet: HidPacket = [0; 64];
+        let captured = core::cmp::min(packet.data.len(), 64);
+        hid_packet[..captured].copy_from_slice(&packet.data[..captured]);
+        let channel = (packet.bus, packet.device, *array_ref!(hid_packet, 0, 4));
+        let key = (channel, packet.direction);
+        self.track_truncation(key, &hid_packet, captured);
+
+        let timestamp = Timestamp::<isize>::from_ms((time_us / 1000) as isize);
+        let assembler = self
+            .assemblers
+            .entry(key)
+            .or_insert_with(MessageAssembler::new);
+        match assembler.parse_packet(&hid_packet, timestamp) {
+            Ok(Some(message)) => {
+                let captured = self
+                    .partial_messages
+                    .remove(&key)
+                    .and_then(|partial| partial.captured);
+                self.write_header(time_us, key, &command_name(message.cmd));
+                match captured {
+                    Some(captured) => writeln!(
+                        self.output,
+                        "    only {} of {} payload bytes were captured: {}",
+                        captured,
+                        message.payload.len(),
+                        hex(&message.payload[..captured])
+                    )
+                    .unwrap(),
+                    None => self.describe_message(channel, packet.direction, &message),
+                }
+            }
+            Ok(None) => (),
+            Err((_, error)) => {
+                self.partial_messages.remove(&key);
+                self.write_header(time_us, key, "invalid packet");
+                writeln!(self.output, "    {:?}", error).unwrap();
+            }
+        }
+    }
+
+    // Remembers which part of the payload is missing, when the capture only kept the start of a
+    // packet.
+    fn track_truncation(
+        &mut self,
+        key: (Channel, Direction),
+        hid_packet: &HidPacket,
+        captured: usize,
+    ) {
+        if hid_packet[4] & 0x80 != 0 {
+            let length = (hid_packet[5] as usize) << 8 | hid_packet[6] as usize;
+            let present = captured - INIT_HEADER_LENGTH;
+            let captured = if present < core::cmp::min(length, INIT_PAYLOAD_LENGTH) {
+                Some(present)
+            } else {
+                None
+            };
+            self.partial_messages
+                .insert(key, PartialMessage { length, captured });
+        } else if let Some(partial) = self.partial_messages.get_mut(&key) {
+            let offset = INIT_PAYLOAD_LENGTH + CONT_PAYLOAD_LENGTH * hid_packet[4] as usize;
+            let present = captured.saturating_sub(CONT_HEADER_LENGTH);
+            if partial.captured.is_none()
+                && present < CONT_PAYLOAD_LENGTH
+                && offset + present < partial.length
+            {
+                partial.captured = Some(offset + present);
+            }
+        }
+    }
+
+    fn write_header(&mut self, time_us: u64, key: (Channel, Direction), what: &str) {
+        let ((bus, device, cid), direction) = key;
+        let arrow = match direction {
+            Direction::HostToDevice => "host -> key",
+            Direction::DeviceToHost => "key -> host",
+        };
+        writeln!(
+            self.output,
+            "{}.{:06} {}:{:03} {} channel {} {}",
+            time_us / 1_000_000,
+            time_us % 1_000_000,
+            bus,
+            device,
+            arrow,
+            hex(&cid),
+            what
+        )
+        .unwrap();
+    }
+
+    fn write_field(&mut self, name: &str, value: impl core::fmt::Display) {
+        writeln!(self.output, "    {}: {}", name, value).unwrap();
+    }
+
+    fn write_debug(&mut self, value: &impl Debug) {
+        for line in compact_byte_lists(&format!("{:#?}", value)).lines() {
+            writeln!(self.output, "    {}", line).unwrap();
+        }
+    }
+
+    fn describe_message(&mut self, channel: Channel, direction: Direction, message: &Message) {
+        let payload = &message.payload;
+        match (message.cmd, direction) {
+            (CtapHid::COMMAND_INIT, Direction::HostToDevice) => {
+                self.write_field("nonce", hex(payload));
+            }
+            (CtapHid::COMMAND_INIT, Direction::DeviceToHost) if payload.len() >= 17 => {
+                self.write_field("nonce", hex(&payload[..8]));
+                self.write_field("channel", hex(&payload[8..12]));
+                self.write_field("protocol version", payload[12]);
+                self.write_field(
+                    "device version",
+                    format!("{}.{}.{}", payload[13], payload[14], payload[15]),
+                );
+                self.write_field("capabilities", capability_names(payload[16]));
+            }
+            (CtapHid::COMMAND_PING, _) => self.write_field("data", hex(payload)),
+            (CtapHid::COMMAND_LOCK, _) if payload.len() == 1 => {
+                self.write_field("seconds", payload[0]);
+            }
+            (CtapHid::COMMAND_WINK, _) | (CtapHid::COMMAND_CANCEL, _) if payload.is_empty() => (),
+            (CtapHid::COMMAND_KEEPALIVE, _) if payload.len() == 1 => {
+                let status = match payload[0] {
+                    KEEPALIVE_STATUS_PROCESSING => "PROCESSING".to_string(),
+                    KEEPALIVE_STATUS_UPNEEDED => "UPNEEDED".to_string(),
+                    status => format!("0x{:02X}", status),
+                };
+                self.write_field("status", status);
+            }
+            (CtapHid::COMMAND_ERROR, _) if payload.len() == 1 => {
+                self.write_field("error", error_name(payload[0]));
+            }
+            (CtapHid::COMMAND_CBOR, Direction::HostToDevice) => {
+                self.describe_cbor_request(channel, payload)
+            }
+            (CtapHid::COMMAND_CBOR, Direction::DeviceToHost) => {
+                self.describe_cbor_response(channel, payload)
+            }
+            (CtapHid::COMMAND_MSG, Direction::HostToDevice) => {
+                self.describe_u2f_request(channel, payload)
+            }
+            (CtapHid::COMMAND_MSG, Direction::DeviceToHost) => {
+                self.describe_u2f_response(channel, payload)
+            }
+            _ => self.write_field("payload", hex(payload)),
+        }
+    }
+
+    fn describe_cbor_request(&mut self, channel: Channel, payload: &[u8]) {
+        match Command::deserialize(payload) {
+            Ok(command) => {
+                let request = match command {
+                    Command::AuthenticatorMakeCredential(_) => Request::MakeCredential,
+                    Command::AuthenticatorGetAssertion(_)
+                    | Command::AuthenticatorGetNextAssertion => Request::GetAssertion,
+                    Command::AuthenticatorGetInfo => Request::GetInfo,
+                    Command::AuthenticatorClientPin(_) => Request::ClientPin,
+                    _ => Request::OtherCbor,
+                };
+                self.requests.insert(channel, request);
+                self.write_debug(&command);
+            }
+            Err(status) => {
+                self.requests.insert(channel, Request::OtherCbor);
+                self.write_field("invalid command", format!("{:?}", status));
+                self.write_field("payload", hex(payload));
+            }
+        }
+    }
+
+    fn describe_cbor_response(&mut self, channel: Channel, payload: &[u8]) {
+        let status = match payload.first() {
+            Some(&status) => status,
+            None => return self.write_field("payload", "empty"),
+        };
+        self.write_field("status", status_name(status));
+        if payload.len() == 1 {
+            return;
+        }
+        let value = match cbor::read(&payload[1..]) {
+            Ok(value) => value,
+            Err(_) => return self.write_field("invalid CBOR", hex(&payload[1..])),
+        };
+        // Decoding with the wrong type fails, in which case the CBOR value is shown as is.
+        let decoded = match self.requests.get(&channel).copied() {
+            Some(Request::MakeCredential) => {
+                decode_response::<AuthenticatorMakeCredentialResponse>(value.clone())
+            }
+            Some(Request::GetAssertion) => {
+                decode_response::<AuthenticatorGetAssertionResponse>(value.clone())
+            }
+            Some(Request::GetInfo) => {
+                decode_response::<AuthenticatorGetInfoResponse>(value.clone())
+            }
+            Some(Request::ClientPin) => {
+                decode_response::<AuthenticatorClientPinResponse>(value.clone())
+            }
+            _ => None,
+        };
+        match decoded {
+            Some(decoded) => {
+                for line in decoded.lines() {
+                    writeln!(self.output, "    {}", line).unwrap();
+                }
+            }
+            None => self.write_debug(&value),
+        }
+    }
+
+    fn describe_u2f_request(&mut self, channel: Channel, payload: &[u8]) {
+        let apdu = match ApduCommand::try_from(payload) {
+            Ok(apdu) => apdu,
+            Err(_) => return self.write_field("invalid APDU", hex(payload)),
+        };
+        self.requests
+            .insert(channel, Request::U2f { ins: apdu.ins });
+        let data = &apdu.data;
+        match apdu.ins {
+            U2F_REGISTER if data.len() == 64 => {
+                self.write_field("instruction", "U2F_REGISTER");
+                self.write_field("challenge", hex(&data[..32]));
+                self.write_field("application", hex(&data[32..]));
+            }
+            U2F_AUTHENTICATE if data.len() > 65 && data.len() == 65 + data[64] as usize => {
+                let control = match apdu.p1 {
+                    0x03 => "enforce-user-presence-and-sign".to_string(),
+                    0x07 => "check-only".to_string(),
+                    0x08 => "dont-enforce-user-presence-and-sign".to_string(),
+                    p1 => format!("0x{:02X}", p1),
+                };
+                self.write_field("instruction", "U2F_AUTHENTICATE");
+                self.write_field("control", control);
+                self.write_field("challenge", hex(&data[..32]));
+                self.write_field("application", hex(&data[32..64]));
+                self.write_field("key handle", hex(&data[65..]));
+            }
+            U2F_VERSION => self.write_field("instruction", "U2F_VERSION"),
+            _ => {
+                self.write_field(
+                    "APDU",
+                    format!(
+                        "CLA 0x{:02X} INS 0x{:02X} P1 0x{:02X} P2 0x{:02X}",
+                        apdu.cla, apdu.ins, apdu.p1, apdu.p2
+                    ),
+                );
+                self.write_field("data", hex(data));
+            }
+        }
+    }
+
+    fn describe_u2f_response(&mut self, channel: Channel, payload: &[u8]) {
+        if payload.len() < 2 {
+            return self.write_field("invalid response", hex(payload));
+        }
+        let (data, status) = payload.split_at(payload.len() - 2);
+        let status = (status[0] as u16) << 8 | status[1] as u16;
+        match ApduStatusCode::try_from(status) {
+            Ok(name) => self.write_field("status", format!("{:?}", name)),
+            Err(()) => self.write_field("status", format!("0x{:04X}", status)),
+        }
+        if data.is_empty() {
+            return;
+        }
+        match self.requests.get(&channel).copied() {
+            // U2F raw message formats (version 20170411) section 4.3
+            Some(Request::U2f { ins: U2F_REGISTER }) if data.len() > 67 && data[0] == 0x05 => {
+                let key_handle_end = 67 + data[66] as usize;
+                let certificate_end = data
+                    .get(key_handle_end..)
+                    .and_then(der_length)
+                    .map(|length| key_handle_end + length);
+                match certificate_end {
+                    Some(certificate_end) if certificate_end <= data.len() => {
+                        self.write_field("public key", hex(&data[1..66]));
+                        self.write_field("key handle", hex(&data[67..key_handle_end]));
+                        self.write_field(
+                            "certificate",
+                            hex(&data[key_handle_end..certificate_end]),
+                        );
+                        self.write_field("signature", hex(&data[certificate_end..]));
+                    }
+                    _ => self.write_field("data", hex(data)),
+                }
+            }
+            // U2F raw message formats (version 20170411) section 5.4
+            Some(Request::U2f {
+                ins: U2F_AUTHENTICATE,
+            }) if data.len() > 5 => {
+                self.write_field("user presence", data[0]);
+                self.write_field("counter", u32::from_be_bytes(*array_ref!(data, 1, 4)));
+                self.write_field("signature", hex(&data[5..]));
+            }
+            Some(Request::U2f { ins: U2F_VERSION }) => {
+                self.write_field("version", String::from_utf8_lossy(data));
+            }
+            _ => self.write_field("data", hex(data)),
+        }
+    }
+}
+
+fn decode_response<T>(value: cbor::Value) -> Option<String>
+where
+    T: TryFrom<cbor::Value, Error = Ctap2StatusCode> + Debug,
+{
+    T::try_from(value)
+        .ok()
+        .map(|response| compact_byte_lists(&format!("{:#?}", response)))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// Returns the length of the DER element at the start of bytes, to split the attestation
+// certificate from the signature.
+fn der_length(bytes: &[u8]) -> Option<usize> {
+    match *bytes.get(1)? {
+        length if length < 0x80 => Some(2 + length as usize),
+        0x81 => Some(3 + *bytes.get(2)? as usize),
+        0x82 => Some(4 + ((*bytes.get(2)? as usize) << 8 | *bytes.get(3)? as usize)),
+        _ => None,
+    }
+}
+
+fn command_name(cmd: u8) -> String {
+    match cmd {
+        CtapHid::COMMAND_PING => "PING".to_string(),
+        CtapHid::COMMAND_MSG => "MSG".to_string(),
+        CtapHid::COMMAND_LOCK => "LOCK".to_string(),
+        CtapHid::COMMAND_INIT => "INIT".to_string(),
+        CtapHid::COMMAND_WINK => "WINK".to_string(),
+        CtapHid::COMMAND_CBOR => "CBOR".to_string(),
+        CtapHid::COMMAND_CANCEL => "CANCEL".to_string(),
+        CtapHid::COMMAND_KEEPALIVE => "KEEPALIVE".to_string(),
+        CtapHid::COMMAND_ERROR => "ERROR".to_string(),
+        CtapHid::COMMAND_VENDOR_FIRST..=CtapHid::COMMAND_VENDOR_LAST => {
+            format!("vendor command 0x{:02X}", cmd)
+        }
+        _ => format!("unknown command 0x{:02X}", cmd),
+    }
+}
+
+fn error_name(error: u8) -> String {
+    match error {
+        CtapHid::ERR_INVALID_CMD => "ERR_INVALID_CMD".to_string(),
+        CtapHid::ERR_INVALID_PAR => "ERR_INVALID_PAR".to_string(),
+        CtapHid::ERR_INVALID_LEN => "ERR_INVALID_LEN".to_string(),
+        CtapHid::ERR_INVALID_SEQ => "ERR_INVALID_SEQ".to_string(),
+        CtapHid::ERR_MSG_TIMEOUT => "ERR_MSG_TIMEOUT".to_string(),
+        CtapHid::ERR_CHANNEL_BUSY => "ERR_CHANNEL_BUSY".to_string(),
+        CtapHid::ERR_LOCK_REQUIRED => "ERR_LOCK_REQUIRED".to_string(),
+        CtapHid::ERR_INVALID_CHANNEL => "ERR_INVALID_CHANNEL".to_string(),
+        CtapHid::ERR_OTHER => "ERR_OTHER".to_string(),
+        _ => format!("0x{:02X}", error),
+    }
+}
+
+fn capability_names(capabilities: u8) -> String {
+    let names: Vec<&str> = [
+        (CtapHid::CAPABILITY_WINK, "WINK"),
+        (CtapHid::CAPABILITY_CBOR, "CBOR"),
+        (CtapHid::CAPABILITY_NMSG, "NMSG"),
+    ]
+    .iter()
+    .filter(|(bit, _)| capabilities & bit != 0)
+    .map(|&(_, name)| name)
+    .collect();
+    format!("0x{:02X} {}", capabilities, names.join(" "))
+}
+
+fn status_name(status: u8) -> String {
+    match Ctap2StatusCode::try_from(status) {
+        Ok(status_code) => format!("{:?}", status_code),
+        Err(()) => format!("0x{:02X}", status),
+    }
+}
+
+// Pretty-printed Debug output puts each byte of a byte string on its own line. Lists of at least
+// 4 bytes are joined into a hexadecimal string instead, written h'...' like in CBOR diagnostics.
+fn compact_byte_lists(text: &str) -> String {
+    let lines: Vec<&str> = text.lines().collect();
+    let mut result = String::new();
+    let mut i = 0;
+    while i < lines.len() {
+        let line = lines[i];
+        i += 1;
+        if line.ends_with('[') {
+            let mut bytes = Vec::new();
+            let mut end = i;
+            while let Some(byte) = lines.get(end).and_then(|line| parse_list_byte(line)) {
+                bytes.push(byte);
+                end += 1;
+            }
+            if let Some(closing) = lines.get(end) {
+                let closing = closing.trim_start();
+                if bytes.len() >= 4 && closing.starts_with(']') {
+                    let prefix = &line[..line.len() - 1];
+                    writeln!(result, "{}h'{}'{}", prefix, hex(&bytes), &closing[1..]).unwrap();
+                    i = end + 1;
+                    continue;
+                }
+            }
+        }
+        writeln!(result, "{}", line).unwrap();
+    }
+    result
+}
+
+fn parse_list_byte(line: &str) -> Option<u8> {
+    let line = line.trim();
+    if line.ends_with(',') {
+        line[..line.len() - 1].parse().ok()
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ctap::CtapState;
+    use crate::env::host::HostEnv;
+    use crate::timer::ClockValue;
+
+    // The authenticator allocates channels incrementally, in native endianness.
+    fn cid() -> ChannelID {
+        1u32.to_ne_bytes()
+    }
+
+    fn init_packet(cid: ChannelID, cmd: u8, length: usize, payload: &[u8]) -> HidPacket {
+        let mut packet = [0; 64];
+        packet[..4].copy_from_slice(&cid);
+        packet[4] = 0x80 | cmd;
+        packet[5] = (length >> 8) as u8;
+        packet[6] = length as u8;
+        packet[7..7 + payload.len()].copy_from_slice(payload);
+        packet
+    }
+
+    fn captured(direction: Direction, time_us: u64, data: &[u8]) -> CapturedPacket {
+        CapturedPacket {
+            time_us,
+            bus: 1,
+            device: 4,
+            direction,
+            length: 64,
+            data: data.to_vec(),
+        }
+    }
+
+    // Captures the packets exchanged with the authenticator for the given requests.
+    fn capture_session(requests: &[HidPacket]) -> Vec<CapturedPacket> {
+        let mut ctap_state = CtapState::new(HostEnv::new(|_| Ok(())));
+        let mut ctap_hid = CtapHid::new();
+        let mut packets = Vec::new();
+        for (i, request) in requests.iter().enumerate() {
+            let time_us = 1_000 * i as u64;
+            packets.push(captured(Direction::HostToDevice, time_us, request));
+            let now = ClockValue::new(i as isize, 1000);
+            for reply in ctap_hid.process_hid_packet(request, now, &mut ctap_state) {
+                packets.push(captured(Direction::DeviceToHost, time_us + 500, &reply));
+            }
+        }
+        packets
+    }
+
+    #[test]
+    fn test_decode_ctap2_session() {
+        let nonce = [0x12, 0x34, 0x56, 0x78, 0x9A, 0xBC, 0xDE, 0xF0];
+        let packets = capture_session(&[
+            init_packet(CtapHid::CHANNEL_BROADCAST, CtapHid::COMMAND_INIT, 8, &nonce),
+            init_packet(cid(), CtapHid::COMMAND_CBOR, 1, &[0x04]),
+            // ClientPin getRetries
+            init_packet(
+                cid(),
+                CtapHid::COMMAND_CBOR,
+                6,
+                &[0x06, 0xA2, 0x01, 0x01, 0x02, 0x01],
+            ),
+        ]);
+        let output = decode_capture(&packets);
+        assert!(output.starts_with(
+            "0.000000 1:004 host -> key channel ffffffff INIT\n    nonce: 123456789abcdef0\n"
+        ));
+        assert!(output.contains("0.000500 1:004 key -> host channel ffffffff INIT\n"));
+        assert!(output.contains(&format!("    channel: {}\n", hex(&cid()))));
+        assert!(output.contains(&format!(
+            "0.001000 1:004 host -> key channel {} CBOR\n",
+            hex(&cid())
+        )));
+        assert!(output.contains("    AuthenticatorGetInfo\n"));
+        assert!(output.contains("    status: CTAP2_OK\n"));
+        assert!(output.contains("    AuthenticatorGetInfoResponse {\n"));
+        assert!(output.contains("aaguid: h'"));
+        assert!(output.contains("sub_command: GetPinRetries,"));
+        assert!(output.contains("retries: Some(\n"));
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        let packets = capture_session(&[
+            init_packet(cid(), CtapHid::COMMAND_CBOR, 1, &[0x04]),
+            init_packet(
+                CtapHid::CHANNEL_BROADCAST,
+                CtapHid::COMMAND_CBOR,
+                1,
+                &[0x42],
+            ),
+        ]);
+        let output = decode_capture(&packets);
+        assert!(output.contains(&format!(
+            "0.000500 1:004 key -> host channel {} ERROR\n    error: ERR_INVALID_CHANNEL\n",
+            hex(&cid())
+        )));
+
+        let mut continuation = [0; 64];
+        continuation[..4].copy_from_slice(&cid());
+        let packets = [captured(Direction::HostToDevice, 0, &continuation)];
+        assert_eq!(
+            decode_capture(&packets),
+            format!(
+                "0.000000 1:004 host -> key channel {} invalid packet\n    \
+                 UnexpectedContinuation\n",
+                hex(&cid())
+            )
+        );
+    }
+
+    #[test]
+    fn test_decode_truncated_capture() {
+        let mut packets = capture_session(&[
+            init_packet(
+                CtapHid::CHANNEL_BROADCAST,
+                CtapHid::COMMAND_INIT,
+                8,
+                &[0; 8],
+            ),
+            init_packet(cid(), CtapHid::COMMAND_CBOR, 1, &[0x04]),
+        ]);
+        // Like the usbmon text format.
+        for packet in &mut packets {
+            packet.data.truncate(32);
+        }
+        let output = decode_capture(&packets);
+        assert!(output.contains("    nonce: 0000000000000000\n"));
+        assert!(output.contains("    AuthenticatorGetInfo\n"));
+        assert!(output.contains("    only 25 of "));
+    }
+
+    #[test]
+    fn test_decode_u2f() {
+        let mut apdu = vec![0x00, U2F_REGISTER, 0x00, 0x00, 0x00, 0x00, 64];
+        apdu.extend_from_slice(&[0xCC; 32]);
+        apdu.extend_from_slice(&[0xAA; 32]);
+        apdu.extend_from_slice(&[0x00, 0x00]);
+        let mut continuation = [0; 64];
+        continuation[..4].copy_from_slice(&cid());
+        continuation[5..5 + apdu.len() - 57].copy_from_slice(&apdu[57..]);
+        let response = [0x69, 0x85];
+        let packets = [
+            captured(
+                Direction::HostToDevice,
+                0,
+                &init_packet(cid(), CtapHid::COMMAND_MSG, apdu.len(), &apdu[..57]),
+            ),
+            captured(Direction::HostToDevice, 10, &continuation),
+            captured(
+                Direction::DeviceToHost,
+                20,
+                &init_packet(cid(), CtapHid::COMMAND_MSG, 2, &response),
+            ),
+        ];
+        assert_eq!(
+            decode_capture(&packets),
+            format!(
+                "0.000010 1:004 host -> key channel {0} MSG\n    \
+                 instruction: U2F_REGISTER\n    \
+                 challenge: {1}\n    \
+                 application: {2}\n\
+                 0.000020 1:004 key -> host channel {0} MSG\n    \
+                 status: SW_CONDITIONS_NOT_SATISFIED\n",
+                hex(&cid()),
+                "cc".repeat(32),
+                "aa".repeat(32)
+            )
+        );
+    }
+
+    #[test]
+    fn test_compact_byte_lists() {
+        let value = (vec![1u8, 2, 3, 0xFF], vec![1u8], Vec::<u8>::new());
+        assert_eq!(
+            compact_byte_lists(&format!("{:#?}", value)),
+            "(\n    h'010203ff',\n    [\n        1,\n    ],\n    [],\n)\n"
+        );
+    }
+
+    #[test]
+    fn test_der_length() {
+        assert_eq!(der_length(&[0x30, 0x03, 0x02, 0x01, 0x00]), Some(5));
+        assert_eq!(der_length(&[0x30, 0x81, 0x80]), Some(0x83));
+        assert_eq!(der_length(&[0x30, 0x82, 0x01, 0x00]), Some(0x104));
+        assert_eq!(der_length(&[0x30, 0x83]), None);
+        assert_eq!(der_length(&[0x30]), None);
+    }
+}
@@ -0,0 +1,744 @@
+// Copyright 2019 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// The desktop build runs the authenticator as a process on the host, with the same CTAP and
+// CTAPHID logic as the firmware. HID reports are exchanged over a socket or a UHID device. The
+// transports built with their feature, like NFC, exchange their messages over a socket.
+
+#[cfg(feature = "debug_ctap")]
+mod capture;
+#[cfg(feature = "debug_ctap")]
+mod decode;
+mod presence;
+mod socket;
+#[cfg(target_os = "linux")]
+mod uhid;
+
+use self::presence::UserPresence;
+#[cfg(any(feature = "with_ble", feature = "with_ccid", feature = "with_nfc"))]
+use self::socket::{FrameEndpoint, FrameStatus};
+use self::socket::{Listener, SocketEndpoint};
+#[cfg(target_os = "linux")]
+use self::uhid::UhidEndpoint;
+use crate::ctap;
+#[cfg(feature = "with_ccid")]
+use crate::ctap::applet::{AppletDispatcher, U2fApplet};
+#[cfg(feature = "with_ble")]
+use crate::ctap::ble::CtapBle;
+#[cfg(feature = "with_ccid")]
+use crate::ctap::ccid::CtapCcid;
+#[cfg(feature = "debug_ctap")]
+use crate::ctap::hid::trace::{self, Recorder, Trace, TraceError};
+use crate::ctap::hid::transport::{HidEndpoint, HidTransport};
+use crate::ctap::hid::CtapHid;
+#[cfg(feature = "with_nfc")]
+use crate::ctap::nfc::CtapNfc;
+use crate::ctap::CtapState;
+use crate::env::host::HostEnv;
+use crate::env::Env;
+use crate::timer::{ClockValue, Duration, Timestamp};
+use crypto::rng256::{Rng256, SeededRng256, ThreadRng256};
+use ctap2::embedded_flash::Storage;
+#[cfg(feature = "debug_ctap")]
+use std::fs::File;
+use std::path::PathBuf;
+
+const RECV_TIMEOUT: Duration<isize> = Duration::from_ms(100);
+const SEND_TIMEOUT: Duration<isize> = Duration::from_ms(1000);
+
+const USAGE: &str = "Usage: ctap2 (--unix PATH | --tcp ADDRESS | --uhid | --replay FILE)
+                    [--protocol NAME] [--presence MODE] [--storage FILE] [--seed HEX]
+                    [--record FILE]
+       ctap2 --decode FILE
+
+Runs the authenticator as a virtual security key.
+
+Transports:
+    --unix PATH       Exchange the messages of the protocol over a UNIX socket.
+    --tcp ADDRESS     Exchange the messages of the protocol over TCP, e.g. 127.0.0.1:8111.
+    --uhid            Create a virtual USB HID device (Linux only, needs access to /dev/uhid).
+    --replay FILE     Feed the packets received in a trace to the authenticator, and report the
+                      replies that differ from the trace. FILE is a trace recorded with --record
+                      or the console log of a device. Needs the debug_ctap feature.
+    --decode FILE     Instead of running the authenticator, print the CTAPHID messages of a Linux
+                      usbmon capture, in the text format or as pcap or pcapng, and the CTAP2 and
+                      U2F requests and responses they carry. Needs the debug_ctap feature.
+
+Options:
+    --protocol NAME   The protocol spoken over --unix and --tcp, see below.
+    --presence MODE   How user presence is decided, see below.
+    --storage FILE    Keep the persistent store in FILE, created if needed. It may also be a flash
+                      dump of the store of a device. Without it, the store is lost on exit.
+                      The store is not sealed: the key protecting it is the same for every run,
+                      so anyone who can read FILE can use the credentials in it.
+                      A replay modifies it, so give it a copy of the store the trace started with.
+    --seed HEX        Seed the rng with these 32 bytes, making keys and replies reproducible.
+                      Replays use the seed of the trace by default, other runs a random seed.
+    --record FILE     Record the packets received and sent, with the seed, into the trace FILE.
+                      Needs the debug_ctap feature. Only HID can be recorded.
+
+Protocols:
+    hid               64-byte HID reports, without framing (default).
+    nfc               Command and response APDUs, like a reader in the field of the key would
+                      exchange them. Needs the with_nfc feature.
+    ccid              Messages of the bulk endpoints of a USB smart card reader, whose card holds
+                      the U2F applet. Needs the with_ccid feature.
+    ble               Fragments written to fidoControlPoint and notified on fidoStatus, with a
+                      controlPointLength of 512 bytes. Needs the with_ble feature.
+Except for hid, each message is prefixed by its length on 4 bytes in big-endian.
+
+User presence modes:
+    auto              Approve every request (default).
+    key               Wait for Enter on the standard input, or n and Enter to deny.
+    script:FILE       Answer with the decisions of FILE in order, one per line: approve, deny or
+                      timeout. Requests time out once the script is exhausted.";
+
+#[derive(Debug, PartialEq)]
+enum Transport {
+    Unix(PathBuf),
+    Tcp(String),
+    Uhid,
+    #[cfg(feature = "debug_ctap")]
+    Replay(PathBuf),
+    #[cfg(feature = "debug_ctap")]
+    Decode(PathBuf),
+}
+
+#[derive(Debug, PartialEq)]
+enum Protocol {
+    Hid,
+    #[cfg(feature = "with_nfc")]
+    Nfc,
+    #[cfg(feature = "with_ccid")]
+    Ccid,
+    #[cfg(feature = "with_ble")]
+    Ble,
+}
+
+#[derive(Debug)]
+struct Options {
+    transport: Transport,
+    protocol: Protocol,
+    presence: UserPresence,
+    storage: Option<PathBuf>,
+    seed: Option<[u8; 32]>,
+    #[cfg(feature = "debug_ctap")]
+    record: Option<PathBuf>,
+}
+
+fn parse_presence(mode: &str) -> Result<UserPresence, String> {
+    match mode {
+        "auto" => Ok(UserPresence::Auto),
+        "key" => Ok(UserPresence::KeyPress(None)),
+        _ if mode.starts_with("script:") => {
+            let path = &mode["script:".len()..];
+            let script = std::fs::read_to_string(path)
+                .map_err(|e| format!("cannot read the script {}: {}", path, e))?;
+            UserPresence::parse_script(&script).map_err(|e| format!("{}: {}", path, e))
+        }
+        _ => Err(format!("unknown user presence mode {:?}", mode)),
+    }
+}
+
+fn parse_protocol(name: &str) -> Result<Protocol, String> {
+    match name {
+        "hid" => Ok(Protocol::Hid),
+        #[cfg(feature = "with_nfc")]
+        "nfc" => Ok(Protocol::Nfc),
+        #[cfg(feature = "with_ccid")]
+        "ccid" => Ok(Protocol::Ccid),
+        #[cfg(feature = "with_ble")]
+        "ble" => Ok(Protocol::Ble),
+        _ => Err(format!("unknown protocol {:?}", name)),
+    }
+}
+
+fn parse_seed(hex: &str) -> Result<[u8; 32], String> {
+    let mut seed = [0; 32];
+    if hex.len() != 64 || !hex.is_ascii() {
+        return Err("the seed must be 64 hexadecimal digits".to_string());
+    }
+    for (i, byte) in seed.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16)
+            .map_err(|_| "the seed must be 64 hexadecimal digits".to_string())?;
+    }
+    Ok(seed)
+}
+
+fn parse_options(args: &[String]) -> Result<Options, String> {
+    let mut transport = None;
+    let mut protocol = Protocol::Hid;
+    let mut presence = UserPresence::Auto;
+    let mut storage = None;
+    let mut seed = None;
+    #[cfg(feature = "debug_ctap")]
+    let mut record = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| format!("missing value for {}", arg))
+        };
+        let new_transport = match arg.as_str() {
+            "--unix" => Transport::Unix(PathBuf::from(value()?)),
+            "--tcp" => Transport::Tcp(value()?.clone()),
+            "--uhid" => Transport::Uhid,
+            #[cfg(feature = "debug_ctap")]
+            "--replay" => Transport::Replay(PathBuf::from(value()?)),
+            #[cfg(feature = "debug_ctap")]
+            "--decode" => Transport::Decode(PathBuf::from(value()?)),
+            "--protocol" => {
+                protocol = parse_protocol(value()?)?;
+                continue;
+            }
+            "--presence" => {
+                presence = parse_presence(value()?)?;
+                continue;
+            }
+            "--storage" => {
+                storage = Some(PathBuf::from(value()?));
+                continue;
+            }
+            "--seed" => {
+                seed = Some(parse_seed(value()?)?);
+                continue;
+            }
+            #[cfg(feature = "debug_ctap")]
+            "--record" => {
+                record = Some(PathBuf::from(value()?));
+                continue;
+            }
+            _ => return Err(format!("unknown argument {:?}", arg)),
+        };
+        if transport.replace(new_transport).is_some() {
+            return Err("only one transport can be used".to_string());
+        }
+    }
+    #[cfg(feature = "debug_ctap")]
+    {
+        if let (Some(Transport::Replay(_)), Some(_)) = (&transport, &record) {
+            return Err("a replay can't be recorded".to_string());
+        }
+        if protocol != Protocol::Hid && record.is_some() {
+            return Err("only the hid protocol can be recorded".to_string());
+        }
+    }
+    if protocol != Protocol::Hid {
+        match transport {
+            None | Some(Transport::Unix(_)) | Some(Transport::Tcp(_)) => (),
+            _ => return Err("the protocol needs --unix or --tcp".to_string()),
+        }
+    }
+    match transport {
+        Some(transport) => Ok(Options {
+            transport,
+            protocol,
+            presence,
+            storage,
+            seed,
+            #[cfg(feature = "debug_ctap")]
+            record,
+        }),
+        None => Err("no transport given".to_string()),
+    }
+}
+
+fn std_duration(duration: Duration<isize>) -> std::time::Duration {
+    // Sockets reject a zero timeout.
+    std::time::Duration::from_millis(core::cmp::max(duration.ms(), 1) as u64)
+}
+
+pub fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let options = match parse_options(&args) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("Error: {}\n\n{}", e, USAGE);
+            std::process::exit(2);
+        }
+    };
+    let result = match &options.storage {
+        None => serve(options, ctap::storage::new_ram_storage()),
+        Some(path) => ctap::storage::new_file_storage(path)
+            .map_err(|e| format!("cannot open the storage {}: {}", path.display(), e))
+            .and_then(|storage| serve(options, storage)),
+    };
+    if let Err(e) = result {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+// The rng is always seeded, randomly unless told otherwise, so that the seed can be recorded with
+// a trace.
+fn new_ctap_state<S: Storage>(
+    mut presence: UserPresence,
+    storage: S,
+    seed: [u8; 32],
+) -> CtapState<impl Env> {
+    // There are no keepalives while waiting for the user, so browsers may give up on slow answers.
+    CtapState::new(HostEnv::with_storage_and_rng(
+        move |cid| presence.check(cid),
+        storage,
+        SeededRng256::new(seed),
+    ))
+}
+
+fn serve<S: Storage>(options: Options, storage: S) -> Result<(), String> {
+    #[cfg(feature = "debug_ctap")]
+    {
+        match &options.transport {
+            Transport::Replay(path) => {
+                return replay(path, options.presence, storage, options.seed)
+            }
+            Transport::Decode(path) => return decode(path),
+            _ => (),
+        }
+    }
+    let seed = options
+        .seed
+        .unwrap_or_else(|| ThreadRng256 {}.gen_uniform_u8x32());
+    #[cfg(feature = "debug_ctap")]
+    let recorder = match &options.record {
+        None => None,
+        Some(path) => {
+            let file = File::create(path)
+                .map_err(|e| format!("cannot create the trace {}: {}", path.display(), e))?;
+            Some(Recorder::new(file, Some(&seed)))
+        }
+    };
+    let ctap_state = new_ctap_state(options.presence, storage, seed);
+    match options.transport {
+        Transport::Unix(path) => {
+            let listener = Listener::bind_unix(&path)
+                .map_err(|e| format!("cannot listen on {}: {}", path.display(), e))?;
+            run_socket(
+                listener,
+                options.protocol,
+                ctap_state,
+                #[cfg(feature = "debug_ctap")]
+                recorder,
+            )
+        }
+        Transport::Tcp(address) => {
+            let listener = Listener::bind_tcp(&address)
+                .map_err(|e| format!("cannot listen on {}: {}", address, e))?;
+            run_socket(
+                listener,
+                options.protocol,
+                ctap_state,
+                #[cfg(feature = "debug_ctap")]
+                recorder,
+            )
+        }
+        #[cfg(target_os = "linux")]
+        Transport::Uhid => run(
+            UhidEndpoint::new(),
+            ctap_state,
+            #[cfg(feature = "debug_ctap")]
+            recorder,
+        ),
+        #[cfg(not(target_os = "linux"))]
+        Transport::Uhid => return Err("UHID is only available on Linux".to_string()),
+        #[cfg(feature = "debug_ctap")]
+        Transport::Replay(_) | Transport::Decode(_) => unreachable!(),
+    }
+}
+
+fn run_socket<E: Env>(
+    listener: Listener,
+    protocol: Protocol,
+    ctap_state: CtapState<E>,
+    #[cfg(feature = "debug_ctap")] recorder: Option<Recorder<File>>,
+) -> ! {
+    match protocol {
+        Protocol::Hid => run(
+            SocketEndpoint::new(listener),
+            ctap_state,
+            #[cfg(feature = "debug_ctap")]
+            recorder,
+        ),
+        #[cfg(feature = "with_nfc")]
+        Protocol::Nfc => run_nfc(FrameEndpoint::new(listener), ctap_state),
+        #[cfg(feature = "with_ccid")]
+        Protocol::Ccid => run_ccid(FrameEndpoint::new(listener), ctap_state),
+        #[cfg(feature = "with_ble")]
+        Protocol::Ble => run_ble(FrameEndpoint::new(listener), ctap_state),
+    }
+}
+
+#[cfg(feature = "debug_ctap")]
+fn replay<S: Storage>(
+    path: &std::path::Path,
+    presence: UserPresence,
+    storage: S,
+    seed: Option<[u8; 32]>,
+) -> Result<(), String> {
+    let bytes = std::fs::read(path)
+        .map_err(|e| format!("cannot read the trace {}: {}", path.display(), e))?;
+    // Traces taken from the console of a device are text.
+    let trace = match Trace::decode(&bytes) {
+        Err(TraceError::InvalidHeader) => Trace::from_console(&String::from_utf8_lossy(&bytes)),
+        result => result,
+    }
+    .map_err(|e| format!("invalid trace {}: {:?}", path.display(), e))?;
+    if seed.is_none() && trace.seed.is_none() {
+        eprintln!("The trace has no seed, replies that depend on randomness will differ.");
+    }
+    let seed = seed
+        .or(trace.seed)
+        .unwrap_or_else(|| ThreadRng256 {}.gen_uniform_u8x32());
+    let mut ctap_state = new_ctap_state(presence, storage, seed);
+    let divergences = trace::replay(&trace, &mut ctap_state);
+    for divergence in &divergences {
+        let received = &trace.records[divergence.index];
+        println!(
+            "Reply to packet {} received at {} ms diverges:\n  packet:   {}",
+            divergence.index,
+            received.time_ms,
+            hex(&received.packet)
+        );
+        for packet in &divergence.expected {
+            println!("  expected: {}", hex(packet));
+        }
+        for packet in &divergence.actual {
+            println!("  actual:   {}", hex(packet));
+        }
+    }
+    if divergences.is_empty() {
+        println!(
+            "Replayed {} records, all replies match the trace.",
+            trace.records.len()
+        );
+        Ok(())
+    } else {
+        Err(format!(
+            "{} replies diverge from the trace",
+            divergences.len()
+        ))
+    }
+}
+
+#[cfg(feature = "debug_ctap")]
+fn decode(path: &std::path::Path) -> Result<(), String> {
+    let bytes = std::fs::read(path)
+        .map_err(|e| format!("cannot read the capture {}: {}", path.display(), e))?;
+    let packets = capture::parse_capture(&bytes)
+        .map_err(|e| format!("invalid capture {}: {}", path.display(), e))?;
+    print!("{}", decode::decode_capture(&packets));
+    Ok(())
+}
+
+#[cfg(feature = "debug_ctap")]
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// The steps of the firmware loop that don't depend on the transport.
+fn check_timeouts<E: Env>(ctap_state: &mut CtapState<E>, now: ClockValue) {
+    #[cfg(feature = "with_ctap1")]
+    {
+        // U2F requests fail until the user is present, and the host retries them. The channel of
+        // the failed request is not known here.
+        if ctap_state.u2f_up_state.is_up_needed(now)
+            && ctap_state.env().check_user_presence([0xFF; 4]).is_ok()
+        {
+            ctap_state.u2f_up_state.grant_up(now);
+        }
+    }
+    ctap_state.check_disable_reset(Timestamp::<isize>::from_clock_value(now));
+}
+
+// Same loop as the firmware, without the LEDs and buttons.
+fn run<H: HidEndpoint, E: Env>(
+    endpoint: H,
+    mut ctap_state: CtapState<E>,
+    #[cfg(feature = "debug_ctap")] mut recorder: Option<Recorder<File>>,
+) -> ! {
+    let mut transport = HidTransport::new(endpoint);
+    let mut ctap_hid = CtapHid::new();
+    // There are no LEDs, so winks are logged instead.
+    let mut winking = false;
+
+    loop {
+        let mut pkt_request = [0; 64];
+        let has_packet = transport.recv_with_timeout(&mut pkt_request, RECV_TIMEOUT);
+
+        let now = ctap_state.env().clock();
+        check_timeouts(&mut ctap_state, now);
+        ctap_hid.wink_permission = ctap_hid.wink_permission.check_expiration(now);
+        if ctap_hid.wink_permission.is_granted(now) != winking {
+            winking = !winking;
+            if winking {
+                eprintln!("Wink");
+            }
+        }
+
+        if has_packet {
+            #[cfg(feature = "debug_ctap")]
+            {
+                if let Some(recorder) = &mut recorder {
+                    recorder.record_received(now, &pkt_request);
+                }
+            }
+            let reply = ctap_hid.process_hid_packet(&pkt_request, now, &mut ctap_state);
+            #[cfg(feature = "debug_ctap")]
+            {
+                if let Some(recorder) = &mut recorder {
+                    recorder.record_reply(now, &reply);
+                }
+            }
+            transport.send_reply(reply, &mut ctap_hid, SEND_TIMEOUT);
+        } else {
+            ctap_state.idle_step();
+        }
+    }
+}
+
+// A client connection stands for a reader with the key in its field.
+#[cfg(feature = "with_nfc")]
+fn run_nfc<E: Env>(mut endpoint: FrameEndpoint, mut ctap_state: CtapState<E>) -> ! {
+    let mut ctap_nfc = CtapNfc::new();
+
+    loop {
+        let status = endpoint.recv_with_timeout(RECV_TIMEOUT);
+        let now = ctap_state.env().clock();
+        check_timeouts(&mut ctap_state, now);
+
+        match status {
+            FrameStatus::Received(apdu) => {
+                let reply = ctap_nfc.process_apdu(&apdu, now, &mut ctap_state);
+                if !endpoint.send_with_timeout(&reply, SEND_TIMEOUT) {
+                    ctap_nfc.reset();
+                }
+                // The host polls for the response with NFCCTAP_GETRESPONSE in the meantime.
+                if ctap_nfc.has_pending_command() {
+                    ctap_nfc.process_pending_command(&mut ctap_state);
+                }
+            }
+            // The key left the field.
+            FrameStatus::Disconnected => ctap_nfc.reset(),
+            FrameStatus::Timeout => {
+                ctap_state.idle_step();
+            }
+        }
+    }
+}
+
+// A client connection stands for a reader plugged in with the card inserted.
+#[cfg(feature = "with_ccid")]
+fn run_ccid<E: Env>(mut endpoint: FrameEndpoint, mut ctap_state: CtapState<E>) -> ! {
+    let mut ctap_ccid = new_ctap_ccid();
+
+    loop {
+        let status = endpoint.recv_with_timeout(RECV_TIMEOUT);
+        let now = ctap_state.env().clock();
+        check_timeouts(&mut ctap_state, now);
+
+        match status {
+            FrameStatus::Received(message) => {
+                let reply = ctap_ccid.process_message(&message, now, &mut ctap_state);
+                // Messages without a header get no reply.
+                if !reply.is_empty() && !endpoint.send_with_timeout(&reply, SEND_TIMEOUT) {
+                    ctap_ccid = new_ctap_ccid();
+                }
+            }
+            // The reader was unplugged.
+            FrameStatus::Disconnected => ctap_ccid = new_ctap_ccid(),
+            FrameStatus::Timeout => {
+                ctap_state.idle_step();
+            }
+        }
+    }
+}
+
+// The only applet is U2F, CTAP2 is reached over the other transports.
+#[cfg(feature = "with_ccid")]
+fn new_ctap_ccid<E: Env>() -> CtapCcid<E> {
+    let mut dispatcher = AppletDispatcher::new();
+    dispatcher.register(Box::new(U2fApplet));
+    CtapCcid::new(dispatcher)
+}
+
+// A client connection stands for a BLE connection with the fidoStatus notifications enabled.
+#[cfg(feature = "with_ble")]
+fn run_ble<E: Env>(mut endpoint: FrameEndpoint, mut ctap_state: CtapState<E>) -> ! {
+    let mut ctap_ble = CtapBle::new(CtapBle::MAX_CONTROL_POINT_LENGTH);
+
+    loop {
+        let status = endpoint.recv_with_timeout(RECV_TIMEOUT);
+        let now = ctap_state.env().clock();
+        check_timeouts(&mut ctap_state, now);
+
+        match status {
+            FrameStatus::Received(fragment) => {
+                for reply in ctap_ble.process_fragment(&fragment, now, &mut ctap_state) {
+                    if !endpoint.send_with_timeout(&reply, SEND_TIMEOUT) {
+                        ctap_ble.reset();
+                        break;
+                    }
+                }
+            }
+            FrameStatus::Disconnected => ctap_ble.reset(),
+            FrameStatus::Timeout => {
+                ctap_state.idle_step();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|&arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_options() {
+        let options = parse_options(&args(&["--unix", "/tmp/opensk.sock"])).unwrap();
+        assert_eq!(
+            options.transport,
+            Transport::Unix(PathBuf::from("/tmp/opensk.sock"))
+        );
+        assert_eq!(options.protocol, Protocol::Hid);
+        assert_eq!(options.storage, None);
+        match options.presence {
+            UserPresence::Auto => (),
+            presence => panic!("Unexpected user presence {:?}", presence),
+        }
+
+        let options =
+            parse_options(&args(&["--presence", "key", "--tcp", "127.0.0.1:8111"])).unwrap();
+        assert_eq!(
+            options.transport,
+            Transport::Tcp("127.0.0.1:8111".to_string())
+        );
+        match options.presence {
+            UserPresence::KeyPress(None) => (),
+            presence => panic!("Unexpected user presence {:?}", presence),
+        }
+
+        let options = parse_options(&args(&["--uhid", "--storage", "flash.bin"])).unwrap();
+        assert_eq!(options.transport, Transport::Uhid);
+        assert_eq!(options.storage, Some(PathBuf::from("flash.bin")));
+        assert_eq!(options.seed, None);
+
+        let seed = "00".repeat(31) + "aB";
+        let options = parse_options(&args(&["--uhid", "--seed", &seed])).unwrap();
+        let mut expected_seed = [0x00; 32];
+        expected_seed[31] = 0xAB;
+        assert_eq!(options.seed, Some(expected_seed));
+    }
+
+    #[test]
+    #[cfg(feature = "debug_ctap")]
+    fn test_parse_trace_options() {
+        let options = parse_options(&args(&["--replay", "trace.bin"])).unwrap();
+        assert_eq!(
+            options.transport,
+            Transport::Replay(PathBuf::from("trace.bin"))
+        );
+        assert_eq!(options.record, None);
+
+        let options = parse_options(&args(&["--decode", "usbmon.pcap"])).unwrap();
+        assert_eq!(
+            options.transport,
+            Transport::Decode(PathBuf::from("usbmon.pcap"))
+        );
+
+        let options = parse_options(&args(&["--uhid", "--record", "trace.bin"])).unwrap();
+        assert_eq!(options.record, Some(PathBuf::from("trace.bin")));
+
+        assert_eq!(
+            parse_options(&args(&["--replay", "a.bin", "--record", "b.bin"])).unwrap_err(),
+            "a replay can't be recorded"
+        );
+    }
+
+    #[test]
+    fn test_parse_options_errors() {
+        assert_eq!(parse_options(&args(&[])).unwrap_err(), "no transport given");
+        assert_eq!(
+            parse_options(&args(&["--uhid", "--tcp", "127.0.0.1:8111"])).unwrap_err(),
+            "only one transport can be used"
+        );
+        assert_eq!(
+            parse_options(&args(&["--unix"])).unwrap_err(),
+            "missing value for --unix"
+        );
+        assert_eq!(
+            parse_options(&args(&["--uhid", "--presence", "never"])).unwrap_err(),
+            "unknown user presence mode \"never\""
+        );
+        assert_eq!(
+            parse_options(&args(&["--uhid", "--seed", "00"])).unwrap_err(),
+            "the seed must be 64 hexadecimal digits"
+        );
+        assert_eq!(
+            parse_options(&args(&["--uhid", "--protocol", "usb"])).unwrap_err(),
+            "unknown protocol \"usb\""
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "with_nfc")]
+    fn test_parse_protocol_options() {
+        let options = parse_options(&args(&["--unix", "nfc.sock", "--protocol", "nfc"])).unwrap();
+        assert_eq!(options.protocol, Protocol::Nfc);
+        let options = parse_options(&args(&["--protocol", "nfc", "--tcp", ":8111"])).unwrap();
+        assert_eq!(options.protocol, Protocol::Nfc);
+
+        assert_eq!(
+            parse_options(&args(&["--uhid", "--protocol", "nfc"])).unwrap_err(),
+            "the protocol needs --unix or --tcp"
+        );
+        #[cfg(feature = "debug_ctap")]
+        assert_eq!(
+            parse_options(&args(&[
+                "--unix",
+                "a",
+                "--protocol",
+                "nfc",
+                "--record",
+                "b"
+            ]))
+            .unwrap_err(),
+            "only the hid protocol can be recorded"
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "with_ble")]
+    fn test_parse_ble_options() {
+        let options = parse_options(&args(&["--tcp", ":8111", "--protocol", "ble"])).unwrap();
+        assert_eq!(options.protocol, Protocol::Ble);
+        assert_eq!(
+            parse_options(&args(&["--uhid", "--protocol", "ble"])).unwrap_err(),
+            "the protocol needs --unix or --tcp"
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "with_ccid")]
+    fn test_parse_ccid_options() {
+        let options = parse_options(&args(&["--unix", "ccid.sock", "--protocol", "ccid"])).unwrap();
+        assert_eq!(options.protocol, Protocol::Ccid);
+        assert_eq!(
+            parse_options(&args(&["--uhid", "--protocol", "ccid"])).unwrap_err(),
+            "the protocol needs --unix or --tcp"
+        );
+    }
+}
@@ -0,0 +1,168 @@
+// Copyright 2019 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::ctap;
+use crate::ctap::hid::ChannelID;
+use crate::ctap::status_code::Ctap2StatusCode;
+use std::collections::VecDeque;
+use std::io::BufRead;
+use std::sync::mpsc::{channel, Receiver};
+
+// The answer to a user presence check.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Decision {
+    Approve,
+    Deny,
+    Timeout,
+}
+
+impl Decision {
+    fn parse(word: &str) -> Option<Decision> {
+        match word {
+            "approve" | "yes" | "y" => Some(Decision::Approve),
+            "deny" | "no" | "n" => Some(Decision::Deny),
+            "timeout" => Some(Decision::Timeout),
+            _ => None,
+        }
+    }
+
+    fn into_result(self) -> Result<(), Ctap2StatusCode> {
+        match self {
+            Decision::Approve => Ok(()),
+            Decision::Deny => Err(Ctap2StatusCode::CTAP2_ERR_OPERATION_DENIED),
+            Decision::Timeout => Err(Ctap2StatusCode::CTAP2_ERR_USER_ACTION_TIMEOUT),
+        }
+    }
+}
+
+// Replaces the button of the device.
+#[derive(Debug)]
+pub enum UserPresence {
+    // Every check succeeds immediately.
+    Auto,
+    // Every check waits for a line on the standard input. An empty line approves, and a line
+    // starting with "n" denies. As for the button, checks time out after TOUCH_TIMEOUT_MS.
+    // The lines are read by a thread, started on the first check.
+    KeyPress(Option<Receiver<String>>),
+    // Checks consume the decisions in order. Once they are exhausted, checks time out.
+    Script(VecDeque<Decision>),
+}
+
+impl UserPresence {
+    // Parses a script with one decision per line: approve (or yes), deny (or no) or timeout.
+    // Empty lines and lines starting with # are ignored.
+    pub fn parse_script(script: &str) -> Result<UserPresence, String> {
+        let mut decisions = VecDeque::new();
+        for (index, line) in script.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match Decision::parse(&line.to_lowercase()) {
+                Some(decision) => decisions.push_back(decision),
+                None => return Err(format!("line {}: unknown decision {:?}", index + 1, line)),
+            }
+        }
+        Ok(UserPresence::Script(decisions))
+    }
+
+    pub fn check(&mut self, cid: ChannelID) -> Result<(), Ctap2StatusCode> {
+        match self {
+            UserPresence::Auto => Ok(()),
+            UserPresence::KeyPress(lines) => {
+                eprintln!(
+                    "User presence requested on channel {:02x?}. Press Enter to approve, or type n \
+                     and Enter to deny.",
+                    cid
+                );
+                let lines = lines.get_or_insert_with(read_lines);
+                // Keys pressed before the request don't count.
+                while lines.try_recv().is_ok() {}
+                let timeout = std::time::Duration::from_millis(ctap::TOUCH_TIMEOUT_MS as u64);
+                match lines.recv_timeout(timeout) {
+                    Ok(line) if line.trim().to_lowercase().starts_with('n') => Decision::Deny,
+                    Ok(_) => Decision::Approve,
+                    // Also when the standard input is closed, since nobody can press a key.
+                    Err(_) => Decision::Timeout,
+                }
+                .into_result()
+            }
+            UserPresence::Script(decisions) => decisions
+                .pop_front()
+                .unwrap_or(Decision::Timeout)
+                .into_result(),
+        }
+    }
+}
+
+// Forwards the lines of the standard input, until it is closed.
+fn read_lines() -> Receiver<String> {
+    let (sender, receiver) = channel();
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lock().lines() {
+            // Stops on read errors, or once the receiver is gone.
+            let sent = match line {
+                Ok(line) => sender.send(line).is_ok(),
+                Err(_) => false,
+            };
+            if !sent {
+                break;
+            }
+        }
+    });
+    receiver
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const DUMMY_CHANNEL_ID: ChannelID = [0x12, 0x34, 0x56, 0x78];
+
+    #[test]
+    fn test_auto() {
+        let mut presence = UserPresence::Auto;
+        assert_eq!(presence.check(DUMMY_CHANNEL_ID), Ok(()));
+        assert_eq!(presence.check(DUMMY_CHANNEL_ID), Ok(()));
+    }
+
+    #[test]
+    fn test_script() {
+        let mut presence =
+            UserPresence::parse_script("# Comment\napprove\n\n  DENY \ntimeout\nyes\n").unwrap();
+        assert_eq!(presence.check(DUMMY_CHANNEL_ID), Ok(()));
+        assert_eq!(
+            presence.check(DUMMY_CHANNEL_ID),
+            Err(Ctap2StatusCode::CTAP2_ERR_OPERATION_DENIED)
+        );
+        assert_eq!(
+            presence.check(DUMMY_CHANNEL_ID),
+            Err(Ctap2StatusCode::CTAP2_ERR_USER_ACTION_TIMEOUT)
+        );
+        assert_eq!(presence.check(DUMMY_CHANNEL_ID), Ok(()));
+        // The script is exhausted.
+        assert_eq!(
+            presence.check(DUMMY_CHANNEL_ID),
+            Err(Ctap2StatusCode::CTAP2_ERR_USER_ACTION_TIMEOUT)
+        );
+    }
+
+    #[test]
+    fn test_script_unknown_decision() {
+        assert_eq!(
+            UserPresence::parse_script("approve\nmaybe\n").unwrap_err(),
+            "line 2: unknown decision \"maybe\""
+        );
+    }
+}
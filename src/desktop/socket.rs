@@ -0,0 +1,446 @@
+// Copyright 2019 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::std_duration;
+use crate::ctap::hid::transport::{HidEndpoint, SendOrRecvStatus};
+use crate::ctap::hid::HidPacket;
+use crate::timer::Duration;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+
+pub enum Listener {
+    Unix(UnixListener),
+    Tcp(TcpListener),
+}
+
+impl Listener {
+    // Listens on a UNIX socket, replacing a stale socket file left by a previous run.
+    pub fn bind_unix(path: &Path) -> std::io::Result<Listener> {
+        if path.exists() {
+            std::fs::remove_file(path)?;
+        }
+        Ok(Listener::Unix(UnixListener::bind(path)?))
+    }
+
+    pub fn bind_tcp(address: &str) -> std::io::Result<Listener> {
+        Ok(Listener::Tcp(TcpListener::bind(address)?))
+    }
+
+    fn accept(&self) -> std::io::Result<Connection> {
+        match self {
+            Listener::Unix(listener) => Ok(Connection::Unix(listener.accept()?.0)),
+            Listener::Tcp(listener) => {
+                let stream = listener.accept()?.0;
+                // Packets are small and latency matters more than throughput.
+                stream.set_nodelay(true)?;
+                Ok(Connection::Tcp(stream))
+            }
+        }
+    }
+}
+
+enum Connection {
+    Unix(UnixStream),
+    Tcp(TcpStream),
+}
+
+impl Connection {
+    fn set_timeouts(&self, timeout: Option<std::time::Duration>) -> std::io::Result<()> {
+        match self {
+            Connection::Unix(stream) => {
+                stream.set_read_timeout(timeout)?;
+                stream.set_write_timeout(timeout)
+            }
+            Connection::Tcp(stream) => {
+                stream.set_read_timeout(timeout)?;
+                stream.set_write_timeout(timeout)
+            }
+        }
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Connection::Unix(stream) => stream.read(buf),
+            Connection::Tcp(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Connection::Unix(stream) => stream.write(buf),
+            Connection::Tcp(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Connection::Unix(stream) => stream.flush(),
+            Connection::Tcp(stream) => stream.flush(),
+        }
+    }
+}
+
+// Exchanges HID reports with one client at a time over a stream socket. Each report is sent as
+// exactly 64 bytes, without any framing, in both directions. When the client disconnects, the
+// next setup waits for a new client.
+pub struct SocketEndpoint {
+    listener: Listener,
+    connection: Option<Connection>,
+    // Bytes of a report that was only partially received when the last read timed out.
+    partial_packet: Vec<u8>,
+}
+
+impl SocketEndpoint {
+    pub fn new(listener: Listener) -> SocketEndpoint {
+        SocketEndpoint {
+            listener,
+            connection: None,
+            partial_packet: Vec::new(),
+        }
+    }
+
+    fn disconnect(&mut self) -> Option<SendOrRecvStatus> {
+        self.connection = None;
+        self.partial_packet.clear();
+        Some(SendOrRecvStatus::Error)
+    }
+}
+
+impl HidEndpoint for SocketEndpoint {
+    // Blocks until a client connects.
+    fn setup(&mut self) -> bool {
+        if self.connection.is_none() {
+            match self.listener.accept() {
+                Ok(connection) => self.connection = Some(connection),
+                Err(e) => {
+                    eprintln!("Failed to accept a connection: {}", e);
+                    return false;
+                }
+            }
+            self.partial_packet.clear();
+        }
+        true
+    }
+
+    fn recv_with_timeout(
+        &mut self,
+        buf: &mut HidPacket,
+        timeout_delay: Duration<isize>,
+    ) -> Option<SendOrRecvStatus> {
+        let connection = match &mut self.connection {
+            Some(connection) => connection,
+            None => return Some(SendOrRecvStatus::Error),
+        };
+        if connection
+            .set_timeouts(Some(std_duration(timeout_delay)))
+            .is_err()
+        {
+            return self.disconnect();
+        }
+        while self.partial_packet.len() < buf.len() {
+            let mut chunk = [0; 64];
+            let missing = buf.len() - self.partial_packet.len();
+            match connection.read(&mut chunk[..missing]) {
+                // The client closed the connection.
+                Ok(0) => return self.disconnect(),
+                Ok(len) => self.partial_packet.extend_from_slice(&chunk[..len]),
+                Err(e) if e.kind() == ErrorKind::Interrupted => (),
+                Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                    return None
+                }
+                Err(_) => return self.disconnect(),
+            }
+        }
+        buf.copy_from_slice(&self.partial_packet);
+        self.partial_packet.clear();
+        Some(SendOrRecvStatus::Received)
+    }
+
+    fn wait(&mut self, delay: Duration<isize>) {
+        std::thread::sleep(std_duration(delay));
+    }
+
+    // The socket is buffered in both directions, so sending never needs to receive first.
+    fn send_or_recv_with_timeout(
+        &mut self,
+        buf: &mut HidPacket,
+        timeout_delay: Duration<isize>,
+    ) -> Option<SendOrRecvStatus> {
+        let connection = match &mut self.connection {
+            Some(connection) => connection,
+            None => return Some(SendOrRecvStatus::Error),
+        };
+        if connection
+            .set_timeouts(Some(std_duration(timeout_delay)))
+            .is_err()
+        {
+            return self.disconnect();
+        }
+        match connection.write_all(buf) {
+            Ok(()) => Some(SendOrRecvStatus::Sent),
+            // A partial report may have been written, so the stream is out of sync.
+            Err(_) => self.disconnect(),
+        }
+    }
+}
+
+// Upper bound on the length of a frame, above any extended APDU, CCID message and BLE fragment. A larger length
+// means that the client is out of sync, and it is disconnected.
+#[cfg(any(feature = "with_ble", feature = "with_ccid", feature = "with_nfc"))]
+const MAX_FRAME_LENGTH: usize = 0x20000;
+
+#[cfg(any(feature = "with_ble", feature = "with_ccid", feature = "with_nfc"))]
+#[derive(Debug, PartialEq)]
+pub enum FrameStatus {
+    Received(Vec<u8>),
+    Timeout,
+    // The client left, the next receive waits for a new client.
+    Disconnected,
+}
+
+// Exchanges frames of variable length with one client at a time over a stream socket, for the
+// transports that are not HID. Each frame is prefixed by its length on 4 bytes in big-endian, in
+// both directions.
+#[cfg(any(feature = "with_ble", feature = "with_ccid", feature = "with_nfc"))]
+pub struct FrameEndpoint {
+    listener: Listener,
+    connection: Option<Connection>,
+    // Bytes received after the last complete frame.
+    received: Vec<u8>,
+}
+
+#[cfg(any(feature = "with_ble", feature = "with_ccid", feature = "with_nfc"))]
+impl FrameEndpoint {
+    pub fn new(listener: Listener) -> FrameEndpoint {
+        FrameEndpoint {
+            listener,
+            connection: None,
+            received: Vec::new(),
+        }
+    }
+
+    fn disconnect(&mut self) -> FrameStatus {
+        self.connection = None;
+        self.received.clear();
+        FrameStatus::Disconnected
+    }
+
+    // Blocks until a client connects, if none is connected.
+    pub fn recv_with_timeout(&mut self, timeout_delay: Duration<isize>) -> FrameStatus {
+        if self.connection.is_none() {
+            match self.listener.accept() {
+                Ok(connection) => self.connection = Some(connection),
+                Err(e) => {
+                    eprintln!("Failed to accept a connection: {}", e);
+                    std::thread::sleep(std_duration(timeout_delay));
+                    return FrameStatus::Timeout;
+                }
+            }
+        }
+        loop {
+            if self.received.len() >= 4 {
+                let length = u32::from_be_bytes(*array_ref!(self.received, 0, 4)) as usize;
+                if length > MAX_FRAME_LENGTH {
+                    return self.disconnect();
+                }
+                if self.received.len() >= 4 + length {
+                    let frame = self.received[4..4 + length].to_vec();
+                    self.received.drain(..4 + length);
+                    return FrameStatus::Received(frame);
+                }
+            }
+            let connection = self.connection.as_mut().unwrap();
+            if connection
+                .set_timeouts(Some(std_duration(timeout_delay)))
+                .is_err()
+            {
+                return self.disconnect();
+            }
+            let mut chunk = [0; 1024];
+            match connection.read(&mut chunk) {
+                // The client closed the connection.
+                Ok(0) => return self.disconnect(),
+                Ok(len) => self.received.extend_from_slice(&chunk[..len]),
+                Err(e) if e.kind() == ErrorKind::Interrupted => (),
+                Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                    return FrameStatus::Timeout
+                }
+                Err(_) => return self.disconnect(),
+            }
+        }
+    }
+
+    // Returns whether the frame was sent. Otherwise, the client is disconnected.
+    pub fn send_with_timeout(&mut self, frame: &[u8], timeout_delay: Duration<isize>) -> bool {
+        let connection = match &mut self.connection {
+            Some(connection) => connection,
+            None => return false,
+        };
+        let mut message = (frame.len() as u32).to_be_bytes().to_vec();
+        message.extend_from_slice(frame);
+        if connection
+            .set_timeouts(Some(std_duration(timeout_delay)))
+            .is_ok()
+            && connection.write_all(&message).is_ok()
+        {
+            return true;
+        }
+        self.disconnect();
+        false
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const TIMEOUT: Duration<isize> = Duration::from_ms(100);
+
+    fn socket_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("opensk-{}-{}.sock", name, std::process::id()))
+    }
+
+    #[test]
+    fn test_unix_socket_exchange() {
+        let path = socket_path("exchange");
+        let mut endpoint = SocketEndpoint::new(Listener::bind_unix(&path).unwrap());
+        let mut client = UnixStream::connect(&path).unwrap();
+        assert!(endpoint.setup());
+
+        // A report split across writes is reassembled.
+        let mut buf = [0x00; 64];
+        assert_eq!(endpoint.recv_with_timeout(&mut buf, TIMEOUT), None);
+        client.write_all(&[0x55; 40]).unwrap();
+        assert_eq!(endpoint.recv_with_timeout(&mut buf, TIMEOUT), None);
+        client.write_all(&[0x55; 24]).unwrap();
+        assert_eq!(
+            endpoint.recv_with_timeout(&mut buf, TIMEOUT),
+            Some(SendOrRecvStatus::Received)
+        );
+        assert_eq!(&buf[..], &[0x55; 64][..]);
+
+        let mut reply = [0xAA; 64];
+        assert_eq!(
+            endpoint.send_or_recv_with_timeout(&mut reply, TIMEOUT),
+            Some(SendOrRecvStatus::Sent)
+        );
+        let mut received = [0x00; 64];
+        client.read_exact(&mut received).unwrap();
+        assert_eq!(&received[..], &[0xAA; 64][..]);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_unix_socket_reconnect() {
+        let path = socket_path("reconnect");
+        let mut endpoint = SocketEndpoint::new(Listener::bind_unix(&path).unwrap());
+        let client = UnixStream::connect(&path).unwrap();
+        assert!(endpoint.setup());
+        drop(client);
+
+        let mut buf = [0x00; 64];
+        assert_eq!(
+            endpoint.recv_with_timeout(&mut buf, TIMEOUT),
+            Some(SendOrRecvStatus::Error)
+        );
+
+        let mut client = UnixStream::connect(&path).unwrap();
+        assert!(endpoint.setup());
+        client.write_all(&[0x33; 64]).unwrap();
+        assert_eq!(
+            endpoint.recv_with_timeout(&mut buf, TIMEOUT),
+            Some(SendOrRecvStatus::Received)
+        );
+        assert_eq!(&buf[..], &[0x33; 64][..]);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    #[cfg(any(feature = "with_ble", feature = "with_ccid", feature = "with_nfc"))]
+    fn test_frame_exchange() {
+        let path = socket_path("frames");
+        let mut endpoint = FrameEndpoint::new(Listener::bind_unix(&path).unwrap());
+        let mut client = UnixStream::connect(&path).unwrap();
+
+        // A frame split across writes is reassembled, and frames sent together are split.
+        assert_eq!(endpoint.recv_with_timeout(TIMEOUT), FrameStatus::Timeout);
+        client.write_all(&[0x00, 0x00, 0x00, 0x03, 0x11]).unwrap();
+        assert_eq!(endpoint.recv_with_timeout(TIMEOUT), FrameStatus::Timeout);
+        client
+            .write_all(&[
+                0x22, 0x33, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x44,
+            ])
+            .unwrap();
+        assert_eq!(
+            endpoint.recv_with_timeout(TIMEOUT),
+            FrameStatus::Received(vec![0x11, 0x22, 0x33])
+        );
+        assert_eq!(
+            endpoint.recv_with_timeout(TIMEOUT),
+            FrameStatus::Received(vec![])
+        );
+        assert_eq!(
+            endpoint.recv_with_timeout(TIMEOUT),
+            FrameStatus::Received(vec![0x44])
+        );
+
+        assert!(endpoint.send_with_timeout(&[0x90, 0x00], TIMEOUT));
+        let mut received = [0x00; 6];
+        client.read_exact(&mut received).unwrap();
+        assert_eq!(received, [0x00, 0x00, 0x00, 0x02, 0x90, 0x00]);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    #[cfg(any(feature = "with_ble", feature = "with_ccid", feature = "with_nfc"))]
+    fn test_frame_reconnect() {
+        let path = socket_path("frames-reconnect");
+        let mut endpoint = FrameEndpoint::new(Listener::bind_unix(&path).unwrap());
+        let mut client = UnixStream::connect(&path).unwrap();
+        // A partial frame is dropped with its client.
+        client.write_all(&[0x00, 0x00, 0x00, 0x02, 0x11]).unwrap();
+        assert_eq!(endpoint.recv_with_timeout(TIMEOUT), FrameStatus::Timeout);
+        drop(client);
+        assert_eq!(
+            endpoint.recv_with_timeout(TIMEOUT),
+            FrameStatus::Disconnected
+        );
+        assert!(!endpoint.send_with_timeout(&[0x90, 0x00], TIMEOUT));
+
+        let mut client = UnixStream::connect(&path).unwrap();
+        client.write_all(&[0x00, 0x00, 0x00, 0x01, 0x22]).unwrap();
+        assert_eq!(
+            endpoint.recv_with_timeout(TIMEOUT),
+            FrameStatus::Received(vec![0x22])
+        );
+
+        // An impossible length disconnects the client.
+        client.write_all(&[0xFF, 0xFF, 0xFF, 0xFF]).unwrap();
+        assert_eq!(
+            endpoint.recv_with_timeout(TIMEOUT),
+            FrameStatus::Disconnected
+        );
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,256 @@
+// Copyright 2019 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::std_duration;
+use crate::ctap::hid::transport::{HidEndpoint, SendOrRecvStatus};
+use crate::ctap::hid::HidPacket;
+use crate::timer::Duration;
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::time::Instant;
+
+// The interface is documented in the Linux kernel, at Documentation/hid/uhid.rst, and the
+// structures are defined in include/uapi/linux/uhid.h.
+const UHID_PATH: &str = "/dev/uhid";
+const O_NONBLOCK: i32 = 0o4000;
+
+// Event types.
+const UHID_DESTROY: u32 = 1;
+const UHID_OUTPUT: u32 = 6;
+const UHID_CREATE2: u32 = 11;
+const UHID_INPUT2: u32 = 12;
+
+// The size of struct uhid_event, which is packed. Its largest member is struct uhid_create2_req.
+const UHID_EVENT_SIZE: usize = 4 + 128 + 64 + 64 + 2 + 2 + 4 + 4 + 4 + 4 + HID_MAX_DESCRIPTOR_SIZE;
+const HID_MAX_DESCRIPTOR_SIZE: usize = 4096;
+const BUS_USB: u16 = 0x03;
+
+// The vendor and product IDs of the OpenSK firmware, see rules.d/55-opensk.rules.
+const VENDOR_ID: u32 = 0x1915;
+const PRODUCT_ID: u32 = 0x521F;
+
+// CTAP specification (version 20190130) section 8.1.8.2
+const REPORT_DESCRIPTOR: [u8; 34] = [
+    0x06, 0xD0, 0xF1, // Usage Page (FIDO Alliance)
+    0x09, 0x01, // Usage (CTAPHID)
+    0xA1, 0x01, // Collection (Application)
+    0x09, 0x20, //   Usage (Input Report Data)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x26, 0xFF, 0x00, //   Logical Maximum (255)
+    0x75, 0x08, //   Report Size (8)
+    0x95, 0x40, //   Report Count (64)
+    0x81, 0x02, //   Input (Data, Var, Abs)
+    0x09, 0x21, //   Usage (Output Report Data)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x26, 0xFF, 0x00, //   Logical Maximum (255)
+    0x75, 0x08, //   Report Size (8)
+    0x95, 0x40, //   Report Count (64)
+    0x91, 0x02, //   Output (Data, Var, Abs)
+    0xC0, // End Collection
+];
+
+// How often a non-blocking read is retried while waiting for an event.
+const POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(1);
+
+// A virtual USB HID device created through the UHID driver of Linux. The host then sees a FIDO
+// security key, usable by browsers and libfido2 like a real one.
+pub struct UhidEndpoint {
+    file: Option<File>,
+}
+
+impl UhidEndpoint {
+    pub fn new() -> UhidEndpoint {
+        UhidEndpoint { file: None }
+    }
+
+    fn create_event() -> Vec<u8> {
+        let mut event = vec![0; UHID_EVENT_SIZE];
+        event[..4].copy_from_slice(&UHID_CREATE2.to_ne_bytes());
+        let name = b"OpenSK virtual authenticator";
+        event[4..4 + name.len()].copy_from_slice(name);
+        // The name, phys and uniq fields take 128, 64 and 64 bytes.
+        let mut offset = 4 + 128 + 64 + 64;
+        event[offset..offset + 2].copy_from_slice(&(REPORT_DESCRIPTOR.len() as u16).to_ne_bytes());
+        offset += 2;
+        event[offset..offset + 2].copy_from_slice(&BUS_USB.to_ne_bytes());
+        offset += 2;
+        event[offset..offset + 4].copy_from_slice(&VENDOR_ID.to_ne_bytes());
+        offset += 4;
+        event[offset..offset + 4].copy_from_slice(&PRODUCT_ID.to_ne_bytes());
+        // The version and country fields stay 0.
+        offset += 4 + 4 + 4;
+        event[offset..offset + REPORT_DESCRIPTOR.len()].copy_from_slice(&REPORT_DESCRIPTOR);
+        event
+    }
+
+    fn input_event(packet: &HidPacket) -> Vec<u8> {
+        let mut event = vec![0; UHID_EVENT_SIZE];
+        event[..4].copy_from_slice(&UHID_INPUT2.to_ne_bytes());
+        event[4..6].copy_from_slice(&(packet.len() as u16).to_ne_bytes());
+        event[6..6 + packet.len()].copy_from_slice(packet);
+        event
+    }
+
+    // Returns the output report carried by an event, if any. Reports written through hidraw are
+    // prefixed with the report number, which is 0 since the descriptor defines no report ID.
+    fn parse_output_event(event: &[u8]) -> Option<HidPacket> {
+        if event.len() < 4 + HID_MAX_DESCRIPTOR_SIZE + 2 {
+            return None;
+        }
+        let mut event_type = [0; 4];
+        event_type.copy_from_slice(&event[..4]);
+        if u32::from_ne_bytes(event_type) != UHID_OUTPUT {
+            return None;
+        }
+        let mut size = [0; 2];
+        size.copy_from_slice(&event[4 + HID_MAX_DESCRIPTOR_SIZE..4 + HID_MAX_DESCRIPTOR_SIZE + 2]);
+        let data = &event[4..4 + u16::from_ne_bytes(size) as usize];
+        let data = match data.len() {
+            64 => data,
+            65 if data[0] == 0 => &data[1..],
+            _ => return None,
+        };
+        let mut packet = [0; 64];
+        packet.copy_from_slice(data);
+        Some(packet)
+    }
+
+    fn disconnect(&mut self) -> Option<SendOrRecvStatus> {
+        if let Some(mut file) = self.file.take() {
+            let mut event = vec![0; UHID_EVENT_SIZE];
+            event[..4].copy_from_slice(&UHID_DESTROY.to_ne_bytes());
+            // Closing the file destroys the device anyway.
+            let _ = file.write_all(&event);
+        }
+        Some(SendOrRecvStatus::Error)
+    }
+}
+
+impl HidEndpoint for UhidEndpoint {
+    fn setup(&mut self) -> bool {
+        if self.file.is_some() {
+            return true;
+        }
+        let mut file = match OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(O_NONBLOCK)
+            .open(UHID_PATH)
+        {
+            Ok(file) => file,
+            Err(e) => {
+                eprintln!("Failed to open {}: {}", UHID_PATH, e);
+                return false;
+            }
+        };
+        if let Err(e) = file.write_all(&UhidEndpoint::create_event()) {
+            eprintln!("Failed to create the UHID device: {}", e);
+            return false;
+        }
+        self.file = Some(file);
+        true
+    }
+
+    fn recv_with_timeout(
+        &mut self,
+        buf: &mut HidPacket,
+        timeout_delay: Duration<isize>,
+    ) -> Option<SendOrRecvStatus> {
+        let deadline = Instant::now() + std_duration(timeout_delay);
+        let mut event = vec![0; UHID_EVENT_SIZE];
+        loop {
+            let file = match &mut self.file {
+                Some(file) => file,
+                None => return Some(SendOrRecvStatus::Error),
+            };
+            match file.read(&mut event) {
+                // Other events, like the host opening or closing the device, are ignored.
+                Ok(len) => {
+                    if let Some(packet) = UhidEndpoint::parse_output_event(&event[..len]) {
+                        *buf = packet;
+                        return Some(SendOrRecvStatus::Received);
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    if Instant::now() >= deadline {
+                        return None;
+                    }
+                    std::thread::sleep(POLL_INTERVAL);
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => (),
+                Err(_) => return self.disconnect(),
+            }
+        }
+    }
+
+    fn wait(&mut self, delay: Duration<isize>) {
+        std::thread::sleep(std_duration(delay));
+    }
+
+    // Input reports are queued by the kernel, so sending never needs to receive first.
+    fn send_or_recv_with_timeout(
+        &mut self,
+        buf: &mut HidPacket,
+        _timeout_delay: Duration<isize>,
+    ) -> Option<SendOrRecvStatus> {
+        let file = match &mut self.file {
+            Some(file) => file,
+            None => return Some(SendOrRecvStatus::Error),
+        };
+        match file.write_all(&UhidEndpoint::input_event(buf)) {
+            Ok(()) => Some(SendOrRecvStatus::Sent),
+            Err(_) => self.disconnect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_create_event() {
+        let event = UhidEndpoint::create_event();
+        assert_eq!(event.len(), 4376);
+        assert_eq!(&event[..4], &UHID_CREATE2.to_ne_bytes());
+        assert_eq!(&event[260..262], &34u16.to_ne_bytes());
+        assert_eq!(&event[262..264], &BUS_USB.to_ne_bytes());
+        assert_eq!(&event[280..314], &REPORT_DESCRIPTOR[..]);
+    }
+
+    #[test]
+    fn test_parse_output_event() {
+        let mut event = vec![0; UHID_EVENT_SIZE];
+        event[..4].copy_from_slice(&UHID_OUTPUT.to_ne_bytes());
+        event[5..69].copy_from_slice(&[0x42; 64]);
+        event[4100..4102].copy_from_slice(&65u16.to_ne_bytes());
+        assert_eq!(
+            UhidEndpoint::parse_output_event(&event).map(|packet| packet.to_vec()),
+            Some(vec![0x42; 64])
+        );
+
+        // Other events are ignored.
+        event[..4].copy_from_slice(&UHID_INPUT2.to_ne_bytes());
+        assert!(UhidEndpoint::parse_output_event(&event).is_none());
+    }
+
+    #[test]
+    fn test_input_event() {
+        let event = UhidEndpoint::input_event(&[0x42; 64]);
+        assert_eq!(&event[..4], &UHID_INPUT2.to_ne_bytes());
+        assert_eq!(&event[4..6], &64u16.to_ne_bytes());
+        assert_eq!(&event[6..70], &[0x42; 64][..]);
+    }
+}
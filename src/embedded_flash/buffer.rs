@@ -83,7 +83,19 @@ impl BufferStorage {
     ///
     /// Panics if a snapshot has been armed and not examined.
     pub fn arm_snapshot(&mut self, delay: usize) {
-        self.snapshot.arm(delay);
+        self.arm_interruption(delay, Interruption::Atomic);
+    }
+
+    /// Takes a snapshot of the storage while a given word operation is interrupted.
+    ///
+    /// This is like `arm_snapshot`, except that the interrupted operation is left in the snapshot
+    /// as described by `interruption`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a snapshot has been armed and not examined.
+    pub fn arm_interruption(&mut self, delay: usize, interruption: Interruption) {
+        self.snapshot.arm(delay, interruption);
     }
 
     /// Unarms and returns the snapshot or the delay remaining.
@@ -134,10 +146,16 @@ impl BufferStorage {
         let dst = index.range(value.len(), self)?.step_by(self.word_size());
         let src = value.chunks(self.word_size());
         // Check and update page shadow.
+        let mut original_page = None;
         if erase {
             let page = index.page;
             assert!(self.page_erases[page] < self.max_page_erases());
             self.page_erases[page] += 1;
+            // Interruptions may need the page content before the erase.
+            if self.snapshot.is_armed() {
+                let page_range = index.range(self.page_size(), self)?;
+                original_page = Some((page_range.start, self.storage[page_range].to_vec()));
+            }
         }
         for (byte, val) in dst.zip(src) {
             let range = byte..byte + self.word_size();
@@ -160,7 +178,10 @@ impl BufferStorage {
                 }
             }
             // Take snapshot if armed and delay expired.
-            self.snapshot.take(&self.storage);
+            let original_page = original_page
+                .as_ref()
+                .map(|(start, page)| (*start, &page[..]));
+            self.snapshot.take(&self.storage, byte, val, original_page);
             // Write storage
             self.storage[range].copy_from_slice(val);
         }
@@ -207,6 +228,30 @@ impl Storage for BufferStorage {
     }
 }
 
+/// How the interrupted word operation is left in a snapshot.
+///
+/// Word operations are executed in increasing address order. The variants other than `Atomic`
+/// model flash which doesn't write or erase words atomically, or which erases pages in another
+/// order. Those are outside the assumptions of the store.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Interruption {
+    /// The interrupted word is left unchanged.
+    Atomic,
+
+    /// Only the given number of bytes at the beginning of the interrupted word are updated.
+    PartialBytes(usize),
+
+    /// Only every other bit that changes in the interrupted word is updated, starting with the
+    /// least significant one.
+    PartialBits,
+
+    /// The interrupted page erase proceeds in decreasing address order.
+    ///
+    /// The words after the interrupted word are erased, those before are not. This is the same as
+    /// `Atomic` when a write is interrupted.
+    ReverseErase,
+}
+
 // Controls when a snapshot of the storage is taken.
 //
 // This can be used to simulate power-offs while the device is writing to the storage or erasing a
@@ -216,37 +261,97 @@ enum Snapshot {
     Ready,
     // If the delay is positive, mutable word operations decrement it. If the count is zero, mutable
     // word operations take a snapshot of the storage.
-    Armed { delay: usize },
+    Armed {
+        delay: usize,
+        interruption: Interruption,
+    },
     // Mutable word operations have normal behavior.
-    Taken { storage: Box<[u8]> },
+    Taken {
+        storage: Box<[u8]>,
+    },
 }
 
 impl Snapshot {
-    fn arm(&mut self, delay: usize) {
+    fn arm(&mut self, delay: usize, interruption: Interruption) {
         match self {
-            Snapshot::Ready => *self = Snapshot::Armed { delay },
+            Snapshot::Ready => {
+                *self = Snapshot::Armed {
+                    delay,
+                    interruption,
+                }
+            }
             _ => panic!(),
         }
     }
 
+    fn is_armed(&self) -> bool {
+        match self {
+            Snapshot::Armed { .. } => true,
+            _ => false,
+        }
+    }
+
     fn get(&mut self) -> Result<Box<[u8]>, usize> {
         let mut snapshot = Snapshot::Ready;
         core::mem::swap(self, &mut snapshot);
         match snapshot {
-            Snapshot::Armed { delay } => Err(delay),
+            Snapshot::Armed { delay, .. } => Err(delay),
             Snapshot::Taken { storage } => Ok(storage),
             _ => panic!(),
         }
     }
 
-    fn take(&mut self, storage: &[u8]) {
-        if let Snapshot::Armed { delay } = self {
-            if *delay == 0 {
-                let storage = storage.to_vec().into_boxed_slice();
-                *self = Snapshot::Taken { storage };
-            } else {
+    // Takes a snapshot before the word at `byte` is updated to `value`. For erases, the original
+    // page is given with the index of its first byte.
+    fn take(
+        &mut self,
+        storage: &[u8],
+        byte: usize,
+        value: &[u8],
+        original_page: Option<(usize, &[u8])>,
+    ) {
+        if let Snapshot::Armed {
+            delay,
+            interruption,
+        } = self
+        {
+            if *delay > 0 {
                 *delay -= 1;
+                return;
+            }
+            let mut storage = storage.to_vec().into_boxed_slice();
+            let word = &mut storage[byte..byte + value.len()];
+            match *interruption {
+                Interruption::Atomic => (),
+                Interruption::PartialBytes(length) => {
+                    let length = core::cmp::min(length, value.len());
+                    word[..length].copy_from_slice(&value[..length]);
+                }
+                Interruption::PartialBits => {
+                    let mut update = true;
+                    for (old, &new) in word.iter_mut().zip(value) {
+                        for bit in 0..8 {
+                            let mask = 1 << bit;
+                            if (*old ^ new) & mask != 0 {
+                                if update {
+                                    *old ^= mask;
+                                }
+                                update = !update;
+                            }
+                        }
+                    }
+                }
+                Interruption::ReverseErase => {
+                    if let Some((start, page)) = original_page {
+                        let end = start + page.len();
+                        storage[start..end].copy_from_slice(page);
+                        for byte in &mut storage[byte + value.len()..end] {
+                            *byte = 0xff;
+                        }
+                    }
+                }
             }
+            *self = Snapshot::Taken { storage };
         }
     }
 }
@@ -453,4 +558,35 @@ mod tests {
         let storage = buffer.take_snapshot();
         assert_eq!(&storage[..8], &value[..]);
     }
+
+    #[test]
+    fn interruption_ok() {
+        let index = Index { page: 0, byte: 0 };
+        let value = [FIRST_WORD, SECOND_WORD].concat();
+        let interrupt_write = |interruption| {
+            let mut buffer = BufferStorage::new(new_storage(), OPTIONS);
+            buffer.write_slice(index, FIRST_WORD).unwrap();
+            buffer.arm_interruption(0, interruption);
+            buffer.write_slice(index, &value).unwrap();
+            buffer.get_snapshot().unwrap()
+        };
+        let storage = interrupt_write(Interruption::PartialBytes(2));
+        assert_eq!(&storage[4..8], &[0xca, 0xc9, 0xff, 0xff]);
+        let storage = interrupt_write(Interruption::PartialBits);
+        // The changed bits of 0xff to 0xca are 0, 2, 4 and 5, so only 0 and 4 are cleared.
+        assert_eq!(storage[4], 0xee);
+        assert_eq!(&storage[..4], FIRST_WORD);
+
+        let mut buffer = BufferStorage::new(new_storage(), OPTIONS);
+        buffer.write_slice(index, &value).unwrap();
+        buffer
+            .write_slice(Index { page: 0, byte: 12 }, THIRD_WORD)
+            .unwrap();
+        buffer.arm_interruption(1, Interruption::ReverseErase);
+        buffer.erase_page(0).unwrap();
+        let storage = buffer.get_snapshot().unwrap();
+        assert_eq!(&storage[..8], &value[..]);
+        assert_eq!(&storage[12..16], BLANK_WORD);
+        assert_eq!(buffer.read_slice(index, 4).unwrap(), BLANK_WORD);
+    }
 }
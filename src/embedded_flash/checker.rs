@@ -0,0 +1,600 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Checks the store against power losses.
+//!
+//! The checker runs a sequence of operations on a `Store` backed by a `BufferStorage`. For each
+//! operation, it interrupts every word write and page erase in turn, re-opens the store from the
+//! resulting storage, and verifies the properties documented in the `store` module:
+//! - The interrupted operation either succeeded or was rolled-back, so the store contains the
+//!   entries from before or after the operation.
+//! - Deleted sensitive entries are wiped.
+//! - Re-opening the store again doesn't change its entries.
+//!
+//! Optionally, the recovery when re-opening the store is interrupted too, at every word operation.
+//! The store must then recover from the second interruption as well.
+
+use super::{
+    BufferOptions, BufferStorage, Interruption, Store, StoreConfig, StoreEntry, StoreError,
+    StoreUpdate,
+};
+use std::collections::BTreeSet;
+use std::panic::{self, AssertUnwindSafe};
+
+/// An entry owning its data, to describe operations and store contents.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct OwnedEntry {
+    pub tag: usize,
+    pub data: Vec<u8>,
+    pub sensitive: bool,
+}
+
+impl OwnedEntry {
+    fn borrow(&self) -> StoreEntry {
+        StoreEntry {
+            tag: self.tag,
+            data: &self.data,
+            sensitive: self.sensitive,
+        }
+    }
+}
+
+impl<'a> From<StoreEntry<'a>> for OwnedEntry {
+    fn from(entry: StoreEntry) -> OwnedEntry {
+        OwnedEntry {
+            tag: entry.tag,
+            data: entry.data.to_vec(),
+            sensitive: entry.sensitive,
+        }
+    }
+}
+
+/// A mutable operation of the store.
+///
+/// Existing entries are referred to by their content. The first matching entry is used.
+#[derive(Clone, Debug)]
+pub enum StoreOperation {
+    Insert(OwnedEntry),
+    Replace {
+        old: OwnedEntry,
+        new: OwnedEntry,
+    },
+    Delete(OwnedEntry),
+
+    /// Applies the operations atomically. Transactions can't be nested.
+    Transaction(Vec<StoreOperation>),
+}
+
+/// Why a store didn't survive an interruption.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Failure {
+    /// An operation or the recovery panicked, with the given message.
+    Panic(String),
+
+    /// The store couldn't be re-opened.
+    NotSupported,
+
+    /// The store contains neither the entries before nor after the operation.
+    UnexpectedEntries(Vec<OwnedEntry>),
+
+    /// A deleted sensitive entry was not wiped.
+    NotWiped,
+
+    /// Re-opening the store again changed its entries.
+    Unstable,
+}
+
+/// Where the store didn't survive an interruption.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Counterexample {
+    /// The index of the interrupted operation.
+    pub operation: usize,
+
+    /// The number of word operations before the interruption.
+    pub delay: usize,
+
+    /// How the word operation is interrupted.
+    pub interruption: Interruption,
+
+    /// The number of word operations of the recovery before it is interrupted, if it is.
+    pub recovery_delay: Option<usize>,
+
+    /// What went wrong.
+    pub failure: Failure,
+}
+
+/// What a successful check covered.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct CheckReport {
+    /// The number of word operations over all operations.
+    pub word_operations: usize,
+
+    /// The number of distinct storage states the store was re-opened from.
+    pub snapshots: usize,
+}
+
+/// Enumerates the interruption points of a sequence of store operations.
+pub struct Checker<C: StoreConfig, F: Fn() -> C> {
+    options: BufferOptions,
+    num_pages: usize,
+    new_config: F,
+    interruptions: Vec<Interruption>,
+    interrupt_recovery: bool,
+}
+
+impl<C: StoreConfig, F: Fn() -> C> Checker<C, F> {
+    /// Creates a checker for stores of `num_pages` pages.
+    ///
+    /// By default, only atomic interruptions are checked, and the recovery is not interrupted.
+    pub fn new(options: BufferOptions, num_pages: usize, new_config: F) -> Checker<C, F> {
+        Checker {
+            options,
+            num_pages,
+            new_config,
+            interruptions: vec![Interruption::Atomic],
+            interrupt_recovery: false,
+        }
+    }
+
+    /// Sets how word operations are interrupted.
+    pub fn interruptions(mut self, interruptions: &[Interruption]) -> Checker<C, F> {
+        self.interruptions = interruptions.to_vec();
+        self
+    }
+
+    /// Sets whether the recovery of each interruption is interrupted too.
+    ///
+    /// The recovery is always interrupted atomically.
+    pub fn interrupt_recovery(mut self, interrupt_recovery: bool) -> Checker<C, F> {
+        self.interrupt_recovery = interrupt_recovery;
+        self
+    }
+
+    /// Checks every interruption point of `operations`, starting from an erased storage.
+    ///
+    /// # Errors
+    ///
+    /// Returns the first interruption the store doesn't survive.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the operations fail without interruption, except with `StoreFull`.
+    pub fn check(&self, operations: &[StoreOperation]) -> Result<CheckReport, Counterexample> {
+        let mut report = CheckReport::default();
+        let mut before = Vec::new();
+        for (operation, store_operation) in operations.iter().enumerate() {
+            let mut after = before.clone();
+            let mut store = self.replay(&operations[..operation]);
+            store.arm_snapshot(usize::max_value());
+            if apply(&mut store, store_operation) {
+                apply_model(&mut after, store_operation);
+            }
+            let word_operations = usize::max_value() - store.get_snapshot().unwrap_err();
+            report.word_operations += word_operations;
+            let allowed = [before, after];
+            let counterexample = |delay, interruption, recovery_delay, failure| Counterexample {
+                operation,
+                delay,
+                interruption,
+                recovery_delay,
+                failure,
+            };
+
+            // The operation is interrupted after each of its word operations.
+            let mut snapshots = BTreeSet::new();
+            for delay in 0..word_operations {
+                for &interruption in &self.interruptions {
+                    let mut store = self.replay(&operations[..operation]);
+                    store.arm_interruption(delay, interruption);
+                    apply(&mut store, store_operation);
+                    let snapshot = store.get_snapshot().unwrap();
+                    if !snapshots.insert(snapshot.clone()) {
+                        continue;
+                    }
+                    self.check_snapshot(snapshot.clone(), None, &allowed)
+                        .map_err(|failure| counterexample(delay, interruption, None, failure))?;
+                    if !self.interrupt_recovery {
+                        continue;
+                    }
+                    for recovery_delay in 0.. {
+                        let interrupted = match self.check_snapshot(
+                            snapshot.clone(),
+                            Some(recovery_delay),
+                            &allowed,
+                        ) {
+                            Ok(interrupted) => interrupted,
+                            Err(failure) => {
+                                return Err(counterexample(
+                                    delay,
+                                    interruption,
+                                    Some(recovery_delay),
+                                    failure,
+                                ))
+                            }
+                        };
+                        if !interrupted {
+                            break;
+                        }
+                    }
+                }
+            }
+            report.snapshots += snapshots.len();
+
+            before = allowed[1].clone();
+        }
+        Ok(report)
+    }
+
+    fn new_store(&self, storage: Box<[u8]>) -> Option<Store<BufferStorage, C>> {
+        Store::new(
+            BufferStorage::new(storage, self.options),
+            (self.new_config)(),
+        )
+    }
+
+    // Runs operations from an erased storage.
+    fn replay(&self, operations: &[StoreOperation]) -> Store<BufferStorage, C> {
+        let storage = vec![0xff; self.num_pages * self.options.page_size].into_boxed_slice();
+        let mut store = self.new_store(storage).unwrap();
+        for operation in operations {
+            apply(&mut store, operation);
+        }
+        store
+    }
+
+    // Re-opens the store from a snapshot, and checks that it contains one of the allowed entries.
+    // If `recovery_delay` is given, the recovery is interrupted at that point first. Returns
+    // whether the recovery was interrupted.
+    fn check_snapshot(
+        &self,
+        snapshot: Box<[u8]>,
+        recovery_delay: Option<usize>,
+        allowed: &[Vec<OwnedEntry>; 2],
+    ) -> Result<bool, Failure> {
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            let mut storage = snapshot;
+            let mut interrupted = false;
+            if let Some(delay) = recovery_delay {
+                let mut buffer = BufferStorage::new(storage, self.options);
+                buffer.arm_snapshot(delay);
+                let mut store =
+                    Store::new(buffer, (self.new_config)()).ok_or(Failure::NotSupported)?;
+                storage = match store.get_snapshot() {
+                    Ok(snapshot) => {
+                        interrupted = true;
+                        snapshot
+                    }
+                    Err(_) => store.get_storage(),
+                };
+            }
+            let store = self.new_store(storage).ok_or(Failure::NotSupported)?;
+            let found = entries(&store);
+            if !allowed.contains(&found) {
+                return Err(Failure::UnexpectedEntries(found));
+            }
+            // Deleted sensitive entries are only erased with their page in the single-write format.
+            if self.options.max_word_writes > 1 && !store.deleted_entries_are_wiped() {
+                return Err(Failure::NotWiped);
+            }
+            let store = self
+                .new_store(store.get_storage())
+                .ok_or(Failure::NotSupported)?;
+            if entries(&store) != found {
+                return Err(Failure::Unstable);
+            }
+            Ok(interrupted)
+        }));
+        match result {
+            Ok(result) => result,
+            Err(payload) => {
+                let message = if let Some(message) = payload.downcast_ref::<&str>() {
+                    (*message).to_string()
+                } else if let Some(message) = payload.downcast_ref::<String>() {
+                    message.clone()
+                } else {
+                    String::new()
+                };
+                Err(Failure::Panic(message))
+            }
+        }
+    }
+}
+
+// Returns the sorted entries of a store.
+fn entries<C: StoreConfig>(store: &Store<BufferStorage, C>) -> Vec<OwnedEntry> {
+    let mut entries: Vec<OwnedEntry> = store.iter().map(|(_, entry)| entry.into()).collect();
+    entries.sort();
+    entries
+}
+
+// Applies an operation to the store. Returns whether it succeeded.
+fn apply<C: StoreConfig>(store: &mut Store<BufferStorage, C>, operation: &StoreOperation) -> bool {
+    let find = |store: &Store<BufferStorage, C>, entry: &OwnedEntry| {
+        store
+            .iter()
+            .find(|(_, other)| *other == entry.borrow())
+            .map(|(index, _)| index)
+            .expect("The operation refers to a missing entry.")
+    };
+    let result = match operation {
+        StoreOperation::Insert(entry) => store.insert(entry.borrow()).map(|_| ()),
+        StoreOperation::Replace { old, new } => {
+            let index = find(store, old);
+            store.replace(index, new.borrow()).map(|_| ())
+        }
+        StoreOperation::Delete(entry) => {
+            let index = find(store, entry);
+            store.delete(index)
+        }
+        StoreOperation::Transaction(operations) => {
+            let updates: Vec<StoreUpdate> = operations
+                .iter()
+                .map(|operation| match operation {
+                    StoreOperation::Insert(entry) => StoreUpdate::Insert(entry.borrow()),
+                    StoreOperation::Replace { old, new } => StoreUpdate::Replace {
+                        old: find(store, old),
+                        new: new.borrow(),
+                    },
+                    StoreOperation::Delete(entry) => StoreUpdate::Delete(find(store, entry)),
+                    StoreOperation::Transaction(_) => panic!("Transactions can't be nested."),
+                })
+                .collect();
+            store.transaction(&updates)
+        }
+    };
+    match result {
+        Ok(()) => true,
+        Err(StoreError::StoreFull) => false,
+        Err(error) => panic!("The operation failed: {:?}", error),
+    }
+}
+
+// Applies a successful operation to the sorted entries.
+fn apply_model(entries: &mut Vec<OwnedEntry>, operation: &StoreOperation) {
+    let remove = |entries: &mut Vec<OwnedEntry>, entry: &OwnedEntry| {
+        let position = entries.iter().position(|other| other == entry).unwrap();
+        entries.remove(position);
+    };
+    match operation {
+        StoreOperation::Insert(entry) => entries.push(entry.clone()),
+        StoreOperation::Replace { old, new } => {
+            remove(entries, old);
+            entries.push(new.clone());
+        }
+        StoreOperation::Delete(entry) => remove(entries, entry),
+        StoreOperation::Transaction(operations) => {
+            for operation in operations {
+                apply_model(entries, operation);
+            }
+        }
+    }
+    entries.sort();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Config {
+        checksum: bool,
+    }
+
+    const WORD_SIZE: usize = 4;
+    const PAGE_SIZE: usize = 8 * WORD_SIZE;
+    const NUM_PAGES: usize = 3;
+    const OPTIONS: BufferOptions = BufferOptions {
+        word_size: WORD_SIZE,
+        page_size: PAGE_SIZE,
+        max_word_writes: 2,
+        max_page_erases: 4,
+        strict_write: true,
+    };
+
+    impl StoreConfig for Config {
+        type Key = ();
+
+        fn num_tags(&self) -> usize {
+            2
+        }
+
+        fn keys(&self, _entry: StoreEntry, _add: impl FnMut(())) {}
+
+        fn checksum(&self) -> bool {
+            self.checksum
+        }
+    }
+
+    fn new_checker() -> Checker<Config, impl Fn() -> Config> {
+        Checker::new(OPTIONS, NUM_PAGES, || Config { checksum: false })
+    }
+
+    fn new_checksum_checker() -> Checker<Config, impl Fn() -> Config> {
+        Checker::new(OPTIONS, NUM_PAGES, || Config { checksum: true })
+    }
+
+    fn entry(tag: usize, data: &[u8], sensitive: bool) -> OwnedEntry {
+        OwnedEntry {
+            tag,
+            data: data.to_vec(),
+            sensitive,
+        }
+    }
+
+    // Inserts, replaces and deletes entries until pages are compacted.
+    fn operations() -> Vec<StoreOperation> {
+        let mut operations = vec![
+            StoreOperation::Insert(entry(0, &[0x00, 0x01], false)),
+            StoreOperation::Insert(entry(1, &[0x10, 0x11, 0x12], true)),
+            StoreOperation::Replace {
+                old: entry(0, &[0x00, 0x01], false),
+                new: entry(0, &[0x02], false),
+            },
+            StoreOperation::Delete(entry(1, &[0x10, 0x11, 0x12], true)),
+        ];
+        for i in 0..6 {
+            operations.push(StoreOperation::Insert(entry(1, &[0x20 + i; 2], false)));
+            operations.push(StoreOperation::Replace {
+                old: entry(1, &[0x20 + i; 2], false),
+                new: entry(1, &[0x30 + i; 3], i % 2 == 0),
+            });
+            operations.push(StoreOperation::Delete(entry(1, &[0x30 + i; 3], i % 2 == 0)));
+        }
+        operations
+    }
+
+    #[test]
+    fn atomic_interruptions_ok() {
+        let report = new_checker().check(&operations()).unwrap();
+        assert!(report.snapshots > 0);
+        assert!(report.snapshots <= report.word_operations);
+        new_checksum_checker().check(&operations()).unwrap();
+    }
+
+    #[test]
+    fn compaction_happens() {
+        // The operations must write more than the store holds, so that pages are compacted.
+        let store = new_checker().replay(&operations());
+        assert!(store.compaction_info().iter().any(|&count| count > 0));
+    }
+
+    #[test]
+    fn interrupted_recovery_ok() {
+        new_checker()
+            .interrupt_recovery(true)
+            .check(&operations())
+            .unwrap();
+    }
+
+    #[test]
+    fn store_full_ok() {
+        // Inserting fails once the store is full, and must not change it even when interrupted.
+        let operations: Vec<_> = (0..16)
+            .map(|i| StoreOperation::Insert(entry(0, &[i; 6], false)))
+            .collect();
+        new_checker().check(&operations).unwrap();
+    }
+
+    #[test]
+    fn partial_writes_ok() {
+        // Torn words are outside the store assumptions, but they only corrupt the entry being
+        // written, which is then quarantined or rolled-back.
+        let interruptions = [
+            Interruption::PartialBytes(1),
+            Interruption::PartialBytes(2),
+            Interruption::PartialBytes(3),
+            Interruption::PartialBits,
+        ];
+        new_checker()
+            .interruptions(&interruptions)
+            .check(&operations())
+            .unwrap();
+        new_checksum_checker()
+            .interruptions(&interruptions)
+            .check(&operations())
+            .unwrap();
+    }
+
+    #[test]
+    fn reverse_erase_found() {
+        // A torn erase is outside the store assumptions: the old page may still look like it is
+        // being compacted while its entries are gone. The checker must report it.
+        let counterexample = new_checker()
+            .interruptions(&[Interruption::ReverseErase])
+            .check(&operations())
+            .unwrap_err();
+        assert_eq!(counterexample.interruption, Interruption::ReverseErase);
+    }
+
+    // Applies transactions until pages are compacted.
+    fn transactions() -> Vec<StoreOperation> {
+        let mut operations = vec![
+            StoreOperation::Insert(entry(0, &[0x00], false)),
+            StoreOperation::Insert(entry(1, &[0x10], true)),
+            StoreOperation::Transaction(vec![
+                StoreOperation::Replace {
+                    old: entry(0, &[0x00], false),
+                    new: entry(0, &[0x01], false),
+                },
+                StoreOperation::Delete(entry(1, &[0x10], true)),
+            ]),
+            // This transaction doesn't fit in a page.
+            StoreOperation::Transaction(vec![
+                StoreOperation::Insert(entry(1, &[0x11; 30], false)),
+                StoreOperation::Insert(entry(1, &[0x12; 30], false)),
+            ]),
+        ];
+        for i in 0..4 {
+            operations.push(StoreOperation::Transaction(vec![
+                StoreOperation::Replace {
+                    old: entry(0, &[i + 1], false),
+                    new: entry(0, &[i + 2], false),
+                },
+                StoreOperation::Insert(entry(1, &[0x20 + i], false)),
+            ]));
+            operations.push(StoreOperation::Transaction(vec![StoreOperation::Delete(
+                entry(1, &[0x20 + i], false),
+            )]));
+        }
+        operations
+    }
+
+    #[test]
+    fn transactions_ok() {
+        // Transactions need larger pages, since they are written in a single page.
+        let options = BufferOptions {
+            page_size: 2 * PAGE_SIZE,
+            ..OPTIONS
+        };
+        let new_checker = |checksum| Checker::new(options, NUM_PAGES, move || Config { checksum });
+        let store = new_checker(false).replay(&transactions());
+        assert!(store.compaction_info().iter().any(|&count| count > 0));
+        new_checker(false)
+            .interrupt_recovery(true)
+            .check(&transactions())
+            .unwrap();
+        new_checker(true)
+            .interruptions(&[Interruption::Atomic, Interruption::PartialBits])
+            .check(&transactions())
+            .unwrap();
+    }
+
+    #[test]
+    fn single_write_ok() {
+        // The buffer panics if a word is written twice, which the checker reports.
+        for &word_size in &[8, 16] {
+            let options = BufferOptions {
+                word_size,
+                page_size: 16 * word_size,
+                max_word_writes: 1,
+                ..OPTIONS
+            };
+            let new_checker =
+                |checksum| Checker::new(options, NUM_PAGES, move || Config { checksum });
+            let store = new_checker(false).replay(&operations());
+            assert!(store.compaction_info().iter().any(|&count| count > 0));
+            new_checker(false)
+                .interrupt_recovery(true)
+                .check(&operations())
+                .unwrap();
+            new_checker(true)
+                .interrupt_recovery(true)
+                .check(&transactions())
+                .unwrap();
+            new_checker(false)
+                .interruptions(&[Interruption::PartialBytes(1), Interruption::PartialBits])
+                .check(&operations())
+                .unwrap();
+        }
+    }
+}
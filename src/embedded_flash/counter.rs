@@ -0,0 +1,414 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Provides a flash-friendly monotonic counter.
+//!
+//! # Description
+//!
+//! A `Counter` holds a 32-bit value which never decreases. An increment usually writes a single
+//! word, and a page is only erased after many increments. So a counter can be incremented often
+//! without wearing the flash, contrary to an entry of the store which is rewritten on each update.
+//!
+//! Increments are atomic: if power is lost during an increment, the counter holds either its
+//! previous value or the incremented one, and keeps holding it when read again. This relies on the
+//! same assumptions as the store, except that words don't need to be written atomically.
+//!
+//! # Implementation
+//!
+//! A counter uses its own pages of the storage, at least 2. A page is either unused or made of a
+//! header followed by step words:
+//!
+//! ```text
+//! Page := Header Step*
+//! Header :=  // 2 words
+//!     base:32 Padding(word)
+//!     check:32 Padding(word)  // the complement of `base`
+//! ```
+//!
+//! A step word is split in as many slices as it can be written between erasures (at most one per
+//! bit). Each step clears one more slice, from the least significant bits, so a slice counts as
+//! soon as one of its bits is cleared. The value of a page is its base plus the number of steps in
+//! its step words. The value of the counter is the value of the valid page (whose `check` is the
+//! complement of `base`) with the largest base, or 0 if there is none.
+//!
+//! An increment writes the next step of the current page. When the page is full, the next page
+//! (cyclically) is erased if needed, and its header is written with the incremented value as base.
+//! An interrupted step either counts or leaves its word unchanged. An interrupted header is not
+//! valid: bits only flip from 1 to 0 while written, and from 0 to 1 while erased, so `base` and
+//! `check` only complement each other once completely written. An interrupted erase only lowers
+//! the value of a page that is not the current one.
+//!
+//! With `P` pages of `N` words, written at most `W` times between erasures, a page is erased at
+//! most once every `P * ((N - 2) * W + 1)` increments.
+
+use super::{Index, Storage};
+use alloc::vec;
+use alloc::vec::Vec;
+
+/// The number of bytes of the header fields, at the beginning of their word.
+const FIELD_SIZE: usize = 4;
+
+/// A monotonic counter on some pages of a storage.
+///
+/// The counter doesn't own its storage. The same storage must be given to all its operations.
+#[derive(Clone)]
+#[cfg_attr(feature = "std", derive(Debug))]
+pub struct Counter {
+    /// The first page of the counter in the storage.
+    first_page: usize,
+
+    /// The number of pages of the counter.
+    num_pages: usize,
+
+    /// The number of steps of a step word.
+    word_steps: usize,
+
+    /// The current page, relative to `first_page`, or `None` if no page is valid.
+    page: Option<usize>,
+
+    /// The word of the current page where the next step is written.
+    ///
+    /// The page is full when this is the number of words in a page.
+    word: usize,
+
+    /// The number of steps already in `word`.
+    steps: usize,
+
+    /// The value of the counter.
+    value: u32,
+}
+
+impl Counter {
+    /// Opens a counter on `num_pages` pages of `storage`, starting at `first_page`.
+    ///
+    /// The pages may be fresh (filled with `0xff`), in which case the counter is 0.
+    ///
+    /// # Errors
+    ///
+    /// Returns `None` if any of the following conditions does not hold:
+    /// - The word size must be a power of two and hold a header field.
+    /// - A page must hold the header and at least one step word.
+    /// - There should be at least 2 pages, and they should be in the storage.
+    /// - It should be possible to write a word at least once.
+    pub fn new(storage: &impl Storage, first_page: usize, num_pages: usize) -> Option<Counter> {
+        let word_size = storage.word_size();
+        if !(word_size.is_power_of_two()
+            && word_size >= FIELD_SIZE
+            && storage.page_size() >= 3 * word_size
+            && num_pages >= 2
+            && first_page + num_pages <= storage.num_pages()
+            && storage.max_word_writes() > 0)
+        {
+            return None;
+        }
+        let mut counter = Counter {
+            first_page,
+            num_pages,
+            word_steps: core::cmp::min(storage.max_word_writes(), 8 * word_size),
+            page: None,
+            word: 0,
+            steps: 0,
+            value: 0,
+        };
+        counter.page = (0..num_pages)
+            .filter_map(|page| Some((counter.read_base(storage, page)?, page)))
+            .max()
+            .map(|(_, page)| page);
+        if let Some(page) = counter.page {
+            counter.value = counter.read_base(storage, page).unwrap();
+            counter.word = 2;
+            while counter.word < counter.words_per_page(storage) {
+                let steps = counter.read_steps(storage, page, counter.word);
+                counter.value = counter.value.saturating_add(steps as u32);
+                if steps < counter.word_steps {
+                    counter.steps = steps;
+                    break;
+                }
+                counter.word += 1;
+            }
+            // Steps after the next one are not written, unless the flash is corrupted.
+            for word in counter.word + 1..counter.words_per_page(storage) {
+                let steps = counter.read_steps(storage, page, word);
+                counter.value = counter.value.saturating_add(steps as u32);
+            }
+        }
+        Some(counter)
+    }
+
+    /// Returns the value of the counter.
+    pub fn value(&self) -> u32 {
+        self.value
+    }
+
+    /// Increments the counter and returns its new value.
+    ///
+    /// # Errors
+    ///
+    /// Returns `None` if the counter is at its maximum value, in which case it is left unchanged.
+    pub fn increment(&mut self, storage: &mut impl Storage) -> Option<u32> {
+        let value = self.value.checked_add(1)?;
+        match self.page {
+            Some(page) if self.word < self.words_per_page(storage) => {
+                self.write_step(storage, page);
+                self.value = value;
+            }
+            _ => self.start_page(storage, value),
+        }
+        Some(value)
+    }
+
+    /// Sets the counter to `value` if it is larger than its current value.
+    ///
+    /// This takes a new page, so it should only be used for occasional jumps, like importing the
+    /// value of another counter.
+    pub fn advance(&mut self, storage: &mut impl Storage, value: u32) {
+        if value > self.value {
+            self.start_page(storage, value);
+        }
+    }
+
+    /// Returns the number of words in a page.
+    fn words_per_page(&self, storage: &impl Storage) -> usize {
+        storage.page_size() / storage.word_size()
+    }
+
+    /// Returns the index of a word of a page.
+    fn index(&self, storage: &impl Storage, page: usize, word: usize) -> Index {
+        Index {
+            page: self.first_page + page,
+            byte: word * storage.word_size(),
+        }
+    }
+
+    /// Returns the base of a page, if its header is valid.
+    fn read_base(&self, storage: &impl Storage, page: usize) -> Option<u32> {
+        let read_field = |word| {
+            let index = self.index(storage, page, word);
+            let mut field = [0; FIELD_SIZE];
+            field.copy_from_slice(storage.read_slice(index, FIELD_SIZE).unwrap());
+            u32::from_le_bytes(field)
+        };
+        let base = read_field(0);
+        if read_field(1) == !base {
+            Some(base)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the number of steps in a step word.
+    fn read_steps(&self, storage: &impl Storage, page: usize, word: usize) -> usize {
+        let index = self.index(storage, page, word);
+        let value = storage.read_slice(index, storage.word_size()).unwrap();
+        let word_bits = 8 * value.len();
+        let is_cleared = |bit: usize| value[bit / 8] & (1 << (bit % 8)) == 0;
+        (0..self.word_steps)
+            .filter(|&step| {
+                let start = step * word_bits / self.word_steps;
+                let end = (step + 1) * word_bits / self.word_steps;
+                (start..end).any(is_cleared)
+            })
+            .count()
+    }
+
+    /// Writes the next step in the current page.
+    fn write_step(&mut self, storage: &mut impl Storage, page: usize) {
+        let word_size = storage.word_size();
+        let cleared_bits = (self.steps + 1) * 8 * word_size / self.word_steps;
+        let value: Vec<u8> = (0..word_size)
+            .map(|byte| {
+                let cleared = core::cmp::min(cleared_bits.saturating_sub(8 * byte), 8);
+                (0xffu16 << cleared) as u8
+            })
+            .collect();
+        let index = self.index(storage, page, self.word);
+        storage.write_slice(index, &value).unwrap();
+        self.steps += 1;
+        if self.steps == self.word_steps {
+            self.word += 1;
+            self.steps = 0;
+        }
+    }
+
+    /// Makes the next page the current one, with a given base.
+    fn start_page(&mut self, storage: &mut impl Storage, base: u32) {
+        let page = self.page.map_or(0, |page| (page + 1) % self.num_pages);
+        let index = self.index(storage, page, 0);
+        let is_erased = storage
+            .read_slice(index, storage.page_size())
+            .unwrap()
+            .iter()
+            .all(|&byte| byte == 0xff);
+        if !is_erased {
+            storage.erase_page(index.page).unwrap();
+        }
+        let word_size = storage.word_size();
+        let mut header = vec![0xff; 2 * word_size];
+        header[..FIELD_SIZE].copy_from_slice(&base.to_le_bytes());
+        header[word_size..word_size + FIELD_SIZE].copy_from_slice(&(!base).to_le_bytes());
+        storage.write_slice(index, &header).unwrap();
+        self.page = Some(page);
+        self.word = 2;
+        self.steps = 0;
+        self.value = base;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{BufferOptions, BufferStorage, Interruption};
+    use super::*;
+
+    const NUM_PAGES: usize = 2;
+    // A page holds 6 step words, so 12 steps with 2 writes per word, and a header.
+    const PAGE_SIZE: usize = 32;
+    const OPTIONS: BufferOptions = BufferOptions {
+        word_size: 4,
+        page_size: PAGE_SIZE,
+        max_word_writes: 2,
+        max_page_erases: 10000,
+        strict_write: true,
+    };
+    const INCREMENTS_PER_PAGE: usize = 13;
+
+    fn new_storage(options: BufferOptions) -> BufferStorage {
+        let storage = vec![0xff; NUM_PAGES * options.page_size].into_boxed_slice();
+        BufferStorage::new(storage, options)
+    }
+
+    fn open(storage: &BufferStorage) -> Counter {
+        Counter::new(storage, 0, NUM_PAGES).unwrap()
+    }
+
+    #[test]
+    fn new_checks_geometry() {
+        let storage = new_storage(OPTIONS);
+        assert!(Counter::new(&storage, 0, 1).is_none());
+        assert!(Counter::new(&storage, 1, 2).is_none());
+        let storage = new_storage(BufferOptions {
+            word_size: 2,
+            ..OPTIONS
+        });
+        assert!(Counter::new(&storage, 0, 2).is_none());
+        let storage = new_storage(BufferOptions {
+            page_size: 8,
+            ..OPTIONS
+        });
+        assert!(Counter::new(&storage, 0, 2).is_none());
+    }
+
+    #[test]
+    fn increment_ok() {
+        let mut storage = new_storage(OPTIONS);
+        let mut counter = open(&storage);
+        assert_eq!(counter.value(), 0);
+        for value in 1..=5 * INCREMENTS_PER_PAGE as u32 {
+            assert_eq!(counter.increment(&mut storage), Some(value));
+            assert_eq!(counter.value(), value);
+            assert_eq!(open(&storage).value(), value);
+        }
+    }
+
+    #[test]
+    fn increment_single_write() {
+        let options = BufferOptions {
+            max_word_writes: 1,
+            ..OPTIONS
+        };
+        let mut storage = new_storage(options);
+        let mut counter = open(&storage);
+        for value in 1..=50 {
+            assert_eq!(counter.increment(&mut storage), Some(value));
+            assert_eq!(open(&storage).value(), value);
+        }
+    }
+
+    #[test]
+    fn increment_overflow() {
+        let mut storage = new_storage(OPTIONS);
+        let mut counter = open(&storage);
+        counter.advance(&mut storage, u32::max_value() - 1);
+        assert_eq!(counter.increment(&mut storage), Some(u32::max_value()));
+        assert_eq!(counter.increment(&mut storage), None);
+        assert_eq!(open(&storage).value(), u32::max_value());
+    }
+
+    #[test]
+    fn advance_ok() {
+        let mut storage = new_storage(OPTIONS);
+        let mut counter = open(&storage);
+        counter.advance(&mut storage, 1000);
+        assert_eq!(open(&storage).value(), 1000);
+        // The counter never decreases.
+        counter.advance(&mut storage, 10);
+        assert_eq!(counter.value(), 1000);
+        assert_eq!(counter.increment(&mut storage), Some(1001));
+        assert_eq!(open(&storage).value(), 1001);
+    }
+
+    #[test]
+    fn increment_wear() {
+        // Each page can be erased 10 times, and isn't erased the first time it is used.
+        let max_page_erases = 10;
+        let options = BufferOptions {
+            max_page_erases,
+            ..OPTIONS
+        };
+        let mut storage = new_storage(options);
+        let mut counter = open(&storage);
+        // The storage panics if a word is written too often or a page erased too often.
+        let increments = NUM_PAGES * (max_page_erases + 1) * INCREMENTS_PER_PAGE;
+        for _ in 0..increments {
+            counter.increment(&mut storage).unwrap();
+        }
+        assert_eq!(open(&storage).value(), increments as u32);
+    }
+
+    #[test]
+    fn increment_atomic() {
+        let interruptions = [
+            Interruption::Atomic,
+            Interruption::PartialBytes(1),
+            Interruption::PartialBytes(2),
+            Interruption::PartialBytes(3),
+            Interruption::PartialBits,
+            Interruption::ReverseErase,
+        ];
+        // Covers a few page changes, including erasing a page previously used.
+        for before in 0..4 * INCREMENTS_PER_PAGE as u32 {
+            for &interruption in &interruptions {
+                for delay in 0.. {
+                    let mut storage = new_storage(OPTIONS);
+                    let mut counter = open(&storage);
+                    for _ in 0..before {
+                        counter.increment(&mut storage).unwrap();
+                    }
+                    storage.arm_interruption(delay, interruption);
+                    counter.increment(&mut storage).unwrap();
+                    let snapshot = match storage.get_snapshot() {
+                        Ok(snapshot) => snapshot,
+                        Err(_) => break,
+                    };
+                    let mut storage = BufferStorage::new(snapshot, OPTIONS);
+                    let mut counter = open(&storage);
+                    let value = counter.value();
+                    assert!(value == before || value == before + 1);
+                    assert_eq!(open(&storage).value(), value);
+                    assert_eq!(counter.increment(&mut storage), Some(value + 1));
+                    assert_eq!(open(&storage).value(), value + 1);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,245 @@
+// Copyright 2019 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::{BufferOptions, BufferStorage, Index, Storage, StorageError, StorageResult};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Embedded flash persisted in a regular file.
+///
+/// The file contains the raw flash image, in the same layout as the flash of a device. Reads are
+/// served from a copy in memory, and writes and erases go through to the file.
+pub struct FileStorage {
+    buffer: BufferStorage,
+    file: File,
+}
+
+impl FileStorage {
+    /// Opens a flash image, or creates an erased one with `num_pages` pages.
+    ///
+    /// This implementation performs the same checks as [`BufferStorage`], except that write and
+    /// erase counts start from zero each time the file is opened.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file can't be accessed, or if an existing file doesn't contain
+    /// exactly `num_pages` pages.
+    ///
+    /// # Panics
+    ///
+    /// Panics under the same conditions as [`BufferStorage::new`].
+    ///
+    /// [`BufferStorage`]: struct.BufferStorage.html
+    /// [`BufferStorage::new`]: struct.BufferStorage.html#method.new
+    pub fn open(
+        path: &Path,
+        num_pages: usize,
+        options: BufferOptions,
+    ) -> std::io::Result<FileStorage> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(path)?;
+        let size = num_pages * options.page_size;
+        let mut storage = Vec::with_capacity(size);
+        file.read_to_end(&mut storage)?;
+        if storage.is_empty() {
+            storage.resize(size, 0xff);
+            file.write_all(&storage)?;
+        } else if storage.len() != size {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "the flash image has {} bytes instead of {}",
+                    storage.len(),
+                    size
+                ),
+            ));
+        }
+        let buffer = BufferStorage::new(storage.into_boxed_slice(), options);
+        Ok(FileStorage { buffer, file })
+    }
+
+    /// Returns the storage in memory, detached from the file.
+    pub fn into_buffer(self) -> BufferStorage {
+        self.buffer
+    }
+
+    // Copies a range of the storage in memory to the file.
+    fn write_through(&mut self, index: Index, length: usize) -> StorageResult<()> {
+        let range = index.range(length, &self.buffer)?;
+        let value = self.buffer.read_slice(index, length)?;
+        let file = &mut self.file;
+        file.seek(SeekFrom::Start(range.start as u64))
+            .and_then(|_| file.write_all(value))
+            .map_err(|_| StorageError::BadFlash)
+    }
+}
+
+/// Loads a raw flash dump in memory, for example one read from a device.
+///
+/// The size of the dump must be page-aligned. Modifications are not written back to the file.
+///
+/// # Panics
+///
+/// Panics under the same conditions as [`BufferStorage::new`].
+///
+/// [`BufferStorage::new`]: struct.BufferStorage.html#method.new
+pub fn load_dump(path: &Path, options: BufferOptions) -> std::io::Result<BufferStorage> {
+    let storage = std::fs::read(path)?;
+    Ok(BufferStorage::new(storage.into_boxed_slice(), options))
+}
+
+impl Storage for FileStorage {
+    fn word_size(&self) -> usize {
+        self.buffer.word_size()
+    }
+
+    fn page_size(&self) -> usize {
+        self.buffer.page_size()
+    }
+
+    fn num_pages(&self) -> usize {
+        self.buffer.num_pages()
+    }
+
+    fn max_word_writes(&self) -> usize {
+        self.buffer.max_word_writes()
+    }
+
+    fn max_page_erases(&self) -> usize {
+        self.buffer.max_page_erases()
+    }
+
+    fn read_slice(&self, index: Index, length: usize) -> StorageResult<&[u8]> {
+        self.buffer.read_slice(index, length)
+    }
+
+    fn write_slice(&mut self, index: Index, value: &[u8]) -> StorageResult<()> {
+        self.buffer.write_slice(index, value)?;
+        self.write_through(index, value.len())
+    }
+
+    fn erase_page(&mut self, page: usize) -> StorageResult<()> {
+        self.buffer.erase_page(page)?;
+        let page_size = self.page_size();
+        self.write_through(Index { page, byte: 0 }, page_size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    const NUM_PAGES: usize = 2;
+    const OPTIONS: BufferOptions = BufferOptions {
+        word_size: 4,
+        page_size: 16,
+        max_word_writes: 2,
+        max_page_erases: 3,
+        strict_write: true,
+    };
+    const BLANK_WORD: &[u8] = &[0xff, 0xff, 0xff, 0xff];
+    const FIRST_WORD: &[u8] = &[0xee, 0xdd, 0xbb, 0x77];
+    const SECOND_WORD: &[u8] = &[0xca, 0xc9, 0xa9, 0x65];
+
+    // Returns a path that doesn't exist yet, and is removed when dropped.
+    struct TempPath(PathBuf);
+
+    impl TempPath {
+        fn new(name: &str) -> TempPath {
+            let path = std::env::temp_dir().join(format!(
+                "opensk-flash-{}-{}.bin",
+                name,
+                std::process::id()
+            ));
+            let _ = std::fs::remove_file(&path);
+            TempPath(path)
+        }
+    }
+
+    impl Drop for TempPath {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn create_erased() {
+        let path = TempPath::new("create");
+        let storage = FileStorage::open(&path.0, NUM_PAGES, OPTIONS).unwrap();
+        assert_eq!(storage.num_pages(), NUM_PAGES);
+        assert_eq!(storage.page_size(), OPTIONS.page_size);
+        assert_eq!(
+            std::fs::read(&path.0).unwrap(),
+            vec![0xff; NUM_PAGES * OPTIONS.page_size]
+        );
+    }
+
+    #[test]
+    fn persist_write_and_erase() {
+        let path = TempPath::new("persist");
+        let index = Index { page: 1, byte: 4 };
+        let mut storage = FileStorage::open(&path.0, NUM_PAGES, OPTIONS).unwrap();
+        storage.write_slice(index, FIRST_WORD).unwrap();
+        drop(storage);
+
+        let mut storage = FileStorage::open(&path.0, NUM_PAGES, OPTIONS).unwrap();
+        assert_eq!(storage.read_slice(index, 4).unwrap(), FIRST_WORD);
+        storage.write_slice(index, SECOND_WORD).unwrap();
+        let image = std::fs::read(&path.0).unwrap();
+        assert_eq!(&image[20..24], SECOND_WORD);
+
+        storage.erase_page(1).unwrap();
+        drop(storage);
+        let storage = FileStorage::open(&path.0, NUM_PAGES, OPTIONS).unwrap();
+        assert_eq!(storage.read_slice(index, 4).unwrap(), BLANK_WORD);
+    }
+
+    #[test]
+    #[should_panic]
+    fn write_three_times_panics() {
+        let path = TempPath::new("write_three_times");
+        let index = Index { page: 0, byte: 4 };
+        let mut storage = FileStorage::open(&path.0, NUM_PAGES, OPTIONS).unwrap();
+        assert!(storage.write_slice(index, FIRST_WORD).is_ok());
+        assert!(storage.write_slice(index, SECOND_WORD).is_ok());
+        let _ = storage.write_slice(index, &[0x88, 0x88, 0x88, 0x44]);
+    }
+
+    #[test]
+    fn wrong_size() {
+        let path = TempPath::new("wrong_size");
+        std::fs::write(&path.0, vec![0xff; OPTIONS.page_size]).unwrap();
+        assert!(FileStorage::open(&path.0, NUM_PAGES, OPTIONS).is_err());
+    }
+
+    #[test]
+    fn load_dump_read_only() {
+        let path = TempPath::new("dump");
+        let mut dump = vec![0xff; NUM_PAGES * OPTIONS.page_size];
+        dump[..4].copy_from_slice(FIRST_WORD);
+        std::fs::write(&path.0, &dump).unwrap();
+
+        let index = Index { page: 0, byte: 0 };
+        let mut storage = load_dump(&path.0, OPTIONS).unwrap();
+        assert_eq!(storage.num_pages(), NUM_PAGES);
+        assert_eq!(storage.read_slice(index, 4).unwrap(), FIRST_WORD);
+        storage.erase_page(0).unwrap();
+        assert_eq!(std::fs::read(&path.0).unwrap(), dump);
+    }
+}
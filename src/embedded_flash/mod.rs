@@ -13,11 +13,21 @@
 // limitations under the License.
 
 mod buffer;
+#[cfg(feature = "std")]
+pub mod checker;
+mod counter;
+#[cfg(feature = "std")]
+mod file;
 mod storage;
 mod store;
 mod syscall;
 
-pub use self::buffer::{BufferOptions, BufferStorage};
+pub use self::buffer::{BufferOptions, BufferStorage, Interruption};
+pub use self::counter::Counter;
+#[cfg(feature = "std")]
+pub use self::file::{load_dump, FileStorage};
 pub use self::storage::{Index, Storage, StorageError, StorageResult};
-pub use self::store::{Store, StoreConfig, StoreEntry, StoreError, StoreIndex};
+pub use self::store::{
+    Store, StoreConfig, StoreEntry, StoreError, StoreHealth, StoreIndex, StoreUpdate, COUNTER_PAGES,
+};
 pub use self::syscall::SyscallStorage;
@@ -58,6 +58,7 @@ pub trait Storage {
     /// Writes a word-aligned slice to the storage.
     ///
     /// The written words should not have been written too many times since last page erasure.
+    /// Words already holding their value are not written and don't count.
     ///
     /// # Errors
     ///
@@ -13,9 +13,12 @@
 // limitations under the License.
 
 use super::super::{Index, Storage};
-use super::{bitfield, StoreConfig, StoreEntry, StoreError};
+use super::{bitfield, StoreConfig, StoreEntry, StoreError, COUNTER_PAGES};
 use alloc::vec::Vec;
 
+/// The length in bits of entry checksums, when enabled.
+const CHECKSUM_BITS: usize = 16;
+
 /// Whether a user entry is a replace entry.
 pub enum IsReplace {
     /// This is a replace entry.
@@ -25,6 +28,21 @@ pub enum IsReplace {
     Insert,
 }
 
+/// The kind of an internal entry.
+pub enum InternalKind {
+    /// An entry to erase the page being compacted.
+    Erase,
+
+    /// The beginning of a transaction.
+    Begin,
+
+    /// The commit point of a transaction.
+    Commit,
+
+    /// An entry to delete when a transaction is committed.
+    Delete,
+}
+
 /// Helpers to parse the store format.
 ///
 /// See the store module-level documentation for information about the format.
@@ -35,6 +53,12 @@ pub struct Format {
     pub max_page_erases: usize,
     pub num_tags: usize,
 
+    /// Whether words can only be written once between erases.
+    ///
+    /// In this format, the flags written after their entry (or page header) are not bits but
+    /// marker words, which are blank until written. See the store module-level documentation.
+    pub single_write: bool,
+
     /// Whether an entry is present.
     ///
     /// - 0 for entries (user entries or internal entries).
@@ -45,6 +69,9 @@ pub struct Format {
     ///
     /// - 0 for deleted entries.
     /// - 1 for alive entries.
+    ///
+    /// This bit is unused in the single-write format, where the last word of an entry is the
+    /// deleted marker.
     deleted_bit: usize,
 
     /// Whether an entry is internal.
@@ -76,6 +103,11 @@ pub struct Format {
     /// The tag of a user entry.
     tag_range: bitfield::BitRange,
 
+    /// The checksum of a user entry.
+    ///
+    /// This range is empty if the pages of the store don't have their checksum bit set.
+    checksum_range: bitfield::BitRange,
+
     /// The page index of a replace entry.
     replace_page_range: bitfield::BitRange,
 
@@ -92,6 +124,24 @@ pub struct Format {
     /// This is only present for internal entries.
     saved_erase_count_range: bitfield::BitRange,
 
+    /// The kind of an internal entry.
+    ///
+    /// - 3 for erase entries (those bits used to be padding).
+    /// - 2 for transaction begin markers.
+    /// - 1 for transaction commit markers.
+    /// - 0 for transaction delete records.
+    internal_kind_range: bitfield::BitRange,
+
+    /// The page index of the entry to delete.
+    ///
+    /// This is only present for transaction delete records.
+    delete_page_range: bitfield::BitRange,
+
+    /// The byte index of the entry to delete.
+    ///
+    /// This is only present for transaction delete records.
+    delete_byte_range: bitfield::BitRange,
+
     /// Whether a page is initialized.
     ///
     /// - 0 for initialized pages.
@@ -105,10 +155,21 @@ pub struct Format {
     ///
     /// - 0 for pages being compacted.
     /// - 1 otherwise.
+    ///
+    /// In the single-write format, this bit starts the second word of the page header.
     compacting_bit: usize,
 
     /// The page index to which a page is being compacted.
     new_page_range: bitfield::BitRange,
+
+    /// Whether the user entries of a page have a checksum.
+    ///
+    /// - 0 for pages with checksums.
+    /// - 1 for pages without checksums (this bit used to be padding).
+    ///
+    /// This bit is written with the initialized bit, in the first word of the page header. All
+    /// pages of a store have the same value.
+    checksum_bit: usize,
 }
 
 impl Format {
@@ -119,21 +180,68 @@ impl Format {
     /// Returns `None` if any of the following conditions does not hold:
     /// - The word size must be a power of two.
     /// - The page size must be a power of two.
-    /// - There should be at least 2 pages in the storage.
-    /// - It should be possible to write a word at least twice.
+    /// - There should be at least 2 pages in the storage, besides the pages of the counters.
+    /// - It should be possible to write a word at least once.
     /// - It should be possible to erase a page at least once.
     /// - There should be at least 1 tag.
+    ///
+    /// The format depends on whether entries have checksums, and on whether words can be written
+    /// only once. Whether entries have checksums is read from the page headers, such that a store
+    /// keeps its format when the config changes. The config only applies to a fresh storage.
     pub fn new<S: Storage, C: StoreConfig>(storage: &S, config: &C) -> Option<Format> {
+        let format = Format::with_checksum(storage, config, config.checksum())?;
+        match format.stored_checksum(storage) {
+            Some(checksum) if checksum != config.checksum() => {
+                Format::with_checksum(storage, config, checksum)
+            }
+            _ => Some(format),
+        }
+    }
+
+    /// Returns whether the initialized pages of a storage have checksums, if any page is
+    /// initialized.
+    ///
+    /// The page headers don't depend on checksums. A majority vote makes a bit flipped in a page
+    /// header harmless, since all pages have the same value.
+    fn stored_checksum<S: Storage>(&self, storage: &S) -> Option<bool> {
+        let mut with_checksum = 0;
+        let mut without_checksum = 0;
+        for page in 0..self.num_pages {
+            let index = Index { page, byte: 0 };
+            let header = storage.read_slice(index, self.page_header_size()).ok()?;
+            if !self.is_initialized(header) {
+                continue;
+            }
+            if self.has_checksum(header) {
+                with_checksum += 1;
+            } else {
+                without_checksum += 1;
+            }
+        }
+        if with_checksum + without_checksum == 0 {
+            None
+        } else {
+            Some(with_checksum > without_checksum)
+        }
+    }
+
+    fn with_checksum<S: Storage, C: StoreConfig>(
+        storage: &S,
+        config: &C,
+        checksum: bool,
+    ) -> Option<Format> {
         let word_size = storage.word_size();
         let page_size = storage.page_size();
-        let num_pages = storage.num_pages();
+        let num_pages = storage
+            .num_pages()
+            .checked_sub(config.num_counters() * COUNTER_PAGES)?;
         let max_word_writes = storage.max_word_writes();
         let max_page_erases = storage.max_page_erases();
         let num_tags = config.num_tags();
         if !(word_size.is_power_of_two()
             && page_size.is_power_of_two()
             && num_pages > 1
-            && max_word_writes >= 2
+            && max_word_writes > 0
             && max_page_erases > 0
             && num_tags > 0)
         {
@@ -144,6 +252,8 @@ impl Format {
         let byte_bits = num_bits(page_size);
         let tag_bits = num_bits(num_tags);
         let erase_bits = num_bits(max_page_erases + 1);
+        let checksum_bits = if checksum { CHECKSUM_BITS } else { 0 };
+        let single_write = max_word_writes == 1;
         // Compute the bit position of the fields.
         let present_bit = 0;
         let deleted_bit = present_bit + 1;
@@ -158,8 +268,12 @@ impl Format {
             start: length_range.end(),
             length: tag_bits,
         };
-        let replace_page_range = bitfield::BitRange {
+        let checksum_range = bitfield::BitRange {
             start: tag_range.end(),
+            length: checksum_bits,
+        };
+        let replace_page_range = bitfield::BitRange {
+            start: checksum_range.end(),
             length: page_bits,
         };
         let replace_byte_range = bitfield::BitRange {
@@ -174,22 +288,51 @@ impl Format {
             start: old_page_range.end(),
             length: erase_bits,
         };
+        let internal_kind_range = bitfield::BitRange {
+            start: saved_erase_count_range.end(),
+            length: 2,
+        };
+        // The fields of delete records overlap those of erase entries if they fit.
+        let delete_start = if internal_bit + 1 + page_bits + byte_bits <= internal_kind_range.start
+        {
+            internal_bit + 1
+        } else {
+            internal_kind_range.end()
+        };
+        let delete_page_range = bitfield::BitRange {
+            start: delete_start,
+            length: page_bits,
+        };
+        let delete_byte_range = bitfield::BitRange {
+            start: delete_page_range.end(),
+            length: byte_bits,
+        };
         let initialized_bit = 0;
         let erase_count_range = bitfield::BitRange {
             start: initialized_bit + 1,
             length: erase_bits,
         };
-        let compacting_bit = erase_count_range.end();
+        let compacting_bit = if single_write {
+            8 * word_size
+        } else {
+            erase_count_range.end()
+        };
         let new_page_range = bitfield::BitRange {
             start: compacting_bit + 1,
             length: page_bits,
         };
+        let checksum_bit = if single_write {
+            erase_count_range.end()
+        } else {
+            new_page_range.end()
+        };
         let format = Format {
             word_size,
             page_size,
             num_pages,
             max_page_erases,
             num_tags,
+            single_write,
             present_bit,
             deleted_bit,
             internal_bit,
@@ -197,23 +340,32 @@ impl Format {
             sensitive_bit,
             length_range,
             tag_range,
+            checksum_range,
             replace_page_range,
             replace_byte_range,
             old_page_range,
             saved_erase_count_range,
+            internal_kind_range,
+            delete_page_range,
+            delete_byte_range,
             initialized_bit,
             erase_count_range,
             compacting_bit,
             new_page_range,
+            checksum_bit,
         };
         // Make sure all the following conditions hold:
-        // - The page header is one word.
-        // - The internal entry is one word.
+        // - The page header is one word, plus the word written when compacting in the
+        //   single-write format.
+        // - The internal entry is one word, plus its deleted marker in the single-write format.
         // - The entry header fits in one word (which is equivalent to the entry header size being
         //   exactly one word for sensitive entries).
-        if format.page_header_size() != word_size
-            || format.internal_entry_size() != word_size
+        // - The erase count and the checksum bit fit in the first word of the page header.
+        let marker_size = format.marker_size();
+        if format.page_header_size() != word_size + marker_size
+            || format.internal_entry_size() != word_size + marker_size
             || format.header_size(true) != word_size
+            || checksum_bit >= 8 * word_size
         {
             return None;
         }
@@ -256,12 +408,17 @@ impl Format {
     ///
     /// This is the number of bytes necessary to store all fields of the entry info. This also
     /// includes the internal padding to protect the `committed` bit from the `deleted` bit and to
-    /// protect the entry info from the user data for sensitive entries.
+    /// protect the entry info from the user data for sensitive entries. This doesn't include the
+    /// marker words of the single-write format.
     fn info_size(&self, is_replace: IsReplace, sensitive: bool) -> usize {
-        let suffix_bits = 2; // committed + complete
+        let suffix_bits = if self.single_write {
+            1 // complete
+        } else {
+            2 // committed + complete
+        };
         let info_bits = match is_replace {
             IsReplace::Replace => self.replace_byte_range.end() + suffix_bits,
-            IsReplace::Insert => self.tag_range.end() + suffix_bits,
+            IsReplace::Insert => self.checksum_range.end() + suffix_bits,
         };
         let mut info_size = self.bits_to_bytes(info_bits);
         // If the suffix bits would end up in the header, we need to add one byte for them.
@@ -280,23 +437,46 @@ impl Format {
     ///
     /// This depends on the length of the user data and whether the entry replaces an old entry or
     /// is an insertion. This also includes the internal padding to protect the `committed` bit from
-    /// the `deleted` bit.
+    /// the `deleted` bit, and the marker words of the single-write format.
     pub fn entry_size(&self, is_replace: IsReplace, sensitive: bool, length: usize) -> usize {
+        let markers_size = self.markers_size(&is_replace);
         let mut entry_size = length + self.info_size(is_replace, sensitive);
         let word_size = self.word_size;
         entry_size = self.align_word(entry_size);
         // The entry must be at least 2 words such that the `committed` and `deleted` bits are on
         // different words.
-        if entry_size == word_size {
+        if entry_size == word_size && !self.single_write {
             entry_size += word_size;
         }
-        entry_size
+        entry_size + markers_size
+    }
+
+    /// Returns the length in bytes of a marker word, which is 0 unless in the single-write format.
+    fn marker_size(&self) -> usize {
+        if self.single_write {
+            self.word_size
+        } else {
+            0
+        }
+    }
+
+    /// Returns the length in bytes of the marker words at the end of a user entry.
+    ///
+    /// In the single-write format, user entries end with their deleted marker, which replace
+    /// entries precede with their committed marker.
+    fn markers_size(&self, is_replace: &IsReplace) -> usize {
+        match is_replace {
+            IsReplace::Replace => 2 * self.marker_size(),
+            IsReplace::Insert => self.marker_size(),
+        }
     }
 
     /// Returns the length in bytes of an internal entry.
+    ///
+    /// All kinds of internal entries have the same length.
     pub fn internal_entry_size(&self) -> usize {
-        let length = self.bits_to_bytes(self.saved_erase_count_range.end());
-        self.align_word(length)
+        let bits = core::cmp::max(self.internal_kind_range.end(), self.delete_byte_range.end());
+        self.align_word(self.bits_to_bytes(bits)) + self.marker_size()
     }
 
     pub fn is_present(&self, header: &[u8]) -> bool {
@@ -307,17 +487,49 @@ impl Format {
         bitfield::set_zero(self.present_bit, header, bitfield::NO_GAP)
     }
 
-    pub fn is_deleted(&self, header: &[u8]) -> bool {
-        bitfield::is_zero(self.deleted_bit, header, bitfield::NO_GAP)
+    /// Returns whether an entry is deleted.
+    ///
+    /// This needs the whole entry, since the single-write format marks it with its last word.
+    pub fn is_deleted(&self, entry: &[u8]) -> bool {
+        if self.single_write {
+            self.is_marked(entry, entry.len() - self.word_size)
+        } else {
+            bitfield::is_zero(self.deleted_bit, entry, bitfield::NO_GAP)
+        }
     }
 
     /// Returns whether an entry is present and not deleted.
-    pub fn is_alive(&self, header: &[u8]) -> bool {
-        self.is_present(header) && !self.is_deleted(header)
+    pub fn is_alive(&self, entry: &[u8]) -> bool {
+        self.is_present(entry) && !self.is_deleted(entry)
     }
 
-    pub fn set_deleted(&self, header: &mut [u8]) {
-        bitfield::set_zero(self.deleted_bit, header, bitfield::NO_GAP)
+    pub fn set_deleted(&self, entry: &mut [u8]) {
+        if self.single_write {
+            let marker = entry.len() - self.word_size;
+            self.set_marker(entry, marker)
+        } else {
+            bitfield::set_zero(self.deleted_bit, entry, bitfield::NO_GAP)
+        }
+    }
+
+    /// Returns whether the marker word at a given byte of an entry is written.
+    ///
+    /// Any written bit counts, such that a marker interrupted while written is still written.
+    fn is_marked(&self, entry: &[u8], marker: usize) -> bool {
+        entry[marker..][..self.word_size]
+            .iter()
+            .any(|&byte| byte != 0xff)
+    }
+
+    /// Writes the marker word at a given byte of an entry.
+    ///
+    /// A written marker is left unchanged, since its word can't be written again.
+    fn set_marker(&self, entry: &mut [u8], marker: usize) {
+        if !self.is_marked(entry, marker) {
+            for byte in &mut entry[marker..][..self.word_size] {
+                *byte = 0x00;
+            }
+        }
     }
 
     pub fn is_internal(&self, header: &[u8]) -> bool {
@@ -364,12 +576,15 @@ impl Format {
     ///
     /// The complement of this gap in the entry is exactly the entry info. The header is before the
     /// gap and the footer is after the gap.
+    ///
+    /// The gap is truncated to the entry, which is only shorter than its length if corrupted.
     pub fn entry_gap(&self, entry: &[u8]) -> bitfield::ByteGap {
         let start = self.header_offset(entry);
         let mut length = self.get_length(entry);
         if self.is_sensitive(entry) {
             length = self.align_word(length);
         }
+        length = core::cmp::min(length, entry.len().saturating_sub(start));
         bitfield::ByteGap { start, length }
     }
 
@@ -396,6 +611,90 @@ impl Format {
         bitfield::set_range(self.replace_byte_range, entry, self.entry_gap(entry), byte)
     }
 
+    fn get_checksum(&self, entry: &[u8]) -> usize {
+        bitfield::get_range(self.checksum_range, entry, self.entry_gap(entry))
+    }
+
+    fn set_checksum(&self, entry: &mut [u8], checksum: usize) {
+        bitfield::set_range(self.checksum_range, entry, self.entry_gap(entry), checksum)
+    }
+
+    /// Computes the checksum of a user entry.
+    ///
+    /// The checksum covers the data and the immutable fields of the entry info. The `deleted` and
+    /// `committed` bits are not covered since they are written after the entry.
+    fn compute_checksum(&self, entry: &[u8]) -> usize {
+        let (replace, Index { page, byte }) = match self.is_replace(entry) {
+            IsReplace::Replace => (0, self.get_replace_index(entry)),
+            IsReplace::Insert => (1, Index { page: 0, byte: 0 }),
+        };
+        let fields = [
+            replace,
+            self.is_sensitive(entry) as usize,
+            self.get_length(entry),
+            self.get_tag(entry),
+            page,
+            byte,
+        ];
+        let mut crc = CRC_INIT;
+        for &field in fields.iter() {
+            crc = crc16(crc, &(field as u32).to_le_bytes());
+        }
+        crc = crc16(crc, self.entry_gap(entry).slice(entry));
+        crc as usize
+    }
+
+    /// Returns whether a present entry can be trusted.
+    ///
+    /// An entry is valid if it fits its length, if its fields are in range, and if it matches its
+    /// checksum (when checksums are enabled). Invalid entries are corrupted, either because bits
+    /// flipped in flash or because a write was interrupted outside the store assumptions.
+    pub fn is_valid(&self, entry: &[u8]) -> bool {
+        if self.is_internal(entry) {
+            if entry.len() != self.internal_entry_size() {
+                return false;
+            }
+            return match self.get_internal_kind(entry) {
+                InternalKind::Erase => self.get_old_page(entry) < self.num_pages,
+                InternalKind::Delete => {
+                    let Index { page, byte } = self.get_delete_index(entry);
+                    page < self.num_pages && byte < self.page_size
+                }
+                InternalKind::Begin | InternalKind::Commit => true,
+            };
+        }
+        let length = self.get_length(entry);
+        let replace = self.is_replace(entry);
+        let is_replace = match replace {
+            IsReplace::Replace => true,
+            IsReplace::Insert => false,
+        };
+        if entry.len() != self.entry_size(replace, self.is_sensitive(entry), length)
+            || self.get_tag(entry) >= self.num_tags
+        {
+            return false;
+        }
+        if is_replace {
+            let Index { page, byte } = self.get_replace_index(entry);
+            if page >= self.num_pages || byte >= self.page_size {
+                return false;
+            }
+        } else if !self.is_committed(entry) {
+            // Insert entries are written committed.
+            return false;
+        }
+        !self.entry_checksum() || self.get_checksum(entry) == self.compute_checksum(entry)
+    }
+
+    /// Returns the byte position of the end of the entry info, before the marker words.
+    ///
+    /// The result is only smaller than the markers if the entry is corrupted.
+    fn info_end(&self, entry: &[u8]) -> usize {
+        entry
+            .len()
+            .saturating_sub(self.markers_size(&self.is_replace(entry)))
+    }
+
     /// Returns the bit position of the `committed` bit.
     ///
     /// This cannot be precomputed like other fields since it depends on the length of the entry.
@@ -407,15 +706,31 @@ impl Format {
     ///
     /// This cannot be precomputed like other fields since it depends on the length of the entry.
     fn complete_bit(&self, entry: &[u8]) -> usize {
-        8 * entry.len() - 1
+        (8 * self.info_end(entry)).saturating_sub(1)
     }
 
+    /// Returns whether an entry is committed.
+    ///
+    /// In the single-write format, insert entries have no committed marker and are always
+    /// committed.
     pub fn is_committed(&self, entry: &[u8]) -> bool {
-        bitfield::is_zero(self.committed_bit(entry), entry, bitfield::NO_GAP)
+        if !self.single_write {
+            return bitfield::is_zero(self.committed_bit(entry), entry, bitfield::NO_GAP);
+        }
+        match self.is_replace(entry) {
+            IsReplace::Replace => self.is_marked(entry, self.info_end(entry)),
+            IsReplace::Insert => true,
+        }
     }
 
     pub fn set_committed(&self, entry: &mut [u8]) {
-        bitfield::set_zero(self.committed_bit(entry), entry, bitfield::NO_GAP)
+        if !self.single_write {
+            return bitfield::set_zero(self.committed_bit(entry), entry, bitfield::NO_GAP);
+        }
+        if let IsReplace::Replace = self.is_replace(entry) {
+            let marker = self.info_end(entry);
+            self.set_marker(entry, marker);
+        }
     }
 
     pub fn is_complete(&self, entry: &[u8]) -> bool {
@@ -472,7 +787,6 @@ impl Format {
         // Build the footer.
         entry.resize(entry_len, 0xff);
         self.set_tag(&mut entry[..], tag);
-        self.set_complete(&mut entry[..]);
         match replace {
             None => self.set_committed(&mut entry[..]),
             Some(Index { page, byte }) => {
@@ -481,14 +795,72 @@ impl Format {
                 self.set_replace_byte(&mut entry[..], byte);
             }
         }
+        // The position of the `complete` bit depends on the `replace` bit.
+        self.set_complete(&mut entry[..]);
+        if self.entry_checksum() {
+            let checksum = self.compute_checksum(&entry);
+            self.set_checksum(&mut entry[..], checksum);
+        }
         entry
     }
 
-    /// Builds an entry for replace or insert operations.
-    pub fn build_erase_entry(&self, old_page: usize, saved_erase_count: usize) -> Vec<u8> {
+    pub fn get_internal_kind(&self, header: &[u8]) -> InternalKind {
+        match bitfield::get_range(self.internal_kind_range, header, bitfield::NO_GAP) {
+            3 => InternalKind::Erase,
+            2 => InternalKind::Begin,
+            1 => InternalKind::Commit,
+            _ => InternalKind::Delete,
+        }
+    }
+
+    fn set_internal_kind(&self, header: &mut [u8], kind: InternalKind) {
+        let value = match kind {
+            InternalKind::Erase => 3,
+            InternalKind::Begin => 2,
+            InternalKind::Commit => 1,
+            InternalKind::Delete => 0,
+        };
+        bitfield::set_range(self.internal_kind_range, header, bitfield::NO_GAP, value)
+    }
+
+    pub fn get_delete_index(&self, header: &[u8]) -> Index {
+        let page = bitfield::get_range(self.delete_page_range, header, bitfield::NO_GAP);
+        let byte = bitfield::get_range(self.delete_byte_range, header, bitfield::NO_GAP);
+        Index { page, byte }
+    }
+
+    /// Builds an internal entry of a given kind.
+    ///
+    /// The fields specific to the kind are left erased.
+    fn build_internal_entry(&self, kind: InternalKind) -> Vec<u8> {
         let mut entry = vec![0xff; self.internal_entry_size()];
         self.set_present(&mut entry[..]);
         self.set_internal(&mut entry[..]);
+        self.set_internal_kind(&mut entry[..], kind);
+        entry
+    }
+
+    /// Builds the begin marker of a transaction.
+    pub fn build_begin_entry(&self) -> Vec<u8> {
+        self.build_internal_entry(InternalKind::Begin)
+    }
+
+    /// Builds the commit marker of a transaction.
+    pub fn build_commit_entry(&self) -> Vec<u8> {
+        self.build_internal_entry(InternalKind::Commit)
+    }
+
+    /// Builds a transaction record to delete an entry.
+    pub fn build_delete_entry(&self, Index { page, byte }: Index) -> Vec<u8> {
+        let mut entry = self.build_internal_entry(InternalKind::Delete);
+        bitfield::set_range(self.delete_page_range, &mut entry, bitfield::NO_GAP, page);
+        bitfield::set_range(self.delete_byte_range, &mut entry, bitfield::NO_GAP, byte);
+        entry
+    }
+
+    /// Builds an entry to erase a page.
+    pub fn build_erase_entry(&self, old_page: usize, saved_erase_count: usize) -> Vec<u8> {
+        let mut entry = self.build_internal_entry(InternalKind::Erase);
         self.set_old_page(&mut entry[..], old_page);
         self.set_saved_erase_count(&mut entry[..], saved_erase_count);
         entry
@@ -498,7 +870,7 @@ impl Format {
     ///
     /// This includes the word padding.
     pub fn page_header_size(&self) -> usize {
-        self.align_word(self.bits_to_bytes(self.erase_count_range.end()))
+        self.align_word(self.bits_to_bytes(self.new_page_range.end()))
     }
 
     pub fn is_initialized(&self, header: &[u8]) -> bool {
@@ -509,6 +881,22 @@ impl Format {
         bitfield::set_zero(self.initialized_bit, header, bitfield::NO_GAP)
     }
 
+    pub fn has_checksum(&self, header: &[u8]) -> bool {
+        bitfield::is_zero(self.checksum_bit, header, bitfield::NO_GAP)
+    }
+
+    /// Returns whether the user entries of this format have a checksum.
+    pub fn entry_checksum(&self) -> bool {
+        self.checksum_range.length > 0
+    }
+
+    /// Marks the page as having checksums, if the entries of this format have one.
+    pub fn set_checksum_bit(&self, header: &mut [u8]) {
+        if self.entry_checksum() {
+            bitfield::set_zero(self.checksum_bit, header, bitfield::NO_GAP)
+        }
+    }
+
     pub fn get_erase_count(&self, header: &[u8]) -> usize {
         bitfield::get_range(self.erase_count_range, header, bitfield::NO_GAP)
     }
@@ -545,6 +933,24 @@ impl Format {
     }
 }
 
+/// The initial value of a CRC-16/CCITT-FALSE.
+const CRC_INIT: u16 = 0xffff;
+
+/// Updates a CRC-16/CCITT-FALSE with some bytes.
+fn crc16(mut crc: u16, bytes: &[u8]) -> u16 {
+    for &byte in bytes {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
 /// Returns the number of bits necessary to write numbers smaller than `x`.
 fn num_bits(x: usize) -> usize {
     x.next_power_of_two().trailing_zeros() as usize
@@ -562,3 +968,10 @@ fn num_bits_ok() {
     assert_eq!(num_bits(9), 4);
     assert_eq!(num_bits(16), 4);
 }
+
+#[test]
+fn crc16_ok() {
+    assert_eq!(crc16(CRC_INIT, b""), 0xffff);
+    assert_eq!(crc16(CRC_INIT, b"123456789"), 0x29b1);
+    assert_eq!(crc16(crc16(CRC_INIT, b"1234"), b"56789"), 0x29b1);
+}
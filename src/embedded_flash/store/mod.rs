@@ -31,6 +31,34 @@
 //! entries associated to a given key. The same key can be associated to multiple entries and the
 //! same entry can be associated to multiple keys.
 //!
+//! # Transactions
+//!
+//! Several inserts, deletes, and replaces can be applied atomically with a transaction. The
+//! entries written by a transaction must fit in a single page.
+//!
+//! # Counters
+//!
+//! The store can also hold monotonic counters, which are configured in the configuration. Each
+//! counter uses `COUNTER_PAGES` pages at the end of the storage, which are not used for entries.
+//! Incrementing a counter usually writes a single word, so counters are meant for values that
+//! change often, like signature counters. See the `Counter` documentation for their format.
+//!
+//! # Compaction
+//!
+//! The space of deleted entries is reclaimed by compacting their page to a blank page, which costs
+//! a page erase. Writes compact pages when they need space. To keep writes fast, `compact_step`
+//! compacts ahead of time (for example while the device is idle), and `compaction_cost` tells how
+//! many pages the next write would compact.
+//!
+//! # Integrity
+//!
+//! Entries read from flash are checked before being trusted: they must fit their length and their
+//! fields must be in range. Entries can also be protected by a checksum, which is enabled in the
+//! configuration when the storage is fresh, and recorded in the page headers so that stores
+//! written without checksums stay readable. Entries failing those checks are _quarantined_: they are not returned by
+//! iteration, never used to complete an interrupted operation, and dropped when their page is
+//! compacted. The `health` function reports how many entries are quarantined.
+//!
 //! # Storage
 //!
 //! The data-structure is parametric over its storage which must implement the `Storage` trait.
@@ -63,7 +91,8 @@
 //! - To decide whether a page has been erased, it is enough to test if all its bits are equal to 1.
 //!
 //! The properties may still hold outside those assumptions but with weaker probabilities as the
-//! usage diverges from the assumptions.
+//! usage diverges from the assumptions. In particular, bits flipped by flash retention errors are
+//! quarantined (see above) and the store keeps working with the remaining entries.
 //!
 //! # Implementation
 //!
@@ -77,6 +106,7 @@
 //!     erase_count:erase_bits
 //!     compacting:1
 //!     new_page:page_bits
+//!     checksum:1  // used to be padding
 //!     Padding(word)
 //! Entry := Header Data Footer
 //! // Let X be the byte (word-aligned for sensitive queries) following `length` in `Info`.
@@ -90,6 +120,7 @@
 //!     sensitive:1
 //!     length:byte_bits
 //!     tag:tag_bits
+//!     checksum:checksum_bits  // 16 if `checksum` is 0 in the page headers, 0 otherwise
 //!     [  // present if `replace` is 0
 //!         replace_page:page_bits
 //!         replace_byte:byte_bits
@@ -101,8 +132,15 @@
 //!     present=0
 //!     deleted:1
 //!     internal=0
-//!     old_page:page_bits
-//!     saved_erase_count:erase_bits
+//!     [  // present if `kind` is 3
+//!         old_page:page_bits
+//!         saved_erase_count:erase_bits
+//!     ]
+//!     kind:2  // starts after `saved_erase_count` for all kinds
+//!     [  // present if `kind` is 0, starts after `internal` if it fits before `kind`
+//!         delete_page:page_bits
+//!         delete_byte:byte_bits
+//!     ]
 //!     Padding(word)
 //! Padding(X) := 1* until X-aligned
 //! ```
@@ -111,8 +149,18 @@
 //! false. They can be set to true by writing 0.
 //!
 //! The `Entry` rule is for user entries and the `InternalEntry` rule is for internal entries of the
-//! store. Currently, there is only one kind of internal entry: an entry to erase the page being
-//! compacted.
+//! store. There are 4 kinds of internal entries:
+//! - 3: An entry to erase the page being compacted. Those bits used to be padding when it was the
+//!   only kind.
+//! - 2: The begin marker of a transaction.
+//! - 1: The commit marker of a transaction.
+//! - 0: A transaction record to delete the entry at `delete_page` and `delete_byte`.
+//!
+//! A transaction is written contiguously in a page: the begin marker, one delete record per deleted
+//! entry, the inserted entries, and the commit marker. Writing the commit marker commits the
+//! transaction. The deleted entries are then deleted, followed by the begin marker, and finally the
+//! delete records and the commit marker. A transaction without commit marker is rolled-back by
+//! deleting its entries before its begin marker.
 //!
 //! The `Header` and `Footer` rules are computed from the `Info` rule. An entry could simply be the
 //! concatenation of internal metadata and the user data. However, to optimize the size in flash, we
@@ -149,23 +197,49 @@
 //!
 //! The store should always contain at least one blank page, so that it is always possible to
 //! compact.
+//!
+//! # Single-write format
+//!
+//! Some flash can only write a word once between erases, usually with larger words (8 or 16
+//! bytes). The format is then selected automatically from the storage parameters. The flags that
+//! are written after their entry or page header are replaced by _marker words_, which are written
+//! to zero and count as written as soon as one of their bits is zero:
+//!
+//! ```text
+//! PageHeader :=
+//!     initialized:1
+//!     erase_count:erase_bits
+//!     checksum:1
+//!     Padding(word)
+//!     compacting:1  // starts the second word
+//!     new_page:page_bits
+//!     Padding(word)
+//! Entry := Header Data Footer [Committed] Deleted  // `Committed` if `replace` is 0
+//! InternalEntry := ... Deleted
+//! Committed := Marker
+//! Deleted := Marker
+//! Marker := 1* (word-sized)
+//! ```
+//!
+//! The `deleted` and `committed` bits of the `Info` rule are unused, insert entries are always
+//! committed, and the padding before `complete` is not needed. Deleted sensitive entries are not
+//! wiped since their data can't be written again: they are only erased with their page when it
+//! is compacted. To bound how long they stay on flash, `compact_step` also compacts pages holding
+//! deleted sensitive entries.
 
 // TODO(cretin): We don't need inner padding for insert entries. The store format can be:
 //   InsertEntry | ReplaceEntry | InternalEntry (maybe rename to EraseEntry)
 //   InsertEntry padding is until `complete` is the last bit of a word.
 //   ReplaceEntry padding is until `complete` is the last bit of a different word than `present`.
-// TODO(cretin): Add checksum (may play the same role as the completed bit) and recovery strategy?
 // TODO(cretin): Add corruption (deterministic but undetermined reads) to fuzzing.
-// TODO(cretin): Add more complex transactions? (this does not seem necessary yet)
-// TODO(cretin): Add possibility to shred an entry (force compact page after delete)?
 
 mod bitfield;
 mod format;
 
-use self::format::{Format, IsReplace};
+use self::format::{Format, InternalKind, IsReplace};
 #[cfg(feature = "std")]
-use super::BufferStorage;
-use super::{Index, Storage};
+use super::{BufferStorage, Interruption};
+use super::{Counter, Index, Storage};
 use alloc::collections::BTreeMap;
 use alloc::vec::Vec;
 
@@ -188,8 +262,28 @@ pub trait StoreConfig {
     /// If keys are not used, this function can immediately return. Otherwise, it should call
     /// `associate_key` for each key that should be associated to `entry`.
     fn keys(&self, entry: StoreEntry, associate_key: impl FnMut(Self::Key));
+
+    /// Whether user entries are protected by a checksum.
+    ///
+    /// The checksum detects corrupted entries that would otherwise look valid, at the cost of 2
+    /// bytes per entry. The page headers record whether entries have a checksum, so this value
+    /// only applies when the storage is fresh: a store keeps its format when it changes.
+    fn checksum(&self) -> bool {
+        false
+    }
+
+    /// Number of counters.
+    ///
+    /// Counters are identified by a number smaller than this value. Changing this value changes
+    /// the storage format, because the counters take the last pages of the storage.
+    fn num_counters(&self) -> usize {
+        0
+    }
 }
 
+/// The number of pages of each counter.
+pub const COUNTER_PAGES: usize = 2;
+
 /// Errors returned by store operations.
 #[derive(Debug, PartialEq, Eq)]
 pub enum StoreError {
@@ -205,7 +299,7 @@ pub enum StoreError {
 
 /// The position of an entry in the store.
 #[cfg_attr(feature = "std", derive(Debug))]
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, PartialEq, Eq)]
 pub struct StoreIndex {
     /// The index of this entry in the storage.
     index: Index,
@@ -230,10 +324,41 @@ pub struct StoreEntry<'a> {
 
     /// Whether the data is sensitive.
     ///
-    /// Sensitive data is overwritten with zeroes when the entry is deleted.
+    /// Sensitive data is overwritten with zeroes when the entry is deleted. In the single-write
+    /// format, it can't be overwritten and stays on flash until its page is compacted, which
+    /// `compact_step` does on the next call. Until then, the data is readable by anyone dumping the
+    /// flash, and it stays there for good once the page reached its maximum erase count.
     pub sensitive: bool,
 }
 
+/// An update of a transaction.
+#[cfg_attr(feature = "std", derive(Debug))]
+#[derive(Copy, Clone)]
+pub enum StoreUpdate<'a> {
+    /// Inserts an entry.
+    Insert(StoreEntry<'a>),
+
+    /// Deletes an entry.
+    Delete(StoreIndex),
+
+    /// Replaces an entry with another.
+    Replace {
+        old: StoreIndex,
+        new: StoreEntry<'a>,
+    },
+}
+
+/// Integrity report of a store.
+#[cfg_attr(feature = "std", derive(Debug, PartialEq, Eq))]
+#[derive(Copy, Clone)]
+pub struct StoreHealth {
+    /// Number of user entries that are alive and valid.
+    pub valid_entries: usize,
+
+    /// Number of user entries that are quarantined because they are corrupted.
+    pub corrupt_entries: usize,
+}
+
 /// Implements a configurable multi-set on top of any storage.
 pub struct Store<S: Storage, C: StoreConfig> {
     storage: S,
@@ -243,6 +368,9 @@ pub struct Store<S: Storage, C: StoreConfig> {
     /// The index of the blank page reserved for compaction.
     blank_page: usize,
 
+    /// The counters, on the pages after the store.
+    counters: Vec<Counter>,
+
     /// Counts the number of compactions since the store creation.
     ///
     /// A `StoreIndex` is valid only if they originate from the same generation. This is checked by
@@ -263,11 +391,18 @@ impl<S: Storage, C: StoreConfig> Store<S, C> {
     pub fn new(storage: S, config: C) -> Option<Store<S, C>> {
         let format = Format::new(&storage, &config)?;
         let blank_page = format.num_pages;
+        let counters = (0..config.num_counters())
+            .map(|counter| {
+                let first_page = format.num_pages + counter * COUNTER_PAGES;
+                Counter::new(&storage, first_page, COUNTER_PAGES)
+            })
+            .collect::<Option<Vec<_>>>()?;
         let mut store = Store {
             storage,
             config,
             format,
             blank_page,
+            counters,
             generation: 0,
         };
         // Finish any ongoing page compaction.
@@ -280,26 +415,33 @@ impl<S: Storage, C: StoreConfig> Store<S, C> {
     }
 
     /// Iterates over all entries in the store.
+    ///
+    /// Quarantined entries are skipped.
     pub fn iter(&self) -> impl Iterator<Item = (StoreIndex, StoreEntry)> {
         Iter::new(self).filter_map(move |(index, entry)| {
-            if self.format.is_alive(entry) {
-                Some((
-                    StoreIndex {
-                        index,
-                        generation: self.generation,
-                    },
-                    StoreEntry {
-                        tag: self.format.get_tag(entry),
-                        data: self.format.get_data(entry),
-                        sensitive: self.format.is_sensitive(entry),
-                    },
-                ))
-            } else {
-                None
-            }
+            let entry = self.user_entry(entry)?;
+            Some((self.store_index(index), entry))
         })
     }
 
+    /// Returns the entry at a given position.
+    ///
+    /// Returns `None` if the index is from a previous generation or if the entry was deleted.
+    pub fn get(&self, index: StoreIndex) -> Option<StoreEntry> {
+        if self.generation != index.generation {
+            return None;
+        }
+        self.user_entry(self.read_entry(index.index))
+    }
+
+    /// Returns the current generation.
+    ///
+    /// A `StoreIndex` is only valid during the generation it was returned. A change of generation
+    /// means that entries may have moved.
+    pub fn generation(&self) -> usize {
+        self.generation
+    }
+
     /// Iterates over all entries matching a key in the store.
     pub fn find_all<'a>(
         &'a self,
@@ -338,6 +480,8 @@ impl<S: Storage, C: StoreConfig> Store<S, C> {
 
     /// Replaces an entry with another with the same tag in the store.
     ///
+    /// Returns the position of the new entry.
+    ///
     /// This operation (like others) is atomic. If it returns successfully, then the old entry is
     /// deleted and the new is inserted. If it fails, the old entry is not deleted and the new entry
     /// is not inserted. If power is lost during the operation, during next startup, the operation
@@ -349,7 +493,7 @@ impl<S: Storage, C: StoreConfig> Store<S, C> {
     /// - `StoreFull` if the new entry does not fit in the store.
     /// - `InvalidTag` if the tag of the new entry is not smaller than the configured number of
     ///   tags.
-    pub fn replace(&mut self, old: StoreIndex, new: StoreEntry) -> Result<(), StoreError> {
+    pub fn replace(&mut self, old: StoreIndex, new: StoreEntry) -> Result<StoreIndex, StoreError> {
         if self.generation != old.generation {
             return Err(StoreError::InvalidPrecondition);
         }
@@ -357,7 +501,7 @@ impl<S: Storage, C: StoreConfig> Store<S, C> {
         let mut old_index = old.index;
         // Find a slot.
         let entry_len = self.replace_len(new.sensitive, new.data.len());
-        let index = self.find_slot_for_write(entry_len, Some(&mut old_index))?;
+        let index = self.find_slot_for_write(entry_len, core::slice::from_mut(&mut old_index))?;
         // Build a new entry replacing the old one.
         let entry = self.format.build_entry(Some(old_index), new);
         debug_assert_eq!(entry.len(), entry_len);
@@ -365,25 +509,141 @@ impl<S: Storage, C: StoreConfig> Store<S, C> {
         self.write_entry(index, &entry);
         // Commit the new entry, which both deletes the old entry and commits the new one.
         self.commit_index(index);
-        Ok(())
+        Ok(self.store_index(index))
     }
 
     /// Inserts an entry in the store.
     ///
+    /// Returns the position of the inserted entry.
+    ///
     /// # Errors
     ///
     /// Returns:
     /// - `StoreFull` if the new entry does not fit in the store.
     /// - `InvalidTag` if the tag of the new entry is not smaller than the configured number of
     ///   tags.
-    pub fn insert(&mut self, entry: StoreEntry) -> Result<(), StoreError> {
+    pub fn insert(&mut self, entry: StoreEntry) -> Result<StoreIndex, StoreError> {
         self.format.validate_entry(entry)?;
         // Build entry.
         let entry = self.format.build_entry(None, entry);
         // Find a slot.
-        let index = self.find_slot_for_write(entry.len(), None)?;
+        let index = self.find_slot_for_write(entry.len(), &mut [])?;
         // Write entry.
         self.write_entry(index, &entry);
+        Ok(self.store_index(index))
+    }
+
+    /// Applies several updates atomically.
+    ///
+    /// If it returns successfully, then all updates are applied. If it fails, none are. If power
+    /// is lost during the operation, during next startup, the transaction is either rolled-back or
+    /// completed.
+    ///
+    /// All the entries written by the transaction must fit in a page: each deleted (or replaced)
+    /// entry costs one word, each inserted (or replacing) entry costs its insert length, and the
+    /// transaction costs two additional words.
+    ///
+    /// # Errors
+    ///
+    /// Returns:
+    /// - `StoreFull` if the transaction does not fit in the store or in a page.
+    /// - `InvalidTag` if the tag of a new entry is not smaller than the configured number of tags.
+    /// - `InvalidPrecondition` if an index is not valid or if an entry is deleted twice.
+    pub fn transaction(&mut self, updates: &[StoreUpdate]) -> Result<(), StoreError> {
+        if updates.is_empty() {
+            return Ok(());
+        }
+        let mut deleted = Vec::new();
+        let mut inserted = Vec::new();
+        for update in updates {
+            let (old, new) = match *update {
+                StoreUpdate::Insert(entry) => (None, Some(entry)),
+                StoreUpdate::Delete(index) => (Some(index), None),
+                StoreUpdate::Replace { old, new } => (Some(old), Some(new)),
+            };
+            if let Some(old) = old {
+                if self.generation != old.generation || deleted.contains(&old.index) {
+                    return Err(StoreError::InvalidPrecondition);
+                }
+                deleted.push(old.index);
+            }
+            if let Some(new) = new {
+                self.format.validate_entry(new)?;
+                inserted.push(self.format.build_entry(None, new));
+            }
+        }
+        // Find a slot.
+        let internal_len = self.format.internal_entry_size();
+        let length = (2 + deleted.len()) * internal_len
+            + inserted.iter().map(|entry| entry.len()).sum::<usize>();
+        if length > self.format.page_size - self.format.page_header_size() {
+            return Err(StoreError::StoreFull);
+        }
+        let begin = self.find_slot_for_write(length, &mut deleted)?;
+        // Write the transaction.
+        let mut entries = vec![self.format.build_begin_entry()];
+        for &index in &deleted {
+            entries.push(self.format.build_delete_entry(index));
+        }
+        entries.extend(inserted);
+        // The commit marker is written last, which commits the transaction.
+        entries.push(self.format.build_commit_entry());
+        let mut index = begin;
+        for entry in entries {
+            self.write_entry(index, &entry);
+            index.byte += entry.len();
+        }
+        // Apply the transaction.
+        self.recover_transaction(begin);
+        Ok(())
+    }
+
+    /// Returns the value of a counter.
+    ///
+    /// # Errors
+    ///
+    /// Returns `InvalidPrecondition` if the counter is not smaller than the configured number of
+    /// counters.
+    pub fn counter(&self, counter: usize) -> Result<u32, StoreError> {
+        let counter = self
+            .counters
+            .get(counter)
+            .ok_or(StoreError::InvalidPrecondition)?;
+        Ok(counter.value())
+    }
+
+    /// Increments a counter and returns its new value.
+    ///
+    /// # Errors
+    ///
+    /// Returns:
+    /// - `StoreFull` if the counter is at its maximum value. It is left unchanged.
+    /// - `InvalidPrecondition` if the counter is not smaller than the configured number of
+    ///   counters.
+    pub fn increment(&mut self, counter: usize) -> Result<u32, StoreError> {
+        let counter = self
+            .counters
+            .get_mut(counter)
+            .ok_or(StoreError::InvalidPrecondition)?;
+        counter
+            .increment(&mut self.storage)
+            .ok_or(StoreError::StoreFull)
+    }
+
+    /// Sets a counter to a value, if it is larger than its current value.
+    ///
+    /// This costs a page erase, so it should only be used for occasional jumps.
+    ///
+    /// # Errors
+    ///
+    /// Returns `InvalidPrecondition` if the counter is not smaller than the configured number of
+    /// counters.
+    pub fn advance(&mut self, counter: usize, value: u32) -> Result<(), StoreError> {
+        let counter = self
+            .counters
+            .get_mut(counter)
+            .ok_or(StoreError::InvalidPrecondition)?;
+        counter.advance(&mut self.storage, value);
         Ok(())
     }
 
@@ -406,6 +666,67 @@ impl<S: Storage, C: StoreConfig> Store<S, C> {
         self.format.entry_size(IsReplace::Insert, sensitive, length)
     }
 
+    /// Returns how many pages must be compacted before writing `length` bytes.
+    ///
+    /// The length is the byte cost of the operation, as returned by `insert_len` or `replace_len`.
+    /// Each compaction erases a page, which dominates the latency of the operation. Returns `None`
+    /// if the operation would fail with `StoreFull`.
+    pub fn compaction_cost(&self, length: usize) -> Option<usize> {
+        // This simulates `find_slot_for_write` on the page information.
+        let mut infos = self.page_infos();
+        let mut blank_page = self.blank_page;
+        let mut cost = 0;
+        let page_length = self.format.page_size - self.format.page_header_size();
+        let internal_length = self.format.internal_entry_size();
+        loop {
+            if (0..self.format.num_pages)
+                .any(|page| page != blank_page && length <= infos[page].free_length)
+            {
+                return Some(cost);
+            }
+            let page = self.choose_page_for_compact(&infos, blank_page)?;
+            // The alive entries move to the blank page, followed by the deleted erase entry. The
+            // compacted page becomes the blank page.
+            let info = &infos[page];
+            let alive_length = page_length - info.deleted_length - info.free_length;
+            infos[blank_page].deleted_length = internal_length;
+            infos[blank_page].free_length = page_length - alive_length - internal_length;
+            infos[page] = PageInfo {
+                erase_count: infos[page].erase_count + 1,
+                deleted_length: 0,
+                deleted_sensitive: false,
+                free_length: page_length,
+            };
+            blank_page = page;
+            cost += 1;
+        }
+    }
+
+    /// Compacts a page if writing `length` bytes would need it.
+    ///
+    /// The length is the byte cost of the operation, as returned by `insert_len` or `replace_len`.
+    /// This permits to compact while idle, such that the next write doesn't have to. Nothing is
+    /// compacted if the write would fail anyway. Returns whether a page was compacted, in which
+    /// case the generation changed and the caller may step again.
+    ///
+    /// In the single-write format, a page holding deleted sensitive entries is compacted even if
+    /// the write doesn't need it, to erase their data.
+    pub fn compact_step(&mut self, length: usize) -> bool {
+        let infos = self.page_infos();
+        let page = match self.compaction_cost(length) {
+            Some(cost) if cost > 0 => self.choose_page_for_compact(&infos, self.blank_page),
+            _ => self.choose_page_for_shred(&infos),
+        };
+        match page {
+            None => false,
+            Some(page) => {
+                let blank_page = self.blank_page;
+                self.compact_page(page, blank_page);
+                true
+            }
+        }
+    }
+
     /// Returns the erase count of all pages.
     ///
     /// The value at index `page` of the result is the number of times page `page` was erased. This
@@ -420,12 +741,37 @@ impl<S: Storage, C: StoreConfig> Store<S, C> {
         info
     }
 
+    /// Returns how many entries are valid and how many are quarantined.
+    pub fn health(&self) -> StoreHealth {
+        let mut health = StoreHealth {
+            valid_entries: 0,
+            corrupt_entries: 0,
+        };
+        for (_, entry) in Iter::new(self) {
+            if !self.format.is_alive(entry) || self.format.is_internal(entry) {
+                continue;
+            }
+            if self.format.is_valid(entry) {
+                health.valid_entries += 1;
+            } else {
+                health.corrupt_entries += 1;
+            }
+        }
+        health
+    }
+
     /// Completes any ongoing page compaction.
     fn recover_compact_page(&mut self) {
         for page in 0..self.format.num_pages {
             let (page_header, _) = self.read_page_header(page);
-            if self.format.is_compacting(page_header) {
+            // In the single-write format, the compacting bit is not in the first word of the page
+            // header. It may outlive the initialized bit when the page erase is interrupted.
+            if self.format.is_initialized(page_header) && self.format.is_compacting(page_header) {
                 let new_page = self.format.get_new_page(page_header);
+                if new_page >= self.format.num_pages || new_page == page {
+                    // The page header is corrupted.
+                    continue;
+                }
                 self.compact_page(page, new_page);
             }
         }
@@ -449,17 +795,96 @@ impl<S: Storage, C: StoreConfig> Store<S, C> {
                     // Wipe sensitive data if needed.
                     self.wipe_sensitive_data(entry_index);
                 } else if self.format.is_internal(entry) {
-                    // Finish page compaction.
-                    self.erase_page(entry_index);
+                    match self.format.get_internal_kind(entry) {
+                        InternalKind::Erase => {
+                            if self.format.is_valid(entry) {
+                                // Finish page compaction.
+                                self.erase_page(entry_index);
+                            } else {
+                                // Drop corrupted page compactions.
+                                self.delete_index(entry_index);
+                            }
+                        }
+                        InternalKind::Begin => {
+                            // Finish or roll-back transactions.
+                            index = self.recover_transaction(entry_index);
+                        }
+                        InternalKind::Commit | InternalKind::Delete => {
+                            // Delete the records of finished transactions.
+                            self.delete_index(entry_index);
+                        }
+                    }
                 } else if !self.format.is_complete(entry) {
                     // Roll-back incomplete operations.
                     self.delete_index(entry_index);
                 } else if !self.format.is_committed(entry) {
-                    // Finish complete but uncommitted operations.
-                    self.commit_index(entry_index)
+                    if self.format.is_valid(entry) {
+                        // Finish complete but uncommitted operations.
+                        self.commit_index(entry_index)
+                    } else {
+                        // Roll-back corrupted operations, since we can't trust the entry to replace.
+                        self.delete_index(entry_index);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Completes or rolls-back a transaction.
+    ///
+    /// The transaction starts with the begin marker at `begin` and is committed if a commit marker
+    /// follows its entries. Returns the index following the transaction.
+    fn recover_transaction(&mut self, begin: Index) -> Index {
+        let mut index = begin;
+        index.byte += self.format.internal_entry_size();
+        let mut records = Vec::new();
+        let mut committed = false;
+        while index.byte < self.format.page_size {
+            let record = index;
+            let entry = self.read_entry(index);
+            if !self.format.is_present(entry) {
+                break;
+            }
+            index.byte += entry.len();
+            records.push(record);
+            if self.format.is_internal(entry) {
+                if let InternalKind::Commit = self.format.get_internal_kind(entry) {
+                    committed = true;
+                    break;
+                }
+            }
+        }
+        if !committed {
+            // Roll-back the transaction. The begin marker is deleted last, such that the
+            // roll-back is restarted if interrupted.
+            for &record in &records {
+                self.delete_index(record);
+            }
+            self.delete_index(begin);
+            return index;
+        }
+        // Delete the deleted entries.
+        for &record in &records {
+            let entry = self.read_entry(record);
+            if !self.format.is_internal(entry) || !self.format.is_valid(entry) {
+                continue;
+            }
+            if let InternalKind::Delete = self.format.get_internal_kind(entry) {
+                let target = self.format.get_delete_index(entry);
+                if self.format.is_present(self.read_entry(target)) {
+                    self.delete_index(target);
                 }
             }
         }
+        // The transaction is complete once the begin marker is deleted. The remaining records are
+        // deleted as leftovers if interrupted.
+        self.delete_index(begin);
+        for record in records {
+            if self.format.is_internal(self.read_entry(record)) {
+                self.delete_index(record);
+            }
+        }
+        index
     }
 
     /// Initializes uninitialized pages.
@@ -483,20 +908,45 @@ impl<S: Storage, C: StoreConfig> Store<S, C> {
     /// Marks an entry as deleted.
     ///
     /// The provided index must point to the beginning of an entry.
+    /// Returns the position of an entry in the current generation.
+    fn store_index(&self, index: Index) -> StoreIndex {
+        StoreIndex {
+            index,
+            generation: self.generation,
+        }
+    }
+
+    /// Returns the user view of an entry, if it is alive and valid.
+    fn user_entry<'a>(&self, entry: &'a [u8]) -> Option<StoreEntry<'a>> {
+        if !self.format.is_alive(entry) || !self.format.is_valid(entry) {
+            return None;
+        }
+        Some(StoreEntry {
+            tag: self.format.get_tag(entry),
+            data: self.format.get_data(entry),
+            sensitive: self.format.is_sensitive(entry),
+        })
+    }
+
     fn delete_index(&mut self, index: Index) {
-        self.update_word(index, |format, word| format.set_deleted(word));
+        let length = self.read_entry(index).len();
+        self.update_slice(index, length, |format, entry| format.set_deleted(entry));
         self.wipe_sensitive_data(index);
     }
 
     /// Wipes the data of a sensitive entry.
     ///
     /// If the entry at the provided index is sensitive, overwrites the data with zeroes. Otherwise,
-    /// does nothing.
+    /// does nothing. In the single-write format, the data can't be overwritten and is only erased
+    /// with its page.
     fn wipe_sensitive_data(&mut self, mut index: Index) {
         let entry = self.read_entry(index);
         debug_assert!(self.format.is_present(entry));
         debug_assert!(self.format.is_deleted(entry));
-        if self.format.is_internal(entry) || !self.format.is_sensitive(entry) {
+        if self.format.single_write
+            || self.format.is_internal(entry)
+            || !self.format.is_sensitive(entry)
+        {
             // No need to wipe the data.
             return;
         }
@@ -515,26 +965,26 @@ impl<S: Storage, C: StoreConfig> Store<S, C> {
     /// Finds a page with enough free space.
     ///
     /// Returns an index to the free space of a page which can hold an entry of `length` bytes. If
-    /// necessary, pages may be compacted to free space. In that case, if provided, the `old_index`
-    /// is updated according to compaction.
+    /// necessary, pages may be compacted to free space. In that case, the `old_indices` are updated
+    /// according to compaction.
     fn find_slot_for_write(
         &mut self,
         length: usize,
-        mut old_index: Option<&mut Index>,
+        old_indices: &mut [Index],
     ) -> Result<Index, StoreError> {
         loop {
             if let Some(index) = self.choose_slot_for_write(length) {
                 return Ok(index);
             }
-            match self.choose_page_for_compact() {
+            match self.choose_page_for_compact(&self.page_infos(), self.blank_page) {
                 None => return Err(StoreError::StoreFull),
                 Some(page) => {
                     let blank_page = self.blank_page;
-                    // Compact the chosen page and update the old index to point to the entry in the
-                    // new page if it happened to be in the old page. This is essentially a way to
-                    // avoid index invalidation due to compaction.
+                    // Compact the chosen page and update the old indices to point to the entries in
+                    // the new page if they happened to be in the old page. This is essentially a
+                    // way to avoid index invalidation due to compaction.
                     let map = self.compact_page(page, blank_page);
-                    if let Some(old_index) = &mut old_index {
+                    for old_index in old_indices.iter_mut() {
                         map_index(page, blank_page, &map, old_index);
                     }
                 }
@@ -558,7 +1008,9 @@ impl<S: Storage, C: StoreConfig> Store<S, C> {
     }
 
     /// Returns the page that should be compacted.
-    fn choose_page_for_compact(&self) -> Option<usize> {
+    ///
+    /// The pages are described by `infos`, and `blank_page` is the page they would be compacted to.
+    fn choose_page_for_compact(&self, infos: &[PageInfo], blank_page: usize) -> Option<usize> {
         // TODO(cretin): This could be optimized by using some cost function depending on:
         // - the erase count
         // - the length of the free space
@@ -569,10 +1021,26 @@ impl<S: Storage, C: StoreConfig> Store<S, C> {
         // We should also make sure that all pages (including if they have no deleted entries and no
         // free space) are eventually compacted (ideally to a heavily used page) to benefit from the
         // low erase count of those pages.
-        (0..self.format.num_pages)
-            .map(|page| (page, self.page_info(page)))
-            .filter(|&(page, ref info)| {
+        infos
+            .iter()
+            .enumerate()
+            .filter(|&(page, info)| {
+                page != blank_page
+                    && info.erase_count < self.format.max_page_erases
+                    && info.deleted_length > self.format.internal_entry_size()
+            })
+            .min_by(|(_, lhs_info), (_, rhs_info)| lhs_info.compare_for_compaction(rhs_info))
+            .map(|(page, _)| page)
+    }
+
+    /// Returns a page holding the data of deleted sensitive entries, if any can be compacted.
+    fn choose_page_for_shred(&self, infos: &[PageInfo]) -> Option<usize> {
+        infos
+            .iter()
+            .enumerate()
+            .filter(|&(page, info)| {
                 page != self.blank_page
+                    && info.deleted_sensitive
                     && info.erase_count < self.format.max_page_erases
                     && info.deleted_length > self.format.internal_entry_size()
             })
@@ -580,11 +1048,18 @@ impl<S: Storage, C: StoreConfig> Store<S, C> {
             .map(|(page, _)| page)
     }
 
+    fn page_infos(&self) -> Vec<PageInfo> {
+        (0..self.format.num_pages)
+            .map(|page| self.page_info(page))
+            .collect()
+    }
+
     fn page_info(&self, page: usize) -> PageInfo {
         let (page_header, mut index) = self.read_page_header(page);
         let mut info = PageInfo {
             erase_count: self.format.get_erase_count(page_header),
             deleted_length: 0,
+            deleted_sensitive: false,
             free_length: 0,
         };
         while index.byte < self.format.page_size {
@@ -593,8 +1068,13 @@ impl<S: Storage, C: StoreConfig> Store<S, C> {
             if !self.format.is_present(entry) {
                 debug_assert_eq!(info.free_length, 0);
                 info.free_length = entry.len();
-            } else if self.format.is_deleted(entry) {
+            } else if self.format.is_deleted(entry) || !self.format.is_valid(entry) {
+                // Quarantined entries are dropped by compaction like deleted entries.
                 info.deleted_length += entry.len();
+                // Only the single-write format doesn't wipe deleted sensitive entries.
+                info.deleted_sensitive |= self.format.single_write
+                    && !self.format.is_internal(entry)
+                    && self.format.is_sensitive(entry);
             }
         }
         debug_assert_eq!(index.page, page);
@@ -640,16 +1120,27 @@ impl<S: Storage, C: StoreConfig> Store<S, C> {
         (page_header, index)
     }
 
-    /// Updates a word at a given index.
+    /// Updates a word-aligned slice at a given index.
     ///
-    /// The `update` function is called with the word at `index`. The input value is the current
-    /// value of the word. The output value is the value that will be written. It should only change
-    /// bits from 1 to 0.
-    fn update_word(&mut self, index: Index, update: impl FnOnce(&Format, &mut [u8])) {
+    /// The `update` function is called with the `length` bytes at `index`. The input value is the
+    /// current value of the slice. The output value is the value that will be written. It should
+    /// only change bits from 1 to 0. Only the words that change are written, such that updates can
+    /// be repeated when recovering from a power loss.
+    fn update_slice(
+        &mut self,
+        mut index: Index,
+        length: usize,
+        update: impl FnOnce(&Format, &mut [u8]),
+    ) {
         let word_size = self.format.word_size;
-        let mut word = self.read_slice(index, word_size).to_vec();
-        update(&self.format, &mut word);
-        self.storage.write_slice(index, &word).unwrap();
+        let mut slice = self.read_slice(index, length).to_vec();
+        update(&self.format, &mut slice);
+        for word in slice.chunks(word_size) {
+            if *word != *self.read_slice(index, word_size) {
+                self.storage.write_slice(index, word).unwrap();
+            }
+            index.byte += word_size;
+        }
     }
 
     fn write_entry(&mut self, index: Index, entry: &[u8]) {
@@ -665,9 +1156,11 @@ impl<S: Storage, C: StoreConfig> Store<S, C> {
         if !page.iter().all(|&byte| byte == 0xff) {
             self.storage.erase_page(index.page).unwrap();
         }
-        self.update_word(index, |format, header| {
+        let length = self.format.page_header_size();
+        self.update_slice(index, length, |format, header| {
             format.set_initialized(header);
             format.set_erase_count(header, erase_count);
+            format.set_checksum_bit(header);
         });
         self.blank_page = index.page;
     }
@@ -675,11 +1168,10 @@ impl<S: Storage, C: StoreConfig> Store<S, C> {
     /// Commits a replace entry.
     ///
     /// Deletes the old entry and commits the new entry.
-    fn commit_index(&mut self, mut index: Index) {
+    fn commit_index(&mut self, index: Index) {
         let entry = self.read_entry(index);
-        index.byte += entry.len();
-        let word_size = self.format.word_size;
-        debug_assert!(entry.len() >= 2 * word_size);
+        let length = entry.len();
+        debug_assert!(length >= 2 * self.format.word_size);
         match self.format.is_replace(entry) {
             IsReplace::Replace => {
                 let delete_index = self.format.get_replace_index(entry);
@@ -687,8 +1179,7 @@ impl<S: Storage, C: StoreConfig> Store<S, C> {
             }
             IsReplace::Insert => debug_assert!(false),
         };
-        index.byte -= word_size;
-        self.update_word(index, |format, word| format.set_committed(word));
+        self.update_slice(index, length, |format, entry| format.set_committed(entry));
     }
 
     /// Compacts a page to an other.
@@ -697,11 +1188,13 @@ impl<S: Storage, C: StoreConfig> Store<S, C> {
     fn compact_page(&mut self, old_page: usize, new_page: usize) -> BTreeMap<usize, usize> {
         // Write the old page as being compacted to the new page.
         let mut erase_count = 0;
-        self.update_word(
+        let page_header_size = self.format.page_header_size();
+        self.update_slice(
             Index {
                 page: old_page,
                 byte: 0,
             },
+            page_header_size,
             |format, header| {
                 erase_count = format.get_erase_count(header);
                 format.set_compacting(header);
@@ -709,7 +1202,6 @@ impl<S: Storage, C: StoreConfig> Store<S, C> {
             },
         );
         // Copy alive entries from the old page to the new page.
-        let page_header_size = self.format.page_header_size();
         let mut old_index = Index {
             page: old_page,
             byte: page_header_size,
@@ -723,7 +1215,7 @@ impl<S: Storage, C: StoreConfig> Store<S, C> {
             let old_entry = self.read_entry(old_index);
             let old_entry_index = old_index.byte;
             old_index.byte += old_entry.len();
-            if !self.format.is_alive(old_entry) {
+            if !self.format.is_alive(old_entry) || !self.format.is_valid(old_entry) {
                 continue;
             }
             let previous_mapping = map.insert(old_entry_index, new_index.byte);
@@ -773,6 +1265,11 @@ impl<C: StoreConfig> Store<BufferStorage, C> {
         self.storage.arm_snapshot(delay);
     }
 
+    /// Takes a snapshot of the storage while a given word operation is interrupted.
+    pub fn arm_interruption(&mut self, delay: usize, interruption: Interruption) {
+        self.storage.arm_interruption(delay, interruption);
+    }
+
     /// Unarms and returns the snapshot or the delay remaining.
     pub fn get_snapshot(&mut self) -> Result<Box<[u8]>, usize> {
         self.storage.get_snapshot()
@@ -829,6 +1326,9 @@ struct PageInfo {
     /// Cumulative length of deleted entries (including header and footer).
     deleted_length: usize,
 
+    /// Whether a deleted entry still holds sensitive data.
+    deleted_sensitive: bool,
+
     /// Length of the free space.
     free_length: usize,
 }
@@ -903,6 +1403,25 @@ mod tests {
         }
     }
 
+    /// Same as `Config` but with checksums.
+    struct ChecksumConfig;
+
+    impl StoreConfig for ChecksumConfig {
+        type Key = u8;
+
+        fn num_tags(&self) -> usize {
+            Config.num_tags()
+        }
+
+        fn keys(&self, entry: StoreEntry, add: impl FnMut(u8)) {
+            Config.keys(entry, add)
+        }
+
+        fn checksum(&self) -> bool {
+            true
+        }
+    }
+
     fn new_buffer(storage: Box<[u8]>) -> BufferStorage {
         let options = BufferOptions {
             word_size: WORD_SIZE,
@@ -914,11 +1433,33 @@ mod tests {
         BufferStorage::new(storage, options)
     }
 
+    /// Returns a buffer where words can be written only once.
+    fn new_single_write_buffer(storage: Box<[u8]>, word_size: usize) -> BufferStorage {
+        let options = BufferOptions {
+            word_size,
+            page_size: 16 * word_size,
+            max_word_writes: 1,
+            max_page_erases: 2,
+            strict_write: true,
+        };
+        BufferStorage::new(storage, options)
+    }
+
+    fn new_single_write_store(word_size: usize) -> Store<BufferStorage, Config> {
+        let storage = vec![0xff; NUM_PAGES * 16 * word_size].into_boxed_slice();
+        Store::new(new_single_write_buffer(storage, word_size), Config).unwrap()
+    }
+
     fn new_store() -> Store<BufferStorage, Config> {
         let storage = vec![0xff; NUM_PAGES * PAGE_SIZE].into_boxed_slice();
         Store::new(new_buffer(storage), Config).unwrap()
     }
 
+    fn new_checksum_store() -> Store<BufferStorage, ChecksumConfig> {
+        let storage = vec![0xff; NUM_PAGES * PAGE_SIZE].into_boxed_slice();
+        Store::new(new_buffer(storage), ChecksumConfig).unwrap()
+    }
+
     #[test]
     fn insert_ok() {
         let mut store = new_store();
@@ -989,6 +1530,89 @@ mod tests {
         assert!(store.deleted_entries_are_wiped());
     }
 
+    #[test]
+    fn get_ok() {
+        let mut store = new_store();
+        let entry = StoreEntry {
+            tag: 0,
+            data: &[1, 2],
+            sensitive: false,
+        };
+        let index = store.insert(entry).unwrap();
+        assert_eq!(store.get(index), Some(entry));
+        let new_entry = StoreEntry {
+            tag: 0,
+            data: &[1, 3],
+            sensitive: false,
+        };
+        let new_index = store.replace(index, new_entry).unwrap();
+        assert_eq!(store.get(index), None);
+        assert_eq!(store.get(new_index), Some(new_entry));
+        store.delete(new_index).unwrap();
+        assert_eq!(store.get(new_index), None);
+
+        // Indices don't survive a compaction.
+        let generation = store.generation();
+        let index = store.insert(entry).unwrap();
+        while store.generation() == generation {
+            let (old, _) = store.find_one(&1).unwrap();
+            store.replace(old, entry).unwrap();
+        }
+        assert_eq!(store.get(index), None);
+    }
+
+    #[test]
+    fn counter_ok() {
+        struct CounterConfig;
+
+        impl StoreConfig for CounterConfig {
+            type Key = u8;
+
+            fn num_tags(&self) -> usize {
+                Config.num_tags()
+            }
+
+            fn keys(&self, entry: StoreEntry, add: impl FnMut(u8)) {
+                Config.keys(entry, add)
+            }
+
+            fn num_counters(&self) -> usize {
+                2
+            }
+        }
+
+        let num_pages = NUM_PAGES + 2 * COUNTER_PAGES;
+        let storage = vec![0xff; num_pages * PAGE_SIZE].into_boxed_slice();
+        let mut store = Store::new(new_buffer(storage), CounterConfig).unwrap();
+        assert_eq!(store.counter(0), Ok(0));
+        assert_eq!(store.increment(0), Ok(1));
+        assert_eq!(store.increment(0), Ok(2));
+        assert_eq!(store.increment(1), Ok(1));
+        store.advance(1, 10).unwrap();
+        assert_eq!(store.counter(2), Err(StoreError::InvalidPrecondition));
+        assert_eq!(store.increment(2), Err(StoreError::InvalidPrecondition));
+
+        // Compactions don't touch the counters.
+        let entry = StoreEntry {
+            tag: 0,
+            data: &[1, 2],
+            sensitive: false,
+        };
+        store.insert(entry).unwrap();
+        let generation = store.generation();
+        while store.generation() == generation {
+            let (old, _) = store.find_one(&1).unwrap();
+            store.replace(old, entry).unwrap();
+        }
+        assert_eq!(store.counter(0), Ok(2));
+        assert_eq!(store.counter(1), Ok(10));
+
+        let store = Store::new(new_buffer(store.get_storage()), CounterConfig).unwrap();
+        assert_eq!(store.find_one(&1).unwrap().1, entry);
+        assert_eq!(store.counter(0), Ok(2));
+        assert_eq!(store.counter(1), Ok(10));
+    }
+
     #[test]
     fn insert_until_full() {
         let mut store = new_store();
@@ -1037,33 +1661,104 @@ mod tests {
     }
 
     #[test]
-    fn reboot_ok() {
+    fn compaction_cost_ok() {
         let mut store = new_store();
-        let tag = 0;
-        let key = 1;
-        let data = &[key, 2];
-        let entry = StoreEntry {
-            tag,
-            data,
-            sensitive: false,
-        };
-        store.insert(entry).unwrap();
-
-        // Reboot the store.
-        let store = store.get_storage();
-        let store = Store::new(new_buffer(store), Config).unwrap();
-
-        assert_eq!(store.iter().count(), 1);
-        assert_eq!(store.find_one(&key).unwrap().1, entry);
-    }
-
-    #[test]
-    fn replace_atomic() {
-        let tag = 0;
-        let key = 1;
-        let old_entry = StoreEntry {
-            tag,
-            data: &[key, 2, 3, 4, 5, 6],
+        let mut key = 0;
+        while store
+            .insert(StoreEntry {
+                tag: 0,
+                data: &[key, 0],
+                sensitive: false,
+            })
+            .is_ok()
+        {
+            key += 1;
+        }
+        let length = store.insert_len(false, 2);
+        assert_eq!(store.compaction_cost(length), None);
+        for k in 0..2 {
+            let (index, _) = store.find_one(&k).unwrap();
+            store.delete(index).unwrap();
+        }
+        let cost = store.compaction_cost(length).unwrap();
+        assert!(cost > 0);
+        let erases: usize = store.compaction_info().iter().sum();
+        store
+            .insert(StoreEntry {
+                tag: 0,
+                data: &[key, 0],
+                sensitive: false,
+            })
+            .unwrap();
+        assert_eq!(store.compaction_info().iter().sum::<usize>(), erases + cost);
+    }
+
+    #[test]
+    fn compact_step_ok() {
+        let mut store = new_store();
+        let mut key = 0;
+        while store
+            .insert(StoreEntry {
+                tag: 0,
+                data: &[key, 0],
+                sensitive: false,
+            })
+            .is_ok()
+        {
+            key += 1;
+        }
+        let length = store.insert_len(false, 2);
+        // Nothing is compacted if it doesn't help.
+        assert!(!store.compact_step(length));
+        let (index, _) = store.find_one(&0).unwrap();
+        store.delete(index).unwrap();
+        let generation = store.generation();
+        while store.compact_step(length) {}
+        assert!(store.generation() > generation);
+        assert_eq!(store.compaction_cost(length), Some(0));
+        // The next write doesn't compact.
+        let generation = store.generation();
+        store
+            .insert(StoreEntry {
+                tag: 0,
+                data: &[key, 0],
+                sensitive: false,
+            })
+            .unwrap();
+        assert_eq!(store.generation(), generation);
+        for k in 1..=key {
+            assert_eq!(store.find_all(&k).count(), 1);
+        }
+    }
+
+    #[test]
+    fn reboot_ok() {
+        let mut store = new_store();
+        let tag = 0;
+        let key = 1;
+        let data = &[key, 2];
+        let entry = StoreEntry {
+            tag,
+            data,
+            sensitive: false,
+        };
+        store.insert(entry).unwrap();
+
+        // Reboot the store.
+        let store = store.get_storage();
+        let store = Store::new(new_buffer(store), Config).unwrap();
+
+        assert_eq!(store.iter().count(), 1);
+        assert_eq!(store.find_one(&key).unwrap().1, entry);
+    }
+
+    #[test]
+    fn replace_atomic() {
+        let tag = 0;
+        let key = 1;
+        let old_entry = StoreEntry {
+            tag,
+            data: &[key, 2, 3, 4, 5, 6],
             sensitive: false,
         };
         let new_entry = StoreEntry {
@@ -1174,4 +1869,597 @@ mod tests {
         };
         assert_eq!(store.insert(entry), Err(StoreError::StoreFull));
     }
+
+    #[test]
+    fn checksum_ok() {
+        let mut store = new_checksum_store();
+        let entry = StoreEntry {
+            tag: 0,
+            data: &[1, 2],
+            sensitive: false,
+        };
+        store.insert(entry).unwrap();
+        let (index, _) = store.find_one(&1).unwrap();
+        let new_entry = StoreEntry {
+            tag: 0,
+            data: &[1, 3],
+            sensitive: true,
+        };
+        store.replace(index, new_entry).unwrap();
+
+        // Reboot the store.
+        let store = store.get_storage();
+        let store = Store::new(new_buffer(store), ChecksumConfig).unwrap();
+
+        assert_eq!(store.find_one(&1).unwrap().1, new_entry);
+        assert_eq!(
+            store.health(),
+            StoreHealth {
+                valid_entries: 1,
+                corrupt_entries: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn checksum_format_kept() {
+        // A store written without checksums, like the stores of devices flashed before checksums.
+        let mut store = new_store();
+        for key in 0..3 {
+            store
+                .insert(StoreEntry {
+                    tag: 0,
+                    data: &[key, 2],
+                    sensitive: key == 0,
+                })
+                .unwrap();
+        }
+
+        // Enabling checksums in the config doesn't change the format of an existing store.
+        let storage = store.get_storage();
+        let mut store = Store::new(new_buffer(storage), ChecksumConfig).unwrap();
+        assert!(!store.format.entry_checksum());
+        assert_eq!(
+            store.health(),
+            StoreHealth {
+                valid_entries: 3,
+                corrupt_entries: 0,
+            }
+        );
+        // Writes and compactions keep the format of the store.
+        let (mut index, _) = store.find_one(&0).unwrap();
+        let erases = store.compaction_info().iter().sum::<usize>();
+        while store.compaction_info().iter().sum::<usize>() == erases {
+            index = store
+                .replace(
+                    index,
+                    StoreEntry {
+                        tag: 0,
+                        data: &[0, 3],
+                        sensitive: true,
+                    },
+                )
+                .unwrap();
+        }
+        let storage = store.get_storage();
+        let store = Store::new(new_buffer(storage), ChecksumConfig).unwrap();
+        assert!(!store.format.entry_checksum());
+        assert_eq!(store.find_one(&0).unwrap().1.data, &[0, 3]);
+        for key in 1..3 {
+            assert_eq!(store.find_one(&key).unwrap().1.data, &[key, 2]);
+        }
+        assert_eq!(store.health().corrupt_entries, 0);
+
+        // Likewise, disabling checksums keeps them in a store written with checksums.
+        let mut store = new_checksum_store();
+        store
+            .insert(StoreEntry {
+                tag: 0,
+                data: &[1, 2],
+                sensitive: false,
+            })
+            .unwrap();
+        let storage = store.get_storage();
+        let store = Store::new(new_buffer(storage), Config).unwrap();
+        assert!(store.format.entry_checksum());
+        assert_eq!(store.find_one(&1).unwrap().1.data, &[1, 2]);
+        assert_eq!(store.health().corrupt_entries, 0);
+    }
+
+    /// A storage written by the store before checksums: entry 1 was inserted then replaced, entry 2
+    /// was inserted, and entry 3 was inserted then deleted.
+    const STORAGE_BEFORE_CHECKSUMS: [u8; NUM_PAGES * PAGE_SIZE] = [
+        0xf8, 0xff, 0xff, 0xff, 0x6c, 0xfc, 0xff, 0xff, 0x00, 0x00, 0x00, 0x00, 0xff, 0xff, 0xff,
+        0x3f, 0x5e, 0xfc, 0x02, 0x21, 0xff, 0xff, 0xff, 0x3f, 0x3c, 0xfc, 0x03, 0xff, 0xff, 0xff,
+        0xff, 0x3f, 0xf8, 0xff, 0xff, 0xff, 0x86, 0x40, 0xfe, 0xff, 0x01, 0x13, 0x14, 0x15, 0xff,
+        0xff, 0xff, 0x3f, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+        0xff, 0xff, 0xff, 0xff, 0xf8, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+        0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+        0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+    ];
+
+    #[test]
+    fn storage_before_checksums_ok() {
+        let storage = STORAGE_BEFORE_CHECKSUMS.to_vec().into_boxed_slice();
+        let mut store = Store::new(new_buffer(storage), ChecksumConfig).unwrap();
+        assert!(!store.format.entry_checksum());
+        assert_eq!(
+            store.health(),
+            StoreHealth {
+                valid_entries: 2,
+                corrupt_entries: 0,
+            }
+        );
+        let (_, entry) = store.find_one(&1).unwrap();
+        assert_eq!(entry.data, &[1, 0x13, 0x14, 0x15]);
+        assert!(entry.sensitive);
+        assert_eq!(store.find_one(&2).unwrap().1.data, &[2, 0x21]);
+        assert!(store.find_one(&3).is_none());
+
+        // The store keeps working in its format.
+        let (index, _) = store.find_one(&2).unwrap();
+        let new_entry = StoreEntry {
+            tag: 0,
+            data: &[2, 0x22],
+            sensitive: false,
+        };
+        store.replace(index, new_entry).unwrap();
+        let storage = store.get_storage();
+        let store = Store::new(new_buffer(storage), ChecksumConfig).unwrap();
+        assert_eq!(store.find_one(&2).unwrap().1, new_entry);
+        assert_eq!(store.find_one(&1).unwrap().1.data, &[1, 0x13, 0x14, 0x15]);
+        assert_eq!(store.health().corrupt_entries, 0);
+    }
+
+    #[test]
+    fn checksum_bit_majority() {
+        let mut store = new_checksum_store();
+        store
+            .insert(StoreEntry {
+                tag: 0,
+                data: &[1, 2],
+                sensitive: false,
+            })
+            .unwrap();
+        // Fresh pages only differ by their checksum bit.
+        let storage = new_store().get_storage();
+        let mut storage_with_checksum = store.get_storage();
+        // Clearing the checksum bit of a page header is outvoted by the other pages.
+        for byte in 0..WORD_SIZE {
+            storage_with_checksum[byte] |= storage[byte];
+        }
+        let storage = storage_with_checksum;
+        let store = Store::new(new_buffer(storage), ChecksumConfig).unwrap();
+        assert!(store.format.entry_checksum());
+        assert_eq!(store.find_one(&1).unwrap().1.data, &[1, 2]);
+    }
+
+    #[test]
+    fn corrupt_entry_quarantined() {
+        let mut store = new_checksum_store();
+        for &key in &[1, 3] {
+            store
+                .insert(StoreEntry {
+                    tag: 0,
+                    data: &[key, 2],
+                    sensitive: false,
+                })
+                .unwrap();
+        }
+
+        // Flip a bit in the data of the first entry, after the page header and the entry header.
+        let mut storage = store.get_storage();
+        storage[7] ^= 0x01;
+        let mut store = Store::new(new_buffer(storage), ChecksumConfig).unwrap();
+
+        assert!(store.find_one(&1).is_none());
+        assert!(store.find_one(&3).is_some());
+        assert_eq!(
+            store.health(),
+            StoreHealth {
+                valid_entries: 1,
+                corrupt_entries: 1,
+            }
+        );
+
+        // The quarantined entry is dropped when its page is compacted.
+        let mut key = 4;
+        while store.health().corrupt_entries > 0 {
+            let entry = StoreEntry {
+                tag: 0,
+                data: &[key, 0],
+                sensitive: false,
+            };
+            store.insert(entry).unwrap();
+            let (index, _) = store.find_one(&key).unwrap();
+            store.delete(index).unwrap();
+            key += 1;
+        }
+        assert!(store.find_one(&3).is_some());
+    }
+
+    #[test]
+    fn truncated_entry_ok() {
+        let mut store = new_store();
+        store
+            .insert(StoreEntry {
+                tag: 0,
+                data: &[1, 2],
+                sensitive: true,
+            })
+            .unwrap();
+
+        // Set all the bits of the length, such that the entry overflows its page.
+        let mut storage = store.get_storage();
+        storage[4] |= 0xe0;
+        storage[5] |= 0x03;
+        let mut store = Store::new(new_buffer(storage), Config).unwrap();
+
+        assert_eq!(store.iter().count(), 0);
+        let entry = StoreEntry {
+            tag: 0,
+            data: &[3, 4],
+            sensitive: false,
+        };
+        store.insert(entry).unwrap();
+        assert_eq!(store.find_one(&3).unwrap().1, entry);
+    }
+
+    #[test]
+    fn corrupt_replace_rolled_back() {
+        let old_entry = StoreEntry {
+            tag: 0,
+            data: &[1, 2],
+            sensitive: false,
+        };
+        let new_entry = StoreEntry {
+            tag: 0,
+            data: &[1, 3],
+            sensitive: false,
+        };
+        for &corrupt in &[false, true] {
+            let mut store = new_checksum_store();
+            store.insert(old_entry).unwrap();
+            // Interrupt the replace after the new entry is written and before it is committed.
+            let delay = store.replace_len(false, new_entry.data.len()) / WORD_SIZE;
+            store.arm_snapshot(delay);
+            let (index, _) = store.find_one(&1).unwrap();
+            store.replace(index, new_entry).unwrap();
+            let mut storage = store.get_snapshot().unwrap();
+            if corrupt {
+                // Flip a bit in the data of the new entry, which follows the old entry.
+                let byte = store.format.page_header_size()
+                    + store.insert_len(false, old_entry.data.len())
+                    + store.format.header_size(false)
+                    + 1;
+                storage[byte] ^= 0x01;
+            }
+            let store = Store::new(new_buffer(storage), ChecksumConfig).unwrap();
+            let expected = if corrupt { old_entry } else { new_entry };
+            assert_eq!(store.find_one(&1).unwrap().1, expected);
+            assert_eq!(store.health().corrupt_entries, 0);
+        }
+    }
+
+    #[test]
+    fn transaction_ok() {
+        let mut store = new_store();
+        for &key in &[1, 2] {
+            store
+                .insert(StoreEntry {
+                    tag: 0,
+                    data: &[key, 0],
+                    sensitive: false,
+                })
+                .unwrap();
+        }
+        let new_entry = StoreEntry {
+            tag: 0,
+            data: &[1, 1],
+            sensitive: true,
+        };
+        let (old, _) = store.find_one(&1).unwrap();
+        let (deleted, _) = store.find_one(&2).unwrap();
+        store
+            .transaction(&[
+                StoreUpdate::Replace {
+                    old,
+                    new: new_entry,
+                },
+                StoreUpdate::Delete(deleted),
+            ])
+            .unwrap();
+        assert_eq!(store.iter().count(), 1);
+        assert_eq!(store.find_one(&1).unwrap().1, new_entry);
+        assert!(store.deleted_entries_are_wiped());
+
+        // This transaction needs a compaction.
+        let entries = [
+            StoreEntry {
+                tag: 0,
+                data: &[3, 0],
+                sensitive: false,
+            },
+            StoreEntry {
+                tag: 0,
+                data: &[4, 0],
+                sensitive: false,
+            },
+        ];
+        store
+            .transaction(&[
+                StoreUpdate::Insert(entries[0]),
+                StoreUpdate::Insert(entries[1]),
+            ])
+            .unwrap();
+
+        // Reboot the store.
+        let store = store.get_storage();
+        let store = Store::new(new_buffer(store), Config).unwrap();
+
+        assert_eq!(store.iter().count(), 3);
+        assert_eq!(store.find_one(&1).unwrap().1, new_entry);
+        assert_eq!(store.find_one(&3).unwrap().1, entries[0]);
+        assert_eq!(store.find_one(&4).unwrap().1, entries[1]);
+    }
+
+    #[test]
+    fn transaction_invalid() {
+        let mut store = new_store();
+        let entry = StoreEntry {
+            tag: 0,
+            data: &[1, 0],
+            sensitive: false,
+        };
+        store.insert(entry).unwrap();
+        let (index, _) = store.find_one(&1).unwrap();
+        assert_eq!(store.transaction(&[]), Ok(()));
+        assert_eq!(
+            store.transaction(&[StoreUpdate::Delete(index), StoreUpdate::Delete(index)]),
+            Err(StoreError::InvalidPrecondition)
+        );
+        assert_eq!(
+            store.transaction(&[
+                StoreUpdate::Delete(index),
+                StoreUpdate::Insert(StoreEntry {
+                    tag: 1,
+                    data: &[],
+                    sensitive: false,
+                }),
+            ]),
+            Err(StoreError::InvalidTag)
+        );
+        // The transaction doesn't fit in a page.
+        let updates = [StoreUpdate::Insert(entry); 4];
+        assert_eq!(store.transaction(&updates), Err(StoreError::StoreFull));
+        assert_eq!(store.iter().count(), 1);
+        assert_eq!(store.find_one(&1).unwrap().1, entry);
+    }
+
+    #[test]
+    fn transaction_atomic() {
+        let old_entries = [
+            StoreEntry {
+                tag: 0,
+                data: &[1, 0],
+                sensitive: false,
+            },
+            StoreEntry {
+                tag: 0,
+                data: &[2, 0],
+                sensitive: true,
+            },
+        ];
+        let new_entry = StoreEntry {
+            tag: 0,
+            data: &[1, 1],
+            sensitive: false,
+        };
+        let mut delay = 0;
+        loop {
+            let mut store = new_store();
+            for &entry in &old_entries {
+                store.insert(entry).unwrap();
+            }
+            store.arm_snapshot(delay);
+            let (old, _) = store.find_one(&1).unwrap();
+            let (deleted, _) = store.find_one(&2).unwrap();
+            store
+                .transaction(&[
+                    StoreUpdate::Replace {
+                        old,
+                        new: new_entry,
+                    },
+                    StoreUpdate::Delete(deleted),
+                ])
+                .unwrap();
+            let (complete, store) = match store.get_snapshot() {
+                Err(_) => (true, store.get_storage()),
+                Ok(store) => (false, store),
+            };
+            let store = Store::new(new_buffer(store), Config).unwrap();
+            let entries: Vec<_> = store.iter().map(|(_, entry)| entry).collect();
+            assert!((entries == old_entries && !complete) || entries == [new_entry]);
+            assert!(store.deleted_entries_are_wiped());
+            if complete {
+                break;
+            }
+            delay += 1;
+        }
+    }
+
+    #[test]
+    fn max_word_writes_ok() {
+        let storage = vec![0xff; NUM_PAGES * PAGE_SIZE].into_boxed_slice();
+        let options = BufferOptions {
+            word_size: WORD_SIZE,
+            page_size: PAGE_SIZE,
+            max_word_writes: 0,
+            max_page_erases: 2,
+            strict_write: true,
+        };
+        assert!(Store::new(BufferStorage::new(storage, options), Config).is_none());
+        assert!(!new_store().format.single_write);
+        assert!(new_single_write_store(WORD_SIZE).format.single_write);
+    }
+
+    #[test]
+    fn single_write_ok() {
+        for &word_size in &[8, 16] {
+            let mut store = new_single_write_store(word_size);
+            assert!(store.format.single_write);
+            let entry = StoreEntry {
+                tag: 0,
+                data: &[1, 2],
+                sensitive: true,
+            };
+            let new_entry = StoreEntry {
+                tag: 0,
+                data: &[1, 3, 4],
+                sensitive: false,
+            };
+            let index = store.insert(entry).unwrap();
+            let index = store.replace(index, new_entry).unwrap();
+            store
+                .insert(StoreEntry {
+                    data: &[2],
+                    ..entry
+                })
+                .unwrap();
+            let (deleted, _) = store.find_one(&2).unwrap();
+            store.delete(deleted).unwrap();
+            assert_eq!(store.get(index), Some(new_entry));
+            assert_eq!(store.iter().count(), 1);
+
+            // Reboot the store.
+            let storage = store.get_storage();
+            let store = Store::new(new_single_write_buffer(storage, word_size), Config).unwrap();
+            assert_eq!(store.iter().count(), 1);
+            assert_eq!(store.find_one(&1).unwrap().1, new_entry);
+        }
+    }
+
+    #[test]
+    fn single_write_compact_ok() {
+        for &word_size in &[8, 16] {
+            let mut store = new_single_write_store(word_size);
+            let mut key = 0;
+            while store
+                .insert(StoreEntry {
+                    tag: 0,
+                    data: &[key, 0],
+                    sensitive: true,
+                })
+                .is_ok()
+            {
+                key += 1;
+            }
+            let (index, _) = store.find_one(&0).unwrap();
+            store.delete(index).unwrap();
+            store
+                .insert(StoreEntry {
+                    tag: 0,
+                    data: &[key, 0],
+                    sensitive: true,
+                })
+                .unwrap();
+            assert!(store.compaction_info().iter().any(|&count| count > 0));
+            for k in 1..=key {
+                assert_eq!(store.find_all(&k).count(), 1);
+            }
+        }
+    }
+
+    #[test]
+    fn single_write_shred_ok() {
+        for &word_size in &[8, 16] {
+            let mut store = new_single_write_store(word_size);
+            let entry = StoreEntry {
+                tag: 0,
+                data: &[1, 0x55, 0x55],
+                sensitive: true,
+            };
+            let index = store.insert(entry).unwrap();
+            store
+                .insert(StoreEntry {
+                    data: &[2, 0xAA],
+                    sensitive: false,
+                    ..entry
+                })
+                .unwrap();
+            let length = store.insert_len(true, 3);
+            // Nothing needs compaction before a sensitive entry is deleted.
+            assert!(!store.compact_step(length));
+            store.delete(index).unwrap();
+            assert!(!store.deleted_entries_are_wiped());
+
+            // The page of the deleted entry is compacted, even though writes don't need it.
+            assert_eq!(store.compaction_cost(length), Some(0));
+            assert!(store.compact_step(length));
+            assert!(store.deleted_entries_are_wiped());
+            assert!(!store.compact_step(length));
+            assert_eq!(store.find_all(&1).count(), 0);
+            assert_eq!(store.find_all(&2).count(), 1);
+
+            // The data of the deleted entry doesn't survive a reboot either.
+            let storage = store.get_storage();
+            assert!(!storage
+                .windows(entry.data.len())
+                .any(|window| window == entry.data));
+        }
+    }
+
+    #[test]
+    fn single_write_transaction_atomic() {
+        let old_entries = [
+            StoreEntry {
+                tag: 0,
+                data: &[1, 0],
+                sensitive: false,
+            },
+            StoreEntry {
+                tag: 0,
+                data: &[2, 0],
+                sensitive: true,
+            },
+        ];
+        let new_entry = StoreEntry {
+            tag: 0,
+            data: &[1, 1],
+            sensitive: false,
+        };
+        for &word_size in &[8, 16] {
+            let mut delay = 0;
+            loop {
+                let mut store = new_single_write_store(word_size);
+                for &entry in &old_entries {
+                    store.insert(entry).unwrap();
+                }
+                store.arm_snapshot(delay);
+                let (old, _) = store.find_one(&1).unwrap();
+                let (deleted, _) = store.find_one(&2).unwrap();
+                store
+                    .transaction(&[
+                        StoreUpdate::Replace {
+                            old,
+                            new: new_entry,
+                        },
+                        StoreUpdate::Delete(deleted),
+                    ])
+                    .unwrap();
+                let (complete, storage) = match store.get_snapshot() {
+                    Err(_) => (true, store.get_storage()),
+                    Ok(storage) => (false, storage),
+                };
+                let buffer = new_single_write_buffer(storage, word_size);
+                let store = Store::new(buffer, Config).unwrap();
+                let entries: Vec<_> = store.iter().map(|(_, entry)| entry).collect();
+                assert!((entries == old_entries && !complete) || entries == [new_entry]);
+                if complete {
+                    break;
+                }
+                delay += 1;
+            }
+        }
+    }
 }
@@ -118,6 +118,40 @@ impl SyscallStorage {
     fn is_page_aligned(&self, x: usize) -> bool {
         x & (self.page_size - 1) == 0
     }
+
+    /// Returns whether the word at a given byte of the storage differs from the start of `value`.
+    fn is_changed(&self, byte: usize, value: &[u8]) -> bool {
+        self.storage[byte..][..self.word_size] != value[..self.word_size]
+    }
+
+    /// Writes word-aligned `value` at a given byte of the storage.
+    fn write_words(&mut self, byte: usize, value: &[u8]) -> StorageResult<()> {
+        let code = unsafe {
+            syscalls::allow_ptr(
+                DRIVER_NUMBER,
+                allow_nr::WRITE_SLICE,
+                // We rely on the driver not writing to the slice. This should use read-only allow
+                // when available. See https://github.com/tock/tock/issues/1274.
+                value.as_ptr() as *mut u8,
+                value.len(),
+            )
+        };
+        if code < 0 {
+            return Err(StorageError::KernelError { code });
+        }
+        let code = unsafe {
+            syscalls::command(
+                DRIVER_NUMBER,
+                command_nr::WRITE_SLICE,
+                self.storage[byte..].as_ptr() as usize,
+                0,
+            )
+        };
+        if code < 0 {
+            return Err(StorageError::KernelError { code });
+        }
+        Ok(())
+    }
 }
 
 impl Storage for SyscallStorage {
@@ -150,29 +184,22 @@ impl Storage for SyscallStorage {
             return Err(StorageError::NotAligned);
         }
         let range = index.range(value.len(), self)?;
-        let code = unsafe {
-            syscalls::allow_ptr(
-                DRIVER_NUMBER,
-                allow_nr::WRITE_SLICE,
-                // We rely on the driver not writing to the slice. This should use read-only allow
-                // when available. See https://github.com/tock/tock/issues/1274.
-                value.as_ptr() as *mut u8,
-                value.len(),
-            )
-        };
-        if code < 0 {
-            return Err(StorageError::KernelError { code });
-        }
-        let code = unsafe {
-            syscalls::command(
-                DRIVER_NUMBER,
-                command_nr::WRITE_SLICE,
-                self.storage[range].as_ptr() as usize,
-                0,
-            )
-        };
-        if code < 0 {
-            return Err(StorageError::KernelError { code });
+        // Words already holding their value are skipped, since flash with a single write per word
+        // (like ECC flash) can't write them again, even with the same value. The other words are
+        // written by runs.
+        let word_size = self.word_size;
+        let mut start = 0;
+        while start < value.len() {
+            if !self.is_changed(range.start + start, &value[start..]) {
+                start += word_size;
+                continue;
+            }
+            let mut end = start + word_size;
+            while end < value.len() && self.is_changed(range.start + end, &value[end..]) {
+                end += word_size;
+            }
+            self.write_words(range.start + start, &value[start..end])?;
+            start = end;
         }
         Ok(())
     }
@@ -0,0 +1,137 @@
+// Copyright 2019 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::{Env, Indicator};
+use crate::ctap::hid::ChannelID;
+use crate::ctap::status_code::Ctap2StatusCode;
+use crate::timer::ClockValue;
+use crypto::rng256::{Rng256, ThreadRng256};
+use ctap2::embedded_flash::{BufferStorage, Storage};
+use std::time::Instant;
+
+// The environment of the authenticator running as a process on the host, in tests and in the
+// desktop build.
+// The persistent store lives in RAM unless another storage is given, and user presence is decided
+// by the given function. Randomness comes from the thread rng unless another rng is given.
+pub struct HostEnv<CheckUserPresence, S = BufferStorage, R = ThreadRng256>
+where
+    CheckUserPresence: FnMut(ChannelID) -> Result<(), Ctap2StatusCode>,
+    S: Storage,
+    R: Rng256,
+{
+    rng: R,
+    check_user_presence: CheckUserPresence,
+    storage: Option<S>,
+    start: Instant,
+}
+
+#[cfg(test)]
+impl<CheckUserPresence> HostEnv<CheckUserPresence>
+where
+    CheckUserPresence: FnMut(ChannelID) -> Result<(), Ctap2StatusCode>,
+{
+    pub fn new(check_user_presence: CheckUserPresence) -> HostEnv<CheckUserPresence> {
+        HostEnv::with_storage(check_user_presence, crate::ctap::storage::new_ram_storage())
+    }
+}
+
+#[cfg(test)]
+impl<CheckUserPresence, S> HostEnv<CheckUserPresence, S>
+where
+    CheckUserPresence: FnMut(ChannelID) -> Result<(), Ctap2StatusCode>,
+    S: Storage,
+{
+    // Uses a given storage for the persistent store, for example to keep credentials in a file.
+    pub fn with_storage(
+        check_user_presence: CheckUserPresence,
+        storage: S,
+    ) -> HostEnv<CheckUserPresence, S> {
+        HostEnv::with_storage_and_rng(check_user_presence, storage, ThreadRng256 {})
+    }
+}
+
+impl<CheckUserPresence, S, R> HostEnv<CheckUserPresence, S, R>
+where
+    CheckUserPresence: FnMut(ChannelID) -> Result<(), Ctap2StatusCode>,
+    S: Storage,
+    R: Rng256,
+{
+    // The clock counts milliseconds since the creation of the environment.
+    const CLOCK_FREQUENCY_HZ: usize = 1000;
+    // The host has nowhere to keep a secret, so the persistent store isn't protected at rest. The
+    // secret is constant so that file storages can be opened again, see the --storage option.
+    const DEVICE_SECRET: [u8; 32] = [0x55; 32];
+
+    // Also uses a given rng, for example a seeded one to make runs reproducible.
+    pub fn with_storage_and_rng(
+        check_user_presence: CheckUserPresence,
+        storage: S,
+        rng: R,
+    ) -> HostEnv<CheckUserPresence, S, R> {
+        HostEnv {
+            rng,
+            check_user_presence,
+            storage: Some(storage),
+            start: Instant::now(),
+        }
+    }
+}
+
+impl<CheckUserPresence, S, R> Env for HostEnv<CheckUserPresence, S, R>
+where
+    CheckUserPresence: FnMut(ChannelID) -> Result<(), Ctap2StatusCode>,
+    S: Storage,
+    R: Rng256,
+{
+    type Rng = R;
+    type Storage = S;
+    type Write = Stdout;
+
+    fn rng(&mut self) -> &mut R {
+        &mut self.rng
+    }
+
+    fn check_user_presence(&mut self, cid: ChannelID) -> Result<(), Ctap2StatusCode> {
+        (self.check_user_presence)(cid)
+    }
+
+    fn take_storage(&mut self) -> Option<S> {
+        self.storage.take()
+    }
+
+    fn device_secret(&self) -> [u8; 32] {
+        Self::DEVICE_SECRET
+    }
+
+    fn clock(&mut self) -> ClockValue {
+        let elapsed_ms = self.start.elapsed().as_millis() as isize;
+        ClockValue::new(elapsed_ms, Self::CLOCK_FREQUENCY_HZ)
+    }
+
+    fn write(&mut self) -> Stdout {
+        Stdout
+    }
+
+    // There are no LEDs on the host.
+    fn indicate(&mut self, _indicator: Indicator) {}
+}
+
+pub struct Stdout;
+
+impl core::fmt::Write for Stdout {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        print!("{}", s);
+        Ok(())
+    }
+}
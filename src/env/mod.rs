@@ -0,0 +1,68 @@
+// Copyright 2019 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#[cfg(any(test, feature = "desktop"))]
+pub mod host;
+#[cfg(not(feature = "desktop"))]
+pub mod tock;
+
+use crate::ctap::hid::ChannelID;
+use crate::ctap::status_code::Ctap2StatusCode;
+use crate::timer::ClockValue;
+use crypto::rng256::Rng256;
+use ctap2::embedded_flash::Storage;
+
+// Feedback given to the user, usually with LEDs. Animations advance with the step, which the
+// caller increments regularly.
+// The desktop build has no LEDs, so only the Tock main loop gives feedback.
+#[cfg_attr(feature = "desktop", allow(dead_code))]
+pub enum Indicator {
+    // Nothing to show.
+    Off,
+    // The authenticator waits for the user to touch it.
+    UserPresenceNeeded { step: isize },
+    // The host asked the authenticator to identify itself, see CTAPHID_WINK.
+    Wink { step: isize },
+}
+
+// Everything the authenticator needs from the platform it runs on.
+pub trait Env {
+    type Rng: Rng256;
+    type Storage: Storage;
+    type Write: core::fmt::Write;
+
+    fn rng(&mut self) -> &mut Self::Rng;
+
+    // Waits for the user to confirm their presence. Returns an error on timeout, or if the host
+    // cancelled the request on the given channel.
+    fn check_user_presence(&mut self, cid: ChannelID) -> Result<(), Ctap2StatusCode>;
+
+    // Gives the storage of the persistent store. Only the first call returns Some.
+    fn take_storage(&mut self) -> Option<Self::Storage>;
+
+    // Returns the secret from which the key sealing the persistent store at rest is derived. It
+    // must not change during the lifetime of the device, so it should be kept outside the storage
+    // of the persistent store, at a location that doesn't depend on its layout. A key bound to the
+    // hardware is the best choice when available. If the secret changes anyway, the sealed values
+    // can't be opened and commands fail until a reset.
+    fn device_secret(&self) -> [u8; 32];
+
+    // Reads a monotonic clock.
+    fn clock(&mut self) -> ClockValue;
+
+    // Returns a sink for debugging output.
+    fn write(&mut self) -> Self::Write;
+
+    fn indicate(&mut self, indicator: Indicator);
+}
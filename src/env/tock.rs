@@ -0,0 +1,304 @@
+// Copyright 2019 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::{Env, Indicator};
+use crate::ctap;
+use crate::ctap::hid::{ChannelID, CtapHid, KeepaliveStatus, ProcessedPacket};
+use crate::ctap::status_code::Ctap2StatusCode;
+use crate::usb_ctap_hid;
+use core::cell::Cell;
+#[cfg(feature = "debug_ctap")]
+use core::fmt::Write;
+#[cfg(any(test, feature = "ram_storage"))]
+use crypto::rng256::Rng256;
+use crypto::rng256::TockRng256;
+use ctap2::embedded_flash;
+use libtock::buttons;
+use libtock::buttons::ButtonState;
+use libtock::console::Console;
+use libtock::led;
+use libtock::result::TockValue;
+use libtock::syscalls;
+use libtock::timer;
+use libtock::timer::{ClockValue, Duration, StopAlarmError, Timer};
+
+pub const KEEPALIVE_DELAY_MS: isize = 100;
+pub const KEEPALIVE_DELAY: Duration<isize> = Duration::from_ms(KEEPALIVE_DELAY_MS);
+
+#[cfg(not(any(test, feature = "ram_storage")))]
+type TockStorage = embedded_flash::SyscallStorage;
+#[cfg(any(test, feature = "ram_storage"))]
+type TockStorage = embedded_flash::BufferStorage;
+
+// The environment of the authenticator running as a Tock application.
+pub struct TockEnv<'a> {
+    rng: TockRng256,
+    timer: Timer<'a>,
+    storage: Option<TockStorage>,
+    device_secret: [u8; 32],
+}
+
+impl<'a> TockEnv<'a> {
+    // This should be called at most once per program lifetime, because the storage would alias.
+    pub fn new(timer: Timer<'a>) -> TockEnv<'a> {
+        let mut rng = TockRng256 {};
+        #[cfg(not(any(test, feature = "ram_storage")))]
+        let storage = ctap::storage::new_flash_storage();
+        #[cfg(not(any(test, feature = "ram_storage")))]
+        let device_secret = ctap::storage::flash_device_secret(&mut rng);
+        #[cfg(any(test, feature = "ram_storage"))]
+        let storage = ctap::storage::new_ram_storage();
+        // The storage is lost on reboot, and so can be the secret.
+        #[cfg(any(test, feature = "ram_storage"))]
+        let device_secret = rng.gen_uniform_u8x32();
+        TockEnv {
+            rng,
+            timer,
+            storage: Some(storage),
+            device_secret,
+        }
+    }
+}
+
+impl<'a> Env for TockEnv<'a> {
+    type Rng = TockRng256;
+    type Storage = TockStorage;
+    type Write = Console;
+
+    fn rng(&mut self) -> &mut TockRng256 {
+        &mut self.rng
+    }
+
+    fn check_user_presence(&mut self, cid: ChannelID) -> Result<(), Ctap2StatusCode> {
+        check_user_presence(self, cid)
+    }
+
+    fn take_storage(&mut self) -> Option<TockStorage> {
+        self.storage.take()
+    }
+
+    fn device_secret(&self) -> [u8; 32] {
+        self.device_secret
+    }
+
+    fn clock(&mut self) -> ClockValue {
+        self.timer.get_current_clock()
+    }
+
+    fn write(&mut self) -> Console {
+        Console::new()
+    }
+
+    fn indicate(&mut self, indicator: Indicator) {
+        match indicator {
+            Indicator::Off => switch_off_leds(),
+            Indicator::UserPresenceNeeded { step } => blink_leds(step),
+            Indicator::Wink { step } => wink_leds(step),
+        }
+    }
+}
+
+// Returns whether the keepalive was sent, or false if cancelled.
+fn send_keepalive_up_needed(
+    cid: ChannelID,
+    timeout: Duration<isize>,
+) -> Result<(), Ctap2StatusCode> {
+    let keepalive_msg = CtapHid::keepalive(cid, KeepaliveStatus::UpNeeded);
+    for mut pkt in keepalive_msg {
+        let status = usb_ctap_hid::send_or_recv_with_timeout(&mut pkt, timeout);
+        match status {
+            None => {
+                #[cfg(feature = "debug_ctap")]
+                writeln!(Console::new(), "Sending a KEEPALIVE packet timed out").unwrap();
+                // TODO: abort user presence test?
+            }
+            Some(usb_ctap_hid::SendOrRecvStatus::Error) => {
+                #[cfg(feature = "debug_ctap")]
+                writeln!(Console::new(), "Error sending KEEPALIVE packet").unwrap();
+                // The host is likely gone, e.g. because of a bus reset. There is no point in
+                // waiting for the user. Sending the reply will fail too, and the transport then
+                // sets up the endpoint again.
+                return Err(Ctap2StatusCode::CTAP2_ERR_KEEPALIVE_CANCEL);
+            }
+            Some(usb_ctap_hid::SendOrRecvStatus::Sent) => {
+                #[cfg(feature = "debug_ctap")]
+                writeln!(Console::new(), "Sent KEEPALIVE packet").unwrap();
+            }
+            Some(usb_ctap_hid::SendOrRecvStatus::Received) => {
+                // We only parse one packet, because we only care about CANCEL.
+                let (received_cid, processed_packet) = CtapHid::process_single_packet(&pkt);
+                if received_cid != &cid {
+                    #[cfg(feature = "debug_ctap")]
+                    writeln!(
+                        Console::new(),
+                        "Received a packet on channel ID {:?} while sending a KEEPALIVE packet",
+                        received_cid,
+                    )
+                    .unwrap();
+                    return Ok(());
+                }
+                match processed_packet {
+                    ProcessedPacket::InitPacket { cmd, .. } => {
+                        if cmd == CtapHid::COMMAND_CANCEL {
+                            // We ignore the payload, we can't answer with an error code anyway.
+                            #[cfg(feature = "debug_ctap")]
+                            writeln!(Console::new(), "User presence check cancelled").unwrap();
+                            return Err(Ctap2StatusCode::CTAP2_ERR_KEEPALIVE_CANCEL);
+                        } else {
+                            #[cfg(feature = "debug_ctap")]
+                            writeln!(
+                                Console::new(),
+                                "Discarded packet with command {} received while sending a KEEPALIVE packet",
+                                cmd,
+                            )
+                            .unwrap();
+                        }
+                    }
+                    ProcessedPacket::ContinuationPacket { .. } => {
+                        #[cfg(feature = "debug_ctap")]
+                        writeln!(
+                            Console::new(),
+                            "Discarded continuation packet received while sending a KEEPALIVE packet",
+                        )
+                        .unwrap();
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn blink_leds(pattern_seed: isize) {
+    for l in 0..led::count() {
+        if (pattern_seed ^ l).count_ones() & 1 != 0 {
+            led::get(l).unwrap().on();
+        } else {
+            led::get(l).unwrap().off();
+        }
+    }
+}
+
+fn wink_leds(pattern_seed: isize) {
+    // This generates a "snake" pattern circling through the LEDs.
+    // Fox example with 4 LEDs the sequence of lit LEDs will be the following.
+    // 0 1 2 3
+    // * *
+    // * * *
+    //   * *
+    //   * * *
+    //     * *
+    // *   * *
+    // *     *
+    // * *   *
+    // * *
+    let count = led::count();
+    let a = (pattern_seed / 2) % count;
+    let b = ((pattern_seed + 1) / 2) % count;
+    let c = ((pattern_seed + 3) / 2) % count;
+
+    for l in 0..count {
+        // On nRF52840-DK, logically swap LEDs 3 and 4 so that the order of LEDs form a circle.
+        let k = match l {
+            2 => 3,
+            3 => 2,
+            _ => l,
+        };
+        if k == a || k == b || k == c {
+            led::get(l).unwrap().on();
+        } else {
+            led::get(l).unwrap().off();
+        }
+    }
+}
+
+fn switch_off_leds() {
+    for l in 0..led::count() {
+        led::get(l).unwrap().off();
+    }
+}
+
+fn check_user_presence(env: &mut TockEnv, cid: ChannelID) -> Result<(), Ctap2StatusCode> {
+    // The timeout is N times the keepalive delay.
+    const TIMEOUT_ITERATIONS: isize = ctap::TOUCH_TIMEOUT_MS / KEEPALIVE_DELAY_MS;
+
+    // First, send a keep-alive packet to notify that the keep-alive status has changed.
+    send_keepalive_up_needed(cid, KEEPALIVE_DELAY)?;
+
+    // Listen to the button presses.
+    let button_touched = Cell::new(false);
+    let mut buttons_callback = buttons::with_callback(|_button_num, state| {
+        match state {
+            ButtonState::Pressed => button_touched.set(true),
+            ButtonState::Released => (),
+        };
+    });
+    let mut buttons = buttons_callback.init().unwrap();
+    // At the moment, all buttons are accepted. You can customize your setup here.
+    for mut button in &mut buttons {
+        button.enable().unwrap();
+    }
+
+    let mut keepalive_response = Ok(());
+    for i in 0..TIMEOUT_ITERATIONS {
+        env.indicate(Indicator::UserPresenceNeeded { step: i });
+
+        // Setup a keep-alive callback.
+        let keepalive_expired = Cell::new(false);
+        let mut keepalive_callback = timer::with_callback(|_, _| {
+            keepalive_expired.set(true);
+        });
+        let mut keepalive = keepalive_callback.init().unwrap();
+        let keepalive_alarm = keepalive.set_alarm(KEEPALIVE_DELAY).unwrap();
+
+        // Wait for a button touch or an alarm.
+        syscalls::yieldk_for(|| button_touched.get() || keepalive_expired.get());
+
+        // Cleanup alarm callback.
+        match keepalive.stop_alarm(keepalive_alarm) {
+            Ok(()) => (),
+            Err(TockValue::Expected(StopAlarmError::AlreadyDisabled)) => {
+                assert!(keepalive_expired.get())
+            }
+            Err(e) => panic!("Unexpected error when stopping alarm: {:?}", e),
+        }
+
+        // TODO: this may take arbitrary time. The keepalive_delay should be adjusted accordingly,
+        // so that LEDs blink with a consistent pattern.
+        if keepalive_expired.get() {
+            // Do not return immediately, because we must clean up still.
+            keepalive_response = send_keepalive_up_needed(cid, KEEPALIVE_DELAY);
+        }
+
+        if button_touched.get() || keepalive_response.is_err() {
+            break;
+        }
+    }
+
+    env.indicate(Indicator::Off);
+
+    // Cleanup button callbacks.
+    for mut button in &mut buttons {
+        button.disable().unwrap();
+    }
+
+    // Returns whether the user was present.
+    if keepalive_response.is_err() {
+        keepalive_response
+    } else if button_touched.get() {
+        Ok(())
+    } else {
+        Err(Ctap2StatusCode::CTAP2_ERR_USER_ACTION_TIMEOUT)
+    }
+}
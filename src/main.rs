@@ -29,48 +29,72 @@ extern crate cbor;
 extern crate crypto;
 
 mod ctap;
+#[cfg(feature = "desktop")]
+mod desktop;
+mod env;
+#[cfg(not(feature = "desktop"))]
 mod usb_ctap_hid;
 
+// The BLE, CCID and NFC transports are driven by the desktop build only, see the desktop module.
+#[cfg(all(
+    any(feature = "with_ble", feature = "with_ccid", feature = "with_nfc"),
+    not(feature = "std")
+))]
+compile_error!("The transport features need a desktop build, there is no Tock driver for them.");
+
+// The desktop build replaces the Tock main loop, see the desktop module.
+#[cfg(all(feature = "with_ctap1", not(feature = "desktop")))]
 use core::cell::Cell;
-#[cfg(feature = "debug_ctap")]
+#[cfg(all(feature = "debug_ctap", not(feature = "desktop")))]
 use core::fmt::Write;
-use crypto::rng256::TockRng256;
-use ctap::hid::{ChannelID, CtapHid, KeepaliveStatus, ProcessedPacket};
-use ctap::status_code::Ctap2StatusCode;
+#[cfg(all(feature = "debug_ctap", not(feature = "desktop")))]
+use ctap::hid::trace::{ConsoleWriter, Recorder};
+#[cfg(not(feature = "desktop"))]
+use ctap::hid::transport::HidTransport;
+#[cfg(not(feature = "desktop"))]
+use ctap::hid::CtapHid;
+#[cfg(not(feature = "desktop"))]
 use ctap::CtapState;
+#[cfg(not(feature = "desktop"))]
+use env::tock::{TockEnv, KEEPALIVE_DELAY};
+#[cfg(not(feature = "desktop"))]
+use env::{Env, Indicator};
+#[cfg(all(feature = "with_ctap1", not(feature = "desktop")))]
 use libtock::buttons;
+#[cfg(all(feature = "with_ctap1", not(feature = "desktop")))]
 use libtock::buttons::ButtonState;
-#[cfg(feature = "debug_ctap")]
+#[cfg(all(feature = "debug_ctap", not(feature = "desktop")))]
 use libtock::console::Console;
-use libtock::led;
-use libtock::result::TockValue;
-use libtock::syscalls;
 use libtock::timer;
-#[cfg(feature = "debug_ctap")]
-use libtock::timer::Timer;
-use libtock::timer::{Duration, StopAlarmError, Timestamp};
+#[cfg(not(feature = "desktop"))]
+use libtock::timer::{Duration, Timestamp};
 
-const KEEPALIVE_DELAY_MS: isize = 100;
-const KEEPALIVE_DELAY: Duration<isize> = Duration::from_ms(KEEPALIVE_DELAY_MS);
+#[cfg(not(feature = "desktop"))]
 const SEND_TIMEOUT: Duration<isize> = Duration::from_ms(1000);
 
+#[cfg(feature = "desktop")]
+fn main() {
+    desktop::main()
+}
+
+#[cfg(not(feature = "desktop"))]
 fn main() {
     // Setup the timer with a dummy callback (we only care about reading the current time, but the
     // API forces us to set an alarm callback too).
     let mut with_callback = timer::with_callback(|_, _| {});
     let timer = with_callback.init().unwrap();
 
-    // Setup USB driver.
-    if !usb_ctap_hid::setup() {
-        panic!("Cannot setup USB driver");
-    }
+    // The USB driver is set up on the first transfer, and again after each error.
+    let mut transport = HidTransport::new(usb_ctap_hid::UsbEndpoint);
 
-    let mut rng = TockRng256 {};
-    let mut ctap_state = CtapState::new(&mut rng, check_user_presence);
+    let mut ctap_state = CtapState::new(TockEnv::new(timer));
     let mut ctap_hid = CtapHid::new();
+    // The device rng can't be seeded, so replies depending on randomness won't replay identically.
+    #[cfg(feature = "debug_ctap")]
+    let mut recorder = Recorder::new(ConsoleWriter(Console::new()), None);
 
     let mut led_counter = 0;
-    let mut last_led_increment = timer.get_current_clock();
+    let mut last_led_increment = ctap_state.env().clock();
 
     // Main loop. If CTAP1 is used, we register button presses for U2F while receiving and waiting.
     // The way TockOS and apps currently interact, callbacks need a yield syscall to execute,
@@ -95,17 +119,15 @@ fn main() {
         }
 
         let mut pkt_request = [0; 64];
-        let has_packet = match usb_ctap_hid::recv_with_timeout(&mut pkt_request, KEEPALIVE_DELAY) {
-            Some(usb_ctap_hid::SendOrRecvStatus::Received) => {
-                #[cfg(feature = "debug_ctap")]
-                print_packet_notice("Received packet", &timer);
-                true
+        let has_packet = transport.recv_with_timeout(&mut pkt_request, KEEPALIVE_DELAY);
+        #[cfg(feature = "debug_ctap")]
+        {
+            if has_packet {
+                print_packet_notice("Received packet", ctap_state.env());
             }
-            Some(_) => panic!("Error receiving packet"),
-            None => false,
-        };
+        }
 
-        let now = timer.get_current_clock();
+        let now = ctap_state.env().clock();
         #[cfg(feature = "with_ctap1")]
         {
             if button_touched.get() {
@@ -127,33 +149,23 @@ fn main() {
         ctap_hid.wink_permission = ctap_hid.wink_permission.check_expiration(now);
 
         if has_packet {
+            #[cfg(feature = "debug_ctap")]
+            recorder.record_received(now, &pkt_request);
             let reply = ctap_hid.process_hid_packet(&pkt_request, now, &mut ctap_state);
-            // This block handles sending packets.
-            for mut pkt_reply in reply {
-                let status = usb_ctap_hid::send_or_recv_with_timeout(&mut pkt_reply, SEND_TIMEOUT);
-                match status {
-                    None => {
-                        #[cfg(feature = "debug_ctap")]
-                        print_packet_notice("Sending packet timed out", &timer);
-                        // TODO: reset the ctap_hid state.
-                        // Since sending the packet timed out, we cancel this reply.
-                        break;
-                    }
-                    Some(usb_ctap_hid::SendOrRecvStatus::Error) => panic!("Error sending packet"),
-                    Some(usb_ctap_hid::SendOrRecvStatus::Sent) => {
-                        #[cfg(feature = "debug_ctap")]
-                        print_packet_notice("Sent packet", &timer);
-                    }
-                    Some(usb_ctap_hid::SendOrRecvStatus::Received) => {
-                        #[cfg(feature = "debug_ctap")]
-                        print_packet_notice("Received an UNEXPECTED packet", &timer);
-                        // TODO: handle this unexpected packet.
-                    }
-                }
+            #[cfg(feature = "debug_ctap")]
+            recorder.record_reply(now, &reply);
+            // Errors are recovered from by the transport, which cancels the reply if needed.
+            if transport.send_reply(reply, &mut ctap_hid, SEND_TIMEOUT) {
+                #[cfg(feature = "debug_ctap")]
+                print_packet_notice("Sent reply", ctap_state.env());
             }
+        } else {
+            // Nothing was received during the timeout, so the flash is compacted now rather than
+            // by the next command. Each step erases at most one page.
+            ctap_state.idle_step();
         }
 
-        let now = timer.get_current_clock();
+        let now = ctap_state.env().clock();
         if let Some(wait_duration) = now.wrapping_sub(last_led_increment) {
             if wait_duration > KEEPALIVE_DELAY {
                 // Loops quickly when waiting for U2F user presence, so the next LED blink
@@ -167,31 +179,30 @@ fn main() {
             last_led_increment = now;
         }
 
-        if ctap_hid.wink_permission.is_granted(now) {
-            wink_leds(led_counter);
+        let indicator = if ctap_hid.wink_permission.is_granted(now) {
+            Indicator::Wink { step: led_counter }
         } else {
             #[cfg(not(feature = "with_ctap1"))]
-            switch_off_leds();
+            let indicator = Indicator::Off;
             #[cfg(feature = "with_ctap1")]
-            {
-                if ctap_state.u2f_up_state.is_up_needed(now) {
-                    // Flash the LEDs with an almost regular pattern. The inaccuracy comes from
-                    // delay caused by processing and sending of packets.
-                    blink_leds(led_counter);
-                } else {
-                    switch_off_leds();
-                }
-            }
-        }
+            let indicator = if ctap_state.u2f_up_state.is_up_needed(now) {
+                // Flash the LEDs with an almost regular pattern. The inaccuracy comes from
+                // delay caused by processing and sending of packets.
+                Indicator::UserPresenceNeeded { step: led_counter }
+            } else {
+                Indicator::Off
+            };
+            indicator
+        };
+        ctap_state.env().indicate(indicator);
     }
 }
 
-#[cfg(feature = "debug_ctap")]
-fn print_packet_notice(notice_text: &str, timer: &Timer) {
-    let now_us =
-        (Timestamp::<f64>::from_clock_value(timer.get_current_clock()).ms() * 1000.0) as u64;
+#[cfg(all(feature = "debug_ctap", not(feature = "desktop")))]
+fn print_packet_notice(notice_text: &str, env: &mut impl Env) {
+    let now_us = (Timestamp::<f64>::from_clock_value(env.clock()).ms() * 1000.0) as u64;
     writeln!(
-        Console::new(),
+        env.write(),
         "{} at {}.{:06} s",
         notice_text,
         now_us / 1_000_000,
@@ -199,190 +210,3 @@ fn print_packet_notice(notice_text: &str, timer: &Timer) {
     )
     .unwrap();
 }
-
-// Returns whether the keepalive was sent, or false if cancelled.
-fn send_keepalive_up_needed(
-    cid: ChannelID,
-    timeout: Duration<isize>,
-) -> Result<(), Ctap2StatusCode> {
-    let keepalive_msg = CtapHid::keepalive(cid, KeepaliveStatus::UpNeeded);
-    for mut pkt in keepalive_msg {
-        let status = usb_ctap_hid::send_or_recv_with_timeout(&mut pkt, timeout);
-        match status {
-            None => {
-                #[cfg(feature = "debug_ctap")]
-                writeln!(Console::new(), "Sending a KEEPALIVE packet timed out").unwrap();
-                // TODO: abort user presence test?
-            }
-            Some(usb_ctap_hid::SendOrRecvStatus::Error) => panic!("Error sending KEEPALIVE packet"),
-            Some(usb_ctap_hid::SendOrRecvStatus::Sent) => {
-                #[cfg(feature = "debug_ctap")]
-                writeln!(Console::new(), "Sent KEEPALIVE packet").unwrap();
-            }
-            Some(usb_ctap_hid::SendOrRecvStatus::Received) => {
-                // We only parse one packet, because we only care about CANCEL.
-                let (received_cid, processed_packet) = CtapHid::process_single_packet(&pkt);
-                if received_cid != &cid {
-                    #[cfg(feature = "debug_ctap")]
-                    writeln!(
-                        Console::new(),
-                        "Received a packet on channel ID {:?} while sending a KEEPALIVE packet",
-                        received_cid,
-                    )
-                    .unwrap();
-                    return Ok(());
-                }
-                match processed_packet {
-                    ProcessedPacket::InitPacket { cmd, .. } => {
-                        if cmd == CtapHid::COMMAND_CANCEL {
-                            // We ignore the payload, we can't answer with an error code anyway.
-                            #[cfg(feature = "debug_ctap")]
-                            writeln!(Console::new(), "User presence check cancelled").unwrap();
-                            return Err(Ctap2StatusCode::CTAP2_ERR_KEEPALIVE_CANCEL);
-                        } else {
-                            #[cfg(feature = "debug_ctap")]
-                            writeln!(
-                                Console::new(),
-                                "Discarded packet with command {} received while sending a KEEPALIVE packet",
-                                cmd,
-                            )
-                            .unwrap();
-                        }
-                    }
-                    ProcessedPacket::ContinuationPacket { .. } => {
-                        #[cfg(feature = "debug_ctap")]
-                        writeln!(
-                            Console::new(),
-                            "Discarded continuation packet received while sending a KEEPALIVE packet",
-                        )
-                        .unwrap();
-                    }
-                }
-            }
-        }
-    }
-    Ok(())
-}
-
-fn blink_leds(pattern_seed: isize) {
-    for l in 0..led::count() {
-        if (pattern_seed ^ l).count_ones() & 1 != 0 {
-            led::get(l).unwrap().on();
-        } else {
-            led::get(l).unwrap().off();
-        }
-    }
-}
-
-fn wink_leds(pattern_seed: isize) {
-    // This generates a "snake" pattern circling through the LEDs.
-    // Fox example with 4 LEDs the sequence of lit LEDs will be the following.
-    // 0 1 2 3
-    // * *
-    // * * *
-    //   * *
-    //   * * *
-    //     * *
-    // *   * *
-    // *     *
-    // * *   *
-    // * *
-    let count = led::count();
-    let a = (pattern_seed / 2) % count;
-    let b = ((pattern_seed + 1) / 2) % count;
-    let c = ((pattern_seed + 3) / 2) % count;
-
-    for l in 0..count {
-        // On nRF52840-DK, logically swap LEDs 3 and 4 so that the order of LEDs form a circle.
-        let k = match l {
-            2 => 3,
-            3 => 2,
-            _ => l,
-        };
-        if k == a || k == b || k == c {
-            led::get(l).unwrap().on();
-        } else {
-            led::get(l).unwrap().off();
-        }
-    }
-}
-
-fn switch_off_leds() {
-    for l in 0..led::count() {
-        led::get(l).unwrap().off();
-    }
-}
-
-fn check_user_presence(cid: ChannelID) -> Result<(), Ctap2StatusCode> {
-    // The timeout is N times the keepalive delay.
-    const TIMEOUT_ITERATIONS: isize = ctap::TOUCH_TIMEOUT_MS / KEEPALIVE_DELAY_MS;
-
-    // First, send a keep-alive packet to notify that the keep-alive status has changed.
-    send_keepalive_up_needed(cid, KEEPALIVE_DELAY)?;
-
-    // Listen to the button presses.
-    let button_touched = Cell::new(false);
-    let mut buttons_callback = buttons::with_callback(|_button_num, state| {
-        match state {
-            ButtonState::Pressed => button_touched.set(true),
-            ButtonState::Released => (),
-        };
-    });
-    let mut buttons = buttons_callback.init().unwrap();
-    // At the moment, all buttons are accepted. You can customize your setup here.
-    for mut button in &mut buttons {
-        button.enable().unwrap();
-    }
-
-    let mut keepalive_response = Ok(());
-    for i in 0..TIMEOUT_ITERATIONS {
-        blink_leds(i);
-
-        // Setup a keep-alive callback.
-        let keepalive_expired = Cell::new(false);
-        let mut keepalive_callback = timer::with_callback(|_, _| {
-            keepalive_expired.set(true);
-        });
-        let mut keepalive = keepalive_callback.init().unwrap();
-        let keepalive_alarm = keepalive.set_alarm(KEEPALIVE_DELAY).unwrap();
-
-        // Wait for a button touch or an alarm.
-        syscalls::yieldk_for(|| button_touched.get() || keepalive_expired.get());
-
-        // Cleanup alarm callback.
-        match keepalive.stop_alarm(keepalive_alarm) {
-            Ok(()) => (),
-            Err(TockValue::Expected(StopAlarmError::AlreadyDisabled)) => {
-                assert!(keepalive_expired.get())
-            }
-            Err(e) => panic!("Unexpected error when stopping alarm: {:?}", e),
-        }
-
-        // TODO: this may take arbitrary time. The keepalive_delay should be adjusted accordingly,
-        // so that LEDs blink with a consistent pattern.
-        if keepalive_expired.get() {
-            // Do not return immediately, because we must clean up still.
-            keepalive_response = send_keepalive_up_needed(cid, KEEPALIVE_DELAY);
-        }
-
-        if button_touched.get() || keepalive_response.is_err() {
-            break;
-        }
-    }
-
-    switch_off_leds();
-
-    // Cleanup button callbacks.
-    for mut button in &mut buttons {
-        button.disable().unwrap();
-    }
-
-    // Returns whether the user was present.
-    if keepalive_response.is_err() {
-        keepalive_response
-    } else if button_touched.get() {
-        Ok(())
-    } else {
-        Err(Ctap2StatusCode::CTAP2_ERR_USER_ACTION_TIMEOUT)
-    }
-}
@@ -12,6 +12,9 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::ctap::hid::transport::HidEndpoint;
+pub use crate::ctap::hid::transport::SendOrRecvStatus;
+use crate::ctap::hid::HidPacket;
 use core::cell::Cell;
 #[cfg(feature = "debug_ctap")]
 use core::fmt::Write;
@@ -110,13 +113,6 @@ pub fn send(buf: &mut [u8; 64]) -> bool {
     true
 }
 
-#[derive(Clone, Copy, PartialEq, Eq)]
-pub enum SendOrRecvStatus {
-    Error,
-    Sent,
-    Received,
-}
-
 // Either sends or receive a packet.
 // Because USB transactions are initiated by the host, we don't decide whether an IN transaction
 // (send for us), an OUT transaction (receive for us), or no transaction at all will happen next.
@@ -160,6 +156,51 @@ pub fn send_or_recv(buf: &mut [u8; 64]) -> SendOrRecvStatus {
     status.get().unwrap()
 }
 
+// The USB endpoint of the CTAP HID driver.
+pub struct UsbEndpoint;
+
+impl HidEndpoint for UsbEndpoint {
+    fn setup(&mut self) -> bool {
+        setup()
+    }
+
+    fn recv_with_timeout(
+        &mut self,
+        buf: &mut HidPacket,
+        timeout_delay: Duration<isize>,
+    ) -> Option<SendOrRecvStatus> {
+        recv_with_timeout(buf, timeout_delay)
+    }
+
+    fn wait(&mut self, delay: Duration<isize>) {
+        sleep(delay)
+    }
+
+    fn send_or_recv_with_timeout(
+        &mut self,
+        buf: &mut HidPacket,
+        timeout_delay: Duration<isize>,
+    ) -> Option<SendOrRecvStatus> {
+        send_or_recv_with_timeout(buf, timeout_delay)
+    }
+}
+
+// Waits for the delay. Returns early if the alarm can't be set.
+fn sleep(delay: Duration<isize>) {
+    let expired = Cell::new(false);
+    let mut callback = timer::with_callback(|_, _| {
+        expired.set(true);
+    });
+    let mut timer = match callback.init() {
+        Ok(x) => x,
+        Err(_) => return,
+    };
+    if timer.set_alarm(delay).is_err() {
+        return;
+    }
+    syscalls::yieldk_for(|| expired.get());
+}
+
 // Same as recv, but with a timeout.
 // If the timeout elapses, return None.
 pub fn recv_with_timeout(